@@ -0,0 +1,258 @@
+use crate::agentx::encryption::{KEY_ENV, StorageKey};
+use crate::agentx::utils::run_blocking;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MANIFEST: &str = "manifest.json";
+
+/// One archived file and where it is restored to
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedFile {
+    archive: String,
+    original: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    session_id: String,
+    archived_at: u64,
+    files: Vec<ArchivedFile>,
+    /// Archived with `ARPC_STORAGE_KEY` set; the files are sealed gzip
+    #[serde(default)]
+    encrypted: bool,
+}
+
+/// Archived sessions live under `<data_local_dir>/arpc/archive/<agent>/<id>/`,
+/// outside the agent directories that session listings scan.
+fn archive_root() -> Result<PathBuf, String> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("arpc/archive"))
+        .ok_or_else(|| "Could not determine local data directory".to_string())
+}
+
+fn session_archive_dir(root: &Path, agent: &str, session_id: &str) -> Result<PathBuf, String> {
+    if session_id.is_empty() || session_id.starts_with('.') || session_id.contains(['/', '\\']) {
+        return Err(format!("Invalid session ID: {}", session_id));
+    }
+    Ok(root.join(agent).join(session_id))
+}
+
+/// Compress `files` into the archive and remove the originals, encrypting
+/// them when `ARPC_STORAGE_KEY` is set
+pub async fn archive_session(
+    agent: &'static str,
+    session_id: String,
+    files: Vec<PathBuf>,
+) -> Result<(), String> {
+    let root = archive_root()?;
+    let key = StorageKey::load()?;
+    run_blocking(move || archive_files(&root, agent, &session_id, &files, key.as_ref())).await?
+}
+
+/// Move an archived session's files back to their original locations
+pub async fn restore_session(agent: &'static str, session_id: String) -> Result<(), String> {
+    let root = archive_root()?;
+    let key = StorageKey::load()?;
+    run_blocking(move || restore_files(&root, agent, &session_id, key.as_ref())).await?
+}
+
+fn archive_files(
+    root: &Path,
+    agent: &str,
+    session_id: &str,
+    files: &[PathBuf],
+    key: Option<&StorageKey>,
+) -> Result<(), String> {
+    let dir = session_archive_dir(root, agent, session_id)?;
+    if dir.join(MANIFEST).exists() {
+        return Err(format!("Session {} is already archived", session_id));
+    }
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create archive directory: {}", e))?;
+
+    let mut archived = Vec::with_capacity(files.len());
+    for (index, original) in files.iter().enumerate() {
+        let archive = match key {
+            Some(_) => format!("{}.gz.enc", index),
+            None => format!("{}.gz", index),
+        };
+        compress(original, &dir.join(&archive), key)
+            .map_err(|e| format!("Failed to archive {:?}: {}", original, e))?;
+        archived.push(ArchivedFile {
+            archive,
+            original: original.clone(),
+        });
+    }
+
+    let manifest = Manifest {
+        session_id: session_id.to_string(),
+        archived_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        files: archived,
+        encrypted: key.is_some(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    fs::write(dir.join(MANIFEST), manifest)
+        .map_err(|e| format!("Failed to write archive manifest: {}", e))?;
+
+    // Originals go only once the archive is complete
+    for original in files {
+        if let Err(e) = fs::remove_file(original) {
+            tracing::warn!("Failed to remove archived file {:?}: {}", original, e);
+        }
+    }
+    tracing::info!("Archived {} session {} to {:?}", agent, session_id, dir);
+    Ok(())
+}
+
+fn restore_files(
+    root: &Path,
+    agent: &str,
+    session_id: &str,
+    key: Option<&StorageKey>,
+) -> Result<(), String> {
+    let dir = session_archive_dir(root, agent, session_id)?;
+    let manifest = fs::read(dir.join(MANIFEST))
+        .map_err(|_| format!("Archived session not found: {}", session_id))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest)
+        .map_err(|e| format!("Invalid archive manifest: {}", e))?;
+    let key = match (manifest.encrypted, key) {
+        (true, None) => {
+            return Err(format!(
+                "Archived session {} is encrypted; set {} to restore it",
+                session_id, KEY_ENV
+            ));
+        }
+        (true, key) => key,
+        (false, _) => None,
+    };
+
+    if let Some(file) = manifest.files.iter().find(|file| file.original.exists()) {
+        return Err(format!(
+            "Cannot restore session {}: {:?} already exists",
+            session_id, file.original
+        ));
+    }
+
+    for file in &manifest.files {
+        if let Some(parent) = file.original.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {:?}: {}", parent, e))?;
+        }
+        decompress(&dir.join(&file.archive), &file.original, key)
+            .map_err(|e| format!("Failed to restore {:?}: {}", file.original, e))?;
+    }
+
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        tracing::warn!("Failed to remove archive {:?}: {}", dir, e);
+    }
+    tracing::info!("Restored {} session {}", agent, session_id);
+    Ok(())
+}
+
+fn compress(source: &Path, target: &Path, key: Option<&StorageKey>) -> io::Result<()> {
+    let mut input = fs::File::open(source)?;
+    let Some(key) = key else {
+        let mut encoder = GzEncoder::new(fs::File::create(target)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        return encoder.finish()?.sync_all();
+    };
+    // Sealing needs the whole compressed file; transcripts are small enough
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    let sealed = key.seal(&encoder.finish()?).map_err(io::Error::other)?;
+    let mut output = fs::File::create(target)?;
+    output.write_all(&sealed)?;
+    output.sync_all()
+}
+
+fn decompress(source: &Path, target: &Path, key: Option<&StorageKey>) -> io::Result<()> {
+    let mut input = fs::File::open(source)?;
+    let compressed = match key {
+        Some(key) => {
+            let mut sealed = Vec::new();
+            input.read_to_end(&mut sealed)?;
+            key.open(&sealed).map_err(io::Error::other)?
+        }
+        None => {
+            let mut output = fs::File::create(target)?;
+            io::copy(&mut GzDecoder::new(input), &mut output)?;
+            return Ok(());
+        }
+    };
+    let mut output = fs::File::create(target)?;
+    io::copy(&mut GzDecoder::new(compressed.as_slice()), &mut output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archived_session_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("archive");
+        let session = dir.path().join("project/abc.jsonl");
+        let todo = dir.path().join("todos/abc.json");
+        fs::create_dir_all(session.parent().unwrap()).unwrap();
+        fs::create_dir_all(todo.parent().unwrap()).unwrap();
+        fs::write(&session, "{\"type\":\"user\"}\n").unwrap();
+        fs::write(&todo, "[]").unwrap();
+
+        let files = vec![session.clone(), todo.clone()];
+        archive_files(&root, "claude", "abc", &files, None).unwrap();
+        assert!(!session.exists() && !todo.exists());
+        assert!(archive_files(&root, "claude", "abc", &files, None).is_err());
+
+        restore_files(&root, "claude", "abc", None).unwrap();
+        assert_eq!(
+            fs::read_to_string(&session).unwrap(),
+            "{\"type\":\"user\"}\n"
+        );
+        assert_eq!(fs::read_to_string(&todo).unwrap(), "[]");
+        assert!(!root.join("claude/abc").exists());
+
+        assert!(restore_files(&root, "claude", "abc", None).is_err());
+        assert!(archive_files(&root, "claude", "../abc", &files, None).is_err());
+    }
+
+    #[test]
+    fn encrypted_archive_needs_its_key_to_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("archive");
+        let session = dir.path().join("project/abc.jsonl");
+        fs::create_dir_all(session.parent().unwrap()).unwrap();
+        fs::write(&session, "{\"type\":\"user\"}\n").unwrap();
+        let key = StorageKey::parse(&"ab".repeat(32)).unwrap();
+
+        archive_files(
+            &root,
+            "claude",
+            "abc",
+            std::slice::from_ref(&session),
+            Some(&key),
+        )
+        .unwrap();
+        let sealed = fs::read(root.join("claude/abc/0.gz.enc")).unwrap();
+        assert!(
+            GzDecoder::new(sealed.as_slice())
+                .read_to_end(&mut Vec::new())
+                .is_err()
+        );
+
+        assert!(restore_files(&root, "claude", "abc", None).is_err());
+        restore_files(&root, "claude", "abc", Some(&key)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&session).unwrap(),
+            "{\"type\":\"user\"}\n"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::agentx::types::{Project, Session, WorkingDirectory};
+use crate::session::SessionEnded;
+use moka::future::Cache;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Listings kept per agent; a few hundred session pages is plenty for polling UIs
+const MAX_ENTRIES: u64 = 512;
+
+/// `(agent, limit, offset, projectPath)` of a session listing
+type SessionsKey = (&'static str, Option<usize>, Option<usize>, Option<String>);
+
+/// Short-lived cache of the project and session listings scanned from the
+/// agents' JSONL history, so dashboards polling them don't rescan every time.
+///
+/// Project metadata (pins, names) is applied on top of cached listings, so
+/// only changes to the history itself need an invalidation.
+pub struct ListingCache {
+    enabled: bool,
+    projects: Cache<&'static str, Arc<Vec<Project>>>,
+    working_directories: Cache<&'static str, Arc<Vec<WorkingDirectory>>>,
+    sessions: Cache<SessionsKey, Arc<Vec<Session>>>,
+}
+
+impl ListingCache {
+    /// Cache listings for `ttl`; a zero `ttl` turns caching off.
+    pub fn new(ttl: Duration) -> Self {
+        ListingCache {
+            enabled: !ttl.is_zero(),
+            projects: Cache::builder()
+                .max_capacity(MAX_ENTRIES)
+                .time_to_live(ttl)
+                .build(),
+            working_directories: Cache::builder()
+                .max_capacity(MAX_ENTRIES)
+                .time_to_live(ttl)
+                .build(),
+            sessions: Cache::builder()
+                .max_capacity(MAX_ENTRIES)
+                .time_to_live(ttl)
+                .support_invalidation_closures()
+                .build(),
+        }
+    }
+
+    pub async fn projects(
+        &self,
+        agent: &'static str,
+        load: impl Future<Output = Result<Vec<Project>, String>>,
+    ) -> Result<Vec<Project>, String> {
+        self.cached(&self.projects, agent, load).await
+    }
+
+    pub async fn working_directories(
+        &self,
+        agent: &'static str,
+        load: impl Future<Output = Result<Vec<WorkingDirectory>, String>>,
+    ) -> Result<Vec<WorkingDirectory>, String> {
+        self.cached(&self.working_directories, agent, load).await
+    }
+
+    pub async fn sessions(
+        &self,
+        agent: &'static str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        project_path: Option<String>,
+        load: impl Future<Output = Result<Vec<Session>, String>>,
+    ) -> Result<Vec<Session>, String> {
+        let key = (agent, limit, offset, project_path);
+        self.cached(&self.sessions, key, load).await
+    }
+
+    /// Concurrent misses for the same key share one load; failures aren't cached.
+    async fn cached<K, V>(
+        &self,
+        cache: &Cache<K, Arc<V>>,
+        key: K,
+        load: impl Future<Output = Result<V, String>>,
+    ) -> Result<V, String>
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+    {
+        if !self.enabled {
+            return load.await;
+        }
+        cache
+            .try_get_with(key, async { load.await.map(Arc::new) })
+            .await
+            .map(|value| (*value).clone())
+            .map_err(|e| (*e).clone())
+    }
+
+    /// Drop every listing of `agent`, after its history changed.
+    pub async fn invalidate(&self, agent: &str) {
+        self.projects.invalidate(agent).await;
+        self.working_directories.invalidate(agent).await;
+        let agent = agent.to_string();
+        // Only fails without support_invalidation_closures, which is set above
+        let _ = self
+            .sessions
+            .invalidate_entries_if(move |key, _| key.0 == agent);
+    }
+
+    /// Invalidate an agent's listings whenever one of its sessions ends and
+    /// its transcript is final.
+    pub async fn invalidate_on_session_end(
+        self: Arc<Self>,
+        mut ended: broadcast::Receiver<SessionEnded>,
+    ) {
+        loop {
+            match ended.recv().await {
+                Ok(event) => self.invalidate(event.executor_kind.as_str()).await,
+                // Missed some; start over from a clean cache
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    self.projects.invalidate_all();
+                    self.working_directories.invalidate_all();
+                    self.sessions.invalidate_all();
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn session(id: &str) -> Session {
+        Session {
+            id: id.to_string(),
+            project_id: "p".to_string(),
+            project_path: "/work".to_string(),
+            todo_data: None,
+            created_at: 0,
+            first_message: None,
+            message_timestamp: None,
+            message_count: 0,
+            status: "completed".to_string(),
+            total_duration: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn listings_are_reused_until_invalidated() {
+        let cache = ListingCache::new(Duration::from_secs(60));
+        let scans = AtomicUsize::new(0);
+        let load = || async {
+            scans.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![session("a")])
+        };
+
+        for _ in 0..3 {
+            let sessions = cache.sessions("claude", None, None, None, load()).await;
+            assert_eq!(sessions.unwrap()[0].id, "a");
+        }
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+
+        // Other pages and agents are listed separately
+        let _ = cache.sessions("claude", Some(10), None, None, load()).await;
+        let _ = cache.sessions("codex", None, None, None, load()).await;
+        assert_eq!(scans.load(Ordering::SeqCst), 3);
+
+        cache.invalidate("claude").await;
+        let _ = cache.sessions("claude", None, None, None, load()).await;
+        let _ = cache.sessions("codex", None, None, None, load()).await;
+        assert_eq!(scans.load(Ordering::SeqCst), 4);
+
+        let failing = cache
+            .projects("gemini", async { Err("unreadable".to_string()) })
+            .await;
+        assert_eq!(failing.unwrap_err(), "unreadable");
+    }
+}
@@ -1,12 +1,17 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::time::SystemTime;
 
-pub use crate::agentx::types::{Project, Session, WorkingDirectory};
-use crate::agentx::utils::{metadata_timestamp, should_filter_message_text};
+use crate::agentx::archive;
+use crate::agentx::types::ProjectMeta;
+pub use crate::agentx::types::{HistoryPage, HistoryWindow, Project, Session, WorkingDirectory};
+use crate::agentx::utils::{
+    metadata_timestamp, read_jsonl_window, run_blocking, scan_blocking, should_filter_message_text,
+};
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -125,11 +130,107 @@ pub fn get_project_path_from_sessions(project_dir: &PathBuf) -> Result<String, S
         .ok_or_else(|| "Could not determine project path from session files".to_string())
 }
 
+/// Best-effort decode of a project directory name when no session records
+/// its `cwd`. Claude replaces path separators (and dots) with `-`, so real
+/// hyphens are recovered by probing the filesystem for existing directories.
 fn decode_project_path(encoded: &str) -> String {
-    encoded.replace('-', "/")
+    let parts: Vec<&str> = encoded.trim_start_matches('-').split('-').collect();
+    resolve_encoded_parts(PathBuf::from("/"), &parts)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|| encoded.replace('-', "/"))
 }
 
-async fn extract_session_metadata(jsonl_path: &PathBuf) -> SessionMetadata {
+fn resolve_encoded_parts(base: PathBuf, parts: &[&str]) -> Option<PathBuf> {
+    if parts.is_empty() {
+        return Some(base);
+    }
+
+    // Prefer the longest existing component, so `my-app` beats `my/app`
+    (1..=parts.len()).rev().find_map(|len| {
+        let name = parts[..len].join("-");
+        // An empty part comes from an encoded `/.`, e.g. `--config` for `/.config`
+        let name = match name.strip_prefix('-') {
+            Some(hidden) => format!(".{}", hidden),
+            None => name,
+        };
+        let candidate = base.join(name);
+        candidate
+            .exists()
+            .then(|| resolve_encoded_parts(candidate, &parts[len..]))
+            .flatten()
+    })
+}
+
+/// Location of the persistent project directory -> cwd index
+fn project_index_path() -> Option<PathBuf> {
+    Some(dirs::data_local_dir()?.join("arpc/claude_project_index.json"))
+}
+
+/// Map every project directory under `projects_dir` to its real path.
+///
+/// Paths learned from session `cwd` fields are kept in an index in the client
+/// data dir, so they survive sessions being deleted and only new project
+/// directories need their JSONL files scanned.
+fn resolve_project_paths(projects_dir: &PathBuf) -> HashMap<String, String> {
+    let index_path = project_index_path();
+    let mut index: HashMap<String, String> = index_path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+
+    let Ok(entries) = fs::read_dir(projects_dir) else {
+        return HashMap::new();
+    };
+
+    let mut changed = false;
+    let mut resolved = HashMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(dir_name) = path
+            .is_dir()
+            .then(|| entry.file_name().to_str().map(String::from))
+            .flatten()
+        else {
+            continue;
+        };
+
+        let real_path = match index.get(&dir_name) {
+            Some(real_path) => real_path.clone(),
+            None => match get_project_path_from_sessions(&path) {
+                Ok(real_path) => {
+                    index.insert(dir_name.clone(), real_path.clone());
+                    changed = true;
+                    real_path
+                }
+                Err(_) => decode_project_path(&dir_name),
+            },
+        };
+        resolved.insert(dir_name, real_path);
+    }
+
+    if changed && let Some(index_path) = index_path {
+        index.retain(|dir_name, _| resolved.contains_key(dir_name));
+        if let Err(e) = save_project_index(&index_path, &index) {
+            tracing::warn!("Failed to save Claude project index: {}", e);
+        }
+    }
+
+    resolved
+}
+
+fn save_project_index(path: &PathBuf, index: &HashMap<String, String>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write then rename so concurrent readers never see a partial file
+    let tmp = path.with_extension(format!("json.{}", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(index)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn extract_session_metadata(jsonl_path: &PathBuf) -> SessionMetadata {
     let Ok(file) = fs::File::open(jsonl_path) else {
         return SessionMetadata {
             status: "pending".to_string(),
@@ -141,7 +242,7 @@ async fn extract_session_metadata(jsonl_path: &PathBuf) -> SessionMetadata {
     let mut first_ts: Option<chrono::DateTime<chrono::Utc>> = None;
     let mut last_ts: Option<chrono::DateTime<chrono::Utc>> = None;
 
-    for line in BufReader::new(file).lines().flatten() {
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
         let Ok(entry) = serde_json::from_str::<JsonlMessage>(&line) else {
             continue;
         };
@@ -150,31 +251,31 @@ async fn extract_session_metadata(jsonl_path: &PathBuf) -> SessionMetadata {
             metadata.message_count += 1;
         }
 
-        if metadata.first_message.is_none() {
-            if let Some(msg_field) = &entry.message {
-                let messages = match msg_field {
-                    MessageField::Single(m) => vec![m],
-                    MessageField::Multiple(ms) => ms.iter().collect(),
-                };
+        if metadata.first_message.is_none()
+            && let Some(msg_field) = &entry.message
+        {
+            let messages = match msg_field {
+                MessageField::Single(m) => vec![m],
+                MessageField::Multiple(ms) => ms.iter().collect(),
+            };
 
-                for msg in messages {
-                    if msg.role.as_deref() == Some("user") {
-                        if let Some(text) = msg.content.as_ref().and_then(extract_content_text) {
-                            metadata.first_message = Some(text);
-                            metadata.message_timestamp = entry.timestamp.clone();
-                            break;
-                        }
-                    }
+            for msg in messages {
+                if msg.role.as_deref() == Some("user")
+                    && let Some(text) = msg.content.as_ref().and_then(extract_content_text)
+                {
+                    metadata.first_message = Some(text);
+                    metadata.message_timestamp = entry.timestamp.clone();
+                    break;
                 }
             }
         }
 
-        if let Some(ts_str) = &entry.timestamp {
-            if let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(ts_str) {
-                let utc = parsed.with_timezone(&chrono::Utc);
-                first_ts.get_or_insert(utc);
-                last_ts = Some(utc);
-            }
+        if let Some(ts_str) = &entry.timestamp
+            && let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(ts_str)
+        {
+            let utc = parsed.with_timezone(&chrono::Utc);
+            first_ts.get_or_insert(utc);
+            last_ts = Some(utc);
         }
     }
 
@@ -214,20 +315,18 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
         return Ok(Vec::new());
     }
 
-    let mut projects: Vec<Project> = fs::read_dir(&projects_dir)
-        .map_err(|e| format!("Failed to read projects directory: {}", e))?
-        .flatten()
-        .filter_map(|entry| {
-            let path = entry.path();
-            if !path.is_dir() {
-                return None;
-            }
+    let project_paths = run_blocking({
+        let projects_dir = projects_dir.clone();
+        move || resolve_project_paths(&projects_dir)
+    })
+    .await?;
 
-            let dir_name = path.file_name()?.to_str()?.to_string();
+    let mut projects = scan_blocking(
+        project_paths.into_iter().collect(),
+        move |(dir_name, project_path): (String, String)| {
+            let path = projects_dir.join(&dir_name);
             let metadata = fs::metadata(&path).ok()?;
             let created_at = metadata_timestamp(&metadata);
-            let project_path = get_project_path_from_sessions(&path)
-                .unwrap_or_else(|_| decode_project_path(&dir_name));
             let (sessions, most_recent_session) = collect_sessions(&path)?;
 
             Some(Project {
@@ -236,9 +335,11 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
                 sessions,
                 created_at,
                 most_recent_session,
+                meta: ProjectMeta::default(),
             })
-        })
-        .collect();
+        },
+    )
+    .await;
 
     projects.sort_by(
         |a, b| match (a.most_recent_session, b.most_recent_session) {
@@ -252,7 +353,10 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
     tracing::info!("Found {} projects", projects.len());
     Ok(projects)
 }
-pub async fn load_session_by_id(session_id: String) -> Result<Vec<serde_json::Value>, String> {
+pub async fn load_session_by_id(
+    session_id: String,
+    window: HistoryWindow,
+) -> Result<HistoryPage, String> {
     tracing::info!("Loading session history for session ID: {}", session_id);
 
     let projects_dir = get_claude_dir()
@@ -262,31 +366,24 @@ pub async fn load_session_by_id(session_id: String) -> Result<Vec<serde_json::Va
         return Err("Projects directory does not exist".to_string());
     }
 
-    let clean_id = session_id.trim_end_matches(".jsonl");
+    let clean_id = session_id.trim_end_matches(".jsonl").to_string();
 
-    fs::read_dir(&projects_dir)
-        .map_err(|e| format!("Failed to read projects directory: {}", e))?
-        .flatten()
-        .find_map(|entry| {
-            let session_path = entry.path().join(format!("{}.jsonl", clean_id));
-            session_path
-                .exists()
-                .then(|| {
-                    tracing::info!("Found session file at: {:?}", session_path);
-                    BufReader::new(fs::File::open(&session_path).ok()?)
-                        .lines()
-                        .flatten()
-                        .filter_map(|line| serde_json::from_str(&line).ok())
-                        .collect()
-                })
-                .flatten()
-        })
-        .ok_or_else(|| format!("Session file not found for session ID: {}", clean_id))
+    run_blocking(move || {
+        let session_path = fs::read_dir(&projects_dir)
+            .map_err(|e| format!("Failed to read projects directory: {}", e))?
+            .flatten()
+            .map(|entry| entry.path().join(format!("{}.jsonl", clean_id)))
+            .find(|session_path| session_path.exists())
+            .ok_or_else(|| format!("Session file not found for session ID: {}", clean_id))?;
+
+        tracing::info!("Found session file at: {:?}", session_path);
+        read_jsonl_window(&session_path, window)
+    })
+    .await?
 }
 
-pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
-    tracing::info!("Deleting session with ID: {}", session_id);
-
+/// Session JSONL file and, if present, its todo file
+fn find_session_files(session_id: &str) -> Result<(PathBuf, Option<PathBuf>), String> {
     let claude_dir = get_claude_dir().map_err(|e| e.to_string())?;
     let projects_dir = claude_dir.join("projects");
     if !projects_dir.exists() {
@@ -305,11 +402,19 @@ pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
         })
         .ok_or_else(|| format!("Session file not found for session ID: {}", clean_id))?;
 
+    let todo_path = claude_dir.join("todos").join(format!("{}.json", clean_id));
+    Ok((session_path, todo_path.exists().then_some(todo_path)))
+}
+
+pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
+    tracing::info!("Deleting session with ID: {}", session_id);
+
+    let (session_path, todo_path) = find_session_files(&session_id)?;
+
     fs::remove_file(&session_path).map_err(|e| format!("Failed to delete session file: {}", e))?;
     tracing::info!("Removed session file at {:?}", session_path);
 
-    let todo_path = claude_dir.join("todos").join(format!("{}.json", clean_id));
-    if todo_path.exists() {
+    if let Some(todo_path) = todo_path {
         if let Err(e) = fs::remove_file(&todo_path) {
             tracing::warn!("Failed to delete session todo file {:?}: {}", todo_path, e);
         } else {
@@ -320,6 +425,13 @@ pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
     Ok(())
 }
 
+pub async fn archive_session_by_id(session_id: String) -> Result<(), String> {
+    let clean_id = session_id.trim_end_matches(".jsonl").to_string();
+    let (session_path, todo_path) = find_session_files(&clean_id)?;
+    let files = std::iter::once(session_path).chain(todo_path).collect();
+    archive::archive_session("claude", clean_id, files).await
+}
+
 pub async fn get_all_sessions(
     limit: Option<usize>,
     offset: Option<usize>,
@@ -341,65 +453,53 @@ pub async fn get_all_sessions(
         return Ok(Vec::new());
     }
 
-    let project_infos: Vec<_> = fs::read_dir(&projects_dir)
-        .map_err(|e| format!("Failed to read projects directory: {}", e))?
-        .flatten()
-        .filter_map(|entry| {
-            let path = entry.path();
-            if !path.is_dir() {
-                return None;
-            }
-
-            let id = path.file_name()?.to_str()?.to_string();
-            let real_path =
-                get_project_path_from_sessions(&path).unwrap_or_else(|_| decode_project_path(&id));
-
-            if let Some(ref filter) = project_path {
-                if &real_path != filter {
-                    return None;
+    // Listing is cheap; parsing every transcript for metadata is not, so the
+    // files are collected first and then parsed in parallel
+    let session_files = run_blocking({
+        let projects_dir = projects_dir.clone();
+        move || {
+            let mut files = Vec::new();
+            for (proj_id, proj_real_path) in resolve_project_paths(&projects_dir) {
+                if let Some(ref filter) = project_path
+                    && &proj_real_path != filter
+                {
+                    continue;
+                }
+                let Ok(entries) = fs::read_dir(projects_dir.join(&proj_id)) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let session_path = entry.path();
+                    if session_path.is_file()
+                        && session_path.extension().and_then(|s| s.to_str()) == Some("jsonl")
+                    {
+                        files.push((session_path, proj_id.clone(), proj_real_path.clone()));
+                    }
                 }
             }
+            files
+        }
+    })
+    .await?;
 
-            Some((path, id, real_path))
-        })
-        .collect();
-
-    tracing::info!("Found {} projects to process", project_infos.len());
-
-    let mut sessions = Vec::new();
-    for (proj_path, proj_id, proj_real_path) in project_infos {
-        let Ok(entries) = fs::read_dir(&proj_path) else {
-            continue;
-        };
-
-        for entry in entries.flatten() {
-            let session_path = entry.path();
-            if !session_path.is_file()
-                || session_path.extension().and_then(|s| s.to_str()) != Some("jsonl")
-            {
-                continue;
-            }
+    tracing::info!("Found {} session files to process", session_files.len());
 
-            let Some(session_id) = session_path.file_stem().and_then(|s| s.to_str()) else {
-                continue;
-            };
-            let Ok(meta) = fs::metadata(&session_path) else {
-                continue;
-            };
-            let created_at = metadata_timestamp(&meta);
+    let mut sessions = scan_blocking(
+        session_files,
+        move |(session_path, proj_id, proj_real_path): (PathBuf, String, String)| {
+            let session_id = session_path.file_stem()?.to_str()?.to_string();
+            let created_at = metadata_timestamp(&fs::metadata(&session_path).ok()?);
 
-            let session_meta = extract_session_metadata(&session_path).await;
-            let todo_data = todos_dir
-                .join(format!("{}.json", session_id))
-                .exists()
-                .then(|| fs::read_to_string(todos_dir.join(format!("{}.json", session_id))).ok())
-                .flatten()
+            let session_meta = extract_session_metadata(&session_path);
+            let todo_path = todos_dir.join(format!("{}.json", session_id));
+            let todo_data = fs::read_to_string(&todo_path)
+                .ok()
                 .and_then(|c| serde_json::from_str(&c).ok());
 
-            sessions.push(Session {
-                id: session_id.to_string(),
-                project_id: proj_id.clone(),
-                project_path: proj_real_path.clone(),
+            Some(Session {
+                id: session_id,
+                project_id: proj_id,
+                project_path: proj_real_path,
                 todo_data,
                 created_at,
                 first_message: session_meta.first_message,
@@ -407,11 +507,12 @@ pub async fn get_all_sessions(
                 message_count: session_meta.message_count,
                 status: session_meta.status,
                 total_duration: session_meta.total_duration,
-            });
-        }
-    }
+            })
+        },
+    )
+    .await;
 
-    sessions.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.created_at));
     tracing::info!("Found {} sessions total", sessions.len());
 
     let result: Vec<_> = sessions
@@ -435,16 +536,16 @@ pub async fn get_working_directories() -> Result<Vec<WorkingDirectory>, String>
         return Ok(Vec::new());
     }
 
-    let mut directories: Vec<WorkingDirectory> = fs::read_dir(&projects_dir)
-        .map_err(|e| format!("Failed to read projects directory: {}", e))?
-        .flatten()
-        .filter_map(|entry| {
-            let path = entry.path();
-            if !path.is_dir() {
-                return None;
-            }
+    let project_paths = run_blocking({
+        let projects_dir = projects_dir.clone();
+        move || resolve_project_paths(&projects_dir)
+    })
+    .await?;
 
-            let real_path = get_project_path_from_sessions(&path).ok()?;
+    let mut directories = scan_blocking(
+        project_paths.into_iter().collect(),
+        move |(dir_name, real_path): (String, String)| {
+            let path = projects_dir.join(&dir_name);
 
             let components: Vec<&str> = real_path.split('/').collect();
             let short_name = if components.len() >= 2 {
@@ -476,10 +577,29 @@ pub async fn get_working_directories() -> Result<Vec<WorkingDirectory>, String>
                 last_date,
                 conversation_count: sessions.len(),
             })
-        })
-        .collect();
+        },
+    )
+    .await;
 
     directories.sort_by(|a, b| b.last_date.cmp(&a.last_date));
     tracing::info!("Found {} working directories", directories.len());
     Ok(directories)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_recovers_hyphenated_directories() {
+        let root = tempfile::tempdir().unwrap();
+        let project = root.path().join("my-app").join(".config");
+        fs::create_dir_all(&project).unwrap();
+
+        let encoded = project.to_string_lossy().replace(['/', '.'], "-");
+        assert_eq!(decode_project_path(&encoded), project.to_string_lossy());
+
+        // Unknown paths fall back to the naive decoding
+        assert_eq!(decode_project_path("-no-such-dir"), "/no/such/dir");
+    }
+}
@@ -1,22 +1,26 @@
 use crate::agentx::claude;
 use crate::agentx::routes_common::{register_project_routes, register_session_routes};
+use crate::handlers::HandlerState;
 use crate::router::RouterBuilder;
 
-pub fn register_claude_project_routes(router_builder: &mut RouterBuilder) {
+pub fn register_claude_project_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
     register_project_routes(
         router_builder,
         "claude",
+        state,
         claude::list_projects,
         claude::get_working_directories,
     );
 }
 
-pub fn register_claude_session_routes(router_builder: &mut RouterBuilder) {
+pub fn register_claude_session_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
     register_session_routes(
         router_builder,
         "claude",
+        state,
         claude::get_all_sessions,
         claude::load_session_by_id,
         claude::delete_session_by_id,
+        claude::archive_session_by_id,
     );
 }
@@ -7,8 +7,10 @@ use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::time::SystemTime;
 
-pub use crate::agentx::types::{Project, Session, WorkingDirectory};
-use crate::agentx::utils::{metadata_timestamp, should_filter_message_text};
+use crate::agentx::archive;
+use crate::agentx::types::ProjectMeta;
+pub use crate::agentx::types::{HistoryPage, HistoryWindow, Project, Session, WorkingDirectory};
+use crate::agentx::utils::{metadata_timestamp, read_jsonl_window, should_filter_message_text};
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -28,7 +30,6 @@ struct JsonlMessage {
 
 #[derive(Debug, Deserialize)]
 struct SessionMetaPayload {
-    id: Option<String>,
     cwd: Option<String>,
 }
 
@@ -109,22 +110,21 @@ fn collect_all_session_files(base_dir: &PathBuf) -> Vec<SessionFile> {
             let path = entry.path();
             if path.is_dir() {
                 walk_dir(&path, files);
-            } else if path.is_file() {
-                if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                    if filename.starts_with("rollout-") && filename.ends_with(".jsonl") {
-                        if let Some(session_id) = extract_session_id(filename) {
-                            let created_at = fs::metadata(&path)
-                                .ok()
-                                .map(|m| metadata_timestamp(&m))
-                                .unwrap_or(0);
-                            files.push(SessionFile {
-                                path,
-                                session_id,
-                                created_at,
-                            });
-                        }
-                    }
-                }
+            } else if path.is_file()
+                && let Some(filename) = path.file_name().and_then(|n| n.to_str())
+                && filename.starts_with("rollout-")
+                && filename.ends_with(".jsonl")
+                && let Some(session_id) = extract_session_id(filename)
+            {
+                let created_at = fs::metadata(&path)
+                    .ok()
+                    .map(|m| metadata_timestamp(&m))
+                    .unwrap_or(0);
+                files.push(SessionFile {
+                    path,
+                    session_id,
+                    created_at,
+                });
             }
         }
     }
@@ -171,41 +171,41 @@ async fn extract_session_metadata(session_path: &PathBuf) -> SessionMetadata {
         };
 
         // Extract cwd from session_meta
-        if entry.msg_type.as_deref() == Some("session_meta") {
-            if let Some(payload) = entry.payload {
-                metadata.cwd = payload.cwd;
-            }
+        if entry.msg_type.as_deref() == Some("session_meta")
+            && let Some(payload) = entry.payload
+        {
+            metadata.cwd = payload.cwd;
         }
 
         if entry.message.is_some() {
             metadata.message_count += 1;
         }
 
-        if metadata.first_message.is_none() {
-            if let Some(msg_field) = &entry.message {
-                let messages = match msg_field {
-                    MessageField::Single(m) => vec![m],
-                    MessageField::Multiple(ms) => ms.iter().collect(),
-                };
-
-                for msg in messages {
-                    if msg.role.as_deref() == Some("user") {
-                        if let Some(text) = msg.content.as_ref().and_then(extract_content_text) {
-                            metadata.first_message = Some(text);
-                            metadata.message_timestamp = entry.timestamp.clone();
-                            break;
-                        }
-                    }
+        if metadata.first_message.is_none()
+            && let Some(msg_field) = &entry.message
+        {
+            let messages = match msg_field {
+                MessageField::Single(m) => vec![m],
+                MessageField::Multiple(ms) => ms.iter().collect(),
+            };
+
+            for msg in messages {
+                if msg.role.as_deref() == Some("user")
+                    && let Some(text) = msg.content.as_ref().and_then(extract_content_text)
+                {
+                    metadata.first_message = Some(text);
+                    metadata.message_timestamp = entry.timestamp.clone();
+                    break;
                 }
             }
         }
 
-        if let Some(ts_str) = &entry.timestamp {
-            if let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(ts_str) {
-                let utc = parsed.with_timezone(&chrono::Utc);
-                first_ts.get_or_insert(utc);
-                last_ts = Some(utc);
-            }
+        if let Some(ts_str) = &entry.timestamp
+            && let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(ts_str)
+        {
+            let utc = parsed.with_timezone(&chrono::Utc);
+            first_ts.get_or_insert(utc);
+            last_ts = Some(utc);
         }
     }
 
@@ -255,7 +255,7 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
         if let Some(cwd) = metadata.cwd {
             projects_map
                 .entry(cwd.clone())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push((file.session_id, file.created_at));
         }
     }
@@ -263,7 +263,7 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
     let mut projects: Vec<Project> = projects_map
         .into_iter()
         .map(|(path, mut sessions_data)| {
-            sessions_data.sort_by(|a, b| b.1.cmp(&a.1)); // Sort by created_at desc
+            sessions_data.sort_by_key(|(_, ts)| std::cmp::Reverse(*ts)); // Sort by created_at desc
             let most_recent_session = sessions_data.first().map(|(_, ts)| *ts);
             let sessions: Vec<String> = sessions_data.into_iter().map(|(id, _)| id).collect();
             let created_at = most_recent_session.unwrap_or(0);
@@ -277,6 +277,7 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
                 sessions,
                 created_at,
                 most_recent_session,
+                meta: ProjectMeta::default(),
             }
         })
         .collect();
@@ -291,7 +292,10 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
     Ok(projects)
 }
 
-pub async fn load_session_by_id(session_id: String) -> Result<Vec<serde_json::Value>, String> {
+pub async fn load_session_by_id(
+    session_id: String,
+    window: HistoryWindow,
+) -> Result<HistoryPage, String> {
     tracing::info!("Loading session history for session ID: {}", session_id);
 
     let sessions_dir = get_codex_dir().map_err(|e| e.to_string())?;
@@ -305,14 +309,7 @@ pub async fn load_session_by_id(session_id: String) -> Result<Vec<serde_json::Va
     for file in session_files {
         if file.session_id == clean_id {
             tracing::info!("Found session file at: {:?}", file.path);
-            let contents = tokio::fs::read_to_string(&file.path)
-                .await
-                .map_err(|e| format!("Failed to read session file: {}", e))?;
-
-            return Ok(contents
-                .lines()
-                .filter_map(|line| serde_json::from_str(line).ok())
-                .collect());
+            return read_jsonl_window(&file.path, window);
         }
     }
 
@@ -349,6 +346,17 @@ pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
     ))
 }
 
+pub async fn archive_session_by_id(session_id: String) -> Result<(), String> {
+    let sessions_dir = get_codex_dir().map_err(|e| e.to_string())?;
+    let clean_id = session_id.trim_end_matches(".jsonl").to_string();
+    let path = collect_all_session_files(&sessions_dir)
+        .into_iter()
+        .find(|file| file.session_id == clean_id)
+        .map(|file| file.path)
+        .ok_or_else(|| format!("Session file not found for session ID: {}", clean_id))?;
+    archive::archive_session("codex", clean_id, vec![path]).await
+}
+
 pub async fn get_all_sessions(
     limit: Option<usize>,
     offset: Option<usize>,
@@ -375,10 +383,10 @@ pub async fn get_all_sessions(
         let session_meta = extract_session_metadata(&file.path).await;
 
         // Filter by project_path if specified
-        if let Some(ref filter_path) = project_path {
-            if session_meta.cwd.as_ref() != Some(filter_path) {
-                continue;
-            }
+        if let Some(ref filter_path) = project_path
+            && session_meta.cwd.as_ref() != Some(filter_path)
+        {
+            continue;
         }
 
         let project_path = session_meta.cwd.clone().unwrap_or_default();
@@ -398,7 +406,7 @@ pub async fn get_all_sessions(
         });
     }
 
-    sessions.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.created_at));
     tracing::info!("Found {} sessions total", sessions.len());
 
     let result: Vec<_> = sessions
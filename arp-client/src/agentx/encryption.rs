@@ -0,0 +1,92 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// Environment variable holding the at-rest key as 64 hex characters
+pub const KEY_ENV: &str = "ARPC_STORAGE_KEY";
+
+/// Marks sealed files, so a wrong key is told apart from a file that was
+/// never encrypted
+const MAGIC: &[u8; 6] = b"ARPCE1";
+const NONCE_LEN: usize = 12;
+
+/// Key for the transcripts arpc writes to disk (ChaCha20-Poly1305).
+pub struct StorageKey(Key);
+
+impl StorageKey {
+    /// The key from `ARPC_STORAGE_KEY`, or from the keychain when the
+    /// variable isn't set; `Ok(None)` when there is neither.
+    pub fn load() -> Result<Option<Self>, String> {
+        if let Ok(value) = std::env::var(KEY_ENV) {
+            return Self::parse(&value).map(Some);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some(value) = crate::credentials::get(KEY_ENV)? {
+            return Self::parse(&value).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let invalid = || format!("{} must be 64 hex characters (32 bytes)", KEY_ENV);
+        if value.len() != 64 || !value.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; 32];
+        for (byte, pair) in key.iter_mut().zip(value.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(StorageKey(key.into()))
+    }
+
+    /// Encrypt `plaintext` under a fresh random nonce.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "Encryption failed".to_string())?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt what `seal` produced; fails on a wrong key or tampering.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        let body = sealed
+            .strip_prefix(MAGIC)
+            .filter(|body| body.len() >= NONCE_LEN)
+            .ok_or_else(|| "Not an encrypted arpc file".to_string())?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                format!(
+                    "Decryption failed; is {} the key it was written with?",
+                    KEY_ENV
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_data_opens_only_with_its_key() {
+        let key = StorageKey::parse(&"ab".repeat(32)).unwrap();
+        let other = StorageKey::parse(&"cd".repeat(32)).unwrap();
+
+        let sealed = key.seal(b"transcript").unwrap();
+        assert!(!sealed.windows(10).any(|w| w == b"transcript"));
+        assert_eq!(key.open(&sealed).unwrap(), b"transcript");
+        assert!(other.open(&sealed).is_err());
+        assert!(key.open(b"plain text").is_err());
+
+        assert!(StorageKey::parse("abcd").is_err());
+        assert!(StorageKey::parse(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,5 +1,7 @@
-pub use crate::agentx::types::{Project, Session, WorkingDirectory};
-use crate::agentx::utils::metadata_timestamp;
+use crate::agentx::archive;
+use crate::agentx::types::ProjectMeta;
+pub use crate::agentx::types::{HistoryPage, HistoryWindow, Project, Session, WorkingDirectory};
+use crate::agentx::utils::{metadata_timestamp, read_jsonl_window, window_messages};
 use anyhow::{Context, Result};
 use chrono::{DateTime, TimeZone, Utc};
 use serde::Deserialize;
@@ -100,10 +102,10 @@ fn extract_first_text(value: &Value) -> Option<String> {
         Value::Array(values) => values.iter().find_map(extract_first_text),
         Value::Object(map) => {
             for key in ["text", "content", "message", "value"] {
-                if let Some(v) = map.get(key) {
-                    if let Some(text) = extract_first_text(v) {
-                        return Some(text);
-                    }
+                if let Some(v) = map.get(key)
+                    && let Some(text) = extract_first_text(v)
+                {
+                    return Some(text);
                 }
             }
             map.values().find_map(extract_first_text)
@@ -132,23 +134,20 @@ fn read_session_value(path: &Path) -> Option<Value> {
 fn parse_session_file(path: &Path) -> Option<(GeminiSessionFile, Value)> {
     let value = read_session_value(path)?;
 
-    if let Some(obj) = value.as_object() {
-        if obj.contains_key("sessionId") {
-            if let Ok(session) = serde_json::from_value::<GeminiSessionFile>(value.clone()) {
-                return Some((session, value));
-            }
-        }
+    if let Some(obj) = value.as_object()
+        && obj.contains_key("sessionId")
+        && let Ok(session) = serde_json::from_value::<GeminiSessionFile>(value.clone())
+    {
+        return Some((session, value));
     }
 
     if let Value::Array(entries) = &value {
         for entry in entries {
-            if let Some(obj) = entry.as_object() {
-                if obj.contains_key("sessionId") {
-                    if let Ok(session) = serde_json::from_value::<GeminiSessionFile>(entry.clone())
-                    {
-                        return Some((session, Value::Object(obj.clone())));
-                    }
-                }
+            if let Some(obj) = entry.as_object()
+                && obj.contains_key("sessionId")
+                && let Ok(session) = serde_json::from_value::<GeminiSessionFile>(entry.clone())
+            {
+                return Some((session, Value::Object(obj.clone())));
             }
         }
     }
@@ -158,10 +157,10 @@ fn parse_session_file(path: &Path) -> Option<(GeminiSessionFile, Value)> {
 
 fn infer_project_path(dir: &Path, summaries: &[GeminiSessionSummary]) -> Option<String> {
     for summary in summaries {
-        if let Some(path) = &summary.project_path {
-            if !path.trim().is_empty() {
-                return Some(path.clone());
-            }
+        if let Some(path) = &summary.project_path
+            && !path.trim().is_empty()
+        {
+            return Some(path.clone());
         }
     }
 
@@ -182,10 +181,10 @@ fn infer_project_path(dir: &Path, summaries: &[GeminiSessionSummary]) -> Option<
         }
     } else {
         for line in content.lines() {
-            if let Ok(value) = serde_json::from_str::<Value>(line) {
-                if let Some(path) = extract_candidate_path(&value) {
-                    return Some(path);
-                }
+            if let Ok(value) = serde_json::from_str::<Value>(line)
+                && let Some(path) = extract_candidate_path(&value)
+            {
+                return Some(path);
             }
         }
     }
@@ -349,6 +348,7 @@ pub async fn list_projects() -> Result<Vec<Project>, String> {
             sessions: data.sessions.iter().map(|s| s.id.clone()).collect(),
             created_at: data.created_at,
             most_recent_session: data.most_recent_session,
+            meta: ProjectMeta::default(),
         })
         .collect();
 
@@ -400,7 +400,7 @@ pub async fn get_all_sessions(
         })
         .collect();
 
-    sessions.sort_by(|a, b| b.0.cmp(&a.0));
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.0));
 
     let offset = offset.unwrap_or(0);
     let limit = limit.unwrap_or(sessions.len());
@@ -415,7 +415,10 @@ pub async fn get_all_sessions(
     Ok(filtered)
 }
 
-pub async fn load_session_by_id(session_id: String) -> Result<Vec<Value>, String> {
+pub async fn load_session_by_id(
+    session_id: String,
+    window: HistoryWindow,
+) -> Result<HistoryPage, String> {
     let tmp_dir = gemini_tmp_dir().map_err(|e| e.to_string())?;
 
     let normalized_id = session_id.trim_end_matches(".json").to_string();
@@ -440,10 +443,10 @@ pub async fn load_session_by_id(session_id: String) -> Result<Vec<Value>, String
                 continue;
             }
 
-            if let Some((session_file, _)) = parse_session_file(&path) {
-                if session_file.session_id.as_deref() == Some(normalized_id.as_str()) {
-                    candidates.push(path);
-                }
+            if let Some((session_file, _)) = parse_session_file(&path)
+                && session_file.session_id.as_deref() == Some(normalized_id.as_str())
+            {
+                candidates.push(path);
             }
         }
     }
@@ -453,34 +456,36 @@ pub async fn load_session_by_id(session_id: String) -> Result<Vec<Value>, String
         .next()
         .ok_or_else(|| format!("Session file not found for session ID: {}", normalized_id))?;
 
+    // Chat files are a single JSON document that has to be parsed whole
+    let size = fs::metadata(&session_path)
+        .map_err(|e| format!("Failed to read session file: {}", e))?
+        .len();
+    if size > window.max_bytes as u64 {
+        return Err(format!(
+            "Session file is too large to load ({} bytes, limit {})",
+            size, window.max_bytes
+        ));
+    }
+
     let content = fs::read_to_string(&session_path)
         .map_err(|e| format!("Failed to read session file: {}", e))?;
 
-    if let Ok(value) = serde_json::from_str::<Value>(&content) {
-        if let Some(messages) = value.get("messages").and_then(|v| v.as_array()) {
-            return Ok(messages.clone());
+    if let Ok(mut value) = serde_json::from_str::<Value>(&content) {
+        if let Some(messages) = value.get_mut("messages").map(Value::take)
+            && let Value::Array(messages) = messages
+        {
+            return Ok(window_messages(messages, window));
         }
-        if value.is_array() {
-            if let Ok(messages) = serde_json::from_value::<Vec<Value>>(value) {
-                return Ok(messages);
-            }
+        if let Value::Array(messages) = value {
+            return Ok(window_messages(messages, window));
         }
     }
 
-    let messages: Vec<Value> = content
-        .lines()
-        .filter_map(|line| {
-            let line = line.trim();
-            (!line.is_empty()).then(|| serde_json::from_str::<Value>(line).ok())?
-        })
-        .collect();
-
-    Ok(messages)
+    read_jsonl_window(&session_path, window)
 }
 
-pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
+fn find_session_file(session_id: &str) -> Result<PathBuf, String> {
     let tmp_dir = gemini_tmp_dir().map_err(|e| e.to_string())?;
-    let normalized_id = session_id.trim_end_matches(".json").to_string();
 
     for entry in fs::read_dir(&tmp_dir)
         .map_err(|e| format!("Failed to read Gemini tmp directory: {}", e))?
@@ -500,22 +505,32 @@ pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
                 continue;
             }
 
-            if let Some((session_file, _)) = parse_session_file(&path) {
-                if session_file.session_id.as_deref() == Some(normalized_id.as_str()) {
-                    fs::remove_file(&path)
-                        .map_err(|e| format!("Failed to delete session file: {}", e))?;
-                    return Ok(());
-                }
+            if let Some((session_file, _)) = parse_session_file(&path)
+                && session_file.session_id.as_deref() == Some(session_id)
+            {
+                return Ok(path);
             }
         }
     }
 
     Err(format!(
         "Session file not found for session ID: {}",
-        normalized_id
+        session_id
     ))
 }
 
+pub async fn delete_session_by_id(session_id: String) -> Result<(), String> {
+    let normalized_id = session_id.trim_end_matches(".json");
+    let path = find_session_file(normalized_id)?;
+    fs::remove_file(&path).map_err(|e| format!("Failed to delete session file: {}", e))
+}
+
+pub async fn archive_session_by_id(session_id: String) -> Result<(), String> {
+    let normalized_id = session_id.trim_end_matches(".json").to_string();
+    let path = find_session_file(&normalized_id)?;
+    archive::archive_session("gemini", normalized_id, vec![path]).await
+}
+
 pub async fn get_working_directories() -> Result<Vec<WorkingDirectory>, String> {
     let projects = list_projects().await?;
 
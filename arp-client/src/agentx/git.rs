@@ -0,0 +1,217 @@
+//! Git work trees driven through the `git` command, and commits of their
+//! state that leave the checkout alone.
+
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Committer used when the repository has no identity configured
+const FALLBACK_NAME: &str = "arpc";
+const FALLBACK_EMAIL: &str = "arpc@localhost";
+
+/// A git work tree, driven through the `git` command
+pub struct Repo {
+    root: PathBuf,
+}
+
+impl Repo {
+    pub async fn open(path: &Path) -> Result<Self, String> {
+        let repo = Repo {
+            root: path.to_path_buf(),
+        };
+        let root = repo
+            .git(&["rev-parse", "--show-toplevel"])
+            .await
+            .map_err(|_| format!("{} is not a git repository", path.display()))?;
+        Ok(Repo {
+            root: PathBuf::from(root),
+        })
+    }
+
+    pub async fn git(&self, args: &[&str]) -> Result<String, String> {
+        self.git_with_env(args, &[]).await
+    }
+
+    pub async fn git_with_env(
+        &self,
+        args: &[&str],
+        env: &[(&str, String)],
+    ) -> Result<String, String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.root)
+            .args(args)
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run git: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "git {} failed: {}",
+                args.first().copied().unwrap_or_default(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// A commit holding the work tree as it is now: `HEAD` itself when
+    /// nothing is uncommitted, otherwise a new commit on top of it
+    pub async fn snapshot(&self, message: &str) -> Result<String, String> {
+        let head = self
+            .git(&["rev-parse", "HEAD"])
+            .await
+            .map_err(|_| "The repository has no commits yet".to_string())?;
+        let index =
+            std::env::temp_dir().join(format!("arpc-snapshot-{}.index", uuid::Uuid::new_v4()));
+        let env = [("GIT_INDEX_FILE", index.to_string_lossy().into_owned())];
+        let tree = async {
+            self.git_with_env(&["read-tree", "HEAD"], &env).await?;
+            self.git_with_env(&["add", "--all"], &env).await?;
+            self.git_with_env(&["write-tree"], &env).await
+        }
+        .await;
+        let _ = std::fs::remove_file(&index);
+        let tree = tree?;
+
+        if tree == self.git(&["rev-parse", "HEAD^{tree}"]).await? {
+            return Ok(head);
+        }
+
+        let mut identity = Vec::new();
+        if self.git(&["config", "user.name"]).await.is_err() {
+            identity.push(("GIT_AUTHOR_NAME", FALLBACK_NAME.to_string()));
+            identity.push(("GIT_COMMITTER_NAME", FALLBACK_NAME.to_string()));
+        }
+        if self.git(&["config", "user.email"]).await.is_err() {
+            identity.push(("GIT_AUTHOR_EMAIL", FALLBACK_EMAIL.to_string()));
+            identity.push(("GIT_COMMITTER_EMAIL", FALLBACK_EMAIL.to_string()));
+        }
+        self.git_with_env(
+            &["commit-tree", &tree, "-p", &head, "-m", message],
+            &identity,
+        )
+        .await
+    }
+
+    /// Bring the work tree from the snapshot `current` back to `commit`:
+    /// files `commit` doesn't have are deleted and the others it differs in
+    /// are rewritten. Ignored files, `HEAD` and the real index are left
+    /// alone. Returns how many files were rewritten and deleted.
+    pub async fn restore(&self, commit: &str, current: &str) -> Result<(usize, usize), String> {
+        let added = self.changed_paths(commit, current, "A").await?;
+        for path in &added {
+            match std::fs::remove_file(self.root.join(path)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("Failed to delete {}: {}", path, e));
+                }
+                _ => {}
+            }
+        }
+
+        let rewritten = self.changed_paths(commit, current, "DMT").await?;
+        if !rewritten.is_empty() {
+            let index =
+                std::env::temp_dir().join(format!("arpc-restore-{}.index", uuid::Uuid::new_v4()));
+            let env = [("GIT_INDEX_FILE", index.to_string_lossy().into_owned())];
+            let checkout = async {
+                self.git_with_env(&["read-tree", commit], &env).await?;
+                for paths in rewritten.chunks(256) {
+                    let mut args = vec!["checkout-index", "-f", "--"];
+                    args.extend(paths.iter().map(String::as_str));
+                    self.git_with_env(&args, &env).await?;
+                }
+                Ok::<_, String>(())
+            }
+            .await;
+            let _ = std::fs::remove_file(&index);
+            checkout?;
+        }
+        Ok((rewritten.len(), added.len()))
+    }
+
+    /// Paths whose change from `from` to `to` is one of `filter`
+    /// (`--diff-filter` letters).
+    async fn changed_paths(
+        &self,
+        from: &str,
+        to: &str,
+        filter: &str,
+    ) -> Result<Vec<String>, String> {
+        let filter = format!("--diff-filter={}", filter);
+        let paths = self
+            .git(&[
+                "diff",
+                "--name-only",
+                "-z",
+                "--no-renames",
+                &filter,
+                from,
+                to,
+            ])
+            .await?;
+        Ok(paths
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn snapshot_commits_changes_without_touching_the_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        run(&["init", "-q"]);
+        std::fs::write(dir.path().join("a.txt"), "one").unwrap();
+        run(&["add", "a.txt"]);
+        run(&[
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "-qm",
+            "init",
+        ]);
+
+        let repo = Repo::open(dir.path()).await.unwrap();
+        let head = repo.git(&["rev-parse", "HEAD"]).await.unwrap();
+        assert_eq!(repo.snapshot("nothing").await.unwrap(), head);
+
+        std::fs::write(dir.path().join("a.txt"), "two").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "new").unwrap();
+        let commit = repo.snapshot("agent work").await.unwrap();
+        assert_ne!(commit, head);
+        assert_eq!(
+            repo.git(&["rev-parse", &format!("{}^", commit)])
+                .await
+                .unwrap(),
+            head
+        );
+        assert_eq!(
+            repo.git(&["show", &format!("{}:b.txt", commit)])
+                .await
+                .unwrap(),
+            "new"
+        );
+        // HEAD and the real index are unchanged
+        assert_eq!(repo.git(&["rev-parse", "HEAD"]).await.unwrap(), head);
+        assert_eq!(
+            repo.git(&["status", "--porcelain"]).await.unwrap(),
+            "M a.txt\n?? b.txt"
+        );
+    }
+}
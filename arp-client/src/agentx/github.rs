@@ -0,0 +1,236 @@
+use super::git::Repo;
+use base64::Engine;
+use serde_json::{Value, json};
+use std::path::Path;
+use std::time::Duration;
+
+/// Environment variable (and keychain entry) the GitHub token is read from
+pub const TOKEN_ENV: &str = "GITHUB_TOKEN";
+
+/// How long one GitHub API request may take
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What to open a pull request with
+pub struct PullRequestOptions {
+    pub title: String,
+    pub body: String,
+    /// Branch the session's changes are pushed to
+    pub branch: String,
+    /// Branch to merge into; the repository's default branch when unset
+    pub base: Option<String>,
+    pub remote: String,
+    pub draft: bool,
+}
+
+/// An opened pull request
+pub struct PullRequest {
+    pub url: String,
+    pub number: u64,
+    pub branch: String,
+    pub base: String,
+    /// The commit pushed to `branch`
+    pub commit: String,
+}
+
+/// The token from `--github-token`, `GITHUB_TOKEN` or the keychain, in that order
+pub fn token(configured: Option<&str>) -> Result<Option<String>, String> {
+    if let Some(token) = configured.filter(|token| !token.is_empty()) {
+        return Ok(Some(token.to_string()));
+    }
+    if let Ok(token) = std::env::var(TOKEN_ENV) {
+        return Ok(Some(token));
+    }
+    #[cfg(feature = "keyring")]
+    if let Some(token) = crate::credentials::get(TOKEN_ENV)? {
+        return Ok(Some(token));
+    }
+    Ok(None)
+}
+
+/// Push what the session left in `project_path` to a new branch and open a
+/// pull request for it.
+///
+/// Uncommitted changes are committed on top of `HEAD` through a scratch
+/// index, so the working tree, the real index and the checked-out branch
+/// are left as they are; commits the agent made itself are included as
+/// ancestors of that commit.
+pub async fn open_pull_request(
+    project_path: &Path,
+    token: &str,
+    api_url: &str,
+    options: PullRequestOptions,
+) -> Result<PullRequest, String> {
+    let repo = Repo::open(project_path).await?;
+    // As configured, before any `url.*.insteadOf` rewriting
+    let remote_url = repo
+        .git(&["config", "--get", &format!("remote.{}.url", options.remote)])
+        .await
+        .map_err(|_| format!("No remote named {}", options.remote))?;
+    let (owner, name) = github_repo(&remote_url)
+        .ok_or_else(|| format!("Remote {} is not a GitHub repository", options.remote))?;
+
+    let commit = repo.snapshot(&options.title).await?;
+    repo.push(
+        &options.remote,
+        &remote_url,
+        token,
+        &commit,
+        &options.branch,
+    )
+    .await?;
+
+    let http = reqwest::Client::builder()
+        .timeout(API_TIMEOUT)
+        .user_agent(concat!("arpc/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let repo_api = format!("{}/repos/{}/{}", api_url.trim_end_matches('/'), owner, name);
+    let base = match options.base {
+        Some(base) => base,
+        None => github_request(http.get(&repo_api), token)
+            .await?
+            .get("default_branch")
+            .and_then(Value::as_str)
+            .ok_or("GitHub didn't report a default branch")?
+            .to_string(),
+    };
+
+    let created = github_request(
+        http.post(format!("{}/pulls", repo_api)).json(&json!({
+            "title": options.title,
+            "body": options.body,
+            "head": options.branch,
+            "base": base,
+            "draft": options.draft,
+        })),
+        token,
+    )
+    .await?;
+
+    Ok(PullRequest {
+        url: created
+            .get("html_url")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        number: created
+            .get("number")
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+        branch: options.branch,
+        base,
+        commit,
+    })
+}
+
+async fn github_request(request: reqwest::RequestBuilder, token: &str) -> Result<Value, String> {
+    let response = request
+        .bearer_auth(token)
+        .header("Accept", "application/vnd.github+json")
+        .send()
+        .await
+        .map_err(|e| format!("GitHub request failed: {}", e))?;
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or(Value::Null);
+    if !status.is_success() {
+        let message = body
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("no details");
+        let details: Vec<&str> = body
+            .get("errors")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|error| error.get("message").and_then(Value::as_str))
+            .collect();
+        return Err(if details.is_empty() {
+            format!("GitHub returned {}: {}", status, message)
+        } else {
+            format!(
+                "GitHub returned {}: {} ({})",
+                status,
+                message,
+                details.join("; ")
+            )
+        });
+    }
+    Ok(body)
+}
+
+/// `owner` and `name` of a GitHub remote, from its SSH or HTTPS URL
+pub fn github_repo(remote_url: &str) -> Option<(String, String)> {
+    let path = remote_url
+        .strip_prefix("git@")
+        .and_then(|rest| rest.split_once(':'))
+        .map(|(_, path)| path)
+        .or_else(|| {
+            let (_, rest) = remote_url.split_once("://")?;
+            rest.split_once('/').map(|(_, path)| path)
+        })?;
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, name) = path.split_once('/')?;
+    (!owner.is_empty() && !name.is_empty() && !name.contains('/'))
+        .then(|| (owner.to_string(), name.to_string()))
+}
+
+impl Repo {
+    /// Push `commit` to `branch` on `remote`. HTTPS remotes authenticate with
+    /// the token, passed in the environment so it never shows up in the
+    /// process list; SSH remotes use the machine's keys.
+    async fn push(
+        &self,
+        remote: &str,
+        remote_url: &str,
+        token: &str,
+        commit: &str,
+        branch: &str,
+    ) -> Result<(), String> {
+        let mut env = Vec::new();
+        if let Some((scheme, rest)) = remote_url.split_once("://")
+            && scheme.starts_with("http")
+        {
+            let host = rest.split('/').next().unwrap_or_default();
+            let host = host.rsplit('@').next().unwrap_or(host);
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("x-access-token:{}", token));
+            env.push(("GIT_CONFIG_COUNT", "1".to_string()));
+            env.push((
+                "GIT_CONFIG_KEY_0",
+                format!("http.{}://{}/.extraheader", scheme, host),
+            ));
+            env.push((
+                "GIT_CONFIG_VALUE_0",
+                format!("AUTHORIZATION: basic {}", credentials),
+            ));
+        }
+        let refspec = format!("{}:refs/heads/{}", commit, branch);
+        self.git_with_env(&["push", remote, &refspec], &env)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_remotes_name_their_repository() {
+        let expected = Some(("sxhxliang".to_string(), "agentx_proxy".to_string()));
+        assert_eq!(
+            github_repo("git@github.com:sxhxliang/agentx_proxy.git"),
+            expected
+        );
+        assert_eq!(
+            github_repo("https://github.com/sxhxliang/agentx_proxy"),
+            expected
+        );
+        assert_eq!(
+            github_repo("https://user@github.com/sxhxliang/agentx_proxy.git/"),
+            expected
+        );
+        assert_eq!(github_repo("/srv/git/project.git"), None);
+    }
+}
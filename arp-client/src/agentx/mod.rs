@@ -1,9 +1,20 @@
+pub mod archive;
+pub mod cache;
 pub mod claude;
 pub mod claude_routes;
 pub mod codex;
 pub mod codex_routes;
+pub mod encryption;
 pub mod gemini;
 pub mod gemini_routes;
+pub mod git;
+#[cfg(feature = "github")]
+pub mod github;
+pub mod project_meta;
+pub mod projects;
 pub mod routes_common;
+pub mod snapshots;
+pub mod storage;
+pub mod tools;
 pub mod types;
 pub mod utils;
@@ -0,0 +1,188 @@
+use crate::agentx::types::{Project, ProjectMeta, WorkingDirectory};
+use crate::agentx::utils::run_blocking;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// agent -> project path -> settings
+type MetaFile = BTreeMap<String, BTreeMap<String, ProjectMeta>>;
+
+/// Serializes read-modify-write cycles on the store file
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Partial update sent by the UI; an empty `display_name` clears it
+#[derive(Debug, Deserialize)]
+pub struct ProjectMetaUpdate {
+    pub path: String,
+    pub pinned: Option<bool>,
+    pub display_name: Option<String>,
+    pub hidden: Option<bool>,
+}
+
+fn store_path() -> Result<PathBuf, String> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("arpc/project_meta.json"))
+        .ok_or_else(|| "Could not determine local data directory".to_string())
+}
+
+fn read_store(path: &Path) -> MetaFile {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_store(path: &Path, store: &MetaFile) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let tmp = path.with_extension(format!("json.{}", std::process::id()));
+    let content = serde_json::to_vec_pretty(store).map_err(|e| e.to_string())?;
+    fs::write(&tmp, content).map_err(|e| e.to_string())?;
+    fs::rename(&tmp, path).map_err(|e| format!("Failed to save project metadata: {}", e))
+}
+
+fn update_store(
+    path: &Path,
+    agent: &str,
+    update: ProjectMetaUpdate,
+) -> Result<ProjectMeta, String> {
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut store = read_store(path);
+    let projects = store.entry(agent.to_string()).or_default();
+    let meta = projects.entry(update.path.clone()).or_default();
+
+    if let Some(pinned) = update.pinned {
+        meta.pinned = pinned;
+    }
+    if let Some(hidden) = update.hidden {
+        meta.hidden = hidden;
+    }
+    if let Some(display_name) = update.display_name {
+        let display_name = display_name.trim();
+        meta.display_name = (!display_name.is_empty()).then(|| display_name.to_string());
+    }
+
+    let meta = meta.clone();
+    if meta == ProjectMeta::default() {
+        projects.remove(&update.path);
+    }
+    write_store(path, &store)?;
+    Ok(meta)
+}
+
+/// Settings for every project of `agent`
+pub async fn load(agent: &'static str) -> HashMap<String, ProjectMeta> {
+    let Ok(path) = store_path() else {
+        return HashMap::new();
+    };
+    run_blocking(move || {
+        read_store(&path)
+            .remove(agent)
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Apply `update` to one project of `agent` and return its new settings
+pub async fn update(agent: &'static str, update: ProjectMetaUpdate) -> Result<ProjectMeta, String> {
+    if update.path.trim().is_empty() {
+        return Err("path is required".to_string());
+    }
+    let path = store_path()?;
+    run_blocking(move || update_store(&path, agent, update)).await?
+}
+
+/// Attach settings, drop hidden projects unless asked for and put pinned
+/// projects first, keeping the existing order otherwise
+pub fn apply_to_projects(
+    projects: &mut Vec<Project>,
+    meta: &HashMap<String, ProjectMeta>,
+    include_hidden: bool,
+) {
+    for project in projects.iter_mut() {
+        if let Some(meta) = meta.get(&project.path) {
+            project.meta = meta.clone();
+        }
+    }
+    projects.retain(|project| include_hidden || !project.meta.hidden);
+    projects.sort_by_key(|project| !project.meta.pinned);
+}
+
+/// Same as [`apply_to_projects`], with the display name used as short name
+pub fn apply_to_directories(
+    directories: &mut Vec<WorkingDirectory>,
+    meta: &HashMap<String, ProjectMeta>,
+) {
+    directories.retain(|dir| !meta.get(&dir.path).is_some_and(|meta| meta.hidden));
+    for dir in directories.iter_mut() {
+        if let Some(name) = meta
+            .get(&dir.path)
+            .and_then(|meta| meta.display_name.clone())
+        {
+            dir.short_name = name;
+        }
+    }
+    directories.sort_by_key(|dir| !meta.get(&dir.path).is_some_and(|meta| meta.pinned));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(path: &str) -> Project {
+        Project {
+            id: path.trim_start_matches('/').to_string(),
+            path: path.to_string(),
+            sessions: Vec::new(),
+            created_at: 0,
+            most_recent_session: None,
+            meta: ProjectMeta::default(),
+        }
+    }
+
+    #[test]
+    fn pinned_first_and_hidden_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("project_meta.json");
+        let set = |path: &str, pinned, hidden, name: Option<&str>| {
+            update_store(
+                &store,
+                "claude",
+                ProjectMetaUpdate {
+                    path: path.to_string(),
+                    pinned,
+                    hidden,
+                    display_name: name.map(String::from),
+                },
+            )
+            .unwrap()
+        };
+        set("/c", Some(true), None, Some("Main"));
+        set("/b", None, Some(true), None);
+        assert!(set("/a", Some(true), None, None).pinned);
+        assert_eq!(set("/a", Some(false), None, None), ProjectMeta::default());
+
+        let meta: HashMap<_, _> = read_store(&store)
+            .remove("claude")
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert!(!meta.contains_key("/a"));
+
+        let mut projects = vec![project("/a"), project("/b"), project("/c")];
+        apply_to_projects(&mut projects, &meta, false);
+        let paths: Vec<_> = projects.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["/c", "/a"]);
+        assert_eq!(projects[0].meta.display_name.as_deref(), Some("Main"));
+
+        let mut projects = vec![project("/a"), project("/b")];
+        apply_to_projects(&mut projects, &meta, true);
+        assert!(projects[1].meta.hidden);
+    }
+}
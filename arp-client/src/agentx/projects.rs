@@ -0,0 +1,121 @@
+use crate::agentx::cache::ListingCache;
+use crate::agentx::types::{ExecutorProject, Project, UnifiedProject};
+use crate::agentx::{claude, codex, gemini, project_meta};
+use std::collections::BTreeMap;
+
+/// Projects of every executor merged by working directory.
+///
+/// Hidden projects are left out; a path counts as pinned if any executor
+/// pinned it. Executors whose history can't be read are skipped.
+pub async fn list_unified_projects(cache: &ListingCache) -> Vec<UnifiedProject> {
+    let (claude, codex, gemini) = tokio::join!(
+        with_meta("claude", cache.projects("claude", claude::list_projects())),
+        with_meta("codex", cache.projects("codex", codex::list_projects())),
+        with_meta("gemini", cache.projects("gemini", gemini::list_projects())),
+    );
+    merge_projects([("claude", claude), ("codex", codex), ("gemini", gemini)])
+}
+
+async fn with_meta(
+    agent: &'static str,
+    projects: impl Future<Output = Result<Vec<Project>, String>>,
+) -> Vec<Project> {
+    match projects.await {
+        Ok(mut projects) => {
+            let meta = project_meta::load(agent).await;
+            project_meta::apply_to_projects(&mut projects, &meta, false);
+            projects
+        }
+        Err(e) => {
+            tracing::debug!("Skipping {} projects: {}", agent, e);
+            Vec::new()
+        }
+    }
+}
+
+fn merge_projects<const N: usize>(
+    executors: [(&'static str, Vec<Project>); N],
+) -> Vec<UnifiedProject> {
+    let mut merged: BTreeMap<String, UnifiedProject> = BTreeMap::new();
+
+    for (executor, projects) in executors {
+        for project in projects {
+            let path = project.path.trim_end_matches('/');
+            let path = if path.is_empty() { "/" } else { path };
+            let unified = merged
+                .entry(path.to_string())
+                .or_insert_with(|| UnifiedProject {
+                    path: path.to_string(),
+                    executors: Vec::new(),
+                    session_count: 0,
+                    created_at: project.created_at,
+                    most_recent_session: None,
+                    pinned: false,
+                    display_name: None,
+                });
+
+            unified.session_count += project.sessions.len();
+            unified.created_at = unified.created_at.min(project.created_at);
+            unified.most_recent_session =
+                unified.most_recent_session.max(project.most_recent_session);
+            unified.pinned |= project.meta.pinned;
+            if unified.display_name.is_none() {
+                unified.display_name = project.meta.display_name;
+            }
+            unified.executors.push(ExecutorProject {
+                executor: executor.to_string(),
+                project_id: project.id,
+                session_count: project.sessions.len(),
+                most_recent_session: project.most_recent_session,
+            });
+        }
+    }
+
+    let mut projects: Vec<UnifiedProject> = merged.into_values().collect();
+    projects.sort_by(|a, b| {
+        b.pinned
+            .cmp(&a.pinned)
+            .then(b.most_recent_session.cmp(&a.most_recent_session))
+            .then(b.created_at.cmp(&a.created_at))
+    });
+    projects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agentx::types::ProjectMeta;
+
+    fn project(id: &str, path: &str, sessions: usize, recent: u64) -> Project {
+        Project {
+            id: id.to_string(),
+            path: path.to_string(),
+            sessions: (0..sessions).map(|n| n.to_string()).collect(),
+            created_at: recent,
+            most_recent_session: Some(recent),
+            meta: ProjectMeta::default(),
+        }
+    }
+
+    #[test]
+    fn projects_merge_by_path() {
+        let mut pinned = project("g1", "/work/app", 1, 5);
+        pinned.meta.pinned = true;
+        let merged = merge_projects([
+            ("claude", vec![project("-work-api", "/work/api", 2, 30)]),
+            ("codex", vec![project("c1", "/work/api/", 3, 40)]),
+            ("gemini", vec![pinned]),
+        ]);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].path, "/work/app");
+        assert!(merged[0].pinned);
+
+        let api = &merged[1];
+        assert_eq!(api.session_count, 5);
+        assert_eq!(api.most_recent_session, Some(40));
+        assert_eq!(api.created_at, 30);
+        let executors: Vec<_> = api.executors.iter().map(|e| e.executor.as_str()).collect();
+        assert_eq!(executors, ["claude", "codex"]);
+    }
+}
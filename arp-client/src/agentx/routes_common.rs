@@ -1,12 +1,19 @@
-use crate::agentx::types::{Project, Session, WorkingDirectory};
+use crate::agentx::archive;
+use crate::agentx::project_meta::{self, ProjectMetaUpdate};
+use crate::agentx::projects;
+use crate::agentx::types::{HistoryPage, HistoryWindow, Project, Session, WorkingDirectory};
+use crate::agentx::utils::parse_age;
+use crate::handlers::HandlerState;
 use crate::router::RouterBuilder;
 use common::http;
-use serde_json::{Value, json};
+use serde_json::json;
 use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn register_project_routes<ListProjectsFn, ListProjectsFut, WorkingDirsFn, WorkingDirsFut>(
     router_builder: &mut RouterBuilder,
     agent_name: &'static str,
+    state: &HandlerState,
     list_projects: ListProjectsFn,
     get_working_directories: WorkingDirsFn,
 ) where
@@ -15,23 +22,34 @@ pub fn register_project_routes<ListProjectsFn, ListProjectsFut, WorkingDirsFn, W
     WorkingDirsFn: Fn() -> WorkingDirsFut + Send + Sync + 'static + Copy,
     WorkingDirsFut: Future<Output = Result<Vec<WorkingDirectory>, String>> + Send + 'static,
 {
-    router_builder.get(format!("/api/{}/projects", agent_name), move |ctx| {
-        let list_projects_fn = list_projects;
-        async move {
-            let mut stream = ctx.stream;
-            match list_projects_fn().await {
-                Ok(projects) => {
-                    let body = json!({
-                        "type": "projects",
-                        "projects": projects
-                    });
-                    let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
-                }
-                Err(e) => {
-                    let _ = http::json_error(500, e).send(&mut stream).await;
+    let cache = state.listing_cache.clone();
+    router_builder.get(format!("/api/{}/projects", agent_name), {
+        let cache = cache.clone();
+        move |ctx| {
+            let list_projects_fn = list_projects;
+            let cache = cache.clone();
+            async move {
+                let include_hidden = ctx
+                    .request
+                    .query_param("includeHidden")
+                    .is_some_and(|v| v == "true" || v == "1");
+                let mut stream = ctx.stream;
+                match cache.projects(agent_name, list_projects_fn()).await {
+                    Ok(mut projects) => {
+                        let meta = project_meta::load(agent_name).await;
+                        project_meta::apply_to_projects(&mut projects, &meta, include_hidden);
+                        let body = json!({
+                            "type": "projects",
+                            "projects": projects
+                        });
+                        let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                    }
+                    Err(e) => {
+                        let _ = http::json_error(500, e).send(&mut stream).await;
+                    }
                 }
+                Ok(http::HttpResponse::ok())
             }
-            Ok(http::HttpResponse::ok())
         }
     });
 
@@ -39,10 +57,16 @@ pub fn register_project_routes<ListProjectsFn, ListProjectsFut, WorkingDirsFn, W
         format!("/api/{}/projects/working-directories", agent_name),
         move |ctx| {
             let get_working_directories_fn = get_working_directories;
+            let cache = cache.clone();
             async move {
                 let mut stream = ctx.stream;
-                match get_working_directories_fn().await {
-                    Ok(directories) => {
+                match cache
+                    .working_directories(agent_name, get_working_directories_fn())
+                    .await
+                {
+                    Ok(mut directories) => {
+                        let meta = project_meta::load(agent_name).await;
+                        project_meta::apply_to_directories(&mut directories, &meta);
                         let body = json!({
                             "directories": directories
                         });
@@ -56,6 +80,62 @@ pub fn register_project_routes<ListProjectsFn, ListProjectsFut, WorkingDirsFn, W
             }
         },
     );
+
+    router_builder.post(
+        format!("/api/{}/projects/meta", agent_name),
+        move |ctx| async move {
+            let mut stream = ctx.stream;
+            let update = match ctx
+                .request
+                .body_as_json()
+                .ok()
+                .and_then(|body| serde_json::from_value::<ProjectMetaUpdate>(body).ok())
+            {
+                Some(update) => update,
+                None => {
+                    let _ = http::json_error(400, "Invalid project metadata body")
+                        .send(&mut stream)
+                        .await;
+                    return Ok(http::HttpResponse::ok());
+                }
+            };
+
+            let path = update.path.clone();
+            match project_meta::update(agent_name, update).await {
+                Ok(meta) => {
+                    let body = json!({
+                        "type": "project_meta",
+                        "path": path,
+                        "meta": meta
+                    });
+                    let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                }
+                Err(e) => {
+                    let status = if e.contains("required") { 400 } else { 500 };
+                    let _ = http::json_error(status, e).send(&mut stream).await;
+                }
+            }
+            Ok(http::HttpResponse::ok())
+        },
+    );
+}
+
+/// `GET /api/projects`: projects of all executors merged by working directory
+pub fn register_unified_project_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
+    let cache = state.listing_cache.clone();
+    router_builder.get("/api/projects", move |ctx| {
+        let cache = cache.clone();
+        async move {
+            let mut stream = ctx.stream;
+            let projects = projects::list_unified_projects(&cache).await;
+            let body = json!({
+                "type": "projects",
+                "projects": projects
+            });
+            let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+            Ok(http::HttpResponse::ok())
+        }
+    });
 }
 
 pub fn register_session_routes<
@@ -65,12 +145,16 @@ pub fn register_session_routes<
     LoadSessionFut,
     DeleteSessionFn,
     DeleteSessionFut,
+    ArchiveSessionFn,
+    ArchiveSessionFut,
 >(
     router_builder: &mut RouterBuilder,
     agent_name: &'static str,
+    state: &HandlerState,
     get_all_sessions: GetSessionsFn,
     load_session_by_id: LoadSessionFn,
     delete_session_by_id: DeleteSessionFn,
+    archive_session_by_id: ArchiveSessionFn,
 ) where
     GetSessionsFn: Fn(Option<usize>, Option<usize>, Option<String>) -> GetSessionsFut
         + Send
@@ -78,38 +162,134 @@ pub fn register_session_routes<
         + 'static
         + Copy,
     GetSessionsFut: Future<Output = Result<Vec<Session>, String>> + Send + 'static,
-    LoadSessionFn: Fn(String) -> LoadSessionFut + Send + Sync + 'static + Copy,
-    LoadSessionFut: Future<Output = Result<Vec<Value>, String>> + Send + 'static,
+    LoadSessionFn: Fn(String, HistoryWindow) -> LoadSessionFut + Send + Sync + 'static + Copy,
+    LoadSessionFut: Future<Output = Result<HistoryPage, String>> + Send + 'static,
     DeleteSessionFn: Fn(String) -> DeleteSessionFut + Send + Sync + 'static + Copy,
     DeleteSessionFut: Future<Output = Result<(), String>> + Send + 'static,
+    ArchiveSessionFn: Fn(String) -> ArchiveSessionFut + Send + Sync + 'static + Copy,
+    ArchiveSessionFut: Future<Output = Result<(), String>> + Send + 'static,
 {
-    router_builder.get(format!("/api/{}/sessions", agent_name), move |ctx| {
-        let get_all_sessions_fn = get_all_sessions;
-        async move {
-            let limit = ctx
-                .request
-                .query_param("limit")
-                .and_then(|v| v.parse::<usize>().ok());
-            let offset = ctx
-                .request
-                .query_param("offset")
-                .and_then(|v| v.parse::<usize>().ok());
-            let project_path = ctx.request.query_param("projectPath").cloned();
+    let redactor = state.session_manager.redactor().clone();
+    let cache = state.listing_cache.clone();
+    router_builder.get(format!("/api/{}/sessions", agent_name), {
+        let cache = cache.clone();
+        move |ctx| {
+            let get_all_sessions_fn = get_all_sessions;
+            let cache = cache.clone();
+            async move {
+                let limit = ctx
+                    .request
+                    .query_param("limit")
+                    .and_then(|v| v.parse::<usize>().ok());
+                let offset = ctx
+                    .request
+                    .query_param("offset")
+                    .and_then(|v| v.parse::<usize>().ok());
+                let project_path = ctx.request.query_param("projectPath").cloned();
 
-            let mut stream = ctx.stream;
-            match get_all_sessions_fn(limit, offset, project_path).await {
-                Ok(sessions) => {
-                    let body = json!({
-                        "type": "sessions",
-                        "sessions": sessions
-                    });
-                    let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                let mut stream = ctx.stream;
+                let sessions = cache.sessions(
+                    agent_name,
+                    limit,
+                    offset,
+                    project_path.clone(),
+                    get_all_sessions_fn(limit, offset, project_path),
+                );
+                match sessions.await {
+                    Ok(sessions) => {
+                        let body = json!({
+                            "type": "sessions",
+                            "sessions": sessions
+                        });
+                        let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                    }
+                    Err(e) => {
+                        let _ = http::json_error(500, e).send(&mut stream).await;
+                    }
                 }
-                Err(e) => {
-                    let _ = http::json_error(500, e).send(&mut stream).await;
+                Ok(http::HttpResponse::ok())
+            }
+        }
+    });
+
+    router_builder.delete(format!("/api/{}/sessions", agent_name), {
+        let cache = cache.clone();
+        move |ctx| {
+            let get_all_sessions_fn = get_all_sessions;
+            let delete_session_by_id_fn = delete_session_by_id;
+            let cache = cache.clone();
+            async move {
+                let mut stream = ctx.stream;
+                let older_than = match ctx.request.query_param("olderThan") {
+                    Some(value) => match parse_age(value) {
+                        Some(age) => Some(age),
+                        None => {
+                            let _ = http::json_error(
+                                400,
+                                format!("Invalid olderThan '{}', expected e.g. 30d or 12h", value),
+                            )
+                            .send(&mut stream)
+                            .await;
+                            return Ok(http::HttpResponse::ok());
+                        }
+                    },
+                    None => None,
+                };
+                let project_path = ctx.request.query_param("projectPath").cloned();
+                if older_than.is_none() && project_path.is_none() {
+                    let _ = http::json_error(400, "olderThan or projectPath is required")
+                        .send(&mut stream)
+                        .await;
+                    return Ok(http::HttpResponse::ok());
+                }
+                let dry_run = ctx
+                    .request
+                    .query_param("dryRun")
+                    .is_some_and(|v| v == "true" || v == "1");
+
+                let sessions = match get_all_sessions_fn(None, None, project_path).await {
+                    Ok(sessions) => sessions,
+                    Err(e) => {
+                        let _ = http::json_error(500, e).send(&mut stream).await;
+                        return Ok(http::HttpResponse::ok());
+                    }
+                };
+
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let matched: Vec<Session> = sessions
+                    .into_iter()
+                    .filter(|session| {
+                        older_than.is_none_or(|age| session.created_at.saturating_add(age) <= now)
+                    })
+                    .collect();
+
+                let mut deleted = Vec::new();
+                let mut failed = Vec::new();
+                if !dry_run {
+                    for session in &matched {
+                        match delete_session_by_id_fn(session.id.clone()).await {
+                            Ok(_) => deleted.push(session.id.clone()),
+                            Err(e) => failed.push(json!({"session_id": session.id, "error": e})),
+                        }
+                    }
+                    if !deleted.is_empty() {
+                        cache.invalidate(agent_name).await;
+                    }
                 }
+
+                let body = json!({
+                    "type": "sessions_deleted",
+                    "dry_run": dry_run,
+                    "sessions": matched,
+                    "deleted": deleted,
+                    "failed": failed
+                });
+                let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                Ok(http::HttpResponse::ok())
             }
-            Ok(http::HttpResponse::ok())
         }
     });
 
@@ -117,6 +297,7 @@ pub fn register_session_routes<
         format!("/api/{}/sessions/{{session_id}}", agent_name),
         move |ctx| {
             let load_session_by_id_fn = load_session_by_id;
+            let redactor = redactor.clone();
             async move {
                 let Some(session_id) = ctx
                     .path_params
@@ -131,13 +312,16 @@ pub fn register_session_routes<
                     return Ok(http::HttpResponse::ok());
                 };
 
+                let window = HistoryWindow::from_request(&ctx.request);
                 let mut stream = ctx.stream;
-                match load_session_by_id_fn(session_id.clone()).await {
-                    Ok(messages) => {
+                match load_session_by_id_fn(session_id.clone(), window).await {
+                    Ok(page) => {
                         let body = json!({
                             "type": "session_history",
                             "session_id": session_id,
-                            "messages": messages
+                            "from": page.from,
+                            "next_from": page.next_from,
+                            "messages": redactor.apply_all(page.messages)
                         });
                         let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
                     }
@@ -150,10 +334,11 @@ pub fn register_session_routes<
         },
     );
 
-    router_builder.delete(
-        format!("/api/{}/sessions/{{session_id}}", agent_name),
+    router_builder.delete(format!("/api/{}/sessions/{{session_id}}", agent_name), {
+        let cache = cache.clone();
         move |ctx| {
             let delete_session_by_id_fn = delete_session_by_id;
+            let cache = cache.clone();
             async move {
                 let Some(session_id) = ctx
                     .path_params
@@ -171,6 +356,7 @@ pub fn register_session_routes<
                 let mut stream = ctx.stream;
                 match delete_session_by_id_fn(session_id.clone()).await {
                     Ok(_) => {
+                        cache.invalidate(agent_name).await;
                         let body = json!({
                             "type": "session_deleted",
                             "session_id": session_id
@@ -178,8 +364,89 @@ pub fn register_session_routes<
                         let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
                     }
                     Err(e) => {
-                        let status = if e.contains("not found") { 404 } else { 500 };
-                        let _ = http::json_error(status, e).send(&mut stream).await;
+                        let _ = http::json_error(error_status(&e), e)
+                            .send(&mut stream)
+                            .await;
+                    }
+                }
+                Ok(http::HttpResponse::ok())
+            }
+        }
+    });
+
+    router_builder.post(
+        format!("/api/{}/sessions/{{session_id}}/archive", agent_name),
+        {
+            let cache = cache.clone();
+            move |ctx| {
+                let archive_session_by_id_fn = archive_session_by_id;
+                let cache = cache.clone();
+                async move {
+                    let mut stream = ctx.stream;
+                    let Some(session_id) = ctx
+                        .path_params
+                        .get("session_id")
+                        .filter(|v| !v.is_empty())
+                        .cloned()
+                    else {
+                        let _ = http::json_error(400, "session_id is required")
+                            .send(&mut stream)
+                            .await;
+                        return Ok(http::HttpResponse::ok());
+                    };
+
+                    match archive_session_by_id_fn(session_id.clone()).await {
+                        Ok(_) => {
+                            cache.invalidate(agent_name).await;
+                            let body = json!({
+                                "type": "session_archived",
+                                "session_id": session_id
+                            });
+                            let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                        }
+                        Err(e) => {
+                            let _ = http::json_error(error_status(&e), e)
+                                .send(&mut stream)
+                                .await;
+                        }
+                    }
+                    Ok(http::HttpResponse::ok())
+                }
+            }
+        },
+    );
+
+    router_builder.post(
+        format!("/api/{}/sessions/{{session_id}}/restore", agent_name),
+        move |ctx| {
+            let cache = cache.clone();
+            async move {
+                let mut stream = ctx.stream;
+                let Some(session_id) = ctx
+                    .path_params
+                    .get("session_id")
+                    .filter(|v| !v.is_empty())
+                    .cloned()
+                else {
+                    let _ = http::json_error(400, "session_id is required")
+                        .send(&mut stream)
+                        .await;
+                    return Ok(http::HttpResponse::ok());
+                };
+
+                match archive::restore_session(agent_name, session_id.clone()).await {
+                    Ok(_) => {
+                        cache.invalidate(agent_name).await;
+                        let body = json!({
+                            "type": "session_restored",
+                            "session_id": session_id
+                        });
+                        let _ = http::HttpResponse::ok().json(&body).send(&mut stream).await;
+                    }
+                    Err(e) => {
+                        let _ = http::json_error(error_status(&e), e)
+                            .send(&mut stream)
+                            .await;
                     }
                 }
                 Ok(http::HttpResponse::ok())
@@ -187,3 +454,16 @@ pub fn register_session_routes<
         },
     );
 }
+
+/// HTTP status for an agent session operation error
+fn error_status(error: &str) -> u16 {
+    if error.contains("not found") {
+        404
+    } else if error.contains("already") {
+        409
+    } else if error.starts_with("Invalid session ID") {
+        400
+    } else {
+        500
+    }
+}
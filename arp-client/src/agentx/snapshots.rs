@@ -0,0 +1,153 @@
+//! Snapshots of a session's project with `--snapshots`: commits of the work
+//! tree taken before the executor starts and after it exits, kept under
+//! `refs/arpc/snapshots/<session_id>/` so git doesn't collect them and
+//! `git diff` can compare them. Reverting brings the work tree back to the
+//! `before` snapshot.
+
+use super::git::Repo;
+use std::path::Path;
+
+const REF_PREFIX: &str = "refs/arpc/snapshots";
+
+/// When a snapshot was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Before the executor was spawned
+    Before,
+    /// After the executor's output ended
+    After,
+    /// Right before a revert, so the revert can be undone
+    Reverted,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Before => "before",
+            Stage::After => "after",
+            Stage::Reverted => "reverted",
+        }
+    }
+}
+
+/// What a revert did
+pub struct Reverted {
+    /// The snapshot the work tree was restored to
+    pub before: String,
+    /// The snapshot of the work tree the revert replaced
+    pub reverted_from: String,
+    pub restored: usize,
+    pub removed: usize,
+}
+
+/// Commit the work tree of `project_path` as it is now.
+pub async fn take(project_path: &Path, message: &str) -> Result<String, String> {
+    Repo::open(project_path).await?.snapshot(message).await
+}
+
+/// Keep `commit` as the `stage` snapshot of `session_id`.
+pub async fn record(
+    project_path: &Path,
+    session_id: &str,
+    stage: Stage,
+    commit: &str,
+) -> Result<(), String> {
+    let repo = Repo::open(project_path).await?;
+    repo.git(&["update-ref", &snapshot_ref(session_id, stage), commit])
+        .await
+        .map(|_| ())
+}
+
+/// Restore the work tree of `project_path` to the state before
+/// `session_id` ran; None when the session has no `before` snapshot.
+pub async fn revert(project_path: &Path, session_id: &str) -> Result<Option<Reverted>, String> {
+    let repo = Repo::open(project_path).await?;
+    let before_ref = format!("{}^{{commit}}", snapshot_ref(session_id, Stage::Before));
+    let Ok(before) = repo
+        .git(&["rev-parse", "--verify", "--quiet", &before_ref])
+        .await
+    else {
+        return Ok(None);
+    };
+
+    let current = repo
+        .snapshot(&format!("Before reverting session {}", session_id))
+        .await?;
+    repo.git(&[
+        "update-ref",
+        &snapshot_ref(session_id, Stage::Reverted),
+        &current,
+    ])
+    .await?;
+    let (restored, removed) = repo.restore(&before, &current).await?;
+    Ok(Some(Reverted {
+        before,
+        reverted_from: current,
+        restored,
+        removed,
+    }))
+}
+
+fn snapshot_ref(session_id: &str, stage: Stage) -> String {
+    format!("{}/{}/{}", REF_PREFIX, session_id, stage.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn revert_restores_the_work_tree_from_before_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        let run = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(path)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        run(&["init", "-q"]);
+        std::fs::write(path.join(".gitignore"), "build/\n").unwrap();
+        std::fs::write(path.join("kept.txt"), "committed").unwrap();
+        run(&["add", "."]);
+        run(&[
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "-qm",
+            "init",
+        ]);
+        std::fs::write(path.join("draft.txt"), "uncommitted").unwrap();
+
+        assert!(revert(path, "s-1").await.unwrap().is_none());
+        let before = take(path, "before").await.unwrap();
+        record(path, "s-1", Stage::Before, &before).await.unwrap();
+
+        // What the agent did
+        std::fs::write(path.join("kept.txt"), "mangled").unwrap();
+        std::fs::remove_file(path.join("draft.txt")).unwrap();
+        std::fs::create_dir_all(path.join("src/new")).unwrap();
+        std::fs::write(path.join("src/new/file.rs"), "fn main() {}").unwrap();
+        std::fs::create_dir(path.join("build")).unwrap();
+        std::fs::write(path.join("build/out"), "ignored").unwrap();
+
+        let reverted = revert(path, "s-1").await.unwrap().unwrap();
+        assert_eq!(reverted.before, before);
+        assert_eq!((reverted.restored, reverted.removed), (2, 1));
+        let read = |file: &str| std::fs::read_to_string(path.join(file)).unwrap();
+        assert_eq!(read("kept.txt"), "committed");
+        assert_eq!(read("draft.txt"), "uncommitted");
+        assert!(!path.join("src/new/file.rs").exists());
+        assert_eq!(read("build/out"), "ignored");
+
+        // The replaced state is kept, so the revert can be undone
+        let repo = Repo::open(path).await.unwrap();
+        let show = format!("{}:src/new/file.rs", snapshot_ref("s-1", Stage::Reverted));
+        assert_eq!(repo.git(&["show", &show]).await.unwrap(), "fn main() {}");
+    }
+}
@@ -0,0 +1,486 @@
+use crate::agentx::cache::ListingCache;
+use crate::agentx::utils::{parse_age, run_blocking};
+use crate::executor::ExecutorKind;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EXECUTORS: [ExecutorKind; 3] = [
+    ExecutorKind::Claude,
+    ExecutorKind::Codex,
+    ExecutorKind::Gemini,
+];
+
+/// Session files touched this recently are never deleted, so a running
+/// session keeps its transcript whatever the rules say.
+const MIN_IDLE_SECS: u64 = 10 * 60;
+
+/// The client's own data: archives, logs, metadata and indexes.
+fn data_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("arpc"))
+}
+
+/// Directories holding an executor's history, by category.
+fn history_dirs(executor: ExecutorKind) -> Vec<(&'static str, PathBuf)> {
+    let Ok(root) = executor.storage_dir() else {
+        return Vec::new();
+    };
+    match executor {
+        ExecutorKind::Claude => vec![
+            ("history", root.join("projects")),
+            ("todos", root.join("todos")),
+        ],
+        ExecutorKind::Codex => vec![("history", root.join("sessions"))],
+        ExecutorKind::Gemini => vec![("history", root.join("tmp"))],
+    }
+}
+
+/// Total size and file count under `path`, without following symlinks.
+fn dir_usage(path: &Path) -> (u64, u64) {
+    let Ok(entries) = fs::read_dir(path) else {
+        return (0, 0);
+    };
+    let mut usage = (0, 0);
+    for entry in entries.flatten() {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            let (bytes, files) = dir_usage(&entry.path());
+            usage.0 += bytes;
+            usage.1 += files;
+        } else if meta.is_file() {
+            usage.0 += meta.len();
+            usage.1 += 1;
+        }
+    }
+    usage
+}
+
+fn usage_json(path: &Path) -> Value {
+    let (bytes, files) = dir_usage(path);
+    json!({ "path": path, "bytes": bytes, "files": files })
+}
+
+/// Bytes used by each executor's history and the client data directory.
+pub async fn usage() -> Result<Value, String> {
+    run_blocking(|| {
+        let mut total = 0;
+        let mut executors = serde_json::Map::new();
+        for executor in EXECUTORS {
+            let mut categories = serde_json::Map::new();
+            for (category, dir) in history_dirs(executor) {
+                let usage = usage_json(&dir);
+                total += usage["bytes"].as_u64().unwrap_or(0);
+                categories.insert(category.to_string(), usage);
+            }
+            executors.insert(executor.as_str().to_string(), Value::Object(categories));
+        }
+
+        // Archives are the client's session artifacts; reported apart from
+        // the rest of the data directory that contains them
+        let (artifacts, data_dir) = match data_dir() {
+            Some(dir) => {
+                let data = usage_json(&dir);
+                total += data["bytes"].as_u64().unwrap_or(0);
+                (usage_json(&dir.join("archive")), data)
+            }
+            None => (Value::Null, Value::Null),
+        };
+        json!({
+            "type": "storage",
+            "executors": executors,
+            "artifacts": artifacts,
+            "data_dir": data_dir,
+            "total_bytes": total,
+        })
+    })
+    .await
+}
+
+/// Parse a size such as `500M`, `2G` or `1048576` into bytes
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_bytes: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    amount.checked_mul(unit_bytes)
+}
+
+/// Limits on one executor's session history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct RetentionRule {
+    /// Sessions idle for longer than this many seconds are deleted
+    pub max_age_secs: Option<u64>,
+    /// Oldest sessions are deleted until the history fits in this many bytes
+    pub max_bytes: Option<u64>,
+}
+
+/// Retention rules by executor, from `--retention <executor|all>=<rule>[,<rule>]`
+/// where each rule is an age (`30d`) or a size (`2G`).
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct RetentionRules {
+    rules: BTreeMap<&'static str, RetentionRule>,
+}
+
+impl RetentionRules {
+    pub fn parse(specs: &[String]) -> Result<Self, String> {
+        let mut all = None;
+        let mut rules = BTreeMap::new();
+        for spec in specs {
+            let (target, limits) = spec.split_once('=').ok_or_else(|| {
+                format!("Invalid retention '{}', expected <executor>=<rule>", spec)
+            })?;
+            let mut rule = RetentionRule::default();
+            for limit in limits.split(',').map(str::trim) {
+                if let Some(age) = parse_age(limit) {
+                    rule.max_age_secs = Some(age);
+                } else if let Some(size) = parse_size(limit) {
+                    rule.max_bytes = Some(size);
+                } else {
+                    return Err(format!(
+                        "Invalid retention rule '{}', expected an age like 30d or a size like 2G",
+                        limit
+                    ));
+                }
+            }
+            match target.trim() {
+                "all" => all = Some(rule),
+                name => {
+                    let executor = ExecutorKind::from_str(name)
+                        .ok_or_else(|| format!("Unknown executor in retention '{}'", spec))?;
+                    rules.insert(executor.as_str(), rule);
+                }
+            }
+        }
+        if let Some(rule) = all {
+            for executor in EXECUTORS {
+                rules.entry(executor.as_str()).or_insert(rule);
+            }
+        }
+        Ok(RetentionRules { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// One session transcript and the files deleted with it
+#[derive(Debug, Clone)]
+struct SessionFile {
+    path: PathBuf,
+    companions: Vec<PathBuf>,
+    bytes: u64,
+    modified: u64,
+}
+
+/// A session file retention would delete, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct Deletion {
+    pub executor: &'static str,
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub modified: u64,
+    /// `max_age` or `max_size`
+    pub reason: &'static str,
+    #[serde(skip)]
+    companions: Vec<PathBuf>,
+}
+
+fn file_info(path: PathBuf) -> Option<SessionFile> {
+    let meta = fs::metadata(&path).ok()?;
+    let modified = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(SessionFile {
+        path,
+        companions: Vec::new(),
+        bytes: meta.len(),
+        modified,
+    })
+}
+
+/// Files matching `keep` under `dir`, `depth` levels down.
+fn files_at_depth(dir: &Path, depth: usize, keep: &dyn Fn(&Path) -> bool) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if depth > 0 && file_type.is_dir() {
+            files.extend(files_at_depth(&path, depth - 1, keep));
+        } else if depth == 0 && file_type.is_file() && keep(&path) {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == extension)
+}
+
+/// Session transcripts of `executor`, oldest first.
+fn session_files(executor: ExecutorKind) -> Vec<SessionFile> {
+    let Ok(root) = executor.storage_dir() else {
+        return Vec::new();
+    };
+    let mut files: Vec<SessionFile> = match executor {
+        // projects/<project>/<session>.jsonl, with todos/<session>.json
+        ExecutorKind::Claude => {
+            let todos = root.join("todos");
+            files_at_depth(&root.join("projects"), 1, &|p| has_extension(p, "jsonl"))
+                .into_iter()
+                .filter_map(file_info)
+                .map(|mut file| {
+                    if let Some(stem) = file.path.file_stem() {
+                        let todo = todos.join(stem).with_extension("json");
+                        if let Ok(meta) = fs::metadata(&todo) {
+                            file.bytes += meta.len();
+                            file.companions.push(todo);
+                        }
+                    }
+                    file
+                })
+                .collect()
+        }
+        // sessions/<yyyy>/<mm>/<dd>/rollout-*.jsonl
+        ExecutorKind::Codex => {
+            files_at_depth(&root.join("sessions"), 3, &|p| has_extension(p, "jsonl"))
+                .into_iter()
+                .filter_map(file_info)
+                .collect()
+        }
+        // tmp/<project hash>/chats/session-*.json
+        ExecutorKind::Gemini => files_at_depth(&root.join("tmp"), 2, &|p| {
+            has_extension(p, "json") && p.parent().is_some_and(|dir| dir.ends_with("chats"))
+        })
+        .into_iter()
+        .filter_map(file_info)
+        .collect(),
+    };
+    files.sort_by_key(|file| file.modified);
+    files
+}
+
+/// Sessions `rule` deletes from `files` (oldest first) at `now`.
+fn plan_executor(
+    executor: &'static str,
+    files: Vec<SessionFile>,
+    rule: RetentionRule,
+    now: u64,
+) -> Vec<Deletion> {
+    let mut total: u64 = files.iter().map(|file| file.bytes).sum();
+    let mut deletions = Vec::new();
+    for file in files {
+        let idle = now.saturating_sub(file.modified);
+        if idle < MIN_IDLE_SECS {
+            continue;
+        }
+        let reason = if rule.max_age_secs.is_some_and(|max| idle > max) {
+            "max_age"
+        } else if rule.max_bytes.is_some_and(|max| total > max) {
+            "max_size"
+        } else {
+            continue;
+        };
+        total -= file.bytes;
+        deletions.push(Deletion {
+            executor,
+            path: file.path,
+            bytes: file.bytes,
+            modified: file.modified,
+            reason,
+            companions: file.companions,
+        });
+    }
+    deletions
+}
+
+/// Sessions the rules would delete now.
+pub async fn plan(rules: &RetentionRules) -> Result<Vec<Deletion>, String> {
+    let rules: Vec<(ExecutorKind, RetentionRule)> = EXECUTORS
+        .into_iter()
+        .filter_map(|executor| Some((executor, *rules.rules.get(executor.as_str())?)))
+        .collect();
+    run_blocking(move || {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        rules
+            .into_iter()
+            .flat_map(|(executor, rule)| {
+                plan_executor(executor.as_str(), session_files(executor), rule, now)
+            })
+            .collect()
+    })
+    .await
+}
+
+/// Delete the planned sessions; returns the deleted paths and failures.
+pub async fn apply(
+    deletions: Vec<Deletion>,
+    cache: &ListingCache,
+) -> Result<(Vec<PathBuf>, Vec<Value>), String> {
+    let executors: Vec<&'static str> = deletions.iter().map(|d| d.executor).collect();
+    let result = run_blocking(move || {
+        let mut deleted = Vec::new();
+        let mut failed = Vec::new();
+        for deletion in deletions {
+            match fs::remove_file(&deletion.path) {
+                Ok(()) => {
+                    for companion in &deletion.companions {
+                        let _ = fs::remove_file(companion);
+                    }
+                    deleted.push(deletion.path);
+                }
+                Err(e) => failed.push(json!({"path": deletion.path, "error": e.to_string()})),
+            }
+        }
+        (deleted, failed)
+    })
+    .await?;
+    for executor in EXECUTORS.map(|executor| executor.as_str()) {
+        if executors.contains(&executor) {
+            cache.invalidate(executor).await;
+        }
+    }
+    Ok(result)
+}
+
+/// JSON report of `deletions`, as answered to a dry run.
+pub fn report(deletions: &[Deletion]) -> Value {
+    json!({
+        "sessions": deletions.len(),
+        "bytes": deletions.iter().map(|d| d.bytes).sum::<u64>(),
+        "deletions": deletions,
+    })
+}
+
+/// Apply `rules` every `interval`, logging what is about to be deleted
+/// first; with `dry_run` only the report is logged.
+pub async fn enforce_periodically(
+    rules: Arc<RetentionRules>,
+    interval: Duration,
+    dry_run: bool,
+    cache: Arc<ListingCache>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let deletions = match plan(&rules).await {
+            Ok(deletions) if deletions.is_empty() => continue,
+            Ok(deletions) => deletions,
+            Err(e) => {
+                tracing::warn!("Retention scan failed: {}", e);
+                continue;
+            }
+        };
+        let bytes: u64 = deletions.iter().map(|d| d.bytes).sum();
+        for deletion in &deletions {
+            tracing::debug!(
+                "Retention ({}): {:?} ({} bytes)",
+                deletion.reason,
+                deletion.path,
+                deletion.bytes
+            );
+        }
+        if dry_run {
+            tracing::info!(
+                "Retention dry run: would delete {} sessions ({} bytes)",
+                deletions.len(),
+                bytes
+            );
+            continue;
+        }
+        tracing::info!(
+            "Retention: deleting {} sessions ({} bytes)",
+            deletions.len(),
+            bytes
+        );
+        match apply(deletions, &cache).await {
+            Ok((_, failed)) if !failed.is_empty() => {
+                tracing::warn!("Retention failed to delete {} sessions", failed.len());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Retention failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, bytes: u64, modified: u64) -> SessionFile {
+        SessionFile {
+            path: PathBuf::from(name),
+            companions: Vec::new(),
+            bytes,
+            modified,
+        }
+    }
+
+    #[test]
+    fn rules_parse_per_executor_with_all_as_default() {
+        let rules =
+            RetentionRules::parse(&["all=90d".to_string(), "claude=30d,2G".to_string()]).unwrap();
+        assert_eq!(
+            rules.rules["claude"],
+            RetentionRule {
+                max_age_secs: Some(30 * 86400),
+                max_bytes: Some(2 << 30),
+            }
+        );
+        assert_eq!(rules.rules["codex"].max_age_secs, Some(90 * 86400));
+        assert!(RetentionRules::parse(&["cursor=30d".to_string()]).is_err());
+        assert!(RetentionRules::parse(&["claude=soon".to_string()]).is_err());
+    }
+
+    #[test]
+    fn oldest_sessions_go_first_and_recent_ones_are_kept() {
+        let day = 86400;
+        let now = 100 * day;
+        let files = vec![
+            file("ancient", 10, now - 60 * day),
+            file("old", 50, now - 5 * day),
+            file("recent", 50, now - 2 * day),
+            file("running", 500, now - 60),
+        ];
+        let rule = RetentionRule {
+            max_age_secs: Some(30 * day),
+            max_bytes: Some(560),
+        };
+
+        let plan = plan_executor("claude", files, rule, now);
+        let planned: Vec<_> = plan
+            .iter()
+            .map(|d| (d.path.to_str().unwrap(), d.reason))
+            .collect();
+        assert_eq!(planned, [("ancient", "max_age"), ("old", "max_size")]);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::agentx::types::ToolCall;
+#[cfg(feature = "github")]
+use crate::agentx::types::TranscriptSummary;
+use chrono::DateTime;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Build a tool-call timeline from transcript messages.
+///
+/// Understands Claude `tool_use`/`tool_result` content blocks, Codex
+/// `function_call`/`function_call_output` items and Gemini `toolCalls`
+/// entries. Calls are returned in the order they were made.
+pub fn extract_tool_calls(messages: &[Value]) -> Vec<ToolCall> {
+    let mut calls: Vec<ToolCall> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for message in messages {
+        let timestamp = message
+            .get("timestamp")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        // Claude: content blocks inside message.content
+        if let Some(blocks) = message
+            .pointer("/message/content")
+            .and_then(Value::as_array)
+        {
+            for block in blocks {
+                match block.get("type").and_then(Value::as_str) {
+                    Some("tool_use") => {
+                        let input = block.get("input").cloned().unwrap_or(Value::Null);
+                        start_call(
+                            &mut calls,
+                            &mut index,
+                            str_field(block, "id"),
+                            str_field(block, "name"),
+                            input,
+                            timestamp.clone(),
+                        );
+                    }
+                    Some("tool_result") => {
+                        let is_error = block
+                            .get("is_error")
+                            .and_then(Value::as_bool)
+                            .unwrap_or(false);
+                        finish_call(
+                            &mut calls,
+                            &index,
+                            &str_field(block, "tool_use_id"),
+                            block.get("content"),
+                            is_error,
+                            timestamp.clone(),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            continue;
+        }
+
+        // Gemini: toolCalls carry their own args and result
+        if let Some(tool_calls) = message.get("toolCalls").and_then(Value::as_array) {
+            for call in tool_calls {
+                let id = str_field(call, "id");
+                let started_at = call
+                    .get("timestamp")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .or_else(|| timestamp.clone());
+                start_call(
+                    &mut calls,
+                    &mut index,
+                    id.clone(),
+                    str_field(call, "name"),
+                    call.get("args").cloned().unwrap_or(Value::Null),
+                    started_at,
+                );
+                if call.get("result").is_some() {
+                    let is_error = call.get("status").and_then(Value::as_str) == Some("error");
+                    finish_call(&mut calls, &index, &id, call.get("result"), is_error, None);
+                }
+            }
+            continue;
+        }
+
+        // Codex: response items, optionally wrapped in a payload
+        let item = message.get("payload").unwrap_or(message);
+        match item.get("type").and_then(Value::as_str) {
+            Some("function_call") | Some("custom_tool_call") => {
+                let input = item
+                    .get("arguments")
+                    .or_else(|| item.get("input"))
+                    .map(parse_arguments)
+                    .unwrap_or(Value::Null);
+                start_call(
+                    &mut calls,
+                    &mut index,
+                    str_field(item, "call_id"),
+                    str_field(item, "name"),
+                    input,
+                    timestamp,
+                );
+            }
+            Some("function_call_output") | Some("custom_tool_call_output") => {
+                finish_call(
+                    &mut calls,
+                    &index,
+                    &str_field(item, "call_id"),
+                    item.get("output"),
+                    false,
+                    timestamp,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    calls
+}
+
+/// Summarize a transcript: the request that started it, the agent's final
+/// answer, the tools it used and the files it wrote. Same formats as
+/// [`extract_tool_calls`].
+#[cfg(feature = "github")]
+pub fn summarize_transcript(messages: &[Value]) -> TranscriptSummary {
+    let mut summary = TranscriptSummary::default();
+    for message in messages {
+        if message.get("type").and_then(Value::as_str) == Some("result")
+            && let Some(result) = message.get("result").and_then(Value::as_str)
+        {
+            summary.result = Some(result.to_string());
+            continue;
+        }
+        match message_text(message) {
+            Some(("user", text)) if summary.prompt.is_none() && !text.starts_with('<') => {
+                summary.prompt = Some(text)
+            }
+            Some(("assistant", text)) => summary.result = Some(text),
+            _ => {}
+        }
+    }
+
+    for call in extract_tool_calls(messages) {
+        match summary
+            .tools
+            .iter_mut()
+            .find(|(name, _)| *name == call.name)
+        {
+            Some((_, count)) => *count += 1,
+            None => summary.tools.push((call.name.clone(), 1)),
+        }
+        for path in written_files(&call) {
+            if !summary.files.contains(&path) {
+                summary.files.push(path);
+            }
+        }
+    }
+    summary
+}
+
+/// Role (`user` or `assistant`) and text of a conversational message
+#[cfg(feature = "github")]
+fn message_text(message: &Value) -> Option<(&'static str, String)> {
+    let (role, content) = if let Some(inner) = message.get("message") {
+        (
+            inner
+                .get("role")
+                .or_else(|| message.get("type"))
+                .and_then(Value::as_str),
+            inner.get("content"),
+        )
+    } else {
+        let item = message.get("payload").unwrap_or(message);
+        let role = match item.get("type").and_then(Value::as_str) {
+            Some("message") => item.get("role").and_then(Value::as_str),
+            other => other,
+        };
+        (role, item.get("content"))
+    };
+    let role = match role? {
+        "user" => "user",
+        "assistant" | "gemini" | "model" => "assistant",
+        _ => return None,
+    };
+    let text = match content? {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => return None,
+    };
+    let text = text.trim();
+    (!text.is_empty()).then(|| (role, text.to_string()))
+}
+
+/// Files a tool call created or changed
+#[cfg(feature = "github")]
+fn written_files(call: &ToolCall) -> Vec<String> {
+    match call.name.as_str() {
+        "Edit" | "MultiEdit" | "Write" | "NotebookEdit" | "write_file" | "replace" => {
+            ["file_path", "notebook_path", "path"]
+                .iter()
+                .find_map(|key| call.input.get(*key).and_then(Value::as_str))
+                .map(str::to_string)
+                .into_iter()
+                .collect()
+        }
+        // Codex patches name their files in the patch text
+        "apply_patch" => {
+            let patch = call
+                .input
+                .as_str()
+                .or_else(|| call.input.get("input").and_then(Value::as_str))
+                .unwrap_or_default();
+            patch
+                .lines()
+                .filter_map(|line| {
+                    line.strip_prefix("*** Update File: ")
+                        .or_else(|| line.strip_prefix("*** Add File: "))
+                        .or_else(|| line.strip_prefix("*** Delete File: "))
+                })
+                .map(str::to_string)
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn start_call(
+    calls: &mut Vec<ToolCall>,
+    index: &mut HashMap<String, usize>,
+    id: String,
+    name: String,
+    input: Value,
+    started_at: Option<String>,
+) {
+    if index.contains_key(&id) {
+        return;
+    }
+    index.insert(id.clone(), calls.len());
+    calls.push(ToolCall {
+        id,
+        name,
+        input_bytes: byte_size(&input),
+        input,
+        output_bytes: None,
+        status: "pending".to_string(),
+        started_at,
+        finished_at: None,
+        duration_ms: None,
+    });
+}
+
+fn finish_call(
+    calls: &mut [ToolCall],
+    index: &HashMap<String, usize>,
+    id: &str,
+    output: Option<&Value>,
+    is_error: bool,
+    finished_at: Option<String>,
+) {
+    let Some(call) = index.get(id).and_then(|&i| calls.get_mut(i)) else {
+        return;
+    };
+
+    call.output_bytes = Some(output.map(byte_size).unwrap_or(0));
+    call.status = if is_error { "error" } else { "completed" }.to_string();
+    call.finished_at = finished_at;
+    call.duration_ms = match (&call.started_at, &call.finished_at) {
+        (Some(start), Some(end)) => {
+            let start = DateTime::parse_from_rfc3339(start).ok();
+            let end = DateTime::parse_from_rfc3339(end).ok();
+            start
+                .zip(end)
+                .map(|(start, end)| (end - start).num_milliseconds())
+        }
+        _ => None,
+    };
+}
+
+fn str_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Codex encodes function arguments as a JSON string
+fn parse_arguments(value: &Value) -> Value {
+    match value {
+        Value::String(s) => serde_json::from_str(s).unwrap_or_else(|_| value.clone()),
+        other => other.clone(),
+    }
+}
+
+/// Size of a payload as the agent saw it: raw text for strings, JSON otherwise
+fn byte_size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        Value::Null => 0,
+        other => other.to_string().len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn claude_and_codex_calls_are_paired_with_results() {
+        let claude = vec![
+            json!({"type": "assistant", "timestamp": "2025-01-01T00:00:00.000Z", "message": {"content": [
+                {"type": "text", "text": "Listing files"},
+                {"type": "tool_use", "id": "t1", "name": "Bash", "input": {"command": "ls"}}
+            ]}}),
+            json!({"type": "user", "timestamp": "2025-01-01T00:00:01.500Z", "message": {"content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": "a\nb", "is_error": false}
+            ]}}),
+            json!({"type": "assistant", "message": {"content": [
+                {"type": "tool_use", "id": "t2", "name": "Read", "input": {"file_path": "a"}}
+            ]}}),
+        ];
+        let calls = extract_tool_calls(&claude);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "Bash");
+        assert_eq!(calls[0].status, "completed");
+        assert_eq!(calls[0].duration_ms, Some(1500));
+        assert_eq!(calls[0].output_bytes, Some(3));
+        assert_eq!(calls[1].status, "pending");
+
+        let codex = vec![
+            json!({"timestamp": "2025-01-01T00:00:00Z", "type": "response_item", "payload": {
+                "type": "function_call", "name": "shell", "call_id": "c1",
+                "arguments": "{\"command\":[\"ls\"]}"
+            }}),
+            json!({"timestamp": "2025-01-01T00:00:02Z", "type": "response_item", "payload": {
+                "type": "function_call_output", "call_id": "c1", "output": "ok"
+            }}),
+        ];
+        let calls = extract_tool_calls(&codex);
+        assert_eq!(calls[0].input["command"][0], "ls");
+        assert_eq!(calls[0].duration_ms, Some(2000));
+        assert_eq!(calls[0].output_bytes, Some(2));
+    }
+
+    #[cfg(feature = "github")]
+    #[test]
+    fn summary_has_prompt_answer_tools_and_files() {
+        let claude = vec![
+            json!({"type": "user", "message": {"role": "user", "content": "Fix the typo"}}),
+            json!({"type": "assistant", "message": {"role": "assistant", "content": [
+                {"type": "tool_use", "id": "t1", "name": "Read", "input": {"file_path": "a.md"}},
+                {"type": "tool_use", "id": "t2", "name": "Edit", "input": {"file_path": "a.md"}}
+            ]}}),
+            json!({"type": "user", "message": {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": "teh"}
+            ]}}),
+            json!({"type": "assistant", "message": {"role": "assistant", "content": [
+                {"type": "text", "text": "Fixed it."}
+            ]}}),
+        ];
+        let summary = summarize_transcript(&claude);
+        assert_eq!(summary.prompt.as_deref(), Some("Fix the typo"));
+        assert_eq!(summary.result.as_deref(), Some("Fixed it."));
+        assert_eq!(
+            summary.tools,
+            vec![("Read".to_string(), 1), ("Edit".to_string(), 1)]
+        );
+        assert_eq!(summary.files, vec!["a.md".to_string()]);
+    }
+}
@@ -8,6 +8,19 @@ pub struct Project {
     pub sessions: Vec<String>,
     pub created_at: u64,
     pub most_recent_session: Option<u64>,
+    #[serde(flatten)]
+    pub meta: ProjectMeta,
+}
+
+/// User-assigned project settings kept by the client, keyed by project path
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProjectMeta {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +37,27 @@ pub struct Session {
     pub total_duration: Option<f64>,
 }
 
+/// One executor's history for a unified project
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutorProject {
+    pub executor: String,
+    pub project_id: String,
+    pub session_count: usize,
+    pub most_recent_session: Option<u64>,
+}
+
+/// A working directory merged across all executors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnifiedProject {
+    pub path: String,
+    pub executors: Vec<ExecutorProject>,
+    pub session_count: usize,
+    pub created_at: u64,
+    pub most_recent_session: Option<u64>,
+    pub pinned: bool,
+    pub display_name: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkingDirectory {
     pub path: String,
@@ -34,3 +68,101 @@ pub struct WorkingDirectory {
     #[serde(rename = "conversationCount")]
     pub conversation_count: usize,
 }
+
+/// One tool invocation extracted from an agent transcript
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+    pub input_bytes: usize,
+    pub output_bytes: Option<usize>,
+    /// "pending" | "completed" | "error"
+    pub status: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<i64>,
+}
+
+/// What a transcript amounts to, for pull request descriptions
+#[cfg(feature = "github")]
+#[derive(Debug, Default)]
+pub struct TranscriptSummary {
+    /// First user message
+    pub prompt: Option<String>,
+    /// Last assistant message (or the run's final result)
+    pub result: Option<String>,
+    /// Tool names with how often each was used, in order of first use
+    pub tools: Vec<(String, usize)>,
+    /// Files the agent created or edited
+    pub files: Vec<String>,
+}
+
+/// Default number of transcript lines returned by one history request
+pub const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+/// Most transcript lines a client may request at once
+pub const MAX_HISTORY_LIMIT: usize = 10_000;
+
+/// Most transcript bytes held in memory for one history request
+pub const MAX_HISTORY_BYTES: usize = 32 * 1024 * 1024;
+
+/// Which slice of a transcript to load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryWindow {
+    /// Number of leading lines to skip
+    pub from: usize,
+    /// Maximum number of lines to return
+    pub limit: usize,
+    /// Stop once this many bytes of transcript have been read
+    pub max_bytes: usize,
+}
+
+impl Default for HistoryWindow {
+    fn default() -> Self {
+        HistoryWindow {
+            from: 0,
+            limit: DEFAULT_HISTORY_LIMIT,
+            max_bytes: MAX_HISTORY_BYTES,
+        }
+    }
+}
+
+impl HistoryWindow {
+    /// Window from the `from` and `limit` query parameters
+    pub fn from_request(request: &common::http::HttpRequest) -> Self {
+        let param = |key: &str| {
+            request
+                .query_param(key)
+                .and_then(|value| value.parse::<usize>().ok())
+        };
+
+        HistoryWindow {
+            from: param("from").unwrap_or(0),
+            limit: param("limit")
+                .filter(|&limit| limit > 0)
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .min(MAX_HISTORY_LIMIT),
+            max_bytes: MAX_HISTORY_BYTES,
+        }
+    }
+
+    /// Every line from `from` on, bounded only by the byte limit
+    pub fn unlimited_from(from: usize) -> Self {
+        HistoryWindow {
+            from,
+            limit: usize::MAX,
+            max_bytes: MAX_HISTORY_BYTES,
+        }
+    }
+}
+
+/// A slice of a transcript
+#[derive(Debug, Clone, Default)]
+pub struct HistoryPage {
+    /// Line number (0-based) of the first message
+    pub from: usize,
+    pub messages: Vec<Value>,
+    /// Line to continue from, if the transcript has more lines
+    pub next_from: Option<usize>,
+}
@@ -1,5 +1,11 @@
+use crate::agentx::types::{HistoryPage, HistoryWindow};
+use serde_json::Value;
 use std::fs::Metadata;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
 use std::time::UNIX_EPOCH;
+use tokio::sync::Semaphore;
 
 /// Convert file metadata timestamps into a Unix timestamp in seconds.
 pub fn metadata_timestamp(metadata: &Metadata) -> u64 {
@@ -27,3 +33,190 @@ pub fn should_filter_message_text(text: &str) -> bool {
         || trimmed.starts_with("<system-reminder>")
         || trimmed == "Warmup"
 }
+
+/// Parse an age such as `90m`, `12h`, `30d` or `2w` into seconds
+pub fn parse_age(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_secs)
+}
+
+/// Upper bound on blocking scan tasks running at once
+fn scan_parallelism() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+        .clamp(2, 8)
+}
+
+/// Run blocking filesystem work off the async runtime
+pub async fn run_blocking<R, F>(f: F) -> Result<R, String>
+where
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| format!("Scan task failed: {}", e))
+}
+
+/// Map `f` over `items` on the blocking pool with bounded parallelism,
+/// keeping input order and dropping `None` results
+pub async fn scan_blocking<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> Option<R> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let permits = Arc::new(Semaphore::new(scan_parallelism()));
+
+    let tasks: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let f = f.clone();
+            let permits = permits.clone();
+            tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await.ok()?;
+                tokio::task::spawn_blocking(move || f(item))
+                    .await
+                    .ok()
+                    .flatten()
+            })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        if let Ok(Some(result)) = task.await {
+            results.push(result);
+        }
+    }
+    results
+}
+
+/// Read one window of a JSONL transcript without loading the whole file.
+///
+/// Skipped lines are not parsed; unparseable lines count towards the window
+/// but are left out of the page.
+pub fn read_jsonl_window(path: &Path, window: HistoryWindow) -> Result<HistoryPage, String> {
+    let file =
+        std::fs::File::open(path).map_err(|e| format!("Failed to read session file: {}", e))?;
+    let mut reader = BufReader::new(file);
+
+    let mut page = HistoryPage {
+        from: window.from,
+        ..Default::default()
+    };
+    let mut line = String::new();
+    let mut line_index = 0;
+    let mut bytes = 0;
+
+    loop {
+        line.clear();
+        let read = reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read session file: {}", e))?;
+        if read == 0 {
+            return Ok(page);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line_index >= window.from {
+            let taken = line_index - window.from;
+            if taken >= window.limit || bytes + read > window.max_bytes {
+                if taken == 0 {
+                    return Err("Transcript line exceeds the history size limit".to_string());
+                }
+                page.next_from = Some(line_index);
+                return Ok(page);
+            }
+            bytes += read;
+            if let Ok(message) = serde_json::from_str(line.trim_end()) {
+                page.messages.push(message);
+            }
+        }
+        line_index += 1;
+    }
+}
+
+/// Apply a window to a transcript that had to be parsed as a whole
+pub fn window_messages(messages: Vec<Value>, window: HistoryWindow) -> HistoryPage {
+    let total = messages.len();
+    let messages: Vec<Value> = messages
+        .into_iter()
+        .skip(window.from)
+        .take(window.limit)
+        .collect();
+    let end = window.from.saturating_add(messages.len());
+
+    HistoryPage {
+        from: window.from,
+        next_from: (end < total).then_some(end),
+        messages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn jsonl_windows_page_through_the_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..5 {
+            writeln!(file, "{{\"n\":{}}}", i).unwrap();
+        }
+        writeln!(file).unwrap();
+
+        let window = |from, limit| HistoryWindow {
+            from,
+            limit,
+            max_bytes: usize::MAX,
+        };
+        let page = read_jsonl_window(file.path(), window(1, 2)).unwrap();
+        assert_eq!(page.messages[0]["n"], 1);
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.next_from, Some(3));
+
+        let page = read_jsonl_window(file.path(), window(3, 10)).unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.next_from, None);
+
+        // Each line is 8 bytes; a 20 byte budget fits two of them
+        let page = read_jsonl_window(
+            file.path(),
+            HistoryWindow {
+                from: 0,
+                limit: 10,
+                max_bytes: 20,
+            },
+        )
+        .unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.next_from, Some(2));
+    }
+
+    #[test]
+    fn ages_parse_with_units() {
+        assert_eq!(parse_age("30d"), Some(30 * 86_400));
+        assert_eq!(parse_age("12h"), Some(12 * 3_600));
+        assert_eq!(parse_age("2w"), Some(14 * 86_400));
+        assert_eq!(parse_age("30"), None);
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("5y"), None);
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::{Context, Result, anyhow};
+use common::auth::{constant_time_eq, fields_mac};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// People who may decide permission prompts, loaded from a JSON file that
+/// names each approver and the secret token they authenticate with:
+///
+/// ```json
+/// {"approvers": {"alice": "4f1c...", "bob": "9e07..."}}
+/// ```
+///
+/// A decision is attributed to the approver whose token it was made with
+/// (`Authorization: Bearer <token>`), so N-of-M approvals need N tokens.
+/// Links in chat messages carry a [`link token`](Approvers::link_token)
+/// instead, which only works for the one decision it was made for.
+/// Without approvers, decisions are anonymous and a prompt can't collect
+/// more than one approval.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Approvers {
+    approvers: HashMap<String, String>,
+}
+
+impl Approvers {
+    /// Load the approvers from `path`; no path means none
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read approvers {}", path.display()))?;
+        let approvers: Approvers = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid approvers {}: {}", path.display(), e))?;
+        approvers
+            .check()
+            .map_err(|e| anyhow!("Invalid approvers {}: {}", path.display(), e))?;
+
+        info!(
+            "Loaded {} approvers from {}",
+            approvers.approvers.len(),
+            path.display()
+        );
+        Ok(approvers)
+    }
+
+    #[cfg(test)]
+    pub fn from_tokens(entries: &[(&str, &str)]) -> Self {
+        Approvers {
+            approvers: entries
+                .iter()
+                .map(|(name, token)| (name.to_string(), token.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Tokens must be set and tell approvers apart
+    fn check(&self) -> Result<(), String> {
+        let mut names: Vec<_> = self.approvers.keys().collect();
+        names.sort();
+        for (i, name) in names.iter().enumerate() {
+            let token = &self.approvers[*name];
+            if name.is_empty() || token.is_empty() {
+                return Err("approver names and tokens cannot be empty".to_string());
+            }
+            if let Some(other) = names[i + 1..]
+                .iter()
+                .find(|other| self.approvers[**other] == *token)
+            {
+                return Err(format!("{} and {} share a token", name, other));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.approvers.is_empty()
+    }
+
+    /// Whether `name` is one of the approvers
+    #[cfg(any(feature = "mcp", test))]
+    pub fn contains(&self, name: &str) -> bool {
+        self.approvers.contains_key(name)
+    }
+
+    /// The approver `token` belongs to
+    pub fn identify(&self, token: &str) -> Option<&str> {
+        self.approvers
+            .iter()
+            .find(|(_, secret)| constant_time_eq(secret.as_bytes(), token.as_bytes()))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// A token letting `name` make `decision` on one permission prompt,
+    /// signed with the approver's own token. It can't be used for other
+    /// prompts or the other decision, and a decided prompt no longer takes
+    /// it, so each link is good for one decision.
+    #[cfg(any(feature = "notifiers", test))]
+    pub fn link_token(
+        &self,
+        name: &str,
+        session_id: &str,
+        permission_id: &str,
+        decision: &str,
+    ) -> Option<String> {
+        let secret = self.approvers.get(name)?;
+        let mac = link_mac(secret, session_id, permission_id, decision);
+        Some(format!(
+            "{}.{}",
+            name,
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+
+    /// The approver a [`link token`](Approvers::link_token) for `decision`
+    /// on this prompt was made for
+    pub fn verify_link(
+        &self,
+        token: &str,
+        session_id: &str,
+        permission_id: &str,
+        decision: &str,
+    ) -> Option<&str> {
+        let (name, mac) = token.rsplit_once('.')?;
+        let (name, secret) = self.approvers.get_key_value(name)?;
+        let mac = hex::decode(mac).ok()?;
+        link_mac(secret, session_id, permission_id, decision)
+            .verify_slice(&mac)
+            .ok()?;
+        Some(name.as_str())
+    }
+}
+
+fn link_mac(secret: &str, session_id: &str, permission_id: &str, decision: &str) -> Hmac<Sha256> {
+    fields_mac(
+        secret.as_bytes(),
+        &[
+            session_id.as_bytes(),
+            permission_id.as_bytes(),
+            decision.as_bytes(),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_identify_their_approver() {
+        let approvers = Approvers::from_tokens(&[("alice", "token-a"), ("bob", "token-b")]);
+        assert_eq!(approvers.identify("token-a"), Some("alice"));
+        assert_eq!(approvers.identify("token-b"), Some("bob"));
+        assert_eq!(approvers.identify("token-"), None);
+        assert_eq!(approvers.identify("alice"), None);
+        assert!(approvers.contains("bob"));
+        assert!(!approvers.contains("carol"));
+
+        let shared = Approvers::from_tokens(&[("alice", "same"), ("bob", "same")]);
+        assert!(shared.check().unwrap_err().contains("share a token"));
+    }
+
+    #[test]
+    fn link_tokens_only_work_for_their_decision() {
+        let approvers = Approvers::from_tokens(&[("alice", "token-a"), ("bob", "token-b")]);
+        let token = approvers
+            .link_token("alice", "s1", "p1", "approve")
+            .unwrap();
+        assert!(!token.contains("token-a"));
+        assert_eq!(
+            approvers.verify_link(&token, "s1", "p1", "approve"),
+            Some("alice")
+        );
+        assert_eq!(approvers.verify_link(&token, "s1", "p1", "deny"), None);
+        assert_eq!(approvers.verify_link(&token, "s1", "p2", "approve"), None);
+        assert_eq!(approvers.verify_link(&token, "s2", "p1", "approve"), None);
+        // Relabelled for another approver
+        let forged = token.replacen("alice", "bob", 1);
+        assert_eq!(approvers.verify_link(&forged, "s1", "p1", "approve"), None);
+        assert_eq!(approvers.link_token("carol", "s1", "p1", "approve"), None);
+    }
+}
@@ -1,12 +1,28 @@
+use crate::executor::OrphanPolicy;
+use crate::handlers::paths::{FsOptions, SymlinkPolicy};
+#[cfg(feature = "executors")]
+use crate::redact::Redactor;
 use clap::Parser;
+use common::http::ParseLimits;
+use common::{CopyConfig, DirectionConfig, FlushPolicy};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 use std::{env, fs};
 use uuid::Uuid;
 
+/// Room left in a request body for multipart boundaries and part headers
+const MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
+
 /// Configuration for the arpc client
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct ClientConfig {
-    /// Unique ID for this client instance (Mechine Code).
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
+
+    /// Unique ID for this client instance (Mechine Code); defaults to the
+    /// one stored by `arpc login`, if any.
     #[arg(short, long, default_value_t = default_client_id())]
     pub client_id: String,
 
@@ -22,6 +38,28 @@ pub struct ClientConfig {
     #[arg(long, default_value_t = 17002)]
     pub proxy_port: u16,
 
+    /// Another arps server, on the same ports, the client may register with
+    /// (repeatable); the one of these and --server-addr with the lowest
+    /// round-trip time is used
+    #[arg(long = "relay")]
+    pub relays: Vec<String>,
+
+    /// Connect to `host:port` of a server at `ip` instead of looking the
+    /// host up, as `host:port=ip` (repeatable), e.g. when DNS is broken
+    #[arg(long = "resolve")]
+    pub resolves: Vec<ResolveSpec>,
+
+    /// DNS server to look the server hosts up with instead of the system
+    /// resolver, as `ip` or `ip:port` (repeatable); answers are cached for
+    /// their TTL
+    #[arg(long = "dns-server", value_parser = parse_dns_server)]
+    pub dns_servers: Vec<SocketAddr>,
+
+    /// Seconds between round-trip measurements of the servers when --relay
+    /// is given (0 = only at startup)
+    #[arg(long, default_value_t = 300)]
+    pub relay_probe_interval: u64,
+
     /// Address of the local service to expose.
     #[arg(long, default_value = "127.0.0.1")]
     pub local_addr: String,
@@ -30,8 +68,15 @@ pub struct ClientConfig {
     #[arg(long)]
     pub local_port: Option<u16>,
 
-    /// Enable command mode (execute a command instead of TCP proxy)
-    #[arg(long, default_value_t = true)]
+    /// Enable command mode (execute a command instead of TCP proxy).
+    /// `--command-mode=false` forwards connections to the local service.
+    #[arg(
+        long,
+        default_value_t = cfg!(feature = "command-mode"),
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = clap::ArgAction::Set
+    )]
     pub command_mode: bool,
 
     /// Command to execute in command mode
@@ -50,20 +95,406 @@ pub struct ClientConfig {
     #[arg(long, default_value_t = 9021)]
     pub mcp_port: u16,
 
+    /// JSON file (`{"mcpServers": {...}}`) of external MCP servers attached to
+    /// Claude and Gemini runs
+    #[arg(long)]
+    pub mcp_config: Option<PathBuf>,
+
+    /// JSON file (`{"languageServers": {...}}`) of language servers remote
+    /// editors can reach through `/api/lsp/{server}`
+    #[arg(long)]
+    pub lsp_config: Option<PathBuf>,
+
+    /// JSON file restricting the executors, models, permission modes and
+    /// project directories sessions may be created with
+    #[arg(long)]
+    pub session_policy: Option<PathBuf>,
+
+    /// JSON file (`{"approvers": {"<name>": "<token>"}}`) of the people who
+    /// may decide permission prompts; decisions are attributed to the
+    /// approver whose token made them
+    #[arg(long)]
+    pub approvers: Option<PathBuf>,
+
+    /// JSON file (`{"sinks": [...]}`) of webhook, file and stdout sinks that
+    /// session, permission and connection events are delivered to
+    #[arg(long)]
+    pub event_sinks: Option<PathBuf>,
+
+    /// JSON file (`{"hooks": [...]}`) of shell commands and HTTP calls run
+    /// after a session completes successfully
+    #[arg(long)]
+    pub session_hooks: Option<PathBuf>,
+
+    /// JSON file of schedule, SMTP settings and recipients for a daily or
+    /// weekly email digest of agent activity
+    #[arg(long)]
+    pub digest: Option<PathBuf>,
+
+    /// Seconds agent project and session listings are served from cache
+    /// before the history files are scanned again (0 disables caching)
+    #[arg(long, default_value_t = 5)]
+    pub listing_cache_secs: u64,
+
+    /// Retention rule `<executor|all>=<age>[,<size>]` for agent session
+    /// history (repeatable), e.g. `claude=30d,2G`; sessions past the age or
+    /// beyond the size budget are deleted oldest first
+    #[arg(long = "retention")]
+    pub retention: Vec<String>,
+
+    /// Seconds between retention runs
+    #[arg(long, default_value_t = 3600)]
+    pub retention_interval: u64,
+
+    /// Only log what retention would delete
+    #[arg(long)]
+    pub retention_dry_run: bool,
+
+    /// Snapshot git projects before each session runs and after it ends, so
+    /// `POST /api/sessions/{id}/revert` can restore the state before the run
+    #[arg(long)]
+    pub snapshots: bool,
+
+    /// GitHub token `POST /api/sessions/{id}/pr` opens pull requests with;
+    /// `GITHUB_TOKEN` or the keychain when unset
+    #[arg(long)]
+    pub github_token: Option<String>,
+
+    /// GitHub API endpoint, for GitHub Enterprise
+    #[arg(long, default_value = "https://api.github.com")]
+    pub github_api_url: String,
+
+    /// Directory sessions may run in (repeatable); project paths that don't
+    /// resolve to somewhere under a root are rejected
+    #[arg(long = "project-root")]
+    pub project_roots: Vec<PathBuf>,
+
     /// Enable auto-reconnect when connection is lost
     #[arg(long, default_value_t = true)]
     pub auto_reconnect: bool,
 
-    /// Reconnect interval in seconds
+    /// Seconds before the first reconnection attempt; doubled, with
+    /// jitter, after each failed one
     #[arg(long, default_value_t = 5)]
     pub reconnect_interval: u64,
 
+    /// Longest wait in seconds between reconnection attempts
+    #[arg(long, default_value_t = 60)]
+    pub max_reconnect_interval: u64,
+
+    /// Number of proxy connections opened right after registration to pre-warm
+    /// the server-side pool (0 disables pre-warming)
+    #[arg(long, default_value_t = 5)]
+    pub pool_size: usize,
+
+    /// Maximum concurrent proxy connections; further requests are rejected so
+    /// the server can fail them immediately (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    pub max_proxy_connections: usize,
+
+    /// Hostname or subdomain to claim for host-based routing (repeatable)
+    #[arg(long = "hostname")]
+    pub hostnames: Vec<String>,
+
+    /// Raw TCP service to open a public port for on the server, as
+    /// `<local port or addr>[=<public port>]`, e.g. `22` for SSH (repeatable)
+    #[arg(long = "open-port")]
+    pub open_ports: Vec<OpenPortSpec>,
+
+    /// Report busy to the server once this many agent sessions are running
+    /// (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    pub max_sessions: usize,
+
+    /// Seconds between load reports sent on the control channel (0 disables)
+    #[arg(long, default_value_t = 5)]
+    pub load_report_interval: u64,
+
+    /// Seconds between heartbeats on the control channel (0 disables)
+    #[arg(long, default_value_t = 15)]
+    pub heartbeat_interval: u64,
+
+    /// Seconds without hearing from the server after which the control
+    /// connection is considered dead and re-established
+    #[arg(long, default_value_t = 45)]
+    pub heartbeat_timeout: u64,
+
+    /// Redact common secrets (API keys, AWS credentials, private keys, emails)
+    /// from session output
+    #[arg(long)]
+    pub redact: bool,
+
+    /// Extra redaction rule `[mask:|drop:]<regex>` (repeatable); `mask`
+    /// replaces the match, `drop` discards the whole output line
+    #[arg(long = "redact-rule")]
+    pub redact_rules: Vec<String>,
+
+    /// Longest executor output line kept, in bytes (0 = unlimited); longer
+    /// lines such as base64 blobs in tool results are replaced by a
+    /// `truncated` event holding their beginning
+    #[arg(long, default_value_t = 4 * 1024 * 1024)]
+    pub max_output_line_bytes: usize,
+
+    /// What to do on startup with executors a crashed previous run left
+    /// running (adopt | terminate | ignore); adopted ones are streamed from
+    /// their transcripts
+    #[arg(long, default_value = "adopt")]
+    pub orphan_policy: OrphanPolicy,
+
     /// Enable filesystem browsing APIs
     #[arg(long)]
     pub enable_fs: bool,
+
+    /// How the filesystem APIs treat symbolic links (follow | deny); followed
+    /// links must still resolve inside the project directory
+    #[arg(long, default_value = "follow")]
+    pub fs_symlinks: SymlinkPolicy,
+
+    /// Hide dotfiles from the filesystem APIs
+    #[arg(long)]
+    pub fs_hide_hidden: bool,
+
+    /// Refuse to read files larger than this many bytes through the filesystem
+    /// APIs (0 = no limit; contents are truncated to 1 MiB either way)
+    #[arg(long, default_value_t = 0)]
+    pub fs_max_file_size: u64,
+
+    /// Allow uploading files into project directories with
+    /// `POST /api/fs/upload` (needs --enable-fs)
+    #[arg(long)]
+    pub fs_upload: bool,
+
+    /// Largest total size in bytes of the files in one upload
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    pub fs_max_upload_size: u64,
+
+    /// Seconds a request handler may run before the request is answered with
+    /// 504; streaming routes (sessions, file downloads, LSP) are exempt. 0 disables
+    #[arg(long, default_value_t = 300)]
+    pub handler_timeout: u64,
+
+    /// Milliseconds after which a finished request is logged as slow; streaming
+    /// routes are exempt. 0 disables
+    #[arg(long, default_value_t = 5000)]
+    pub slow_request_ms: u64,
+
+    /// Per-direction buffer size (bytes) used when relaying proxied traffic
+    #[arg(long, default_value_t = common::DEFAULT_COPY_BUFFER_SIZE)]
+    pub copy_buffer_size: usize,
+
+    /// Flush policy for tunnel -> local service traffic (immediate | on-idle)
+    #[arg(long, default_value = "immediate")]
+    pub upstream_flush: FlushPolicy,
+
+    /// Flush policy for local service -> tunnel traffic (immediate | on-idle)
+    #[arg(long, default_value = "immediate")]
+    pub downstream_flush: FlushPolicy,
+
+    /// Also POST crash reports, written to the `arpc/crashes` data
+    /// directory, to this URL
+    #[arg(long)]
+    pub crash_report_url: Option<String>,
+
+    /// Encrypt tunnels end to end with the key in `ARPC_E2EE_KEY` (or the
+    /// keychain): the client then only serves `arpc --e2ee connect`, and
+    /// the server relays ciphertext it can't read
+    #[arg(long)]
+    pub e2ee: bool,
+
+    /// Connect to the control and proxy ports over TLS, as servers started
+    /// with `--client-ca` require
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM CA certificates the server's certificate is checked against,
+    /// instead of the built-in web roots
+    #[arg(long, requires = "tls")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// PEM certificate chain presented to the server for mutual TLS
+    #[arg(long, requires_all = ["tls", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Name the server's certificate must be valid for; the address of the
+    /// server connected to when unset
+    #[arg(long, requires = "tls")]
+    pub tls_server_name: Option<String>,
+}
+
+/// Credential management and peer tunnel commands; without one arpc runs
+/// the tunnel.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ClientCommand {
+    /// Store the client ID and agent API keys in the OS keychain, so they
+    /// stay out of shell history and process listings
+    #[cfg(feature = "keyring")]
+    Login {
+        /// Only list which credentials are stored
+        #[arg(long)]
+        status: bool,
+    },
+    /// Remove every credential arpc stored in the keychain
+    #[cfg(feature = "keyring")]
+    Logout,
+    /// Run the tunnel and offer a local TCP service under a name, for other
+    /// clients to reach with `arpc connect <client_id>/<name>`
+    Tunnel {
+        /// Port on --local-addr, or a full `host:port`
+        #[arg(long)]
+        local: String,
+        /// Name the service is reached by
+        #[arg(long)]
+        name: String,
+        /// Let `arpc connect --direct` peers reach the service directly
+        /// through NAT hole punching; they learn this host's public address
+        #[arg(long)]
+        direct: bool,
+    },
+    /// Forward a local port to a service another client offers with
+    /// `arpc tunnel`, or to its usual service, relayed through the server
+    Connect {
+        /// `<client_id>/<name>` of the service, or `<client_id>` for the
+        /// client's usual service
+        service: PeerService,
+        /// Local port or `host:port` to accept connections on; any free
+        /// port on 127.0.0.1 when unset
+        #[arg(long, default_value = "127.0.0.1:0")]
+        listen: String,
+        /// Try a direct connection through NAT hole punching first, relaying
+        /// through the server when it fails
+        #[arg(long)]
+        direct: bool,
+    },
+}
+
+/// A service offered by another client with `arpc tunnel`, or the
+/// client's usual service when unnamed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerService {
+    pub client_id: String,
+    pub name: Option<String>,
+}
+
+impl std::str::FromStr for PeerService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client_id, name) = match s.split_once('/') {
+            Some((client_id, name)) => (client_id, Some(name)),
+            None => (s, None),
+        };
+        if client_id.is_empty() || name.is_some_and(|name| !valid_service_name(name)) {
+            return Err(format!("expected <client_id>[/<name>], got '{}'", s));
+        }
+        Ok(PeerService {
+            client_id: client_id.to_string(),
+            name: name.map(str::to_string),
+        })
+    }
+}
+
+impl std::fmt::Display for PeerService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}/{}", self.client_id, name),
+            None => write!(f, "{}", self.client_id),
+        }
+    }
+}
+
+/// Names of `arpc tunnel` services can't be mistaken for addresses
+fn valid_service_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A local service reachable through a port the server opens for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenPortSpec {
+    /// Port on `local_addr`, or a full `host:port`
+    pub local: String,
+    /// Public port asked for; any the server hands out when unset
+    pub public_port: Option<u16>,
+}
+
+impl std::str::FromStr for OpenPortSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (local, public_port) = match s.rsplit_once('=') {
+            Some((local, port)) => {
+                let port = port
+                    .trim()
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|port| *port != 0)
+                    .ok_or_else(|| format!("invalid public port in '{}'", s))?;
+                (local.trim(), Some(port))
+            }
+            None => (s.trim(), None),
+        };
+        if local.is_empty() {
+            return Err(format!("no local port or address in '{}'", s));
+        }
+        Ok(OpenPortSpec {
+            local: local.to_string(),
+            public_port,
+        })
+    }
+}
+
+/// A `--dns-server`, on port 53 unless it names one
+fn parse_dns_server(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("expected ip or ip:port, got '{}'", s))
+}
+
+/// A server address to connect to at a fixed IP, like curl's `--resolve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveSpec {
+    pub host: String,
+    pub port: u16,
+    pub ip: IpAddr,
+}
+
+impl std::str::FromStr for ResolveSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected host:port=ip, got '{}'", s);
+        let (addr, ip) = s.split_once('=').ok_or_else(invalid)?;
+        let (host, port) = addr.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let ip = ip
+            .trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid IP address in '{}'", s))?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ResolveSpec {
+            host: host.to_ascii_lowercase(),
+            port,
+            ip,
+        })
+    }
 }
 
 fn default_client_id() -> String {
+    #[cfg(feature = "keyring")]
+    if let Ok(Some(client_id)) = crate::credentials::get(crate::credentials::CLIENT_ID) {
+        return client_id;
+    }
     ClientConfig::generate_machine_code()
 }
 
@@ -78,6 +509,37 @@ impl ClientConfig {
         format!("{}:{}", self.server_addr, self.proxy_port)
     }
 
+    /// What a crash report says about the configuration; the client ID and
+    /// tokens are redacted by the reporter.
+    pub fn crash_summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "client_id": self.client_id,
+            "server_addr": self.server_addr,
+            "control_port": self.control_port,
+            "proxy_port": self.proxy_port,
+            "relays": self.relays,
+            "local_service": self.local_service_addr(),
+            "command_mode": self.command_mode,
+            "enable_mcp": self.enable_mcp,
+            "enable_fs": self.enable_fs,
+            "github_token": self.github_token,
+            "hostnames": self.hostnames,
+            "open_ports": self.open_ports.iter().map(|spec| self.open_port_target(spec)).collect::<Vec<_>>(),
+            "pool_size": self.pool_size,
+            "max_proxy_connections": self.max_proxy_connections,
+            "max_sessions": self.max_sessions,
+            "auto_reconnect": self.auto_reconnect,
+            "reconnect_interval": self.reconnect_interval,
+            "max_reconnect_interval": self.max_reconnect_interval,
+            "redact": self.redact,
+            "handler_timeout": self.handler_timeout,
+            "copy_buffer_size": self.copy_buffer_size,
+            "e2ee": self.e2ee,
+            "tls": self.tls,
+            "tls_client_cert": self.tls_cert.is_some(),
+        })
+    }
+
     /// Get the local service address
     pub fn local_service_addr(&self) -> String {
         match self.local_port {
@@ -86,14 +548,90 @@ impl ClientConfig {
         }
     }
 
-    /// Ensure a valid client_id is present, generating one if needed.
-    pub fn ensure_client_id(&mut self) -> bool {
-        if self.client_id.trim().is_empty() {
-            self.client_id = Self::generate_machine_code();
-            true
-        } else {
-            false
+    /// Address connections to an opened port are forwarded to
+    pub fn open_port_target(&self, spec: &OpenPortSpec) -> String {
+        self.local_target(&spec.local)
+    }
+
+    /// Address of the `arpc tunnel` service named `name`, if it may be
+    /// reached directly
+    pub fn direct_service(&self, name: &str) -> Option<String> {
+        match &self.command {
+            Some(ClientCommand::Tunnel {
+                local,
+                name: offered,
+                direct: true,
+            }) if offered == name => Some(self.local_target(local)),
+            _ => None,
+        }
+    }
+
+    /// A port on `local_addr`, or a full address as is
+    fn local_target(&self, local: &str) -> String {
+        match local.parse::<u16>() {
+            Ok(port) => format!("{}:{}", self.local_addr, port),
+            Err(_) => local.to_string(),
+        }
+    }
+
+    /// Address a tunnel requested for `target` is relayed to: the target
+    /// itself when it's an opened port, the service's address when it names
+    /// the one offered with `arpc tunnel`. None for anything else.
+    pub fn resolve_target(&self, target: &str) -> Option<String> {
+        if let Some(ClientCommand::Tunnel { local, name, .. }) = &self.command
+            && name == target
+        {
+            return Some(self.local_target(local));
+        }
+        self.open_ports
+            .iter()
+            .any(|spec| self.open_port_target(spec) == target)
+            .then(|| target.to_string())
+    }
+
+    /// Get the copy tuning used when joining proxied streams
+    pub fn copy_config(&self) -> CopyConfig {
+        CopyConfig {
+            upstream: DirectionConfig {
+                buffer_size: self.copy_buffer_size,
+                flush: self.upstream_flush,
+            },
+            downstream: DirectionConfig {
+                buffer_size: self.copy_buffer_size,
+                flush: self.downstream_flush,
+            },
+        }
+    }
+
+    /// Get the default handler timeout, `None` when disabled
+    pub fn handler_timeout(&self) -> Option<Duration> {
+        (self.handler_timeout > 0).then(|| Duration::from_secs(self.handler_timeout))
+    }
+
+    /// Get the slow request threshold, `None` when disabled
+    pub fn slow_request_threshold(&self) -> Option<Duration> {
+        (self.slow_request_ms > 0).then(|| Duration::from_millis(self.slow_request_ms))
+    }
+
+    /// Get the restrictions applied by the filesystem APIs
+    pub fn fs_options(&self) -> FsOptions {
+        FsOptions {
+            symlinks: self.fs_symlinks,
+            hide_hidden: self.fs_hide_hidden,
+            max_file_size: self.fs_max_file_size,
+        }
+    }
+
+    /// Get the limits applied to requests in command mode; bodies are only
+    /// capped when uploads are enabled, leaving room for the multipart framing
+    pub fn request_limits(&self) -> ParseLimits {
+        let mut limits = ParseLimits::default();
+        if self.enable_fs && self.fs_upload {
+            limits.max_body_bytes = usize::try_from(self.fs_max_upload_size)
+                .unwrap_or(usize::MAX)
+                .saturating_add(MULTIPART_OVERHEAD_BYTES);
         }
+        limits
     }
 
     /// Validate the configuration
@@ -122,10 +660,17 @@ impl ClientConfig {
             return Err("local_port cannot be 0 when not in command_mode".to_string());
         }
 
+        if self.copy_buffer_size == 0 {
+            return Err("copy_buffer_size cannot be 0".to_string());
+        }
+
         // Validate server address is not empty
         if self.server_addr.trim().is_empty() {
             return Err("server_addr cannot be empty".to_string());
         }
+        if self.relays.iter().any(|relay| relay.trim().is_empty()) {
+            return Err("relay cannot be empty".to_string());
+        }
 
         // Validate local address is not empty when not in command mode
         if !self.command_mode && self.local_addr.trim().is_empty() {
@@ -133,12 +678,166 @@ impl ClientConfig {
         }
 
         // Validate command_path exists if provided
-        if let Some(ref cmd_path) = self.command_path {
-            if !cmd_path.trim().is_empty() && !std::path::Path::new(cmd_path).exists() {
-                return Err(format!("command_path does not exist: {}", cmd_path));
+        if let Some(ref cmd_path) = self.command_path
+            && !cmd_path.trim().is_empty()
+            && !std::path::Path::new(cmd_path).exists()
+        {
+            return Err(format!("command_path does not exist: {}", cmd_path));
+        }
+
+        #[cfg(feature = "executors")]
+        Redactor::new(self.redact, &self.redact_rules)?;
+
+        if let Some(ClientCommand::Tunnel { local, name, .. }) = &self.command {
+            if !valid_service_name(name) {
+                return Err(format!(
+                    "invalid service name '{}': use letters, digits, '-' and '_'",
+                    name
+                ));
+            }
+            if local.trim().is_empty() {
+                return Err("tunnel --local cannot be empty".to_string());
             }
         }
 
+        // Options for parts left out of this build at compile time
+        #[cfg(not(feature = "command-mode"))]
+        if self.command_mode {
+            return Err("command_mode requires the `command-mode` feature".to_string());
+        }
+        #[cfg(not(feature = "dns"))]
+        if !self.dns_servers.is_empty() {
+            return Err("dns_server requires the `dns` feature".to_string());
+        }
+        #[cfg(not(feature = "lsp"))]
+        if self.lsp_config.is_some() {
+            return Err("lsp_config requires the `lsp` feature".to_string());
+        }
+        #[cfg(not(feature = "tls"))]
+        if self.tls {
+            return Err("tls requires the `tls` feature".to_string());
+        }
+        #[cfg(not(feature = "executors"))]
+        if self.session_policy.is_some() || self.approvers.is_some() {
+            return Err("session_policy and approvers require the `executors` feature".to_string());
+        }
+        #[cfg(not(feature = "executors"))]
+        if self.redact || !self.redact_rules.is_empty() {
+            return Err("redact and redact_rule require the `executors` feature".to_string());
+        }
+        #[cfg(not(feature = "executors"))]
+        if !self.retention.is_empty() {
+            return Err("retention requires the `executors` feature".to_string());
+        }
+        #[cfg(feature = "executors")]
+        crate::agentx::storage::RetentionRules::parse(&self.retention)?;
+        #[cfg(feature = "executors")]
+        crate::agentx::encryption::StorageKey::load()?;
+        if !self.retention.is_empty() && self.retention_interval == 0 {
+            return Err("retention_interval cannot be 0".to_string());
+        }
+        #[cfg(not(feature = "events"))]
+        if self.event_sinks.is_some() {
+            return Err("event_sinks requires the `events` feature".to_string());
+        }
+        #[cfg(not(feature = "events"))]
+        if self.crash_report_url.is_some() {
+            return Err("crash_report_url requires the `events` feature".to_string());
+        }
+        #[cfg(not(feature = "e2ee"))]
+        if self.e2ee {
+            return Err("e2ee requires the `e2ee` feature".to_string());
+        }
+        #[cfg(feature = "e2ee")]
+        if self.e2ee && crate::e2ee::E2eeKey::load()?.is_none() {
+            return Err(format!(
+                "e2ee needs a key in {} or the keychain",
+                crate::e2ee::KEY_ENV
+            ));
+        }
+        if let Some(ClientCommand::Connect {
+            service,
+            direct: true,
+            ..
+        }) = &self.command
+            && service.name.is_none()
+        {
+            return Err("connect --direct needs a <client_id>/<name> service".to_string());
+        }
+        #[cfg(not(feature = "hooks"))]
+        if self.session_hooks.is_some() {
+            return Err("session_hooks requires the `hooks` feature".to_string());
+        }
+        #[cfg(not(feature = "digest"))]
+        if self.digest.is_some() {
+            return Err("digest requires the `digest` feature".to_string());
+        }
+        #[cfg(not(feature = "github"))]
+        if self.github_token.is_some() {
+            return Err("github_token requires the `github` feature".to_string());
+        }
+        #[cfg(not(feature = "mcp"))]
+        if self.enable_mcp || self.mcp_config.is_some() {
+            return Err("enable_mcp and mcp_config require the `mcp` feature".to_string());
+        }
+        #[cfg(not(feature = "fs"))]
+        if self.enable_fs {
+            return Err("enable_fs requires the `fs` feature".to_string());
+        }
+
+        if let Some(ref path) = self.mcp_config
+            && !path.is_file()
+        {
+            return Err(format!("mcp_config does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.lsp_config
+            && !path.is_file()
+        {
+            return Err(format!("lsp_config does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.session_policy
+            && !path.is_file()
+        {
+            return Err(format!("session_policy does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.approvers
+            && !path.is_file()
+        {
+            return Err(format!("approvers does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.event_sinks
+            && !path.is_file()
+        {
+            return Err(format!("event_sinks does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.session_hooks
+            && !path.is_file()
+        {
+            return Err(format!("session_hooks does not exist: {}", path.display()));
+        }
+
+        if let Some(ref path) = self.digest
+            && !path.is_file()
+        {
+            return Err(format!("digest does not exist: {}", path.display()));
+        }
+
+        if self.fs_upload && !self.enable_fs {
+            return Err("fs_upload requires enable_fs".to_string());
+        }
+
+        if let Some(root) = self.project_roots.iter().find(|root| !root.is_dir()) {
+            return Err(format!(
+                "project_root is not a directory: {}",
+                root.display()
+            ));
+        }
+
         // Validate MCP port is different from control and proxy ports
         if self.enable_mcp {
             if self.mcp_port == self.control_port {
@@ -183,10 +882,10 @@ impl ClientConfig {
             parts.push(format!("machine_id:{machine_id}"));
         }
 
-        if let Ok(user) = env::var("USER").or_else(|_| env::var("USERNAME")) {
-            if !user.is_empty() {
-                parts.push(format!("user:{user}"));
-            }
+        if let Ok(user) = env::var("USER").or_else(|_| env::var("USERNAME"))
+            && !user.is_empty()
+        {
+            parts.push(format!("user:{user}"));
         }
 
         parts.push(format!("os:{}", env::consts::OS));
@@ -0,0 +1,162 @@
+use crate::config::ClientCommand;
+#[cfg(feature = "executors")]
+use crate::executor::ExecutorKind;
+use anyhow::{Result, anyhow};
+use keyring::Entry;
+#[cfg(feature = "executors")]
+use std::collections::HashMap;
+#[cfg(feature = "executors")]
+use std::sync::OnceLock;
+
+/// Keychain service all arpc credentials are stored under
+const SERVICE: &str = "arpc";
+
+/// Entry holding the client ID, the token public requests are routed by
+pub const CLIENT_ID: &str = "client-id";
+
+/// Secrets stored under the environment variable they stand in for
+const SECRETS: [(&str, &str); 9] = [
+    ("ANTHROPIC_API_KEY", "Anthropic API key (claude)"),
+    ("OPENAI_API_KEY", "OpenAI API key (codex)"),
+    ("GEMINI_API_KEY", "Gemini API key (gemini)"),
+    ("GITHUB_TOKEN", "GitHub token (pull requests)"),
+    ("ARPC_SMTP_PASSWORD", "SMTP password (email digest)"),
+    (
+        "ARPC_STORAGE_KEY",
+        "Archive encryption key (64 hex characters)",
+    ),
+    ("ARPC_E2EE_KEY", "End-to-end tunnel key (64 hex characters)"),
+    ("ARPC_AUTH_KEY", "Server auth key (signed control commands)"),
+    ("ARPC_TENANT_TOKEN", "Tenant token (shared servers)"),
+];
+
+/// Read a stored credential. A machine without a usable keychain (say, a
+/// headless server with no secret service) simply has nothing stored.
+pub fn get(name: &str) -> Result<Option<String>, String> {
+    match Entry::new(SERVICE, name).and_then(|entry| entry.get_password()) {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e @ (keyring::Error::NoStorageAccess(_) | keyring::Error::PlatformFailure(_))) => {
+            tracing::debug!("Keychain unavailable reading {}: {}", name, e);
+            Ok(None)
+        }
+        Err(e) => Err(format!("Failed to read {} from the keychain: {}", name, e)),
+    }
+}
+
+fn set(name: &str, value: &str) -> Result<()> {
+    Entry::new(SERVICE, name)
+        .and_then(|entry| entry.set_password(value))
+        .map_err(|e| anyhow!("Failed to store {} in the keychain: {}", name, e))
+}
+
+/// Remove a credential; `false` when none was stored.
+fn delete(name: &str) -> Result<bool> {
+    match Entry::new(SERVICE, name).and_then(|entry| entry.delete_credential()) {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(anyhow!(
+            "Failed to remove {} from the keychain: {}",
+            name,
+            e
+        )),
+    }
+}
+
+#[cfg(feature = "executors")]
+fn api_key_name(kind: ExecutorKind) -> &'static str {
+    match kind {
+        ExecutorKind::Claude => "ANTHROPIC_API_KEY",
+        ExecutorKind::Codex => "OPENAI_API_KEY",
+        ExecutorKind::Gemini => "GEMINI_API_KEY",
+    }
+}
+
+/// Environment an agent gets from the keychain: its API key, unless the
+/// variable is already set. The keychain is read once per process.
+#[cfg(feature = "executors")]
+pub fn agent_env(kind: ExecutorKind) -> Option<(&'static str, String)> {
+    static API_KEYS: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+    let keys = API_KEYS.get_or_init(|| {
+        [
+            ExecutorKind::Claude,
+            ExecutorKind::Codex,
+            ExecutorKind::Gemini,
+        ]
+        .into_iter()
+        .map(api_key_name)
+        .filter(|name| std::env::var_os(name).is_none())
+        .filter_map(|name| match get(name) {
+            Ok(value) => Some((name, value?)),
+            Err(e) => {
+                tracing::warn!("{}", e);
+                None
+            }
+        })
+        .collect()
+    });
+    let name = api_key_name(kind);
+    keys.get(name).map(|value| (name, value.clone()))
+}
+
+/// Run `arpc login` or `arpc logout`; other commands aren't handled here.
+pub fn run(command: &ClientCommand) -> Result<()> {
+    match command {
+        ClientCommand::Login { status: true } => {
+            for (name, label) in entries() {
+                let stored = get(name).map_err(|e| anyhow!(e))?.is_some();
+                println!(
+                    "{:<18} {:<44} {}",
+                    name,
+                    label,
+                    if stored { "stored" } else { "-" }
+                );
+            }
+            Ok(())
+        }
+        ClientCommand::Login { status: false } => login(),
+        ClientCommand::Logout => {
+            let mut removed = 0;
+            for (name, _) in entries() {
+                if delete(name)? {
+                    removed += 1;
+                }
+            }
+            println!("Removed {} credentials from the keychain", removed);
+            Ok(())
+        }
+        ClientCommand::Tunnel { .. } | ClientCommand::Connect { .. } => {
+            Err(anyhow!("not a credential command"))
+        }
+    }
+}
+
+fn entries() -> impl Iterator<Item = (&'static str, &'static str)> {
+    std::iter::once((CLIENT_ID, "Client ID (token)")).chain(SECRETS)
+}
+
+/// Prompt for each credential without echo; blank input keeps what's stored.
+fn login() -> Result<()> {
+    println!("Press Enter to keep a stored value or skip an unused one.");
+    let mut stored = 0;
+    for (name, label) in entries() {
+        let current = if get(name).map_err(|e| anyhow!(e))?.is_some() {
+            " [stored]"
+        } else {
+            ""
+        };
+        let value = rpassword::prompt_password(format!("{}{}: ", label, current))?;
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        #[cfg(feature = "executors")]
+        if name == crate::agentx::encryption::KEY_ENV {
+            crate::agentx::encryption::StorageKey::parse(value).map_err(|e| anyhow!(e))?;
+        }
+        set(name, value)?;
+        stored += 1;
+    }
+    println!("Stored {} credentials in the keychain", stored);
+    Ok(())
+}
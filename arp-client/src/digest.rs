@@ -0,0 +1,494 @@
+use crate::session::{SessionEnded, SessionManager, StalePermission};
+use anyhow::{Context, Result, anyhow};
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDateTime, NaiveTime, Weekday};
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Environment variable (and keychain entry) the SMTP password is read from
+/// when the digest file doesn't set one
+pub const PASSWORD_ENV: &str = "ARPC_SMTP_PASSWORD";
+
+/// Failures listed in one digest; the rest are only counted
+const MAX_LISTED_FAILURES: usize = 20;
+
+/// A periodic email summarizing agent activity, loaded from a JSON file:
+///
+/// ```json
+/// {
+///   "schedule": "weekly", "day": "monday", "at": "08:00",
+///   "to": ["lead@example.com"], "from": "arpc <arpc@example.com>",
+///   "smtp": {"host": "smtp.example.com", "username": "arpc"},
+///   "stale_permission_hours": 4
+/// }
+/// ```
+///
+/// `at` is local time. The statistics are kept in memory, so a digest
+/// covers the sessions since the previous one or since arpc started.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailDigest {
+    #[serde(default)]
+    schedule: Schedule,
+    /// Day of the week for weekly digests
+    #[serde(default = "default_day")]
+    day: String,
+    #[serde(default = "default_at")]
+    at: String,
+    to: Vec<String>,
+    from: String,
+    smtp: SmtpSettings,
+    /// Pending permission prompts at least this old are listed
+    #[serde(default = "default_stale_permission_hours")]
+    stale_permission_hours: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Schedule {
+    #[default]
+    Daily,
+    Weekly,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SmtpSettings {
+    host: String,
+    /// 587 for `starttls`, 465 for `tls`, 25 for `none` when unset
+    port: Option<u16>,
+    #[serde(default)]
+    tls: SmtpTls,
+    username: Option<String>,
+    /// Falls back to `ARPC_SMTP_PASSWORD` or the keychain
+    password: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SmtpTls {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+fn default_day() -> String {
+    "monday".to_string()
+}
+
+fn default_at() -> String {
+    "08:00".to_string()
+}
+
+fn default_stale_permission_hours() -> u64 {
+    4
+}
+
+impl EmailDigest {
+    /// Load the digest settings from `path`; no path means no digest
+    pub fn load(path: Option<&Path>) -> Result<Option<Self>> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read email digest {}", path.display()))?;
+        let digest: EmailDigest = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid email digest {}: {}", path.display(), e))?;
+        digest
+            .time()
+            .with_context(|| format!("Invalid email digest {}", path.display()))?;
+        digest
+            .weekday()
+            .with_context(|| format!("Invalid email digest {}", path.display()))?;
+        if digest.to.is_empty() {
+            return Err(anyhow!("Email digest {} has no recipients", path.display()));
+        }
+        digest
+            .message("", String::new())
+            .with_context(|| format!("Invalid email digest addresses in {}", path.display()))?;
+
+        info!(
+            "Loaded {:?} email digest to {} from {}",
+            digest.schedule,
+            digest.to.join(", "),
+            path.display()
+        );
+        Ok(Some(digest))
+    }
+
+    fn time(&self) -> Result<NaiveTime> {
+        NaiveTime::parse_from_str(&self.at, "%H:%M")
+            .map_err(|_| anyhow!("`at` must be HH:MM, got '{}'", self.at))
+    }
+
+    fn weekday(&self) -> Result<Weekday> {
+        self.day
+            .parse()
+            .map_err(|_| anyhow!("`day` must be a weekday, got '{}'", self.day))
+    }
+
+    /// When the digest after `now` is due, in local time
+    fn next_run(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let at = self.time().unwrap_or_default();
+        let mut next = now.date().and_time(at);
+        if self.schedule == Schedule::Weekly {
+            let target = self.weekday().unwrap_or(Weekday::Mon);
+            let days = (7 + target.num_days_from_monday() as i64
+                - next.weekday().num_days_from_monday() as i64)
+                % 7;
+            next += ChronoDuration::days(days);
+        }
+        if next <= now {
+            next += ChronoDuration::days(match self.schedule {
+                Schedule::Daily => 1,
+                Schedule::Weekly => 7,
+            });
+        }
+        next
+    }
+
+    /// Collect session outcomes and send a digest on schedule
+    pub fn start(self, session_manager: SessionManager) {
+        let activity = Arc::new(Mutex::new(Activity::default()));
+        tokio::spawn(collect(
+            session_manager.subscribe_ended(),
+            session_manager.clone(),
+            activity.clone(),
+        ));
+        tokio::spawn(async move {
+            let mut since = Local::now().naive_local();
+            loop {
+                let next = self.next_run(Local::now().naive_local());
+                let due = next.and_local_timezone(Local).earliest().or_else(|| {
+                    // A time skipped by a DST change exists an hour later
+                    (next + ChronoDuration::hours(1))
+                        .and_local_timezone(Local)
+                        .earliest()
+                });
+                let wait = due
+                    .and_then(|due| due.signed_duration_since(Local::now()).to_std().ok())
+                    .unwrap_or_default();
+                tokio::time::sleep(wait).await;
+
+                let sessions = std::mem::take(&mut activity.lock().unwrap().sessions);
+                let stale = session_manager
+                    .stale_permissions(Duration::from_secs(self.stale_permission_hours * 3600))
+                    .await;
+                let now = Local::now().naive_local();
+                let (subject, body) = self.render(since, now, &sessions, &stale);
+                match self.send(&subject, body).await {
+                    Ok(()) => info!("Sent email digest to {}", self.to.join(", ")),
+                    Err(e) => warn!("Failed to send email digest: {}", e),
+                }
+                since = now;
+            }
+        });
+    }
+
+    fn render(
+        &self,
+        since: NaiveDateTime,
+        until: NaiveDateTime,
+        sessions: &[SessionRecord],
+        stale: &[StalePermission],
+    ) -> (String, String) {
+        let hostname = hostname::get()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "arpc".to_string());
+        let count = |status: &str| sessions.iter().filter(|s| s.status == status).count();
+        let failed = count("failed");
+
+        let mut body = String::new();
+        let _ = writeln!(
+            body,
+            "Agent activity on {} from {} to {}\n",
+            hostname,
+            since.format("%Y-%m-%d %H:%M"),
+            until.format("%Y-%m-%d %H:%M")
+        );
+        let _ = writeln!(
+            body,
+            "Sessions: {} ({} completed, {} failed, {} cancelled)",
+            sessions.len(),
+            count("completed"),
+            failed,
+            count("cancelled")
+        );
+        let mut executors: Vec<(&str, usize)> = Vec::new();
+        for session in sessions {
+            match executors
+                .iter_mut()
+                .find(|(name, _)| *name == session.executor)
+            {
+                Some((_, n)) => *n += 1,
+                None => executors.push((session.executor, 1)),
+            }
+        }
+        if !executors.is_empty() {
+            let by_agent: Vec<String> = executors
+                .iter()
+                .map(|(name, n)| format!("{} {}", name, n))
+                .collect();
+            let _ = writeln!(body, "By agent: {}", by_agent.join(", "));
+        }
+        let costs: Vec<f64> = sessions.iter().filter_map(|s| s.cost_usd).collect();
+        if !costs.is_empty() {
+            let _ = writeln!(
+                body,
+                "Cost: ${:.2} across the {} sessions that reported one",
+                costs.iter().sum::<f64>(),
+                costs.len()
+            );
+        }
+
+        if failed > 0 {
+            let _ = writeln!(body, "\nFailures:");
+            for session in sessions
+                .iter()
+                .filter(|s| s.status == "failed")
+                .take(MAX_LISTED_FAILURES)
+            {
+                let _ = writeln!(
+                    body,
+                    "- {} ({}) in {}: {}",
+                    session.session_id,
+                    session.executor,
+                    session.project_path.as_deref().unwrap_or("-"),
+                    session.reason.as_deref().unwrap_or("failed")
+                );
+            }
+            if failed > MAX_LISTED_FAILURES {
+                let _ = writeln!(body, "- and {} more", failed - MAX_LISTED_FAILURES);
+            }
+        }
+
+        if !stale.is_empty() {
+            let _ = writeln!(
+                body,
+                "\nPermission prompts waiting over {}h:",
+                self.stale_permission_hours
+            );
+            for permission in stale {
+                let _ = writeln!(
+                    body,
+                    "- session {} wants {} ({}h, permission {})",
+                    permission.session_id,
+                    permission.tool_name,
+                    permission.waiting.as_secs() / 3600,
+                    permission.permission_id
+                );
+            }
+        }
+
+        let mut subject = format!("arpc digest for {}: {} sessions", hostname, sessions.len());
+        if failed > 0 {
+            let _ = write!(subject, ", {} failed", failed);
+        }
+        if !stale.is_empty() {
+            let _ = write!(subject, ", {} waiting for permission", stale.len());
+        }
+        (subject, body)
+    }
+
+    fn message(&self, subject: &str, body: String) -> Result<Message> {
+        let mut message = Message::builder()
+            .from(self.from.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.parse()?);
+        }
+        Ok(message.body(body)?)
+    }
+
+    async fn send(&self, subject: &str, body: String) -> Result<()> {
+        let smtp = &self.smtp;
+        let (builder, default_port) = match smtp.tls {
+            SmtpTls::Starttls => (
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+                587,
+            ),
+            SmtpTls::Tls => (
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+                465,
+            ),
+            SmtpTls::None => (
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+                25,
+            ),
+        };
+        let mut builder = builder.port(smtp.port.unwrap_or(default_port));
+        if let Some(username) = &smtp.username {
+            let password = match &smtp.password {
+                Some(password) => password.clone(),
+                None => password_from_environment()?.ok_or_else(|| {
+                    anyhow!("No SMTP password set (password or {})", PASSWORD_ENV)
+                })?,
+            };
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+        builder.build().send(self.message(subject, body)?).await?;
+        Ok(())
+    }
+}
+
+fn password_from_environment() -> Result<Option<String>> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(Some(password));
+    }
+    #[cfg(feature = "keyring")]
+    if let Some(password) = crate::credentials::get(PASSWORD_ENV).map_err(|e| anyhow!(e))? {
+        return Ok(Some(password));
+    }
+    Ok(None)
+}
+
+/// Sessions that ended since the last digest
+#[derive(Default)]
+struct Activity {
+    sessions: Vec<SessionRecord>,
+}
+
+struct SessionRecord {
+    session_id: String,
+    executor: &'static str,
+    status: &'static str,
+    project_path: Option<String>,
+    /// Error or exit code of a failed session
+    reason: Option<String>,
+    /// As reported by the agent; only Claude reports one
+    cost_usd: Option<f64>,
+}
+
+async fn collect(
+    mut ended: broadcast::Receiver<SessionEnded>,
+    session_manager: SessionManager,
+    activity: Arc<Mutex<Activity>>,
+) {
+    loop {
+        let session = match ended.recv().await {
+            Ok(session) => session,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Email digest missed {} ended sessions", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (status, exit_code, error) = session.status.outcome();
+        let cost_usd = match session_manager.get_session(&session.session_id).await {
+            Some(running) => running
+                .get_output_from(0)
+                .await
+                .iter()
+                .rev()
+                .find_map(|line| reported_cost(&line.content)),
+            None => None,
+        };
+        activity.lock().unwrap().sessions.push(SessionRecord {
+            session_id: session.session_id,
+            executor: session.executor_kind.as_str(),
+            status,
+            project_path: session
+                .project_path
+                .map(|path| path.to_string_lossy().into_owned()),
+            reason: error.or_else(|| exit_code.map(|code| format!("exit code {}", code))),
+            cost_usd,
+        });
+    }
+}
+
+/// `total_cost_usd` of a Claude `result` line
+fn reported_cost(line: &str) -> Option<f64> {
+    if !line.contains("total_cost_usd") {
+        return None;
+    }
+    serde_json::from_str::<Value>(line)
+        .ok()?
+        .get("total_cost_usd")?
+        .as_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn digest(settings: Value) -> EmailDigest {
+        let mut config = serde_json::json!({
+            "to": ["lead@example.com"],
+            "from": "arpc@example.com",
+            "smtp": {"host": "localhost"},
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2026-06-01 is a Monday
+        NaiveDate::from_ymd_opt(2026, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn digests_are_scheduled_at_the_next_slot() {
+        let daily = digest(serde_json::json!({"at": "08:30"}));
+        assert_eq!(daily.next_run(at(3, 7, 0)), at(3, 8, 30));
+        assert_eq!(daily.next_run(at(3, 8, 30)), at(4, 8, 30));
+
+        let weekly = digest(serde_json::json!({"schedule": "weekly", "day": "wed", "at": "09:00"}));
+        assert_eq!(weekly.next_run(at(1, 12, 0)), at(3, 9, 0));
+        assert_eq!(weekly.next_run(at(3, 10, 0)), at(10, 9, 0));
+    }
+
+    #[test]
+    fn digest_lists_failures_costs_and_stale_permissions() {
+        let digest = digest(serde_json::json!({}));
+        let sessions = vec![
+            SessionRecord {
+                session_id: "s1".to_string(),
+                executor: "claude",
+                status: "completed",
+                project_path: Some("/work/app".to_string()),
+                reason: None,
+                cost_usd: reported_cost(r#"{"type":"result","total_cost_usd":0.25}"#),
+            },
+            SessionRecord {
+                session_id: "s2".to_string(),
+                executor: "codex",
+                status: "failed",
+                project_path: Some("/work/api".to_string()),
+                reason: Some("exit code 2".to_string()),
+                cost_usd: None,
+            },
+        ];
+        let stale = vec![StalePermission {
+            session_id: "s3".to_string(),
+            permission_id: "p1".to_string(),
+            tool_name: "Bash".to_string(),
+            waiting: Duration::from_secs(5 * 3600),
+        }];
+
+        let (subject, body) = digest.render(at(1, 8, 0), at(2, 8, 0), &sessions, &stale);
+        assert!(subject.ends_with(": 2 sessions, 1 failed, 1 waiting for permission"));
+        assert!(body.contains("Sessions: 2 (1 completed, 1 failed, 0 cancelled)"));
+        assert!(body.contains("By agent: claude 1, codex 1"));
+        assert!(body.contains("Cost: $0.25 across the 1 sessions"));
+        assert!(body.contains("- s2 (codex) in /work/api: exit code 2"));
+        assert!(body.contains("- session s3 wants Bash (5h, permission p1)"));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::router::loopback_pair;
+use anyhow::{Result, anyhow};
+use snow::{Builder, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Environment variable holding the tunnel key as 64 hex characters
+pub const KEY_ENV: &str = "ARPC_E2EE_KEY";
+
+/// Both sides prove they hold the key in the handshake itself (psk0), so
+/// neither needs a static key pair.
+const PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// Mixed into the handshake so keys only ever agree between arpc tunnels
+/// speaking the same framing
+const PROLOGUE: &[u8] = b"arpc-e2ee-2";
+
+/// Largest Noise message, and so the largest frame on the wire
+const MAX_MESSAGE: usize = 65535;
+
+/// Authentication tag each transport message carries
+const TAG_LEN: usize = 16;
+
+/// Plaintext sealed per message
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Key shared by a client and the users who may reach it; the relay never
+/// sees it, only what it encrypts.
+pub struct E2eeKey([u8; 32]);
+
+impl E2eeKey {
+    /// The key from `ARPC_E2EE_KEY`, or from the keychain when the variable
+    /// isn't set; `Ok(None)` when there is neither.
+    pub fn load() -> Result<Option<Self>, String> {
+        if let Ok(value) = std::env::var(KEY_ENV) {
+            return Self::parse(&value).map(Some);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some(value) = crate::credentials::get(KEY_ENV)? {
+            return Self::parse(&value).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let invalid = || format!("{} must be 64 hex characters (32 bytes)", KEY_ENV);
+        if value.len() != 64 || !value.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; 32];
+        for (byte, i) in key.iter_mut().zip((0..64).step_by(2)) {
+            *byte = u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(E2eeKey(key))
+    }
+}
+
+/// Encrypt `plain` end to end over `stream` and decrypt what comes back:
+/// `arpc connect` is the `initiator`, the client answering it is not.
+pub async fn relay<S, P>(mut stream: S, plain: P, key: &E2eeKey, initiator: bool) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    let transport = handshake(&mut stream, key, initiator).await?;
+    pump(stream, plain, &transport).await
+}
+
+/// Terminate the encryption of a tunnel on the client. Once the handshake
+/// is done the returned loopback stream carries the plaintext, so the
+/// tunnel is served as if it had never been encrypted.
+pub async fn accept(mut tunnel: TcpStream, key: &E2eeKey) -> Result<TcpStream> {
+    let transport = handshake(&mut tunnel, key, false).await?;
+    let (plain, bridge) = loopback_pair().await?;
+    tokio::spawn(async move {
+        if let Err(e) = pump(tunnel, bridge, &transport).await {
+            tracing::debug!("Encrypted tunnel ended: {}", e);
+        }
+    });
+    Ok(plain)
+}
+
+/// Run the Noise handshake, failing when the other side has another key.
+async fn handshake<S>(
+    stream: &mut S,
+    key: &E2eeKey,
+    initiator: bool,
+) -> Result<StatelessTransportState>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let builder = Builder::new(PARAMS.parse()?)
+        .prologue(PROLOGUE)
+        .psk(0, &key.0);
+    let mut state = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+    let mut message = vec![0u8; MAX_MESSAGE];
+    let mut payload = vec![0u8; MAX_MESSAGE];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut message)?;
+            write_frame(stream, &message[..len]).await?;
+        } else {
+            let len = read_frame(stream, &mut message)
+                .await?
+                .ok_or_else(|| anyhow!("Connection closed during the handshake"))?;
+            state
+                .read_message(&message[..len], &mut payload)
+                .map_err(|_| {
+                    anyhow!("Handshake failed; do both sides use the same {}?", KEY_ENV)
+                })?;
+        }
+    }
+    Ok(state.into_stateless_transport_mode()?)
+}
+
+/// Relay between the encrypted `stream` and `plain` until both directions
+/// are done.
+async fn pump<S, P>(stream: S, plain: P, transport: &StatelessTransportState) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    let (encrypted_rx, encrypted_tx) = tokio::io::split(stream);
+    let (plain_rx, plain_tx) = tokio::io::split(plain);
+    tokio::try_join!(
+        seal(plain_rx, encrypted_tx, transport),
+        open(encrypted_rx, plain_tx, transport),
+    )?;
+    Ok(())
+}
+
+/// Encrypt everything read from `plain` into frames on `stream`, ending
+/// with an empty sealed message so the other side can tell the end of the
+/// data from a relay cutting the connection.
+async fn seal<R, W>(mut plain: R, mut stream: W, transport: &StatelessTransportState) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut message = vec![0u8; CHUNK_SIZE + TAG_LEN];
+    for nonce in 0.. {
+        let n = plain.read(&mut chunk).await?;
+        let len = transport.write_message(nonce, &chunk[..n], &mut message)?;
+        write_frame(&mut stream, &message[..len]).await?;
+        if n == 0 {
+            break;
+        }
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Decrypt the frames read from `stream` into `plain` up to the empty
+/// message that ends them; a frame that was tampered with, replayed or
+/// reordered, or the stream ending before that message, fails the
+/// connection.
+async fn open<R, W>(mut stream: R, mut plain: W, transport: &StatelessTransportState) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut message = vec![0u8; MAX_MESSAGE];
+    let mut chunk = vec![0u8; MAX_MESSAGE];
+    for nonce in 0.. {
+        let len = read_frame(&mut stream, &mut message)
+            .await?
+            .ok_or_else(|| anyhow!("Encrypted stream was cut off after message {}", nonce))?;
+        let n = transport
+            .read_message(nonce, &message[..len], &mut chunk)
+            .map_err(|_| anyhow!("Invalid encrypted message {}", nonce))?;
+        if n == 0 {
+            break;
+        }
+        plain.write_all(&chunk[..n]).await?;
+    }
+    plain.shutdown().await?;
+    Ok(())
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, message: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Read one frame into `buf`; None when the stream ended between frames.
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut [u8]) -> Result<Option<usize>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    stream.read_exact(&mut buf[..len]).await?;
+    Ok(Some(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn the_relay_only_sees_ciphertext() {
+        let key = E2eeKey::parse(&"ab".repeat(32)).unwrap();
+        let (mut user_app, user_plain) = duplex(64 * 1024);
+        let (user_encrypted, relay_a) = duplex(64 * 1024);
+        let (relay_b, client_encrypted) = duplex(64 * 1024);
+        let (client_plain, mut client_app) = duplex(64 * 1024);
+
+        // The relay forwards, keeping what it saw on the way to the client
+        let (mut a_rx, mut a_tx) = tokio::io::split(relay_a);
+        let (mut b_rx, mut b_tx) = tokio::io::split(relay_b);
+        let seen = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = a_rx.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                seen.extend_from_slice(&buf[..n]);
+                b_tx.write_all(&buf[..n]).await.unwrap();
+            }
+            b_tx.shutdown().await.unwrap();
+            seen
+        });
+        tokio::spawn(async move { tokio::io::copy(&mut b_rx, &mut a_tx).await });
+
+        let key_copy = E2eeKey(key.0);
+        tokio::spawn(async move { relay(user_encrypted, user_plain, &key, true).await });
+        tokio::spawn(async move { relay(client_encrypted, client_plain, &key_copy, false).await });
+
+        user_app.write_all(b"GET /api/sessions").await.unwrap();
+        user_app.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client_app.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"GET /api/sessions");
+
+        let seen = seen.await.unwrap();
+        assert!(!seen.is_empty());
+        assert!(!seen.windows(4).any(|w| w == b"/api"));
+    }
+
+    #[tokio::test]
+    async fn a_wrong_key_fails_the_handshake() {
+        let key = E2eeKey::parse(&"ab".repeat(32)).unwrap();
+        let other = E2eeKey::parse(&"cd".repeat(32)).unwrap();
+        let (user, client) = duplex(64 * 1024);
+        let (user_plain, _user_app) = duplex(1024);
+        let (client_plain, _client_app) = duplex(1024);
+
+        let (user, client) = tokio::join!(
+            relay(user, user_plain, &key, true),
+            relay(client, client_plain, &other, false),
+        );
+        assert!(user.is_err());
+        assert!(client.is_err());
+
+        assert!(E2eeKey::parse("abcd").is_err());
+        assert!(E2eeKey::parse(&"zz".repeat(32)).is_err());
+    }
+
+    /// Transport states of both ends of a finished handshake
+    async fn transports(key: &E2eeKey) -> (StatelessTransportState, StatelessTransportState) {
+        let (mut a, mut b) = duplex(64 * 1024);
+        let (initiator, responder) =
+            tokio::join!(handshake(&mut a, key, true), handshake(&mut b, key, false));
+        (initiator.unwrap(), responder.unwrap())
+    }
+
+    /// Lengths of the frames `ciphertext` is made of
+    fn frame_ends(ciphertext: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut at = 0;
+        while at < ciphertext.len() {
+            at += 2 + u16::from_be_bytes([ciphertext[at], ciphertext[at + 1]]) as usize;
+            ends.push(at);
+        }
+        ends
+    }
+
+    #[tokio::test]
+    async fn streams_cut_at_a_frame_boundary_are_rejected() {
+        let key = E2eeKey::parse(&"ab".repeat(32)).unwrap();
+        let (sender, receiver) = transports(&key).await;
+        let data = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let mut ciphertext = Vec::new();
+        seal(data.as_slice(), &mut ciphertext, &sender)
+            .await
+            .unwrap();
+        let ends = frame_ends(&ciphertext);
+        assert!(ends.len() > 2);
+
+        let mut plain = Vec::new();
+        open(ciphertext.as_slice(), &mut plain, &receiver)
+            .await
+            .unwrap();
+        assert_eq!(plain, data);
+
+        // Dropped by the relay after some data frames, or just the final one
+        for end in [ends[0], ends[ends.len() - 2]] {
+            let mut plain = Vec::new();
+            let error = open(&ciphertext[..end], &mut plain, &receiver)
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains("cut off"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::runtime::Load;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events a slow sink may fall behind by before it starts missing some
+const BUS_CAPACITY: usize = 1024;
+
+/// A `session_output` event is published for the first output line and
+/// then every this many lines
+pub const OUTPUT_MILESTONE_LINES: usize = 100;
+
+/// Something that happened in this client, published on the [`EventBus`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// Registered with the server
+    Connected {
+        server: String,
+        generation: Option<u64>,
+    },
+    /// The control connection was lost
+    Disconnected { reason: String },
+    SessionStarted {
+        session_id: String,
+        executor: &'static str,
+        project_path: String,
+    },
+    /// Output milestone, see [`OUTPUT_MILESTONE_LINES`]
+    SessionOutput { session_id: String, lines: usize },
+    SessionEnded {
+        session_id: String,
+        executor: &'static str,
+        status: &'static str,
+        agent_session_id: Option<String>,
+        project_path: Option<String>,
+        exit_code: Option<i32>,
+        error: Option<String>,
+    },
+    #[cfg(any(feature = "mcp", test))]
+    PermissionRequested {
+        session_id: String,
+        permission_id: String,
+        tool_name: String,
+    },
+    /// `decision` is `approved`, `denied`, or `timeout_approved` /
+    /// `timeout_denied` for prompts nobody answered in time
+    PermissionResolved {
+        session_id: String,
+        permission_id: String,
+        decision: &'static str,
+        decided_by: Option<String>,
+    },
+    /// A session couldn't be started
+    Error {
+        session_id: Option<String>,
+        message: String,
+    },
+    /// Published at the load report interval whenever it changed
+    Load(Load),
+}
+
+#[cfg(feature = "events")]
+impl ClientEvent {
+    /// Every event `type`, for validating sink filters
+    pub const TYPES: [&'static str; 9] = [
+        "connected",
+        "disconnected",
+        "session_started",
+        "session_output",
+        "session_ended",
+        "permission_requested",
+        "permission_resolved",
+        "error",
+        "load",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientEvent::Connected { .. } => "connected",
+            ClientEvent::Disconnected { .. } => "disconnected",
+            ClientEvent::SessionStarted { .. } => "session_started",
+            ClientEvent::SessionOutput { .. } => "session_output",
+            ClientEvent::SessionEnded { .. } => "session_ended",
+            #[cfg(any(feature = "mcp", test))]
+            ClientEvent::PermissionRequested { .. } => "permission_requested",
+            ClientEvent::PermissionResolved { .. } => "permission_resolved",
+            ClientEvent::Error { .. } => "error",
+            ClientEvent::Load(_) => "load",
+        }
+    }
+}
+
+/// A [`ClientEvent`] and when it happened (Unix seconds)
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: ClientEvent,
+}
+
+/// Fan-out of client events to whoever subscribes (event sinks, mostly).
+/// Publishing never waits; with nobody subscribed events are dropped.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            tx: broadcast::channel(BUS_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: ClientEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let _ = self.tx.send(Event { timestamp, event });
+    }
+
+    #[cfg(feature = "events")]
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
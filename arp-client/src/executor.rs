@@ -1,10 +1,16 @@
+#[cfg(feature = "executors")]
 use anyhow::{Result, anyhow};
+#[cfg(feature = "executors")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "executors")]
 use std::path::PathBuf;
+#[cfg(feature = "executors")]
 use tokio::process::Command as TokioCommand;
+#[cfg(feature = "executors")]
 use tracing::info;
 
 /// Executor type for command execution
+#[cfg(feature = "executors")]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ExecutorKind {
@@ -14,6 +20,7 @@ pub enum ExecutorKind {
     Gemini, // Future support
 }
 
+#[cfg(feature = "executors")]
 impl ExecutorKind {
     pub fn from_str(value: &str) -> Option<Self> {
         match value.to_lowercase().as_str() {
@@ -46,7 +53,36 @@ impl ExecutorKind {
     }
 }
 
+/// What the client does on startup with executors a previous run left
+/// running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    /// Track the process again and stream its transcript as a live session
+    Adopt,
+    /// Stop the process
+    Terminate,
+    /// Leave the process alone and forget about it
+    Ignore,
+}
+
+impl std::str::FromStr for OrphanPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "adopt" => Ok(OrphanPolicy::Adopt),
+            "terminate" => Ok(OrphanPolicy::Terminate),
+            "ignore" => Ok(OrphanPolicy::Ignore),
+            other => Err(format!(
+                "invalid orphan policy '{}', expected 'adopt', 'terminate' or 'ignore'",
+                other
+            )),
+        }
+    }
+}
+
 /// Options for Claude executor
+#[cfg(feature = "executors")]
 #[derive(Debug, Clone, Default)]
 pub struct ClaudeOptions {
     pub resume: Option<String>, // session_id to resume
@@ -56,6 +92,7 @@ pub struct ClaudeOptions {
 }
 
 /// Options for Codex executor
+#[cfg(feature = "executors")]
 #[derive(Debug, Clone, Default)]
 pub struct CodexOptions {
     pub model: Option<String>,
@@ -63,12 +100,14 @@ pub struct CodexOptions {
 }
 
 /// Options for Gemini executor
+#[cfg(feature = "executors")]
 #[derive(Debug, Clone, Default)]
 pub struct GeminiOptions {
     pub approval_mode: Option<String>, // "default" | "auto_edit" | "yolo"
 }
 
 /// Options for command execution
+#[cfg(feature = "executors")]
 #[derive(Debug, Clone)]
 pub enum ExecutorOptions {
     Claude(ClaudeOptions),
@@ -76,6 +115,7 @@ pub enum ExecutorOptions {
     Gemini(GeminiOptions),
 }
 
+#[cfg(feature = "executors")]
 impl ExecutorOptions {
     pub fn kind(&self) -> ExecutorKind {
         match self {
@@ -84,22 +124,54 @@ impl ExecutorOptions {
             ExecutorOptions::Gemini(_) => ExecutorKind::Gemini,
         }
     }
+
+    /// Model requested for the run, if any
+    pub fn model(&self) -> Option<&str> {
+        match self {
+            ExecutorOptions::Claude(options) => options.model.as_deref(),
+            ExecutorOptions::Codex(options) => options.model.as_deref(),
+            ExecutorOptions::Gemini(_) => None,
+        }
+    }
+
+    /// Permission mode the agent actually runs with, in the executor's own
+    /// terms: Claude without a mode skips permission checks and Codex always
+    /// runs with full access
+    pub fn permission_mode(&self) -> &str {
+        match self {
+            ExecutorOptions::Claude(options) => options
+                .permission_mode
+                .as_deref()
+                .unwrap_or("bypassPermissions"),
+            ExecutorOptions::Codex(_) => "danger-full-access",
+            ExecutorOptions::Gemini(options) => {
+                options.approval_mode.as_deref().unwrap_or("default")
+            }
+        }
+    }
 }
 
 /// Build a command for the specified executor
+#[cfg(feature = "executors")]
 pub fn build_command(
     executor_options: &ExecutorOptions,
     prompt: &str,
     project_path: &str,
 ) -> Result<TokioCommand> {
-    match executor_options {
+    let mut cmd = match executor_options {
         ExecutorOptions::Claude(options) => build_claude_command(prompt, project_path, options),
         ExecutorOptions::Codex(options) => build_codex_command(prompt, project_path, options),
         ExecutorOptions::Gemini(options) => build_gemini_command(prompt, project_path, options),
-    }
+    }?;
+    #[cfg(feature = "keyring")]
+    cmd.envs(crate::credentials::agent_env(executor_options.kind()));
+    // Lets cancellation reach the processes the executor starts
+    crate::process::configure(&mut cmd);
+    Ok(cmd)
 }
 
 /// Build Claude command
+#[cfg(feature = "executors")]
 fn build_claude_command(
     prompt: &str,
     project_path: &str,
@@ -157,6 +229,7 @@ fn build_claude_command(
 }
 
 /// Build Codex command
+#[cfg(feature = "executors")]
 fn build_codex_command(
     prompt: &str,
     project_path: &str,
@@ -203,6 +276,7 @@ fn build_codex_command(
 }
 
 /// Build Gemini command
+#[cfg(feature = "executors")]
 fn build_gemini_command(
     prompt: &str,
     project_path: &str,
@@ -252,8 +326,18 @@ fn build_gemini_command(
     Ok(cmd)
 }
 
+/// Binary that would be launched for `kind`, or None if it is not installed
+#[cfg(feature = "executors")]
+pub fn find_binary(kind: ExecutorKind) -> Option<String> {
+    match kind {
+        ExecutorKind::Claude => find_claude_binary().ok(),
+        ExecutorKind::Codex => which::which("codex").ok().map(|_| "codex".to_string()),
+        ExecutorKind::Gemini => which::which("gemini").ok().map(|_| "gemini".to_string()),
+    }
+}
+
 /// Find Claude binary on the system
-#[cfg(windows)]
+#[cfg(all(feature = "executors", windows))]
 fn find_claude_binary() -> Result<String> {
     // First try the bundled binary (same location as Tauri app uses)
     let bundled_binary = "src-tauri/binaries/claude-code-x86_64-pc-windows-msvc.exe";
@@ -304,7 +388,7 @@ fn find_claude_binary() -> Result<String> {
     ))
 }
 
-#[cfg(not(windows))]
+#[cfg(all(feature = "executors", not(windows)))]
 fn find_claude_binary() -> Result<String> {
     let candidates = vec!["claude", "claude-code"];
 
@@ -317,12 +401,3 @@ fn find_claude_binary() -> Result<String> {
 
     Err(anyhow!("Claude binary not found in system PATH"))
 }
-
-/// Parse a boolean string value
-pub fn parse_bool_str(value: &str) -> Option<bool> {
-    match value.trim().to_ascii_lowercase().as_str() {
-        "true" | "1" | "yes" | "on" => Some(true),
-        "false" | "0" | "no" | "off" => Some(false),
-        _ => None,
-    }
-}
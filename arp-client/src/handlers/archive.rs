@@ -0,0 +1,375 @@
+use crate::handlers::paths::{FsOptions, SymlinkPolicy};
+use anyhow::{Result, anyhow};
+use chrono::{Datelike, Local, Timelike};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use ignore::WalkBuilder;
+use std::fs::{File, Metadata};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Archive data is handed to the connection in chunks of this size
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Chunks buffered between the archiver and a slow connection
+const CHANNEL_CHUNKS: usize = 8;
+
+/// Archive formats offered by `?format=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            other => Err(format!(
+                "invalid archive format '{}', expected 'tar.gz' or 'zip'",
+                other
+            )),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+/// A directory to export
+pub struct ArchiveRequest {
+    /// Canonical directory to archive
+    pub root: PathBuf,
+    /// Canonical project directory nothing may escape
+    pub base: PathBuf,
+    pub format: ArchiveFormat,
+    /// Leave out files matched by `.gitignore` and the `.git` directory
+    pub respect_gitignore: bool,
+    pub options: FsOptions,
+}
+
+/// A file or directory going into the archive
+struct Entry {
+    name: String,
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+/// Stream `request.root` to `stream` as an archive download.
+///
+/// The archive is built on the blocking pool while it is sent, so its size
+/// isn't known up front; the response has no `Content-Length` and ends when
+/// the connection closes. Files the filesystem API wouldn't serve (hidden,
+/// over the size limit, or behind a link leaving the project) are left out.
+pub async fn stream_archive<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: ArchiveRequest,
+) -> Result<()> {
+    let top = request
+        .root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "archive".to_string());
+    let file_name = format!("{}.{}", top, request.format.extension());
+
+    let headers = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename*=UTF-8''{}\r\nX-Content-Type-Options: nosniff\r\nCache-Control: no-store\r\nConnection: close\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+        request.format.content_type(),
+        urlencoding::encode(&file_name)
+    );
+    stream.write_all(headers.as_bytes()).await?;
+
+    let (tx, mut rx) = mpsc::channel(CHANNEL_CHUNKS);
+    let archiver = tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(CHUNK_BYTES, ChannelWriter { tx });
+        write_archive(writer, &request, &top)
+    });
+
+    // Dropping `rx` on a write error stops the archiver at its next chunk
+    while let Some(chunk) = rx.recv().await {
+        stream.write_all(&chunk).await?;
+    }
+    stream.flush().await?;
+
+    archiver
+        .await
+        .map_err(|e| anyhow!("Archive task failed: {}", e))?
+        .map_err(|e| anyhow!("Failed to build archive: {}", e))
+}
+
+fn write_archive<W: Write>(writer: W, request: &ArchiveRequest, top: &str) -> io::Result<()> {
+    let entries = collect_entries(request, top);
+    match request.format {
+        ArchiveFormat::TarGz => {
+            let mut tar = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            for entry in entries {
+                if entry.metadata.is_dir() {
+                    tar.append_dir(&entry.name, &entry.path)?;
+                } else {
+                    tar.append_path_with_name(&entry.path, &entry.name)?;
+                }
+            }
+            tar.into_inner()?.finish()?.flush()
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new_stream(writer);
+            for entry in entries {
+                let options = zip_options(&entry.metadata);
+                if entry.metadata.is_dir() {
+                    zip.add_directory(&entry.name, options)
+                        .map_err(io::Error::other)?;
+                } else {
+                    zip.start_file(&entry.name, options)
+                        .map_err(io::Error::other)?;
+                    io::copy(&mut File::open(&entry.path)?, &mut zip)?;
+                }
+            }
+            zip.finish().map_err(io::Error::other)?.into_inner().flush()
+        }
+    }
+}
+
+/// Everything under the root that the filesystem API would serve, in a
+/// stable order, named `<top>/<relative path>`
+fn collect_entries(request: &ArchiveRequest, top: &str) -> Vec<Entry> {
+    let options = &request.options;
+    let mut walker = WalkBuilder::new(&request.root);
+    walker
+        .standard_filters(false)
+        .hidden(options.hide_hidden)
+        .follow_links(options.symlinks == SymlinkPolicy::Follow)
+        .sort_by_file_name(|a, b| a.cmp(b));
+    if request.respect_gitignore {
+        walker
+            .git_ignore(true)
+            .git_exclude(true)
+            .parents(true)
+            .require_git(false);
+    }
+    // Links are checked before the walker enters them, so nothing is
+    // collected from a directory outside the base
+    let (respect_gitignore, symlinks, base) = (
+        request.respect_gitignore,
+        options.symlinks,
+        request.base.clone(),
+    );
+    walker.filter_entry(move |entry| {
+        if respect_gitignore && entry.file_name() == ".git" {
+            return false;
+        }
+        if !entry.path_is_symlink() {
+            return true;
+        }
+        symlinks != SymlinkPolicy::Deny
+            && entry
+                .path()
+                .canonicalize()
+                .is_ok_and(|target| target.starts_with(&base))
+    });
+
+    let mut entries = Vec::new();
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Skipping archive entry: {}", e);
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if !metadata.is_dir() && !metadata.is_file() {
+            continue;
+        }
+        if metadata.is_file() && options.max_file_size > 0 && metadata.len() > options.max_file_size
+        {
+            continue;
+        }
+
+        entries.push(Entry {
+            name: archive_name(top, &request.root, path, metadata.is_dir()),
+            path: path.to_path_buf(),
+            metadata,
+        });
+    }
+    entries
+}
+
+fn archive_name(top: &str, root: &Path, path: &Path, is_dir: bool) -> String {
+    let mut name = top.to_string();
+    for part in path.strip_prefix(root).unwrap_or(Path::new("")) {
+        name.push('/');
+        name.push_str(&part.to_string_lossy());
+    }
+    if is_dir {
+        name.push('/');
+    }
+    name
+}
+
+fn zip_options(metadata: &Metadata) -> SimpleFileOptions {
+    let mut options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(metadata.len() > u32::MAX as u64);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(metadata.permissions().mode() & 0o777);
+    }
+
+    let modified = metadata.modified().ok().and_then(|modified| {
+        let local = chrono::DateTime::<Local>::from(modified);
+        zip::DateTime::from_date_and_time(
+            u16::try_from(local.year()).ok()?,
+            local.month() as u8,
+            local.day() as u8,
+            local.hour() as u8,
+            local.minute() as u8,
+            local.second() as u8,
+        )
+        .ok()
+    });
+    if let Some(modified) = modified {
+        options = options.last_modified_time(modified);
+    }
+    options
+}
+
+/// Blocking writer feeding the async connection
+struct ChannelWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.blocking_send(buf.to_vec()).map_err(|_| {
+            warn!("Archive download closed before it finished");
+            io::Error::new(io::ErrorKind::BrokenPipe, "download closed")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn body(response: &[u8]) -> &[u8] {
+        let start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        &response[start..]
+    }
+
+    #[tokio::test]
+    async fn archives_follow_the_fs_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        let project = base.join("app");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::fs::create_dir_all(project.join("target")).unwrap();
+        std::fs::write(project.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(project.join("target/app.bin"), [0u8; 16]).unwrap();
+        std::fs::write(base.join("secret.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), project.join("leak")).unwrap();
+        std::fs::create_dir(base.join("outside")).unwrap();
+        std::fs::write(base.join("outside/secret.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink("../outside", project.join("leakdir")).unwrap();
+        std::os::unix::fs::symlink("src", project.join("code")).unwrap();
+
+        let request = |format, respect_gitignore, hide_hidden| ArchiveRequest {
+            root: project.clone(),
+            base: project.clone(),
+            format,
+            respect_gitignore,
+            options: FsOptions {
+                symlinks: SymlinkPolicy::Follow,
+                hide_hidden,
+                max_file_size: 0,
+            },
+        };
+
+        let mut response = Vec::new();
+        stream_archive(&mut response, request(ArchiveFormat::TarGz, true, false))
+            .await
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: application/gzip"));
+        let mut tar = tar::Archive::new(GzDecoder::new(body(&response)));
+        let mut names = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            if name == "app/src/main.rs" {
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                assert_eq!(content, "fn main() {}\n");
+            }
+            names.push(name);
+        }
+        assert_eq!(
+            names,
+            [
+                "app/",
+                "app/.gitignore",
+                "app/code/",
+                "app/code/main.rs",
+                "app/src/",
+                "app/src/main.rs"
+            ]
+        );
+
+        let mut response = Vec::new();
+        stream_archive(&mut response, request(ArchiveFormat::Zip, false, true))
+            .await
+            .unwrap();
+        let zip = zip::ZipArchive::new(io::Cursor::new(body(&response).to_vec())).unwrap();
+        let mut names: Vec<_> = zip.file_names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "app/",
+                "app/code/",
+                "app/code/main.rs",
+                "app/src/",
+                "app/src/main.rs",
+                "app/target/",
+                "app/target/app.bin"
+            ]
+        );
+    }
+}
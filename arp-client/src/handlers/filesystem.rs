@@ -1,17 +1,30 @@
-use crate::handlers::HandlerState;
+use crate::handlers::archive::{self, ArchiveFormat, ArchiveRequest};
+use crate::handlers::parse_bool_str;
+use crate::handlers::paths::{
+    FsOptions, FsRequestPaths, SymlinkPolicy, resolve_request_paths, resolve_target,
+};
+use crate::handlers::{HandlerState, preview};
 use crate::router::HandlerContext;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use common::http::{HttpResponse, json_error};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::io::ErrorKind;
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncReadExt;
+use tracing::warn;
 
 const MAX_FILE_BYTES: usize = 1_048_576;
 
+/// Largest file served whole with `raw=true` (64 MiB)
+const MAX_RAW_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Leading bytes inspected to tell a file's type
+const SNIFF_BYTES: usize = 8192;
+
 pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
     let HandlerContext {
         request,
@@ -27,150 +40,34 @@ pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Resu
         return Ok(HttpResponse::ok());
     }
 
-    let mut session_id_for_response: Option<String> = None;
-
-    let base_path_candidate = if let Some(session_id) = path_params.get("session_id").cloned() {
-        session_id_for_response = Some(session_id.clone());
-
-        let session = match state.session_manager.get_session(&session_id).await {
-            Some(session) => session,
-            None => {
-                let _ = json_error(404, "Session not found").send(&mut stream).await;
-                return Ok(HttpResponse::ok());
-            }
-        };
-
-        match session.get_project_path().await {
-            Some(path) => path,
-            None => {
-                let _ = json_error(404, "Project path unavailable for this session")
-                    .send(&mut stream)
-                    .await;
-                return Ok(HttpResponse::ok());
-            }
+    let FsRequestPaths {
+        session_id: session_id_for_response,
+        base: canonical_base,
+        path: decoded_path,
+    } = match resolve_request_paths(&request, &mut path_params, &state).await {
+        Ok(paths) => paths,
+        Err((status, message)) => {
+            let _ = json_error(status, message).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
         }
-    } else {
-        let project_path_raw = match request.query_param("project_path") {
-            Some(value) if !value.trim().is_empty() => value.clone(),
-            _ => {
-                let _ = json_error(
-                    400,
-                    "project_path query parameter is required when session_id is not provided",
-                )
-                .send(&mut stream)
-                .await;
-                return Ok(HttpResponse::ok());
-            }
-        };
+    };
 
-        let decoded = match urlencoding::decode(project_path_raw.as_str()) {
-            Ok(path) => path.into_owned(),
-            Err(e) => {
-                let _ = json_error(
-                    400,
-                    format!("Failed to decode project_path parameter: {}", e),
-                )
-                .send(&mut stream)
-                .await;
+    let options = state.config.fs_options();
+    let canonical_target =
+        match resolve_target(&canonical_base, decoded_path.as_str(), &options).await {
+            Ok(path) => path,
+            Err((status, message)) => {
+                let _ = json_error(status, message).send(&mut stream).await;
                 return Ok(HttpResponse::ok());
             }
         };
 
-        PathBuf::from(decoded)
-    };
-
-    let canonical_base = match fs::canonicalize(&base_path_candidate).await {
-        Ok(path) => path,
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            let _ = json_error(
-                404,
-                "Requested project_path does not exist or is not accessible",
-            )
-            .send(&mut stream)
-            .await;
-            return Ok(HttpResponse::ok());
-        }
-        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
-            let _ = json_error(403, "Permission denied accessing project_path")
-                .send(&mut stream)
-                .await;
-            return Ok(HttpResponse::ok());
-        }
-        Err(e) => {
-            let _ = json_error(500, format!("Failed to resolve project_path: {}", e))
-                .send(&mut stream)
-                .await;
-            return Ok(HttpResponse::ok());
-        }
-    };
-
-    let base_metadata = match fs::metadata(&canonical_base).await {
-        Ok(meta) => meta,
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            let _ = json_error(
-                404,
-                "Requested project_path does not exist or is not accessible",
-            )
-            .send(&mut stream)
-            .await;
-            return Ok(HttpResponse::ok());
-        }
-        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
-            let _ = json_error(403, "Permission denied accessing project_path")
-                .send(&mut stream)
-                .await;
-            return Ok(HttpResponse::ok());
-        }
-        Err(e) => {
-            let _ = json_error(
-                500,
-                format!("Failed to access project_path metadata: {}", e),
-            )
-            .send(&mut stream)
-            .await;
-            return Ok(HttpResponse::ok());
-        }
-    };
-
-    if !base_metadata.is_dir() {
-        let _ = json_error(400, "project_path must reference a directory")
-            .send(&mut stream)
-            .await;
-        return Ok(HttpResponse::ok());
-    }
-
-    let raw_path = path_params
-        .remove("path")
-        .or_else(|| request.query_param("path").cloned())
-        .unwrap_or_default();
-
-    let decoded_path = match urlencoding::decode(&raw_path) {
-        Ok(value) => value.into_owned(),
-        Err(e) => {
-            let _ = json_error(400, format!("Failed to decode path parameter: {}", e))
-                .send(&mut stream)
-                .await;
-            return Ok(HttpResponse::ok());
-        }
-    };
-
-    let resolved_path = match resolve_path(&canonical_base, decoded_path.as_str()) {
-        Ok(path) => path,
-        Err(message) => {
-            let _ = json_error(400, message).send(&mut stream).await;
-            return Ok(HttpResponse::ok());
-        }
-    };
-
-    let metadata = match fs::metadata(&resolved_path).await {
+    let metadata = match fs::metadata(&canonical_target).await {
         Ok(meta) => meta,
         Err(e) if e.kind() == ErrorKind::NotFound => {
             let _ = json_error(
                 404,
-                format!(
-                    "Path not found: {}",
-                    decoded_path.trim_start_matches('/').to_string()
-                ),
+                format!("Path not found: {}", decoded_path.trim_start_matches('/')),
             )
             .send(&mut stream)
             .await;
@@ -190,31 +87,89 @@ pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Resu
         }
     };
 
-    let canonical_target = match fs::canonicalize(&resolved_path).await {
-        Ok(path) => path,
-        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
-            let _ = json_error(403, "Permission denied accessing requested path")
+    let project_root_display = canonical_base.to_string_lossy().replace('\\', "/");
+
+    if request
+        .query_param("stat")
+        .is_some_and(|stat| parse_bool_str(stat) == Some(true))
+    {
+        let sha256 = if metadata.is_file() {
+            if options.max_file_size > 0 && metadata.len() > options.max_file_size {
+                let _ = json_error(
+                    413,
+                    format!(
+                        "File is {} bytes, larger than the {} byte limit",
+                        metadata.len(),
+                        options.max_file_size
+                    ),
+                )
                 .send(&mut stream)
                 .await;
-            return Ok(HttpResponse::ok());
-        }
-        Err(_) => resolved_path.clone(),
-    };
+                return Ok(HttpResponse::ok());
+            }
+            match sha256_file(canonical_target.clone()).await {
+                Ok(digest) => Some(digest),
+                Err(message) => {
+                    let _ = json_error(500, message).send(&mut stream).await;
+                    return Ok(HttpResponse::ok());
+                }
+            }
+        } else {
+            None
+        };
 
-    if !canonical_target.starts_with(&canonical_base) {
-        let _ = json_error(
-            403,
-            "Access denied: requested path escapes the project directory",
-        )
-        .send(&mut stream)
-        .await;
+        let body = json!({
+            "type": "stat",
+            "session_id": session_id_for_response,
+            "project_path": project_root_display,
+            "path": decoded_path,
+            "is_dir": metadata.is_dir(),
+            "size": if metadata.is_file() { Some(metadata.len()) } else { None },
+            "modified": metadata
+                .modified()
+                .ok()
+                .map(|ts| DateTime::<Utc>::from(ts).to_rfc3339()),
+            "mode": file_mode(&metadata),
+            "readonly": metadata.permissions().readonly(),
+            "sha256": sha256,
+        });
+
+        HttpResponse::ok()
+            .json(&body)
+            .header("X-Content-Type-Options", "nosniff")
+            .header("Cache-Control", "no-store")
+            .send(&mut stream)
+            .await?;
         return Ok(HttpResponse::ok());
     }
 
-    let project_root_display = canonical_base.to_string_lossy().replace('\\', "/");
-
     if metadata.is_dir() {
-        let entries = match list_directory(&canonical_base, &canonical_target).await {
+        if let Some(format) = request.query_param("format") {
+            let format = match format.parse::<ArchiveFormat>() {
+                Ok(format) => format,
+                Err(message) => {
+                    let _ = json_error(400, message).send(&mut stream).await;
+                    return Ok(HttpResponse::ok());
+                }
+            };
+            let respect_gitignore = request
+                .query_param("gitignore")
+                .is_some_and(|value| parse_bool_str(value) == Some(true));
+
+            let archive = ArchiveRequest {
+                root: canonical_target,
+                base: canonical_base,
+                format,
+                respect_gitignore,
+                options,
+            };
+            if let Err(e) = archive::stream_archive(&mut stream, archive).await {
+                warn!("Archive download of {} failed: {}", decoded_path, e);
+            }
+            return Ok(HttpResponse::ok());
+        }
+
+        let entries = match list_directory(&canonical_base, &canonical_target, &options).await {
             Ok(entries) => entries,
             Err(message) => {
                 let _ = json_error(500, message).send(&mut stream).await;
@@ -230,19 +185,94 @@ pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Resu
             "entries": entries,
         });
 
-        HttpResponse::ok().json(&body).send(&mut stream).await?;
+        HttpResponse::ok()
+            .json(&body)
+            .header("X-Content-Type-Options", "nosniff")
+            .send(&mut stream)
+            .await?;
         return Ok(HttpResponse::ok());
     }
 
     if metadata.is_file() {
-        let (content, encoding, truncated, bytes_read) =
-            match read_file_content(&canonical_target).await {
-                Ok(result) => result,
-                Err(message) => {
-                    let _ = json_error(500, message).send(&mut stream).await;
+        if options.max_file_size > 0 && metadata.len() > options.max_file_size {
+            let _ = json_error(
+                413,
+                format!(
+                    "File is {} bytes, larger than the {} byte limit",
+                    metadata.len(),
+                    options.max_file_size
+                ),
+            )
+            .send(&mut stream)
+            .await;
+            return Ok(HttpResponse::ok());
+        }
+
+        if request
+            .query_param("raw")
+            .is_some_and(|raw| parse_bool_str(raw) == Some(true))
+        {
+            let _ = serve_raw_file(&mut stream, &canonical_target, metadata.len()).await;
+            return Ok(HttpResponse::ok());
+        }
+
+        if request
+            .query_param("preview")
+            .is_some_and(|preview| parse_bool_str(preview) == Some(true))
+        {
+            let thumbnail_size = match request.query_param("size") {
+                Some(size) => match size.parse::<u32>() {
+                    Ok(size) if size > 0 => size,
+                    _ => {
+                        let _ = json_error(400, "size must be a positive number of pixels")
+                            .send(&mut stream)
+                            .await;
+                        return Ok(HttpResponse::ok());
+                    }
+                },
+                None => preview::DEFAULT_THUMBNAIL_SIZE,
+            };
+
+            let preview = match sniff_mime(&canonical_target).await {
+                Ok(mime_type) => {
+                    preview::build_preview(
+                        &canonical_target,
+                        metadata.len(),
+                        &mime_type,
+                        thumbnail_size,
+                    )
+                    .await
+                }
+                Err(message) => Err((500, message)),
+            };
+            let mut body = match preview {
+                Ok(preview) => preview,
+                Err((status, message)) => {
+                    let _ = json_error(status, message).send(&mut stream).await;
                     return Ok(HttpResponse::ok());
                 }
             };
+            body["type"] = json!("preview");
+            body["session_id"] = json!(session_id_for_response);
+            body["project_path"] = json!(project_root_display);
+            body["path"] = json!(decoded_path);
+            body["size"] = json!(metadata.len());
+
+            HttpResponse::ok()
+                .json(&body)
+                .header("X-Content-Type-Options", "nosniff")
+                .send(&mut stream)
+                .await?;
+            return Ok(HttpResponse::ok());
+        }
+
+        let file = match read_file_content(&canonical_target).await {
+            Ok(file) => file,
+            Err(message) => {
+                let _ = json_error(500, message).send(&mut stream).await;
+                return Ok(HttpResponse::ok());
+            }
+        };
 
         let body = json!({
             "type": "file",
@@ -250,13 +280,18 @@ pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Resu
             "project_path": project_root_display,
             "path": decoded_path,
             "size": metadata.len(),
-            "bytes": bytes_read,
-            "encoding": encoding,
-            "truncated": truncated,
-            "content": content,
+            "bytes": file.bytes_read,
+            "encoding": file.encoding,
+            "mime_type": file.mime_type,
+            "truncated": file.truncated,
+            "content": file.content,
         });
 
-        HttpResponse::ok().json(&body).send(&mut stream).await?;
+        HttpResponse::ok()
+            .json(&body)
+            .header("X-Content-Type-Options", "nosniff")
+            .send(&mut stream)
+            .await?;
         return Ok(HttpResponse::ok());
     }
 
@@ -266,31 +301,11 @@ pub async fn handle_filesystem(ctx: HandlerContext, state: HandlerState) -> Resu
     Ok(HttpResponse::ok())
 }
 
-fn resolve_path(base: &Path, relative: &str) -> Result<PathBuf, String> {
-    let mut resolved = base.to_path_buf();
-
-    if relative.is_empty() {
-        return Ok(resolved);
-    }
-
-    let relative_path = Path::new(relative);
-    for component in relative_path.components() {
-        match component {
-            Component::Normal(part) => resolved.push(part),
-            Component::CurDir => {}
-            Component::ParentDir => {
-                return Err("Path traversal outside the project directory is not allowed".into());
-            }
-            Component::RootDir | Component::Prefix(_) => {
-                return Err("Absolute paths are not allowed".into());
-            }
-        }
-    }
-
-    Ok(resolved)
-}
-
-async fn list_directory(base: &Path, target: &Path) -> Result<Vec<serde_json::Value>, String> {
+async fn list_directory(
+    base: &Path,
+    target: &Path,
+    options: &FsOptions,
+) -> Result<Vec<serde_json::Value>, String> {
     let mut entries = fs::read_dir(target)
         .await
         .map_err(|e| format!("Failed to read directory: {}", e))?;
@@ -309,28 +324,48 @@ async fn list_directory(base: &Path, target: &Path) -> Result<Vec<serde_json::Va
         if file_name == "." || file_name == ".." {
             continue;
         }
+        if options.hide_hidden && file_name.starts_with('.') {
+            continue;
+        }
 
         let entry_path = entry.path();
-        let metadata = entry
+        let mut metadata = entry
             .metadata()
             .await
             .map_err(|e| format!("Failed to read metadata: {}", e))?;
 
+        // Links are listed with their target's details, and only when the
+        // target could be opened through this API
+        let is_symlink = metadata.file_type().is_symlink();
+        if is_symlink {
+            if options.symlinks == SymlinkPolicy::Deny {
+                continue;
+            }
+            match fs::canonicalize(&entry_path).await {
+                Ok(target) if target.starts_with(base) => match fs::metadata(&target).await {
+                    Ok(target_metadata) => metadata = target_metadata,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            }
+        }
+
         let relative = entry_path
             .strip_prefix(base)
-            .unwrap_or_else(|_| entry_path.as_path())
+            .unwrap_or(entry_path.as_path())
             .to_string_lossy()
             .replace('\\', "/");
 
         let modified = metadata
             .modified()
             .ok()
-            .and_then(|ts| Some(DateTime::<Utc>::from(ts).to_rfc3339()));
+            .map(|ts| DateTime::<Utc>::from(ts).to_rfc3339());
 
         result.push(json!({
             "name": file_name,
             "path": relative,
             "is_dir": metadata.is_dir(),
+            "is_symlink": is_symlink,
             "size": if metadata.is_file() { Some(metadata.len()) } else { None },
             "modified": modified,
         }));
@@ -348,7 +383,16 @@ async fn list_directory(base: &Path, target: &Path) -> Result<Vec<serde_json::Va
     Ok(result)
 }
 
-async fn read_file_content(path: &Path) -> Result<(String, String, bool, usize), String> {
+/// Start of a file as returned in JSON responses
+struct FileContent {
+    content: String,
+    encoding: &'static str,
+    mime_type: String,
+    truncated: bool,
+    bytes_read: usize,
+}
+
+async fn read_file_content(path: &Path) -> Result<FileContent, String> {
     let file = fs::File::open(path)
         .await
         .map_err(|e| format!("Failed to read file: {}", e))?;
@@ -366,38 +410,266 @@ async fn read_file_content(path: &Path) -> Result<(String, String, bool, usize),
     }
 
     let bytes_read = buffer.len();
+    let mime_type = detect_mime(path, &buffer[..bytes_read.min(SNIFF_BYTES)]);
     let content_cow = String::from_utf8_lossy(&buffer);
     let (content, encoding) = match content_cow {
-        Cow::Borrowed(s) => (s.to_string(), "utf-8".to_string()),
-        Cow::Owned(s) => (s, "utf-8-lossy".to_string()),
+        Cow::Borrowed(s) => (s.to_string(), "utf-8"),
+        Cow::Owned(s) => (s, "utf-8-lossy"),
     };
 
-    Ok((content, encoding, truncated, bytes_read))
+    Ok(FileContent {
+        content,
+        encoding,
+        mime_type,
+        truncated,
+        bytes_read,
+    })
+}
+
+/// Hex SHA-256 of a file, read in chunks on the blocking pool so large files
+/// neither fill memory nor stall the runtime
+pub async fn sha256_file(path: PathBuf) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file =
+            std::fs::File::open(&path).map_err(|e| format!("Failed to read file: {}", e))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)
+            .map_err(|e| format!("Failed to read file content: {}", e))?;
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Checksum task failed: {}", e))?
+}
+
+/// Permission bits in octal, e.g. `"644"`; not available off Unix
+fn file_mode(metadata: &std::fs::Metadata) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(format!("{:o}", metadata.permissions().mode() & 0o7777))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// MIME type of the file at `path`, from its first few kilobytes
+async fn sniff_mime(path: &Path) -> Result<String, String> {
+    let file = fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let mut head = Vec::with_capacity(SNIFF_BYTES);
+    file.take(SNIFF_BYTES as u64)
+        .read_to_end(&mut head)
+        .await
+        .map_err(|e| format!("Failed to read file content: {}", e))?;
+    Ok(detect_mime(path, &head))
+}
+
+/// Send a file's bytes as-is. Types a browser could run scripts from are
+/// only offered as downloads, so repository files can't act as pages of the
+/// tunnel's origin.
+async fn serve_raw_file<S: tokio::io::AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &Path,
+    size: u64,
+) -> Result<()> {
+    if size > MAX_RAW_FILE_BYTES {
+        return json_error(
+            413,
+            format!(
+                "File is {} bytes, larger than the {} byte raw download limit",
+                size, MAX_RAW_FILE_BYTES
+            ),
+        )
+        .send(stream)
+        .await;
+    }
+
+    let bytes = match fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return json_error(500, format!("Failed to read file: {}", e))
+                .send(stream)
+                .await;
+        }
+    };
+
+    let head = &bytes[..bytes.len().min(SNIFF_BYTES)];
+    let mime_type = detect_mime(path, head);
+    let content_type = if mime_type.starts_with("text/") && looks_like_text(head) {
+        format!("{}; charset=utf-8", mime_type)
+    } else {
+        mime_type.clone()
+    };
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let disposition = if renders_safely(&mime_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    HttpResponse::ok()
+        .header("Content-Type", content_type)
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Content-Security-Policy", "default-src 'none'; sandbox")
+        .header(
+            "Content-Disposition",
+            format!(
+                "{}; filename*=UTF-8''{}",
+                disposition,
+                urlencoding::encode(&file_name)
+            ),
+        )
+        .body(bytes)
+        .send(stream)
+        .await
+}
+
+/// MIME type from the file's leading bytes, falling back to its extension
+fn detect_mime(path: &Path, head: &[u8]) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    let guessed = mime_guess::from_path(path).first_raw();
+    if looks_like_text(head) {
+        // Some extensions also name binary formats (`.ts` is MPEG-TS), so
+        // text content keeps a text type
+        match guessed {
+            Some(mime) if is_textual(mime) => mime.to_string(),
+            _ => "text/plain".to_string(),
+        }
+    } else {
+        guessed.unwrap_or("application/octet-stream").to_string()
+    }
+}
+
+fn is_textual(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime,
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// UTF-8 without NUL bytes; a character cut off at the end of `head` is fine
+fn looks_like_text(head: &[u8]) -> bool {
+    !head.contains(&0)
+        && match std::str::from_utf8(head) {
+            Ok(_) => true,
+            Err(e) => e.error_len().is_none(),
+        }
+}
+
+/// Types a browser displays without running anything from the file
+fn renders_safely(mime: &str) -> bool {
+    if mime.contains("html") || mime.contains("xml") {
+        return false;
+    }
+    mime.starts_with("text/") || mime.starts_with("image/") || mime == "application/json"
 }
 
 #[cfg(test)]
 mod tests {
-    use super::resolve_path;
-    use std::path::PathBuf;
-
-    #[test]
-    fn resolve_path_rejects_parent_dir() {
-        let base = PathBuf::from("/workspace/project");
-        let result = resolve_path(&base, "../secret");
-        assert!(result.is_err());
+    use super::{detect_mime, file_mode, renders_safely, sha256_file};
+    use crate::handlers::paths::{FsOptions, SymlinkPolicy};
+    use std::path::Path;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listings_apply_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("project");
+        std::fs::create_dir_all(base.join("src")).unwrap();
+        symlink(base.join("src"), base.join("code")).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        for (symlinks, expected) in [
+            (SymlinkPolicy::Follow, &["code", "src"][..]),
+            (SymlinkPolicy::Deny, &["src"][..]),
+        ] {
+            let options = FsOptions {
+                symlinks,
+                hide_hidden: true,
+                max_file_size: 0,
+            };
+            let entries = super::list_directory(&base, &base, &options).await.unwrap();
+            let names: Vec<_> = entries
+                .iter()
+                .map(|e| e["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(names, expected, "{:?}", symlinks);
+        }
     }
 
     #[test]
-    fn resolve_path_rejects_absolute() {
-        let base = PathBuf::from("/workspace/project");
-        let result = resolve_path(&base, "/etc/passwd");
-        assert!(result.is_err());
+    fn active_content_is_never_rendered_inline() {
+        let mime = |name: &str, head: &[u8]| detect_mime(Path::new(name), head);
+
+        assert_eq!(
+            mime("page.html", b"<!DOCTYPE html><script>x()</script>"),
+            "text/html"
+        );
+        assert_eq!(
+            mime("logo.svg", b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            "image/svg+xml"
+        );
+        assert_eq!(mime("main.ts", b"export const a = 1;\n"), "text/plain");
+        assert_eq!(mime("README", "caf\u{e9}".as_bytes()), "text/plain");
+        assert_eq!(mime("a.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(mime("blob", b"\0\x01\x02"), "application/octet-stream");
+        // Content outranks a misleading extension
+        assert_eq!(
+            mime("notes.txt", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            "image/png"
+        );
+
+        for unsafe_type in [
+            "text/html",
+            "image/svg+xml",
+            "application/xhtml+xml",
+            "text/xml",
+            "application/octet-stream",
+        ] {
+            assert!(!renders_safely(unsafe_type), "{}", unsafe_type);
+        }
+        for safe_type in ["text/plain", "image/png", "application/json", "text/css"] {
+            assert!(renders_safely(safe_type), "{}", safe_type);
+        }
     }
 
-    #[test]
-    fn resolve_path_joins_relative() {
-        let base = PathBuf::from("/workspace/project");
-        let result = resolve_path(&base, "src/lib.rs").unwrap();
-        assert_eq!(result, PathBuf::from("/workspace/project/src/lib.rs"));
+    #[tokio::test]
+    async fn stat_digest_is_streamed_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("abc.txt");
+        std::fs::write(&file, "abc").unwrap();
+
+        assert_eq!(
+            sha256_file(file.clone()).await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(sha256_file(dir.path().join("missing")).await.is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+            let metadata = std::fs::metadata(&file).unwrap();
+            assert_eq!(file_mode(&metadata).as_deref(), Some("640"));
+        }
     }
 }
@@ -0,0 +1,146 @@
+use crate::handlers::HandlerState;
+use crate::handlers::paths::resolve_request_paths;
+use crate::lsp::{self, LspServerConfig};
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::{HttpResponse, json_error};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+/// Bridge a remote editor to a configured language server.
+///
+/// The request must ask for `Upgrade: lsp`; after the `101` response the
+/// connection carries LSP base-protocol messages in both directions. Stdio
+/// servers are started in the project directory (`project_path` or the
+/// session's) and stopped when the editor disconnects.
+pub async fn handle_lsp(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
+    let HandlerContext {
+        request,
+        mut stream,
+        proxy_conn_id,
+        mut path_params,
+    } = ctx;
+
+    let name = path_params.get("server").cloned().unwrap_or_default();
+    let Some(server) = state.lsp_servers.get(&name).cloned() else {
+        let _ = json_error(404, format!("Unknown language server '{}'", name))
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    };
+
+    if !request
+        .header("upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("lsp"))
+    {
+        let _ = json_error(
+            426,
+            "Language servers need a connection with 'Upgrade: lsp'",
+        )
+        .header("Upgrade", "lsp")
+        .header("Connection", "Upgrade")
+        .send(&mut stream)
+        .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    match server {
+        LspServerConfig::Stdio { command, args, env } => {
+            let project = match resolve_request_paths(&request, &mut path_params, &state).await {
+                Ok(paths) => paths.base,
+                Err((status, message)) => {
+                    let _ = json_error(status, message).send(&mut stream).await;
+                    return Ok(HttpResponse::ok());
+                }
+            };
+
+            let mut child = match Command::new(&command)
+                .args(&args)
+                .envs(&env)
+                .current_dir(&project)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+            {
+                Ok(child) => child,
+                Err(e) => {
+                    let _ = json_error(502, format!("Failed to start '{}': {}", command, e))
+                        .send(&mut stream)
+                        .await;
+                    return Ok(HttpResponse::ok());
+                }
+            };
+            info!(
+                "('{}') Started language server '{}' in {}",
+                proxy_conn_id,
+                name,
+                project.display()
+            );
+
+            let (Some(stdin), Some(stdout), Some(stderr)) =
+                (child.stdin.take(), child.stdout.take(), child.stderr.take())
+            else {
+                return Ok(HttpResponse::ok());
+            };
+            let log_name = name.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    debug!("[lsp {}] {}", log_name, line);
+                }
+            });
+
+            switch_protocols(&mut stream).await?;
+            let (editor_reader, editor_writer) = stream.into_split();
+            if let Err(e) = lsp::bridge(editor_reader, editor_writer, stdout, stdin).await {
+                warn!("('{}') Language server '{}': {}", proxy_conn_id, name, e);
+            }
+            let _ = child.kill().await;
+        }
+        LspServerConfig::Tcp { address } => {
+            let server = match TcpStream::connect(&address).await {
+                Ok(server) => server,
+                Err(e) => {
+                    let _ = json_error(502, format!("Failed to connect to {}: {}", address, e))
+                        .send(&mut stream)
+                        .await;
+                    return Ok(HttpResponse::ok());
+                }
+            };
+            info!(
+                "('{}') Connected to language server '{}' at {}",
+                proxy_conn_id, name, address
+            );
+
+            switch_protocols(&mut stream).await?;
+            let (editor_reader, editor_writer) = stream.into_split();
+            let (server_reader, server_writer) = server.into_split();
+            if let Err(e) =
+                lsp::bridge(editor_reader, editor_writer, server_reader, server_writer).await
+            {
+                warn!("('{}') Language server '{}': {}", proxy_conn_id, name, e);
+            }
+        }
+    }
+
+    info!(
+        "('{}') Language server '{}' disconnected",
+        proxy_conn_id, name
+    );
+    Ok(HttpResponse::ok())
+}
+
+async fn switch_protocols(stream: &mut TcpStream) -> Result<()> {
+    stream
+        .write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: lsp\r\n\r\n",
+        )
+        .await?;
+    stream.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use crate::handlers::HandlerState;
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::HttpResponse;
+use serde_json::json;
+
+/// List external MCP servers and their health (GET /api/mcp/servers)
+pub async fn handle_list_mcp_servers(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let mut stream = ctx.stream;
+    let servers = state.mcp_servers.health().await;
+
+    let body = json!({
+        "total": servers.len(),
+        "servers": servers,
+    });
+    let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
+    Ok(HttpResponse::ok())
+}
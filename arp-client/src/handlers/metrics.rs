@@ -0,0 +1,19 @@
+use crate::metrics::RouteMetrics;
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::HttpResponse;
+use std::sync::Arc;
+
+/// Report request counts, error rates and latency percentiles per route
+pub async fn handle_metrics(
+    ctx: HandlerContext,
+    metrics: Arc<RouteMetrics>,
+) -> Result<HttpResponse> {
+    let mut stream = ctx.stream;
+    let _ = HttpResponse::ok()
+        .json(&metrics.snapshot())
+        .header("Cache-Control", "no-store")
+        .send(&mut stream)
+        .await;
+    Ok(HttpResponse::ok())
+}
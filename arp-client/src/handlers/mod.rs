@@ -1,25 +1,183 @@
+#[cfg(feature = "fs")]
+pub mod archive;
+#[cfg(feature = "fs")]
 pub mod filesystem;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "mcp")]
+pub mod mcp;
+#[cfg(feature = "dashboard")]
+pub mod metrics;
+pub mod paths;
+#[cfg(feature = "fs")]
+pub mod preview;
+#[cfg(feature = "command-mode")]
 pub mod proxy;
+#[cfg(feature = "executors")]
 pub mod session;
+#[cfg(feature = "executors")]
+pub mod stats;
+#[cfg(feature = "executors")]
+pub mod storage;
+#[cfg(feature = "fs")]
+pub mod sync;
+#[cfg(feature = "fs")]
+pub mod upload;
 
+#[cfg(feature = "executors")]
+use crate::agentx::cache::ListingCache;
+#[cfg(feature = "executors")]
+use crate::agentx::storage::{RetentionRules, enforce_periodically};
+#[cfg(feature = "executors")]
+use crate::approvers::Approvers;
+#[cfg(feature = "command-mode")]
 use crate::config::ClientConfig;
+#[cfg(feature = "lsp")]
+use crate::lsp::LspServers;
+#[cfg(feature = "mcp")]
+use crate::mcp::servers::McpServers;
+#[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+use crate::policy::SessionPolicy;
+#[cfg(feature = "executors")]
+use crate::redact::Redactor;
+#[cfg(feature = "command-mode")]
+use crate::relays::Relays;
+#[cfg(feature = "executors")]
 use crate::session::SessionManager;
+#[cfg(feature = "executors")]
+use crate::session_records::SessionRecords;
+#[cfg(feature = "command-mode")]
 use std::sync::Arc;
 
 /// Shared state for handlers
+#[cfg(feature = "command-mode")]
 #[derive(Clone)]
 pub struct HandlerState {
     pub config: Arc<ClientConfig>,
+    #[cfg(feature = "executors")]
     pub session_manager: SessionManager,
+    #[cfg(feature = "mcp")]
+    pub mcp_servers: Arc<McpServers>,
+    #[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+    pub session_policy: Arc<SessionPolicy>,
+    #[cfg(feature = "lsp")]
+    pub lsp_servers: Arc<LspServers>,
+    /// The servers the tunnel may register with and their round-trip times
+    pub relays: Arc<Relays>,
+    /// Agent project and session listings, refreshed when sessions end
+    #[cfg(feature = "executors")]
+    pub listing_cache: Arc<ListingCache>,
+    /// Rules from `--retention`, enforced in the background when present
+    #[cfg(feature = "executors")]
+    pub retention: Arc<RetentionRules>,
+    /// Who may decide permission prompts, from `--approvers`
+    #[cfg(feature = "executors")]
+    pub approvers: Arc<Approvers>,
+    /// When this state was created, for the uptime in `/api/stats`
+    #[cfg(feature = "executors")]
+    pub started: std::time::Instant,
 }
 
+#[cfg(feature = "command-mode")]
 impl HandlerState {
     pub fn new(config: ClientConfig) -> Self {
-        let session_manager = SessionManager::new();
+        // Rules are checked by ClientConfig::validate at startup
+        #[cfg(feature = "executors")]
+        let session_manager = {
+            let redactor = Redactor::new(config.redact, &config.redact_rules).unwrap_or_default();
+            SessionManager::with_redactor(redactor).with_records(SessionRecords::open_default())
+        };
+        #[cfg(feature = "executors")]
+        let listing_cache = {
+            let cache = Arc::new(ListingCache::new(std::time::Duration::from_secs(
+                config.listing_cache_secs,
+            )));
+            tokio::spawn(
+                cache
+                    .clone()
+                    .invalidate_on_session_end(session_manager.subscribe_ended()),
+            );
+            cache
+        };
+        #[cfg(feature = "executors")]
+        let retention = {
+            // Rules are checked by ClientConfig::validate at startup
+            let rules = Arc::new(RetentionRules::parse(&config.retention).unwrap_or_default());
+            if !rules.is_empty() {
+                tokio::spawn(enforce_periodically(
+                    rules.clone(),
+                    std::time::Duration::from_secs(config.retention_interval),
+                    config.retention_dry_run,
+                    listing_cache.clone(),
+                ));
+            }
+            rules
+        };
 
+        let relays = Arc::new(Relays::new(&config));
         HandlerState {
             config: Arc::new(config),
+            #[cfg(feature = "executors")]
             session_manager,
+            #[cfg(feature = "mcp")]
+            mcp_servers: Arc::new(McpServers::default()),
+            #[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+            session_policy: Arc::new(SessionPolicy::default()),
+            #[cfg(feature = "lsp")]
+            lsp_servers: Arc::new(LspServers::default()),
+            relays,
+            #[cfg(feature = "executors")]
+            listing_cache,
+            #[cfg(feature = "executors")]
+            retention,
+            #[cfg(feature = "executors")]
+            approvers: Arc::new(Approvers::default()),
+            #[cfg(feature = "executors")]
+            started: std::time::Instant::now(),
         }
     }
+
+    /// Attach external MCP servers to the agents this state launches
+    #[cfg(feature = "mcp")]
+    pub fn with_mcp_servers(mut self, mcp_servers: McpServers) -> Self {
+        self.mcp_servers = Arc::new(mcp_servers);
+        self
+    }
+
+    /// Restrict the sessions this state may create
+    #[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+    pub fn with_session_policy(mut self, session_policy: SessionPolicy) -> Self {
+        self.session_policy = Arc::new(session_policy);
+        self
+    }
+
+    /// Attribute permission decisions to these approvers
+    #[cfg(feature = "executors")]
+    pub fn with_approvers(mut self, approvers: Approvers) -> Self {
+        self.approvers = Arc::new(approvers);
+        self
+    }
+
+    /// Make language servers reachable through `/api/lsp/{server}`
+    #[cfg(feature = "lsp")]
+    pub fn with_lsp_servers(mut self, lsp_servers: LspServers) -> Self {
+        self.lsp_servers = Arc::new(lsp_servers);
+        self
+    }
+
+    /// Report the tunnel's server measurements in `/api/stats`
+    pub fn with_relays(mut self, relays: Arc<Relays>) -> Self {
+        self.relays = relays;
+        self
+    }
+}
+
+/// Parse a boolean string value
+#[cfg(any(feature = "fs", feature = "executors"))]
+pub fn parse_bool_str(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
 }
@@ -0,0 +1,361 @@
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use crate::handlers::HandlerState;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use common::http::HttpRequest;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use std::collections::HashMap;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use std::io::ErrorKind;
+#[cfg(feature = "fs")]
+use std::path::Component;
+#[cfg(feature = "fs")]
+use std::path::Path;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use std::path::PathBuf;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use tokio::fs;
+#[cfg(any(feature = "fs", feature = "lsp"))]
+use tracing::warn;
+
+/// Whether the filesystem API follows symbolic links
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow links whose target stays inside the project directory
+    Follow,
+    /// Refuse paths through a link and leave links out of listings
+    Deny,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "deny" => Ok(SymlinkPolicy::Deny),
+            other => Err(format!(
+                "invalid symlink policy '{}', expected 'follow' or 'deny'",
+                other
+            )),
+        }
+    }
+}
+
+/// Restrictions applied by the filesystem API
+#[derive(Debug, Clone, Copy)]
+pub struct FsOptions {
+    pub symlinks: SymlinkPolicy,
+    /// Hide dotfiles and refuse paths through them
+    pub hide_hidden: bool,
+    /// Refuse files larger than this many bytes (0 = no limit)
+    pub max_file_size: u64,
+}
+
+/// Status code and message of a rejected path
+#[cfg(any(feature = "fs", feature = "lsp"))]
+pub type PathError = (u16, String);
+
+/// Project directory and requested path of a filesystem API call
+#[cfg(any(feature = "fs", feature = "lsp"))]
+pub struct FsRequestPaths {
+    #[cfg(feature = "fs")]
+    pub session_id: Option<String>,
+    /// Canonical project directory
+    pub base: PathBuf,
+    /// Requested path relative to `base`, decoded but not yet resolved
+    #[cfg(feature = "fs")]
+    pub path: String,
+}
+
+/// Find the project directory of a filesystem API call, from its session
+/// or `project_path`, and check it against the session policy
+#[cfg(any(feature = "fs", feature = "lsp"))]
+pub async fn resolve_request_paths(
+    request: &HttpRequest,
+    path_params: &mut HashMap<String, String>,
+    state: &HandlerState,
+) -> Result<FsRequestPaths, PathError> {
+    let session_id = path_params.get("session_id").cloned();
+
+    let base_path_candidate = if let Some(session_id) = &session_id {
+        session_project_path(state, session_id).await?
+    } else {
+        let project_path_raw = match request.query_param("project_path") {
+            Some(value) if !value.trim().is_empty() => value.clone(),
+            _ => {
+                return Err((
+                    400,
+                    "project_path query parameter is required when session_id is not provided"
+                        .to_string(),
+                ));
+            }
+        };
+
+        // Query parameters arrive decoded; decoding again would turn `%252e`
+        // into `.` and make literal `%` names unreachable
+        PathBuf::from(project_path_raw)
+    };
+
+    let access_error = |e: std::io::Error, action: &str| match e.kind() {
+        ErrorKind::NotFound => (
+            404,
+            "Requested project_path does not exist or is not accessible".to_string(),
+        ),
+        ErrorKind::PermissionDenied => {
+            (403, "Permission denied accessing project_path".to_string())
+        }
+        _ => (500, format!("Failed to {} project_path: {}", action, e)),
+    };
+
+    let canonical_base = fs::canonicalize(&base_path_candidate)
+        .await
+        .map_err(|e| access_error(e, "resolve"))?;
+    let base_metadata = fs::metadata(&canonical_base)
+        .await
+        .map_err(|e| access_error(e, "access metadata of"))?;
+
+    if !base_metadata.is_dir() {
+        return Err((400, "project_path must reference a directory".to_string()));
+    }
+
+    state
+        .session_policy
+        .check_project_path(&canonical_base)
+        .map_err(|violation| {
+            warn!(
+                "Filesystem request denied by policy ({}): {}",
+                violation.rule, violation.message
+            );
+            (403, violation.message)
+        })?;
+
+    // Wildcard path parameters are still percent-encoded, query parameters are not
+    #[cfg(feature = "fs")]
+    let path = match path_params.remove("path") {
+        Some(raw_path) => urlencoding::decode(&raw_path)
+            .map_err(|e| (400, format!("Failed to decode path parameter: {}", e)))?
+            .into_owned(),
+        None => request.query_param("path").cloned().unwrap_or_default(),
+    };
+
+    Ok(FsRequestPaths {
+        #[cfg(feature = "fs")]
+        session_id,
+        base: canonical_base,
+        #[cfg(feature = "fs")]
+        path,
+    })
+}
+
+/// Project directory of the session `session_id`
+#[cfg(all(any(feature = "fs", feature = "lsp"), feature = "executors"))]
+async fn session_project_path(
+    state: &HandlerState,
+    session_id: &str,
+) -> Result<PathBuf, PathError> {
+    let session = state
+        .session_manager
+        .get_session(session_id)
+        .await
+        .ok_or_else(|| (404, "Session not found".to_string()))?;
+    session
+        .get_project_path()
+        .await
+        .ok_or_else(|| (404, "Project path unavailable for this session".to_string()))
+}
+
+/// Builds without executors have no sessions
+#[cfg(all(any(feature = "fs", feature = "lsp"), not(feature = "executors")))]
+async fn session_project_path(_: &HandlerState, _: &str) -> Result<PathBuf, PathError> {
+    Err((404, "Session not found".to_string()))
+}
+
+#[cfg(feature = "fs")]
+fn resolve_path(base: &Path, relative: &str) -> Result<PathBuf, String> {
+    let mut resolved = base.to_path_buf();
+
+    if relative.is_empty() {
+        return Ok(resolved);
+    }
+
+    let relative_path = Path::new(relative);
+    for component in relative_path.components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                return Err("Path traversal outside the project directory is not allowed".into());
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("Absolute paths are not allowed".into());
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Resolve `relative` below the canonical `base` and make sure the result,
+/// after following any links the options allow, is still inside `base`
+#[cfg(feature = "fs")]
+pub async fn resolve_target(
+    base: &Path,
+    relative: &str,
+    options: &FsOptions,
+) -> Result<PathBuf, PathError> {
+    let not_found = || {
+        (
+            404,
+            format!("Path not found: {}", relative.trim_start_matches('/')),
+        )
+    };
+    let access_error = |e: std::io::Error| match e.kind() {
+        ErrorKind::NotFound => not_found(),
+        ErrorKind::PermissionDenied => (403, "Permission denied accessing requested path".into()),
+        _ => (500, format!("Failed to access path: {}", e)),
+    };
+
+    if relative.contains('\0') {
+        return Err((400, "Path contains a NUL byte".into()));
+    }
+    let resolved = resolve_path(base, relative).map_err(|message| (400, message))?;
+    let components = resolved.strip_prefix(base).unwrap_or(Path::new(""));
+
+    if options.hide_hidden
+        && components
+            .iter()
+            .any(|part| part.to_string_lossy().starts_with('.'))
+    {
+        return Err(not_found());
+    }
+
+    if options.symlinks == SymlinkPolicy::Deny {
+        let mut current = base.to_path_buf();
+        for part in components {
+            current.push(part);
+            let metadata = fs::symlink_metadata(&current).await.map_err(access_error)?;
+            if metadata.file_type().is_symlink() {
+                return Err((
+                    403,
+                    "Access denied: path goes through a symbolic link".into(),
+                ));
+            }
+        }
+    }
+
+    let canonical = fs::canonicalize(&resolved).await.map_err(access_error)?;
+    if !canonical.starts_with(base) {
+        return Err((
+            403,
+            "Access denied: requested path escapes the project directory".into(),
+        ));
+    }
+    Ok(canonical)
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+
+    fn options(symlinks: SymlinkPolicy) -> FsOptions {
+        FsOptions {
+            symlinks,
+            hide_hidden: true,
+            max_file_size: 0,
+        }
+    }
+
+    #[test]
+    fn resolve_path_rejects_parent_dir() {
+        let base = PathBuf::from("/workspace/project");
+        let result = resolve_path(&base, "../secret");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn resolve_path_rejects_absolute() {
+        let base = PathBuf::from("/workspace/project");
+        let result = resolve_path(&base, "/etc/passwd");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn resolve_path_joins_relative() {
+        let base = PathBuf::from("/workspace/project");
+        let result = resolve_path(&base, "src/lib.rs").unwrap();
+        assert_eq!(result, PathBuf::from("/workspace/project/src/lib.rs"));
+    }
+
+    #[tokio::test]
+    async fn resolve_target_stays_inside_the_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("project");
+        std::fs::create_dir_all(base.join("src")).unwrap();
+        std::fs::write(base.join("src/lib.rs"), "").unwrap();
+        std::fs::write(base.join(".env"), "SECRET=1").unwrap();
+        std::fs::write(dir.path().join("secret"), "").unwrap();
+        let base = base.canonicalize().unwrap();
+        let follow = options(SymlinkPolicy::Follow);
+
+        let status = |result: Result<PathBuf, (u16, String)>| result.err().map(|(s, _)| s);
+        assert_eq!(
+            resolve_target(&base, "src/lib.rs", &follow).await.unwrap(),
+            base.join("src/lib.rs")
+        );
+        assert_eq!(
+            status(resolve_target(&base, "src/../../secret", &follow).await),
+            Some(400)
+        );
+        assert_eq!(
+            status(resolve_target(&base, "src/\0", &follow).await),
+            Some(400)
+        );
+        assert_eq!(
+            status(resolve_target(&base, ".env", &follow).await),
+            Some(404)
+        );
+
+        // `%2e%2e` decodes to `..`; a second, erroneous decode of `%252e` would too
+        let encoded = urlencoding::decode("%2e%2e/secret").unwrap();
+        assert_eq!(
+            status(resolve_target(&base, &encoded, &follow).await),
+            Some(400)
+        );
+        assert_eq!(
+            status(resolve_target(&base, "%2e%2e/secret", &follow).await),
+            Some(404)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolve_target_applies_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("project");
+        std::fs::create_dir_all(base.join("src")).unwrap();
+        std::fs::write(dir.path().join("secret"), "").unwrap();
+        symlink(dir.path().join("secret"), base.join("escape")).unwrap();
+        symlink(base.join("src"), base.join("code")).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let follow = options(SymlinkPolicy::Follow);
+        let deny = options(SymlinkPolicy::Deny);
+        let status = |result: Result<PathBuf, (u16, String)>| result.err().map(|(s, _)| s);
+
+        assert_eq!(
+            status(resolve_target(&base, "escape", &follow).await),
+            Some(403)
+        );
+        assert_eq!(
+            resolve_target(&base, "code", &follow).await.unwrap(),
+            base.join("src")
+        );
+        assert_eq!(
+            status(resolve_target(&base, "code", &deny).await),
+            Some(403)
+        );
+        assert!(resolve_target(&base, "src", &deny).await.is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{Value, json};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+/// Images larger than this are not previewed (20 MiB)
+const MAX_IMAGE_SOURCE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Images embedded as-is when thumbnails are unavailable (512 KiB)
+#[cfg(not(feature = "thumbnails"))]
+const MAX_INLINE_IMAGE_BYTES: u64 = 512 * 1024;
+
+/// Bytes shown in a hexdump or text preview
+const PREVIEW_BYTES: usize = 4096;
+
+/// Default and largest edge of a thumbnail, in pixels
+pub const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+#[cfg(feature = "thumbnails")]
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// Status code and message of a preview that couldn't be made
+type PreviewError = (u16, String);
+
+/// Preview of the file at `path` for `?preview=true`: a base64 image
+/// (downscaled with the `thumbnails` feature), a hexdump of the start of a
+/// binary file, or the start of a text file
+pub async fn build_preview(
+    path: &Path,
+    size: u64,
+    mime_type: &str,
+    thumbnail_size: u32,
+) -> Result<Value, PreviewError> {
+    let is_image = mime_type.starts_with("image/") && mime_type != "image/svg+xml";
+    if is_image {
+        return image_preview(path, size, mime_type, thumbnail_size).await;
+    }
+
+    let head = read_head(path).await?;
+    let truncated = size > head.len() as u64;
+    if mime_type.starts_with("text/") || is_printable_text(&head) {
+        return Ok(json!({
+            "kind": "text",
+            "mime_type": mime_type,
+            "content": String::from_utf8_lossy(&head),
+            "truncated": truncated,
+        }));
+    }
+
+    Ok(json!({
+        "kind": "hex",
+        "mime_type": mime_type,
+        "content": hexdump(&head),
+        "truncated": truncated,
+    }))
+}
+
+async fn read_head(path: &Path) -> Result<Vec<u8>, PreviewError> {
+    let file = fs::File::open(path)
+        .await
+        .map_err(|e| (500, format!("Failed to read file: {}", e)))?;
+    let mut head = Vec::with_capacity(PREVIEW_BYTES);
+    file.take(PREVIEW_BYTES as u64)
+        .read_to_end(&mut head)
+        .await
+        .map_err(|e| (500, format!("Failed to read file content: {}", e)))?;
+    Ok(head)
+}
+
+#[cfg_attr(not(feature = "thumbnails"), allow(unused_variables))]
+async fn image_preview(
+    path: &Path,
+    size: u64,
+    mime_type: &str,
+    thumbnail_size: u32,
+) -> Result<Value, PreviewError> {
+    if size > MAX_IMAGE_SOURCE_BYTES {
+        return Err((
+            413,
+            format!(
+                "Image is {} bytes, larger than the {} byte preview limit",
+                size, MAX_IMAGE_SOURCE_BYTES
+            ),
+        ));
+    }
+    let bytes = fs::read(path)
+        .await
+        .map_err(|e| (500, format!("Failed to read file: {}", e)))?;
+
+    #[cfg(feature = "thumbnails")]
+    {
+        let max_edge = thumbnail_size.clamp(16, MAX_THUMBNAIL_SIZE);
+        let mut preview = tokio::task::spawn_blocking(move || thumbnail(&bytes, max_edge))
+            .await
+            .map_err(|e| (500, format!("Thumbnail task failed: {}", e)))??;
+        preview["source_mime_type"] = json!(mime_type);
+        Ok(preview)
+    }
+
+    #[cfg(not(feature = "thumbnails"))]
+    {
+        if size > MAX_INLINE_IMAGE_BYTES {
+            return Err((
+                413,
+                format!(
+                    "Image is {} bytes; previews of images over {} bytes need the thumbnails feature",
+                    size, MAX_INLINE_IMAGE_BYTES
+                ),
+            ));
+        }
+        Ok(json!({
+            "kind": "image",
+            "mime_type": mime_type,
+            "data": BASE64.encode(&bytes),
+            "thumbnail": false,
+        }))
+    }
+}
+
+/// Downscale an image so its longest edge is at most `max_edge` and encode it as PNG
+#[cfg(feature = "thumbnails")]
+fn thumbnail(bytes: &[u8], max_edge: u32) -> Result<Value, PreviewError> {
+    use image::{GenericImageView, ImageFormat};
+
+    let image = image::load_from_memory(bytes)
+        .map_err(|e| (422, format!("Failed to decode image: {}", e)))?;
+    let (original_width, original_height) = image.dimensions();
+    let thumbnail = image.thumbnail(max_edge, max_edge);
+
+    let mut png = Vec::new();
+    thumbnail
+        .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| (500, format!("Failed to encode thumbnail: {}", e)))?;
+
+    Ok(json!({
+        "kind": "image",
+        "mime_type": "image/png",
+        "data": BASE64.encode(&png),
+        "thumbnail": true,
+        "width": thumbnail.width(),
+        "height": thumbnail.height(),
+        "original_width": original_width,
+        "original_height": original_height,
+    }))
+}
+
+/// Valid UTF-8 without control characters other than whitespace
+fn is_printable_text(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_ok_and(|text| {
+        text.chars()
+            .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+    })
+}
+
+/// Classic `offset  hex bytes  |ascii|` dump, 16 bytes per line
+fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 4 + bytes.len() / 16 * 12);
+    for (index, chunk) in bytes.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x} ", index * 16));
+        for column in 0..16 {
+            if column == 8 {
+                out.push(' ');
+            }
+            match chunk.get(column) {
+                Some(byte) => out.push_str(&format!(" {:02x}", byte)),
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_matches_the_usual_layout() {
+        let dump = hexdump(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\x01");
+        assert_eq!(
+            dump,
+            "00000000  89 50 4e 47 0d 0a 1a 0a  00 00 00 0d 49 48 44 52  |.PNG........IHDR|\n\
+             00000010  01                                                |.|\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn preview_picks_text_hex_or_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("notes.md");
+        let blob = dir.path().join("data.bin");
+        std::fs::write(&text, "# Notes\n").unwrap();
+        std::fs::write(&blob, [0u8, 159, 146, 150]).unwrap();
+
+        let preview = build_preview(&text, 8, "text/markdown", DEFAULT_THUMBNAIL_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(preview["kind"], "text");
+        assert_eq!(preview["content"], "# Notes\n");
+
+        let preview = build_preview(&blob, 4, "application/octet-stream", DEFAULT_THUMBNAIL_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(preview["kind"], "hex");
+        assert!(
+            preview["content"]
+                .as_str()
+                .unwrap()
+                .starts_with("00000000  00 9f 92 96")
+        );
+
+        let missing = build_preview(&dir.path().join("gone.png"), 10, "image/png", 64).await;
+        assert_eq!(missing.unwrap_err().0, 500);
+    }
+}
@@ -2,7 +2,7 @@ use crate::handlers::HandlerState;
 use crate::router::HandlerContext;
 use anyhow::Result;
 use common::http::HttpResponse;
-use common::join_streams;
+use common::join_streams_with;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tracing::{error, info};
@@ -44,33 +44,11 @@ fn validate_port(port: u16) -> Result<u16, String> {
     Ok(port)
 }
 
-/// Handle TCP proxy requests
-pub async fn handle_proxy(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
-    let proxy_conn_id = &ctx.proxy_conn_id;
-    let config = &state.config;
-
-    // Connect to local service
-    let local_stream = TcpStream::connect(config.local_service_addr()).await?;
-    info!(
-        "('{}') Connected to local service at {}.",
-        proxy_conn_id,
-        config.local_service_addr()
-    );
-
-    // Join streams (proxy <-> local service)
-    info!("('{}') Joining streams...", proxy_conn_id);
-    join_streams(ctx.stream, local_stream).await?;
-    info!("('{}') Streams joined and finished.", proxy_conn_id);
-
-    // Return a dummy response (stream already handled)
-    Ok(HttpResponse::ok())
-}
-
 /// Handle dynamic proxy requests to local ports
 /// Route pattern: /proxy/{port}/{*path}
 pub async fn handle_dynamic_proxy(
     ctx: HandlerContext,
-    _state: HandlerState,
+    state: HandlerState,
 ) -> Result<HttpResponse> {
     let proxy_conn_id = &ctx.proxy_conn_id;
 
@@ -123,7 +101,7 @@ pub async fn handle_dynamic_proxy(
             .request
             .query_params
             .iter()
-            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
             .collect();
         format!("{}?{}", target_path, query_string.join("&"))
     } else {
@@ -166,24 +144,31 @@ pub async fn handle_dynamic_proxy(
     )
     .into_bytes();
 
-    // Add headers (skip host and connection)
+    // Add headers (skip host, connection and the body framing set below)
     for (key, value) in &ctx.request.headers {
-        if !matches!(key.to_lowercase().as_str(), "host" | "connection") {
+        if !matches!(
+            key.to_lowercase().as_str(),
+            "host" | "connection" | "content-length"
+        ) {
             request_data.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
         }
     }
 
     request_data.extend_from_slice(b"Connection: close\r\n");
 
-    if !ctx.request.body.is_empty() {
-        request_data.extend_from_slice(
-            format!("Content-Length: {}\r\n", ctx.request.body.len()).as_bytes(),
-        );
+    if ctx.request.is_chunked() {
+        request_data.extend_from_slice(b"\r\n");
+        request_data.extend_from_slice(&ctx.request.chunked_body());
+    } else {
+        if !ctx.request.body.is_empty() {
+            request_data.extend_from_slice(
+                format!("Content-Length: {}\r\n", ctx.request.body.len()).as_bytes(),
+            );
+        }
+        request_data.extend_from_slice(b"\r\n");
+        request_data.extend_from_slice(&ctx.request.body);
     }
 
-    request_data.extend_from_slice(b"\r\n");
-    request_data.extend_from_slice(&ctx.request.body);
-
     // Send request
     if let Err(e) = target_stream.write_all(&request_data).await {
         error!("('{}') Send failed: {}", proxy_conn_id, e);
@@ -201,8 +186,8 @@ pub async fn handle_dynamic_proxy(
         proxy_conn_id
     );
 
-    // Stream response back using join_streams
-    join_streams(ctx.stream, target_stream).await?;
+    // Stream response back using join_streams_with
+    join_streams_with(ctx.stream, target_stream, &state.config.copy_config()).await?;
     info!("('{}') Proxy completed", proxy_conn_id);
 
     Ok(HttpResponse::ok())
@@ -1,19 +1,32 @@
+#[cfg(feature = "github")]
+use crate::agentx::github;
+use crate::agentx::snapshots::{self, Stage};
+use crate::agentx::tools::extract_tool_calls;
+#[cfg(feature = "github")]
+use crate::agentx::tools::summarize_transcript;
+#[cfg(feature = "github")]
+use crate::agentx::types::TranscriptSummary;
+use crate::agentx::types::{HistoryPage, HistoryWindow};
 use crate::agentx::{claude, codex, gemini};
+use crate::events::ClientEvent;
 use crate::executor::{
     ClaudeOptions, CodexOptions, ExecutorKind, ExecutorOptions, GeminiOptions, build_command,
-    parse_bool_str,
 };
-use crate::handlers::HandlerState;
+use crate::handlers::{HandlerState, parse_bool_str};
+use crate::orphans;
 use crate::router::HandlerContext;
-use crate::session::{CommandSession, SessionStatus};
+use crate::session::{CommandSession, PermissionDecision, PermissionOutcome, SessionStatus};
+use crate::session_records::SessionRecord;
 use anyhow::{Result, anyhow};
 use common::http::{HttpResponse, json_error};
 use serde_json::{Value, json};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::oneshot;
-use tracing::{error, info, warn};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, error, info, warn};
 
 /// Unified handler for session operations
 pub async fn handle_session(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
@@ -34,13 +47,16 @@ pub async fn handle_session(ctx: HandlerContext, state: HandlerState) -> Result<
         (common::http::HttpMethod::DELETE, Some(session_id)) => {
             handle_delete_session(ctx, state, &session_id).await
         }
-        // GET /api/sessions - List all sessions (future implementation)
+        // GET /api/sessions - List sessions held in memory
         (common::http::HttpMethod::GET, None) => {
             info!("('{}') List sessions request", proxy_conn_id);
+            let body = json!({
+                "type": "sessions",
+                "stats": state.session_manager.get_stats().await,
+                "sessions": state.session_manager.list_sessions().await,
+            });
             let mut stream = ctx.stream;
-            let _ = json_error(501, "List sessions not yet implemented")
-                .send(&mut stream)
-                .await;
+            let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
             Ok(HttpResponse::ok())
         }
         _ => {
@@ -77,29 +93,51 @@ async fn handle_create_session(ctx: HandlerContext, state: HandlerState) -> Resu
         .unwrap_or("")
         .to_string();
 
-    // Validate required parameters
-    if prompt.is_empty() || project_path.is_empty() {
-        let mut stream = ctx.stream;
-        let _ = json_error(
-            400,
-            "prompt and project_path are required and cannot be empty",
-        )
-        .send(&mut stream)
-        .await;
-        return Ok(HttpResponse::ok());
-    }
+    // Validate required parameters, then the executor options
+    let validated = if prompt.is_empty() {
+        Err(ValidationError::Missing("prompt"))
+    } else if project_path.is_empty() {
+        Err(ValidationError::Missing("project_path"))
+    } else {
+        parse_executor_options(&body_json, request)
+    };
+    let executor_options = match validated {
+        Ok(options) => options,
+        Err(e) => {
+            let mut stream = ctx.stream;
+            let _ = e.response().send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
 
-    // Parse executor options
-    let (executor_options, error) = parse_executor_options(&body_json, request);
+    // Check and run in the resolved directory, not the client-supplied string
+    let project_path = match tokio::fs::canonicalize(&project_path).await {
+        Ok(path) if path.is_dir() => path,
+        _ => {
+            let mut stream = ctx.stream;
+            let _ = ValidationError::NotADirectory(project_path)
+                .response()
+                .send(&mut stream)
+                .await;
+            return Ok(HttpResponse::ok());
+        }
+    };
 
-    if let Some(error_message) = error {
+    if let Err(violation) = state.session_policy.check(&executor_options, &project_path) {
+        warn!(
+            "('{}') Session request denied by policy ({}): {}",
+            proxy_conn_id, violation.rule, violation.message
+        );
+        let body = json!({
+            "type": "error",
+            "message": violation.message,
+            "rule": violation.rule,
+        });
         let mut stream = ctx.stream;
-        let _ = json_error(400, error_message).send(&mut stream).await;
+        let _ = HttpResponse::new(403).json(&body).send(&mut stream).await;
         return Ok(HttpResponse::ok());
     }
 
-    let executor_options = executor_options.unwrap();
-
     info!(
         "('{}') Creating session with executor: {}",
         proxy_conn_id,
@@ -110,18 +148,23 @@ async fn handle_create_session(ctx: HandlerContext, state: HandlerState) -> Resu
     let (session_tx, session_rx) = oneshot::channel();
 
     // Start command execution in background
-    let session_manager_clone = state.session_manager.clone();
+    let state_clone = state.clone();
     tokio::spawn(async move {
+        let events = state_clone.session_manager.events().clone();
         if let Err(e) = execute_command(
             session_tx,
             prompt,
-            project_path,
+            project_path.to_string_lossy().into_owned(),
             executor_options,
-            session_manager_clone,
+            state_clone,
         )
         .await
         {
             error!("Command execution failed: {}", e);
+            events.publish(ClientEvent::Error {
+                session_id: None,
+                message: e.to_string(),
+            });
         }
     });
 
@@ -166,13 +209,14 @@ async fn handle_get_session(
     session_id: &str,
 ) -> Result<HttpResponse> {
     let proxy_conn_id = &ctx.proxy_conn_id;
-    let from_line = ctx
-        .request
-        .query_param("from_line")
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(0);
+    let from_line = resume_from_line(&ctx.request);
 
     let in_memory_session = state.session_manager.get_session(session_id).await;
+    // Sessions from before a restart are only known by their record
+    let record = match in_memory_session {
+        Some(_) => None,
+        None => state.session_manager.find_record(session_id).await,
+    };
 
     // Determine which executor to use for loading history
     let executor_kind = if let Some(session) = &in_memory_session {
@@ -181,19 +225,38 @@ async fn handle_get_session(
         ctx.request
             .query_param("executor")
             .and_then(|value| ExecutorKind::from_str(value))
+            .or(record.as_ref().map(|record| record.executor))
             .unwrap_or(ExecutorKind::Claude)
     };
+    let history_id = record
+        .as_ref()
+        .and_then(|record| record.agent_session_id.as_deref())
+        .unwrap_or(session_id);
 
-    let historical_messages = load_history_for_executor(executor_kind, session_id).await;
+    // SSE line numbers are 1-based; `from_line` is the first line to send
+    let window = HistoryWindow::unlimited_from(from_line.saturating_sub(1));
+    let historical_messages = load_history_for_executor(executor_kind, history_id, window)
+        .await
+        .map(|mut page| {
+            page.messages = state.session_manager.redactor().apply_all(page.messages);
+            page
+        });
 
-    if in_memory_session.is_none() && historical_messages.is_none() {
+    if in_memory_session.is_none() && historical_messages.is_none() && record.is_none() {
         warn!("('{}') Session not found: {}", proxy_conn_id, session_id);
         let mut stream = ctx.stream;
         let _ = json_error(404, "Session not found").send(&mut stream).await;
         return Ok(HttpResponse::ok());
     }
 
-    stream_unified_session(ctx, in_memory_session, historical_messages, from_line).await
+    stream_unified_session(
+        ctx,
+        in_memory_session,
+        record,
+        historical_messages,
+        from_line,
+    )
+    .await
 }
 
 /// Handle session cancellation without deletion (POST /api/sessions/{session_id}/cancel)
@@ -205,7 +268,8 @@ pub async fn handle_cancel_session(
         Some(v) if !v.is_empty() => v.clone(),
         _ => {
             let mut stream = ctx.stream;
-            let _ = json_error(400, "session_id is required")
+            let _ = ValidationError::Missing("session_id")
+                .response()
                 .send(&mut stream)
                 .await;
             return Ok(HttpResponse::ok());
@@ -235,6 +299,530 @@ pub async fn handle_cancel_session(
     Ok(HttpResponse::ok())
 }
 
+/// List the tools a session invoked (GET /api/sessions/{session_id}/tools)
+///
+/// Reads the on-disk transcript, falling back to the live output buffer while
+/// the agent has not written one yet.
+pub async fn handle_session_tools(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let mut stream = ctx.stream;
+
+    let in_memory_session = state.session_manager.get_session(&session_id).await;
+    let executor_kind = match &in_memory_session {
+        Some(session) => session.executor_kind,
+        None => ctx
+            .request
+            .query_param("executor")
+            .and_then(|value| ExecutorKind::from_str(value))
+            .unwrap_or(ExecutorKind::Claude),
+    };
+
+    let history =
+        load_history_for_executor(executor_kind, &session_id, HistoryWindow::unlimited_from(0))
+            .await;
+    let messages = match history {
+        Some(page) => state.session_manager.redactor().apply_all(page.messages),
+        None => match &in_memory_session {
+            Some(session) => session
+                .get_output_from(0)
+                .await
+                .iter()
+                .filter_map(|line| serde_json::from_str(&line.content).ok())
+                .collect(),
+            None => {
+                let _ = json_error(404, "Session not found").send(&mut stream).await;
+                return Ok(HttpResponse::ok());
+            }
+        },
+    };
+
+    let tool_calls = extract_tool_calls(&messages);
+    let body = json!({
+        "session_id": session_id,
+        "executor": executor_kind.as_str(),
+        "total": tool_calls.len(),
+        "tool_calls": tool_calls,
+    });
+    let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
+    Ok(HttpResponse::ok())
+}
+
+/// Decide a permission prompt published on the session stream
+/// (POST /api/sessions/{session_id}/permissions/{permission_id})
+///
+/// Body: `{"decision": "approve" | "deny", "reason"?, "updated_input"?, "approver_token"?}`
+///
+/// With `--approvers`, the decision is made as the approver whose token is
+/// sent in `Authorization: Bearer <token>`, or whom the `approver_token` of
+/// a chat link was made for; a `decided_by` in the body is ignored. Prompts that need several approvers stay pending, answering
+/// `permission_pending` with the count so far, until enough distinct
+/// approvers have approved; a single denial decides them.
+pub async fn handle_permission_decision(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let permission_id = ctx
+        .path_params
+        .get("permission_id")
+        .cloned()
+        .unwrap_or_default();
+    let body = ctx.request.body_as_json().unwrap_or(Value::Null);
+    let token = ctx
+        .request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let mut stream = ctx.stream;
+
+    let approved = match body.get("decision").and_then(Value::as_str) {
+        Some("approve") | Some("approved") | Some("allow") => true,
+        Some("deny") | Some("denied") => false,
+        decision => {
+            let _ = ValidationError::choice("decision", decision, DECISIONS)
+                .response()
+                .send(&mut stream)
+                .await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let link_token = body.get("approver_token").and_then(Value::as_str);
+    let decided_by = match (token, link_token) {
+        (Some(token), _) => state.approvers.identify(&token),
+        (None, Some(link_token)) => state.approvers.verify_link(
+            link_token,
+            &session_id,
+            &permission_id,
+            if approved { "approve" } else { "deny" },
+        ),
+        (None, None) => None,
+    }
+    .map(str::to_string);
+    if decided_by.is_none() && !state.approvers.is_empty() {
+        let _ = json_error(401, "Missing or invalid approver token")
+            .header("WWW-Authenticate", "Bearer")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let Some(session) = state.session_manager.get_session(&session_id).await else {
+        let _ = json_error(404, "Session not found").send(&mut stream).await;
+        return Ok(HttpResponse::ok());
+    };
+
+    let decision = PermissionDecision {
+        approved,
+        reason: body
+            .get("reason")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        updated_input: body.get("updated_input").filter(|v| !v.is_null()).cloned(),
+        decided_by,
+    };
+
+    let response = match session.resolve_permission(&permission_id, decision).await {
+        PermissionOutcome::Decided => HttpResponse::ok().json(&json!({
+            "type": "permission_decided",
+            "session_id": session_id,
+            "permission_id": permission_id,
+            "approved": approved,
+        })),
+        PermissionOutcome::Awaiting {
+            approvals,
+            required,
+        } => HttpResponse::ok().json(&json!({
+            "type": "permission_pending",
+            "session_id": session_id,
+            "permission_id": permission_id,
+            "approvals": approvals,
+            "required_approvals": required,
+        })),
+        PermissionOutcome::AlreadyApproved => json_error(
+            409,
+            "Permission request was already approved by this approver",
+        ),
+        PermissionOutcome::NotPending => {
+            json_error(404, "Permission request not found or already resolved")
+        }
+    };
+    let _ = response.send(&mut stream).await;
+
+    Ok(HttpResponse::ok())
+}
+
+/// Decisions made on a session's permission requests, oldest first
+/// (GET /api/sessions/{session_id}/permissions)
+pub async fn handle_permission_history(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let mut stream = ctx.stream;
+
+    let body = json!({
+        "type": "permission_history",
+        "session_id": session_id,
+        "decisions": state.session_manager.permission_history().of(&session_id),
+    });
+    let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
+    Ok(HttpResponse::ok())
+}
+
+/// Open a GitHub pull request with what a session changed
+/// (POST /api/sessions/{session_id}/pr). Takes optional `title`, `branch`,
+/// `base`, `remote` and `draft`; the description summarizes the transcript.
+#[cfg(feature = "github")]
+pub async fn handle_create_pull_request(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let body = ctx.request.body_as_json().unwrap_or(json!({}));
+    let mut stream = ctx.stream;
+
+    let token = match github::token(state.config.github_token.as_deref()) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let _ = json_error(
+                409,
+                "No GitHub token configured (--github-token, GITHUB_TOKEN or arpc login)",
+            )
+            .send(&mut stream)
+            .await;
+            return Ok(HttpResponse::ok());
+        }
+        Err(e) => {
+            let _ = json_error(500, e).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let Some(session) = state.session_manager.get_session(&session_id).await else {
+        let _ = json_error(404, "Session not found").send(&mut stream).await;
+        return Ok(HttpResponse::ok());
+    };
+    if matches!(session.get_status().await, SessionStatus::Running) {
+        let _ = json_error(409, "Session is still running")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+    let Some(project_path) = session.get_project_path().await else {
+        let _ = json_error(409, "Session has no project path")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    };
+
+    let history = load_history_for_executor(
+        session.executor_kind,
+        &session_id,
+        HistoryWindow::unlimited_from(0),
+    )
+    .await;
+    let messages = match history {
+        Some(page) => state.session_manager.redactor().apply_all(page.messages),
+        None => session
+            .get_output_from(0)
+            .await
+            .iter()
+            .filter_map(|line| serde_json::from_str(&line.content).ok())
+            .collect(),
+    };
+    let summary = summarize_transcript(&messages);
+
+    let text = |key: &str| {
+        body.get(key)
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let title = text("title")
+        .or_else(|| {
+            let first_line = summary.prompt.as_deref()?.lines().next()?;
+            Some(first_line.chars().take(72).collect())
+        })
+        .unwrap_or_else(|| format!("Changes from session {}", session_id));
+    let options = github::PullRequestOptions {
+        body: pull_request_body(&summary, session.executor_kind, &session_id),
+        branch: text("branch")
+            .unwrap_or_else(|| format!("arpc/{}", session_id.chars().take(8).collect::<String>())),
+        base: text("base"),
+        remote: text("remote").unwrap_or_else(|| "origin".to_string()),
+        draft: body.get("draft").and_then(Value::as_bool).unwrap_or(false),
+        title,
+    };
+
+    match github::open_pull_request(&project_path, &token, &state.config.github_api_url, options)
+        .await
+    {
+        Ok(pr) => {
+            info!("Opened pull request {} for session {}", pr.url, session_id);
+            let body = json!({
+                "url": pr.url,
+                "number": pr.number,
+                "branch": pr.branch,
+                "base": pr.base,
+                "commit": pr.commit,
+            });
+            let _ = HttpResponse::new(201).json(&body).send(&mut stream).await;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to open pull request for session {}: {}",
+                session_id, e
+            );
+            let _ = json_error(422, e).send(&mut stream).await;
+        }
+    }
+    Ok(HttpResponse::ok())
+}
+
+/// Pull request description: the agent's answer, the request, and what it used
+#[cfg(feature = "github")]
+fn pull_request_body(
+    summary: &TranscriptSummary,
+    executor: ExecutorKind,
+    session_id: &str,
+) -> String {
+    let mut body = String::new();
+    if let Some(result) = &summary.result {
+        body.push_str(result);
+        body.push_str("\n\n");
+    }
+    if let Some(prompt) = &summary.prompt {
+        body.push_str("### Request\n\n");
+        for line in prompt.lines() {
+            body.push_str(&format!("> {}\n", line));
+        }
+        body.push('\n');
+    }
+    body.push_str(&format!(
+        "### Session\n\n- Agent: {} session `{}`\n",
+        executor.as_str(),
+        session_id
+    ));
+    if !summary.tools.is_empty() {
+        let tools: Vec<String> = summary
+            .tools
+            .iter()
+            .map(|(name, count)| format!("{} ×{}", name, count))
+            .collect();
+        body.push_str(&format!("- Tools: {}\n", tools.join(", ")));
+    }
+    if !summary.files.is_empty() {
+        let files: Vec<String> = summary
+            .files
+            .iter()
+            .map(|file| format!("`{}`", file))
+            .collect();
+        body.push_str(&format!("- Files written: {}\n", files.join(", ")));
+    }
+    body
+}
+
+/// Restore the session's project to its snapshot from before the run
+/// (POST /api/sessions/{session_id}/revert); needs `--snapshots`.
+pub async fn handle_revert_session(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let mut stream = ctx.stream;
+
+    let Some(session) = state.session_manager.get_session(&session_id).await else {
+        let _ = json_error(404, "Session not found").send(&mut stream).await;
+        return Ok(HttpResponse::ok());
+    };
+    if matches!(session.get_status().await, SessionStatus::Running) {
+        let _ = json_error(409, "Session is still running")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+    let Some(project_path) = session.get_project_path().await else {
+        let _ = json_error(409, "Session has no project path")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    };
+
+    match snapshots::revert(&project_path, &session_id).await {
+        Ok(Some(reverted)) => {
+            info!(
+                "Reverted {} to its state before session {}",
+                project_path.display(),
+                session_id
+            );
+            let body = json!({
+                "session_id": session_id,
+                "before": reverted.before,
+                "reverted_from": reverted.reverted_from,
+                "restored": reverted.restored,
+                "removed": reverted.removed,
+            });
+            let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
+        }
+        Ok(None) => {
+            let _ = json_error(404, "No snapshot was taken before this session")
+                .send(&mut stream)
+                .await;
+        }
+        Err(e) => {
+            warn!("Failed to revert session {}: {}", session_id, e);
+            let _ = json_error(422, e).send(&mut stream).await;
+        }
+    }
+    Ok(HttpResponse::ok())
+}
+
+/// Confirmation page for a permission prompt, the target of the approve/deny
+/// links chat notifiers post
+/// (GET /api/sessions/{session_id}/permissions/{permission_id}?decision=approve&approver=<link token>).
+/// Chat apps fetch links to preview them, so opening the page decides
+/// nothing; its button posts the decision with the link's approver token.
+pub async fn handle_permission_page(
+    ctx: HandlerContext,
+    state: HandlerState,
+) -> Result<HttpResponse> {
+    let session_id = ctx
+        .path_params
+        .get("session_id")
+        .cloned()
+        .unwrap_or_default();
+    let permission_id = ctx
+        .path_params
+        .get("permission_id")
+        .cloned()
+        .unwrap_or_default();
+    let decision = ctx.request.query_param("decision").cloned();
+    let link_token = ctx.request.query_param("approver").cloned();
+    let mut stream = ctx.stream;
+
+    let decision = match decision.as_deref() {
+        Some("approve") => "approve",
+        Some("deny") => "deny",
+        decision => {
+            let _ = ValidationError::choice("decision", decision, DECISIONS)
+                .response()
+                .send(&mut stream)
+                .await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let approver = link_token.as_deref().and_then(|token| {
+        state
+            .approvers
+            .verify_link(token, &session_id, &permission_id, decision)
+    });
+    if approver.is_none() && !state.approvers.is_empty() {
+        let _ = json_error(401, "Missing or invalid approver token")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let pending = match state.session_manager.get_session(&session_id).await {
+        Some(session) => session.has_pending_permission(&permission_id).await,
+        None => false,
+    };
+    if !pending {
+        let _ = json_error(404, "Permission request not found or already resolved")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let page = PERMISSION_PAGE
+        .replace("{decision}", decision)
+        .replace(
+            "{label}",
+            if decision == "approve" {
+                "Approve"
+            } else {
+                "Deny"
+            },
+        )
+        .replace("{session_id}", &escape_html(&session_id))
+        .replace("{permission_id}", &escape_html(&permission_id))
+        .replace(
+            "{approver}",
+            &approver
+                .map(|name| format!("<p>Deciding as <b>{}</b>.</p>", escape_html(name)))
+                .unwrap_or_default(),
+        );
+    let _ = HttpResponse::ok()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(page.into_bytes())
+        .send(&mut stream)
+        .await;
+    Ok(HttpResponse::ok())
+}
+
+/// Posts the decision back to the page's own URL, with the approver token
+/// of the link it was opened from
+const PERMISSION_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>{label} permission</title></head>
+<body style="font-family: sans-serif; max-width: 32em; margin: 3em auto">
+<p>Session <code>{session_id}</code> is waiting for permission <code>{permission_id}</code>.</p>
+{approver}
+<button id="decide" style="font-size: 1.2em">{label}</button>
+<p id="result"></p>
+<script>
+document.getElementById("decide").onclick = async (event) => {
+  event.target.disabled = true;
+  const response = await fetch(location.pathname + location.search, {
+    method: "POST",
+    headers: {"Content-Type": "application/json"},
+    body: JSON.stringify({
+      decision: "{decision}",
+      approver_token: new URLSearchParams(location.search).get("approver"),
+    }),
+  });
+  const body = await response.json().catch(() => ({}));
+  document.getElementById("result").textContent = response.ok
+    ? "Done: " + (body.approved ? "approved" : "denied")
+    : "Failed: " + (body.message || response.status);
+};
+</script>
+</body>
+</html>
+"#;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 /// Handle session deletion/cancellation (DELETE /api/sessions/{session_id})
 async fn handle_delete_session(
     ctx: HandlerContext,
@@ -296,8 +884,13 @@ async fn handle_delete_session(
             .query_param("executor")
             .and_then(|value| ExecutorKind::from_str(value));
 
+        let record = state.session_manager.find_record(session_id).await;
+        let requested_executor =
+            requested_executor.or(record.as_ref().map(|record| record.executor));
+
         match delete_history_for_executor(requested_executor, session_id).await {
             Ok(_) => {
+                state.session_manager.forget_record(session_id).await;
                 let body = json!({
                     "type": "session_deleted",
                     "session_id": session_id
@@ -314,16 +907,27 @@ async fn handle_delete_session(
     }
 }
 
-async fn load_history_for_executor(executor: ExecutorKind, session_id: &str) -> Option<Vec<Value>> {
-    match executor {
-        ExecutorKind::Claude => claude::load_session_by_id(session_id.to_string())
-            .await
-            .ok(),
-        ExecutorKind::Codex => codex::load_session_by_id(session_id.to_string()).await.ok(),
-        ExecutorKind::Gemini => gemini::load_session_by_id(session_id.to_string())
-            .await
-            .ok(),
+pub async fn load_history_for_executor(
+    executor: ExecutorKind,
+    session_id: &str,
+    window: HistoryWindow,
+) -> Option<HistoryPage> {
+    let session_id = session_id.to_string();
+    let page = match executor {
+        ExecutorKind::Claude => claude::load_session_by_id(session_id, window).await,
+        ExecutorKind::Codex => codex::load_session_by_id(session_id, window).await,
+        ExecutorKind::Gemini => gemini::load_session_by_id(session_id, window).await,
     }
+    .ok()?;
+
+    if page.next_from.is_some() && window.limit == usize::MAX {
+        warn!(
+            "Transcript exceeds {} bytes, history truncated at line {}",
+            window.max_bytes,
+            page.from + page.messages.len()
+        );
+    }
+    Some(page)
 }
 
 async fn delete_history_for_executor(
@@ -378,17 +982,113 @@ fn get_array_param(body: &Value, key: &str) -> Option<Vec<String>> {
     })
 }
 
-// Helper to validate enum values
-fn validate_enum(value: &str, valid: &[&str], name: &str) -> Result<(), String> {
-    if valid.contains(&value) {
-        Ok(())
-    } else {
-        Err(format!(
-            "Invalid {}: {}. Valid options: {}",
-            name,
-            value,
-            valid.join(", ")
-        ))
+const PERMISSION_MODES: &[&str] = &["acceptEdits", "bypassPermissions", "default", "plan"];
+const APPROVAL_MODES: &[&str] = &["default", "auto_edit", "yolo"];
+const DECISIONS: &[&str] = &["approve", "deny"];
+
+/// A field of a session request that failed validation. Answered with 400
+/// and a `code` naming the field, so UIs can show the error next to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ValidationError {
+    /// The field is missing or empty.
+    Missing(&'static str),
+    /// The field holds none of the `allowed` values.
+    NotAllowed {
+        field: &'static str,
+        value: String,
+        allowed: &'static [&'static str],
+    },
+    /// The field has a JSON type it can't take.
+    WrongType {
+        field: &'static str,
+        expected: &'static str,
+    },
+    /// `project_path` names no existing directory.
+    NotADirectory(String),
+}
+
+impl ValidationError {
+    /// `field` missing, or holding a value that isn't `allowed`.
+    fn choice(field: &'static str, value: Option<&str>, allowed: &'static [&'static str]) -> Self {
+        match value {
+            Some(value) => ValidationError::NotAllowed {
+                field,
+                value: value.to_string(),
+                allowed,
+            },
+            None => ValidationError::Missing(field),
+        }
+    }
+
+    fn field(&self) -> &'static str {
+        match self {
+            ValidationError::Missing(field)
+            | ValidationError::NotAllowed { field, .. }
+            | ValidationError::WrongType { field, .. } => field,
+            ValidationError::NotADirectory(_) => "project_path",
+        }
+    }
+
+    /// Stable machine-readable code, `missing_<field>` or `invalid_<field>`.
+    fn code(&self) -> String {
+        match self {
+            ValidationError::Missing(field) => format!("missing_{}", field),
+            _ => format!("invalid_{}", self.field()),
+        }
+    }
+
+    /// The 400 answer, listing the values the field may take when it has a
+    /// fixed set.
+    fn response(&self) -> HttpResponse {
+        let mut body = json!({
+            "type": "error",
+            "code": self.code(),
+            "field": self.field(),
+            "message": self.to_string(),
+        });
+        if let ValidationError::NotAllowed { allowed, .. } = self {
+            body["allowed"] = json!(allowed);
+        }
+        HttpResponse::new(400).json(&body)
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Missing(field) => write!(f, "{} is required", field),
+            ValidationError::NotAllowed {
+                field,
+                value,
+                allowed,
+            } => write!(
+                f,
+                "Invalid {}: {}. Valid options: {}",
+                field,
+                value,
+                allowed.join(", ")
+            ),
+            ValidationError::WrongType { field, expected } => {
+                write!(f, "{} must be {}", field, expected)
+            }
+            ValidationError::NotADirectory(path) => {
+                write!(f, "project_path is not an existing directory: {}", path)
+            }
+        }
+    }
+}
+
+/// `value` of `field` when it is one of `allowed`.
+fn validate_enum(
+    field: &'static str,
+    value: Option<String>,
+    allowed: &'static [&'static str],
+) -> Result<Option<String>, ValidationError> {
+    match value {
+        Some(value) if !allowed.contains(&value.as_str()) => {
+            Err(ValidationError::choice(field, Some(&value), allowed))
+        }
+        value => Ok(value),
     }
 }
 
@@ -396,7 +1096,7 @@ fn validate_enum(value: &str, valid: &[&str], name: &str) -> Result<(), String>
 fn parse_executor_options(
     body_json: &Value,
     request: &common::http::HttpRequest,
-) -> (Option<ExecutorOptions>, Option<String>) {
+) -> Result<ExecutorOptions, ValidationError> {
     let executor_kind = get_param(body_json, request, "executor")
         .and_then(|s| ExecutorKind::from_str(&s))
         .unwrap_or(ExecutorKind::Claude);
@@ -405,19 +1105,13 @@ fn parse_executor_options(
         ExecutorKind::Claude => {
             let resume = get_param(body_json, request, "resume");
             let model = get_param(body_json, request, "model");
-            let permission_mode = get_param(body_json, request, "permission_mode");
+            let permission_mode = validate_enum(
+                "permission_mode",
+                get_param(body_json, request, "permission_mode"),
+                PERMISSION_MODES,
+            )?;
             let allowed_tools = get_array_param(body_json, "allowed_tools");
 
-            if let Some(ref mode) = permission_mode {
-                if let Err(e) = validate_enum(
-                    mode,
-                    &["acceptEdits", "bypassPermissions", "default", "plan"],
-                    "permission_mode",
-                ) {
-                    return (None, Some(e));
-                }
-            }
-
             ExecutorOptions::Claude(ClaudeOptions {
                 resume,
                 model,
@@ -436,31 +1130,27 @@ fn parse_executor_options(
                     .and_then(|s| parse_bool_str(s.trim()))
                     .unwrap_or(false),
                 _ => {
-                    return (
-                        None,
-                        Some("resume_last must be a boolean, string, or number".to_string()),
-                    );
+                    return Err(ValidationError::WrongType {
+                        field: "resume_last",
+                        expected: "a boolean, string, or number",
+                    });
                 }
             };
 
             ExecutorOptions::Codex(CodexOptions { model, resume_last })
         }
         ExecutorKind::Gemini => {
-            let approval_mode = get_param(body_json, request, "approval_mode");
-
-            if let Some(ref mode) = approval_mode {
-                if let Err(e) =
-                    validate_enum(mode, &["default", "auto_edit", "yolo"], "approval_mode")
-                {
-                    return (None, Some(e));
-                }
-            }
+            let approval_mode = validate_enum(
+                "approval_mode",
+                get_param(body_json, request, "approval_mode"),
+                APPROVAL_MODES,
+            )?;
 
             ExecutorOptions::Gemini(GeminiOptions { approval_mode })
         }
     };
 
-    (Some(options), None)
+    Ok(options)
 }
 
 /// Execute the command and store output in session
@@ -469,8 +1159,34 @@ async fn execute_command(
     prompt: String,
     project_path: String,
     executor_options: ExecutorOptions,
-    session_manager: crate::session::SessionManager,
+    state: HandlerState,
 ) -> Result<()> {
+    let session_manager = state.session_manager;
+    // Projects that aren't git repositories with a commit go without
+    let take_snapshots = state.config.snapshots;
+    let snapshot = |stage: Stage| {
+        let project_path = Path::new(&project_path);
+        async move {
+            if !take_snapshots {
+                return None;
+            }
+            let message = format!("Snapshot {} the session", stage.as_str());
+            match snapshots::take(project_path, &message).await {
+                Ok(commit) => Some(commit),
+                Err(e) => {
+                    debug!(
+                        "No {} snapshot of {}: {}",
+                        stage.as_str(),
+                        project_path.display(),
+                        e
+                    );
+                    None
+                }
+            }
+        }
+    };
+    let before = snapshot(Stage::Before).await;
+
     // Build command
     let mut cmd = match build_command(&executor_options, &prompt, &project_path) {
         Ok(cmd) => cmd,
@@ -480,6 +1196,8 @@ async fn execute_command(
             return Err(e);
         }
     };
+    #[cfg(feature = "mcp")]
+    state.mcp_servers.apply(&mut cmd, executor_options.kind());
 
     // Spawn the process
     let mut child = match cmd.spawn() {
@@ -498,13 +1216,18 @@ async fn execute_command(
         .take()
         .ok_or_else(|| anyhow!("Failed to get stdout"))?;
     let mut stdout_reader = BufReader::new(stdout);
+    let max_line_bytes = state.config.max_output_line_bytes;
 
     info!("Command started, reading output...");
 
     // Read first line to extract session ID and create session
-    let mut first_line = String::new();
-    let bytes_read = match stdout_reader.read_line(&mut first_line).await {
-        Ok(n) => n,
+    let first_line = match read_output_line(&mut stdout_reader, max_line_bytes).await {
+        Ok(Some(line)) => line,
+        Ok(None) => {
+            error!("Command produced no output");
+            let _ = session_tx.send(None);
+            return Err(anyhow!("Command produced no output"));
+        }
         Err(e) => {
             error!("Error reading first line: {}", e);
             let _ = session_tx.send(None);
@@ -512,17 +1235,8 @@ async fn execute_command(
         }
     };
 
-    if bytes_read == 0 {
-        error!("Command produced no output");
-        let _ = session_tx.send(None);
-        return Err(anyhow!("Command produced no output"));
-    }
-
-    // Trim the first line
-    let trimmed_first_line = first_line.trim_end_matches(['\r', '\n']);
-
     // Try to parse as JSON and extract session_id field
-    let session = match serde_json::from_str::<Value>(trimmed_first_line) {
+    let session = match serde_json::from_str::<Value>(&first_line) {
         Ok(json_value) => {
             if let Some(session_id) = json_value.get("session_id").and_then(|v| v.as_str()) {
                 info!("Extracted session ID: {}", session_id);
@@ -532,6 +1246,7 @@ async fn execute_command(
                         executor_options.kind(),
                     )
                     .await;
+                session.set_project_path(PathBuf::from(&project_path)).await;
                 session_manager
                     .register_agent_session(
                         executor_options.kind(),
@@ -539,7 +1254,13 @@ async fn execute_command(
                         &session,
                     )
                     .await;
-                session.set_project_path(PathBuf::from(&project_path)).await;
+                session_manager
+                    .events()
+                    .publish(ClientEvent::SessionStarted {
+                        session_id: session_id.to_string(),
+                        executor: executor_options.kind().as_str(),
+                        project_path: project_path.clone(),
+                    });
                 session
             } else {
                 error!("First line JSON missing 'session_id' field");
@@ -556,12 +1277,20 @@ async fn execute_command(
 
     let session_id = &session.session_id;
     info!("[Session {}] Created session", session_id);
+    if let Some(before) = &before {
+        keep_snapshot(&project_path, session_id, Stage::Before, before).await;
+    }
+
+    // Lets a restarted client find the executor if this one dies first
+    if let Some(pid) = child.id() {
+        orphans::record(pid, session_id, executor_options.kind(), &project_path).await;
+    }
 
     // Store process handle for cancellation
     session.set_process_handle(child).await;
 
     // Add first line to session buffer
-    session.add_output(trimmed_first_line.to_string()).await;
+    session.add_output(first_line).await;
 
     // Send session back to handle_create_session
     if session_tx.send(Some(session.clone())).is_err() {
@@ -571,57 +1300,156 @@ async fn execute_command(
 
     // Continue reading remaining output lines
     loop {
-        let mut line = String::new();
-        let bytes_read = match stdout_reader.read_line(&mut line).await {
-            Ok(n) => n,
+        let line = match read_output_line(&mut stdout_reader, max_line_bytes).await {
+            Ok(Some(line)) => line,
+            Ok(None) => break, // EOF
             Err(e) => {
                 error!("[Session {}] Error reading stdout: {}", session_id, e);
                 break;
             }
         };
 
-        if bytes_read == 0 {
-            break; // EOF
-        }
-
-        // Trim the line
-        let trimmed_line = line.trim_end_matches(['\r', '\n']);
-
         // Add to session buffer
-        session.add_output(trimmed_line.to_string()).await;
+        session.add_output(line).await;
+    }
+
+    // Taken while the session still counts as running, so it can't be
+    // reverted halfway through
+    if let Some(after) = snapshot(Stage::After).await {
+        keep_snapshot(&project_path, session_id, Stage::After, &after).await;
     }
 
     // Retrieve process handle and wait for completion
     let mut process_handle = session.process_handle.lock().await;
     if let Some(child) = process_handle.as_mut() {
-        let exit_status = child.wait().await?;
-        let exit_code = exit_status.code();
-
-        info!(
-            "[Session {}] Command completed with exit code: {:?}",
-            session_id, exit_code
-        );
-
-        // Mark session as completed
+        let exit = child.wait().await;
         drop(process_handle);
-        session.mark_completed(exit_code).await;
+        record_exit(&session, exit).await?;
     }
 
     Ok(())
 }
 
+/// End the session with how its command exited. A command that couldn't
+/// be waited on fails the session, which would otherwise stay running.
+async fn record_exit(
+    session: &CommandSession,
+    exit: std::io::Result<std::process::ExitStatus>,
+) -> Result<()> {
+    match exit {
+        Ok(status) => {
+            info!(
+                "[Session {}] Command completed with exit code: {:?}",
+                session.session_id,
+                status.code()
+            );
+            session.mark_completed(status.code()).await;
+            Ok(())
+        }
+        Err(e) => {
+            session
+                .mark_failed(format!("Failed to wait for the command: {}", e))
+                .await;
+            Err(e.into())
+        }
+    }
+}
+
+async fn keep_snapshot(project_path: &str, session_id: &str, stage: Stage, commit: &str) {
+    match snapshots::record(Path::new(project_path), session_id, stage, commit).await {
+        Ok(()) => info!(
+            "[Session {}] Snapshot {} of the project: {}",
+            session_id,
+            stage.as_str(),
+            commit
+        ),
+        Err(e) => warn!(
+            "[Session {}] Failed to keep the {} snapshot: {}",
+            session_id,
+            stage.as_str(),
+            e
+        ),
+    }
+}
+
+/// Characters of an over-long line kept in its `truncated` event
+const TRUNCATED_PREVIEW_CHARS: usize = 1024;
+
+/// Read one output line without its line ending, or `None` at EOF.
+///
+/// At most `max_bytes` of a line are buffered (0 = no limit); the rest is
+/// read and dropped, and the line is replaced by a `truncated` event so
+/// stream consumers can tell it apart from the executor's own output.
+async fn read_output_line<R>(reader: &mut R, max_bytes: usize) -> std::io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut kept = Vec::new();
+    let mut line_bytes = 0usize;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            if line_bytes == 0 {
+                return Ok(None);
+            }
+            break;
+        }
+        let newline = buf.iter().position(|&b| b == b'\n');
+        let content = &buf[..newline.unwrap_or(buf.len())];
+        let room = if max_bytes == 0 {
+            content.len()
+        } else {
+            max_bytes.saturating_sub(kept.len()).min(content.len())
+        };
+        kept.extend_from_slice(&content[..room]);
+        line_bytes += content.len();
+
+        let used = newline.map_or(buf.len(), |i| i + 1);
+        reader.consume(used);
+        if newline.is_some() {
+            break;
+        }
+    }
+
+    // A CRLF ending is only whole when nothing was dropped
+    if kept.len() == line_bytes && kept.last() == Some(&b'\r') {
+        kept.pop();
+        line_bytes -= 1;
+    }
+    let line = String::from_utf8_lossy(&kept).into_owned();
+    if max_bytes == 0 || line_bytes <= max_bytes {
+        return Ok(Some(line));
+    }
+
+    warn!(
+        "Truncated a {} byte executor output line to {} bytes",
+        line_bytes, max_bytes
+    );
+    Ok(Some(
+        json!({
+            "type": "truncated",
+            "line_bytes": line_bytes,
+            "max_line_bytes": max_bytes,
+            "preview": line.chars().take(TRUNCATED_PREVIEW_CHARS).collect::<String>(),
+        })
+        .to_string(),
+    ))
+}
+
 /// Unified SSE streaming for all session types
 async fn stream_unified_session(
     ctx: HandlerContext,
     session: Option<Arc<CommandSession>>,
-    historical_messages: Option<Vec<serde_json::Value>>,
+    record: Option<SessionRecord>,
+    historical_messages: Option<HistoryPage>,
     from_line: usize,
 ) -> Result<HttpResponse> {
     // let proxy_conn_id = &ctx.proxy_conn_id;
+    let filter = EventFilter::from_request(&ctx.request);
     let mut stream = ctx.stream;
 
     // Send SSE headers
-    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, PUT, DELETE, PATCH, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\n\r\n").await?;
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, PUT, DELETE, PATCH, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type, Authorization, Last-Event-ID\r\n\r\n").await?;
     stream.flush().await?;
 
     // Send session info
@@ -632,15 +1460,19 @@ async fn stream_unified_session(
 
     info!("[Session {}] Sending session info", session_id);
     // Stream historical messages first
-    if let Some(messages) = historical_messages {
-        for (idx, msg) in messages.iter().enumerate() {
-            let line = idx + 1;
+    if let Some(history) = historical_messages {
+        for (idx, msg) in history.messages.iter().enumerate() {
+            let line = history.from + idx + 1;
             if line < from_line {
                 continue;
             }
+            let content = msg.to_string();
+            let Some(content) = filter.apply(&content) else {
+                continue;
+            };
 
             if stream
-                .write_all(format!("data: {}\n\n", msg).as_bytes())
+                .write_all(sse_event(line, &content).as_bytes())
                 .await
                 .is_err()
             {
@@ -652,70 +1484,267 @@ async fn stream_unified_session(
 
     // Stream live session if exists
     let Some(session) = session else {
-        let completion = json!({"type":"completion","success":true});
+        let completion = match record {
+            Some(record) => json!({
+                "type": "completion",
+                "success": record.final_status() == "completed",
+                "status": record.final_status(),
+                "exit_code": record.exit_code,
+                "error": record.error,
+            }),
+            None => json!({"type":"completion","success":true}),
+        };
         let _ = stream
             .write_all(format!("data: {}\n\n", completion).as_bytes())
             .await;
         return Ok(HttpResponse::ok());
     };
 
-    let mut current_line = *session.total_lines.lock().await;
-    drop(session.total_lines.lock().await);
+    stream_live_output(&mut stream, &filter, &session, from_line).await?;
+    Ok(HttpResponse::ok())
+}
+
+/// Stream a live session's output from `from_line` until it finishes, then
+/// send the completion event
+async fn stream_live_output<S>(
+    stream: &mut S,
+    filter: &EventFilter,
+    session: &CommandSession,
+    from_line: usize,
+) -> Result<()>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+{
+    // Subscribe before reading the buffer so no line falls between the two
+    let mut updates = session.subscribe();
+    let mut current_line = from_line.saturating_sub(1);
 
     // Send buffered output
-    for line in session.get_output_from(from_line).await {
-        // let event = json!({"type":"output","line":line.line_number,"content":line.content});
+    if !send_lines(stream, filter, session, &mut current_line).await? {
+        return Ok(());
+    }
+
+    // Forward new output as it is broadcast; the status is checked on a
+    // slower tick since finishing a session broadcasts nothing
+    let mut status_tick = tokio::time::interval(STATUS_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(line) => {
+                    if line.line_number <= current_line {
+                        continue;
+                    }
+                    if line.line_number > current_line + 1 {
+                        // Out of step with the broadcast; the buffer has the rest
+                        if !send_lines(stream, filter, session, &mut current_line).await? {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    current_line = line.line_number;
+                    let Some(content) = filter.apply(&line.content) else {
+                        continue;
+                    };
+                    if stream
+                        .write_all(sse_event(line.line_number, &content).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                    stream.flush().await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(
+                        "[Session {}] Stream subscriber missed {} lines, refilling from the buffer",
+                        session.session_id, missed
+                    );
+                    let gap = json!({"type":"gap","missed":missed,"from_line":current_line + 1});
+                    if stream
+                        .write_all(format!("data: {}\n\n", gap).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                    if !send_lines(stream, filter, session, &mut current_line).await? {
+                        return Ok(());
+                    }
+                }
+                // The session is gone; what it wrote is still buffered
+                Err(broadcast::error::RecvError::Closed) => status_tick.reset_immediately(),
+            },
+            _ = status_tick.tick() => {
+                let status = session.status.read().await.clone();
+                if matches!(status, SessionStatus::Running) {
+                    continue;
+                }
+
+                // Output is fully buffered before a session finishes
+                if !send_lines(stream, filter, session, &mut current_line).await? {
+                    return Ok(());
+                }
+                let completion = match status {
+                    SessionStatus::Completed { exit_code } => {
+                        json!({"type":"completion","success":true,"exit_code":exit_code,"total_lines":current_line})
+                    }
+                    SessionStatus::Failed { error } => {
+                        json!({"type":"completion","success":false,"error":error,"total_lines":current_line})
+                    }
+                    SessionStatus::Cancelled { reason } => {
+                        json!({"type":"completion","success":false,"cancelled":true,"reason":reason,"total_lines":current_line})
+                    }
+                    SessionStatus::Running => unreachable!(),
+                };
+                let _ = stream
+                    .write_all(format!("data: {}\n\n", completion).as_bytes())
+                    .await;
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How often a live stream checks whether its session has finished
+const STATUS_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Send buffered lines after `current_line`, advancing it; false once the
+/// peer has gone away
+async fn send_lines<S>(
+    stream: &mut S,
+    filter: &EventFilter,
+    session: &CommandSession,
+    current_line: &mut usize,
+) -> Result<bool>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+{
+    for line in session.get_output_from(*current_line + 1).await {
+        *current_line = line.line_number;
+        let Some(content) = filter.apply(&line.content) else {
+            continue;
+        };
         if stream
-            .write_all(format!("data: {}\n\n", line.content).as_bytes())
+            .write_all(sse_event(line.line_number, &content).as_bytes())
             .await
             .is_err()
         {
-            return Ok(HttpResponse::ok());
+            return Ok(false);
         }
         stream.flush().await?;
     }
+    Ok(true)
+}
 
-    // Poll for new output
-    loop {
-        let status = session.status.read().await.clone();
-        let is_complete = !matches!(status, SessionStatus::Running);
+/// Format an output line as an SSE event whose `id` is its line number
+fn sse_event(line_number: usize, content: &str) -> String {
+    format!("id: {}\ndata: {}\n\n", line_number, content)
+}
 
-        for line in session.get_output_from(current_line + 1).await {
-            current_line = line.line_number;
+/// Server-side filter for session streams, built from the `types` and
+/// `exclude` query parameters (comma-separated).
+///
+/// An event matches a name if its `type`, its Codex `payload.type` or any of
+/// its content block types (e.g. `tool_result`) equals it. `exclude` also
+/// strips fields of the same name, so `exclude=usage` drops token usage.
+#[derive(Debug, Default)]
+struct EventFilter {
+    include: Option<HashSet<String>>,
+    exclude: HashSet<String>,
+}
 
-            if stream
-                .write_all(format!("data: {}\n\n", line.content).as_bytes())
-                .await
-                .is_err()
-            {
-                return Ok(HttpResponse::ok());
+impl EventFilter {
+    fn from_request(request: &common::http::HttpRequest) -> Self {
+        let names = |key: &str| {
+            request.query_param(key).map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect::<HashSet<_>>()
+            })
+        };
+
+        EventFilter {
+            include: names("types").filter(|names| !names.is_empty()),
+            exclude: names("exclude").unwrap_or_default(),
+        }
+    }
+
+    /// Filtered event content, or None if the event should not be sent
+    fn apply<'a>(&self, content: &'a str) -> Option<Cow<'a, str>> {
+        if self.include.is_none() && self.exclude.is_empty() {
+            return Some(Cow::Borrowed(content));
+        }
+
+        let Ok(mut event) = serde_json::from_str::<Value>(content) else {
+            return self.include.is_none().then_some(Cow::Borrowed(content));
+        };
+
+        let types = event_types(&event);
+        if let Some(ref include) = self.include
+            && !types.iter().any(|t| include.contains(t))
+        {
+            return None;
+        }
+        if types.iter().any(|t| self.exclude.contains(t)) {
+            return None;
+        }
+
+        let mut stripped = false;
+        for name in &self.exclude {
+            if let Some(message) = event.get_mut("message").and_then(Value::as_object_mut) {
+                stripped |= message.remove(name).is_some();
+            }
+            if let Some(object) = event.as_object_mut() {
+                stripped |= object.remove(name).is_some();
             }
-            stream.flush().await?;
         }
 
-        if is_complete {
-            let completion = match status {
-                SessionStatus::Completed { exit_code } => {
-                    json!({"type":"completion","success":true,"exit_code":exit_code,"total_lines":current_line})
-                }
-                SessionStatus::Failed { error } => {
-                    json!({"type":"completion","success":false,"error":error,"total_lines":current_line})
-                }
-                SessionStatus::Cancelled { reason } => {
-                    json!({"type":"completion","success":false,"cancelled":true,"reason":reason,"total_lines":current_line})
-                }
-                _ => unreachable!(),
-            };
-            let _ = stream
-                .write_all(format!("data: {}\n\n", completion).as_bytes())
-                .await;
-            break;
+        Some(if stripped {
+            Cow::Owned(event.to_string())
+        } else {
+            Cow::Borrowed(content)
+        })
+    }
+}
+
+/// Type names an event can be filtered by
+fn event_types(event: &Value) -> Vec<String> {
+    let mut types = Vec::new();
+    let mut push = |value: Option<&Value>| {
+        if let Some(name) = value.and_then(Value::as_str) {
+            types.push(name.to_string());
         }
+    };
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    push(event.get("type"));
+    push(event.pointer("/payload/type"));
+    if let Some(blocks) = event.pointer("/message/content").and_then(Value::as_array) {
+        for block in blocks {
+            push(block.get("type"));
+        }
     }
+    types
+}
 
-    Ok(HttpResponse::ok())
+/// First line to stream: an explicit `from_line` query parameter, otherwise
+/// the line after the `Last-Event-ID` an EventSource sends on reconnect
+fn resume_from_line(request: &common::http::HttpRequest) -> usize {
+    if let Some(from_line) = request
+        .query_param("from_line")
+        .and_then(|s| s.parse::<usize>().ok())
+    {
+        return from_line;
+    }
+
+    request
+        .header("last-event-id")
+        .and_then(|id| id.trim().parse::<usize>().ok())
+        .map_or(0, |id| id + 1)
 }
 
 /// Stream session output to client via SSE (used by create_session)
@@ -724,5 +1753,420 @@ async fn stream_session_output(
     session: Arc<CommandSession>,
     from_line: usize,
 ) -> Result<HttpResponse> {
-    stream_unified_session(ctx, Some(session), None, from_line).await
+    stream_unified_session(ctx, Some(session), None, None, from_line).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approvers::Approvers;
+    use crate::config::ClientConfig;
+    use crate::policy::SessionPolicy;
+    use crate::session::SessionManager;
+    use clap::Parser;
+    use common::http::{HttpMethod, HttpRequest, Params};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn filter(types: &[&str], exclude: &[&str]) -> EventFilter {
+        let set = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<HashSet<_>>();
+        EventFilter {
+            include: (!types.is_empty()).then(|| set(types)),
+            exclude: set(exclude),
+        }
+    }
+
+    #[tokio::test]
+    async fn output_lines_over_the_limit_become_truncated_events() {
+        let long = "x".repeat(5000);
+        let input = format!("first\r\n{}\nlast", long);
+        let mut reader = input.as_bytes();
+
+        let mut lines = Vec::new();
+        while let Some(line) = read_output_line(&mut reader, 4096).await.unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "first");
+        let truncated: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(truncated["type"], "truncated");
+        assert_eq!(truncated["line_bytes"], 5000);
+        assert_eq!(
+            truncated["preview"].as_str().unwrap().len(),
+            TRUNCATED_PREVIEW_CHARS
+        );
+        assert_eq!(lines[2], "last");
+
+        let mut reader = input.as_bytes();
+        read_output_line(&mut reader, 0).await.unwrap();
+        assert_eq!(read_output_line(&mut reader, 0).await.unwrap(), Some(long));
+    }
+
+    #[test]
+    fn event_filter_matches_types_and_strips_excluded_fields() {
+        let assistant = r#"{"type":"assistant","message":{"content":[{"type":"text","text":"hi"}],"usage":{"input_tokens":3}}}"#;
+        let tool_result =
+            r#"{"type":"user","message":{"content":[{"type":"tool_result","tool_use_id":"t1"}]}}"#;
+        let system = r#"{"type":"system","subtype":"init"}"#;
+
+        let only = filter(&["assistant", "tool_result"], &["usage"]);
+        let kept: Value = serde_json::from_str(&only.apply(assistant).unwrap()).unwrap();
+        assert!(kept["message"].get("usage").is_none());
+        assert!(only.apply(tool_result).is_some());
+        assert!(only.apply(system).is_none());
+        assert!(only.apply("not json").is_none());
+
+        let none = filter(&[], &[]);
+        assert!(matches!(none.apply(system), Some(Cow::Borrowed(_))));
+        assert!(filter(&[], &["system"]).apply(system).is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_stream_reports_gap_and_refills_from_buffer() {
+        let session = Arc::new(CommandSession::new(
+            "lagging".to_string(),
+            ExecutorKind::Claude,
+        ));
+        for n in 1..=10 {
+            session.add_output(format!("{{\"n\":{}}}", n)).await;
+        }
+
+        // A tiny pipe stalls the stream while it writes the buffered lines
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let streamed = session.clone();
+        let task = tokio::spawn(async move {
+            stream_live_output(&mut server, &EventFilter::default(), &streamed, 1).await
+        });
+
+        // Once bytes arrive the stream has subscribed and read the buffer
+        let mut output = vec![0u8; 16];
+        let read = client.read(&mut output).await.unwrap();
+        output.truncate(read);
+
+        // More lines than the broadcast channel holds
+        for n in 11..=1110 {
+            session.add_output(format!("{{\"n\":{}}}", n)).await;
+        }
+        session.mark_completed(Some(0)).await;
+
+        client.read_to_end(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let ids: Vec<usize> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("id: "))
+            .map(|id| id.parse().unwrap())
+            .collect();
+        assert_eq!(ids, (1..=1110).collect::<Vec<_>>());
+        assert_eq!(output.matches("\"type\":\"gap\"").count(), 1);
+        assert!(
+            output.trim_end().ends_with(
+                &json!({"type":"completion","success":true,"exit_code":0,"total_lines":1110})
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_options_name_the_field_and_its_allowed_values() {
+        let request = common::http::HttpRequest {
+            method: common::http::HttpMethod::POST,
+            path: "/api/sessions".to_string(),
+            query_params: common::http::Params::new(),
+            headers: common::http::Params::new(),
+            body: Vec::new(),
+            trailers: common::http::Params::new(),
+        };
+        let parse = |body: Value| parse_executor_options(&body, &request);
+
+        let error = parse(json!({"permission_mode": "yolo"})).unwrap_err();
+        assert_eq!(error.code(), "invalid_permission_mode");
+        let ValidationError::NotAllowed { allowed, .. } = &error else {
+            panic!("{:?}", error);
+        };
+        assert_eq!(*allowed, PERMISSION_MODES);
+        let error = parse(json!({"executor": "gemini", "approval_mode": "plan"})).unwrap_err();
+        assert_eq!(error.code(), "invalid_approval_mode");
+        let error = parse(json!({"executor": "codex", "resume_last": []})).unwrap_err();
+        assert_eq!(error.code(), "invalid_resume_last");
+        assert!(parse(json!({"permission_mode": "plan"})).is_ok());
+
+        assert_eq!(
+            ValidationError::choice("decision", None, DECISIONS).code(),
+            "missing_decision"
+        );
+        assert_eq!(
+            ValidationError::NotADirectory("/nope".into()).code(),
+            "invalid_project_path"
+        );
+    }
+
+    /// State whose sessions are kept in memory only
+    fn state() -> HandlerState {
+        let mut state = HandlerState::new(ClientConfig::parse_from(["arpc"]));
+        state.session_manager = SessionManager::new();
+        state
+    }
+
+    /// Run `handler` on a POST with `path_params` and a JSON `body`, and
+    /// return the status and JSON body of its response
+    async fn call<F, Fut>(
+        handler: F,
+        state: &HandlerState,
+        path_params: &[(&str, &str)],
+        body: Value,
+    ) -> (u16, Value)
+    where
+        F: FnOnce(HandlerContext, HandlerState) -> Fut,
+        Fut: std::future::Future<Output = Result<HttpResponse>>,
+    {
+        call_with_headers(handler, state, path_params, &[], body).await
+    }
+
+    /// [`call`] with request `headers`
+    async fn call_with_headers<F, Fut>(
+        handler: F,
+        state: &HandlerState,
+        path_params: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Value,
+    ) -> (u16, Value)
+    where
+        F: FnOnce(HandlerContext, HandlerState) -> Fut,
+        Fut: std::future::Future<Output = Result<HttpResponse>>,
+    {
+        let mut request_headers = Params::new();
+        for (name, value) in headers {
+            request_headers.insert(*name, *value);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let ctx = HandlerContext {
+            request: HttpRequest {
+                method: HttpMethod::POST,
+                path: "/".to_string(),
+                query_params: Params::new(),
+                headers: request_headers,
+                body: serde_json::to_vec(&body).unwrap(),
+                trailers: Params::new(),
+            },
+            stream,
+            proxy_conn_id: "test".to_string(),
+            path_params: path_params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        handler(ctx, state.clone()).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sessions_fail_when_their_command_cannot_be_waited_on() {
+        let session = SessionManager::new()
+            .create_session_with_id_and_executor("s1".to_string(), ExecutorKind::Claude)
+            .await;
+
+        let exit = Err(std::io::Error::other("no child processes"));
+        assert!(record_exit(&session, exit).await.is_err());
+        assert_eq!(
+            session.get_status().await,
+            SessionStatus::Failed {
+                error: "Failed to wait for the command: no child processes".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn project_paths_must_resolve_to_allowed_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(dir.path().join("outside")).unwrap();
+        std::fs::write(root.join("notes.txt"), "").unwrap();
+        let policy = SessionPolicy::default()
+            .with_project_roots(std::slice::from_ref(&root))
+            .unwrap();
+        let state = state().with_session_policy(policy);
+        let create = |path: PathBuf| {
+            let body = json!({"prompt": "hi", "project_path": path});
+            call(handle_create_session, &state, &[], body)
+        };
+
+        // A file, and a path that can't be resolved
+        for path in [root.join("notes.txt"), root.join("missing")] {
+            let (status, body) = create(path.clone()).await;
+            assert_eq!(status, 400, "{}", path.display());
+            assert_eq!(body["code"], "invalid_project_path");
+            assert_eq!(body["field"], "project_path");
+        }
+        let (status, body) = create(root.join("../outside")).await;
+        assert_eq!(status, 403);
+        assert_eq!(body["rule"], "project_roots");
+    }
+
+    #[tokio::test]
+    async fn permission_decisions_are_counted_per_approver() {
+        let state = state().with_approvers(Approvers::from_tokens(&[
+            ("alice", "token-a"),
+            ("bob", "token-b"),
+            ("carol", "token-c"),
+        ]));
+        let session = state
+            .session_manager
+            .create_session_with_id_and_executor("s1".to_string(), ExecutorKind::Claude)
+            .await;
+        let (id, rx) = session
+            .request_permission("Bash", &json!({"command": "ls"}), 2)
+            .await;
+        let params = [("session_id", "s1"), ("permission_id", id.as_str())];
+        let decide = |token: &str, body: Value| {
+            let (state, params) = (&state, &params);
+            let authorization = format!("Bearer {}", token);
+            async move {
+                call_with_headers(
+                    handle_permission_decision,
+                    state,
+                    params,
+                    &[("authorization", authorization.as_str())],
+                    body,
+                )
+                .await
+            }
+        };
+
+        let (status, body) = decide("token-a", json!({"decision": "maybe"})).await;
+        assert_eq!(
+            (status, body["code"].clone()),
+            (400, json!("invalid_decision"))
+        );
+        // Naming an approver in the body proves nothing
+        let (status, _) = call(
+            handle_permission_decision,
+            &state,
+            &params,
+            json!({"decision": "approve", "decided_by": "bob"}),
+        )
+        .await;
+        assert_eq!(status, 401);
+        let (status, _) = decide("token-x", json!({"decision": "approve"})).await;
+        assert_eq!(status, 401);
+
+        let (status, body) = decide(
+            "token-a",
+            json!({"decision": "approve", "decided_by": "bob"}),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["type"], "permission_pending");
+        assert_eq!(
+            (
+                body["approvals"].clone(),
+                body["required_approvals"].clone()
+            ),
+            (json!(1), json!(2))
+        );
+        // The same approver again, whatever name the body gives
+        let (status, _) = decide(
+            "token-a",
+            json!({"decision": "approve", "decided_by": "carol"}),
+        )
+        .await;
+        assert_eq!(status, 409);
+        let (status, body) = decide("token-b", json!({"decision": "approve"})).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["type"], "permission_decided");
+        assert!(rx.await.unwrap().approved);
+        let (status, _) = decide("token-c", json!({"decision": "deny"})).await;
+        assert_eq!(status, 404);
+
+        let (_, history) = call(
+            handle_permission_history,
+            &state,
+            &[("session_id", "s1")],
+            Value::Null,
+        )
+        .await;
+        assert_eq!(history["type"], "permission_history");
+        let decided_by: Vec<_> = history["decisions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| (record["decision"].clone(), record["decided_by"].clone()))
+            .collect();
+        assert_eq!(
+            decided_by,
+            [
+                (json!("approved"), json!("alice")),
+                (json!("approved"), json!("bob"))
+            ]
+        );
+        assert_eq!(history["decisions"][0]["tool_name"], "Bash");
+
+        let params = [("session_id", "missing"), ("permission_id", id.as_str())];
+        let (status, _) = call_with_headers(
+            handle_permission_decision,
+            &state,
+            &params,
+            &[("authorization", "Bearer token-a")],
+            json!({"decision": "deny"}),
+        )
+        .await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn chat_links_decide_once_as_their_approver() {
+        let approvers = Approvers::from_tokens(&[("alice", "token-a"), ("bob", "token-b")]);
+        let link = |name: &str, permission_id: &str, decision: &str| {
+            approvers
+                .link_token(name, "s1", permission_id, decision)
+                .unwrap()
+        };
+        let state = state().with_approvers(Approvers::from_tokens(&[
+            ("alice", "token-a"),
+            ("bob", "token-b"),
+        ]));
+        let session = state
+            .session_manager
+            .create_session_with_id_and_executor("s1".to_string(), ExecutorKind::Claude)
+            .await;
+        let (id, rx) = session
+            .request_permission("Bash", &json!({"command": "ls"}), 2)
+            .await;
+        let params = [("session_id", "s1"), ("permission_id", id.as_str())];
+        let decide = |decision: &str, token: String| {
+            let body = json!({"decision": decision, "approver_token": token});
+            call(handle_permission_decision, &state, &params, body)
+        };
+
+        // A link for another prompt, or for the other decision
+        let (status, _) = decide("approve", link("alice", "other", "approve")).await;
+        assert_eq!(status, 401);
+        let (status, _) = decide("approve", link("alice", &id, "deny")).await;
+        assert_eq!(status, 401);
+
+        let (status, body) = decide("approve", link("alice", &id, "approve")).await;
+        assert_eq!((status, body["approvals"].clone()), (200, json!(1)));
+        // Used again, the same link doesn't count twice
+        let (status, _) = decide("approve", link("alice", &id, "approve")).await;
+        assert_eq!(status, 409);
+        let (status, body) = decide("approve", link("bob", &id, "approve")).await;
+        assert_eq!(
+            (status, body["type"].clone()),
+            (200, json!("permission_decided"))
+        );
+        assert_eq!(rx.await.unwrap().decided_by.as_deref(), Some("bob"));
+        let (status, _) = decide("deny", link("bob", &id, "deny")).await;
+        assert_eq!(status, 404);
+    }
 }
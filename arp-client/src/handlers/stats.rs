@@ -0,0 +1,55 @@
+use super::HandlerState;
+use crate::executor::{ExecutorKind, find_binary};
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::HttpResponse;
+use serde_json::{Map, Value, json};
+
+const EXECUTORS: [ExecutorKind; 3] = [
+    ExecutorKind::Claude,
+    ExecutorKind::Codex,
+    ExecutorKind::Gemini,
+];
+
+/// Report session counts, uptime, executor availability, stream
+/// subscribers and server round-trip times as one document for monitoring
+/// to scrape
+pub async fn handle_stats(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
+    let manager = &state.session_manager;
+    let body = json!({
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "sessions": manager.get_stats().await,
+        "pending_permissions": manager.pending_permission_count().await,
+        "stream_subscribers": manager.subscriber_count(),
+        "executors": executor_availability().await,
+        "relays": state.relays.snapshot(),
+    });
+
+    let mut stream = ctx.stream;
+    let _ = HttpResponse::ok()
+        .json(&body)
+        .header("Cache-Control", "no-store")
+        .send(&mut stream)
+        .await;
+    Ok(HttpResponse::ok())
+}
+
+/// Whether each executor's binary is installed, and which one would run
+async fn executor_availability() -> Value {
+    // Searching PATH touches the filesystem
+    let found = tokio::task::spawn_blocking(|| EXECUTORS.map(find_binary))
+        .await
+        .unwrap_or_default();
+
+    let executors: Map<String, Value> = EXECUTORS
+        .iter()
+        .zip(found)
+        .map(|(kind, binary)| {
+            (
+                kind.as_str().to_string(),
+                json!({ "available": binary.is_some(), "binary": binary }),
+            )
+        })
+        .collect();
+    Value::Object(executors)
+}
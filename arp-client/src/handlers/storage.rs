@@ -0,0 +1,59 @@
+use crate::agentx::storage;
+use crate::handlers::HandlerState;
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::{self, HttpResponse};
+use serde_json::json;
+
+/// Report bytes used by each executor's history and the client data directory
+pub async fn handle_storage(ctx: HandlerContext) -> Result<HttpResponse> {
+    let mut stream = ctx.stream;
+    let response = match storage::usage().await {
+        Ok(usage) => HttpResponse::ok().json(&usage),
+        Err(e) => http::json_error(500, e),
+    };
+    let _ = response.send(&mut stream).await;
+    Ok(HttpResponse::ok())
+}
+
+/// Apply the `--retention` rules now, or with `dryRun=true` only report what
+/// they would delete
+pub async fn handle_retention(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
+    let mut stream = ctx.stream;
+    if state.retention.is_empty() {
+        let _ = http::json_error(409, "No retention rules configured")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+    let dry_run = ctx
+        .request
+        .query_param("dryRun")
+        .is_some_and(|v| v == "true" || v == "1");
+
+    let deletions = match storage::plan(&state.retention).await {
+        Ok(deletions) => deletions,
+        Err(e) => {
+            let _ = http::json_error(500, e).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+    let mut body = storage::report(&deletions);
+    body["type"] = json!("retention");
+    body["dry_run"] = json!(dry_run);
+    body["rules"] = json!(*state.retention);
+    if !dry_run {
+        match storage::apply(deletions, &state.listing_cache).await {
+            Ok((deleted, failed)) => {
+                body["deleted"] = json!(deleted);
+                body["failed"] = json!(failed);
+            }
+            Err(e) => {
+                let _ = http::json_error(500, e).send(&mut stream).await;
+                return Ok(HttpResponse::ok());
+            }
+        }
+    }
+    let _ = HttpResponse::ok().json(&body).send(&mut stream).await;
+    Ok(HttpResponse::ok())
+}
@@ -0,0 +1,221 @@
+use crate::handlers::HandlerState;
+use crate::handlers::filesystem::sha256_file;
+use crate::handlers::paths::{FsOptions, FsRequestPaths, resolve_request_paths, resolve_target};
+use crate::router::HandlerContext;
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use common::http::{HttpResponse, json_error};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+
+/// Most paths accepted in one sync request
+const MAX_SYNC_FILES: usize = 10_000;
+
+/// File contents returned by one sync response (32 MiB); changed files past
+/// this budget are reported as deferred
+const SYNC_CONTENT_BUDGET: u64 = 32 * 1024 * 1024;
+
+/// What the caller already has: project-relative path to its SHA-256, or
+/// `null` for a file it doesn't have yet
+#[derive(Debug, Deserialize)]
+struct SyncManifest {
+    files: BTreeMap<String, Option<String>>,
+}
+
+/// Compare a manifest of path → SHA-256 with the project and return, in one
+/// response, which files differ along with the contents of the changed ones.
+///
+/// Each entry has a `status` of `unchanged`, `changed`, `deleted` or
+/// `error`. Changed files carry base64 `content` until the response's
+/// content budget runs out; the rest are marked `deferred` and can be synced
+/// again or fetched with `raw=true`.
+pub async fn handle_sync(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
+    let HandlerContext {
+        request,
+        mut stream,
+        proxy_conn_id: _,
+        mut path_params,
+    } = ctx;
+
+    if !state.config.enable_fs {
+        let _ = json_error(403, "Filesystem browsing API is disabled")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let FsRequestPaths {
+        session_id, base, ..
+    } = match resolve_request_paths(&request, &mut path_params, &state).await {
+        Ok(paths) => paths,
+        Err((status, message)) => {
+            let _ = json_error(status, message).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let manifest: SyncManifest = match serde_json::from_slice(&request.body) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = json_error(400, format!("Invalid sync manifest: {}", e))
+                .send(&mut stream)
+                .await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+    if manifest.files.len() > MAX_SYNC_FILES {
+        let _ = json_error(
+            400,
+            format!(
+                "Sync manifest lists {} files, more than the limit of {}",
+                manifest.files.len(),
+                MAX_SYNC_FILES
+            ),
+        )
+        .send(&mut stream)
+        .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let options = state.config.fs_options();
+    let mut budget = SYNC_CONTENT_BUDGET;
+    let mut files = Vec::with_capacity(manifest.files.len());
+    let mut differing = 0;
+    for (path, known) in &manifest.files {
+        let entry = sync_entry(&base, path, known.as_deref(), &options, &mut budget).await;
+        if entry["status"] != "unchanged" {
+            differing += 1;
+        }
+        files.push(entry);
+    }
+
+    let body = json!({
+        "type": "sync",
+        "session_id": session_id,
+        "project_path": base.to_string_lossy().replace('\\', "/"),
+        "differing": differing,
+        "files": files,
+    });
+    HttpResponse::ok()
+        .json(&body)
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Cache-Control", "no-store")
+        .send(&mut stream)
+        .await?;
+    Ok(HttpResponse::ok())
+}
+
+/// Compare one manifest entry with the file on disk, spending `budget` on
+/// the content of a changed file
+async fn sync_entry(
+    base: &Path,
+    path: &str,
+    known: Option<&str>,
+    options: &FsOptions,
+    budget: &mut u64,
+) -> Value {
+    let error = |message: String| json!({ "path": path, "status": "error", "error": message });
+
+    let target = match resolve_target(base, path, options).await {
+        Ok(target) => target,
+        Err((404, _)) => return json!({ "path": path, "status": "deleted" }),
+        Err((_, message)) => return error(message),
+    };
+    let metadata = match fs::metadata(&target).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return error("Not a regular file".to_string()),
+        Err(e) => return error(format!("Failed to access path: {}", e)),
+    };
+    let size = metadata.len();
+    if options.max_file_size > 0 && size > options.max_file_size {
+        return error(format!(
+            "File is {} bytes, larger than the {} byte limit",
+            size, options.max_file_size
+        ));
+    }
+
+    // Files that fit the budget are read once for both hash and content;
+    // larger ones are only hashed, in chunks
+    let (sha256, content) = if size <= *budget {
+        match fs::read(&target).await {
+            Ok(bytes) => (hex_sha256(&bytes), Some(bytes)),
+            Err(e) => return error(format!("Failed to read file: {}", e)),
+        }
+    } else {
+        match sha256_file(target.clone()).await {
+            Ok(digest) => (digest, None),
+            Err(message) => return error(message),
+        }
+    };
+
+    if known.is_some_and(|known| known.eq_ignore_ascii_case(&sha256)) {
+        return json!({ "path": path, "status": "unchanged", "sha256": sha256 });
+    }
+
+    let mut entry = json!({
+        "path": path,
+        "status": "changed",
+        "sha256": sha256,
+        "size": size,
+    });
+    match content {
+        Some(bytes) => {
+            *budget = budget.saturating_sub(bytes.len() as u64);
+            entry["content"] = json!(BASE64.encode(&bytes));
+        }
+        None => entry["deferred"] = json!(true),
+    }
+    entry
+}
+
+fn hex_sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::paths::SymlinkPolicy;
+
+    #[tokio::test]
+    async fn sync_reports_only_what_differs() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        std::fs::write(base.join("same.txt"), "abc").unwrap();
+        std::fs::write(base.join("edited.txt"), "new").unwrap();
+        std::fs::write(base.join("large.bin"), [7u8; 64]).unwrap();
+        let options = FsOptions {
+            symlinks: SymlinkPolicy::Follow,
+            hide_hidden: false,
+            max_file_size: 0,
+        };
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        let mut budget = 10;
+        let entry = sync_entry(&base, "same.txt", Some(abc), &options, &mut budget).await;
+        assert_eq!(entry["status"], "unchanged");
+
+        let entry = sync_entry(&base, "edited.txt", Some(abc), &options, &mut budget).await;
+        assert_eq!(entry["status"], "changed");
+        assert_eq!(entry["content"], BASE64.encode("new"));
+        assert_eq!(budget, 7);
+
+        let entry = sync_entry(&base, "large.bin", None, &options, &mut budget).await;
+        assert_eq!(entry["status"], "changed");
+        assert_eq!(entry["deferred"], true);
+        assert!(entry.get("content").is_none());
+
+        let entry = sync_entry(&base, "gone.txt", Some(abc), &options, &mut budget).await;
+        assert_eq!(entry["status"], "deleted");
+        let entry = sync_entry(&base, "../etc/passwd", None, &options, &mut budget).await;
+        assert_eq!(entry["status"], "error");
+    }
+}
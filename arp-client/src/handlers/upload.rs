@@ -0,0 +1,259 @@
+use crate::handlers::HandlerState;
+use crate::handlers::parse_bool_str;
+use crate::handlers::paths::{FsRequestPaths, resolve_request_paths, resolve_target};
+use crate::router::HandlerContext;
+use anyhow::Result;
+use common::http::{HttpRequest, HttpResponse, MultipartPart, json_error};
+use serde_json::json;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Status code and message of a rejected upload
+type UploadError = (u16, String);
+
+/// A file from the request, checked and ready to be written
+struct PendingFile<'a> {
+    name: &'a str,
+    target: PathBuf,
+    data: &'a [u8],
+}
+
+/// Store the files of a `multipart/form-data` body in a project directory.
+///
+/// `{*path}` names an existing directory under the project. Every part with
+/// a file name is saved there under that name; other fields are ignored.
+/// Existing files are only replaced with `overwrite=true`, and nothing is
+/// written unless every file passes the checks.
+pub async fn handle_upload(ctx: HandlerContext, state: HandlerState) -> Result<HttpResponse> {
+    let HandlerContext {
+        request,
+        mut stream,
+        proxy_conn_id: _,
+        mut path_params,
+    } = ctx;
+
+    if !state.config.enable_fs || !state.config.fs_upload {
+        let _ = json_error(403, "Filesystem upload API is disabled")
+            .send(&mut stream)
+            .await;
+        return Ok(HttpResponse::ok());
+    }
+
+    let FsRequestPaths {
+        session_id,
+        base,
+        path,
+    } = match resolve_request_paths(&request, &mut path_params, &state).await {
+        Ok(paths) => paths,
+        Err((status, message)) => {
+            let _ = json_error(status, message).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let overwrite = request
+        .query_param("overwrite")
+        .is_some_and(|value| parse_bool_str(value) == Some(true));
+
+    let stored = match store_upload(&request, &base, &path, overwrite, &state).await {
+        Ok(stored) => stored,
+        Err((status, message)) => {
+            let _ = json_error(status, message).send(&mut stream).await;
+            return Ok(HttpResponse::ok());
+        }
+    };
+
+    let body = json!({
+        "type": "upload",
+        "session_id": session_id,
+        "project_path": base.to_string_lossy().replace('\\', "/"),
+        "path": path,
+        "files": stored,
+    });
+    HttpResponse::new(201)
+        .json(&body)
+        .header("X-Content-Type-Options", "nosniff")
+        .send(&mut stream)
+        .await?;
+    Ok(HttpResponse::ok())
+}
+
+async fn store_upload(
+    request: &HttpRequest,
+    base: &Path,
+    path: &str,
+    overwrite: bool,
+    state: &HandlerState,
+) -> Result<Vec<serde_json::Value>, UploadError> {
+    let options = state.config.fs_options();
+    let directory = resolve_target(base, path, &options).await?;
+    let is_dir = fs::metadata(&directory)
+        .await
+        .map_err(|e| (500, format!("Failed to access path: {}", e)))?
+        .is_dir();
+    if !is_dir {
+        return Err((400, "Upload target must be a directory".to_string()));
+    }
+
+    let parts = request
+        .multipart()
+        .map_err(|e| (400, format!("Invalid upload: {}", e)))?;
+    let files: Vec<&MultipartPart> = parts.iter().filter(|p| p.filename.is_some()).collect();
+    if files.is_empty() {
+        return Err((400, "Upload contains no files".to_string()));
+    }
+
+    let total: u64 = files.iter().map(|file| file.data.len() as u64).sum();
+    let limit = state.config.fs_max_upload_size;
+    if total > limit {
+        return Err((
+            413,
+            format!(
+                "Upload is {} bytes, larger than the {} byte limit",
+                total, limit
+            ),
+        ));
+    }
+
+    // Check every file before writing any of them
+    let mut names = HashSet::new();
+    let mut pending = Vec::with_capacity(files.len());
+    for file in files {
+        let name = file.filename.as_deref().unwrap_or_default();
+        check_file_name(name, options.hide_hidden)?;
+        if !names.insert(name) {
+            return Err((400, format!("File '{}' appears more than once", name)));
+        }
+
+        let target = directory.join(name);
+        check_target(&target, name, overwrite).await?;
+        pending.push(PendingFile {
+            name,
+            target,
+            data: &file.data,
+        });
+    }
+
+    let mut stored = Vec::with_capacity(pending.len());
+    for file in pending {
+        write_atomically(&directory, &file).await?;
+        let relative = file
+            .target
+            .strip_prefix(base)
+            .unwrap_or(&file.target)
+            .to_string_lossy()
+            .replace('\\', "/");
+        stored.push(json!({
+            "name": file.name,
+            "path": relative,
+            "size": file.data.len(),
+        }));
+    }
+    Ok(stored)
+}
+
+/// Uploaded names must be a single plain path component
+fn check_file_name(name: &str, hide_hidden: bool) -> Result<(), UploadError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err((400, format!("Invalid file name '{}'", name)));
+    }
+    if hide_hidden && name.starts_with('.') {
+        return Err((
+            403,
+            format!("Uploading hidden file '{}' is not allowed", name),
+        ));
+    }
+    Ok(())
+}
+
+async fn check_target(target: &Path, name: &str, overwrite: bool) -> Result<(), UploadError> {
+    match fs::symlink_metadata(target).await {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            Err((403, format!("Refusing to replace symbolic link '{}'", name)))
+        }
+        Ok(metadata) if metadata.is_dir() => {
+            Err((409, format!("'{}' is an existing directory", name)))
+        }
+        Ok(_) if !overwrite => Err((
+            409,
+            format!(
+                "File '{}' already exists; pass overwrite=true to replace it",
+                name
+            ),
+        )),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err((500, format!("Failed to access '{}': {}", name, e))),
+    }
+}
+
+/// Write to a temporary file beside the target and rename it into place, so
+/// readers never see a partly written file
+async fn write_atomically(directory: &Path, file: &PendingFile<'_>) -> Result<(), UploadError> {
+    let temp = directory.join(format!(".{}.upload-{}", file.name, uuid::Uuid::new_v4()));
+    let write = async {
+        let mut out = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .await?;
+        out.write_all(file.data).await?;
+        out.sync_all().await?;
+        fs::rename(&temp, &file.target).await
+    };
+
+    if let Err(e) = write.await {
+        let _ = fs::remove_file(&temp).await;
+        return Err((500, format!("Failed to write '{}': {}", file.name, e)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn upload_targets_are_checked_before_writing() {
+        assert!(check_file_name("data.csv", true).is_ok());
+        assert_eq!(check_file_name("../x", false).unwrap_err().0, 400);
+        assert_eq!(check_file_name("a\\b", false).unwrap_err().0, 400);
+        assert_eq!(check_file_name(".env", true).unwrap_err().0, 403);
+        assert!(check_file_name(".env", false).is_ok());
+
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("config.json");
+        std::fs::write(&existing, "{}").unwrap();
+        assert!(
+            check_target(&dir.path().join("new.bin"), "new.bin", false)
+                .await
+                .is_ok()
+        );
+        assert_eq!(
+            check_target(&existing, "config.json", false)
+                .await
+                .unwrap_err()
+                .0,
+            409
+        );
+        assert!(check_target(&existing, "config.json", true).await.is_ok());
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&existing, &link).unwrap();
+            assert_eq!(check_target(&link, "link", true).await.unwrap_err().0, 403);
+        }
+
+        let file = PendingFile {
+            name: "config.json",
+            target: existing.clone(),
+            data: b"{\"a\":1}",
+        };
+        write_atomically(dir.path(), &file).await.unwrap();
+        assert_eq!(std::fs::read(&existing).unwrap(), b"{\"a\":1}");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::executor::ExecutorKind;
+use crate::session::SessionEnded;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Characters of a failed command's output kept in the log
+const OUTPUT_TAIL_CHARS: usize = 2000;
+
+/// Hooks run after a session completes successfully, loaded from a JSON file:
+///
+/// ```json
+/// {
+///   "hooks": [
+///     {"type": "command", "command": "cargo test", "executors": ["claude"]},
+///     {"type": "http", "url": "https://ci.example.com/trigger?ref={session_id}",
+///      "headers": {"Authorization": "Bearer ..."}, "body": {"path": "{project_path}"}}
+///   ]
+/// }
+/// ```
+///
+/// `{session_id}`, `{agent_session_id}`, `{executor}`, `{project_path}`,
+/// `{status}` and `{exit_code}` are replaced in commands (shell-quoted), URLs
+/// (percent-encoded), header values and the strings of `body`. Commands run
+/// in the project directory with the same values in `ARPC_*` variables; an
+/// HTTP hook without `body` posts all of them as JSON. `cmd` can't quote a
+/// value safely, so on Windows commands only get the variables.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionHooks {
+    hooks: Vec<HookConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HookConfig {
+    #[serde(flatten)]
+    action: Action,
+    /// Only run after sessions of these executors; all when empty
+    #[serde(default)]
+    executors: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    600
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Action {
+    /// Run a shell command
+    Command { command: String },
+    /// Call a URL
+    Http {
+        url: String,
+        #[serde(default = "default_method")]
+        method: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        body: Option<Value>,
+    },
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Action::Command { command } => format!("command `{}`", command),
+            Action::Http { method, url, .. } => format!("{} {}", method, url),
+        }
+    }
+}
+
+impl SessionHooks {
+    /// Load the hooks from `path`; no path means no hooks
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read session hooks {}", path.display()))?;
+        let hooks: SessionHooks = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid session hooks {}: {}", path.display(), e))?;
+        for hook in &hooks.hooks {
+            if let Some(unknown) = hook
+                .executors
+                .iter()
+                .find(|name| ExecutorKind::from_str(name).is_none())
+            {
+                return Err(anyhow!(
+                    "Unknown executor '{}' in {}",
+                    unknown,
+                    path.display()
+                ));
+            }
+            if cfg!(windows)
+                && let Action::Command { command } = &hook.action
+                && let Some(name) = HookValues::NAMES
+                    .iter()
+                    .find(|name| command.contains(&format!("{{{}}}", name)))
+            {
+                return Err(anyhow!(
+                    "Command hooks can't use {{{}}} on Windows, use %ARPC_{}% in {}",
+                    name,
+                    name.to_uppercase(),
+                    path.display()
+                ));
+            }
+            if let Action::Http { method, .. } = &hook.action {
+                reqwest::Method::from_bytes(method.as_bytes()).map_err(|_| {
+                    anyhow!("Invalid HTTP method '{}' in {}", method, path.display())
+                })?;
+            }
+        }
+
+        info!(
+            "Loaded {} session hooks from {}",
+            hooks.hooks.len(),
+            path.display()
+        );
+        Ok(hooks)
+    }
+
+    /// Run the hooks for every session that completes successfully, each
+    /// in its own task so a slow hook doesn't hold up the others
+    pub fn start(self, mut ended: broadcast::Receiver<SessionEnded>) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let hooks: Vec<Arc<HookConfig>> = self.hooks.into_iter().map(Arc::new).collect();
+        let http = reqwest::Client::new();
+        tokio::spawn(async move {
+            loop {
+                let session = match ended.recv().await {
+                    Ok(session) => session,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Session hooks missed {} ended sessions", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let (status, exit_code, _) = session.status.outcome();
+                if status != "completed" {
+                    continue;
+                }
+                let values = Arc::new(HookValues::new(&session, status, exit_code));
+
+                for hook in &hooks {
+                    let executor = session.executor_kind.as_str();
+                    if !hook.executors.is_empty() && !hook.executors.iter().any(|e| e == executor) {
+                        continue;
+                    }
+                    let hook = hook.clone();
+                    let values = values.clone();
+                    let http = http.clone();
+                    tokio::spawn(async move {
+                        let name = hook.action.describe();
+                        match run(&hook, &values, &http).await {
+                            Ok(()) => {
+                                info!("Session hook {} succeeded for {}", name, values.session_id)
+                            }
+                            Err(e) => warn!(
+                                "Session hook {} failed for {}: {}",
+                                name, values.session_id, e
+                            ),
+                        }
+                    });
+                }
+            }
+        });
+    }
+}
+
+/// What a hook may refer to about the session that ended
+struct HookValues {
+    session_id: String,
+    project_path: Option<String>,
+    values: Vec<(&'static str, String)>,
+}
+
+impl HookValues {
+    /// Names hooks can refer to, in the order of `values`
+    const NAMES: [&'static str; 6] = [
+        "session_id",
+        "agent_session_id",
+        "executor",
+        "project_path",
+        "status",
+        "exit_code",
+    ];
+
+    fn new(session: &SessionEnded, status: &str, exit_code: Option<i32>) -> Self {
+        let project_path = session
+            .project_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned());
+        HookValues {
+            session_id: session.session_id.clone(),
+            values: Self::NAMES
+                .into_iter()
+                .zip([
+                    session.session_id.clone(),
+                    session.agent_session_id.clone().unwrap_or_default(),
+                    session.executor_kind.as_str().to_string(),
+                    project_path.clone().unwrap_or_default(),
+                    status.to_string(),
+                    exit_code.map(|code| code.to_string()).unwrap_or_default(),
+                ])
+                .collect(),
+            project_path,
+        }
+    }
+
+    /// Replace every `{name}` in `template`, passing values through `escape`.
+    /// One pass over the template, so text from a value is never filled in
+    /// again.
+    fn fill(&self, template: &str, escape: impl Fn(&str) -> String) -> String {
+        let mut text = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find('}').and_then(|end| {
+                self.values
+                    .iter()
+                    .find(|(name, _)| *name == &rest[1..end])
+                    .map(|(_, value)| (end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    text.push_str(&escape(value));
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    text.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        text.push_str(rest);
+        text
+    }
+
+    fn fill_json(&self, template: &Value) -> Value {
+        match template {
+            Value::String(text) => Value::String(self.fill(text, str::to_string)),
+            Value::Array(items) => Value::Array(items.iter().map(|v| self.fill_json(v)).collect()),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), self.fill_json(value)))
+                    .collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+}
+
+async fn run(hook: &HookConfig, values: &HookValues, http: &reqwest::Client) -> Result<()> {
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    match &hook.action {
+        Action::Command { command } => {
+            let mut cmd = if cfg!(windows) {
+                let mut cmd = Command::new("cmd");
+                cmd.arg("/C").arg(command);
+                cmd
+            } else {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(values.fill(command, shell_quote));
+                cmd
+            };
+            if let Some(path) = values.project_path.as_deref().map(Path::new)
+                && path.is_dir()
+            {
+                cmd.current_dir(path);
+            }
+            for (name, value) in &values.values {
+                cmd.env(format!("ARPC_{}", name.to_uppercase()), value);
+            }
+            cmd.stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true);
+
+            let output = tokio::time::timeout(timeout, cmd.output())
+                .await
+                .map_err(|_| anyhow!("timed out after {}s", hook.timeout_secs))??;
+            if !output.status.success() {
+                let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                let tail: String = text
+                    .chars()
+                    .rev()
+                    .take(OUTPUT_TAIL_CHARS)
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .collect();
+                return Err(anyhow!("{}: {}", output.status, tail.trim()));
+            }
+        }
+        Action::Http {
+            url,
+            method,
+            headers,
+            body,
+        } => {
+            let url = values.fill(url, |value| urlencoding::encode(value).into_owned());
+            let method = reqwest::Method::from_bytes(method.as_bytes())?;
+            let body = match body {
+                Some(template) => values.fill_json(template),
+                None => Value::Object(
+                    values
+                        .values
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), Value::from(value.as_str())))
+                        .collect(),
+                ),
+            };
+            let mut request = http.request(method, url).timeout(timeout).json(&body);
+            for (name, value) in headers {
+                request = request.header(name, values.fill(value, str::to_string));
+            }
+            request.send().await?.error_for_status()?;
+        }
+    }
+    Ok(())
+}
+
+/// Quote a value so `sh` reads it as one literal word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::session::SessionStatus;
+
+    #[tokio::test]
+    async fn command_hooks_get_quoted_values_and_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("it's a project");
+        std::fs::create_dir(&project).unwrap();
+        let hook = HookConfig {
+            action: Action::Command {
+                command: "printf '%s|%s|%s' {project_path} \"$ARPC_EXECUTOR\" \"$PWD\" > out.txt"
+                    .to_string(),
+            },
+            executors: Vec::new(),
+            timeout_secs: 10,
+        };
+        let session = SessionEnded {
+            session_id: "s1".to_string(),
+            executor_kind: ExecutorKind::Claude,
+            agent_session_id: None,
+            project_path: Some(project.clone()),
+            status: SessionStatus::Completed { exit_code: Some(0) },
+        };
+        let values = HookValues::new(&session, "completed", Some(0));
+
+        run(&hook, &values, &reqwest::Client::new()).await.unwrap();
+        let out = std::fs::read_to_string(project.join("out.txt")).unwrap();
+        let project = project.to_string_lossy();
+        assert_eq!(out, format!("{}|claude|{}", project, project));
+    }
+
+    #[tokio::test]
+    async fn values_are_never_filled_in_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = HookConfig {
+            action: Action::Command {
+                command: "printf '%s|%s' {agent_session_id} {project_path} > out.txt".to_string(),
+            },
+            executors: Vec::new(),
+            timeout_secs: 10,
+        };
+        let session = SessionEnded {
+            session_id: "s1".to_string(),
+            executor_kind: ExecutorKind::Claude,
+            agent_session_id: Some("it's {project_path}; touch pwned".to_string()),
+            project_path: Some(dir.path().to_path_buf()),
+            status: SessionStatus::Completed { exit_code: Some(0) },
+        };
+        let values = HookValues::new(&session, "completed", Some(0));
+        assert_eq!(
+            values.fill("{{status}{nope}{", str::to_string),
+            "{completed{nope}{"
+        );
+
+        run(&hook, &values, &reqwest::Client::new()).await.unwrap();
+        let out = std::fs::read_to_string(dir.path().join("out.txt")).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "it's {{project_path}}; touch pwned|{}",
+                dir.path().to_string_lossy()
+            )
+        );
+        assert!(!dir.path().join("pwned").exists());
+    }
+}
@@ -0,0 +1,75 @@
+//! Tunnel client for arps. The `arpc` binary is a thin wrapper around
+//! [`TunnelClient`], which other applications can embed the same way:
+//!
+//! ```no_run
+//! use arpc::{ClientConfig, TunnelClient, http::HttpResponse};
+//! use clap::Parser;
+//!
+//! # async fn embed() -> anyhow::Result<()> {
+//! let config = ClientConfig::parse_from(["arpc", "--server-addr", "203.0.113.7"]);
+//! let client = TunnelClient::builder(config)
+//!     .on_request(|mut ctx| async move {
+//!         HttpResponse::ok().text("hello").send(&mut ctx.stream).await?;
+//!         Ok(HttpResponse::ok())
+//!     })
+//!     .build()?;
+//! client.run().await
+//! # }
+//! ```
+
+#[cfg(feature = "executors")]
+mod agentx;
+#[cfg(feature = "executors")]
+mod approvers;
+mod config;
+#[cfg(feature = "keyring")]
+pub mod credentials;
+#[cfg(feature = "digest")]
+mod digest;
+#[cfg(feature = "e2ee")]
+mod e2ee;
+#[cfg(feature = "executors")]
+mod events;
+mod executor;
+mod handlers;
+#[cfg(feature = "hooks")]
+mod hooks;
+#[cfg(feature = "lsp")]
+mod lsp;
+#[cfg(feature = "mcp")]
+mod mcp;
+#[cfg(feature = "dashboard")]
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "notifiers")]
+mod notifiers;
+#[cfg(feature = "executors")]
+mod orphans;
+pub mod peer;
+#[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+mod policy;
+#[cfg(feature = "executors")]
+mod process;
+#[cfg(feature = "executors")]
+mod redact;
+mod relays;
+mod resolver;
+mod router;
+#[cfg(feature = "command-mode")]
+mod routes;
+mod runtime;
+#[cfg(feature = "executors")]
+mod session;
+#[cfg(feature = "executors")]
+mod session_records;
+#[cfg(feature = "events")]
+mod sinks;
+mod tls;
+mod tunnel;
+
+pub use common::http;
+pub use config::{ClientCommand, ClientConfig, PeerService};
+pub use router::HandlerContext;
+pub use runtime::LogLevelHandle;
+pub use tunnel::{TunnelClient, TunnelClientBuilder, TunnelConnection};
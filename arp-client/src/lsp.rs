@@ -0,0 +1,229 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tracing::info;
+
+/// Largest LSP message relayed in either direction (64 MiB)
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Longest header line accepted in an LSP message
+const MAX_HEADER_LINE: u64 = 1024;
+
+/// How to reach one language server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LspServerConfig {
+    /// Spawned in the project directory for each connection, spoken to over stdio
+    Stdio {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+    /// Already running and listening on `address`
+    Tcp { address: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LspServersFile {
+    #[serde(default)]
+    language_servers: BTreeMap<String, LspServerConfig>,
+}
+
+/// Language servers remote editors may reach through `/api/lsp/{server}`
+///
+/// Loaded from a JSON file, e.g.
+///
+/// ```json
+/// {
+///   "languageServers": {
+///     "rust": {"command": "rust-analyzer"},
+///     "python": {"command": "pyright-langserver", "args": ["--stdio"]},
+///     "go": {"address": "127.0.0.1:4389"}
+///   }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct LspServers {
+    servers: BTreeMap<String, LspServerConfig>,
+}
+
+impl LspServers {
+    /// Load the servers from `path`; no path means no language servers
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read LSP config {}", path.display()))?;
+        let file: LspServersFile = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid LSP config {}: {}", path.display(), e))?;
+
+        info!(
+            "Loaded {} language server(s) from {}",
+            file.language_servers.len(),
+            path.display()
+        );
+        Ok(Self {
+            servers: file.language_servers,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LspServerConfig> {
+        self.servers.get(name)
+    }
+}
+
+/// Read one base-protocol message (`Content-Length` headers, blank line,
+/// JSON body) and return its body; `None` when the peer closed between messages
+pub async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut content_length = None;
+    let mut started = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = (&mut *reader)
+            .take(MAX_HEADER_LINE)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 {
+            if started {
+                return Err(anyhow!("Connection closed inside an LSP message header"));
+            }
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(anyhow!("LSP header line too long"));
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            if started {
+                break;
+            }
+            continue;
+        }
+        started = true;
+
+        let (key, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Malformed LSP header: {}", header))?;
+        if key.trim().eq_ignore_ascii_case("content-length") {
+            content_length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("Invalid Content-Length: {}", value.trim()))?,
+            );
+        }
+    }
+
+    let length = content_length.ok_or_else(|| anyhow!("LSP message without Content-Length"))?;
+    if length > MAX_MESSAGE_BYTES {
+        return Err(anyhow!(
+            "LSP message of {} bytes is larger than the {} byte limit",
+            length,
+            MAX_MESSAGE_BYTES
+        ));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body))
+}
+
+/// Write one base-protocol message
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, body: &[u8]) -> Result<()> {
+    writer
+        .write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+        .await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Relay messages from `reader` to `writer` until `reader` closes
+async fn relay<R, W>(mut reader: R, mut writer: W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(body) = read_message(&mut reader).await? {
+        write_message(&mut writer, &body).await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Bridge an editor and a language server message by message, so each
+/// side always receives whole, correctly framed messages. Returns when
+/// either side goes away.
+pub async fn bridge<ER, EW, SR, SW>(
+    editor_reader: ER,
+    editor_writer: EW,
+    server_reader: SR,
+    server_writer: SW,
+) -> Result<()>
+where
+    ER: AsyncRead + Unpin,
+    EW: AsyncWrite + Unpin,
+    SR: AsyncRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    let to_server = relay(tokio::io::BufReader::new(editor_reader), server_writer);
+    let to_editor = relay(tokio::io::BufReader::new(server_reader), editor_writer);
+    tokio::select! {
+        result = to_server => result,
+        result = to_editor => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_are_reframed_across_the_bridge() {
+        let (mut editor, editor_side) = tokio::io::duplex(4096);
+        let (server_side, mut server) = tokio::io::duplex(4096);
+        let (editor_reader, editor_writer) = tokio::io::split(editor_side);
+        let (server_reader, server_writer) = tokio::io::split(server_side);
+        let bridge = tokio::spawn(bridge(
+            editor_reader,
+            editor_writer,
+            server_reader,
+            server_writer,
+        ));
+
+        // Extra headers are dropped and split writes are reassembled
+        editor
+            .write_all(b"Content-Type: application/vscode-jsonrpc\r\ncontent-length: 2")
+            .await
+            .unwrap();
+        editor.write_all(b"\r\n\r\n{}").await.unwrap();
+        let mut server_reader = tokio::io::BufReader::new(&mut server);
+        assert_eq!(
+            read_message(&mut server_reader).await.unwrap().unwrap(),
+            b"{}"
+        );
+
+        write_message(&mut server, b"{\"id\":1}").await.unwrap();
+        let mut editor_reader = tokio::io::BufReader::new(&mut editor);
+        assert_eq!(
+            read_message(&mut editor_reader).await.unwrap().unwrap(),
+            b"{\"id\":1}"
+        );
+
+        editor
+            .write_all(b"Content-Length: x\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(bridge.await.unwrap().is_err());
+    }
+}
@@ -1,29 +1,18 @@
-mod agentx;
-mod config;
-mod executor;
-mod handlers;
-mod mcp;
-mod router;
-mod routes;
-mod session;
-
 use anyhow::{Result, anyhow};
+use arpc::{ClientCommand, ClientConfig, TunnelClient};
 use clap::Parser;
-use common::http;
-use common::{Command, read_command, write_command};
-use config::ClientConfig;
-use handlers::HandlerState;
-use router::{HandlerContext, Router};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info, warn};
-use tracing_subscriber::{Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use common::crash::CrashReporter;
+use tracing::{debug, error, info};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::{Layer, layer::SubscriberExt, reload, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = ClientConfig::parse();
+    #[cfg(feature = "keyring")]
+    if let Some(command @ (ClientCommand::Login { .. } | ClientCommand::Logout)) = &config.command {
+        return arpc::credentials::run(command);
+    }
 
     // Setup dual logging: all levels -> file, INFO -> terminal
     let log_dir = dirs::data_local_dir()
@@ -34,6 +23,10 @@ async fn main() -> Result<()> {
     let (non_blocking, _guard) =
         tracing_appender::non_blocking(tracing_appender::rolling::daily(log_dir, "arpc.log"));
 
+    // The terminal filter can be changed later by a config update or SIGUSR1
+    let (terminal_filter, terminal_level) =
+        reload::Layer::new(Targets::new().with_default(LevelFilter::INFO));
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -43,7 +36,7 @@ async fn main() -> Result<()> {
         .with(
             tracing_subscriber::fmt::layer()
                 .with_target(false)
-                .with_filter(tracing_subscriber::filter::LevelFilter::INFO),
+                .with_filter(terminal_filter),
         )
         .init();
 
@@ -53,6 +46,19 @@ async fn main() -> Result<()> {
         return Err(anyhow!("Invalid configuration: {}", e));
     }
 
+    // `arpc connect` only dials the server, it doesn't register
+    if let Some(ClientCommand::Connect {
+        service,
+        listen,
+        direct,
+    }) = &config.command
+    {
+        return tokio::select! {
+            result = arpc::peer::connect(&config, service, listen, *direct) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        };
+    }
+
     info!(
         "✅ Starting arpc with client_id（Token）: {}",
         config.client_id
@@ -64,233 +70,92 @@ async fn main() -> Result<()> {
     } else {
         info!("Local service: {}", config.local_service_addr());
     }
-
-    // Start MCP server if enabled
-    if config.enable_mcp {
-        let mcp_port = config.mcp_port;
-        tokio::spawn(async move {
-            if let Err(e) = mcp::start_mcp_server(mcp_port).await {
-                error!("MCP server error: {}", e);
-            }
-        });
-        info!("MCP server enabled on port {}", config.mcp_port);
-    }
-
-    // Create shared state
-    let state = HandlerState::new(config.clone());
-
-    // Extract Arc-wrapped config to avoid repeated cloning in the loop
-    let config_arc = state.config.clone();
-
-    // Build router and wrap in Arc to avoid repeated cloning
-    let router = Arc::new(routes::build_router(state));
-
-    loop {
-        match run_client_loop(config_arc.clone(), router.clone()).await {
-            Ok(_) => break,
-            Err(e) if config_arc.auto_reconnect => {
-                error!(
-                    "Connection error: {}. Reconnecting in {}s...",
-                    e, config_arc.reconnect_interval
-                );
-                tokio::time::sleep(tokio::time::Duration::from_secs(
-                    config_arc.reconnect_interval,
-                ))
-                .await;
-            }
-            Err(e) => return Err(e),
-        }
-    }
-
-    Ok(())
-}
-
-async fn run_client_loop(config: Arc<ClientConfig>, router: Arc<Router>) -> Result<()> {
-    let control_stream = TcpStream::connect(config.control_addr()).await?;
-    info!("Connected to control port.");
-
-    let (mut reader, mut writer) = tokio::io::split(control_stream);
-
-    let register_cmd = Command::Register {
-        client_id: config.client_id.clone(),
-    };
-    write_command(&mut writer, &register_cmd).await?;
-    debug!("Sent registration command");
-
-    match tokio::time::timeout(
-        tokio::time::Duration::from_secs(10),
-        read_command(&mut reader),
-    )
-    .await?
-    {
-        Ok(Command::RegisterResult { success, error }) if success => {
-            info!("Successfully registered with the server.");
-        }
-        Ok(Command::RegisterResult { error, .. }) => {
-            return Err(anyhow!(
-                "Registration failed: {}",
-                error.unwrap_or_default()
-            ));
-        }
-        Ok(cmd) => return Err(anyhow!("Unexpected command: {:?}", cmd)),
-        Err(e) => return Err(e),
-    }
-
-    if config.server_addr != "proxy.agentx.plus" {
-        info!(
-            "🌐 Public URL: {}:17003?token={}",
-            config.server_addr, config.client_id
-        );
-    } else {
+    if let Some(ClientCommand::Tunnel { name, .. }) = &config.command {
         info!(
-            "🌐 Public URL: https://console.agentx.plus/?token={}",
-            config.client_id
+            "Offering {} as {}/{}",
+            config.resolve_target(name).unwrap_or_default(),
+            config.client_id,
+            name
         );
     }
 
-    loop {
-        tokio::select! {
-            _ = tokio::signal::ctrl_c() => {
-                info!("Received Ctrl+C signal. Shutting down gracefully...");
-                return Ok(());
-            }
-            result = read_command(&mut reader) => {
-                match result {
-                    Ok(Command::RequestNewProxyConn { proxy_conn_id }) => {
-                        debug!("Received request for new proxy connection: {}", proxy_conn_id);
-                        let config_ref = Arc::clone(&config);
-                        let router_ref = Arc::clone(&router);
-                        tokio::spawn(async move {
-                            if let Err(e) = create_proxy_connection(config_ref, router_ref, proxy_conn_id).await {
-                                error!("Failed to create proxy connection: {}", e);
-                            }
-                        });
-                    }
-                    Ok(cmd) => warn!("Received unexpected command: {:?}", cmd),
-                    Err(ref e) if e.downcast_ref::<io::Error>().is_some_and(|io_err| io_err.kind() == io::ErrorKind::UnexpectedEof) => {
-                        return Err(anyhow!("Control connection closed by server"));
-                    }
-                    Err(e) => return Err(anyhow!("Error reading from control connection: {}", e)),
-                }
-            }
+    #[cfg(unix)]
+    tokio::spawn(toggle_debug_on_sigusr1(terminal_level.clone()));
+
+    let client = TunnelClient::builder(config)
+        .log_level_handle(Box::new(move |level| {
+            terminal_level
+                .modify(|filter| *filter = level)
+                .map_err(|e| e.to_string())
+        }))
+        .build()?;
+    install_crash_reporter(&client);
+
+    let shutdown = client.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Received Ctrl+C signal. Shutting down gracefully...");
+            shutdown.shutdown();
         }
-    }
-}
+    });
 
-async fn create_proxy_connection(
-    config: Arc<ClientConfig>,
-    router: Arc<Router>,
-    proxy_conn_id: String,
-) -> Result<()> {
-    let command_mode_enabled = config.command_mode;
-    let mut proxy_stream = TcpStream::connect(config.proxy_addr()).await?;
-    debug!("('{}') Connected to proxy port.", proxy_conn_id);
+    client.run().await
+}
 
-    let notify_cmd = Command::NewProxyConn {
-        proxy_conn_id: proxy_conn_id.clone(),
-        client_id: config.client_id.clone(),
+/// Write a crash report with the client's state to the `arpc/crashes` data
+/// directory on every panic, and POST it to `crash_report_url` if set.
+fn install_crash_reporter(client: &TunnelClient) {
+    let dir = dirs::data_local_dir()
+        .unwrap_or_else(|| std::path::PathBuf::from("."))
+        .join("arpc/crashes");
+    let state = client.clone();
+    let reporter = CrashReporter::new("arpc", env!("CARGO_PKG_VERSION"), dir)
+        .with_config(client.config().crash_summary())
+        .with_state(move || state.crash_state());
+    #[cfg(feature = "events")]
+    let reporter = match client.config().crash_report_url.clone() {
+        None => reporter,
+        Some(url) => reporter.with_upload(move |body| {
+            reqwest::blocking::Client::new()
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .timeout(std::time::Duration::from_secs(10))
+                .body(body)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }),
     };
-    write_command(&mut proxy_stream, &notify_cmd).await?;
-    debug!(
-        "('{}') Sent new proxy connection notification.",
-        proxy_conn_id
-    );
-
-    if command_mode_enabled {
-        handle_command_mode_connection(proxy_stream, router, proxy_conn_id).await
-    } else {
-        handle_tcp_proxy_connection(config, proxy_stream, proxy_conn_id).await
-    }
+    reporter.install();
 }
 
-async fn handle_command_mode_connection(
-    mut proxy_stream: TcpStream,
-    router: Arc<Router>,
-    proxy_conn_id: String,
-) -> Result<()> {
-    debug!(
-        "('{}') Running in command mode (HTTP routing)",
-        proxy_conn_id
-    );
-
-    match http::HttpRequest::parse(&mut proxy_stream, &proxy_conn_id).await {
-        Ok(request) => {
-            // Handle CORS preflight early to avoid empty responses
-            if request.method == http::HttpMethod::OPTIONS {
-                let stream = &mut proxy_stream;
-                let _ = http::HttpResponse::new(204)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header(
-                        "Access-Control-Allow-Methods",
-                        "GET, POST, PUT, DELETE, PATCH, OPTIONS",
-                    )
-                    .header(
-                        "Access-Control-Allow-Headers",
-                        "Content-Type, Authorization",
-                    )
-                    .header("Access-Control-Max-Age", "86400")
-                    .body(Vec::new())
-                    .send(stream)
-                    .await;
-                info!(
-                    "('{}') Responded to CORS preflight (OPTIONS)",
-                    proxy_conn_id
-                );
-                return Ok(());
-            }
+/// Switch debug logging of arpc on the terminal on or off whenever the
+/// process receives SIGUSR1, falling back to INFO when switched off.
+#[cfg(unix)]
+async fn toggle_debug_on_sigusr1<S: 'static>(terminal_level: reload::Handle<Targets, S>) {
+    use tokio::signal::unix::{SignalKind, signal};
 
-            let ctx = HandlerContext {
-                request,
-                stream: proxy_stream,
-                proxy_conn_id: proxy_conn_id.clone(),
-                path_params: HashMap::new(),
-            };
-
-            match router.handle(ctx).await {
-                Ok(_response) => {
-                    info!("('{}') Request handled successfully", proxy_conn_id);
-                }
-                Err(e) => {
-                    error!("('{}') Handler error: {}", proxy_conn_id, e);
-                }
-            }
-        }
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
         Err(e) => {
-            error!("('{}') Failed to parse HTTP request: {}", proxy_conn_id, e);
+            error!("Failed to listen for SIGUSR1: {}", e);
+            return;
         }
-    }
-
-    Ok(())
-}
-
-async fn handle_tcp_proxy_connection(
-    config: Arc<ClientConfig>,
-    proxy_stream: TcpStream,
-    proxy_conn_id: String,
-) -> Result<()> {
-    // Clone the config from Arc for HandlerState::new
-    let state = HandlerState::new((*config).clone());
-    let ctx = HandlerContext {
-        request: http::HttpRequest {
-            method: http::HttpMethod::GET,
-            path: "/".to_string(),
-            query_params: HashMap::new(),
-            headers: HashMap::new(),
-            body: Vec::new(),
-        },
-        stream: proxy_stream,
-        proxy_conn_id: proxy_conn_id.clone(),
-        path_params: HashMap::new(),
     };
-
-    match handlers::proxy::handle_proxy(ctx, state).await {
-        Ok(_) => {
-            info!("('{}') TCP proxy completed successfully", proxy_conn_id);
+    let mut debugging = false;
+    while signals.recv().await.is_some() {
+        debugging = !debugging;
+        let mut filter = Targets::new().with_default(LevelFilter::INFO);
+        if debugging {
+            filter = filter.with_target("arpc", LevelFilter::DEBUG);
         }
-        Err(e) => {
-            error!("('{}') TCP proxy error: {}", proxy_conn_id, e);
+        match terminal_level.modify(|current| *current = filter) {
+            Ok(()) => info!(
+                "Debug logging {}",
+                if debugging { "enabled" } else { "disabled" }
+            ),
+            Err(e) => error!("Failed to change log filter: {}", e),
         }
     }
-
-    Ok(())
 }
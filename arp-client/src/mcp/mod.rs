@@ -1,5 +1,9 @@
 pub mod permissions;
-use permissions::PermissionManager;
+pub mod servers;
+use crate::approvers::Approvers;
+use crate::session::SessionManager;
+use permissions::{PermissionManager, SharedExpiryPolicy};
+use std::sync::Arc;
 
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
@@ -10,10 +14,23 @@ use rmcp::transport::streamable_http_server::{
     StreamableHttpService, session::local::LocalSessionManager,
 };
 
-/// Start the MCP server on the specified port
-pub async fn start_mcp_server(port: u16) -> anyhow::Result<()> {
+/// Start the MCP server on the specified port. Permission prompts for
+/// sessions known to `sessions` are published on their output streams and
+/// expire according to `expiry`; only `approvers` count towards the
+/// approvals destructive tools need.
+pub async fn start_mcp_server(
+    port: u16,
+    sessions: SessionManager,
+    expiry: SharedExpiryPolicy,
+    approvers: Arc<Approvers>,
+) -> anyhow::Result<()> {
     let service = TowerToHyperService::new(StreamableHttpService::new(
-        || Ok(PermissionManager::new(None, None)),
+        move || {
+            Ok(PermissionManager::new(None, None)
+                .with_sessions(sessions.clone())
+                .with_expiry_policy(expiry.clone())
+                .with_approvers(approvers.clone()))
+        },
         LocalSessionManager::default().into(),
         Default::default(),
     ));
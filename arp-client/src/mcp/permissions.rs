@@ -1,3 +1,6 @@
+use crate::approvers::Approvers;
+use crate::session::{CommandSession, PermissionRecord, SessionManager};
+use common::ConfigSettings;
 use http;
 use rmcp::{
     ErrorData as McpError, RoleServer, ServerHandler,
@@ -7,6 +10,8 @@ use rmcp::{
     service::RequestContext,
     tool, tool_handler, tool_router,
 };
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::Duration;
 
 // ==================== Constants ====================
@@ -14,7 +19,7 @@ use std::time::Duration;
 /// Default polling interval for permission status checks
 const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-/// Maximum timeout for permission requests (1 hour)
+/// Default timeout for permission requests (1 hour)
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 
 /// Default ARP server port
@@ -23,8 +28,99 @@ const DEFAULT_ARP_PORT: &str = "17004";
 /// Default streaming ID when none is provided
 const DEFAULT_STREAMING_ID: &str = "unknown";
 
+/// Header an MCP client can use to name the session it runs in
+const STREAMING_ID_HEADER: &str = "x-arp-streaming-id";
+
+/// Message returned to the agent when a request is denied without a reason
+const DEFAULT_DENY_MESSAGE: &str = "The user doesn't want to proceed with this tool use. The tool use was rejected. STOP what you are doing and wait for the user to tell you how to proceed.";
+
+/// Tools whose effects can't be undone and may require several approvers
+const DESTRUCTIVE_TOOLS: &[&str] = &["Bash", "Write", "Edit", "MultiEdit", "NotebookEdit"];
+
 // ==================== Permission Management Structures ====================
 
+/// What happens to a permission request nobody answered in time
+#[derive(Debug, Clone, Default)]
+pub struct ExpiryPolicy {
+    /// Timeout for tools without a specific entry
+    default_timeout: Option<Duration>,
+    /// Per-tool timeouts
+    tool_timeouts: HashMap<String, Duration>,
+    /// Tools approved instead of denied when their request expires
+    auto_approve: HashSet<String>,
+}
+
+impl ExpiryPolicy {
+    /// Read the policy from the environment:
+    /// - `ARP_PERMISSION_TIMEOUT_SECS`: default timeout (default: 3600)
+    /// - `ARP_PERMISSION_TOOL_TIMEOUTS`: per-tool timeouts, e.g. `Bash=60,Write=120`
+    /// - `ARP_PERMISSION_AUTO_APPROVE`: tools approved on expiry, e.g. `Read,Glob`
+    pub fn from_env() -> Self {
+        let env = |key: &str| std::env::var(key).unwrap_or_default();
+        Self::parse(
+            &env("ARP_PERMISSION_TIMEOUT_SECS"),
+            &env("ARP_PERMISSION_TOOL_TIMEOUTS"),
+            &env("ARP_PERMISSION_AUTO_APPROVE"),
+        )
+    }
+
+    fn parse(default_secs: &str, tool_timeouts: &str, auto_approve: &str) -> Self {
+        let tool_timeouts = tool_timeouts
+            .split(',')
+            .filter_map(|entry| {
+                let (tool, secs) = entry.split_once('=')?;
+                let secs = secs.trim().parse().ok()?;
+                Some((tool.trim().to_string(), Duration::from_secs(secs)))
+            })
+            .collect();
+        let auto_approve = auto_approve
+            .split(',')
+            .map(str::trim)
+            .filter(|tool| !tool.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Self {
+            default_timeout: default_secs.trim().parse().ok().map(Duration::from_secs),
+            tool_timeouts,
+            auto_approve,
+        }
+    }
+
+    fn timeout_for(&self, tool_name: &str) -> Duration {
+        self.tool_timeouts
+            .get(tool_name)
+            .copied()
+            .or(self.default_timeout)
+            .unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Apply the permission settings of a pushed config update and return
+    /// the names of those that were set
+    pub fn update(&mut self, settings: &ConfigSettings) -> Vec<&'static str> {
+        let mut applied = Vec::new();
+        if let Some(secs) = settings.permission_timeout_secs {
+            self.default_timeout = Some(Duration::from_secs(secs));
+            applied.push("permission_timeout_secs");
+        }
+        if let Some(timeouts) = &settings.permission_tool_timeouts {
+            self.tool_timeouts = timeouts
+                .iter()
+                .map(|(tool, secs)| (tool.clone(), Duration::from_secs(*secs)))
+                .collect();
+            applied.push("permission_tool_timeouts");
+        }
+        if let Some(tools) = &settings.permission_auto_approve {
+            self.auto_approve = tools.iter().cloned().collect();
+            applied.push("permission_auto_approve");
+        }
+        applied
+    }
+}
+
+/// Expiry policy shared by every MCP session and updated by config pushes
+pub type SharedExpiryPolicy = Arc<RwLock<ExpiryPolicy>>;
+
 /// Arguments for the approval_prompt tool
 #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
 pub struct ApprovalPromptArgs {
@@ -41,17 +137,18 @@ struct PermissionNotificationRequest {
     tool_name: String,
     tool_input: serde_json::Value,
     streaming_id: String,
+    /// Number of distinct approvals needed before the request counts as approved
+    required_approvals: u32,
 }
 
 /// Response from permission notification
 #[derive(Debug, serde::Deserialize)]
 struct PermissionNotificationResponse {
-    success: bool,
     id: String,
 }
 
 /// Permission status
-#[derive(Debug, serde::Deserialize, Clone)]
+#[derive(Debug, serde::Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum PermissionStatus {
     Pending,
@@ -69,6 +166,20 @@ struct Permission {
     modified_input: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     deny_reason: Option<String>,
+    /// Individual approve/deny decisions, in the order they were made
+    #[serde(default)]
+    decisions: Vec<PermissionDecision>,
+}
+
+/// A single approve/deny decision recorded by the ARP server
+#[derive(Debug, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PermissionDecision {
+    /// Approver that made the decision, as the server reports it
+    decided_by: Option<String>,
+    status: PermissionStatus,
+    #[serde(default)]
+    decided_at: Option<String>,
 }
 
 /// Response from permissions list endpoint
@@ -102,12 +213,29 @@ struct ApprovalResponse {
 /// - `ARP_SERVER_URL`: Base URL of the ARP server (default: http://localhost:17004)
 /// - `ARP_SERVER_PORT`: Port of the ARP server (used if full URL not provided)
 /// - `ARP_STREAMING_ID`: Unique identifier for the streaming session (default: "unknown")
+/// - `ARP_DESTRUCTIVE_APPROVALS`: Approvals required for destructive tools such as
+///   `Bash` or `Write` (default: 1), each from a different approver given
+///   with [`PermissionManager::with_approvers`]
+/// - `ARP_PERMISSION_TIMEOUT_SECS`, `ARP_PERMISSION_TOOL_TIMEOUTS`,
+///   `ARP_PERMISSION_AUTO_APPROVE`: expiry policy, see [`ExpiryPolicy::from_env`]
+///
+/// When the streaming ID of a request (the `streamingId` query parameter or
+/// `X-ARP-Streaming-Id` header of the MCP endpoint) names a local session, the
+/// prompt is published inline on that session's output stream instead.
 #[derive(Clone)]
 pub struct PermissionManager {
     /// Base URL of the ARP server for API communication
     arp_server_url: String,
     /// Unique identifier for the current streaming session
     arp_streaming_id: String,
+    /// Approvals required before a destructive tool may run
+    destructive_approvals: u32,
+    /// The approvers whose approvals count towards `destructive_approvals`
+    approvers: Arc<Approvers>,
+    /// Expiry and auto-deny/approve behaviour for unanswered requests
+    expiry: SharedExpiryPolicy,
+    /// Local sessions that can receive permission prompts inline
+    sessions: Option<SessionManager>,
     /// HTTP client with optimized timeout settings for API communication
     http_client: reqwest::Client,
     /// Tool router for handling MCP tool registration
@@ -144,9 +272,19 @@ impl PermissionManager {
             .or_else(|| std::env::var("ARP_STREAMING_ID").ok())
             .unwrap_or_else(|| DEFAULT_STREAMING_ID.to_string());
 
+        let destructive_approvals = std::env::var("ARP_DESTRUCTIVE_APPROVALS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1)
+            .max(1);
+
         Self {
             arp_server_url: server_url,
             arp_streaming_id: streaming_id,
+            destructive_approvals,
+            approvers: Arc::new(Approvers::default()),
+            expiry: Arc::new(RwLock::new(ExpiryPolicy::from_env())),
+            sessions: None,
             http_client: reqwest::Client::builder()
                 .timeout(Duration::from_secs(30))
                 .connect_timeout(Duration::from_secs(10))
@@ -156,6 +294,69 @@ impl PermissionManager {
         }
     }
 
+    /// Publish prompts for local sessions on their output streams
+    pub fn with_sessions(mut self, sessions: SessionManager) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Use a policy that can be changed while the server runs
+    pub fn with_expiry_policy(mut self, expiry: SharedExpiryPolicy) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Only count approvals attributed to one of `approvers`
+    pub fn with_approvers(mut self, approvers: Arc<Approvers>) -> Self {
+        self.approvers = approvers;
+        self
+    }
+
+    fn expiry(&self) -> RwLockReadGuard<'_, ExpiryPolicy> {
+        self.expiry.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Streaming ID named by the MCP HTTP request, falling back to the configured one
+    fn request_streaming_id(&self, extensions: &Extensions) -> String {
+        let Some(parts) = extensions.get::<http::request::Parts>() else {
+            return self.arp_streaming_id.clone();
+        };
+
+        parts
+            .uri
+            .query()
+            .and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| match pair.split_once('=') {
+                        Some(("streamingId", value)) if !value.is_empty() => {
+                            Some(value.to_string())
+                        }
+                        _ => None,
+                    })
+            })
+            .or_else(|| {
+                parts
+                    .headers
+                    .get(STREAMING_ID_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| self.arp_streaming_id.clone())
+    }
+
+    /// Create a standardized allow response
+    fn create_allow_response(input: serde_json::Value) -> CallToolResult {
+        let response = ApprovalResponse {
+            behavior: "allow".to_string(),
+            updated_input: Some(input),
+            message: None,
+        };
+        CallToolResult::success(vec![Content::text(
+            serde_json::to_string(&response).unwrap(),
+        )])
+    }
+
     /// Create a standardized error response
     fn create_error_response(message: String) -> CallToolResult {
         let deny_response = ApprovalResponse {
@@ -168,11 +369,46 @@ impl PermissionManager {
         )])
     }
 
-    /// Create a timeout response
-    fn create_timeout_response() -> CallToolResult {
-        Self::create_error_response(
-            "Permission request timed out after 1 hour - user did not respond".to_string(),
-        )
+    /// Resolve an expired request according to the expiry policy
+    fn create_timeout_response(
+        &self,
+        tool_name: &str,
+        timeout: Duration,
+        original_input: &serde_json::Value,
+    ) -> CallToolResult {
+        if self.expiry().auto_approve.contains(tool_name) {
+            return Self::create_allow_response(original_input.clone());
+        }
+
+        Self::create_error_response(format!(
+            "Permission request timed out after {}s - user did not respond",
+            timeout.as_secs()
+        ))
+    }
+
+    /// Tell the ARP server a request expired so it can notify subscribed UIs.
+    /// Best effort: the decision has already been made locally.
+    async fn report_expiry(&self, permission_id: &str, auto_approved: bool) {
+        let url = format!(
+            "{}/api/permissions/{}/expire",
+            self.arp_server_url, permission_id
+        );
+        let body = serde_json::json!({
+            "streamingId": self.arp_streaming_id,
+            "resolution": if auto_approved { "approved" } else { "denied" },
+        });
+        if let Err(e) = self.http_client.post(&url).json(&body).send().await {
+            tracing::debug!("Failed to report permission expiry: {}", e);
+        }
+    }
+
+    /// Number of approvals a tool call needs (N-of-M for destructive tools)
+    fn required_approvals(&self, tool_name: &str) -> u32 {
+        if DESTRUCTIVE_TOOLS.contains(&tool_name) {
+            self.destructive_approvals
+        } else {
+            1
+        }
     }
 
     /// Send notification to ARP server
@@ -186,6 +422,7 @@ impl PermissionManager {
             tool_name: tool_name.to_string(),
             tool_input: input.clone(),
             streaming_id: self.arp_streaming_id.clone(),
+            required_approvals: self.required_approvals(tool_name),
         };
 
         let response = self
@@ -220,16 +457,20 @@ impl PermissionManager {
         original_input: &serde_json::Value,
     ) -> Result<CallToolResult, McpError> {
         let start_time = std::time::Instant::now();
+        let timeout = self.expiry().timeout_for(tool_name);
 
         loop {
             // Check timeout
-            if start_time.elapsed() > DEFAULT_TIMEOUT {
+            if start_time.elapsed() > timeout {
+                let auto_approved = self.expiry().auto_approve.contains(tool_name);
                 tracing::warn!(
-                    "Permission request timed out: tool_name={}, id={}",
+                    "Permission request timed out: tool_name={}, id={}, auto_approved={}",
                     tool_name,
-                    permission_id
+                    permission_id,
+                    auto_approved
                 );
-                return Ok(Self::create_timeout_response());
+                self.report_expiry(permission_id, auto_approved).await;
+                return Ok(self.create_timeout_response(tool_name, timeout, original_input));
             }
 
             // Poll for pending permissions first
@@ -282,6 +523,18 @@ impl PermissionManager {
             .find(|p| p.id == permission_id))
     }
 
+    /// Number of distinct configured approvers among the approvals in
+    /// `decisions`; names nobody holds a token for don't count
+    fn approvers(&self, decisions: &[PermissionDecision]) -> u32 {
+        decisions
+            .iter()
+            .filter(|decision| decision.status == PermissionStatus::Approved)
+            .filter_map(|decision| decision.decided_by.as_deref())
+            .filter(|name| self.approvers.contains(name))
+            .collect::<HashSet<_>>()
+            .len() as u32
+    }
+
     /// Handle permission result and create appropriate response
     fn handle_permission_result(
         &self,
@@ -289,6 +542,63 @@ impl PermissionManager {
         tool_name: &str,
         original_input: &serde_json::Value,
     ) -> CallToolResult {
+        // Keep an audit trail of who decided, locally as well as on the server
+        for decision in &permission.decisions {
+            tracing::info!(
+                "Permission decision: tool_name={}, id={}, status={:?}, decided_by={}, decided_at={}",
+                tool_name,
+                permission.id,
+                decision.status,
+                decision.decided_by.as_deref().unwrap_or("unknown"),
+                decision.decided_at.as_deref().unwrap_or("unknown")
+            );
+            if let Some(sessions) = &self.sessions
+                && decision.status != PermissionStatus::Pending
+            {
+                let mut record = PermissionRecord::now(
+                    &permission.id,
+                    tool_name,
+                    decision.status == PermissionStatus::Approved,
+                    decision.decided_by.clone(),
+                );
+                if let Some(decided_at) = &decision.decided_at {
+                    record.decided_at = decided_at.clone();
+                }
+                sessions
+                    .permission_history()
+                    .record(&self.arp_streaming_id, record);
+            }
+        }
+
+        // Don't take the server's word for a destructive tool: it must show
+        // the approvers, and none of them may have denied
+        let required = self.required_approvals(tool_name);
+        if permission.status == PermissionStatus::Approved && required > 1 {
+            let approvers = self.approvers(&permission.decisions);
+            let denied = permission
+                .decisions
+                .iter()
+                .any(|decision| decision.status == PermissionStatus::Denied);
+            if denied || approvers < required {
+                tracing::warn!(
+                    "Permission approval rejected: tool_name={}, id={}, approvers={}, required={}, denied={}",
+                    tool_name,
+                    permission.id,
+                    approvers,
+                    required,
+                    denied
+                );
+                return Self::create_error_response(if denied {
+                    DEFAULT_DENY_MESSAGE.to_string()
+                } else {
+                    format!(
+                        "Permission was approved by {} of the {} distinct approvers {} requires",
+                        approvers, required, tool_name
+                    )
+                });
+            }
+        }
+
         match permission.status {
             PermissionStatus::Approved => {
                 tracing::debug!(
@@ -296,18 +606,11 @@ impl PermissionManager {
                     tool_name,
                     permission.id
                 );
-                let response = ApprovalResponse {
-                    behavior: "allow".to_string(),
-                    updated_input: Some(
-                        permission
-                            .modified_input
-                            .unwrap_or_else(|| original_input.clone()),
-                    ),
-                    message: None,
-                };
-                CallToolResult::success(vec![Content::text(
-                    serde_json::to_string(&response).unwrap(),
-                )])
+                Self::create_allow_response(
+                    permission
+                        .modified_input
+                        .unwrap_or_else(|| original_input.clone()),
+                )
             }
             PermissionStatus::Denied => {
                 tracing::debug!(
@@ -315,9 +618,9 @@ impl PermissionManager {
                     tool_name,
                     permission.id
                 );
-                let msg = permission.deny_reason.unwrap_or_else(||
-                    "The user doesn't want to proceed with this tool use. The tool use was rejected. STOP what you are doing and wait for the user to tell you how to proceed.".to_string()
-                );
+                let msg = permission
+                    .deny_reason
+                    .unwrap_or_else(|| DEFAULT_DENY_MESSAGE.to_string());
                 Self::create_error_response(msg)
             }
             PermissionStatus::Pending => {
@@ -326,18 +629,85 @@ impl PermissionManager {
         }
     }
 
+    /// Ask for a decision on the session's own output stream and wait for it
+    async fn prompt_in_session(
+        &self,
+        session: Arc<CommandSession>,
+        tool_name: &str,
+        input: &serde_json::Value,
+    ) -> CallToolResult {
+        let (permission_id, decision) = session
+            .request_permission(tool_name, input, self.required_approvals(tool_name))
+            .await;
+        tracing::debug!(
+            "Inline permission request published: id={}, session={}",
+            permission_id,
+            session.session_id
+        );
+
+        let timeout = self.expiry().timeout_for(tool_name);
+        match tokio::time::timeout(timeout, decision).await {
+            Ok(Ok(decision)) => {
+                tracing::info!(
+                    "Permission decision: tool_name={}, id={}, approved={}, decided_by={}",
+                    tool_name,
+                    permission_id,
+                    decision.approved,
+                    decision.decided_by.as_deref().unwrap_or("unknown")
+                );
+                if decision.approved {
+                    Self::create_allow_response(
+                        decision.updated_input.unwrap_or_else(|| input.clone()),
+                    )
+                } else {
+                    Self::create_error_response(
+                        decision
+                            .reason
+                            .unwrap_or_else(|| DEFAULT_DENY_MESSAGE.to_string()),
+                    )
+                }
+            }
+            Ok(Err(_)) => Self::create_error_response(
+                "Permission request was cancelled before a decision was made".to_string(),
+            ),
+            Err(_) => {
+                let auto_approved = self.expiry().auto_approve.contains(tool_name);
+                tracing::warn!(
+                    "Permission request timed out: tool_name={}, id={}, auto_approved={}",
+                    tool_name,
+                    permission_id,
+                    auto_approved
+                );
+                session
+                    .expire_permission(&permission_id, auto_approved)
+                    .await;
+                self.create_timeout_response(tool_name, timeout, input)
+            }
+        }
+    }
+
     /// Request approval for tool usage from ARP
     #[tool(description = "Request approval for tool usage from ARP")]
     async fn approval_prompt(
         &self,
         Parameters(args): Parameters<ApprovalPromptArgs>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
+        let streaming_id = self.request_streaming_id(&extensions);
         tracing::debug!(
             "MCP Permission request received: tool_name={}, streaming_id={}",
             args.tool_name,
-            self.arp_streaming_id
+            streaming_id
         );
 
+        if let Some(sessions) = &self.sessions
+            && let Some(session) = sessions.get_session(&streaming_id).await
+        {
+            return Ok(self
+                .prompt_in_session(session, &args.tool_name, &args.input)
+                .await);
+        }
+
         // Send permission notification to ARP server
         let permission_id = match self.send_notification(&args.tool_name, &args.input).await {
             Ok(id) => id,
@@ -395,3 +765,186 @@ impl ServerHandler for PermissionManager {
         Ok(self.get_info())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::ExecutorKind;
+    use crate::session::{PermissionDecision as InlineDecision, PermissionOutcome};
+
+    /// The JSON a permission response tells Claude
+    fn behavior(result: &CallToolResult) -> serde_json::Value {
+        let result = serde_json::to_value(result).unwrap();
+        serde_json::from_str(result["content"][0]["text"].as_str().unwrap()).unwrap()
+    }
+
+    fn manager(destructive_approvals: u32) -> PermissionManager {
+        let mut manager = PermissionManager::new(
+            Some("http://127.0.0.1:1".to_string()),
+            Some("s1".to_string()),
+        )
+        .with_approvers(Arc::new(Approvers::from_tokens(&[
+            ("alice", "token-a"),
+            ("bob", "token-b"),
+            ("carol", "token-c"),
+        ])));
+        manager.destructive_approvals = destructive_approvals;
+        manager
+    }
+
+    fn permission(status: PermissionStatus, decisions: &[(&str, PermissionStatus)]) -> Permission {
+        Permission {
+            id: "p1".to_string(),
+            status,
+            modified_input: None,
+            deny_reason: None,
+            decisions: decisions
+                .iter()
+                .map(|(by, status)| PermissionDecision {
+                    decided_by: Some(by.to_string()),
+                    status: status.clone(),
+                    decided_at: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn destructive_tools_require_the_configured_approvals() {
+        let manager = manager(3);
+        assert_eq!(manager.required_approvals("Bash"), 3);
+        assert_eq!(manager.required_approvals("Write"), 3);
+        assert_eq!(manager.required_approvals("Read"), 1);
+        assert_eq!(
+            PermissionManager::new(None, None).required_approvals("Read"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn approvals_with_too_few_approvers_are_rejected() {
+        use PermissionStatus::{Approved, Denied};
+        let sessions = SessionManager::new();
+        let manager = manager(2).with_sessions(sessions.clone());
+        let input = serde_json::json!({"command": "rm -rf build"});
+        let decide = |permission| {
+            behavior(&manager.handle_permission_result(permission, "Bash", &input))["behavior"]
+                .clone()
+        };
+
+        assert_eq!(decide(permission(Approved, &[])), "deny");
+        assert_eq!(
+            decide(permission(
+                Approved,
+                &[("alice", Approved), ("alice", Approved)]
+            )),
+            "deny"
+        );
+        assert_eq!(
+            decide(permission(
+                Approved,
+                &[("alice", Approved), ("bob", Approved), ("carol", Denied)]
+            )),
+            "deny"
+        );
+        // Names the server reports that no approver holds a token for
+        assert_eq!(
+            decide(permission(
+                Approved,
+                &[("alice", Approved), ("mallory", Approved)]
+            )),
+            "deny"
+        );
+        assert_eq!(
+            decide(permission(
+                Approved,
+                &[("alice", Approved), ("bob", Approved)]
+            )),
+            "allow"
+        );
+        // Tools that need one approval keep trusting the server
+        let read = manager.handle_permission_result(permission(Approved, &[]), "Read", &input);
+        assert_eq!(behavior(&read)["behavior"], "allow");
+
+        let history = sessions.permission_history().of("s1");
+        assert_eq!(history.len(), 9);
+        assert_eq!(history[8].decided_by.as_deref(), Some("bob"));
+        assert_eq!(history[4].decision, "denied");
+    }
+
+    #[tokio::test]
+    async fn inline_prompts_for_destructive_tools_need_every_approver() {
+        let sessions = SessionManager::new();
+        let session = sessions
+            .create_session_with_id_and_executor("s1".to_string(), ExecutorKind::Claude)
+            .await;
+        let manager = manager(2).with_sessions(sessions);
+        let input = serde_json::json!({"command": "rm -rf build"});
+        let prompt = tokio::spawn({
+            let (manager, session, input) = (manager.clone(), session.clone(), input.clone());
+            async move { manager.prompt_in_session(session, "Bash", &input).await }
+        });
+
+        let permission_id = loop {
+            if let Some(id) = session.pending_permissions.lock().await.keys().next() {
+                break id.clone();
+            }
+            tokio::task::yield_now().await;
+        };
+        let approve = |by: &str| InlineDecision {
+            approved: true,
+            reason: None,
+            updated_input: None,
+            decided_by: Some(by.to_string()),
+        };
+        assert!(matches!(
+            session
+                .resolve_permission(&permission_id, approve("alice"))
+                .await,
+            PermissionOutcome::Awaiting { .. }
+        ));
+        // One approval is not enough for a destructive tool
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!prompt.is_finished());
+        assert_eq!(
+            session
+                .resolve_permission(&permission_id, approve("bob"))
+                .await,
+            PermissionOutcome::Decided
+        );
+        let response = behavior(&prompt.await.unwrap());
+        assert_eq!(response["behavior"], "allow");
+        assert_eq!(response["updatedInput"], input);
+    }
+
+    #[test]
+    fn expiry_policy_parses_per_tool_timeouts_and_allowlist() {
+        let policy = ExpiryPolicy::parse("300", "Bash=60, Write=120,bad", "Read, Glob,");
+
+        assert_eq!(policy.timeout_for("Bash"), Duration::from_secs(60));
+        assert_eq!(policy.timeout_for("Write"), Duration::from_secs(120));
+        assert_eq!(policy.timeout_for("Edit"), Duration::from_secs(300));
+        assert!(policy.auto_approve.contains("Read"));
+        assert!(policy.auto_approve.contains("Glob"));
+        assert_eq!(policy.auto_approve.len(), 2);
+
+        assert_eq!(
+            ExpiryPolicy::parse("", "", "").timeout_for("Bash"),
+            DEFAULT_TIMEOUT
+        );
+
+        let mut policy = policy;
+        let applied = policy.update(&ConfigSettings {
+            permission_tool_timeouts: Some(HashMap::from([("Edit".to_string(), 30)])),
+            permission_auto_approve: Some(Vec::new()),
+            ..ConfigSettings::default()
+        });
+        assert_eq!(
+            applied,
+            ["permission_tool_timeouts", "permission_auto_approve"]
+        );
+        assert_eq!(policy.timeout_for("Edit"), Duration::from_secs(30));
+        assert_eq!(policy.timeout_for("Bash"), Duration::from_secs(300));
+        assert!(policy.auto_approve.is_empty());
+    }
+}
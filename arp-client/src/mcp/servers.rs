@@ -0,0 +1,266 @@
+use crate::executor::ExecutorKind;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+use tracing::info;
+
+/// Timeout for probing a remote MCP server
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to reach one user-defined MCP server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum McpServerConfig {
+    /// Local server spawned by the agent and spoken to over stdio
+    Stdio {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+    /// Remote server reached over streamable HTTP (default) or SSE
+    Remote {
+        url: String,
+        #[serde(default, rename = "type")]
+        transport: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+impl McpServerConfig {
+    fn transport(&self) -> &str {
+        match self {
+            McpServerConfig::Stdio { .. } => "stdio",
+            McpServerConfig::Remote { transport, .. } => transport.as_deref().unwrap_or("http"),
+        }
+    }
+
+    /// Entry in Claude's `--mcp-config` format
+    fn claude_entry(&self) -> Value {
+        match self {
+            McpServerConfig::Stdio { command, args, env } => {
+                json!({"type": "stdio", "command": command, "args": args, "env": env})
+            }
+            McpServerConfig::Remote { url, headers, .. } => {
+                json!({"type": self.transport(), "url": url, "headers": headers})
+            }
+        }
+    }
+
+    /// Entry in Gemini's `settings.json` format
+    fn gemini_entry(&self) -> Value {
+        match self {
+            McpServerConfig::Stdio { command, args, env } => {
+                json!({"command": command, "args": args, "env": env})
+            }
+            McpServerConfig::Remote { url, headers, .. } if self.transport() == "sse" => {
+                json!({"url": url, "headers": headers})
+            }
+            McpServerConfig::Remote { url, headers, .. } => {
+                json!({"httpUrl": url, "headers": headers})
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct McpServersFile {
+    #[serde(default)]
+    mcp_servers: BTreeMap<String, McpServerConfig>,
+}
+
+/// External MCP servers attached to every Claude and Gemini run
+///
+/// Loaded from a JSON file in the usual `{"mcpServers": {...}}` layout, e.g.
+///
+/// ```json
+/// {
+///   "mcpServers": {
+///     "github": {"command": "npx", "args": ["-y", "@modelcontextprotocol/server-github"]},
+///     "docs": {"type": "http", "url": "https://docs.example.com/mcp"}
+///   }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct McpServers {
+    servers: BTreeMap<String, McpServerConfig>,
+    /// Generated Gemini system settings holding the same servers
+    gemini_settings: Option<PathBuf>,
+}
+
+impl McpServers {
+    /// Load the servers from `path`; no path means no external servers
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read MCP config {}", path.display()))?;
+        let file: McpServersFile = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid MCP config {}: {}", path.display(), e))?;
+
+        let mut servers = Self {
+            servers: file.mcp_servers,
+            gemini_settings: None,
+        };
+        if !servers.servers.is_empty() {
+            let settings =
+                std::env::temp_dir().join(format!("arpc-gemini-mcp-{}.json", std::process::id()));
+            std::fs::write(&settings, servers.gemini_config().to_string())?;
+            servers.gemini_settings = Some(settings);
+        }
+
+        info!(
+            "Loaded {} external MCP server(s) from {}",
+            servers.servers.len(),
+            path.display()
+        );
+        Ok(servers)
+    }
+
+    /// Merged config passed to Claude via `--mcp-config`
+    fn claude_config(&self) -> Value {
+        let servers: serde_json::Map<String, Value> = self
+            .servers
+            .iter()
+            .map(|(name, server)| (name.clone(), server.claude_entry()))
+            .collect();
+        json!({ "mcpServers": servers })
+    }
+
+    /// Settings file contents that make Gemini load the same servers
+    fn gemini_config(&self) -> Value {
+        let servers: serde_json::Map<String, Value> = self
+            .servers
+            .iter()
+            .map(|(name, server)| (name.clone(), server.gemini_entry()))
+            .collect();
+        json!({ "mcpServers": servers })
+    }
+
+    /// Attach the servers to an agent invocation
+    pub fn apply(&self, cmd: &mut TokioCommand, kind: ExecutorKind) {
+        if self.servers.is_empty() {
+            return;
+        }
+
+        match kind {
+            ExecutorKind::Claude => {
+                cmd.arg("--mcp-config");
+                cmd.arg(self.claude_config().to_string());
+            }
+            ExecutorKind::Gemini => {
+                if let Some(ref settings) = self.gemini_settings {
+                    cmd.env("GEMINI_CLI_SYSTEM_SETTINGS_PATH", settings);
+                }
+            }
+            ExecutorKind::Codex => {}
+        }
+    }
+
+    /// Describe every server along with a best-effort health check: stdio
+    /// servers must resolve to an executable, remote servers must answer HTTP.
+    pub async fn health(&self) -> Vec<Value> {
+        let client = reqwest::Client::builder()
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+
+        let checks: Vec<_> = self
+            .servers
+            .iter()
+            .map(|(name, server)| {
+                let name = name.clone();
+                let server = server.clone();
+                let client = client.clone();
+                tokio::spawn(async move { check_server(&client, name, &server).await })
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(checks.len());
+        for check in checks {
+            if let Ok(result) = check.await {
+                results.push(result);
+            }
+        }
+        results
+    }
+}
+
+async fn check_server(client: &reqwest::Client, name: String, server: &McpServerConfig) -> Value {
+    match server {
+        McpServerConfig::Stdio { command, args, .. } => {
+            let healthy = which::which(command).is_ok();
+            json!({
+                "name": name,
+                "transport": server.transport(),
+                "command": command,
+                "args": args,
+                "healthy": healthy,
+                "error": (!healthy).then(|| format!("Command not found: {}", command)),
+            })
+        }
+        McpServerConfig::Remote { url, .. } => {
+            // Any HTTP response means the server is up; MCP endpoints commonly
+            // reject a bare GET with 4xx.
+            let (healthy, status, error) = match client.get(url).send().await {
+                Ok(response) => (true, Some(response.status().as_u16()), None),
+                Err(e) => (false, None, Some(e.to_string())),
+            };
+            json!({
+                "name": name,
+                "transport": server.transport(),
+                "url": url,
+                "healthy": healthy,
+                "status": status,
+                "error": error,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servers_are_rendered_for_each_agent() {
+        let file: McpServersFile = serde_json::from_str(
+            r#"{"mcpServers": {
+                "github": {"command": "npx", "args": ["-y", "server-github"]},
+                "docs": {"type": "http", "url": "https://docs.example.com/mcp"},
+                "events": {"type": "sse", "url": "https://events.example.com/sse"}
+            }}"#,
+        )
+        .unwrap();
+        let servers = McpServers {
+            servers: file.mcp_servers,
+            gemini_settings: None,
+        };
+
+        let claude = servers.claude_config();
+        assert_eq!(claude["mcpServers"]["github"]["type"], "stdio");
+        assert_eq!(claude["mcpServers"]["github"]["args"][1], "server-github");
+        assert_eq!(claude["mcpServers"]["docs"]["type"], "http");
+        assert_eq!(claude["mcpServers"]["events"]["type"], "sse");
+
+        let gemini = servers.gemini_config();
+        assert_eq!(gemini["mcpServers"]["github"]["command"], "npx");
+        assert_eq!(
+            gemini["mcpServers"]["docs"]["httpUrl"],
+            "https://docs.example.com/mcp"
+        );
+        assert_eq!(
+            gemini["mcpServers"]["events"]["url"],
+            "https://events.example.com/sse"
+        );
+    }
+}
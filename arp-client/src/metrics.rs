@@ -0,0 +1,130 @@
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Latencies kept per route for the percentiles; older samples are dropped
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Counters and recent latencies of one route
+#[derive(Default)]
+struct RouteStats {
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+    /// Most recent latencies in microseconds, oldest first
+    latencies: VecDeque<u64>,
+    max_latency: u64,
+}
+
+/// Request counts, error counts and latency percentiles per route, keyed by
+/// the route's method and pattern (e.g. `GET /api/fs/{*path}`)
+pub struct RouteMetrics {
+    started: Instant,
+    routes: Mutex<BTreeMap<String, RouteStats>>,
+}
+
+impl RouteMetrics {
+    pub fn new() -> Self {
+        RouteMetrics {
+            started: Instant::now(),
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record one handled request
+    pub fn record(&self, route: &str, status: u16, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = routes.entry(route.to_string()).or_default();
+        stats.requests += 1;
+        match status {
+            400..=499 => stats.client_errors += 1,
+            500..=599 => stats.server_errors += 1,
+            _ => {}
+        }
+        if stats.latencies.len() == LATENCY_SAMPLES {
+            stats.latencies.pop_front();
+        }
+        stats.latencies.push_back(micros);
+        stats.max_latency = stats.max_latency.max(micros);
+    }
+
+    /// JSON summary of every route that has seen a request
+    pub fn snapshot(&self) -> Value {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let entries: Vec<Value> = routes
+            .iter()
+            .map(|(route, stats)| {
+                let mut sorted: Vec<u64> = stats.latencies.iter().copied().collect();
+                sorted.sort_unstable();
+                let errors = stats.client_errors + stats.server_errors;
+                json!({
+                    "route": route,
+                    "requests": stats.requests,
+                    "client_errors": stats.client_errors,
+                    "server_errors": stats.server_errors,
+                    "error_rate": errors as f64 / stats.requests as f64,
+                    "latency_ms": {
+                        "p50": percentile_ms(&sorted, 50),
+                        "p90": percentile_ms(&sorted, 90),
+                        "p99": percentile_ms(&sorted, 99),
+                        "max": stats.max_latency as f64 / 1000.0,
+                    },
+                })
+            })
+            .collect();
+
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "latency_samples": LATENCY_SAMPLES,
+            "routes": entries,
+        })
+    }
+}
+
+impl Default for RouteMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Nearest-rank percentile of sorted microsecond samples, in milliseconds
+fn percentile_ms(sorted: &[u64], percentile: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (sorted.len() * percentile).div_ceil(100).max(1);
+    sorted[rank - 1] as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_report_errors_and_percentiles() {
+        let metrics = RouteMetrics::new();
+        for ms in 1..=100 {
+            metrics.record("GET /api/fs", 200, Duration::from_millis(ms));
+        }
+        metrics.record("GET /api/fs", 404, Duration::from_millis(1));
+        metrics.record("POST /api/fs/sync", 500, Duration::from_millis(7));
+
+        let snapshot = metrics.snapshot();
+        let routes = snapshot["routes"].as_array().unwrap();
+        assert_eq!(routes.len(), 2);
+
+        let fs = &routes[0];
+        assert_eq!(fs["route"], "GET /api/fs");
+        assert_eq!(fs["requests"], 101);
+        assert_eq!(fs["client_errors"], 1);
+        assert_eq!(fs["latency_ms"]["p50"], 50.0);
+        assert_eq!(fs["latency_ms"]["p99"], 99.0);
+        assert_eq!(fs["latency_ms"]["max"], 100.0);
+
+        let sync = &routes[1];
+        assert_eq!(sync["server_errors"], 1);
+        assert_eq!(sync["error_rate"], 1.0);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::events::{ClientEvent, Event};
+use anyhow::{Result, anyhow};
+use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Requests buffered for the broker while it is unreachable
+const QUEUE_CAPACITY: usize = 256;
+
+/// How long to wait before reconnecting to an unreachable broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// An MQTT broker event sink:
+///
+/// ```json
+/// {"type": "mqtt", "broker": "mqtt://broker.local:1883", "topic": "fleet/{hostname}",
+///  "username": "arpc", "password": "secret", "qos": 1}
+/// ```
+///
+/// Under the base `topic` the client publishes
+/// - `status`: retained `online` / `offline`, also set as last will
+/// - `sessions`: `session_started` and `session_ended` events
+/// - `load`: retained `load` events
+/// - `events/<type>`: every other event
+///
+/// `topics` overrides any of these, e.g. `{"status": "fleet/status/{hostname}"}`.
+/// Topics may use `{hostname}` and `{client_id}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttSink {
+    /// `mqtt://host:port` or `mqtts://host:port`
+    broker: String,
+    #[serde(default = "default_topic")]
+    topic: String,
+    #[serde(default)]
+    topics: TopicOverrides,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default = "default_qos")]
+    qos: u8,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopicOverrides {
+    status: Option<String>,
+    sessions: Option<String>,
+    load: Option<String>,
+    events: Option<String>,
+}
+
+fn default_topic() -> String {
+    "arpc/{hostname}".to_string()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+impl MqttSink {
+    pub fn describe(&self) -> String {
+        format!("mqtt {}", self.broker)
+    }
+
+    /// Connect to the broker. The connection is kept up, and re-established
+    /// after failures, by a background task; publishes made while the broker
+    /// is away are queued.
+    pub fn connect(&self, client_id: &str) -> Result<MqttTarget> {
+        let qos = match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            other => return Err(anyhow!("Invalid MQTT qos {}; expected 0, 1 or 2", other)),
+        };
+        let hostname = hostname::get()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let expand = |topic: &str| {
+            topic
+                .replace("{hostname}", &hostname)
+                .replace("{client_id}", client_id)
+        };
+        let base = expand(&self.topic);
+        let topic = |custom: &Option<String>, name: &str| match custom {
+            Some(custom) => expand(custom),
+            None => format!("{}/{}", base, name),
+        };
+        let topics = Topics {
+            status: topic(&self.topics.status, "status"),
+            sessions: topic(&self.topics.sessions, "sessions"),
+            load: topic(&self.topics.load, "load"),
+            events: topic(&self.topics.events, "events"),
+        };
+
+        let separator = if self.broker.contains('?') { '&' } else { '?' };
+        let mqtt_client_id = format!("arpc-{}", &uuid::Uuid::new_v4().simple().to_string()[..12]);
+        let mut options = MqttOptions::parse_url(format!(
+            "{}{}client_id={}",
+            self.broker, separator, mqtt_client_id
+        ))
+        .map_err(|e| anyhow!("Invalid MQTT broker {}: {}", self.broker, e))?;
+        options.set_last_will(LastWill::new(&topics.status, "offline", qos, true));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let broker = self.broker.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(notification) => debug!("MQTT {}: {:?}", broker, notification),
+                    Err(e) => {
+                        warn!("MQTT broker {} unreachable: {}", broker, e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttTarget {
+            client,
+            qos,
+            topics,
+        })
+    }
+}
+
+struct Topics {
+    status: String,
+    sessions: String,
+    load: String,
+    events: String,
+}
+
+/// A connected MQTT sink
+pub struct MqttTarget {
+    client: AsyncClient,
+    qos: QoS,
+    topics: Topics,
+}
+
+impl MqttTarget {
+    pub async fn send(&self, event: &Event, payload: &Value) -> Result<()> {
+        let (topic, retain, body) = match &event.event {
+            ClientEvent::Connected { .. } => (self.topics.status.clone(), true, "online".into()),
+            ClientEvent::Disconnected { .. } => {
+                (self.topics.status.clone(), true, "offline".into())
+            }
+            ClientEvent::SessionStarted { .. } | ClientEvent::SessionEnded { .. } => {
+                (self.topics.sessions.clone(), false, payload.to_string())
+            }
+            ClientEvent::Load(_) => (self.topics.load.clone(), true, payload.to_string()),
+            other => (
+                format!("{}/{}", self.topics.events, other.kind()),
+                false,
+                payload.to_string(),
+            ),
+        };
+        self.client
+            .publish(topic, self.qos, retain, body.into_bytes())
+            .await?;
+        Ok(())
+    }
+}
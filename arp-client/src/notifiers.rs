@@ -0,0 +1,278 @@
+use crate::approvers::Approvers;
+use crate::events::{ClientEvent, Event};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a chat webhook gets to accept one message
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A chat channel notified through its incoming webhook:
+///
+/// ```json
+/// {"type": "slack", "webhook": "https://hooks.slack.com/services/...",
+///  "public_url": "http://arps.example.com:17003", "approver": "alice"}
+/// ```
+///
+/// Posts finished and failed sessions, sessions that couldn't start, and
+/// permission prompts; other events are skipped.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Notifier {
+    webhook: String,
+    /// Where this client is reached through the server; with it and
+    /// `approver` permission prompts get approve/deny links
+    public_url: Option<String>,
+    /// One of `--approvers` the webhook reaches, e.g. through a direct
+    /// message; its links decide as this approver, once per prompt
+    approver: Option<String>,
+}
+
+/// Message format of a chat service
+#[derive(Debug, Clone, Copy)]
+pub enum Chat {
+    Slack,
+    Discord,
+}
+
+impl Chat {
+    fn bold(self, text: &str) -> String {
+        match self {
+            Chat::Slack => format!("*{}*", text),
+            Chat::Discord => format!("**{}**", text),
+        }
+    }
+
+    fn link(self, label: &str, url: &str) -> String {
+        match self {
+            Chat::Slack => format!("<{}|{}>", url, label),
+            // Angle brackets keep Discord from embedding a preview
+            Chat::Discord => format!("[{}](<{}>)", label, url),
+        }
+    }
+
+    fn body(self, text: String) -> Value {
+        match self {
+            Chat::Slack => json!({ "text": text }),
+            Chat::Discord => json!({ "content": text }),
+        }
+    }
+}
+
+impl Notifier {
+    pub fn describe(&self, chat: Chat) -> String {
+        format!("{:?} notifier", chat).to_lowercase()
+    }
+
+    pub fn connect(
+        &self,
+        chat: Chat,
+        client_id: &str,
+        approvers: Arc<Approvers>,
+    ) -> Result<NotifierTarget> {
+        if let Some(approver) = &self.approver
+            && !approvers.contains(approver)
+        {
+            return Err(anyhow!("approver '{}' is not in --approvers", approver));
+        }
+        Ok(NotifierTarget {
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            chat,
+            notifier: self.clone(),
+            client_id: client_id.to_string(),
+            approvers,
+            hostname: hostname::get()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "arpc".to_string()),
+        })
+    }
+}
+
+/// A notifier ready to post
+pub struct NotifierTarget {
+    http: reqwest::Client,
+    chat: Chat,
+    notifier: Notifier,
+    client_id: String,
+    approvers: Arc<Approvers>,
+    hostname: String,
+}
+
+impl NotifierTarget {
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        let Some(text) = self.message(&event.event) else {
+            return Ok(());
+        };
+        self.http
+            .post(self.notifier.webhook.as_str())
+            .json(&self.chat.body(text))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// The message for an event a person should hear about
+    fn message(&self, event: &ClientEvent) -> Option<String> {
+        let host = self.chat.bold(&self.hostname);
+        match event {
+            ClientEvent::SessionEnded {
+                session_id,
+                executor,
+                status,
+                project_path,
+                exit_code,
+                error,
+                ..
+            } => {
+                let icon = match *status {
+                    "completed" => "✅",
+                    "failed" => "❌",
+                    _ => "⏹️",
+                };
+                let mut text = format!(
+                    "{} {}: {} session `{}` {}",
+                    icon, host, executor, session_id, status
+                );
+                if let Some(path) = project_path {
+                    text.push_str(&format!(" in `{}`", path));
+                }
+                match (error, exit_code) {
+                    (Some(error), _) => text.push_str(&format!(": {}", error)),
+                    (None, Some(code)) if *code != 0 => {
+                        text.push_str(&format!(" (exit code {})", code))
+                    }
+                    _ => {}
+                }
+                Some(text)
+            }
+            #[cfg(any(feature = "mcp", test))]
+            ClientEvent::PermissionRequested {
+                session_id,
+                permission_id,
+                tool_name,
+            } => {
+                let mut text = format!(
+                    "🔐 {}: session `{}` asks to use `{}`",
+                    host, session_id, tool_name
+                );
+                if let (Some(public_url), Some(approver)) =
+                    (&self.notifier.public_url, &self.notifier.approver)
+                {
+                    // `token` only routes the request to this client; the
+                    // approver token is what lets the link decide
+                    let url = |decision: &str| {
+                        let approver_token = self
+                            .approvers
+                            .link_token(approver, session_id, permission_id, decision)
+                            .unwrap_or_default();
+                        format!(
+                            "{}/api/sessions/{}/permissions/{}?token={}&decision={}&approver={}",
+                            public_url.trim_end_matches('/'),
+                            urlencoding::encode(session_id),
+                            urlencoding::encode(permission_id),
+                            urlencoding::encode(&self.client_id),
+                            decision,
+                            urlencoding::encode(&approver_token)
+                        )
+                    };
+                    text.push_str(&format!(
+                        "\n{} · {}",
+                        self.chat.link("Approve", &url("approve")),
+                        self.chat.link("Deny", &url("deny"))
+                    ));
+                }
+                Some(text)
+            }
+            ClientEvent::Error {
+                session_id,
+                message,
+            } => Some(match session_id {
+                Some(session_id) => format!(
+                    "⚠️ {}: session `{}` couldn't start: {}",
+                    host, session_id, message
+                ),
+                None => format!("⚠️ {}: {}", host, message),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permission_prompts_link_back_to_the_client() {
+        let notifier: Notifier = serde_json::from_value(json!({
+            "webhook": "https://hooks.slack.com/services/T/B/X",
+            "public_url": "http://arps.example.com:17003/",
+            "approver": "alice"
+        }))
+        .unwrap();
+        let approvers = Arc::new(Approvers::from_tokens(&[("alice", "token-a")]));
+        let target = notifier
+            .connect(Chat::Slack, "box 1", approvers.clone())
+            .unwrap();
+
+        let text = target
+            .message(&ClientEvent::PermissionRequested {
+                session_id: "s1".to_string(),
+                permission_id: "p1".to_string(),
+                tool_name: "Bash".to_string(),
+            })
+            .unwrap();
+        assert!(text.contains("asks to use `Bash`"));
+        let approve_token = approvers
+            .link_token("alice", "s1", "p1", "approve")
+            .unwrap();
+        assert!(text.contains(&format!(
+            "<http://arps.example.com:17003/api/sessions/s1/permissions/p1?token=box%201&decision=approve&approver={}|Approve>",
+            approve_token
+        )));
+        assert!(!text.contains("token-a"));
+        assert!(
+            target
+                .message(&ClientEvent::SessionOutput {
+                    session_id: "s1".to_string(),
+                    lines: 100,
+                })
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn links_need_a_known_approver() {
+        let notifier = |approver: Option<&str>| -> Notifier {
+            serde_json::from_value(json!({
+                "webhook": "https://discord.com/api/webhooks/1/x",
+                "public_url": "http://arps.example.com:17003",
+                "approver": approver
+            }))
+            .unwrap()
+        };
+        let approvers = Arc::new(Approvers::from_tokens(&[("alice", "token-a")]));
+        assert!(
+            notifier(Some("mallory"))
+                .connect(Chat::Discord, "box", approvers.clone())
+                .is_err()
+        );
+
+        // Without an approver the prompt is posted without links
+        let target = notifier(None)
+            .connect(Chat::Discord, "box", approvers)
+            .unwrap();
+        let text = target
+            .message(&ClientEvent::PermissionRequested {
+                session_id: "s1".to_string(),
+                permission_id: "p1".to_string(),
+                tool_name: "Bash".to_string(),
+            })
+            .unwrap();
+        assert!(!text.contains("http"));
+    }
+}
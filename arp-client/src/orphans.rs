@@ -0,0 +1,245 @@
+//! Executors outliving the client. A pid file is written for every spawned
+//! executor and removed when its session ends; files left behind on startup
+//! belong to executors a crashed client left running, which are adopted,
+//! terminated or ignored according to `--orphan-policy`.
+
+use crate::agentx::types::HistoryWindow;
+use crate::executor::{ExecutorKind, OrphanPolicy};
+use crate::handlers::session::load_history_for_executor;
+use crate::process;
+use crate::session::{CommandSession, SessionManager};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Pid files older than this are assumed to name a reused pid
+const MAX_ORPHAN_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// How often an adopted executor's transcript and liveness are checked
+const ADOPTED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A running executor, as written next to its siblings in the pid directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PidFile {
+    pub pid: u32,
+    pub session_id: String,
+    pub executor: ExecutorKind,
+    pub project_path: PathBuf,
+    /// Unix seconds
+    pub started_at: u64,
+}
+
+fn pid_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("arpc/pids"))
+}
+
+/// Agent session IDs come from executor output, so they never name the file
+fn pid_file_path(dir: &Path, session_id: &str) -> PathBuf {
+    let name = Uuid::new_v5(&Uuid::NAMESPACE_OID, session_id.as_bytes());
+    dir.join(format!("{}.json", name))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Remember that `pid` runs the executor of `session_id`
+pub async fn record(pid: u32, session_id: &str, executor: ExecutorKind, project_path: &str) {
+    let Some(dir) = pid_dir() else {
+        return;
+    };
+    let pid_file = PidFile {
+        pid,
+        session_id: session_id.to_string(),
+        executor,
+        project_path: PathBuf::from(project_path),
+        started_at: unix_now(),
+    };
+    if let Err(e) = write_pid_file(&dir, &pid_file).await {
+        warn!("[Session {}] Failed to write pid file: {}", session_id, e);
+    }
+}
+
+async fn write_pid_file(dir: &Path, pid_file: &PidFile) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let content = serde_json::to_vec(pid_file)?;
+    tokio::fs::write(pid_file_path(dir, &pid_file.session_id), content).await
+}
+
+/// Pid files in `dir` naming live executors started within
+/// [`MAX_ORPHAN_AGE`]; the rest are deleted
+async fn scan(dir: &Path) -> Vec<PidFile> {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return Vec::new();
+    };
+
+    let mut orphans = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let pid_file = tokio::fs::read(&path)
+            .await
+            .ok()
+            .and_then(|content| serde_json::from_slice::<PidFile>(&content).ok());
+        match pid_file {
+            Some(pid_file)
+                if unix_now().saturating_sub(pid_file.started_at) < MAX_ORPHAN_AGE.as_secs()
+                    && process::is_alive(pid_file.pid) =>
+            {
+                orphans.push(pid_file)
+            }
+            _ => {
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+        }
+    }
+    orphans
+}
+
+/// Handle the executors a previous run left behind, then keep the pid
+/// directory in step with the sessions of `manager`
+pub fn start(policy: OrphanPolicy, manager: SessionManager) {
+    let Some(dir) = pid_dir() else {
+        return;
+    };
+    let mut ended = manager.subscribe_ended();
+
+    tokio::spawn(async move {
+        for orphan in scan(&dir).await {
+            match policy {
+                OrphanPolicy::Adopt => adopt(&manager, orphan).await,
+                OrphanPolicy::Terminate => {
+                    info!(
+                        "[Session {}] Terminating orphaned {} executor (pid {})",
+                        orphan.session_id,
+                        orphan.executor.as_str(),
+                        orphan.pid
+                    );
+                    if let Err(e) = process::terminate_pid(orphan.pid) {
+                        warn!("Failed to terminate pid {}: {}", orphan.pid, e);
+                    }
+                    let _ = tokio::fs::remove_file(pid_file_path(&dir, &orphan.session_id)).await;
+                }
+                OrphanPolicy::Ignore => {
+                    info!(
+                        "[Session {}] Leaving orphaned {} executor (pid {}) running",
+                        orphan.session_id,
+                        orphan.executor.as_str(),
+                        orphan.pid
+                    );
+                    let _ = tokio::fs::remove_file(pid_file_path(&dir, &orphan.session_id)).await;
+                }
+            }
+        }
+
+        loop {
+            match ended.recv().await {
+                Ok(ended) => {
+                    let _ = tokio::fs::remove_file(pid_file_path(&dir, &ended.session_id)).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Pid file cleanup missed {} session ends", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Track an orphaned executor as a live session fed from its transcript
+async fn adopt(manager: &SessionManager, orphan: PidFile) {
+    info!(
+        "[Session {}] Adopting orphaned {} executor (pid {})",
+        orphan.session_id,
+        orphan.executor.as_str(),
+        orphan.pid
+    );
+    let session = manager
+        .create_session_with_id_and_executor(orphan.session_id.clone(), orphan.executor)
+        .await;
+    session.set_project_path(orphan.project_path).await;
+    manager
+        .register_agent_session(orphan.executor, orphan.session_id, &session)
+        .await;
+    tokio::spawn(follow_transcript(session, orphan.pid));
+}
+
+/// Copy new transcript lines into `session` until process `pid` exits
+async fn follow_transcript(session: Arc<CommandSession>, pid: u32) {
+    let mut read = 0;
+    loop {
+        // Checked before reading so the last lines are read after the exit
+        let alive = process::is_alive(pid);
+        let window = HistoryWindow::unlimited_from(read);
+        if let Some(page) =
+            load_history_for_executor(session.executor_kind, &session.session_id, window).await
+        {
+            read += page.messages.len();
+            for message in page.messages {
+                session.add_output(message.to_string()).await;
+            }
+        }
+
+        if !alive {
+            info!(
+                "[Session {}] Adopted executor (pid {}) exited",
+                session.session_id, pid
+            );
+            // The exit code went to the client that spawned it
+            session.mark_completed(None).await;
+            return;
+        }
+        tokio::time::sleep(ADOPTED_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn pid_file(pid: u32, session_id: &str, started_at: u64) -> PidFile {
+        PidFile {
+            pid,
+            session_id: session_id.to_string(),
+            executor: ExecutorKind::Codex,
+            project_path: PathBuf::from("/work/app"),
+            started_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_keeps_recent_live_executors_and_deletes_the_rest() {
+        let dir = std::env::temp_dir().join(format!("arpc-pids-{}", Uuid::new_v4()));
+        let mut exited = tokio::process::Command::new("true").spawn().unwrap();
+        let exited_pid = exited.id().unwrap();
+        exited.wait().await.unwrap();
+
+        let live = pid_file(std::process::id(), "live", unix_now());
+        write_pid_file(&dir, &live).await.unwrap();
+        write_pid_file(&dir, &pid_file(exited_pid, "exited", unix_now()))
+            .await
+            .unwrap();
+        write_pid_file(&dir, &pid_file(std::process::id(), "stale", 0))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("garbage.json"), b"not json")
+            .await
+            .unwrap();
+
+        assert_eq!(scan(&dir).await, vec![live.clone()]);
+        let mut left = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = left.next_entry().await.unwrap() {
+            names.push(entry.path());
+        }
+        assert_eq!(names, vec![pid_file_path(&dir, "live")]);
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
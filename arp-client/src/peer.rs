@@ -0,0 +1,351 @@
+use crate::config::{ClientConfig, PeerService};
+#[cfg(feature = "e2ee")]
+use crate::e2ee::{self, E2eeKey};
+use crate::resolver::Resolver;
+use crate::tls::{self, ServerTls};
+use anyhow::{Context, Result, anyhow, bail};
+use common::{Command, CopyConfig, join_streams_with, read_command, write_command};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// How long to wait for the server to pair both sides of a hole punch; it
+/// gives up on its own a little earlier.
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long both sides keep connecting to each other before the connection
+/// is relayed instead.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Pause between connection attempts refused while the other side's NAT
+/// has no hole yet.
+const PUNCH_RETRY: Duration = Duration::from_millis(50);
+
+/// How `arpc connect` reaches its service.
+struct Connector {
+    proxy_addr: String,
+    service: PeerService,
+    /// Try a direct connection before relaying
+    direct: bool,
+    copy_config: CopyConfig,
+    /// Encrypt end to end with this key
+    #[cfg(feature = "e2ee")]
+    e2ee_key: Option<E2eeKey>,
+    /// Connections to the server go over TLS
+    server_tls: Option<Arc<ServerTls>>,
+    /// Looks up the server, taking `--resolve` into account
+    resolver: Resolver,
+    /// The service is looked up in this tenant of a shared server
+    tenant_token: Option<String>,
+}
+
+/// Run `arpc connect`: accept connections on `listen` and relay each to
+/// `service` through the server, or reach it directly first if `direct`.
+pub async fn connect(
+    config: &ClientConfig,
+    service: &PeerService,
+    listen: &str,
+    direct: bool,
+) -> Result<()> {
+    let connector = Connector {
+        proxy_addr: config.proxy_addr(),
+        service: service.clone(),
+        direct,
+        copy_config: config.copy_config(),
+        #[cfg(feature = "e2ee")]
+        e2ee_key: if config.e2ee {
+            E2eeKey::load().map_err(|e| anyhow!(e))?
+        } else {
+            None
+        },
+        server_tls: ServerTls::from_config(config)?,
+        resolver: Resolver::from_config(config),
+        tenant_token: crate::tunnel::tenant_token().map_err(|e| anyhow!(e))?,
+    };
+
+    // A bare port listens on loopback only
+    let listen = match listen.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => listen.to_string(),
+    };
+    let listener = TcpListener::bind(&listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen))?;
+    info!(
+        "Forwarding {} to {} via {}",
+        listener.local_addr()?,
+        service,
+        config.proxy_addr()
+    );
+    serve(listener, Arc::new(connector)).await
+}
+
+/// Relay every connection accepted on `listener` to the service.
+async fn serve(listener: TcpListener, connector: Arc<Connector>) -> Result<()> {
+    loop {
+        let (local, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept a local connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let connector = connector.clone();
+        tokio::spawn(async move {
+            let service = &connector.service;
+            match connector.relay(local).await {
+                Ok(()) => debug!("Connection from {} to {} closed", addr, service),
+                Err(e) => warn!("Connection from {} to {} failed: {}", addr, service, e),
+            }
+        });
+    }
+}
+
+impl Connector {
+    /// Ask the server to join a proxy port connection with a tunnel to the
+    /// service, then relay `local` over it. With `direct`, a connection
+    /// straight to the service is tried first.
+    async fn relay(&self, local: TcpStream) -> Result<()> {
+        if let (true, Some(name)) = (self.direct, &self.service.name) {
+            match self.connect_direct(name).await {
+                Ok(Some(stream)) => {
+                    debug!("Reached {} directly", self.service);
+                    return self.join(local, stream).await;
+                }
+                Ok(None) => debug!("No direct connection to {}, relaying", self.service),
+                Err(e) => debug!(
+                    "Direct connection to {} failed, relaying: {}",
+                    self.service, e
+                ),
+            }
+        }
+
+        let mut proxy_stream =
+            tls::connect(&self.proxy_addr, &self.resolver, self.server_tls.as_deref())
+                .await
+                .with_context(|| format!("Failed to connect to {}", self.proxy_addr))?;
+        let command = Command::ConnectService {
+            client_id: self.service.client_id.clone(),
+            service: self.service.name.clone(),
+            token: self.tenant_token.clone(),
+        };
+        write_command(&mut proxy_stream, &command).await?;
+        self.join(local, proxy_stream).await
+    }
+
+    /// Relay `local` over a connection to the service, encrypting it end to
+    /// end when a key is set.
+    async fn join(&self, local: TcpStream, tunnel: TcpStream) -> Result<()> {
+        #[cfg(feature = "e2ee")]
+        if let Some(key) = &self.e2ee_key {
+            return e2ee::relay(tunnel, local, key, true).await;
+        }
+        join_streams_with(local, tunnel, &self.copy_config).await?;
+        Ok(())
+    }
+
+    /// Try to reach the service `name` directly, meeting it through the
+    /// server first. None when it refused or no hole could be punched.
+    async fn connect_direct(&self, name: &str) -> Result<Option<TcpStream>> {
+        let punch_id = uuid::Uuid::new_v4().simple().to_string();
+        let request = Command::PunchRequest {
+            client_id: self.service.client_id.clone(),
+            service: name.to_string(),
+            punch_id: punch_id.clone(),
+            token: self.tenant_token.clone(),
+        };
+        let server_tls = self.server_tls.as_deref();
+        let (local, peer) =
+            rendezvous(&self.proxy_addr, &self.resolver, server_tls, &request).await?;
+        let Some(peer) = peer else {
+            return Ok(None);
+        };
+        let Some(mut stream) = punch(local, peer).await else {
+            return Ok(None);
+        };
+        // Tells the other side which attempt this is, so it serves no stranger
+        write_command(&mut stream, &Command::PunchReady { punch_id }).await?;
+        Ok(Some(stream))
+    }
+}
+
+/// Answer a `Punch`: meet the peer through the server and punch towards
+/// it, returning the direct connection once the peer proved it's the one
+/// that asked. The peer relays when this fails.
+pub async fn answer_punch(
+    proxy_addr: &str,
+    resolver: &Resolver,
+    server_tls: Option<&ServerTls>,
+    punch_id: &str,
+) -> Result<TcpStream> {
+    let ready = Command::PunchReady {
+        punch_id: punch_id.to_string(),
+    };
+    let (local, peer) = rendezvous(proxy_addr, resolver, server_tls, &ready).await?;
+    let peer = peer.ok_or_else(|| anyhow!("the peer gave up"))?;
+    let mut stream = punch(local, peer)
+        .await
+        .ok_or_else(|| anyhow!("no direct connection to {}", peer))?;
+    match tokio::time::timeout(RENDEZVOUS_TIMEOUT, read_command(&mut stream)).await {
+        Ok(Ok(Command::PunchReady { punch_id: id })) if id == punch_id => Ok(stream),
+        _ => bail!("{} is not the peer of punch {}", peer, punch_id),
+    }
+}
+
+/// A TCP socket on `local` that others may share, so the hole a connection
+/// to the server opens in the NAT can be reused to reach the peer.
+fn shared_socket(local: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = if local.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(local)?;
+    Ok(socket)
+}
+
+/// Send `hello` to the server's proxy port from a shareable port and read
+/// where the peer is. Returns the local address used and the peer's public
+/// address, unset when the server couldn't pair both sides.
+async fn rendezvous(
+    proxy_addr: &str,
+    resolver: &Resolver,
+    server_tls: Option<&ServerTls>,
+    hello: &Command,
+) -> Result<(SocketAddr, Option<SocketAddr>)> {
+    let server = resolver
+        .lookup(proxy_addr)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{} does not resolve", proxy_addr))?;
+    let any: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let stream = shared_socket(any)?.connect(server).await?;
+    let local = stream.local_addr()?;
+    let mut stream = match server_tls {
+        Some(tls) => tls.wrap(stream, tls::host_of(proxy_addr)).await?,
+        None => stream,
+    };
+    write_command(&mut stream, hello).await?;
+    match tokio::time::timeout(RENDEZVOUS_TIMEOUT, read_command(&mut stream)).await?? {
+        Command::PunchPeer { peer_addr, .. } => {
+            let peer = peer_addr.map(|peer| peer.parse()).transpose()?;
+            Ok((local, peer))
+        }
+        other => bail!("unexpected answer from the server: {:?}", other),
+    }
+}
+
+/// Keep connecting from `local` to `peer` until the attempts of both sides
+/// meet in a simultaneous open that gets through both NATs, or
+/// [`PUNCH_TIMEOUT`] passes.
+async fn punch(local: SocketAddr, peer: SocketAddr) -> Option<TcpStream> {
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+    while Instant::now() < deadline {
+        let socket = shared_socket(local).ok()?;
+        match tokio::time::timeout_at(deadline, socket.connect(peer)).await {
+            Ok(Ok(stream)) => return Some(stream),
+            Ok(Err(e)) => {
+                debug!("Punching from {} to {}: {}", local, peer, e);
+                tokio::time::sleep(PUNCH_RETRY).await;
+            }
+            Err(_) => break,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::read_command;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn connector(proxy_addr: String, service: PeerService, direct: bool) -> Arc<Connector> {
+        Arc::new(Connector {
+            proxy_addr,
+            service,
+            direct,
+            copy_config: CopyConfig::default(),
+            #[cfg(feature = "e2ee")]
+            e2ee_key: None,
+            server_tls: None,
+            resolver: Resolver::default(),
+            tenant_token: Some("acme.s3cret".to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn local_connections_are_relayed_to_the_named_service() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = server.local_addr().unwrap().to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let service: PeerService = "db-box/pg".parse().unwrap();
+        tokio::spawn(serve(listener, connector(proxy_addr, service, false)));
+
+        let mut local = TcpStream::connect(local_addr).await.unwrap();
+        local.write_all(b"ping").await.unwrap();
+
+        let (mut relayed, _) = server.accept().await.unwrap();
+        let command = read_command(&mut relayed).await.unwrap();
+        assert!(matches!(
+            command,
+            Command::ConnectService { ref client_id, service: Some(ref service), ref token }
+                if client_id == "db-box" && service == "pg" && token.as_deref() == Some("acme.s3cret")
+        ));
+        let mut ping = [0; 4];
+        relayed.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        relayed.write_all(b"pong").await.unwrap();
+        let mut pong = [0; 4];
+        local.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+
+        let usual: PeerService = "db-box".parse().unwrap();
+        assert_eq!(usual.name, None);
+        for bad in ["", "/pg", "db-box/", "db-box/127.0.0.1:5432"] {
+            assert!(bad.parse::<PeerService>().is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_direct_connections_fall_back_to_the_relay() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = server.local_addr().unwrap().to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let service: PeerService = "db-box/pg".parse().unwrap();
+        tokio::spawn(serve(listener, connector(proxy_addr, service, true)));
+        let _local = TcpStream::connect(local_addr).await.unwrap();
+
+        let (mut rendezvous, _) = server.accept().await.unwrap();
+        let Command::PunchRequest { punch_id, .. } = read_command(&mut rendezvous).await.unwrap()
+        else {
+            panic!("expected a punch request");
+        };
+        let refused = Command::PunchPeer {
+            punch_id,
+            peer_addr: None,
+        };
+        write_command(&mut rendezvous, &refused).await.unwrap();
+
+        let (mut relayed, _) = server.accept().await.unwrap();
+        assert!(matches!(
+            read_command(&mut relayed).await.unwrap(),
+            Command::ConnectService { .. }
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+#[cfg(feature = "executors")]
+use crate::executor::ExecutorOptions;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Limits on what `POST /api/sessions` may launch
+///
+/// Loaded from a JSON file; a list left out allows anything and an empty list
+/// allows nothing, e.g.
+///
+/// ```json
+/// {
+///   "executors": ["claude", "gemini"],
+///   "models": ["sonnet"],
+///   "permission_modes": ["default", "plan", "acceptEdits"],
+///   "project_paths": ["/home/dev/work"]
+/// }
+/// ```
+///
+/// Requests without a model use the agent's default model and pass the
+/// `models` rule.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionPolicy {
+    #[cfg(feature = "executors")]
+    executors: Option<Vec<String>>,
+    #[cfg(feature = "executors")]
+    models: Option<Vec<String>>,
+    #[cfg(feature = "executors")]
+    permission_modes: Option<Vec<String>>,
+    project_paths: Option<Vec<PathBuf>>,
+    /// Roots given with `--project-root`, checked on top of `project_paths`
+    #[serde(skip)]
+    project_roots: Vec<PathBuf>,
+}
+
+/// The policy rule a session request broke
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl SessionPolicy {
+    /// Load the policy from `path`; no path means no restrictions
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read session policy {}", path.display()))?;
+        let mut policy: SessionPolicy = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid session policy {}: {}", path.display(), e))?;
+
+        // Compare resolved paths so symlinks and `..` can't step outside a prefix
+        if let Some(prefixes) = policy.project_paths.as_mut() {
+            for prefix in prefixes.iter_mut() {
+                *prefix = canonical_root(prefix)?;
+            }
+        }
+
+        info!("Loaded session policy from {}", path.display());
+        Ok(policy)
+    }
+
+    /// Only allow sessions under one of `roots` (no roots means anywhere)
+    pub fn with_project_roots(mut self, roots: &[PathBuf]) -> Result<Self> {
+        self.project_roots = roots
+            .iter()
+            .map(|root| canonical_root(root))
+            .collect::<Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Check a session request against every rule; `project_path` must
+    /// already be canonical
+    #[cfg(feature = "executors")]
+    pub fn check(
+        &self,
+        options: &ExecutorOptions,
+        project_path: &Path,
+    ) -> Result<(), PolicyViolation> {
+        let executor = options.kind().as_str();
+        if !allows(&self.executors, executor) {
+            return Err(PolicyViolation {
+                rule: "executors",
+                message: format!("Executor '{}' is not allowed", executor),
+            });
+        }
+
+        if let Some(model) = options.model()
+            && !allows(&self.models, model)
+        {
+            return Err(PolicyViolation {
+                rule: "models",
+                message: format!("Model '{}' is not allowed", model),
+            });
+        }
+
+        let mode = options.permission_mode();
+        if !allows(&self.permission_modes, mode) {
+            return Err(PolicyViolation {
+                rule: "permission_modes",
+                message: format!("Permission mode '{}' is not allowed", mode),
+            });
+        }
+
+        self.check_project_path(project_path)
+    }
+
+    /// Check that a canonical path lies inside the allowed project directories
+    pub fn check_project_path(&self, project_path: &Path) -> Result<(), PolicyViolation> {
+        let outside = |prefixes: &[PathBuf]| {
+            !prefixes
+                .iter()
+                .any(|prefix| project_path.starts_with(prefix))
+        };
+        let rule = match &self.project_paths {
+            Some(prefixes) if outside(prefixes) => Some("project_paths"),
+            _ if !self.project_roots.is_empty() && outside(&self.project_roots) => {
+                Some("project_roots")
+            }
+            _ => None,
+        };
+        if let Some(rule) = rule {
+            return Err(PolicyViolation {
+                rule,
+                message: format!(
+                    "Project path '{}' is not under an allowed directory",
+                    project_path.display()
+                ),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn canonical_root(root: &Path) -> Result<PathBuf> {
+    root.canonicalize()
+        .with_context(|| format!("Invalid project root {}", root.display()))
+}
+
+#[cfg(feature = "executors")]
+fn allows(list: &Option<Vec<String>>, value: &str) -> bool {
+    list.as_ref()
+        .is_none_or(|list| list.iter().any(|allowed| allowed == value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "executors")]
+    use crate::executor::{ClaudeOptions, CodexOptions};
+
+    #[cfg(feature = "executors")]
+    #[test]
+    fn policy_rejects_with_the_violated_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir_all(work.join("app")).unwrap();
+        std::fs::create_dir_all(dir.path().join("other")).unwrap();
+
+        let policy_file = dir.path().join("policy.json");
+        let policy = serde_json::json!({
+            "executors": ["claude"],
+            "models": ["sonnet"],
+            "permission_modes": ["default", "plan"],
+            "project_paths": [work],
+        });
+        std::fs::write(&policy_file, policy.to_string()).unwrap();
+        let policy = SessionPolicy::load(Some(&policy_file)).unwrap();
+
+        let claude = |model: Option<&str>, mode: Option<&str>| {
+            ExecutorOptions::Claude(ClaudeOptions {
+                model: model.map(String::from),
+                permission_mode: mode.map(String::from),
+                ..ClaudeOptions::default()
+            })
+        };
+        let app = work.join("app");
+        let app = app.to_str().unwrap();
+        let rule = |options: &ExecutorOptions, path: &str| {
+            let path = Path::new(path).canonicalize().unwrap();
+            policy.check(options, &path).err().map(|v| v.rule)
+        };
+
+        assert_eq!(rule(&claude(None, Some("plan")), app), None);
+        assert_eq!(rule(&claude(Some("sonnet"), Some("default")), app), None);
+        assert_eq!(
+            rule(&claude(Some("opus"), Some("plan")), app),
+            Some("models")
+        );
+        assert_eq!(rule(&claude(None, None), app), Some("permission_modes"));
+        assert_eq!(
+            rule(&ExecutorOptions::Codex(CodexOptions::default()), app),
+            Some("executors")
+        );
+
+        let escaped = format!("{}/../../other", app);
+        assert_eq!(
+            rule(&claude(None, Some("plan")), &escaped),
+            Some("project_paths")
+        );
+
+        let policy = SessionPolicy::default()
+            .with_project_roots(&[dir.path().join("other")])
+            .unwrap();
+        let app = work.join("app").canonicalize().unwrap();
+        let violation = policy.check(&claude(None, None), &app).unwrap_err();
+        assert_eq!(violation.rule, "project_roots");
+        assert!(
+            SessionPolicy::default()
+                .check(&claude(None, None), &app)
+                .is_ok()
+        );
+        assert!(
+            policy
+                .with_project_roots(&[dir.path().join("missing")])
+                .is_err()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn project_roots_hold_resolved_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        std::fs::create_dir_all(dir.path().join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside"), root.join("escape")).unwrap();
+        // A root reached through a symlink is compared by its target
+        std::os::unix::fs::symlink(&root, dir.path().join("link")).unwrap();
+
+        let policy = SessionPolicy::default()
+            .with_project_roots(&[dir.path().join("link")])
+            .unwrap();
+        let rule = |path: PathBuf| {
+            let path = path.canonicalize().unwrap();
+            policy.check_project_path(&path).err().map(|v| v.rule)
+        };
+
+        assert_eq!(rule(root.join("app")), None);
+        assert_eq!(rule(root.clone()), None);
+        assert_eq!(rule(dir.path().join("outside")), Some("project_roots"));
+        assert_eq!(rule(root.join("escape")), Some("project_roots"));
+        assert_eq!(rule(root.join("app/../../outside")), Some("project_roots"));
+        assert_eq!(rule(root.join("app/..")), None);
+        // A sibling sharing the root's name as a prefix is still outside
+        std::fs::create_dir_all(dir.path().join("root2")).unwrap();
+        assert_eq!(rule(dir.path().join("root2")), Some("project_roots"));
+    }
+}
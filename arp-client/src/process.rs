@@ -0,0 +1,251 @@
+//! Platform-specific control of executor processes.
+//!
+//! Executors are wrappers that start further processes (`claude.cmd` runs
+//! node through cmd.exe, the npm shims do the same on Unix), so stopping
+//! only the direct child leaves the agent running. Each executor is
+//! therefore started in its own process group, and on Windows also placed
+//! in a Job Object, so the whole tree can be interrupted or killed:
+//!
+//! | | Unix | Windows |
+//! |---|---|---|
+//! | interrupt | `SIGINT` to the process group | `CTRL_BREAK_EVENT` to the process group |
+//! | terminate | `SIGKILL` to the process group | `TerminateJobObject` |
+
+use std::io;
+use tokio::process::{Child, Command};
+
+/// Start the command in a process group of its own
+pub fn configure(cmd: &mut Command) {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    #[cfg(windows)]
+    cmd.creation_flags(windows_sys::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP);
+}
+
+/// A spawned executor and every process it starts
+pub struct ProcessTree {
+    pid: u32,
+    #[cfg(windows)]
+    job: Option<windows::Job>,
+}
+
+impl ProcessTree {
+    /// Track `child`, which must have been started with [`configure`];
+    /// None once it has been reaped
+    pub fn attach(child: &Child) -> Option<Self> {
+        let pid = child.id()?;
+        Some(ProcessTree {
+            pid,
+            #[cfg(windows)]
+            job: windows::Job::assign(child),
+        })
+    }
+
+    /// Ask the executor to stop, as Ctrl-C in a terminal would
+    pub fn interrupt(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            signal_group(self.pid, libc::SIGINT)
+        }
+        #[cfg(windows)]
+        {
+            windows::ctrl_break(self.pid)
+        }
+    }
+
+    /// Kill the executor and everything it started
+    pub fn terminate(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            signal_group(self.pid, libc::SIGKILL)
+        }
+        #[cfg(windows)]
+        {
+            match &self.job {
+                Some(job) => job.terminate(),
+                None => windows::terminate_pid(self.pid),
+            }
+        }
+    }
+}
+
+/// Whether a process with this pid is running and ours to control
+pub fn is_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // Signal 0 only checks that the process exists and may be signalled
+        unsafe { libc::kill(pid, 0) == 0 }
+    }
+    #[cfg(windows)]
+    {
+        windows::is_alive(pid)
+    }
+}
+
+/// Stop a process that is not our child, such as an orphaned executor
+pub fn terminate_pid(pid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let pid = libc::pid_t::try_from(pid).map_err(|_| io::ErrorKind::InvalidInput)?;
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(windows)]
+    {
+        windows::terminate_pid(pid)
+    }
+}
+
+#[cfg(unix)]
+fn signal_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    let pgid = libc::pid_t::try_from(pgid).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // A negative pid addresses the whole process group
+    if unsafe { libc::kill(-pgid, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use tokio::process::Child;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, STILL_ACTIVE};
+    use windows_sys::Win32::System::Console::{CTRL_BREAK_EVENT, GenerateConsoleCtrlEvent};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject,
+    };
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE,
+        TerminateProcess,
+    };
+
+    /// A Job Object holding an executor's processes. It is not created
+    /// with kill-on-close, so executors outlive a crashed client and can
+    /// be adopted by the next one.
+    pub struct Job(HANDLE);
+
+    // The handle is only passed to thread-safe kernel calls
+    unsafe impl Send for Job {}
+    unsafe impl Sync for Job {}
+
+    impl Job {
+        /// Processes the child started before this call are not included
+        pub fn assign(child: &Child) -> Option<Self> {
+            let process = child.raw_handle()?;
+            let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+            if job.is_null() {
+                tracing::warn!(
+                    "Failed to create job object: {}",
+                    io::Error::last_os_error()
+                );
+                return None;
+            }
+            let job = Job(job);
+            if unsafe { AssignProcessToJobObject(job.0, process as HANDLE) } == 0 {
+                tracing::warn!(
+                    "Failed to assign executor to job object: {}",
+                    io::Error::last_os_error()
+                );
+                return None;
+            }
+            Some(job)
+        }
+
+        pub fn terminate(&self) -> io::Result<()> {
+            check(unsafe { TerminateJobObject(self.0, 1) })
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    pub fn ctrl_break(pid: u32) -> io::Result<()> {
+        // The process group ID of a CREATE_NEW_PROCESS_GROUP child is its pid
+        check(unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) })
+    }
+
+    pub fn is_alive(pid: u32) -> bool {
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if process.is_null() {
+            return false;
+        }
+        let mut exit_code = 0u32;
+        let queried = unsafe { GetExitCodeProcess(process, &mut exit_code) } != 0;
+        unsafe { CloseHandle(process) };
+        queried && exit_code == STILL_ACTIVE as u32
+    }
+
+    pub fn terminate_pid(pid: u32) -> io::Result<()> {
+        let process = unsafe { OpenProcess(PROCESS_TERMINATE, 0, pid) };
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let result = check(unsafe { TerminateProcess(process, 1) });
+        unsafe { CloseHandle(process) };
+        result
+    }
+
+    fn check(result: windows_sys::core::BOOL) -> io::Result<()> {
+        if result != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Running as opposed to gone or a zombie nobody has reaped yet
+    fn running(pid: u32) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+            !stat
+                .rsplit(')')
+                .next()
+                .is_some_and(|rest| rest.trim_start().starts_with('Z'))
+        })
+    }
+
+    #[tokio::test]
+    async fn terminate_kills_processes_the_executor_started() {
+        // The shell stands in for an executor wrapper that starts the agent
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 30 & echo $!; wait"])
+            .stdout(std::process::Stdio::piped());
+        configure(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        let tree = ProcessTree::attach(&child).unwrap();
+
+        let mut stdout = tokio::io::BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        tokio::io::AsyncBufReadExt::read_line(&mut stdout, &mut line)
+            .await
+            .unwrap();
+        let grandchild: u32 = line.trim().parse().unwrap();
+        assert!(running(grandchild));
+
+        tree.terminate().unwrap();
+        child.wait().await.unwrap();
+        for _ in 0..50 {
+            if !running(grandchild) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("grandchild {} survived terminate", grandchild);
+    }
+}
@@ -0,0 +1,238 @@
+//! Picking the nearest of several arps servers. With `--relay`, the control
+//! port of `--server-addr` and of every relay is timed at startup and every
+//! `--relay-probe-interval` seconds, and the client registers with the one
+//! that answered fastest. A new pick is used from the next (re)connection
+//! on, so live sessions aren't cut off by a measurement.
+
+use crate::config::ClientConfig;
+use crate::resolver::Resolver;
+#[cfg(any(feature = "executors", test))]
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "executors", test))]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info};
+
+/// Connections timed per server; the fastest one counts
+const PROBE_ATTEMPTS: usize = 3;
+
+/// How long one timed connection may take before the server counts as
+/// unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The server in use is only left for one at least this much faster
+/// (relative to its own round trip), so close ones don't flap
+const SWITCH_MARGIN: f64 = 0.2;
+
+/// The last round trip measured to one server.
+type Measurement = Result<Duration, String>;
+
+/// The servers the client may register with and their round-trip times.
+pub struct Relays {
+    hosts: Vec<String>,
+    port: u16,
+    resolver: Resolver,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Index into `hosts` of the server in use
+    selected: usize,
+    /// By index into `hosts`; empty until the first probe
+    measurements: Vec<Measurement>,
+    probed_at: Option<SystemTime>,
+}
+
+impl Relays {
+    /// `--server-addr` followed by the `--relay` servers.
+    pub fn new(config: &ClientConfig) -> Self {
+        let mut hosts = vec![config.server_addr.clone()];
+        for relay in &config.relays {
+            if !hosts.contains(relay) {
+                hosts.push(relay.clone());
+            }
+        }
+        Relays {
+            hosts,
+            port: config.control_port,
+            resolver: Resolver::from_config(config),
+            state: Mutex::new(State {
+                selected: 0,
+                measurements: Vec::new(),
+                probed_at: None,
+            }),
+        }
+    }
+
+    /// The server to connect to, probed first when there is a choice and
+    /// none was measured yet.
+    pub async fn select(&self) -> String {
+        if self.hosts.len() > 1 && self.lock().probed_at.is_none() {
+            self.probe().await;
+        }
+        self.current()
+    }
+
+    /// The server in use.
+    pub fn current(&self) -> String {
+        self.hosts[self.lock().selected].clone()
+    }
+
+    /// Time every server and pick the fastest.
+    pub async fn probe(&self) {
+        let timings: Vec<_> = self
+            .hosts
+            .iter()
+            .map(|host| {
+                tokio::spawn(measure(
+                    format!("{}:{}", host, self.port),
+                    self.resolver.clone(),
+                ))
+            })
+            .collect();
+        let mut measurements = Vec::with_capacity(timings.len());
+        for timing in timings {
+            measurements.push(timing.await.unwrap_or_else(|e| Err(e.to_string())));
+        }
+        self.record(measurements);
+    }
+
+    /// Re-probe every `interval` in the background; nothing to do with a
+    /// single server or a zero interval.
+    pub fn start(self: &Arc<Self>, interval: Duration) {
+        if self.hosts.len() < 2 || interval.is_zero() {
+            return;
+        }
+        let relays = self.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                relays.probe().await;
+            }
+        });
+    }
+
+    fn record(&self, measurements: Vec<Measurement>) {
+        let mut state = self.lock();
+        let fastest = measurements
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rtt)| rtt.as_ref().ok().map(|rtt| (index, *rtt)))
+            .min_by_key(|(_, rtt)| *rtt);
+        if let Some((fastest, rtt)) = fastest {
+            let worth_switching = match measurements.get(state.selected) {
+                Some(Ok(current)) => rtt < current.mul_f64(1.0 - SWITCH_MARGIN),
+                _ => true,
+            };
+            if fastest != state.selected && worth_switching {
+                info!(
+                    "Switching to server {} ({:.1}ms round trip)",
+                    self.hosts[fastest],
+                    rtt.as_secs_f64() * 1000.0
+                );
+                state.selected = fastest;
+            }
+        } else {
+            debug!("No server answered the round-trip probe");
+        }
+        state.measurements = measurements;
+        state.probed_at = Some(SystemTime::now());
+    }
+
+    /// Each server's last round-trip time and the one in use, for
+    /// `/api/stats`.
+    #[cfg(any(feature = "executors", test))]
+    pub fn snapshot(&self) -> Value {
+        let state = self.lock();
+        let servers: Vec<Value> = self
+            .hosts
+            .iter()
+            .enumerate()
+            .map(|(index, host)| {
+                let measurement = state.measurements.get(index);
+                json!({
+                    "server": host,
+                    "selected": index == state.selected,
+                    "rtt_ms": measurement
+                        .and_then(|rtt| rtt.as_ref().ok())
+                        .map(|rtt| rtt.as_secs_f64() * 1000.0),
+                    "error": measurement.and_then(|rtt| rtt.as_ref().err()),
+                })
+            })
+            .collect();
+        json!({
+            "selected": self.hosts[state.selected],
+            "probed_at": state.probed_at.map(|at| {
+                at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+            }),
+            "servers": servers,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The quickest of [`PROBE_ATTEMPTS`] TCP handshakes with `addr`.
+async fn measure(addr: String, resolver: Resolver) -> Measurement {
+    let mut fastest: Option<Duration> = None;
+    for _ in 0..PROBE_ATTEMPTS {
+        let started = Instant::now();
+        match tokio::time::timeout(PROBE_TIMEOUT, resolver.connect(&addr)).await {
+            Ok(Ok(_)) => {
+                let rtt = started.elapsed();
+                fastest = Some(fastest.map_or(rtt, |fastest| fastest.min(rtt)));
+            }
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("no answer within {}s", PROBE_TIMEOUT.as_secs())),
+        }
+    }
+    fastest.ok_or_else(|| "not probed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn the_fastest_reachable_server_is_picked() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+        // Nothing listens on 127.0.0.2, so it refuses
+        let config = ClientConfig::parse_from([
+            "arpc",
+            "--server-addr",
+            "127.0.0.2",
+            "--relay",
+            "127.0.0.1",
+            "--control-port",
+            &port,
+        ]);
+        let relays = Relays::new(&config);
+        assert_eq!(relays.current(), "127.0.0.2");
+
+        assert_eq!(relays.select().await, "127.0.0.1");
+        let snapshot = relays.snapshot();
+        assert_eq!(snapshot["selected"], "127.0.0.1");
+        assert!(snapshot["servers"][0]["error"].is_string());
+        assert!(snapshot["servers"][1]["rtt_ms"].is_f64());
+        assert_eq!(snapshot["servers"][1]["selected"], true);
+
+        // Slightly faster isn't worth a switch, much faster is
+        let ms = Duration::from_millis;
+        relays.record(vec![Ok(ms(45)), Ok(ms(50))]);
+        assert_eq!(relays.current(), "127.0.0.1");
+        relays.record(vec![Ok(ms(10)), Ok(ms(50))]);
+        assert_eq!(relays.current(), "127.0.0.2");
+        // Nobody answering keeps the current pick
+        relays.record(vec![Err("refused".into()), Err("refused".into())]);
+        assert_eq!(relays.current(), "127.0.0.2");
+    }
+}
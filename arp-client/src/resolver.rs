@@ -0,0 +1,246 @@
+use crate::config::{ClientConfig, ResolveSpec};
+#[cfg(feature = "dns")]
+use hickory_resolver::TokioAsyncResolver;
+#[cfg(feature = "dns")]
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+/// Looks up server addresses, taking the `--resolve` overrides before DNS.
+/// Hosts go to the `--dns-server`s when there are any, whose answers are
+/// cached for as long as their TTL allows, and to the system resolver
+/// otherwise. Clones share the same overrides and cache.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    overrides: Arc<HashMap<(String, u16), IpAddr>>,
+    #[cfg(feature = "dns")]
+    dns: Option<TokioAsyncResolver>,
+}
+
+impl Resolver {
+    pub fn from_config(config: &ClientConfig) -> Self {
+        #[cfg(feature = "dns")]
+        return Self::new(&config.resolves).with_dns_servers(&config.dns_servers);
+        #[cfg(not(feature = "dns"))]
+        Self::new(&config.resolves)
+    }
+
+    pub fn new(specs: &[ResolveSpec]) -> Self {
+        Resolver {
+            overrides: Arc::new(
+                specs
+                    .iter()
+                    .map(|spec| ((spec.host.clone(), spec.port), spec.ip))
+                    .collect(),
+            ),
+            #[cfg(feature = "dns")]
+            dns: None,
+        }
+    }
+
+    /// Send DNS queries to `servers` instead of the system resolver; no
+    /// servers keeps the system resolver
+    #[cfg(feature = "dns")]
+    pub fn with_dns_servers(mut self, servers: &[SocketAddr]) -> Self {
+        if servers.is_empty() {
+            return self;
+        }
+        let mut config = ResolverConfig::new();
+        for server in servers {
+            config.add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+            config.add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+        }
+        self.dns = Some(TokioAsyncResolver::tokio(config, ResolverOpts::default()));
+        self
+    }
+
+    /// Where `addr` (`host:port`) was pinned by `--resolve`, if it was
+    fn pinned(&self, addr: &str) -> Option<SocketAddr> {
+        let (host, port) = split_host_port(addr)?;
+        let ip = self.overrides.get(&(host.to_ascii_lowercase(), port))?;
+        Some(SocketAddr::new(*ip, port))
+    }
+
+    /// The addresses `addr` resolves to
+    pub async fn lookup(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(pinned) = self.pinned(addr) {
+            return Ok(vec![pinned]);
+        }
+        #[cfg(feature = "dns")]
+        if let Some(dns) = &self.dns
+            && let Some((host, port)) = split_host_port(addr)
+            && host.parse::<IpAddr>().is_err()
+        {
+            let ips = dns
+                .lookup_ip(host)
+                .await
+                .map_err(|e| io::Error::other(format!("failed to resolve {}: {}", host, e)))?;
+            return Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect());
+        }
+        Ok(tokio::net::lookup_host(addr).await?.collect())
+    }
+
+    /// Open a TCP connection to `addr`, trying its addresses in turn
+    pub async fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for candidate in self.lookup(addr).await? {
+            match TcpStream::connect(candidate).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} resolved to no addresses", addr),
+            )
+        }))
+    }
+}
+
+/// Host and port of `host:port`, without the brackets of an IPv6 host
+fn split_host_port(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some((host, port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn pinned_hosts_skip_dns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let spec: ResolveSpec = format!("Server.invalid:{}=127.0.0.1", port)
+            .parse()
+            .unwrap();
+        let resolver = Resolver::new(&[spec]);
+
+        let addr = format!("server.invalid:{}", port);
+        assert_eq!(
+            resolver.lookup(&addr).await.unwrap(),
+            vec![listener.local_addr().unwrap()]
+        );
+        resolver.connect(&addr).await.unwrap();
+        // IP addresses need no lookup
+        assert_eq!(
+            resolver.lookup("127.0.0.2:80").await.unwrap(),
+            vec!["127.0.0.2:80".parse().unwrap()]
+        );
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn dns_servers_answer_other_hosts_and_are_cached() {
+        use hickory_resolver::proto::op::{Message, MessageType};
+        use hickory_resolver::proto::rr::rdata::A;
+        use hickory_resolver::proto::rr::{RData, Record, RecordType};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::net::UdpSocket;
+
+        // Answers every A query with 192.0.2.7 for 300 seconds
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dns_server = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(request.op_code())
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(request.queries().to_vec());
+                for query in request.queries() {
+                    if query.query_type() == RecordType::A {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        response.add_answer(Record::from_rdata(
+                            query.name().clone(),
+                            300,
+                            RData::A(A::new(192, 0, 2, 7)),
+                        ));
+                    }
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+
+        let spec: ResolveSpec = "server.example.net:443=127.0.0.1".parse().unwrap();
+        let resolver = Resolver::new(&[spec]).with_dns_servers(&[dns_server]);
+
+        assert_eq!(
+            resolver.lookup("server.example.net:443").await.unwrap(),
+            vec!["127.0.0.1:443".parse().unwrap()]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+        // Only the pinned port is overridden
+        assert_eq!(
+            resolver.lookup("server.example.net:1").await.unwrap(),
+            vec!["192.0.2.7:1".parse().unwrap()]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        // Within the TTL the cached answer is used, by clones too
+        assert_eq!(
+            resolver
+                .clone()
+                .lookup("server.example.net:2")
+                .await
+                .unwrap(),
+            vec!["192.0.2.7:2".parse().unwrap()]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dns_servers_default_to_port_53() {
+        use clap::Parser;
+
+        let config = ClientConfig::try_parse_from([
+            "arpc",
+            "--dns-server",
+            "192.0.2.53",
+            "--dns-server",
+            "[2001:db8::53]:5353",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.dns_servers,
+            [
+                "192.0.2.53:53".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::53]:5353".parse().unwrap()
+            ]
+        );
+        assert!(ClientConfig::try_parse_from(["arpc", "--dns-server", "ns1.example.net"]).is_err());
+    }
+
+    #[test]
+    fn resolve_specs_need_a_host_port_and_ip() {
+        let spec: ResolveSpec = "[::1]:443=[2001:db8::1]".parse().unwrap();
+        assert_eq!((spec.host.as_str(), spec.port), ("::1", 443));
+        assert_eq!(spec.ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        for invalid in [
+            "example.com=1.2.3.4",
+            "example.com:x=1.2.3.4",
+            ":443=1.2.3.4",
+        ] {
+            assert!(invalid.parse::<ResolveSpec>().is_err(), "{}", invalid);
+        }
+        assert!(
+            "example.com:443=nope"
+                .parse::<ResolveSpec>()
+                .unwrap_err()
+                .contains("invalid IP")
+        );
+    }
+}
@@ -1,9 +1,17 @@
-use anyhow::Result;
-use common::http::{HttpMethod, HttpRequest, HttpResponse};
+#[cfg(feature = "dashboard")]
+use crate::metrics::RouteMetrics;
+use anyhow::{Result, anyhow};
+use common::http::{self, HttpMethod, HttpRequest, HttpResponse, json_error};
+use futures_util::FutureExt;
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tracing::warn;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, warn};
 
 /// Handler context containing request and connection info
 pub struct HandlerContext {
@@ -23,95 +31,187 @@ pub type Handler = Arc<
         + Sync,
 >;
 
+/// How long a route's handler may run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RouteTimeout {
+    /// The router's default timeout
+    Default,
+    #[cfg(any(feature = "mcp", test))]
+    After(Duration),
+    /// Streaming routes run for as long as the connection lasts
+    Never,
+}
+
 /// Route definition
 struct Route {
     method: Option<HttpMethod>,
     path_pattern: String,
     handler: Handler,
+    timeout: RouteTimeout,
+    /// Method and pattern the route's metrics are kept under
+    name: String,
+    /// Names the matched params are stored under in `path_params`
+    param_names: Vec<String>,
 }
 
 impl Route {
-    fn matches(&self, method: &HttpMethod, path: &str) -> Option<HashMap<String, String>> {
-        // Check method
-        if let Some(ref route_method) = self.method {
-            if route_method != method {
-                return None;
-            }
+    fn timeout(&self, default: Option<Duration>) -> Option<Duration> {
+        match self.timeout {
+            RouteTimeout::Default => default,
+            #[cfg(any(feature = "mcp", test))]
+            RouteTimeout::After(timeout) => Some(timeout),
+            RouteTimeout::Never => None,
         }
+    }
 
-        // Simple path matching (exact match or wildcard)
-        if self.path_pattern == path {
-            return Some(HashMap::new());
-        }
+    fn accepts(&self, method: &HttpMethod) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|route_method| route_method == method)
+    }
+}
 
-        // Check for path parameters (e.g., /file/{path})
-        let pattern_parts: Vec<&str> = self.path_pattern.split('/').collect();
-        let path_parts: Vec<&str> = path.split('/').collect();
+/// Names of a pattern's `{param}` and `{*wildcard}` segments, in order
+fn param_names(pattern: &str) -> Vec<String> {
+    pattern_segments(pattern)
+        .filter_map(|segment| match segment {
+            Segment::Literal(_) => None,
+            Segment::Param(name) | Segment::Wildcard(name) => Some(name.to_string()),
+        })
+        .collect()
+}
 
-        let mut params = HashMap::new();
+/// One `/`-separated part of a route pattern
+enum Segment<'a> {
+    Literal(&'a str),
+    /// `{name}` matches any single part
+    Param(&'a str),
+    /// `{*name}` matches the rest of the path, possibly nothing
+    Wildcard(&'a str),
+}
 
-        // Check if pattern contains wildcard parameter (e.g., {*path})
-        let mut wildcard_index = None;
-        for (i, pattern_part) in pattern_parts.iter().enumerate() {
-            if pattern_part.starts_with("{*") && pattern_part.ends_with('}') {
-                wildcard_index = Some(i);
-                break;
-            }
+/// Segments of a pattern up to and including its first wildcard
+fn pattern_segments(pattern: &str) -> impl Iterator<Item = Segment<'_>> {
+    let mut ended = false;
+    pattern.split('/').map_while(move |part| {
+        if ended {
+            return None;
         }
+        Some(
+            if let Some(name) = part.strip_prefix("{*").and_then(|p| p.strip_suffix('}')) {
+                ended = true;
+                Segment::Wildcard(name)
+            } else if let Some(name) = part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Segment::Param(name)
+            } else {
+                Segment::Literal(part)
+            },
+        )
+    })
+}
 
-        if let Some(wildcard_idx) = wildcard_index {
-            // Handle wildcard matching
-            // Pattern must have at least as many parts before wildcard as path has
-            if path_parts.len() < wildcard_idx {
-                return None;
-            }
+/// Trie of route patterns over path segments, built once from the route
+/// table. Where several routes match a path, the one registered first wins,
+/// exactly as with a scan of the table in order.
+struct RouteNode {
+    literals: HashMap<String, RouteNode>,
+    param: Option<Box<RouteNode>>,
+    /// Routes whose pattern ends at this node
+    routes: Vec<usize>,
+    /// Routes whose pattern ends in a wildcard at this node
+    wildcards: Vec<usize>,
+    /// Lowest route index in this subtree, to skip subtrees that can't win
+    first_route: usize,
+}
 
-            // Match all parts before the wildcard
-            for i in 0..wildcard_idx {
-                let pattern_part = pattern_parts[i];
-                let path_part = path_parts[i];
-
-                if pattern_part.starts_with('{') && pattern_part.ends_with('}') {
-                    // Regular parameter
-                    let param_name = &pattern_part[1..pattern_part.len() - 1];
-                    params.insert(param_name.to_string(), path_part.to_string());
-                } else if pattern_part != path_part {
-                    return None;
-                }
-            }
+/// The winning route's index and the values of its params, in order
+type RouteMatch = (usize, Vec<String>);
 
-            // Capture remaining path as wildcard parameter
-            let wildcard_pattern = pattern_parts[wildcard_idx];
-            let param_name = &wildcard_pattern[2..wildcard_pattern.len() - 1]; // Remove {* and }
+impl Default for RouteNode {
+    fn default() -> Self {
+        RouteNode {
+            literals: HashMap::new(),
+            param: None,
+            routes: Vec::new(),
+            wildcards: Vec::new(),
+            first_route: usize::MAX,
+        }
+    }
+}
 
-            // Join remaining path parts
-            let remaining_path = if wildcard_idx < path_parts.len() {
-                path_parts[wildcard_idx..].join("/")
-            } else {
-                String::new()
-            };
+impl RouteNode {
+    fn build(routes: &[Route]) -> Self {
+        let mut root = RouteNode::default();
+        for (index, route) in routes.iter().enumerate() {
+            let mut node = &mut root;
+            node.first_route = node.first_route.min(index);
+            for segment in pattern_segments(&route.path_pattern) {
+                node = match segment {
+                    Segment::Literal(part) => node.literals.entry(part.to_string()).or_default(),
+                    Segment::Param(_) => node.param.get_or_insert_with(Default::default),
+                    Segment::Wildcard(_) => {
+                        node.wildcards.push(index);
+                        break;
+                    }
+                };
+                node.first_route = node.first_route.min(index);
+            }
+            if !node.wildcards.contains(&index) {
+                node.routes.push(index);
+            }
+        }
+        root
+    }
 
-            params.insert(param_name.to_string(), remaining_path);
+    /// Find the first route accepting `path`
+    fn find(&self, path: &str, accepts: &impl Fn(usize) -> bool) -> Option<RouteMatch> {
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut best = None;
+        self.search(&parts, 0, &mut Vec::new(), accepts, &mut best);
+        best
+    }
 
-            return Some(params);
+    fn search<'p>(
+        &self,
+        parts: &[&'p str],
+        depth: usize,
+        captures: &mut Vec<&'p str>,
+        accepts: &impl Fn(usize) -> bool,
+        best: &mut Option<RouteMatch>,
+    ) {
+        let beats = |index: usize, best: &Option<RouteMatch>| {
+            best.as_ref().is_none_or(|(current, _)| index < *current)
+        };
+        if !beats(self.first_route, best) {
+            return;
         }
 
-        // Regular matching without wildcard (original logic)
-        if pattern_parts.len() != path_parts.len() {
-            return None;
+        // Indexes are ascending, so the first accepted one is this node's best
+        if let Some(&index) = self.wildcards.iter().find(|&&index| accepts(index))
+            && beats(index, best)
+        {
+            let mut values: Vec<String> = captures.iter().map(|value| value.to_string()).collect();
+            values.push(parts[depth..].join("/"));
+            *best = Some((index, values));
         }
 
-        for (pattern_part, path_part) in pattern_parts.iter().zip(path_parts.iter()) {
-            if pattern_part.starts_with('{') && pattern_part.ends_with('}') {
-                // Extract parameter name
-                let param_name = &pattern_part[1..pattern_part.len() - 1];
-                params.insert(param_name.to_string(), path_part.to_string());
-            } else if pattern_part != path_part {
-                return None;
+        let Some(&part) = parts.get(depth) else {
+            if let Some(&index) = self.routes.iter().find(|&&index| accepts(index))
+                && beats(index, best)
+            {
+                *best = Some((index, captures.iter().map(|v| v.to_string()).collect()));
             }
-        }
+            return;
+        };
 
-        Some(params)
+        if let Some(child) = self.literals.get(part) {
+            child.search(parts, depth + 1, captures, accepts, best);
+        }
+        if let Some(child) = &self.param {
+            captures.push(part);
+            child.search(parts, depth + 1, captures, accepts, best);
+            captures.pop();
+        }
     }
 }
 
@@ -119,21 +219,111 @@ impl Route {
 #[derive(Clone)]
 pub struct Router {
     routes: Arc<Vec<Route>>,
+    tree: Arc<RouteNode>,
+    default_timeout: Option<Duration>,
+    slow_threshold: Option<Duration>,
+    #[cfg(feature = "dashboard")]
+    metrics: Arc<RouteMetrics>,
 }
 
 /// Builder for constructing a Router
 pub struct RouterBuilder {
     routes: Vec<Route>,
+    default_timeout: Option<Duration>,
+    slow_threshold: Option<Duration>,
+    #[cfg(feature = "dashboard")]
+    metrics: Arc<RouteMetrics>,
+}
+
+/// Options of a route that was just added
+pub struct RouteOptions<'a> {
+    // Only sessions, filesystem, LSP and MCP routes have options to set
+    #[cfg_attr(
+        not(any(
+            feature = "executors",
+            feature = "fs",
+            feature = "lsp",
+            feature = "mcp",
+            test
+        )),
+        allow(dead_code)
+    )]
+    route: &'a mut Route,
+}
+
+impl RouteOptions<'_> {
+    /// Answer with 504 when the handler runs longer than `timeout`, instead
+    /// of the router's default
+    #[cfg(any(feature = "mcp", test))]
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.route.timeout = RouteTimeout::After(timeout);
+        self
+    }
+
+    /// Never time the handler out; for routes that stream for as long as the
+    /// client stays connected
+    #[cfg(any(feature = "executors", feature = "fs", feature = "lsp", test))]
+    pub fn streaming(self) -> Self {
+        self.route.timeout = RouteTimeout::Never;
+        self
+    }
 }
 
 impl RouterBuilder {
     /// Create a new router builder
     pub fn new() -> Self {
-        RouterBuilder { routes: Vec::new() }
+        RouterBuilder {
+            routes: Vec::new(),
+            default_timeout: None,
+            slow_threshold: None,
+            #[cfg(feature = "dashboard")]
+            metrics: Arc::new(RouteMetrics::new()),
+        }
+    }
+
+    /// Time out handlers of routes without their own timeout after
+    /// `timeout`; `None` lets them run indefinitely
+    pub fn default_timeout(&mut self, timeout: Option<Duration>) {
+        self.default_timeout = timeout;
+    }
+
+    /// Log a warning for requests to non-streaming routes that take longer
+    /// than `threshold`; `None` disables the warning
+    pub fn slow_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_threshold = threshold;
+    }
+
+    /// Per-route metrics the built router records into
+    #[cfg(feature = "dashboard")]
+    pub fn metrics(&self) -> Arc<RouteMetrics> {
+        self.metrics.clone()
+    }
+
+    fn push(
+        &mut self,
+        method: Option<HttpMethod>,
+        path: String,
+        handler: Handler,
+    ) -> RouteOptions<'_> {
+        let name = format!(
+            "{} {}",
+            method.as_ref().map_or("*", HttpMethod::as_str),
+            path
+        );
+        self.routes.push(Route {
+            method,
+            param_names: param_names(&path),
+            path_pattern: path,
+            handler,
+            timeout: RouteTimeout::Default,
+            name,
+        });
+        let route = self.routes.last_mut().expect("route was just added");
+        RouteOptions { route }
     }
 
     /// Add a route with any HTTP method
-    pub fn route<F, Fut>(&mut self, path: impl Into<String>, handler: F)
+    pub fn route<F, Fut>(&mut self, path: impl Into<String>, handler: F) -> RouteOptions<'_>
     where
         F: Fn(HandlerContext) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = Result<HttpResponse>> + Send + 'static,
@@ -143,15 +333,19 @@ impl RouterBuilder {
                 as std::pin::Pin<Box<dyn std::future::Future<Output = Result<HttpResponse>> + Send>>
         });
 
-        self.routes.push(Route {
-            method: None,
-            path_pattern: path.into(),
-            handler: handler_arc,
-        });
+        self.push(None, path.into(), handler_arc)
     }
 
     /// Add a GET route
-    pub fn get<F, Fut>(&mut self, path: impl Into<String>, handler: F)
+    #[cfg(any(
+        feature = "executors",
+        feature = "fs",
+        feature = "lsp",
+        feature = "dashboard",
+        feature = "mcp",
+        test
+    ))]
+    pub fn get<F, Fut>(&mut self, path: impl Into<String>, handler: F) -> RouteOptions<'_>
     where
         F: Fn(HandlerContext) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = Result<HttpResponse>> + Send + 'static,
@@ -161,15 +355,12 @@ impl RouterBuilder {
                 as std::pin::Pin<Box<dyn std::future::Future<Output = Result<HttpResponse>> + Send>>
         });
 
-        self.routes.push(Route {
-            method: Some(HttpMethod::GET),
-            path_pattern: path.into(),
-            handler: handler_arc,
-        });
+        self.push(Some(HttpMethod::GET), path.into(), handler_arc)
     }
 
     /// Add a POST route
-    pub fn post<F, Fut>(&mut self, path: impl Into<String>, handler: F)
+    #[cfg(any(feature = "executors", feature = "fs", test))]
+    pub fn post<F, Fut>(&mut self, path: impl Into<String>, handler: F) -> RouteOptions<'_>
     where
         F: Fn(HandlerContext) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = Result<HttpResponse>> + Send + 'static,
@@ -179,15 +370,12 @@ impl RouterBuilder {
                 as std::pin::Pin<Box<dyn std::future::Future<Output = Result<HttpResponse>> + Send>>
         });
 
-        self.routes.push(Route {
-            method: Some(HttpMethod::POST),
-            path_pattern: path.into(),
-            handler: handler_arc,
-        });
+        self.push(Some(HttpMethod::POST), path.into(), handler_arc)
     }
 
     /// Add a DELETE route
-    pub fn delete<F, Fut>(&mut self, path: impl Into<String>, handler: F)
+    #[cfg(feature = "executors")]
+    pub fn delete<F, Fut>(&mut self, path: impl Into<String>, handler: F) -> RouteOptions<'_>
     where
         F: Fn(HandlerContext) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = Result<HttpResponse>> + Send + 'static,
@@ -197,17 +385,18 @@ impl RouterBuilder {
                 as std::pin::Pin<Box<dyn std::future::Future<Output = Result<HttpResponse>> + Send>>
         });
 
-        self.routes.push(Route {
-            method: Some(HttpMethod::DELETE),
-            path_pattern: path.into(),
-            handler: handler_arc,
-        });
+        self.push(Some(HttpMethod::DELETE), path.into(), handler_arc)
     }
 
     /// Build the final Router
     pub fn build(self) -> Router {
         Router {
+            tree: Arc::new(RouteNode::build(&self.routes)),
             routes: Arc::new(self.routes),
+            default_timeout: self.default_timeout,
+            slow_threshold: self.slow_threshold,
+            #[cfg(feature = "dashboard")]
+            metrics: self.metrics,
         }
     }
 }
@@ -225,19 +414,56 @@ impl Router {
                 )
                 .header(
                     "Access-Control-Allow-Headers",
-                    "Content-Type, Authorization",
+                    "Content-Type, Authorization, Last-Event-ID",
                 )
                 .header("Access-Control-Max-Age", "86400")
                 .body(Vec::new()));
         }
 
-        // Find matching route
-        for route in self.routes.iter() {
-            if let Some(params) = route.matches(&ctx.request.method, &ctx.request.path) {
-                // Inject path parameters into context
-                ctx.path_params = params;
-                return (route.handler)(ctx).await;
+        // Find matching route; HEAD falls back to the GET route of the path
+        let method = &ctx.request.method;
+        let mut head_only = false;
+        let mut found = self.tree.find(&ctx.request.path, &|index| {
+            self.routes[index].accepts(method)
+        });
+        if found.is_none() && *method == HttpMethod::HEAD {
+            found = self.tree.find(&ctx.request.path, &|index| {
+                self.routes[index].method == Some(HttpMethod::GET)
+            });
+            head_only = found.is_some();
+        }
+        if let Some((index, values)) = found {
+            let route = &self.routes[index];
+            // Inject path parameters into context
+            ctx.path_params = route.param_names.iter().cloned().zip(values).collect();
+            let timeout = route.timeout(self.default_timeout);
+            let proxy_conn_id = ctx.proxy_conn_id.clone();
+            let caller = ctx.request.remote_addr().cloned();
+            let started = Instant::now();
+            let (result, status) = if head_only {
+                ctx.request.method = HttpMethod::GET;
+                run_head(&route.handler, ctx, timeout).await
+            } else {
+                run_handler(&route.handler, ctx, timeout).await
+            };
+            let elapsed = started.elapsed();
+
+            #[cfg(feature = "dashboard")]
+            self.metrics.record(&route.name, status, elapsed);
+            if route.timeout != RouteTimeout::Never
+                && let Some(threshold) = self.slow_threshold
+                && elapsed > threshold
+            {
+                warn!(
+                    "('{}') Slow request: {} from {} took {:?} (status {})",
+                    proxy_conn_id,
+                    route.name,
+                    caller.as_deref().unwrap_or("unknown caller"),
+                    elapsed,
+                    status
+                );
             }
+            return result;
         }
 
         // No route found
@@ -253,6 +479,183 @@ impl Router {
     }
 }
 
+/// Run a handler, answering 500 if it panics and 504 (after dropping it) once
+/// `timeout` has passed, unless it already sent a response. Returns the
+/// handler's result and the status of the response it sent; 200 when it
+/// wrote its own response, 500 when it failed, panicked or timed out.
+///
+/// The handler owns the connection, so a duplicate of the socket is kept to
+/// send the error after the handler (and its copy of the socket) is gone.
+async fn run_handler(
+    handler: &Handler,
+    ctx: HandlerContext,
+    timeout: Option<Duration>,
+) -> (Result<HttpResponse>, u16) {
+    let (ctx, reply) = match duplicate_stream(ctx) {
+        Ok(duplicated) => duplicated,
+        Err(e) => return (Err(e), 500),
+    };
+
+    let proxy_conn_id = ctx.proxy_conn_id.clone();
+    let route = format!("{} {}", ctx.request.method.as_str(), ctx.request.path);
+    let sent = Arc::new(AtomicU16::new(0));
+    let guarded = AssertUnwindSafe(http::record_status(sent.clone(), async move {
+        handler(ctx).await
+    }))
+    .catch_unwind();
+    let outcome = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, guarded).await,
+        None => Ok(guarded.await),
+    };
+
+    let sent = match sent.load(Ordering::SeqCst) {
+        0 => None,
+        status => Some(status),
+    };
+    let (status, message) = match outcome {
+        Ok(Ok(result)) => {
+            let status = match (&result, sent) {
+                (_, Some(status)) => status,
+                (Ok(_), None) => 200,
+                (Err(_), None) => 500,
+            };
+            return (result, status);
+        }
+        Ok(Err(panic)) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!(
+                "('{}') Handler for {} panicked: {}",
+                proxy_conn_id, route, message
+            );
+            (500, "Internal error while handling the request".to_string())
+        }
+        Err(_) => {
+            let timeout = timeout.unwrap_or_default();
+            warn!(
+                "('{}') Handler for {} timed out after {:?}",
+                proxy_conn_id, route, timeout
+            );
+            (
+                504,
+                format!(
+                    "Handler did not finish within {} seconds",
+                    timeout.as_secs_f64()
+                ),
+            )
+        }
+    };
+
+    // A second response would land in the middle of the one already sent
+    if sent.is_none()
+        && let Ok(mut stream) = TcpStream::from_std(reply)
+    {
+        let _ = json_error(status, message)
+            .header("Connection", "close")
+            .send(&mut stream)
+            .await;
+    }
+    (Ok(HttpResponse::ok()), status)
+}
+
+/// Answer a HEAD request with the head of the GET handler's response.
+///
+/// The handler writes to one end of a loopback connection, so its status and
+/// headers (including any `Content-Length`) are passed on whether it uses
+/// `HttpResponse` or writes the response itself. Once the head has been read
+/// the handler is dropped, which also ends streaming handlers.
+async fn run_head(
+    handler: &Handler,
+    mut ctx: HandlerContext,
+    timeout: Option<Duration>,
+) -> (Result<HttpResponse>, u16) {
+    let (handler_end, mut response) = match loopback_pair().await {
+        Ok(pair) => pair,
+        Err(e) => return (Err(e), 500),
+    };
+    let mut client = std::mem::replace(&mut ctx.stream, handler_end);
+
+    let handled = run_handler(handler, ctx, timeout);
+    tokio::pin!(handled);
+    let mut result = Ok(HttpResponse::ok());
+    let head = tokio::select! {
+        head = read_head(&mut response) => head,
+        (handled, _) = &mut handled => {
+            result = handled;
+            read_head(&mut response).await
+        }
+    };
+    let head = match head {
+        Ok(head) => head,
+        Err(e) => return (Err(e), 500),
+    };
+
+    // `HTTP/1.1 200 OK`; a handler that sent nothing failed
+    let status = std::str::from_utf8(&head)
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(500);
+    if let Err(e) = client.write_all(&head).await {
+        return (Err(e.into()), status);
+    }
+    let _ = client.flush().await;
+    (result, status)
+}
+
+/// Largest response head passed on for a HEAD request
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Read up to and including the blank line ending a response head
+async fn read_head(response: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = response.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(head);
+        }
+        let searched = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = head[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            head.truncate(searched + end + 4);
+            return Ok(head);
+        }
+        if head.len() > MAX_HEAD_BYTES {
+            return Err(anyhow!(
+                "Response head is larger than {} bytes",
+                MAX_HEAD_BYTES
+            ));
+        }
+    }
+}
+
+/// Two ends of a fresh loopback TCP connection
+pub(crate) async fn loopback_pair() -> Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let connecting = TcpStream::connect(listener.local_addr()?).await?;
+    let expected = connecting.local_addr()?;
+    loop {
+        let (accepted, peer) = listener.accept().await?;
+        // Anything else that raced to the port is turned away
+        if peer == expected {
+            return Ok((accepted, connecting));
+        }
+    }
+}
+
+/// Keep a second handle on the connection's socket for answering after the
+/// handler is gone
+fn duplicate_stream(mut ctx: HandlerContext) -> Result<(HandlerContext, std::net::TcpStream)> {
+    let socket = ctx.stream.into_std()?;
+    let reply = socket.try_clone()?;
+    ctx.stream = TcpStream::from_std(socket)?;
+    Ok((ctx, reply))
+}
+
 impl Default for Router {
     fn default() -> Self {
         RouterBuilder::new().build()
@@ -264,3 +667,307 @@ impl Default for RouterBuilder {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::http::Params;
+
+    /// Route a GET for `path` through `router` and return what the client read
+    async fn request(router: &Router, path: &str) -> String {
+        request_with(router, HttpMethod::GET, path).await
+    }
+
+    async fn request_with(router: &Router, method: HttpMethod, path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let ctx = HandlerContext {
+            request: HttpRequest {
+                method,
+                path: path.to_string(),
+                query_params: Params::new(),
+                headers: Params::new(),
+                body: Vec::new(),
+                trailers: Params::new(),
+            },
+            stream,
+            proxy_conn_id: "test".to_string(),
+            path_params: HashMap::new(),
+        };
+        router.handle(ctx).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn first_registered_route_wins() {
+        let handler = |_ctx: HandlerContext| async move { Ok(HttpResponse::ok()) };
+        let mut builder = RouterBuilder::new();
+        builder.get("/api/fs", handler);
+        builder.get("/api/fs/{*path}", handler);
+        builder.post("/api/sessions/{session_id}/cancel", handler);
+        builder.get("/api/sessions/{id}", handler);
+        builder.get("/api/sessions/latest", handler);
+        builder.route("/api/{agent}/{session_id}/cancel", handler);
+        let router = builder.build();
+
+        let find = |method: HttpMethod, path: &str| {
+            let (index, values) = router
+                .tree
+                .find(path, &|index| router.routes[index].accepts(&method))?;
+            let params: HashMap<String, String> = router.routes[index]
+                .param_names
+                .iter()
+                .cloned()
+                .zip(values)
+                .collect();
+            Some((index, params))
+        };
+        let param =
+            |name: &str, value: &str| HashMap::from([(name.to_string(), value.to_string())]);
+
+        assert_eq!(find(HttpMethod::GET, "/api/fs"), Some((0, HashMap::new())));
+        assert_eq!(
+            find(HttpMethod::GET, "/api/fs/"),
+            Some((1, param("path", "")))
+        );
+        assert_eq!(
+            find(HttpMethod::GET, "/api/fs/src/main.rs"),
+            Some((1, param("path", "src/main.rs")))
+        );
+        assert_eq!(find(HttpMethod::POST, "/api/fs/x"), None);
+        // The param route was registered before the literal one
+        assert_eq!(
+            find(HttpMethod::GET, "/api/sessions/latest"),
+            Some((3, param("id", "latest")))
+        );
+        // A method mismatch falls through to a later route
+        let (index, params) = find(HttpMethod::GET, "/api/sessions/abc/cancel").unwrap();
+        assert_eq!(index, 5);
+        assert_eq!(params["agent"], "sessions");
+        assert_eq!(params["session_id"], "abc");
+        assert_eq!(find(HttpMethod::DELETE, "/api/sessions/abc/cancel/x"), None);
+    }
+
+    /// The matcher the trie replaced: every route, in registration order
+    fn linear_find(routes: &[Route], method: &HttpMethod, path: &str) -> Option<RouteMatch> {
+        let parts: Vec<&str> = path.split('/').collect();
+        'routes: for (index, route) in routes.iter().enumerate() {
+            if !route.accepts(method) {
+                continue;
+            }
+            let mut values = Vec::new();
+            let mut depth = 0;
+            for segment in pattern_segments(&route.path_pattern) {
+                match segment {
+                    Segment::Wildcard(_) => {
+                        values.push(parts[depth..].join("/"));
+                        return Some((index, values));
+                    }
+                    Segment::Literal(literal) if parts.get(depth) == Some(&literal) => {}
+                    Segment::Param(_) if depth < parts.len() => {
+                        values.push(parts[depth].to_string())
+                    }
+                    _ => continue 'routes,
+                }
+                depth += 1;
+            }
+            if depth == parts.len() {
+                return Some((index, values));
+            }
+        }
+        None
+    }
+
+    /// A router with `count` routes shaped like the client API, and paths
+    /// that hit routes all over the table or miss it
+    fn route_table(count: usize) -> (Router, Vec<(HttpMethod, String)>) {
+        let handler = |_ctx: HandlerContext| async move { Ok(HttpResponse::ok()) };
+        let mut builder = RouterBuilder::new();
+        for i in 0..count {
+            match i % 4 {
+                0 => builder.get(format!("/api/r{}/items", i), handler),
+                1 => builder.post(format!("/api/r{}/items/{{id}}", i), handler),
+                2 => builder.get(format!("/api/r{}/fs/{{*path}}", i), handler),
+                _ => builder.route(format!("/api/{{agent}}/r{}/cancel", i), handler),
+            };
+        }
+        let mut paths = Vec::new();
+        for i in (0..count).step_by((count / 10).max(1)) {
+            paths.push((HttpMethod::GET, format!("/api/r{}/items", i)));
+            paths.push((HttpMethod::POST, format!("/api/r{}/items/42", i)));
+            paths.push((HttpMethod::GET, format!("/api/r{}/fs/src/main.rs", i)));
+            paths.push((HttpMethod::DELETE, format!("/api/claude/r{}/cancel", i)));
+        }
+        paths.push((HttpMethod::GET, "/api/missing".to_string()));
+        (builder.build(), paths)
+    }
+
+    #[test]
+    fn trie_matches_like_a_linear_scan() {
+        let (router, paths) = route_table(100);
+        for (method, path) in &paths {
+            assert_eq!(
+                router
+                    .tree
+                    .find(path, &|index| router.routes[index].accepts(method)),
+                linear_find(&router.routes, method, path),
+                "{} {}",
+                method.as_str(),
+                path
+            );
+        }
+    }
+
+    /// Time per lookup of the trie against a linear scan:
+    /// `cargo test --release -p arpc route_lookup_times -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn route_lookup_times() {
+        const ROUNDS: u32 = 2000;
+        println!("{:>7} {:>10} {:>10}", "routes", "linear", "trie");
+        for count in [20, 100, 400, 1000] {
+            let (router, paths) = route_table(count);
+            let time = |find: &dyn Fn(&HttpMethod, &str) -> Option<RouteMatch>| {
+                let started = std::time::Instant::now();
+                for _ in 0..ROUNDS {
+                    for (method, path) in &paths {
+                        std::hint::black_box(find(method, path));
+                    }
+                }
+                started.elapsed() / (ROUNDS * paths.len() as u32)
+            };
+            let linear = time(&|method, path| linear_find(&router.routes, method, path));
+            let trie = time(&|method, path| {
+                router
+                    .tree
+                    .find(path, &|index| router.routes[index].accepts(method))
+            });
+            println!("{:>7} {:>10.1?} {:>10.1?}", count, linear, trie);
+        }
+    }
+
+    #[tokio::test]
+    async fn head_requests_get_the_head_of_the_get_response() {
+        let mut builder = RouterBuilder::new();
+        builder.get("/json", |mut ctx: HandlerContext| async move {
+            assert_eq!(ctx.request.method, HttpMethod::GET);
+            HttpResponse::ok()
+                .text("hello")
+                .send(&mut ctx.stream)
+                .await?;
+            Ok(HttpResponse::ok())
+        });
+        builder
+            .get("/events", |mut ctx: HandlerContext| async move {
+                ctx.stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: 1\n\n",
+                    )
+                    .await?;
+                std::future::pending::<()>().await;
+                Ok(HttpResponse::ok())
+            })
+            .streaming();
+        let router = builder.build();
+
+        let response = request_with(&router, HttpMethod::HEAD, "/json").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = request_with(&router, HttpMethod::HEAD, "/events").await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_handlers_time_out_unless_streaming() {
+        let slow = |mut ctx: HandlerContext| async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            ctx.stream.write_all(b"done").await?;
+            Ok(HttpResponse::ok())
+        };
+        let mut builder = RouterBuilder::new();
+        builder.default_timeout(Some(Duration::from_millis(50)));
+        builder.get("/slow", slow);
+        builder
+            .get("/patient", slow)
+            .timeout(Duration::from_secs(5));
+        builder.get("/stream", slow).streaming();
+        let router = builder.build();
+
+        let response = request(&router, "/slow").await;
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout"));
+        assert!(!response.contains("done"));
+        assert_eq!(request(&router, "/patient").await, "done");
+        assert_eq!(request(&router, "/stream").await, "done");
+    }
+
+    #[tokio::test]
+    async fn failures_after_a_response_add_no_second_one() {
+        let mut builder = RouterBuilder::new();
+        builder.default_timeout(Some(Duration::from_millis(50)));
+        builder.get("/panic", |mut ctx: HandlerContext| async move {
+            HttpResponse::ok()
+                .text("sent")
+                .send(&mut ctx.stream)
+                .await?;
+            if true {
+                panic!("handler bug");
+            }
+            Ok(HttpResponse::ok())
+        });
+        builder.get("/slow", |mut ctx: HandlerContext| async move {
+            HttpResponse::ok()
+                .text("sent")
+                .send(&mut ctx.stream)
+                .await?;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(HttpResponse::ok())
+        });
+        let router = builder.build();
+
+        for path in ["/panic", "/slow"] {
+            let response = request(&router, path).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\nsent"), "{}", response);
+        }
+    }
+
+    #[cfg(feature = "dashboard")]
+    #[tokio::test]
+    async fn panics_and_sent_statuses_are_counted() {
+        let mut builder = RouterBuilder::new();
+        builder.get("/panic", |_ctx: HandlerContext| async move {
+            if true {
+                panic!("handler bug");
+            }
+            Ok(HttpResponse::ok())
+        });
+        builder.get("/missing", |mut ctx: HandlerContext| async move {
+            json_error(404, "missing").send(&mut ctx.stream).await?;
+            Ok(HttpResponse::ok())
+        });
+        let metrics = builder.metrics();
+        let router = builder.build();
+
+        let response = request(&router, "/panic").await;
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(response.contains("Internal error while handling the request"));
+
+        request(&router, "/missing").await;
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot["routes"][0]["route"], "GET /missing");
+        assert_eq!(snapshot["routes"][0]["client_errors"], 1);
+        assert_eq!(snapshot["routes"][1]["route"], "GET /panic");
+        assert_eq!(snapshot["routes"][1]["server_errors"], 1);
+    }
+}
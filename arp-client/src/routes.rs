@@ -1,31 +1,65 @@
+#[cfg(feature = "executors")]
 use crate::agentx::claude_routes::{
     register_claude_project_routes, register_claude_session_routes,
 };
+#[cfg(feature = "executors")]
 use crate::agentx::codex_routes::{register_codex_project_routes, register_codex_session_routes};
+#[cfg(feature = "executors")]
 use crate::agentx::gemini_routes::{
     register_gemini_project_routes, register_gemini_session_routes,
 };
+#[cfg(feature = "executors")]
+use crate::agentx::routes_common::register_unified_project_routes;
 use crate::handlers::{self, HandlerState};
-use crate::router::{Router, RouterBuilder};
-
-/// Build and return the router with all application routes registered.
-pub fn build_router(state: HandlerState) -> Router {
-    let mut builder = RouterBuilder::new();
-
-    register_session_routes(&mut builder, &state);
-    register_claude_project_routes(&mut builder);
-    register_claude_session_routes(&mut builder);
-    register_codex_project_routes(&mut builder);
-    register_codex_session_routes(&mut builder);
-    register_gemini_project_routes(&mut builder);
-    register_gemini_session_routes(&mut builder);
-    register_proxy_routes(&mut builder, &state);
-    builder.build()
+use crate::router::RouterBuilder;
+#[cfg(feature = "mcp")]
+use std::time::Duration;
+
+/// A health listing shouldn't wait long on an unresponsive MCP server
+#[cfg(feature = "mcp")]
+const MCP_HEALTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Register all application routes on `builder`.
+pub fn register_routes(builder: &mut RouterBuilder, state: &HandlerState) {
+    #[cfg(feature = "executors")]
+    {
+        register_session_routes(builder, state);
+        register_unified_project_routes(builder, state);
+        register_claude_project_routes(builder, state);
+        register_claude_session_routes(builder, state);
+        register_codex_project_routes(builder, state);
+        register_codex_session_routes(builder, state);
+        register_gemini_project_routes(builder, state);
+        register_gemini_session_routes(builder, state);
+        register_storage_routes(builder, state);
+        register_stats_routes(builder, state);
+    }
+    #[cfg(feature = "fs")]
+    register_fs_routes(builder, state);
+    #[cfg(feature = "mcp")]
+    register_mcp_routes(builder, state);
+    #[cfg(feature = "lsp")]
+    register_lsp_routes(builder, state);
+    #[cfg(feature = "dashboard")]
+    register_metrics_routes(builder);
+    register_proxy_routes(builder, state);
 }
 
+#[cfg(feature = "executors")]
 fn register_session_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
     // POST /api/sessions - Create new command execution session
-    router_builder.post("/api/sessions", {
+    router_builder
+        .post("/api/sessions", {
+            let state = state.clone();
+            move |ctx| {
+                let state = state.clone();
+                async move { handlers::session::handle_session(ctx, state).await }
+            }
+        })
+        .streaming();
+
+    // GET /api/sessions - List sessions held in memory with their status
+    router_builder.get("/api/sessions", {
         let state = state.clone();
         move |ctx| {
             let state = state.clone();
@@ -34,13 +68,15 @@ fn register_session_routes(router_builder: &mut RouterBuilder, state: &HandlerSt
     });
 
     // GET /api/sessions/{session_id} - Get session details or reconnect to active session
-    router_builder.get("/api/sessions/{session_id}", {
-        let state = state.clone();
-        move |ctx| {
+    router_builder
+        .get("/api/sessions/{session_id}", {
             let state = state.clone();
-            async move { handlers::session::handle_session(ctx, state).await }
-        }
-    });
+            move |ctx| {
+                let state = state.clone();
+                async move { handlers::session::handle_session(ctx, state).await }
+            }
+        })
+        .streaming();
 
     // DELETE /api/sessions/{session_id} - Cancel active session or delete historical session
     router_builder.delete("/api/sessions/{session_id}", {
@@ -60,43 +96,194 @@ fn register_session_routes(router_builder: &mut RouterBuilder, state: &HandlerSt
         }
     });
 
+    // GET /api/sessions/{session_id}/tools - Tool-call timeline extracted from the transcript
+    router_builder.get("/api/sessions/{session_id}/tools", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_session_tools(ctx, state).await }
+        }
+    });
+
+    // GET /api/sessions/{session_id}/permissions - Decisions made on the session's permission requests
+    router_builder.get("/api/sessions/{session_id}/permissions", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_permission_history(ctx, state).await }
+        }
+    });
+
+    // POST /api/sessions/{session_id}/permissions/{permission_id} - Decide an inline permission prompt
+    router_builder.post("/api/sessions/{session_id}/permissions/{permission_id}", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_permission_decision(ctx, state).await }
+        }
+    });
+
+    // POST /api/sessions/{session_id}/pr - Push the session's changes and open a GitHub pull request
+    #[cfg(feature = "github")]
+    router_builder.post("/api/sessions/{session_id}/pr", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_create_pull_request(ctx, state).await }
+        }
+    });
+
+    // POST /api/sessions/{session_id}/revert - Restore the project to its snapshot from before the run
+    router_builder.post("/api/sessions/{session_id}/revert", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_revert_session(ctx, state).await }
+        }
+    });
+
+    // GET /api/sessions/{session_id}/permissions/{permission_id}?decision= - Page confirming a decision from a chat link
+    router_builder.get("/api/sessions/{session_id}/permissions/{permission_id}", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::session::handle_permission_page(ctx, state).await }
+        }
+    });
+}
+
+#[cfg(feature = "executors")]
+fn register_storage_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
+    // GET /api/storage - Disk usage of agent history and the client data directory
+    router_builder.get("/api/storage", move |ctx| async move {
+        handlers::storage::handle_storage(ctx).await
+    });
+
+    // POST /api/storage/retention - Apply the retention rules now (?dryRun=true to preview)
+    router_builder.post("/api/storage/retention", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::storage::handle_retention(ctx, state).await }
+        }
+    });
+}
+
+#[cfg(feature = "fs")]
+fn register_fs_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
     if state.config.enable_fs {
         // GET /api/sessions/{session_id}/fs - Inspect session project root
-        router_builder.get("/api/sessions/{session_id}/fs", {
+        router_builder
+            .get("/api/sessions/{session_id}/fs", {
+                let state = state.clone();
+                move |ctx| {
+                    let state = state.clone();
+                    async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                }
+            })
+            .streaming();
+
+        // GET /api/sessions/{session_id}/fs/{*path} - Inspect directory or file under project root
+        router_builder
+            .get("/api/sessions/{session_id}/fs/{*path}", {
+                let state = state.clone();
+                move |ctx| {
+                    let state = state.clone();
+                    async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                }
+            })
+            .streaming();
+
+        // POST /api/fs/sync - Compare a path -> SHA-256 manifest and return changed files
+        router_builder.post("/api/fs/sync", {
             let state = state.clone();
             move |ctx| {
                 let state = state.clone();
-                async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                async move { handlers::sync::handle_sync(ctx, state).await }
             }
         });
 
-        // GET /api/sessions/{session_id}/fs/{*path} - Inspect directory or file under project root
-        router_builder.get("/api/sessions/{session_id}/fs/{*path}", {
+        // POST /api/fs/upload/{*path} - Upload files into a project directory
+        router_builder.post("/api/fs/upload/{*path}", {
             let state = state.clone();
             move |ctx| {
                 let state = state.clone();
-                async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                async move { handlers::upload::handle_upload(ctx, state).await }
             }
         });
 
         // GET /api/fs - Inspect project root without session
-        router_builder.get("/api/fs", {
+        router_builder
+            .get("/api/fs", {
+                let state = state.clone();
+                move |ctx| {
+                    let state = state.clone();
+                    async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                }
+            })
+            .streaming();
+
+        // GET /api/fs/{*path} - Inspect directory or file without session
+        router_builder
+            .get("/api/fs/{*path}", {
+                let state = state.clone();
+                move |ctx| {
+                    let state = state.clone();
+                    async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                }
+            })
+            .streaming();
+    }
+}
+
+#[cfg(feature = "lsp")]
+fn register_lsp_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
+    // GET /api/lsp/{server} - Upgrade to an LSP connection with a configured language server
+    router_builder
+        .get("/api/lsp/{server}", {
             let state = state.clone();
             move |ctx| {
                 let state = state.clone();
-                async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                async move { handlers::lsp::handle_lsp(ctx, state).await }
             }
-        });
+        })
+        .streaming();
+}
 
-        // GET /api/fs/{*path} - Inspect directory or file without session
-        router_builder.get("/api/fs/{*path}", {
+#[cfg(feature = "executors")]
+fn register_stats_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
+    // GET /api/stats - Session counts, uptime, executor availability, stream subscribers and server RTTs
+    router_builder.get("/api/stats", {
+        let state = state.clone();
+        move |ctx| {
+            let state = state.clone();
+            async move { handlers::stats::handle_stats(ctx, state).await }
+        }
+    });
+}
+
+#[cfg(feature = "dashboard")]
+fn register_metrics_routes(router_builder: &mut RouterBuilder) {
+    // GET /api/metrics - Request counts, error rates and latency percentiles per route
+    let metrics = router_builder.metrics();
+    router_builder.get("/api/metrics", move |ctx| {
+        let metrics = metrics.clone();
+        async move { handlers::metrics::handle_metrics(ctx, metrics).await }
+    });
+}
+
+#[cfg(feature = "mcp")]
+fn register_mcp_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
+    // GET /api/mcp/servers - List external MCP servers and their health
+    router_builder
+        .get("/api/mcp/servers", {
             let state = state.clone();
             move |ctx| {
                 let state = state.clone();
-                async move { handlers::filesystem::handle_filesystem(ctx, state).await }
+                async move { handlers::mcp::handle_list_mcp_servers(ctx, state).await }
             }
-        });
-    }
+        })
+        .timeout(MCP_HEALTH_TIMEOUT);
 }
 
 fn register_proxy_routes(router_builder: &mut RouterBuilder, state: &HandlerState) {
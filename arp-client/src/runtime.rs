@@ -0,0 +1,224 @@
+use crate::config::ClientConfig;
+#[cfg(feature = "mcp")]
+use crate::mcp::permissions::{ExpiryPolicy, SharedExpiryPolicy};
+use common::ConfigSettings;
+use serde::Serialize;
+use std::sync::Arc;
+#[cfg(feature = "mcp")]
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing_subscriber::filter::Targets;
+
+/// Changes the filter of the terminal log output
+pub type LogLevelHandle = Box<dyn Fn(Targets) -> Result<(), String> + Send + Sync>;
+
+/// How busy the client is
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Load {
+    pub active_sessions: usize,
+    pub proxy_connections: usize,
+    /// Permission prompts waiting on a person
+    pub pending_permissions: usize,
+    /// The session or proxy connection limit is reached
+    pub busy: bool,
+}
+
+/// Settings that an operator can change on a running client with a
+/// `ConfigUpdate`. They start from the command line and environment.
+pub struct RuntimeSettings {
+    pool_size: AtomicUsize,
+    max_sessions: AtomicUsize,
+    proxy_limiter: Arc<ProxyLimiter>,
+    #[cfg(feature = "mcp")]
+    expiry_policy: SharedExpiryPolicy,
+    log_level: Option<LogLevelHandle>,
+}
+
+impl RuntimeSettings {
+    pub fn new(config: &ClientConfig) -> Self {
+        RuntimeSettings {
+            pool_size: AtomicUsize::new(config.pool_size),
+            max_sessions: AtomicUsize::new(config.max_sessions),
+            proxy_limiter: Arc::new(ProxyLimiter::new(config.max_proxy_connections)),
+            #[cfg(feature = "mcp")]
+            expiry_policy: Arc::new(RwLock::new(ExpiryPolicy::from_env())),
+            log_level: None,
+        }
+    }
+
+    pub fn with_log_level_handle(mut self, handle: LogLevelHandle) -> Self {
+        self.log_level = Some(handle);
+        self
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size.load(Ordering::Relaxed)
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn proxy_limiter(&self) -> &Arc<ProxyLimiter> {
+        &self.proxy_limiter
+    }
+
+    #[cfg(feature = "mcp")]
+    pub fn expiry_policy(&self) -> SharedExpiryPolicy {
+        self.expiry_policy.clone()
+    }
+
+    /// Apply `settings` and return the names of those that changed. Nothing
+    /// is applied when any of them is invalid.
+    pub fn apply(&self, settings: &ConfigSettings) -> Result<Vec<String>, String> {
+        let log_level = match &settings.log_level {
+            Some(level) => {
+                if self.log_level.is_none() {
+                    return Err("log level can't be changed on this client".to_string());
+                }
+                let level = level
+                    .parse::<Targets>()
+                    .map_err(|_| format!("Invalid log level '{}'", level))?;
+                Some(level)
+            }
+            None => None,
+        };
+
+        #[cfg(not(feature = "mcp"))]
+        if settings.permission_timeout_secs.is_some()
+            || settings.permission_tool_timeouts.is_some()
+            || settings.permission_auto_approve.is_some()
+        {
+            return Err("permission settings need a client built with MCP support".to_string());
+        }
+
+        let mut applied = Vec::new();
+        if let Some(pool_size) = settings.pool_size {
+            self.pool_size.store(pool_size, Ordering::Relaxed);
+            applied.push("pool_size");
+        }
+        if let Some(limit) = settings.max_proxy_connections {
+            self.proxy_limiter.set_limit(limit);
+            applied.push("max_proxy_connections");
+        }
+        if let Some(max_sessions) = settings.max_sessions {
+            self.max_sessions.store(max_sessions, Ordering::Relaxed);
+            applied.push("max_sessions");
+        }
+        if let (Some(level), Some(set_level)) = (log_level, &self.log_level) {
+            set_level(level)?;
+            applied.push("log_level");
+        }
+        #[cfg(feature = "mcp")]
+        applied.extend(
+            self.expiry_policy
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .update(settings),
+        );
+
+        Ok(applied.into_iter().map(str::to_string).collect())
+    }
+}
+
+/// Caps concurrent proxy connections; unlike a semaphore the limit can be
+/// changed while connections are open.
+pub struct ProxyLimiter {
+    limit: AtomicUsize,
+    active: AtomicUsize,
+}
+
+/// A proxy connection slot, released when dropped
+pub struct ProxyPermit {
+    limiter: Arc<ProxyLimiter>,
+}
+
+impl Drop for ProxyPermit {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ProxyLimiter {
+    /// `limit` of 0 means unlimited
+    pub fn new(limit: usize) -> Self {
+        ProxyLimiter {
+            limit: AtomicUsize::new(limit),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Lowering the limit doesn't close open connections, it only refuses new
+    /// ones until enough of them finished
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ProxyPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                let limit = self.limit();
+                (limit == 0 || active < limit).then_some(active + 1)
+            })
+            .ok()?;
+        Some(ProxyPermit {
+            limiter: self.clone(),
+        })
+    }
+
+    /// Proxy connections currently open
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn is_full(&self) -> bool {
+        let limit = self.limit();
+        limit > 0 && self.active.load(Ordering::Acquire) >= limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn config_update_applies_all_or_nothing() {
+        let config = ClientConfig::parse_from(["arpc", "--max-proxy-connections", "1"]);
+        let settings = RuntimeSettings::new(&config);
+
+        let limiter = settings.proxy_limiter().clone();
+        let permit = limiter.try_acquire().unwrap();
+        assert!(limiter.is_full() && limiter.try_acquire().is_none());
+
+        let applied = settings
+            .apply(&ConfigSettings {
+                pool_size: Some(2),
+                max_proxy_connections: Some(2),
+                max_sessions: Some(4),
+                ..ConfigSettings::default()
+            })
+            .unwrap();
+        assert_eq!(
+            applied,
+            ["pool_size", "max_proxy_connections", "max_sessions"]
+        );
+        assert_eq!((settings.pool_size(), settings.max_sessions()), (2, 4));
+        let second = limiter.try_acquire().unwrap();
+        assert!(limiter.is_full());
+        drop((permit, second));
+        assert!(!limiter.is_full());
+
+        let rejected = settings.apply(&ConfigSettings {
+            pool_size: Some(9),
+            log_level: Some("debug".to_string()),
+            ..ConfigSettings::default()
+        });
+        assert!(rejected.is_err());
+        assert_eq!(settings.pool_size(), 2);
+    }
+}
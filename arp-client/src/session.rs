@@ -1,13 +1,24 @@
+use crate::events::{ClientEvent, EventBus, OUTPUT_MILESTONE_LINES};
 use crate::executor::ExecutorKind;
+use crate::process::ProcessTree;
+use crate::redact::Redactor;
+use crate::session_records::{SessionRecord, SessionRecords};
+use dashmap::DashMap;
 use serde_json::json;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock, broadcast};
+use tokio::sync::{Mutex, RwLock, broadcast, oneshot};
 use tokio::time::{Duration, Instant};
 use tracing::{info, warn};
+#[cfg(any(feature = "mcp", test))]
 use uuid::Uuid;
 
+/// How long a cancelled executor gets to exit after being interrupted
+/// before it is killed
+const CANCEL_GRACE: Duration = Duration::from_secs(3);
+
 /// Status of a command session
 #[derive(Debug, Clone, PartialEq)]
 pub enum SessionStatus {
@@ -17,12 +28,143 @@ pub enum SessionStatus {
     Cancelled { reason: String },
 }
 
+impl SessionStatus {
+    /// Status name, exit code and error as reported to the server and to
+    /// event sinks; a non-zero exit counts as a failure
+    pub fn outcome(&self) -> (&'static str, Option<i32>, Option<String>) {
+        match self {
+            SessionStatus::Completed {
+                exit_code: Some(code),
+            } if *code != 0 => ("failed", Some(*code), None),
+            SessionStatus::Completed { exit_code } => ("completed", *exit_code, None),
+            SessionStatus::Failed { error } => ("failed", None, Some(error.clone())),
+            SessionStatus::Cancelled { reason } => ("cancelled", None, Some(reason.clone())),
+            SessionStatus::Running => ("running", None, None),
+        }
+    }
+}
+
 /// A buffered output line from command execution
 #[derive(Debug, Clone)]
 pub struct OutputLine {
     pub line_number: usize,
     pub content: String,
-    pub timestamp: Instant,
+}
+
+/// Decision on a permission prompt published in the session stream
+#[derive(Debug, Clone)]
+pub struct PermissionDecision {
+    pub approved: bool,
+    pub reason: Option<String>,
+    pub updated_input: Option<serde_json::Value>,
+    pub decided_by: Option<String>,
+}
+
+/// Published by the session manager whenever a session stops running
+#[derive(Debug, Clone)]
+pub struct SessionEnded {
+    pub session_id: String,
+    pub executor_kind: ExecutorKind,
+    pub agent_session_id: Option<String>,
+    pub project_path: Option<PathBuf>,
+    pub status: SessionStatus,
+}
+
+/// A permission prompt waiting for a decision
+pub struct PendingPermission {
+    pub tool_name: String,
+    pub requested_at: Instant,
+    /// Distinct approvers needed before the prompt is approved
+    pub required_approvals: u32,
+    /// Approvals collected so far, one per approver
+    approvals: Vec<PermissionDecision>,
+    tx: oneshot::Sender<PermissionDecision>,
+}
+
+/// Most permission decisions kept per session
+const MAX_PERMISSION_HISTORY: usize = 500;
+
+/// A decision on a permission request, kept for audits
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PermissionRecord {
+    pub permission_id: String,
+    pub tool_name: String,
+    /// `approved` or `denied`
+    pub decision: &'static str,
+    pub decided_by: Option<String>,
+    /// RFC 3339 timestamp
+    pub decided_at: String,
+}
+
+impl PermissionRecord {
+    /// A decision made now
+    pub fn now(
+        permission_id: &str,
+        tool_name: &str,
+        approved: bool,
+        decided_by: Option<String>,
+    ) -> Self {
+        PermissionRecord {
+            permission_id: permission_id.to_string(),
+            tool_name: tool_name.to_string(),
+            decision: if approved { "approved" } else { "denied" },
+            decided_by,
+            decided_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Decisions made on permission requests, per session
+#[derive(Clone, Default)]
+pub struct PermissionHistory(Arc<DashMap<String, Vec<PermissionRecord>>>);
+
+impl PermissionHistory {
+    /// Add `record` to the history of `session_id`, dropping its oldest
+    /// record when the history is full
+    pub fn record(&self, session_id: &str, record: PermissionRecord) {
+        let mut history = self.0.entry(session_id.to_string()).or_default();
+        if history.len() >= MAX_PERMISSION_HISTORY {
+            history.remove(0);
+        }
+        history.push(record);
+    }
+
+    /// Decisions made on the requests of `session_id`, oldest first
+    pub fn of(&self, session_id: &str) -> Vec<PermissionRecord> {
+        self.0
+            .get(session_id)
+            .map(|history| history.clone())
+            .unwrap_or_default()
+    }
+
+    fn forget(&self, session_id: &str) {
+        self.0.remove(session_id);
+    }
+}
+
+/// What a decision did to a pending permission prompt, see
+/// [`CommandSession::resolve_permission`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionOutcome {
+    /// The prompt is unknown, or was already decided or expired
+    NotPending,
+    /// The approver had already approved the prompt
+    AlreadyApproved,
+    /// The approval was counted and more approvers are needed
+    Awaiting { approvals: u32, required: u32 },
+    /// The prompt was approved or denied
+    Decided,
+}
+
+/// A permission prompt that has been waiting a while, see
+/// [`SessionManager::stale_permissions`]
+#[cfg(feature = "digest")]
+#[derive(Debug, Clone)]
+pub struct StalePermission {
+    pub session_id: String,
+    pub permission_id: String,
+    pub tool_name: String,
+    pub waiting: Duration,
 }
 
 /// Session data for a running command
@@ -38,7 +180,19 @@ pub struct CommandSession {
     pub broadcast_tx: broadcast::Sender<OutputLine>,
     /// Process handle for cancellation (only available while running)
     pub process_handle: Arc<Mutex<Option<tokio::process::Child>>>,
+    /// The executor and the processes it started, for cancellation
+    process_tree: Arc<Mutex<Option<ProcessTree>>>,
     pub project_path: Arc<RwLock<Option<PathBuf>>>,
+    /// Permission prompts waiting for a decision, keyed by permission ID
+    pub pending_permissions: Arc<Mutex<HashMap<String, PendingPermission>>>,
+    /// Applied to every output line before it is buffered or broadcast
+    redactor: Arc<Redactor>,
+    /// Where the end of the session is announced
+    ended_tx: Option<broadcast::Sender<SessionEnded>>,
+    /// Where lifecycle, output and permission events are published
+    events: EventBus,
+    /// Where decisions on the session's permission prompts are kept
+    permission_history: PermissionHistory,
 }
 
 impl CommandSession {
@@ -56,20 +210,101 @@ impl CommandSession {
             total_lines: Arc::new(Mutex::new(0)),
             broadcast_tx: tx,
             process_handle: Arc::new(Mutex::new(None)),
+            process_tree: Arc::new(Mutex::new(None)),
             project_path: Arc::new(RwLock::new(None)),
+            pending_permissions: Arc::new(Mutex::new(HashMap::new())),
+            redactor: Arc::new(Redactor::default()),
+            ended_tx: None,
+            events: EventBus::default(),
+            permission_history: PermissionHistory::default(),
         }
     }
 
+    /// Redact output lines with `redactor`
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Announce the end of the session on `ended_tx`
+    pub fn with_ended_notifier(mut self, ended_tx: broadcast::Sender<SessionEnded>) -> Self {
+        self.ended_tx = Some(ended_tx);
+        self
+    }
+
+    /// Publish the session's events on `events`
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Keep decisions on the session's permission prompts in `history`
+    pub fn with_permission_history(mut self, history: PermissionHistory) -> Self {
+        self.permission_history = history;
+        self
+    }
+
+    /// Leave the running state, keeping the first final status (a killed
+    /// process still reaches EOF after `mark_cancelled`); announce it once
+    async fn finish(&self, status: SessionStatus) -> bool {
+        {
+            let mut current = self.status.write().await;
+            if *current != SessionStatus::Running {
+                return false;
+            }
+            *current = status.clone();
+        }
+
+        let agent_session_id = self.get_agent_session().await.map(|(_, id)| id);
+        let project_path = self.get_project_path().await;
+        let (name, exit_code, error) = status.outcome();
+        self.events.publish(ClientEvent::SessionEnded {
+            session_id: self.session_id.clone(),
+            executor: self.executor_kind.as_str(),
+            status: name,
+            agent_session_id: agent_session_id.clone(),
+            project_path: project_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            exit_code,
+            error,
+        });
+
+        let Some(ended_tx) = &self.ended_tx else {
+            return true;
+        };
+        let _ = ended_tx.send(SessionEnded {
+            session_id: self.session_id.clone(),
+            executor_kind: self.executor_kind,
+            agent_session_id,
+            project_path,
+            status,
+        });
+        true
+    }
+
     /// Add a new output line
     pub async fn add_output(&self, content: String) {
+        let redacted = match self.redactor.apply(&content) {
+            None => return,
+            Some(Cow::Owned(redacted)) => Some(redacted),
+            Some(Cow::Borrowed(_)) => None,
+        };
+        let content = redacted.unwrap_or(content);
+
         let mut total = self.total_lines.lock().await;
         *total += 1;
         let line_number = *total;
+        if line_number == 1 || line_number % OUTPUT_MILESTONE_LINES == 0 {
+            self.events.publish(ClientEvent::SessionOutput {
+                session_id: self.session_id.clone(),
+                lines: line_number,
+            });
+        }
 
         let output_line = OutputLine {
             line_number,
             content,
-            timestamp: Instant::now(),
         };
 
         // Add to buffer
@@ -82,55 +317,79 @@ impl CommandSession {
 
     /// Mark session as completed
     pub async fn mark_completed(&self, exit_code: Option<i32>) {
-        let mut status = self.status.write().await;
-        *status = SessionStatus::Completed { exit_code };
-        info!("Session {} marked as completed", self.session_id);
+        if self.finish(SessionStatus::Completed { exit_code }).await {
+            info!("Session {} marked as completed", self.session_id);
+        }
     }
 
     /// Mark session as failed
     pub async fn mark_failed(&self, error: String) {
-        let mut status = self.status.write().await;
-        *status = SessionStatus::Failed { error };
-        warn!("Session {} marked as failed", self.session_id);
+        if self.finish(SessionStatus::Failed { error }).await {
+            warn!("Session {} marked as failed", self.session_id);
+        }
     }
 
     /// Mark session as cancelled
     pub async fn mark_cancelled(&self, reason: String) {
-        let mut status = self.status.write().await;
-        *status = SessionStatus::Cancelled { reason };
-        info!("Session {} marked as cancelled", self.session_id);
+        if self.finish(SessionStatus::Cancelled { reason }).await {
+            info!("Session {} marked as cancelled", self.session_id);
+        }
     }
 
-    /// Cancel the running process
+    /// Cancel the running process: interrupt it, then kill it and
+    /// everything it started if it has not exited within [`CANCEL_GRACE`]
     pub async fn cancel(&self) -> Result<(), String> {
         let mut process = self.process_handle.lock().await;
+        let Some(ref mut child) = *process else {
+            return Err(
+                "No process handle available (process may have already completed)".to_string(),
+            );
+        };
 
-        if let Some(ref mut child) = *process {
-            match child.kill().await {
-                Ok(_) => {
-                    info!(
-                        "Process for session {} killed successfully",
-                        self.session_id
-                    );
-                    drop(process);
-                    self.mark_cancelled("User cancelled".to_string()).await;
-                    Ok(())
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to kill process for session {}: {}",
-                        self.session_id, e
-                    );
-                    Err(format!("Failed to kill process: {}", e))
-                }
+        let tree = self.process_tree.lock().await.take();
+        let interrupted = match tree.as_ref().map(ProcessTree::interrupt) {
+            Some(Ok(())) => tokio::time::timeout(CANCEL_GRACE, child.wait())
+                .await
+                .is_ok_and(|status| status.is_ok()),
+            Some(Err(e)) => {
+                warn!(
+                    "Failed to interrupt process for session {}: {}",
+                    self.session_id, e
+                );
+                false
+            }
+            None => false,
+        };
+
+        if !interrupted {
+            if let Some(Err(e)) = tree.as_ref().map(ProcessTree::terminate) {
+                warn!(
+                    "Failed to kill process tree for session {}: {}",
+                    self.session_id, e
+                );
+            }
+            if let Err(e) = child.kill().await {
+                warn!(
+                    "Failed to kill process for session {}: {}",
+                    self.session_id, e
+                );
+                return Err(format!("Failed to kill process: {}", e));
             }
-        } else {
-            Err("No process handle available (process may have already completed)".to_string())
         }
+
+        info!(
+            "Process for session {} {}",
+            self.session_id,
+            if interrupted { "interrupted" } else { "killed" }
+        );
+        drop(process);
+        self.mark_cancelled("User cancelled".to_string()).await;
+        Ok(())
     }
 
     /// Set the process handle for this session
     pub async fn set_process_handle(&self, child: tokio::process::Child) {
+        *self.process_tree.lock().await = ProcessTree::attach(&child);
         let mut handle = self.process_handle.lock().await;
         *handle = Some(child);
     }
@@ -192,20 +451,218 @@ impl CommandSession {
         let project_path = self.project_path.read().await;
         project_path.clone()
     }
+
+    /// Publish a `permission_request` event and return its ID together with
+    /// a receiver for the decision, which is approved once
+    /// `required_approvals` distinct approvers have approved it
+    #[cfg(any(feature = "mcp", test))]
+    pub async fn request_permission(
+        &self,
+        tool_name: &str,
+        input: &serde_json::Value,
+        required_approvals: u32,
+    ) -> (String, oneshot::Receiver<PermissionDecision>) {
+        let permission_id = Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending_permissions.lock().await.insert(
+            permission_id.clone(),
+            PendingPermission {
+                tool_name: tool_name.to_string(),
+                requested_at: Instant::now(),
+                required_approvals,
+                approvals: Vec::new(),
+                tx,
+            },
+        );
+        self.events.publish(ClientEvent::PermissionRequested {
+            session_id: self.session_id.clone(),
+            permission_id: permission_id.clone(),
+            tool_name: tool_name.to_string(),
+        });
+
+        let event = json!({
+            "type": "permission_request",
+            "permission_id": permission_id,
+            "tool_name": tool_name,
+            "input": input,
+            "required_approvals": required_approvals,
+        });
+        self.add_output(event.to_string()).await;
+        (permission_id, rx)
+    }
+
+    /// Whether `permission_id` is still waiting for a decision
+    pub async fn has_pending_permission(&self, permission_id: &str) -> bool {
+        self.pending_permissions
+            .lock()
+            .await
+            .contains_key(permission_id)
+    }
+
+    /// Count a decision on a pending prompt. A denial decides the prompt at
+    /// once; an approval decides it when enough distinct approvers (by
+    /// `decided_by`) have approved, the last one's input edits winning.
+    pub async fn resolve_permission(
+        &self,
+        permission_id: &str,
+        mut decision: PermissionDecision,
+    ) -> PermissionOutcome {
+        let mut pending_permissions = self.pending_permissions.lock().await;
+        let Some(pending) = pending_permissions.get_mut(permission_id) else {
+            return PermissionOutcome::NotPending;
+        };
+        if decision.approved {
+            if pending
+                .approvals
+                .iter()
+                .any(|approval| approval.decided_by == decision.decided_by)
+            {
+                return PermissionOutcome::AlreadyApproved;
+            }
+            pending.approvals.push(decision.clone());
+            self.permission_history.record(
+                &self.session_id,
+                PermissionRecord::now(
+                    permission_id,
+                    &pending.tool_name,
+                    true,
+                    decision.decided_by.clone(),
+                ),
+            );
+            let (approvals, required) =
+                (pending.approvals.len() as u32, pending.required_approvals);
+            if approvals < required {
+                drop(pending_permissions);
+                let event = json!({
+                    "type": "permission_approval",
+                    "permission_id": permission_id,
+                    "decided_by": decision.decided_by,
+                    "approvals": approvals,
+                    "required_approvals": required,
+                });
+                self.add_output(event.to_string()).await;
+                return PermissionOutcome::Awaiting {
+                    approvals,
+                    required,
+                };
+            }
+        }
+        let Some(pending) = pending_permissions.remove(permission_id) else {
+            return PermissionOutcome::NotPending;
+        };
+        drop(pending_permissions);
+        if !decision.approved {
+            self.permission_history.record(
+                &self.session_id,
+                PermissionRecord::now(
+                    permission_id,
+                    &pending.tool_name,
+                    false,
+                    decision.decided_by.clone(),
+                ),
+            );
+        }
+
+        let verdict = if decision.approved {
+            "approved"
+        } else {
+            "denied"
+        };
+        if decision.approved {
+            decision.updated_input = pending
+                .approvals
+                .iter()
+                .rev()
+                .find_map(|approval| approval.updated_input.clone());
+        }
+        let approved_by: Vec<_> = pending
+            .approvals
+            .iter()
+            .map(|approval| approval.decided_by.clone())
+            .collect();
+        let decided_by = decision.decided_by.clone();
+        let event = json!({
+            "type": "permission_decision",
+            "permission_id": permission_id,
+            "decision": verdict,
+            "reason": decision.reason,
+            "decided_by": decision.decided_by,
+            "approved_by": approved_by,
+        });
+        if pending.tx.send(decision).is_err() {
+            return PermissionOutcome::NotPending;
+        }
+        self.events.publish(ClientEvent::PermissionResolved {
+            session_id: self.session_id.clone(),
+            permission_id: permission_id.to_string(),
+            decision: verdict,
+            decided_by,
+        });
+        self.add_output(event.to_string()).await;
+        PermissionOutcome::Decided
+    }
+
+    /// Drop a prompt nobody answered in time and publish a `permission_timeout` event
+    #[cfg(feature = "mcp")]
+    pub async fn expire_permission(&self, permission_id: &str, auto_approved: bool) {
+        if self
+            .pending_permissions
+            .lock()
+            .await
+            .remove(permission_id)
+            .is_none()
+        {
+            return;
+        }
+
+        self.events.publish(ClientEvent::PermissionResolved {
+            session_id: self.session_id.clone(),
+            permission_id: permission_id.to_string(),
+            decision: if auto_approved {
+                "timeout_approved"
+            } else {
+                "timeout_denied"
+            },
+            decided_by: None,
+        });
+        let event = json!({
+            "type": "permission_timeout",
+            "permission_id": permission_id,
+            "resolution": if auto_approved { "approved" } else { "denied" },
+        });
+        self.add_output(event.to_string()).await;
+    }
 }
 
 /// Session manager for tracking command executions
 #[derive(Clone)]
 pub struct SessionManager {
-    sessions: Arc<Mutex<HashMap<String, Arc<CommandSession>>>>,
-    agent_session_map: Arc<Mutex<HashMap<(ExecutorKind, String), String>>>,
+    sessions: Arc<DashMap<String, Arc<CommandSession>>>,
+    agent_session_map: Arc<DashMap<(ExecutorKind, String), String>>,
+    redactor: Arc<Redactor>,
+    ended_tx: broadcast::Sender<SessionEnded>,
+    events: EventBus,
+    /// Executor, status and agent mapping of sessions, kept across restarts
+    records: SessionRecords,
+    /// Decisions on permission requests, per session, for audits
+    permission_history: PermissionHistory,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
+        Self::with_redactor(Redactor::default())
+    }
+
+    /// Create a manager whose sessions redact their output with `redactor`
+    pub fn with_redactor(redactor: Redactor) -> Self {
         let manager = SessionManager {
-            sessions: Arc::new(Mutex::new(HashMap::new())),
-            agent_session_map: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(DashMap::new()),
+            agent_session_map: Arc::new(DashMap::new()),
+            redactor: Arc::new(redactor),
+            ended_tx: broadcast::channel(256).0,
+            events: EventBus::default(),
+            records: SessionRecords::default(),
+            permission_history: PermissionHistory::default(),
         };
 
         // Start cleanup task
@@ -217,29 +674,34 @@ impl SessionManager {
         manager
     }
 
-    /// Create a new session with specific executor
-    pub async fn create_session_with_executor(
-        &self,
-        executor: ExecutorKind,
-    ) -> Arc<CommandSession> {
-        let session_id = Uuid::new_v4().to_string();
-        let session = Arc::new(CommandSession::new(session_id.clone(), executor));
-
-        let mut sessions = self.sessions.lock().await;
-        sessions.insert(session_id.clone(), session.clone());
-
-        info!(
-            "Created new session: {} with executor: {}",
-            session_id,
-            executor.as_str()
-        );
-        session
-    }
-
-    /// Create a new session with a custom session ID
-    pub async fn create_session_with_id(&self, session_id: String) -> Arc<CommandSession> {
-        self.create_session_with_id_and_executor(session_id, ExecutorKind::Claude)
-            .await
+    /// Persist session records to `records`, updating them when sessions
+    /// are registered and when they end
+    pub fn with_records(mut self, records: SessionRecords) -> Self {
+        let mut ended = self.subscribe_ended();
+        let saved = records.clone();
+        tokio::spawn(async move {
+            loop {
+                match ended.recv().await {
+                    Ok(ended) => {
+                        saved
+                            .save(SessionRecord::new(
+                                ended.session_id,
+                                ended.executor_kind,
+                                &ended.status,
+                                ended.project_path,
+                                ended.agent_session_id,
+                            ))
+                            .await
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Session records missed {} session ends", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        self.records = records;
+        self
     }
 
     /// Create a new session with custom ID and executor
@@ -248,10 +710,15 @@ impl SessionManager {
         session_id: String,
         executor: ExecutorKind,
     ) -> Arc<CommandSession> {
-        let session = Arc::new(CommandSession::new(session_id.clone(), executor));
+        let session = Arc::new(
+            CommandSession::new(session_id.clone(), executor)
+                .with_redactor(self.redactor.clone())
+                .with_ended_notifier(self.ended_tx.clone())
+                .with_event_bus(self.events.clone())
+                .with_permission_history(self.permission_history.clone()),
+        );
 
-        let mut sessions = self.sessions.lock().await;
-        sessions.insert(session_id.clone(), session.clone());
+        self.sessions.insert(session_id.clone(), session.clone());
 
         info!(
             "Created new session with custom ID: {} executor: {}",
@@ -261,10 +728,32 @@ impl SessionManager {
         session
     }
 
+    /// Receive a notification whenever one of the sessions stops running
+    pub fn subscribe_ended(&self) -> broadcast::Receiver<SessionEnded> {
+        self.ended_tx.subscribe()
+    }
+
+    /// Bus the sessions publish their events on
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Decisions on the permission requests of every session
+    pub fn permission_history(&self) -> &PermissionHistory {
+        &self.permission_history
+    }
+
+    /// Redaction rules applied to session output and served transcripts
+    pub fn redactor(&self) -> &Arc<Redactor> {
+        &self.redactor
+    }
+
     /// Get an existing session
     pub async fn get_session(&self, session_id: &str) -> Option<Arc<CommandSession>> {
-        let sessions = self.sessions.lock().await;
-        let session = sessions.get(session_id).cloned();
+        let session = self
+            .sessions
+            .get(session_id)
+            .map(|entry| entry.value().clone());
 
         if let Some(ref s) = session {
             s.touch().await;
@@ -284,11 +773,31 @@ impl SessionManager {
             .set_agent_session(executor_kind, agent_session_id.clone())
             .await;
 
-        let mut agent_map = self.agent_session_map.lock().await;
-        agent_map.insert(
-            (executor_kind, agent_session_id),
+        self.agent_session_map.insert(
+            (executor_kind, agent_session_id.clone()),
             session.session_id.clone(),
         );
+
+        self.records
+            .save(SessionRecord::new(
+                session.session_id.clone(),
+                session.executor_kind,
+                &session.get_status().await,
+                session.get_project_path().await,
+                Some(agent_session_id),
+            ))
+            .await;
+    }
+
+    /// Persisted record of a session by its ID or agent session ID, for
+    /// sessions no longer held in memory
+    pub async fn find_record(&self, id: &str) -> Option<SessionRecord> {
+        self.records.find(id).await
+    }
+
+    /// Drop the persisted record of a session whose history was deleted
+    pub async fn forget_record(&self, id: &str) {
+        self.records.remove(id).await;
     }
 
     /// Cancel a running session
@@ -303,20 +812,22 @@ impl SessionManager {
 
     /// Remove a session
     pub async fn remove_session(&self, session_id: &str) {
-        let mut sessions = self.sessions.lock().await;
-
-        // Get the session to retrieve its Claude session ID
-        if let Some(session) = sessions.get(session_id) {
-            if let Some(agent_info) = session.get_agent_session().await {
-                let mut agent_map = self.agent_session_map.lock().await;
-                agent_map.remove(&agent_info);
-            }
+        if let Some((_, session)) = self.sessions.remove(session_id) {
+            self.forget_agent_session(&session).await;
         }
-
-        sessions.remove(session_id);
+        self.permission_history.forget(session_id);
         info!("Removed session: {}", session_id);
     }
 
+    /// Drop the agent session mapping that points at `session`, leaving
+    /// it alone if the agent session has since been resumed elsewhere
+    async fn forget_agent_session(&self, session: &CommandSession) {
+        if let Some(agent_info) = session.get_agent_session().await {
+            self.agent_session_map
+                .remove_if(&agent_info, |_, id| *id == session.session_id);
+        }
+    }
+
     /// Cleanup old sessions periodically
     async fn cleanup_loop(&self) {
         let cleanup_interval = Duration::from_secs(60); // Check every minute
@@ -324,45 +835,61 @@ impl SessionManager {
 
         loop {
             tokio::time::sleep(cleanup_interval).await;
+            self.remove_expired(session_timeout).await;
+        }
+    }
 
-            let mut sessions = self.sessions.lock().await;
-            let mut agent_map = self.agent_session_map.lock().await;
+    /// Remove sessions idle for longer than `timeout`, returning how many
+    /// were removed. The map is never locked across an await: sessions are
+    /// checked from a snapshot and only removed if still the same session
+    /// and still idle.
+    async fn remove_expired(&self, timeout: Duration) -> usize {
+        let mut removed = 0;
+        for session in self.snapshot() {
             let now = Instant::now();
+            if now.duration_since(*session.last_accessed.lock().await) <= timeout {
+                continue;
+            }
 
-            // Find expired sessions
-            let expired: Vec<(String, Option<(ExecutorKind, String)>)> = {
-                let mut expired = Vec::new();
-                for (id, session) in sessions.iter() {
-                    let last_accessed = session.last_accessed.lock().await;
-                    if now.duration_since(*last_accessed) > session_timeout {
-                        let agent_info = session.get_agent_session().await;
-                        expired.push((id.clone(), agent_info));
-                    }
-                }
-                expired
-            };
-
-            // Remove expired sessions
-            for (id, agent_info) in expired {
-                sessions.remove(&id);
-                if let Some(agent_info) = agent_info {
-                    agent_map.remove(&agent_info);
-                }
-                info!("Cleaned up expired session: {}", id);
+            let expired = self
+                .sessions
+                .remove_if(&session.session_id, |_, current| {
+                    Arc::ptr_eq(current, &session)
+                        && current
+                            .last_accessed
+                            .try_lock()
+                            .is_ok_and(|last| now.duration_since(*last) > timeout)
+                })
+                .is_some();
+            if expired {
+                self.forget_agent_session(&session).await;
+                self.permission_history.forget(&session.session_id);
+                info!("Cleaned up expired session: {}", session.session_id);
+                removed += 1;
             }
         }
+        removed
+    }
+
+    /// The sessions currently held, cloned out so callers can await on
+    /// them without holding any shard of the map
+    fn snapshot(&self) -> Vec<Arc<CommandSession>> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
     }
 
     /// Get session statistics
     pub async fn get_stats(&self) -> serde_json::Value {
-        let sessions = self.sessions.lock().await;
+        let sessions = self.snapshot();
 
         let mut running = 0;
         let mut completed = 0;
         let mut failed = 0;
         let mut cancelled = 0;
 
-        for session in sessions.values() {
+        for session in &sessions {
             let status = session.status.read().await;
             match *status {
                 SessionStatus::Running => running += 1,
@@ -381,10 +908,107 @@ impl SessionManager {
         })
     }
 
-    /// Query session status by session ID
-    pub async fn get_session_status(&self, session_id: &str) -> Option<SessionStatus> {
-        let session = self.get_session(session_id).await?;
-        Some(session.get_status().await)
+    /// Summaries of the sessions held in memory, most recently used first
+    pub async fn list_sessions(&self) -> Vec<serde_json::Value> {
+        let sessions = self.snapshot();
+
+        let mut summaries = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            let idle = session.last_accessed.lock().await.elapsed();
+            let (status, detail) = match session.get_status().await {
+                SessionStatus::Running => ("running", json!(null)),
+                SessionStatus::Completed { exit_code } => ("completed", json!(exit_code)),
+                SessionStatus::Failed { error } => ("failed", json!(error)),
+                SessionStatus::Cancelled { reason } => ("cancelled", json!(reason)),
+            };
+            let pending_permissions: Vec<serde_json::Value> = session
+                .pending_permissions
+                .lock()
+                .await
+                .iter()
+                .map(|(permission_id, pending)| {
+                    json!({
+                        "permission_id": permission_id,
+                        "tool_name": pending.tool_name,
+                        "waiting_secs": pending.requested_at.elapsed().as_secs(),
+                        "approvals": pending.approvals.len(),
+                        "required_approvals": pending.required_approvals,
+                    })
+                })
+                .collect();
+            summaries.push((
+                idle,
+                json!({
+                    "session_id": session.session_id,
+                    "executor": session.executor_kind.as_str(),
+                    "agent_session_id": session.get_agent_session().await.map(|(_, id)| id),
+                    "project_path": session.get_project_path().await,
+                    "status": status,
+                    "status_detail": detail,
+                    "total_lines": *session.total_lines.lock().await,
+                    "pending_permissions": pending_permissions,
+                    "idle_secs": idle.as_secs(),
+                }),
+            ));
+        }
+
+        summaries.sort_by_key(|(idle, _)| *idle);
+        summaries.into_iter().map(|(_, summary)| summary).collect()
+    }
+
+    /// Number of sessions, running or not
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Number of sessions that are still running
+    pub async fn running_count(&self) -> usize {
+        let mut running = 0;
+        for session in self.snapshot() {
+            if matches!(*session.status.read().await, SessionStatus::Running) {
+                running += 1;
+            }
+        }
+        running
+    }
+
+    /// Number of permission prompts waiting for an answer, across sessions
+    pub async fn pending_permission_count(&self) -> usize {
+        let mut pending = 0;
+        for session in self.snapshot() {
+            pending += session.pending_permissions.lock().await.len();
+        }
+        pending
+    }
+
+    /// Number of live streams subscribed to session output
+    pub fn subscriber_count(&self) -> usize {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().broadcast_tx.receiver_count())
+            .sum()
+    }
+
+    /// Permission prompts that have been waiting at least `min_age`, longest
+    /// waiting first
+    #[cfg(feature = "digest")]
+    pub async fn stale_permissions(&self, min_age: Duration) -> Vec<StalePermission> {
+        let mut stale = Vec::new();
+        for session in self.snapshot() {
+            for (permission_id, pending) in session.pending_permissions.lock().await.iter() {
+                let waiting = pending.requested_at.elapsed();
+                if waiting >= min_age {
+                    stale.push(StalePermission {
+                        session_id: session.session_id.clone(),
+                        permission_id: permission_id.clone(),
+                        tool_name: pending.tool_name.clone(),
+                        waiting,
+                    });
+                }
+            }
+        }
+        stale.sort_by_key(|permission| std::cmp::Reverse(permission.waiting));
+        stale
     }
 }
 
@@ -393,3 +1017,168 @@ impl Default for SessionManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn permission_prompts_are_published_and_resolved_once() {
+        let session = CommandSession::new("s1".to_string(), ExecutorKind::Claude);
+        let (id, rx) = session
+            .request_permission("Bash", &json!({"command": "ls"}), 1)
+            .await;
+
+        let decision = PermissionDecision {
+            approved: true,
+            reason: None,
+            updated_input: None,
+            decided_by: Some("alice".to_string()),
+        };
+        assert_eq!(
+            session.resolve_permission(&id, decision.clone()).await,
+            PermissionOutcome::Decided
+        );
+        assert_eq!(
+            session.resolve_permission(&id, decision).await,
+            PermissionOutcome::NotPending
+        );
+        assert!(rx.await.unwrap().approved);
+
+        let events: Vec<Value> = session
+            .get_output_from(1)
+            .await
+            .iter()
+            .map(|line| serde_json::from_str(&line.content).unwrap())
+            .collect();
+        assert_eq!(events[0]["type"], "permission_request");
+        assert_eq!(events[0]["permission_id"], id.as_str());
+        assert_eq!(events[1]["type"], "permission_decision");
+        assert_eq!(events[1]["decided_by"], "alice");
+    }
+
+    #[tokio::test]
+    async fn destructive_prompts_wait_for_distinct_approvers() {
+        let session = CommandSession::new("s1".to_string(), ExecutorKind::Claude);
+        let decision = |approved: bool, by: &str| PermissionDecision {
+            approved,
+            reason: None,
+            updated_input: (by == "bob").then(|| json!({"command": "ls -a"})),
+            decided_by: Some(by.to_string()),
+        };
+
+        let (id, mut rx) = session
+            .request_permission("Bash", &json!({"command": "ls"}), 2)
+            .await;
+        assert_eq!(
+            session
+                .resolve_permission(&id, decision(true, "alice"))
+                .await,
+            PermissionOutcome::Awaiting {
+                approvals: 1,
+                required: 2
+            }
+        );
+        // One approver approving twice is still one approval
+        assert_eq!(
+            session
+                .resolve_permission(&id, decision(true, "alice"))
+                .await,
+            PermissionOutcome::AlreadyApproved
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            session.resolve_permission(&id, decision(true, "bob")).await,
+            PermissionOutcome::Decided
+        );
+        let approved = rx.await.unwrap();
+        assert!(approved.approved);
+        assert_eq!(approved.updated_input, Some(json!({"command": "ls -a"})));
+
+        // A denial decides the prompt whatever was approved before
+        let (id, rx) = session
+            .request_permission("Bash", &json!({"command": "rm -rf /"}), 2)
+            .await;
+        session
+            .resolve_permission(&id, decision(true, "alice"))
+            .await;
+        assert_eq!(
+            session
+                .resolve_permission(&id, decision(false, "bob"))
+                .await,
+            PermissionOutcome::Decided
+        );
+        assert!(!rx.await.unwrap().approved);
+    }
+
+    #[tokio::test]
+    async fn session_end_is_announced_once() {
+        let manager = SessionManager::new();
+        let mut ended = manager.subscribe_ended();
+        let session = manager
+            .create_session_with_id_and_executor("s2".to_string(), ExecutorKind::Codex)
+            .await;
+
+        session.mark_cancelled("User cancelled".to_string()).await;
+        session.mark_completed(Some(0)).await;
+
+        let event = ended.recv().await.unwrap();
+        assert_eq!(event.session_id, "s2");
+        assert!(matches!(event.status, SessionStatus::Cancelled { .. }));
+        assert!(ended.try_recv().is_err());
+        assert_eq!(
+            session.get_status().await,
+            SessionStatus::Cancelled {
+                reason: "User cancelled".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_and_their_agent_mappings_are_removed() {
+        let manager = SessionManager::new();
+        let idle = manager
+            .create_session_with_id_and_executor("idle".to_string(), ExecutorKind::Claude)
+            .await;
+        manager
+            .register_agent_session(ExecutorKind::Claude, "agent-idle".to_string(), &idle)
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let active = manager
+            .create_session_with_id_and_executor("active".to_string(), ExecutorKind::Claude)
+            .await;
+        manager
+            .register_agent_session(ExecutorKind::Claude, "agent-active".to_string(), &active)
+            .await;
+
+        assert_eq!(manager.remove_expired(Duration::from_millis(50)).await, 1);
+        assert!(manager.get_session("idle").await.is_none());
+        assert!(manager.get_session("active").await.is_some());
+        let agent = |id: &str| (ExecutorKind::Claude, id.to_string());
+        assert!(!manager.agent_session_map.contains_key(&agent("agent-idle")));
+        assert!(
+            manager
+                .agent_session_map
+                .contains_key(&agent("agent-active"))
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriber_count_tracks_live_streams() {
+        let manager = SessionManager::new();
+        let first = manager
+            .create_session_with_id_and_executor("first".to_string(), ExecutorKind::Claude)
+            .await;
+        let second = manager
+            .create_session_with_id_and_executor("second".to_string(), ExecutorKind::Codex)
+            .await;
+        assert_eq!(manager.subscriber_count(), 0);
+
+        let streams = [first.subscribe(), first.subscribe(), second.subscribe()];
+        assert_eq!(manager.subscriber_count(), 3);
+        drop(streams);
+        assert_eq!(manager.subscriber_count(), 0);
+    }
+}
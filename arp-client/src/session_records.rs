@@ -0,0 +1,236 @@
+use crate::executor::ExecutorKind;
+use crate::session::SessionStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Records kept on disk; the least recently updated are dropped first
+const MAX_RECORDS: usize = 1000;
+
+/// session ID -> record
+type RecordFile = BTreeMap<String, SessionRecord>;
+
+/// What is remembered about a session once it has left memory, so its
+/// executor and outcome survive a client restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub executor: ExecutorKind,
+    /// `running`, `completed`, `failed` or `cancelled`, as reported when the
+    /// record was written
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_session_id: Option<String>,
+    /// Unix seconds
+    pub updated_at: u64,
+}
+
+impl SessionRecord {
+    pub fn new(
+        session_id: String,
+        executor: ExecutorKind,
+        status: &SessionStatus,
+        project_path: Option<PathBuf>,
+        agent_session_id: Option<String>,
+    ) -> Self {
+        let (status, exit_code, error) = status.outcome();
+        SessionRecord {
+            session_id,
+            executor,
+            status: status.to_string(),
+            exit_code,
+            error,
+            project_path,
+            agent_session_id,
+            updated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Status as seen by a client that no longer holds the session: one
+    /// still running when the record was written was cut off by a restart
+    pub fn final_status(&self) -> &str {
+        match self.status.as_str() {
+            "running" => "interrupted",
+            status => status,
+        }
+    }
+
+    fn matches(&self, id: &str) -> bool {
+        self.session_id == id || self.agent_session_id.as_deref() == Some(id)
+    }
+}
+
+/// Session records persisted to `<data_local_dir>/arpc/sessions.json`
+#[derive(Clone, Default)]
+pub struct SessionRecords {
+    /// None keeps nothing, as for managers that are not serving requests
+    path: Option<Arc<PathBuf>>,
+    /// Serializes read-modify-write cycles on the file
+    lock: Arc<Mutex<()>>,
+}
+
+impl SessionRecords {
+    /// Records in the client data directory
+    pub fn open_default() -> Self {
+        match dirs::data_local_dir() {
+            Some(dir) => Self::at(dir.join("arpc/sessions.json")),
+            None => {
+                warn!("Could not determine local data directory, session records are disabled");
+                Self::default()
+            }
+        }
+    }
+
+    /// Records kept in `path`
+    pub fn at(path: PathBuf) -> Self {
+        SessionRecords {
+            path: Some(Arc::new(path)),
+            lock: Arc::default(),
+        }
+    }
+
+    /// Store `record`, replacing any earlier record of the same session
+    pub async fn save(&self, record: SessionRecord) {
+        self.update(move |records| {
+            records.insert(record.session_id.clone(), record);
+            if records.len() > MAX_RECORDS {
+                let mut by_age: Vec<(u64, String)> = records
+                    .values()
+                    .map(|record| (record.updated_at, record.session_id.clone()))
+                    .collect();
+                by_age.sort();
+                for (_, session_id) in by_age.into_iter().take(records.len() - MAX_RECORDS) {
+                    records.remove(&session_id);
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Forget the session with ID or agent session ID `id`
+    pub async fn remove(&self, id: &str) {
+        let id = id.to_string();
+        self.update(move |records| records.retain(|_, record| !record.matches(&id)))
+            .await;
+    }
+
+    /// The record of the session with ID or agent session ID `id`
+    pub async fn find(&self, id: &str) -> Option<SessionRecord> {
+        let path = self.path.clone()?;
+        let id = id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut records = read_records(&path);
+            records
+                .remove(&id)
+                .or_else(|| records.into_values().find(|record| record.matches(&id)))
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    async fn update(&self, change: impl FnOnce(&mut RecordFile) + Send + 'static) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let lock = self.lock.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            let mut records = read_records(&path);
+            change(&mut records);
+            write_records(&path, &records)
+        })
+        .await;
+        match result {
+            Ok(Err(e)) => warn!("Failed to save session records: {}", e),
+            Err(e) => warn!("Failed to save session records: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+fn read_records(path: &Path) -> RecordFile {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_records(path: &Path, records: &RecordFile) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let tmp = path.with_extension(format!("json.{}", std::process::id()));
+    let content = serde_json::to_vec_pretty(records).map_err(|e| e.to_string())?;
+    fs::write(&tmp, content).map_err(|e| e.to_string())?;
+    fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, agent_id: Option<&str>, status: SessionStatus) -> SessionRecord {
+        SessionRecord::new(
+            id.to_string(),
+            ExecutorKind::Codex,
+            &status,
+            Some(PathBuf::from("/work/app")),
+            agent_id.map(str::to_string),
+        )
+    }
+
+    #[tokio::test]
+    async fn records_survive_reopening_and_resolve_agent_ids() {
+        let path = std::env::temp_dir().join(format!(
+            "arpc-session-records-{}-{}.json",
+            std::process::id(),
+            uuid::Uuid::new_v4()
+        ));
+        let records = SessionRecords::at(path.clone());
+        records
+            .save(record("s1", Some("agent-1"), SessionStatus::Running))
+            .await;
+        records
+            .save(record(
+                "s1",
+                Some("agent-1"),
+                SessionStatus::Completed { exit_code: Some(0) },
+            ))
+            .await;
+        records
+            .save(record("s2", None, SessionStatus::Running))
+            .await;
+
+        let reopened = SessionRecords::at(path.clone());
+        let s1 = reopened.find("agent-1").await.unwrap();
+        assert_eq!(s1.session_id, "s1");
+        assert_eq!(s1.executor, ExecutorKind::Codex);
+        assert_eq!(s1.final_status(), "completed");
+        assert_eq!(s1.exit_code, Some(0));
+        assert_eq!(
+            reopened.find("s2").await.unwrap().final_status(),
+            "interrupted"
+        );
+
+        reopened.remove("agent-1").await;
+        assert!(reopened.find("s1").await.is_none());
+        assert!(reopened.find("s2").await.is_some());
+        assert!(SessionRecords::default().find("s2").await.is_none());
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::approvers::Approvers;
+use crate::events::{ClientEvent, Event, EventBus};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{MqttSink, MqttTarget};
+#[cfg(feature = "notifiers")]
+use crate::notifiers::{Chat, Notifier, NotifierTarget};
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// How long a webhook endpoint gets to answer one delivery
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where client events are delivered, loaded from a JSON file:
+///
+/// ```json
+/// {
+///   "sinks": [
+///     {"type": "webhook", "url": "https://ops.example.com/hooks", "events": ["session_ended"]},
+///     {"type": "file", "path": "/var/log/arpc/events.jsonl"},
+///     {"type": "stdout", "events": ["permission_requested"]},
+///     {"type": "mqtt", "broker": "mqtt://broker.local:1883"},
+///     {"type": "slack", "webhook": "https://hooks.slack.com/services/..."}
+///   ]
+/// }
+/// ```
+///
+/// `events` picks event types; left out, a sink receives every event. The
+/// `mqtt` sink needs the `mqtt` feature, see [`MqttSink`]; the `slack` and
+/// `discord` sinks the `notifiers` feature, see [`Notifier`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventSinks {
+    sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SinkConfig {
+    #[serde(flatten)]
+    sink: Sink,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Sink {
+    /// POST each event as JSON
+    Webhook { url: String },
+    /// Append each event as a line of JSON
+    File { path: PathBuf },
+    /// Print each event as a line of JSON
+    Stdout,
+    /// Publish each event to an MQTT broker
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttSink),
+    /// Post messages to a Slack channel
+    #[cfg(feature = "notifiers")]
+    Slack(Notifier),
+    /// Post messages to a Discord channel
+    #[cfg(feature = "notifiers")]
+    Discord(Notifier),
+}
+
+impl Sink {
+    fn describe(&self) -> String {
+        match self {
+            Sink::Webhook { url } => format!("webhook {}", url),
+            Sink::File { path } => format!("file {}", path.display()),
+            Sink::Stdout => "stdout".to_string(),
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(mqtt) => mqtt.describe(),
+            #[cfg(feature = "notifiers")]
+            Sink::Slack(notifier) => notifier.describe(Chat::Slack),
+            #[cfg(feature = "notifiers")]
+            Sink::Discord(notifier) => notifier.describe(Chat::Discord),
+        }
+    }
+}
+
+impl EventSinks {
+    /// Load the sinks from `path`; no path means no sinks
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read event sinks {}", path.display()))?;
+        let sinks: EventSinks = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid event sinks {}: {}", path.display(), e))?;
+        for config in &sinks.sinks {
+            if let Some(unknown) = config
+                .events
+                .iter()
+                .find(|name| !ClientEvent::TYPES.contains(&name.as_str()))
+            {
+                return Err(anyhow!(
+                    "Unknown event type '{}' in {}; expected one of {}",
+                    unknown,
+                    path.display(),
+                    ClientEvent::TYPES.join(", ")
+                ));
+            }
+        }
+
+        info!(
+            "Loaded {} event sinks from {}",
+            sinks.sinks.len(),
+            path.display()
+        );
+        Ok(sinks)
+    }
+
+    /// Deliver the events published on `bus` to every sink, each from its
+    /// own task so a slow sink doesn't hold up the others
+    pub fn start(self, bus: &EventBus, client_id: &str, approvers: Arc<Approvers>) {
+        for config in self.sinks {
+            let events = bus.subscribe();
+            let client_id = client_id.to_string();
+            let approvers = approvers.clone();
+            tokio::spawn(async move {
+                let name = config.sink.describe();
+                if let Err(e) = deliver(config, events, client_id, approvers).await {
+                    warn!("Event sink {} stopped: {}", name, e);
+                }
+            });
+        }
+    }
+}
+
+/// Deliver events to one sink, in order, until the bus closes. Failed
+/// deliveries are logged and not retried.
+#[cfg_attr(not(feature = "notifiers"), allow(unused_variables))]
+async fn deliver(
+    config: SinkConfig,
+    mut events: broadcast::Receiver<Event>,
+    client_id: String,
+    approvers: Arc<Approvers>,
+) -> Result<()> {
+    let mut target = match &config.sink {
+        Sink::Webhook { url } => Target::Webhook {
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            url: url.clone(),
+        },
+        Sink::File { path } => Target::File(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+        ),
+        Sink::Stdout => Target::Stdout(tokio::io::stdout()),
+        #[cfg(feature = "mqtt")]
+        Sink::Mqtt(mqtt) => Target::Mqtt(mqtt.connect(&client_id)?),
+        #[cfg(feature = "notifiers")]
+        Sink::Slack(notifier) => {
+            Target::Notifier(notifier.connect(Chat::Slack, &client_id, approvers)?)
+        }
+        #[cfg(feature = "notifiers")]
+        Sink::Discord(notifier) => {
+            Target::Notifier(notifier.connect(Chat::Discord, &client_id, approvers)?)
+        }
+    };
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!(
+                    "Event sink {} missed {} events",
+                    config.sink.describe(),
+                    missed
+                );
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if !config.events.is_empty() && !config.events.iter().any(|e| e == event.event.kind()) {
+            continue;
+        }
+        if let Err(e) = target.send(&event, &payload(&event, &client_id)).await {
+            warn!(
+                "Event sink {} failed for {}: {}",
+                config.sink.describe(),
+                event.event.kind(),
+                e
+            );
+        }
+    }
+}
+
+/// An opened sink
+enum Target {
+    Webhook {
+        http: reqwest::Client,
+        url: String,
+    },
+    File(tokio::fs::File),
+    Stdout(tokio::io::Stdout),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttTarget),
+    #[cfg(feature = "notifiers")]
+    Notifier(NotifierTarget),
+}
+
+impl Target {
+    #[cfg_attr(
+        not(any(feature = "mqtt", feature = "notifiers")),
+        allow(unused_variables)
+    )]
+    async fn send(&mut self, event: &Event, payload: &Value) -> Result<()> {
+        let line = format!("{}\n", payload);
+        match self {
+            Target::Webhook { http, url } => {
+                http.post(url.as_str())
+                    .json(payload)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Target::File(file) => {
+                file.write_all(line.as_bytes()).await?;
+                file.flush().await?;
+            }
+            Target::Stdout(stdout) => {
+                stdout.write_all(line.as_bytes()).await?;
+                stdout.flush().await?;
+            }
+            #[cfg(feature = "mqtt")]
+            Target::Mqtt(mqtt) => mqtt.send(event, payload).await?,
+            #[cfg(feature = "notifiers")]
+            Target::Notifier(notifier) => notifier.send(event).await?,
+        }
+        Ok(())
+    }
+}
+
+/// The event as delivered: its fields plus the client it came from
+fn payload(event: &Event, client_id: &str) -> Value {
+    let mut payload = serde_json::to_value(event).unwrap_or_default();
+    payload["client_id"] = Value::from(client_id);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_sink_appends_the_events_it_asked_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("events.jsonl");
+        let config = dir.path().join("sinks.json");
+        std::fs::write(
+            &config,
+            serde_json::json!({
+                "sinks": [{"type": "file", "path": log, "events": ["session_ended"]}]
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let bus = EventBus::default();
+        EventSinks::load(Some(&config))
+            .unwrap()
+            .start(&bus, "box-1", Arc::default());
+        tokio::task::yield_now().await;
+        bus.publish(ClientEvent::SessionOutput {
+            session_id: "s1".to_string(),
+            lines: 1,
+        });
+        bus.publish(ClientEvent::SessionEnded {
+            session_id: "s1".to_string(),
+            executor: "claude",
+            status: "completed",
+            agent_session_id: None,
+            project_path: None,
+            exit_code: Some(0),
+            error: None,
+        });
+
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = std::fs::read_to_string(&log).unwrap_or_default();
+            if !content.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let event: Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(event["type"], "session_ended");
+        assert_eq!(event["client_id"], "box-1");
+        assert_eq!(event["status"], "completed");
+
+        std::fs::write(
+            &config,
+            r#"{"sinks": [{"type": "stdout", "events": ["ended"]}]}"#,
+        )
+        .unwrap();
+        assert!(EventSinks::load(Some(&config)).is_err());
+    }
+}
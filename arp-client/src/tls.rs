@@ -0,0 +1,145 @@
+use crate::config::ClientConfig;
+use crate::resolver::Resolver;
+#[cfg(feature = "tls")]
+use crate::router::loopback_pair;
+use anyhow::Result;
+#[cfg(feature = "tls")]
+use anyhow::{Context, anyhow};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::crypto::ring::default_provider;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{ClientConfig as TlsConfig, RootCertStore};
+
+/// TLS towards the server's control and proxy ports, with a client
+/// certificate when the server asks for mutual TLS.
+#[cfg(feature = "tls")]
+pub struct ServerTls {
+    connector: TlsConnector,
+    /// From `--tls-server-name`; otherwise each server's own address
+    server_name: Option<ServerName<'static>>,
+}
+
+#[cfg(feature = "tls")]
+impl ServerTls {
+    /// The TLS settings of `config`; None unless `--tls` is set.
+    pub fn from_config(config: &ClientConfig) -> Result<Option<Arc<Self>>> {
+        if !config.tls {
+            return Ok(None);
+        }
+
+        let mut roots = RootCertStore::empty();
+        match &config.tls_ca {
+            Some(path) => {
+                for cert in read_certs(path)? {
+                    roots.add(cert)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let builder = TlsConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .map_err(|e| anyhow!("Invalid private key in {}: {:?}", key.display(), e))?;
+                builder.with_client_auth_cert(read_certs(cert)?, key)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        let server_name = config
+            .tls_server_name
+            .as_deref()
+            .map(server_name)
+            .transpose()?;
+        Ok(Some(Arc::new(ServerTls {
+            connector: TlsConnector::from(Arc::new(tls)),
+            server_name,
+        })))
+    }
+
+    /// Run the TLS handshake with `host` on `stream`. The returned loopback
+    /// stream carries the plaintext, so the connection is used as if it
+    /// were the plain TCP one.
+    pub async fn wrap(&self, stream: TcpStream, host: &str) -> Result<TcpStream> {
+        let server_name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => server_name(host)?,
+        };
+        let tls = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .context("TLS handshake with the server failed")?;
+        let (plain, bridge) = loopback_pair().await?;
+        tokio::spawn(async move {
+            if let Err(e) = common::join_streams(tls, bridge).await {
+                tracing::debug!("TLS connection to the server ended: {}", e);
+            }
+        });
+        Ok(plain)
+    }
+}
+
+/// Without the `tls` feature there is no TLS to set up: `--tls` is refused
+/// by [`ClientConfig::validate`].
+#[cfg(not(feature = "tls"))]
+pub enum ServerTls {}
+
+#[cfg(not(feature = "tls"))]
+impl ServerTls {
+    pub fn from_config(_config: &ClientConfig) -> Result<Option<Arc<Self>>> {
+        Ok(None)
+    }
+
+    pub async fn wrap(&self, _stream: TcpStream, _host: &str) -> Result<TcpStream> {
+        match *self {}
+    }
+}
+
+/// Connect to `addr` on the server, over TLS when `tls` is set.
+pub async fn connect(
+    addr: &str,
+    resolver: &Resolver,
+    tls: Option<&ServerTls>,
+) -> Result<TcpStream> {
+    let stream = resolver.connect(addr).await?;
+    match tls {
+        Some(tls) => tls.wrap(stream, host_of(addr)).await,
+        None => Ok(stream),
+    }
+}
+
+/// The host part of a `host:port` address, without IPv6 brackets.
+pub fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(feature = "tls")]
+fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string())
+        .map_err(|_| anyhow!("Invalid TLS server name '{}'", name))
+}
+
+#[cfg(feature = "tls")]
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Invalid certificate PEM in {}: {:?}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
@@ -0,0 +1,1296 @@
+#[cfg(feature = "executors")]
+use crate::approvers::Approvers;
+use crate::config::ClientConfig;
+#[cfg(feature = "digest")]
+use crate::digest::EmailDigest;
+#[cfg(feature = "e2ee")]
+use crate::e2ee::{self, E2eeKey};
+#[cfg(feature = "executors")]
+use crate::events::ClientEvent;
+#[cfg(feature = "command-mode")]
+use crate::handlers::HandlerState;
+#[cfg(feature = "hooks")]
+use crate::hooks::SessionHooks;
+#[cfg(feature = "lsp")]
+use crate::lsp::LspServers;
+#[cfg(feature = "mcp")]
+use crate::mcp::{self, servers::McpServers};
+#[cfg(feature = "executors")]
+use crate::orphans;
+use crate::peer;
+#[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+use crate::policy::SessionPolicy;
+use crate::relays::Relays;
+use crate::resolver::Resolver;
+use crate::router::{Handler, HandlerContext, Router, RouterBuilder};
+#[cfg(feature = "command-mode")]
+use crate::routes;
+use crate::runtime::{Load, LogLevelHandle, ProxyLimiter, RuntimeSettings};
+#[cfg(feature = "executors")]
+use crate::session::{SessionEnded, SessionManager};
+#[cfg(feature = "events")]
+use crate::sinks::EventSinks;
+use crate::tls::{self, ServerTls};
+use anyhow::{Result, anyhow};
+use common::Command;
+use common::auth::{AuthError, CommandAuth, read_command_with, write_command_with};
+use common::http::{self, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "executors")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{self, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+#[cfg(feature = "executors")]
+use tokio::sync::broadcast;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, warn};
+
+/// Environment variable holding the key shared with a server started with
+/// `--auth-key-file`
+const AUTH_KEY_ENV: &str = "ARPC_AUTH_KEY";
+
+/// Signs commands to the server with the key from `ARPC_AUTH_KEY` or the
+/// keychain; None when there is neither, and commands go unsigned.
+fn command_auth() -> Result<Option<CommandAuth>, String> {
+    if let Ok(key) = std::env::var(AUTH_KEY_ENV) {
+        return Ok(Some(CommandAuth::new(key.trim())));
+    }
+    #[cfg(feature = "keyring")]
+    if let Some(key) = crate::credentials::get(AUTH_KEY_ENV)? {
+        return Ok(Some(CommandAuth::new(key.trim())));
+    }
+    Ok(None)
+}
+
+/// Environment variable holding the tenant token, `<tenant>.<secret>`, of
+/// a server started with `--tenants-file`
+const TENANT_TOKEN_ENV: &str = "ARPC_TENANT_TOKEN";
+
+/// The tenant token from `ARPC_TENANT_TOKEN` or the keychain, sent
+/// wherever the server is told a client ID.
+pub(crate) fn tenant_token() -> Result<Option<String>, String> {
+    if let Ok(token) = std::env::var(TENANT_TOKEN_ENV) {
+        return Ok(Some(token.trim().to_string()));
+    }
+    #[cfg(feature = "keyring")]
+    if let Some(token) = crate::credentials::get(TENANT_TOKEN_ENV)? {
+        return Ok(Some(token.trim().to_string()));
+    }
+    Ok(None)
+}
+
+/// Configures a [`TunnelClient`] before it is built
+pub struct TunnelClientBuilder {
+    config: ClientConfig,
+    log_level: Option<LogLevelHandle>,
+    on_request: Option<Handler>,
+}
+
+impl TunnelClientBuilder {
+    /// Let config updates from the server change the log level
+    pub fn log_level_handle(mut self, handle: LogLevelHandle) -> Self {
+        self.log_level = Some(handle);
+        self
+    }
+
+    /// Answer tunneled HTTP requests with `handler`. In command mode the
+    /// built-in API routes are tried first; otherwise every request goes to
+    /// `handler` instead of the local service.
+    pub fn on_request<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(HandlerContext) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<HttpResponse>> + Send + 'static,
+    {
+        self.on_request = Some(Arc::new(move |ctx: HandlerContext| {
+            Box::pin(handler(ctx))
+                as std::pin::Pin<Box<dyn std::future::Future<Output = Result<HttpResponse>> + Send>>
+        }));
+        self
+    }
+
+    /// Validate the configuration and load the files it refers to. Starts
+    /// the MCP server when enabled, so it must run inside a Tokio runtime.
+    pub fn build(self) -> Result<TunnelClient> {
+        let config = self.config;
+        config
+            .validate()
+            .map_err(|e| anyhow!("Invalid configuration: {}", e))?;
+
+        let mut runtime = RuntimeSettings::new(&config);
+        if let Some(handle) = self.log_level {
+            runtime = runtime.with_log_level_handle(handle);
+        }
+        let runtime = Arc::new(runtime);
+
+        let relays = Arc::new(Relays::new(&config));
+        relays.start(Duration::from_secs(config.relay_probe_interval));
+        #[cfg(feature = "command-mode")]
+        let state = HandlerState::new(config.clone()).with_relays(relays.clone());
+        #[cfg(any(feature = "fs", feature = "lsp", feature = "executors"))]
+        let state = state.with_session_policy(
+            SessionPolicy::load(config.session_policy.as_deref())?
+                .with_project_roots(&config.project_roots)?,
+        );
+        #[cfg(feature = "lsp")]
+        let state = state.with_lsp_servers(LspServers::load(config.lsp_config.as_deref())?);
+        #[cfg(feature = "mcp")]
+        let state = state.with_mcp_servers(McpServers::load(config.mcp_config.as_deref())?);
+        #[cfg(feature = "executors")]
+        let state = state.with_approvers(Approvers::load(config.approvers.as_deref())?);
+        #[cfg(feature = "events")]
+        EventSinks::load(config.event_sinks.as_deref())?.start(
+            state.session_manager.events(),
+            &config.client_id,
+            state.approvers.clone(),
+        );
+        #[cfg(feature = "hooks")]
+        SessionHooks::load(config.session_hooks.as_deref())?
+            .start(state.session_manager.subscribe_ended());
+        #[cfg(feature = "digest")]
+        if let Some(digest) = EmailDigest::load(config.digest.as_deref())? {
+            digest.start(state.session_manager.clone());
+        }
+        #[cfg(feature = "executors")]
+        orphans::start(config.orphan_policy, state.session_manager.clone());
+
+        #[cfg(feature = "mcp")]
+        if config.enable_mcp {
+            let mcp_port = config.mcp_port;
+            let sessions = state.session_manager.clone();
+            let expiry = runtime.expiry_policy();
+            let approvers = state.approvers.clone();
+            tokio::spawn(async move {
+                if let Err(e) = mcp::start_mcp_server(mcp_port, sessions, expiry, approvers).await {
+                    error!("MCP server error: {}", e);
+                }
+            });
+            info!("MCP server enabled on port {}", config.mcp_port);
+        }
+
+        let serve_http = config.command_mode || self.on_request.is_some();
+        let mut router = RouterBuilder::new();
+        router.default_timeout(config.handler_timeout());
+        router.slow_threshold(config.slow_request_threshold());
+        #[cfg(feature = "command-mode")]
+        if config.command_mode {
+            routes::register_routes(&mut router, &state);
+        }
+        if let Some(handler) = self.on_request {
+            router.route("/{*path}", move |ctx| handler(ctx));
+        }
+
+        #[cfg(feature = "e2ee")]
+        let e2ee_key = if config.e2ee {
+            E2eeKey::load().map_err(|e| anyhow!(e))?.map(Arc::new)
+        } else {
+            None
+        };
+
+        let server_tls = ServerTls::from_config(&config)?;
+        let auth = command_auth().map_err(|e| anyhow!(e))?.map(Arc::new);
+        let tenant_token = tenant_token().map_err(|e| anyhow!(e))?;
+        let resolver = Resolver::from_config(&config);
+        let (shutdown, _) = watch::channel(false);
+        Ok(TunnelClient {
+            config: Arc::new(config),
+            #[cfg(feature = "executors")]
+            session_manager: state.session_manager.clone(),
+            router: Arc::new(router.build()),
+            serve_http,
+            runtime,
+            shutdown: Arc::new(shutdown),
+            #[cfg(feature = "e2ee")]
+            e2ee_key,
+            server_tls,
+            resolver,
+            auth,
+            tenant_token,
+            server: Arc::from(relays.current()),
+            relays,
+        })
+    }
+}
+
+/// Client side of the tunnel: registers with arps and serves the proxy
+/// connections it asks for. Clones share the same client.
+#[derive(Clone)]
+pub struct TunnelClient {
+    config: Arc<ClientConfig>,
+    #[cfg(feature = "executors")]
+    session_manager: SessionManager,
+    router: Arc<Router>,
+    /// Parse tunneled connections as HTTP rather than forward them as TCP
+    serve_http: bool,
+    runtime: Arc<RuntimeSettings>,
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Tunnels must be encrypted end to end with this key
+    #[cfg(feature = "e2ee")]
+    e2ee_key: Option<Arc<E2eeKey>>,
+    /// Connections to the server go over TLS
+    server_tls: Option<Arc<ServerTls>>,
+    /// Looks up the server, taking `--resolve` into account
+    resolver: Resolver,
+    /// Commands to and from the server are signed with this key
+    auth: Option<Arc<CommandAuth>>,
+    /// Registers in this tenant of a shared server
+    tenant_token: Option<String>,
+    /// The server connected to, `--server-addr` or the nearest `--relay`;
+    /// set on the clone each connection carries
+    server: Arc<str>,
+    relays: Arc<Relays>,
+}
+
+/// A registered control connection, returned by [`TunnelClient::connect`]
+pub struct TunnelConnection {
+    client: TunnelClient,
+    reader: ReadHalf<TcpStream>,
+    writer: WriteHalf<TcpStream>,
+    generation: Option<u64>,
+    /// ID the server registered the client under
+    client_id: String,
+}
+
+impl TunnelClient {
+    pub fn builder(config: ClientConfig) -> TunnelClientBuilder {
+        TunnelClientBuilder {
+            config,
+            log_level: None,
+            on_request: None,
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    fn control_addr(&self) -> String {
+        format!("{}:{}", self.server, self.config.control_port)
+    }
+
+    fn proxy_addr(&self) -> String {
+        format!("{}:{}", self.server, self.config.proxy_port)
+    }
+
+    /// With `--e2ee`, finish the encryption handshake on a new tunnel and
+    /// return its plaintext side; otherwise the tunnel itself.
+    async fn open_tunnel(&self, stream: TcpStream) -> Result<TcpStream> {
+        #[cfg(feature = "e2ee")]
+        if let Some(key) = &self.e2ee_key {
+            return e2ee::accept(stream, key).await;
+        }
+        Ok(stream)
+    }
+
+    /// Counts included in crash reports; must not wait on async locks.
+    pub fn crash_state(&self) -> serde_json::Value {
+        #[cfg(feature = "executors")]
+        return serde_json::json!({
+            "sessions": self.session_manager.session_count(),
+            "stream_subscribers": self.session_manager.subscriber_count(),
+            "proxy_connections": self.runtime.proxy_limiter().active(),
+        });
+        #[cfg(not(feature = "executors"))]
+        serde_json::json!({
+            "proxy_connections": self.runtime.proxy_limiter().active(),
+        })
+    }
+
+    /// Connect to the control port of the nearest server and register.
+    /// Serving starts with [`TunnelConnection::serve`].
+    pub async fn connect(&self) -> Result<TunnelConnection> {
+        let client = TunnelClient {
+            server: Arc::from(self.relays.select().await),
+            ..self.clone()
+        };
+        let control_stream = tls::connect(
+            &client.control_addr(),
+            &self.resolver,
+            self.server_tls.as_deref(),
+        )
+        .await?;
+        info!("Connected to control port of {}.", client.server);
+
+        let (mut reader, mut writer) = tokio::io::split(control_stream);
+
+        let register_cmd = Command::Register {
+            client_id: self.config.client_id.clone(),
+            hostnames: self.config.hostnames.clone(),
+            token: self.tenant_token.clone(),
+        };
+        write_command_with(&mut writer, &register_cmd, self.auth.as_deref()).await?;
+        debug!("Sent registration command");
+
+        let (generation, client_id) = match tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            read_command_with(&mut reader, self.auth.as_deref()),
+        )
+        .await?
+        {
+            Ok(Command::RegisterResult {
+                success,
+                generation,
+                client_id,
+                ..
+            }) if success => {
+                info!("Successfully registered with the server.");
+                let client_id = client_id.unwrap_or_else(|| self.config.client_id.clone());
+                (generation, client_id)
+            }
+            Ok(Command::RegisterResult {
+                error, error_code, ..
+            }) => {
+                return Err(anyhow!(
+                    "Registration failed ({}): {}",
+                    error_code.as_deref().unwrap_or("unknown"),
+                    error.unwrap_or_default()
+                ));
+            }
+            Ok(cmd) => return Err(anyhow!("Unexpected command: {:?}", cmd)),
+            Err(e) => return Err(e),
+        };
+
+        Ok(TunnelConnection {
+            client,
+            reader,
+            writer,
+            generation,
+            client_id,
+        })
+    }
+
+    /// Connect and serve until [`shutdown`](Self::shutdown), reconnecting
+    /// after errors when `auto_reconnect` is set
+    pub async fn run(&self) -> Result<()> {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut backoff = Backoff::new(
+            Duration::from_secs(self.config.reconnect_interval),
+            Duration::from_secs(self.config.max_reconnect_interval),
+        );
+        loop {
+            let mut connected = false;
+            let result = tokio::select! {
+                result = async {
+                    let connection = self.connect().await?;
+                    connected = true;
+                    info!("🌐 Public URL: {}", connection.public_url());
+                    #[cfg(feature = "executors")]
+                    self.session_manager.events().publish(ClientEvent::Connected {
+                        server: connection.client.control_addr(),
+                        generation: connection.generation(),
+                    });
+                    connection.serve().await
+                } => result,
+                _ = shut_down(&mut shutdown) => return Ok(()),
+            };
+
+            #[cfg(feature = "executors")]
+            if let Err(e) = &result
+                && connected
+            {
+                self.session_manager
+                    .events()
+                    .publish(ClientEvent::Disconnected {
+                        reason: e.to_string(),
+                    });
+            }
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if self.config.auto_reconnect => {
+                    // A connection that got registered starts over from the
+                    // shortest delay; failed attempts wait longer each time
+                    if connected {
+                        backoff.reset();
+                    }
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Connection error: {}. Reconnecting in {:.1}s (attempt {})...",
+                        e,
+                        delay.as_secs_f64(),
+                        backoff.attempts()
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = shut_down(&mut shutdown) => return Ok(()),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Close the control connection and make [`run`](Self::run) return.
+    /// Requests already being handled are left to finish.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+/// Delays between reconnection attempts: doubling from `initial` up to
+/// `max`, each one picked at random from its upper half so clients cut off
+/// together don't all come back at once.
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            attempts: 0,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        ceiling / 2 + ceiling.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Attempts since the last [`reset`](Self::reset)
+    fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+impl TunnelConnection {
+    /// Generation the server assigned to this registration
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    /// Where the tunneled service can be reached
+    pub fn public_url(&self) -> String {
+        let server = &self.client.server;
+        if &**server != "proxy.agentx.plus" {
+            format!("{}:17003?token={}", server, self.client_id)
+        } else {
+            format!("https://console.agentx.plus/?token={}", self.client_id)
+        }
+    }
+
+    /// Serve proxy connections and config updates until the control
+    /// connection closes (an error) or the client is shut down (`Ok`)
+    pub async fn serve(self) -> Result<()> {
+        let TunnelConnection {
+            client,
+            reader,
+            mut writer,
+            generation,
+            ..
+        } = self;
+        let config = &client.config;
+        let runtime = &client.runtime;
+        let mut shutdown = client.shutdown.subscribe();
+
+        // Acknowledgements are sent from spawned tasks, so funnel all writes to
+        // the control connection through one writer task.
+        let (control_tx, mut control_rx) = mpsc::unbounded_channel::<Command>();
+        let signer = client.auth.clone();
+        tokio::spawn(async move {
+            while let Some(cmd) = control_rx.recv().await {
+                if let Err(e) = write_command_with(&mut writer, &cmd, signer.as_deref()).await {
+                    warn!("Failed to write to control connection: {}", e);
+                    break;
+                }
+            }
+        });
+
+        // Ask for the public ports of raw TCP services; they close with
+        // this connection, so they're asked for again on every registration
+        for spec in &config.open_ports {
+            let _ = control_tx.send(Command::OpenPort {
+                target: config.open_port_target(spec),
+                port: spec.public_port,
+            });
+        }
+
+        let proxy_slots = runtime.proxy_limiter();
+
+        prewarm_pool(&client, generation, proxy_slots, runtime.pool_size());
+
+        let mut load_ticker = tokio::time::interval(tokio::time::Duration::from_secs(
+            config.load_report_interval.max(1),
+        ));
+
+        #[cfg(feature = "executors")]
+        let mut ended_sessions = client.session_manager.subscribe_ended();
+        #[cfg(not(feature = "executors"))]
+        let mut ended_sessions = ();
+        let mut last_load = None;
+
+        // A server that never answered a heartbeat predates them, and a
+        // dead one is left to TCP to notice
+        let mut commands = common::spawn_command_reader(reader, client.auth.clone());
+        let heartbeat_interval = tokio::time::Duration::from_secs(config.heartbeat_interval.max(1));
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat_interval,
+            heartbeat_interval,
+        );
+        let heartbeat_timeout = tokio::time::Duration::from_secs(config.heartbeat_timeout);
+        let mut last_heard = tokio::time::Instant::now();
+        let mut server_answers = false;
+        let mut ping_seq = 0;
+
+        loop {
+            tokio::select! {
+                event = next_session_event(&mut ended_sessions) => {
+                    let _ = control_tx.send(event);
+                }
+                _ = load_ticker.tick(), if config.load_report_interval > 0 => {
+                    let load = current_load(&client).await;
+                    let _ = control_tx.send(load_report(&load));
+                    if last_load.as_ref() != Some(&load) {
+                        #[cfg(feature = "executors")]
+                        client.session_manager.events().publish(ClientEvent::Load(load.clone()));
+                        last_load = Some(load);
+                    }
+                }
+                _ = shut_down(&mut shutdown) => {
+                    info!("Shutting down tunnel client");
+                    return Ok(());
+                }
+                _ = heartbeat.tick(), if config.heartbeat_interval > 0 => {
+                    if server_answers && last_heard.elapsed() > heartbeat_timeout {
+                        return Err(anyhow!("No heartbeat from the server for {}s", heartbeat_timeout.as_secs()));
+                    }
+                    ping_seq += 1;
+                    let _ = control_tx.send(Command::Ping { seq: ping_seq });
+                }
+                result = commands.recv() => {
+                    let result = result.unwrap_or_else(|| Err(anyhow!("Control connection closed by server")));
+                    if result.is_ok() {
+                        last_heard = tokio::time::Instant::now();
+                    }
+                    match result {
+                        Ok(Command::Ping { seq }) => {
+                            server_answers = true;
+                            let _ = control_tx.send(Command::Pong { seq });
+                        }
+                        Ok(Command::Pong { .. }) => server_answers = true,
+                        Ok(Command::RequestNewProxyConn { proxy_conn_id, target }) => {
+                            debug!("Received request for new proxy connection: {}", proxy_conn_id);
+                            handle_proxy_request(&client, proxy_conn_id, target, generation, &control_tx, proxy_slots);
+                        }
+                        Ok(Command::Punch { punch_id, service }) => {
+                            debug!("Received punch {} for {}", punch_id, service);
+                            handle_punch(&client, punch_id, service, &control_tx, proxy_slots);
+                        }
+                        Ok(Command::OpenPortResult { target, port: Some(port), .. }) => {
+                            info!("{} is reachable at {}:{}", target, client.server, port);
+                        }
+                        Ok(Command::OpenPortResult { target, error, .. }) => {
+                            warn!("Server refused to open a port for {}: {}", target, error.unwrap_or_default());
+                        }
+                        Ok(Command::ConfigUpdate { update_id, settings }) => {
+                            let previous_pool_size = runtime.pool_size();
+                            let ack = match runtime.apply(&settings) {
+                                Ok(applied) => {
+                                    info!("Applied config update {}: {:?}", update_id, applied);
+                                    Command::ConfigUpdateAck { update_id, applied, error: None }
+                                }
+                                Err(e) => {
+                                    warn!("Rejected config update {}: {}", update_id, e);
+                                    Command::ConfigUpdateAck { update_id, applied: Vec::new(), error: Some(e) }
+                                }
+                            };
+                            let _ = control_tx.send(ack);
+                            // A larger pool is filled right away rather than on reconnect
+                            let added = runtime.pool_size().saturating_sub(previous_pool_size);
+                            prewarm_pool(&client, generation, proxy_slots, added);
+                        }
+                        Ok(cmd) => warn!("Received unexpected command: {:?}", cmd),
+                        Err(e) if e.is::<AuthError>() => warn!("Dropped command from the server: {}", e),
+                        Err(ref e) if e.downcast_ref::<io::Error>().is_some_and(|io_err| io_err.kind() == io::ErrorKind::UnexpectedEof) => {
+                            return Err(anyhow!("Control connection closed by server"));
+                        }
+                        Err(e) => return Err(anyhow!("Error reading from control connection: {}", e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Resolves once the client is shut down
+async fn shut_down(shutdown: &mut watch::Receiver<bool>) {
+    // The borrow the wait returns isn't `Send`, so it's dropped right here
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+/// Measure how busy the client is; busy when the session or proxy
+/// connection limits are reached.
+async fn current_load(client: &TunnelClient) -> Load {
+    let runtime = &client.runtime;
+    #[cfg(feature = "executors")]
+    let (active_sessions, pending_permissions) = (
+        client.session_manager.running_count().await,
+        client.session_manager.pending_permission_count().await,
+    );
+    // Builds without executors never run sessions
+    #[cfg(not(feature = "executors"))]
+    let (active_sessions, pending_permissions) = (0, 0);
+    let max_sessions = runtime.max_sessions();
+    let sessions_full = max_sessions > 0 && active_sessions >= max_sessions;
+    let limiter = runtime.proxy_limiter();
+
+    Load {
+        active_sessions,
+        proxy_connections: limiter.active(),
+        pending_permissions,
+        busy: sessions_full || limiter.is_full(),
+    }
+}
+
+/// Build a `LoadReport` from the current load.
+fn load_report(load: &Load) -> Command {
+    Command::LoadReport {
+        active_sessions: load.active_sessions as u32,
+        load_avg: read_load_avg(),
+        busy: load.busy,
+    }
+}
+
+/// The `SessionEvent` for the next session that stops running
+#[cfg(feature = "executors")]
+async fn next_session_event(ended: &mut broadcast::Receiver<SessionEnded>) -> Command {
+    loop {
+        match ended.recv().await {
+            Ok(ended) => return session_event(ended),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// Builds without executors have no sessions to announce
+#[cfg(not(feature = "executors"))]
+async fn next_session_event(_: &mut ()) -> Command {
+    std::future::pending().await
+}
+
+/// Build the `SessionEvent` announcing that a session stopped running.
+#[cfg(feature = "executors")]
+fn session_event(ended: SessionEnded) -> Command {
+    let (status, exit_code, error) = ended.status.outcome();
+
+    Command::SessionEvent {
+        session_id: ended.session_id,
+        status: status.to_string(),
+        executor: ended.executor_kind.as_str().to_string(),
+        agent_session_id: ended.agent_session_id,
+        project_path: ended
+            .project_path
+            .map(|path| path.to_string_lossy().into_owned()),
+        exit_code,
+        error,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    }
+}
+
+/// One-minute load average from `/proc/loadavg` (Linux only).
+fn read_load_avg() -> Option<f64> {
+    std::fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Answer a `RequestNewProxyConn`: reject it when at capacity, when it's for
+/// a target that wasn't opened or when the proxy port is unreachable,
+/// otherwise acknowledge it and serve the connection.
+fn handle_proxy_request(
+    client: &TunnelClient,
+    proxy_conn_id: String,
+    target: Option<String>,
+    generation: Option<u64>,
+    control_tx: &mpsc::UnboundedSender<Command>,
+    proxy_slots: &Arc<ProxyLimiter>,
+) {
+    let ack = {
+        let target = target.clone();
+        move |proxy_conn_id: String, reason: Option<String>| Command::ProxyConnAck {
+            proxy_conn_id,
+            accepted: reason.is_none(),
+            reason,
+            target: target.clone(),
+        }
+    };
+
+    // Only ports opened with --open-port and the `arpc tunnel` service may
+    // be reached
+    if let Some(ref target) = target
+        && client.config.resolve_target(target).is_none()
+    {
+        warn!(
+            "('{}') Rejecting proxy connection to unknown target {}",
+            proxy_conn_id, target
+        );
+        let _ = control_tx.send(ack(proxy_conn_id, Some("unknown target".into())));
+        return;
+    }
+
+    let Some(permit) = proxy_slots.try_acquire() else {
+        warn!(
+            "('{}') Rejecting proxy connection: limit of {} reached",
+            proxy_conn_id,
+            proxy_slots.limit()
+        );
+        let _ = control_tx.send(ack(proxy_conn_id, Some("client overloaded".into())));
+        return;
+    };
+
+    let client = client.clone();
+    let control_tx = control_tx.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        let proxy_stream = match tls::connect(
+            &client.proxy_addr(),
+            &client.resolver,
+            client.server_tls.as_deref(),
+        )
+        .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!(
+                    "('{}') Failed to connect to proxy port: {}",
+                    proxy_conn_id, e
+                );
+                let _ = control_tx.send(ack(proxy_conn_id, Some(e.to_string())));
+                return;
+            }
+        };
+        let _ = control_tx.send(ack(proxy_conn_id.clone(), None));
+        drop(control_tx);
+
+        if let Err(e) = run_proxy_connection(
+            client,
+            proxy_stream,
+            proxy_conn_id,
+            false,
+            generation,
+            target,
+        )
+        .await
+        {
+            error!("Failed to create proxy connection: {}", e);
+        }
+    });
+}
+
+/// Answer a `Punch` by trying to reach the peer directly, when the
+/// `arpc tunnel` service allows it and a connection slot is free; refused
+/// ones are relayed by the peer.
+fn handle_punch(
+    client: &TunnelClient,
+    punch_id: String,
+    service: String,
+    control_tx: &mpsc::UnboundedSender<Command>,
+    proxy_slots: &Arc<ProxyLimiter>,
+) {
+    let refuse = |punch_id: String, service: String, reason: &str| Command::ProxyConnAck {
+        proxy_conn_id: punch_id,
+        accepted: false,
+        reason: Some(reason.to_string()),
+        target: Some(service),
+    };
+    let Some(address) = client.config.direct_service(&service) else {
+        let _ = control_tx.send(refuse(punch_id, service, "direct connections not allowed"));
+        return;
+    };
+    let Some(permit) = proxy_slots.try_acquire() else {
+        let _ = control_tx.send(refuse(punch_id, service, "client overloaded"));
+        return;
+    };
+
+    let client = client.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        if let Err(e) = serve_direct(&client, &punch_id, &address).await {
+            debug!("No direct connection for punch {}: {}", punch_id, e);
+        }
+    });
+}
+
+/// Serve the `arpc tunnel` service at `address` over a direct connection
+/// punched for `punch_id`.
+async fn serve_direct(client: &TunnelClient, punch_id: &str, address: &str) -> Result<()> {
+    let stream = peer::answer_punch(
+        &client.proxy_addr(),
+        &client.resolver,
+        client.server_tls.as_deref(),
+        punch_id,
+    )
+    .await?;
+    let stream = client.open_tunnel(stream).await?;
+    let service = TcpStream::connect(address)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to {}: {}", address, e))?;
+    info!("Serving {} directly for punch {}", address, punch_id);
+    common::join_streams_with(stream, service, &client.config.copy_config()).await?;
+    Ok(())
+}
+
+/// Open `count` proxy connections tagged for pooling so the first requests
+/// don't wait for the server's pool maintainer to ask for them.
+fn prewarm_pool(
+    client: &TunnelClient,
+    generation: Option<u64>,
+    proxy_slots: &Arc<ProxyLimiter>,
+    count: usize,
+) {
+    if count == 0 {
+        return;
+    }
+
+    info!("Pre-warming {} pooled proxy connections", count);
+    for _ in 0..count {
+        let Some(permit) = proxy_slots.try_acquire() else {
+            break;
+        };
+        let proxy_conn_id = format!("pool-{}", uuid::Uuid::new_v4().simple());
+        let client = client.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = create_proxy_connection(client, proxy_conn_id, true, generation).await {
+                warn!("Failed to pre-warm proxy connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn create_proxy_connection(
+    client: TunnelClient,
+    proxy_conn_id: String,
+    pooled: bool,
+    generation: Option<u64>,
+) -> Result<()> {
+    let proxy_stream = tls::connect(
+        &client.proxy_addr(),
+        &client.resolver,
+        client.server_tls.as_deref(),
+    )
+    .await?;
+    run_proxy_connection(
+        client,
+        proxy_stream,
+        proxy_conn_id,
+        pooled,
+        generation,
+        None,
+    )
+    .await
+}
+
+async fn run_proxy_connection(
+    client: TunnelClient,
+    mut proxy_stream: TcpStream,
+    proxy_conn_id: String,
+    pooled: bool,
+    generation: Option<u64>,
+    target: Option<String>,
+) -> Result<()> {
+    let config = client.config.clone();
+    debug!("('{}') Connected to proxy port.", proxy_conn_id);
+
+    let notify_cmd = Command::NewProxyConn {
+        proxy_conn_id: proxy_conn_id.clone(),
+        client_id: config.client_id.clone(),
+        pooled,
+        generation,
+        target: target.clone(),
+        token: client.tenant_token.clone(),
+    };
+    write_command_with(&mut proxy_stream, &notify_cmd, client.auth.as_deref()).await?;
+    debug!(
+        "('{}') Sent new proxy connection notification.",
+        proxy_conn_id
+    );
+    let proxy_stream = client.open_tunnel(proxy_stream).await?;
+
+    if let Some(target) = target {
+        return handle_target_connection(&config, proxy_stream, &proxy_conn_id, &target).await;
+    }
+
+    if client.serve_http {
+        let limits = config.request_limits();
+        handle_command_mode_connection(proxy_stream, client.router, proxy_conn_id, &limits).await
+    } else {
+        handle_tcp_proxy_connection(config, proxy_stream, proxy_conn_id).await
+    }
+}
+
+async fn handle_command_mode_connection(
+    mut proxy_stream: TcpStream,
+    router: Arc<Router>,
+    proxy_conn_id: String,
+    limits: &http::ParseLimits,
+) -> Result<()> {
+    debug!(
+        "('{}') Running in command mode (HTTP routing)",
+        proxy_conn_id
+    );
+
+    match http::HttpRequest::parse_with_limits(&mut proxy_stream, &proxy_conn_id, limits).await {
+        Ok(request) => {
+            // Handle CORS preflight early to avoid empty responses
+            if request.method == http::HttpMethod::OPTIONS {
+                let stream = &mut proxy_stream;
+                let _ = http::HttpResponse::new(204)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header(
+                        "Access-Control-Allow-Methods",
+                        "GET, POST, PUT, DELETE, PATCH, OPTIONS",
+                    )
+                    .header(
+                        "Access-Control-Allow-Headers",
+                        "Content-Type, Authorization, Last-Event-ID",
+                    )
+                    .header("Access-Control-Max-Age", "86400")
+                    .body(Vec::new())
+                    .send(stream)
+                    .await;
+                info!(
+                    "('{}') Responded to CORS preflight (OPTIONS)",
+                    proxy_conn_id
+                );
+                return Ok(());
+            }
+
+            let ctx = HandlerContext {
+                request,
+                stream: proxy_stream,
+                proxy_conn_id: proxy_conn_id.clone(),
+                path_params: HashMap::new(),
+            };
+
+            match router.handle(ctx).await {
+                Ok(_response) => {
+                    info!("('{}') Request handled successfully", proxy_conn_id);
+                }
+                Err(e) => {
+                    error!("('{}') Handler error: {}", proxy_conn_id, e);
+                }
+            }
+        }
+        Err(e) if e.downcast_ref::<http::BodyTooLargeError>().is_some() => {
+            warn!("('{}') Refusing request: {}", proxy_conn_id, e);
+            let _ = http::json_error(413, e.to_string())
+                .header("Connection", "close")
+                .send(&mut proxy_stream)
+                .await;
+        }
+        Err(e) => {
+            error!("('{}') Failed to parse HTTP request: {}", proxy_conn_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Relay a connection to a port opened with `--open-port`, or to the
+/// `arpc tunnel` service, as raw TCP.
+async fn handle_target_connection(
+    config: &ClientConfig,
+    proxy_stream: TcpStream,
+    proxy_conn_id: &str,
+    target: &str,
+) -> Result<()> {
+    let address = config
+        .resolve_target(target)
+        .ok_or_else(|| anyhow!("('{}') Unknown target {}", proxy_conn_id, target))?;
+    let local = TcpStream::connect(&address).await.map_err(|e| {
+        anyhow!(
+            "('{}') Failed to connect to {}: {}",
+            proxy_conn_id,
+            address,
+            e
+        )
+    })?;
+    common::join_streams_with(proxy_stream, local, &config.copy_config()).await?;
+    debug!("('{}') Connection to {} closed", proxy_conn_id, target);
+    Ok(())
+}
+
+async fn handle_tcp_proxy_connection(
+    config: Arc<ClientConfig>,
+    proxy_stream: TcpStream,
+    proxy_conn_id: String,
+) -> Result<()> {
+    match relay_to_local_service(&config, proxy_stream, &proxy_conn_id).await {
+        Ok(()) => {
+            info!("('{}') TCP proxy completed successfully", proxy_conn_id);
+        }
+        Err(e) => {
+            error!("('{}') TCP proxy error: {}", proxy_conn_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Relay a connection to `--local-addr`/`--local-port` as raw TCP.
+async fn relay_to_local_service(
+    config: &ClientConfig,
+    proxy_stream: TcpStream,
+    proxy_conn_id: &str,
+) -> Result<()> {
+    let address = config.local_service_addr();
+    let local = TcpStream::connect(&address).await?;
+    info!(
+        "('{}') Connected to local service at {}.",
+        proxy_conn_id, address
+    );
+    common::join_streams_with(proxy_stream, local, &config.copy_config()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use common::{read_command, write_command};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn reconnection_delays_double_with_jitter_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(30));
+        for ceiling in [2, 4, 8, 16, 30, 30] {
+            let delay = backoff.next_delay();
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn on_request_answers_tunneled_requests() {
+        let control = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ClientConfig::parse_from([
+            "arpc",
+            "--client-id",
+            "embedded",
+            "--server-addr",
+            "127.0.0.1",
+            "--control-port",
+            &control.local_addr().unwrap().port().to_string(),
+            "--proxy-port",
+            &proxy.local_addr().unwrap().port().to_string(),
+            "--command-mode=false",
+            "--pool-size",
+            "0",
+            "--load-report-interval",
+            "0",
+        ]);
+        let client = TunnelClient::builder(config)
+            .on_request(|mut ctx| async move {
+                let body = format!("hello {}", ctx.request.path);
+                HttpResponse::ok().text(body).send(&mut ctx.stream).await?;
+                Ok(HttpResponse::ok())
+            })
+            .build()
+            .unwrap();
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.run().await }
+        });
+
+        let (mut control_stream, _) = control.accept().await.unwrap();
+        let register = read_command(&mut control_stream).await.unwrap();
+        assert!(matches!(register, Command::Register { client_id, .. } if client_id == "embedded"));
+        let registered = Command::RegisterResult {
+            success: true,
+            error: None,
+            error_code: None,
+            generation: Some(1),
+            client_id: None,
+        };
+        write_command(&mut control_stream, &registered)
+            .await
+            .unwrap();
+        let request = Command::RequestNewProxyConn {
+            proxy_conn_id: "conn-1".to_string(),
+            target: None,
+        };
+        write_command(&mut control_stream, &request).await.unwrap();
+
+        let (mut proxy_stream, _) = proxy.accept().await.unwrap();
+        let hello = read_command(&mut proxy_stream).await.unwrap();
+        assert!(matches!(
+            hello,
+            Command::NewProxyConn {
+                generation: Some(1),
+                ..
+            }
+        ));
+        proxy_stream
+            .write_all(b"GET /greeting HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        proxy_stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("hello /greeting"));
+
+        client.shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_server_that_stops_answering_heartbeats_is_dropped() {
+        let control = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ClientConfig::parse_from([
+            "arpc",
+            "--client-id",
+            "quiet",
+            "--server-addr",
+            "127.0.0.1",
+            "--control-port",
+            &control.local_addr().unwrap().port().to_string(),
+            "--pool-size",
+            "0",
+            "--load-report-interval",
+            "0",
+            "--heartbeat-interval",
+            "1",
+            "--heartbeat-timeout",
+            "1",
+        ]);
+        let client = TunnelClient::builder(config).build().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut control_stream, _) = control.accept().await.unwrap();
+            read_command(&mut control_stream).await.unwrap();
+            let registered = Command::RegisterResult {
+                success: true,
+                error: None,
+                error_code: None,
+                generation: Some(1),
+                client_id: None,
+            };
+            write_command(&mut control_stream, &registered)
+                .await
+                .unwrap();
+            let Command::Ping { seq } = read_command(&mut control_stream).await.unwrap() else {
+                panic!("expected a heartbeat");
+            };
+            write_command(&mut control_stream, &Command::Pong { seq })
+                .await
+                .unwrap();
+            // Silent from now on, with the connection left open
+            control_stream
+        });
+
+        let connection = client.connect().await.unwrap();
+        let error = connection.serve().await.unwrap_err();
+        assert!(error.to_string().contains("No heartbeat"), "{}", error);
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn opened_ports_are_relayed_to_their_service() {
+        let control = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let service_port = service.local_addr().unwrap().port().to_string();
+        let config = ClientConfig::parse_from([
+            "arpc",
+            "--client-id",
+            "ssh-box",
+            "--server-addr",
+            "127.0.0.1",
+            "--control-port",
+            &control.local_addr().unwrap().port().to_string(),
+            "--proxy-port",
+            &proxy.local_addr().unwrap().port().to_string(),
+            "--command-mode=false",
+            "--pool-size",
+            "0",
+            "--load-report-interval",
+            "0",
+            "--open-port",
+            &format!("{}=2222", service_port),
+        ]);
+        let target = format!("127.0.0.1:{}", service_port);
+        let client = TunnelClient::builder(config).build().unwrap();
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.run().await }
+        });
+
+        let (mut control_stream, _) = control.accept().await.unwrap();
+        read_command(&mut control_stream).await.unwrap();
+        let registered = Command::RegisterResult {
+            success: true,
+            error: None,
+            error_code: None,
+            generation: Some(1),
+            client_id: None,
+        };
+        write_command(&mut control_stream, &registered)
+            .await
+            .unwrap();
+        let open = read_command(&mut control_stream).await.unwrap();
+        assert!(matches!(
+            open,
+            Command::OpenPort { target: ref t, port: Some(2222) } if *t == target
+        ));
+
+        // Only the opened service may be dialed
+        let request = Command::RequestNewProxyConn {
+            proxy_conn_id: "conn-1".to_string(),
+            target: Some("127.0.0.1:25".to_string()),
+        };
+        write_command(&mut control_stream, &request).await.unwrap();
+        let refused = read_command(&mut control_stream).await.unwrap();
+        assert!(matches!(
+            refused,
+            Command::ProxyConnAck {
+                accepted: false,
+                target: Some(_),
+                ..
+            }
+        ));
+
+        let request = Command::RequestNewProxyConn {
+            proxy_conn_id: "conn-2".to_string(),
+            target: Some(target.clone()),
+        };
+        write_command(&mut control_stream, &request).await.unwrap();
+        let (mut proxy_stream, _) = proxy.accept().await.unwrap();
+        let hello = read_command(&mut proxy_stream).await.unwrap();
+        assert!(matches!(
+            hello,
+            Command::NewProxyConn { target: Some(ref t), .. } if *t == target
+        ));
+        let (mut local, _) = service.accept().await.unwrap();
+        local.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        let mut banner = [0; 14];
+        proxy_stream.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"SSH-2.0-test\r\n");
+
+        client.shutdown();
+        running.await.unwrap().unwrap();
+    }
+}
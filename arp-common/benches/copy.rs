@@ -0,0 +1,125 @@
+//! Throughput of `join_streams_with` between two loopback TCP connections,
+//! for each buffer size and flush policy, next to
+//! `tokio::io::copy_bidirectional` as the baseline.
+//!
+//! ```text
+//! cargo bench -p common --bench copy
+//! ```
+//!
+//! Every row sends `COPY_BENCH_MIB` MiB (default 64) through the joined
+//! streams in writes of `chunk` bytes and prints the best of three runs.
+
+use common::{CopyConfig, DirectionConfig, FlushPolicy};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const RUNS: usize = 3;
+
+/// How the joined streams are copied
+#[derive(Clone, Copy)]
+enum Copier {
+    Baseline,
+    Joined(DirectionConfig),
+}
+
+/// A connected pair of loopback sockets
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(
+        TcpStream::connect(listener.local_addr().unwrap()),
+        listener.accept()
+    );
+    (client.unwrap(), server.unwrap().0)
+}
+
+/// Time to send `total` bytes from one end of the joined streams to the other
+async fn transfer(copier: Copier, total: usize, chunk: usize) -> Duration {
+    let (mut sender, a) = socket_pair().await;
+    let (b, mut receiver) = socket_pair().await;
+    let join = tokio::spawn(async move {
+        match copier {
+            Copier::Baseline => {
+                let (mut a, mut b) = (a, b);
+                tokio::io::copy_bidirectional(&mut a, &mut b)
+                    .await
+                    .map(|_| ())
+            }
+            Copier::Joined(direction) => {
+                let config = CopyConfig {
+                    upstream: direction,
+                    downstream: direction,
+                };
+                common::join_streams_with(a, b, &config).await
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let send = async {
+        let data = vec![0x5a; chunk];
+        let mut sent = 0;
+        while sent < total {
+            let n = chunk.min(total - sent);
+            sender.write_all(&data[..n]).await.unwrap();
+            sent += n;
+        }
+        sender.shutdown().await.unwrap();
+        sender
+    };
+    let receive = async {
+        let mut buf = vec![0; 256 * 1024];
+        let mut received = 0;
+        while received < total {
+            let n = receiver.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "stream ended after {} bytes", received);
+            received += n;
+        }
+        receiver
+    };
+    let (sender, receiver) = tokio::join!(send, receive);
+    let elapsed = started.elapsed();
+
+    drop((sender, receiver));
+    let _ = join.await;
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let mib: usize = std::env::var("COPY_BENCH_MIB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(64);
+    let total = mib * 1024 * 1024;
+
+    let mut copiers = vec![("copy_bidirectional".to_string(), Copier::Baseline)];
+    for buffer_size in [8 * 1024, 64 * 1024, 256 * 1024] {
+        for (name, flush) in [
+            ("immediate", FlushPolicy::Immediate),
+            ("on-idle", FlushPolicy::OnIdle),
+        ] {
+            copiers.push((
+                format!("{} KiB {}", buffer_size / 1024, name),
+                Copier::Joined(DirectionConfig { buffer_size, flush }),
+            ));
+        }
+    }
+
+    println!("{} MiB per run, best of {}", mib, RUNS);
+    println!("{:<22} {:>8} {:>10}", "copier", "chunk", "MiB/s");
+    for chunk in [1024, 64 * 1024] {
+        for (label, copier) in &copiers {
+            let mut best = Duration::MAX;
+            for _ in 0..RUNS {
+                best = best.min(transfer(*copier, total, chunk).await);
+            }
+            println!(
+                "{:<22} {:>8} {:>10.0}",
+                label,
+                chunk,
+                mib as f64 / best.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+use common::http::{HttpRequest, ParseLimits};
+use libfuzzer_sys::fuzz_target;
+
+// The public port parses requests from anyone on the internet.
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let limits = ParseLimits {
+            max_header_bytes: 64 * 1024,
+            ..ParseLimits::default()
+        };
+        let mut reader = data;
+        if let Ok(request) = HttpRequest::parse_with_limits(&mut reader, "fuzz", &limits).await {
+            let _ = request.multipart();
+            let _ = request.body_as_json();
+        }
+    });
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Control and proxy ports read commands straight off the network.
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut reader = data;
+        // Keep reading until the input is exhausted so later frames are
+        // exercised after skipped unknown commands too
+        while common::read_command(&mut reader).await.is_ok() {}
+    });
+});
@@ -0,0 +1,266 @@
+//! Authenticated control commands. With a key shared by the server and its
+//! clients, commands travel as [`Command::Signed`]: the command's JSON with
+//! a random nonce, a timestamp and an HMAC-SHA256 over all three, so frames
+//! that were altered, replayed or captured too long ago are rejected.
+
+use crate::{Command, read_command, write_command};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How far a signed command's timestamp may be from the receiver's clock.
+/// Nonces are remembered for as long, so a frame can't be replayed inside
+/// the window nor after it.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Remembered nonces beyond which expired ones are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Why a command was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Not signed although a key is configured
+    Unsigned,
+    /// The MAC doesn't match: another key, or an altered frame
+    BadMac,
+    /// The timestamp is outside [`MAX_CLOCK_SKEW`]
+    Stale,
+    /// The nonce was seen before
+    Replayed,
+    /// The signed payload is not a command
+    Malformed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthError::Unsigned => "command is not signed",
+            AuthError::BadMac => "command signature is invalid",
+            AuthError::Stale => "command timestamp is outside the allowed clock skew",
+            AuthError::Replayed => "command was replayed",
+            AuthError::Malformed => "signed payload is not a command",
+        })
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Signs and verifies commands with a shared key, remembering the nonces
+/// it accepted. One instance is shared by every connection it guards.
+pub struct CommandAuth {
+    key: Vec<u8>,
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl CommandAuth {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        CommandAuth {
+            key: key.into(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wrap `command` in a [`Command::Signed`].
+    pub fn sign(&self, command: &Command) -> Result<Command> {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let timestamp = unix_now();
+        let payload = serde_json::to_string(command)?;
+        let mac = hex::encode(
+            self.mac(&nonce, timestamp, &payload)
+                .finalize()
+                .into_bytes(),
+        );
+        Ok(Command::Signed {
+            nonce,
+            timestamp,
+            payload,
+            mac,
+        })
+    }
+
+    /// The command inside a [`Command::Signed`], once its MAC, timestamp
+    /// and nonce check out.
+    pub fn verify(&self, command: Command) -> Result<Command, AuthError> {
+        self.verify_at(command, unix_now())
+    }
+
+    fn verify_at(&self, command: Command, now: u64) -> Result<Command, AuthError> {
+        let Command::Signed {
+            nonce,
+            timestamp,
+            payload,
+            mac,
+        } = command
+        else {
+            return Err(AuthError::Unsigned);
+        };
+        let mac = hex::decode(mac).map_err(|_| AuthError::BadMac)?;
+        self.mac(&nonce, timestamp, &payload)
+            .verify_slice(&mac)
+            .map_err(|_| AuthError::BadMac)?;
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return Err(AuthError::Stale);
+        }
+
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.len() >= PRUNE_THRESHOLD {
+            seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_CLOCK_SKEW.as_secs());
+        }
+        if seen.insert(nonce, timestamp).is_some() {
+            return Err(AuthError::Replayed);
+        }
+        drop(seen);
+
+        serde_json::from_str(&payload).map_err(|_| AuthError::Malformed)
+    }
+
+    fn mac(&self, nonce: &str, timestamp: u64, payload: &str) -> Hmac<Sha256> {
+        fields_mac(
+            &self.key,
+            &[
+                nonce.as_bytes(),
+                &timestamp.to_be_bytes(),
+                payload.as_bytes(),
+            ],
+        )
+    }
+}
+
+/// [`write_command`], signing `command` first when `auth` is set.
+pub async fn write_command_with<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    command: &Command,
+    auth: Option<&CommandAuth>,
+) -> Result<()> {
+    match auth {
+        Some(auth) => write_command(writer, &auth.sign(command)?).await,
+        None => write_command(writer, command).await,
+    }
+}
+
+/// [`read_command`], requiring a valid signature when `auth` is set.
+pub async fn read_command_with<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    auth: Option<&CommandAuth>,
+) -> Result<Command> {
+    let command = read_command(reader).await?;
+    match auth {
+        Some(auth) => Ok(auth.verify(command)?),
+        None => Ok(command),
+    }
+}
+
+/// HMAC-SHA256 under `key` over `fields`, each prefixed with its length so
+/// no field can borrow bytes from the next
+pub fn fields_mac(key: &[u8], fields: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for field in fields {
+        mac.update(&(field.len() as u64).to_be_bytes());
+        mac.update(field);
+    }
+    mac
+}
+
+/// Compare secrets without leaking how much of them matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A signed frame as it goes over the wire
+    async fn captured(auth: &CommandAuth, command: &Command) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_command_with(&mut frame, command, Some(auth))
+            .await
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn fields_cannot_borrow_bytes_from_each_other() {
+        let mac = |fields: &[&[u8]]| fields_mac(b"key", fields).finalize().into_bytes();
+        assert_eq!(mac(&[b"ab", b"c"]), mac(&[b"ab", b"c"]));
+        assert_ne!(mac(&[b"ab", b"c"]), mac(&[b"a", b"bc"]));
+        assert_ne!(mac(&[b"abc"]), mac(&[b"abc", b""]));
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+    }
+
+    #[tokio::test]
+    async fn captured_frames_are_accepted_once() {
+        let client = CommandAuth::new("shared secret");
+        let server = CommandAuth::new("shared secret");
+        let register = Command::Register {
+            client_id: "agent".to_string(),
+            hostnames: Vec::new(),
+            token: None,
+        };
+        let frame = captured(&client, &register).await;
+
+        let command = read_command_with(&mut frame.as_slice(), Some(&server))
+            .await
+            .unwrap();
+        assert!(matches!(command, Command::Register { ref client_id, .. } if client_id == "agent"));
+        let replayed = read_command_with(&mut frame.as_slice(), Some(&server)).await;
+        assert_eq!(
+            replayed.unwrap_err().downcast::<AuthError>().unwrap(),
+            AuthError::Replayed
+        );
+
+        // A fresh signature of the same command is a new frame
+        let again = captured(&client, &register).await;
+        assert!(
+            read_command_with(&mut again.as_slice(), Some(&server))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn altered_unsigned_foreign_and_stale_frames_are_rejected() {
+        let server = &CommandAuth::new("shared secret");
+        let request = Command::RequestNewProxyConn {
+            proxy_conn_id: "conn-1".to_string(),
+            target: None,
+        };
+        let reject = |frame: Vec<u8>| async move {
+            let result = read_command_with(&mut frame.as_slice(), Some(server)).await;
+            result.unwrap_err().downcast::<AuthError>().unwrap()
+        };
+
+        // Retargeting the captured request at another connection
+        let mut altered = captured(&CommandAuth::new("shared secret"), &request).await;
+        let at = altered.windows(6).position(|w| w == b"conn-1").unwrap();
+        altered[at + 5] = b'2';
+        assert_eq!(reject(altered).await, AuthError::BadMac);
+
+        let mut unsigned = Vec::new();
+        write_command(&mut unsigned, &request).await.unwrap();
+        assert_eq!(reject(unsigned).await, AuthError::Unsigned);
+
+        let foreign = captured(&CommandAuth::new("other secret"), &request).await;
+        assert_eq!(reject(foreign).await, AuthError::BadMac);
+
+        let auth = CommandAuth::new("shared secret");
+        let signed = auth.sign(&request).unwrap();
+        let later = unix_now() + MAX_CLOCK_SKEW.as_secs() + 1;
+        assert_eq!(auth.verify_at(signed, later).unwrap_err(), AuthError::Stale);
+    }
+}
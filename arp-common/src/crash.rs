@@ -0,0 +1,259 @@
+//! Crash reports for post-mortems of relay outages. The panic hook installed
+//! by [`CrashReporter::install`] writes the panic message, a backtrace, a
+//! snapshot of the process state and a redacted summary of its
+//! configuration to `<dir>/crash-<unix millis>-<pid>.json`, and can POST
+//! the same report to an endpoint.
+
+use serde_json::{Value, json};
+use std::backtrace::Backtrace;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the hook waits for the state snapshot, which may need locks
+/// the panicking thread holds
+const STATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the hook waits for the upload before letting the process go on
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reports kept in the directory; older ones are deleted
+const MAX_REPORTS: usize = 20;
+
+/// Configuration keys containing any of these never leave the process
+const SECRET_KEYS: [&str; 5] = ["client_id", "token", "secret", "password", "key"];
+
+/// Snapshot of the process state included in a report
+pub type StateFn = Arc<dyn Fn() -> Value + Send + Sync>;
+
+/// Sends a serialized report to the configured endpoint
+pub type UploadFn = Arc<dyn Fn(Vec<u8>) -> Result<(), String> + Send + Sync>;
+
+pub struct CrashReporter {
+    app: &'static str,
+    version: &'static str,
+    dir: PathBuf,
+    config: Value,
+    state: Option<StateFn>,
+    upload: Option<UploadFn>,
+}
+
+impl CrashReporter {
+    /// Reports of `app` at `version`, written to `dir`
+    pub fn new(app: &'static str, version: &'static str, dir: PathBuf) -> Self {
+        CrashReporter {
+            app,
+            version,
+            dir,
+            config: Value::Null,
+            state: None,
+            upload: None,
+        }
+    }
+
+    /// Configuration summary; values of secret-looking keys are redacted
+    pub fn with_config(mut self, mut config: Value) -> Self {
+        redact(&mut config);
+        self.config = config;
+        self
+    }
+
+    /// Counts and other state captured when a panic happens
+    pub fn with_state(mut self, state: impl Fn() -> Value + Send + Sync + 'static) -> Self {
+        self.state = Some(Arc::new(state));
+        self
+    }
+
+    /// Also send every report with `upload`
+    pub fn with_upload(
+        mut self,
+        upload: impl Fn(Vec<u8>) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.upload = Some(Arc::new(upload));
+        self
+    }
+
+    /// Report every panic from now on, after the previously installed hook
+    /// has printed its message
+    pub fn install(self) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            let location = info
+                .location()
+                .map(|location| format!("{}:{}", location.file(), location.line()));
+            let report = self.report(message, location);
+
+            // The log subscriber may be what panicked, so report on stderr
+            match write_report(&self.dir, &report) {
+                Ok(path) => eprintln!("Crash report written to {}", path.display()),
+                Err(e) => eprintln!("Failed to write crash report: {}", e),
+            }
+            if let Some(upload) = self.upload.clone() {
+                let body = report.to_string().into_bytes();
+                match with_timeout(UPLOAD_TIMEOUT, move || upload(body)) {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => eprintln!("Failed to upload crash report: {}", e),
+                    None => eprintln!("Crash report upload timed out"),
+                }
+            }
+        }));
+    }
+
+    fn report(&self, message: &str, location: Option<String>) -> Value {
+        let state = self.state.clone().map(|state| {
+            with_timeout(STATE_TIMEOUT, move || state())
+                .unwrap_or_else(|| json!({ "error": "state snapshot timed out" }))
+        });
+        json!({
+            "app": self.app,
+            "version": self.version,
+            "timestamp": unix_timestamp(),
+            "pid": std::process::id(),
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "thread": std::thread::current().name().unwrap_or("unnamed"),
+            "message": message,
+            "location": location,
+            "backtrace": Backtrace::force_capture().to_string(),
+            "state": state,
+            "config": self.config,
+        })
+    }
+}
+
+/// Replace the values of secret-looking keys, at any depth
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    if !value.is_null() {
+                        *value = json!("[redacted]");
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Run `f` on its own thread, giving up on it after `timeout`
+fn with_timeout<T: Send + 'static>(
+    timeout: Duration,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("crash-report".to_string())
+        .spawn(move || {
+            let _ = tx.send(f());
+        })
+        .ok()?;
+    rx.recv_timeout(timeout).ok()
+}
+
+/// Write `report` to `dir`, keeping the newest [`MAX_REPORTS`]
+fn write_report(dir: &Path, report: &Value) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = dir.join(format!("crash-{}-{}.json", millis, std::process::id()));
+    std::fs::write(&path, serde_json::to_vec_pretty(report)?)?;
+
+    let mut reports: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("crash-") && name.ends_with(".json"))
+        })
+        .collect();
+    if reports.len() > MAX_REPORTS {
+        // Oldest first, by the timestamp in the name
+        reports.sort_by_key(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('-').nth(1))
+                .and_then(|timestamp| timestamp.parse::<u64>().ok())
+                .unwrap_or(0)
+        });
+        for old in &reports[..reports.len() - MAX_REPORTS] {
+            let _ = std::fs::remove_file(old);
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_redact_secrets_and_time_out_stuck_state() {
+        let reporter = CrashReporter::new("arpc", "1.2.3", PathBuf::new())
+            .with_config(json!({
+                "client_id": "abc123",
+                "server_addr": "relay.example.com",
+                "github_token": "ghp_x",
+                "sinks": [{ "url": "https://hooks.example.com", "password": "p" }],
+                "tls_key": null,
+            }))
+            .with_state(|| {
+                std::thread::sleep(Duration::from_secs(5));
+                json!({ "sessions": 1 })
+            });
+
+        let report = reporter.report("boom", Some("src/main.rs:1".to_string()));
+        assert_eq!(report["message"], "boom");
+        assert_eq!(report["config"]["client_id"], "[redacted]");
+        assert_eq!(report["config"]["github_token"], "[redacted]");
+        assert_eq!(report["config"]["sinks"][0]["password"], "[redacted]");
+        assert_eq!(report["config"]["server_addr"], "relay.example.com");
+        assert_eq!(report["config"]["tls_key"], Value::Null);
+        assert_eq!(report["state"]["error"], "state snapshot timed out");
+        assert!(report["backtrace"].as_str().is_some());
+    }
+
+    #[test]
+    fn only_the_newest_reports_are_kept() {
+        let dir = std::env::temp_dir().join(format!(
+            "arp-crash-{}-{}",
+            std::process::id(),
+            unix_timestamp()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..MAX_REPORTS + 5 {
+            std::fs::write(dir.join(format!("crash-{}-0.json", i)), "{}").unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "keep").unwrap();
+
+        let path = write_report(&dir, &json!({ "message": "boom" })).unwrap();
+        assert!(path.exists());
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(left, MAX_REPORTS + 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
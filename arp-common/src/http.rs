@@ -1,11 +1,26 @@
 use anyhow::{Result, anyhow};
 use serde_json::{Value, json};
 use std::collections::HashMap;
+use std::future::Future;
 use std::str::FromStr;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 use tracing::info;
 
+/// Address of the caller as arps accepted it
+pub const REMOTE_ADDR_HEADER: &str = "x-arp-remote-addr";
+/// ID of the public connection the request arrived on, also sent as
+/// `X-ARP-Request-Id` on the error pages of that connection
+pub const CONN_ID_HEADER: &str = "x-arp-conn-id";
+/// Client the request was routed to
+pub const CLIENT_ID_HEADER: &str = "x-arp-client-id";
+
 /// HTTP request method
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HttpMethod {
@@ -54,97 +69,218 @@ impl FromStr for HttpMethod {
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
-    pub query_params: HashMap<String, String>,
-    pub headers: HashMap<String, String>,
+    pub query_params: Params,
+    pub headers: Params,
+    /// Body, with `Transfer-Encoding: chunked` framing removed
     pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body
+    pub trailers: Params,
 }
 
-impl HttpRequest {
-    /// Parse an HTTP request from a TCP stream
-    pub async fn parse(stream: &mut TcpStream, proxy_conn_id: &str) -> Result<Self> {
-        let mut reader = BufReader::new(stream);
+/// Ordered key/value pairs in which a key may repeat, as in query strings
+/// (`?tag=a&tag=b`) and headers (several `Cookie` lines).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
 
-        // Read request line
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line).await?;
-        info!(
-            "('{}') Request line: {}",
-            proxy_conn_id,
-            request_line.trim()
-        );
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Parse request line: METHOD PATH HTTP/VERSION
-        let parts: Vec<&str> = request_line.split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(anyhow!("Invalid HTTP request line"));
-        }
+    /// First value of `key`
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
 
-        let method = parts[0]
-            .parse::<HttpMethod>()
-            .map_err(|_| anyhow!("Invalid HTTP method: {}", parts[0]))?;
+    /// Every value of `key`, in the order they were received
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.0.iter().filter(move |(k, _)| k == key).map(|(_, v)| v)
+    }
 
-        let url_part = parts[1];
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.iter().any(|(k, _)| k == key)
+    }
 
-        // Parse path and query string
-        let (path, query_string) = if let Some(pos) = url_part.find('?') {
-            let (p, q) = url_part.split_at(pos);
-            (p.to_string(), Some(&q[1..])) // Skip the '?'
-        } else {
-            (url_part.to_string(), None)
-        };
+    /// Set `key` to `value`, replacing any values it had
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let key = key.into();
+        self.0.retain(|(k, _)| *k != key);
+        self.0.push((key, value.into()));
+    }
 
-        // Parse query parameters
-        let mut query_params = HashMap::new();
-        if let Some(qs) = query_string {
-            for pair in qs.split('&') {
-                if let Some((key, value)) = pair.split_once('=') {
-                    // URL decode
-                    let decoded_value = urlencoding::decode(value)
-                        .unwrap_or(std::borrow::Cow::Borrowed(value))
-                        .to_string();
-                    query_params.insert(key.to_string(), decoded_value);
-                }
+    /// Add another value for `key`, keeping the ones it had
+    pub fn append(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.push((key.into(), value.into()));
+    }
+
+    /// Remove every value of `key`, returning the first
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let mut removed = None;
+        self.0.retain_mut(|(k, v)| {
+            if k != key {
+                return true;
             }
-        }
+            removed.get_or_insert_with(|| std::mem::take(v));
+            false
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
 
-        // Read headers
-        let mut headers = HashMap::new();
-        let mut content_length = 0usize;
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-        loop {
-            let mut header_line = String::new();
-            reader.read_line(&mut header_line).await?;
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
-            if header_line == "\r\n" || header_line == "\n" {
-                break; // End of headers
-            }
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Params {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Params(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
 
-            if let Some((key, value)) = header_line.split_once(':') {
-                let key = key.trim().to_lowercase();
-                let value = value.trim().to_string();
+impl IntoIterator for Params {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
 
-                if key == "content-length" {
-                    content_length = value.parse().unwrap_or(0);
-                }
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
 
-                headers.insert(key, value);
-            }
+impl<'a> IntoIterator for &'a Params {
+    type Item = (&'a String, &'a String);
+    type IntoIter = std::iter::Map<
+        std::slice::Iter<'a, (String, String)>,
+        fn(&'a (String, String)) -> (&'a String, &'a String),
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
+}
+
+/// Header lines accepted by default, matching common HTTP servers.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// Limits applied while parsing a request from an untrusted peer.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    /// Deadline for receiving the request line and all headers.
+    pub header_timeout: Option<Duration>,
+    /// Maximum bytes accepted for the request line plus headers.
+    pub max_header_bytes: usize,
+    /// Maximum number of header lines accepted.
+    pub max_headers: usize,
+    /// Minimum average body transfer rate in bytes per second (0 disables).
+    pub min_body_rate: u64,
+    /// Time allowed before the minimum body rate starts being enforced.
+    pub body_grace: Duration,
+    /// Largest `Content-Length` accepted; bigger bodies are refused unread.
+    pub max_body_bytes: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            header_timeout: None,
+            max_header_bytes: usize::MAX,
+            max_headers: DEFAULT_MAX_HEADERS,
+            min_body_rate: 0,
+            body_grace: Duration::from_secs(10),
+            max_body_bytes: usize::MAX,
         }
+    }
+}
 
-        info!(
-            "('{}') Method: {}, Path: {}, Query params: {:?}",
-            proxy_conn_id,
-            method.as_str(),
-            path,
-            query_params
-        );
-        info!("('{}') Content-Length: {}", proxy_conn_id, content_length);
+/// Returned when a peer sends its request too slowly or too large.
+#[derive(Debug)]
+pub struct SlowClientError(pub &'static str);
+
+impl std::fmt::Display for SlowClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for SlowClientError {}
+
+/// Returned when a request declares a body over `ParseLimits::max_body_bytes`.
+#[derive(Debug)]
+pub struct BodyTooLargeError {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for BodyTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request body is larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLargeError {}
+
+/// One part of a `multipart/form-data` body
+#[derive(Debug, Clone)]
+pub struct MultipartPart {
+    /// Form field name
+    pub name: String,
+    /// File name sent by the browser, present for file fields
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Parse an HTTP request from a TCP stream
+    pub async fn parse(stream: &mut TcpStream, proxy_conn_id: &str) -> Result<Self> {
+        Self::parse_with_limits(stream, proxy_conn_id, &ParseLimits::default()).await
+    }
+
+    /// Parse an HTTP request, enforcing header deadlines, header size and a
+    /// minimum body transfer rate so trickling peers cannot hold the connection.
+    pub async fn parse_with_limits<S>(
+        stream: &mut S,
+        proxy_conn_id: &str,
+        limits: &ParseLimits,
+    ) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut reader = BufReader::new(stream);
+
+        let head = read_head(&mut reader, proxy_conn_id, limits);
+        let (method, path, query_params, headers, framing) = match limits.header_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, head)
+                .await
+                .map_err(|_| anyhow!(SlowClientError("Timed out reading request headers")))??,
+            None => head.await?,
+        };
 
         // Read request body
-        let mut body = vec![0u8; content_length];
-        if content_length > 0 {
-            reader.read_exact(&mut body).await?;
-        }
+        let mut pace = BodyPace::new(limits);
+        let (body, trailers) = match framing {
+            BodyFraming::Length(content_length) => {
+                if content_length > limits.max_body_bytes {
+                    return Err(anyhow!(BodyTooLargeError {
+                        limit: limits.max_body_bytes,
+                    }));
+                }
+                let mut body = Vec::new();
+                read_body(&mut reader, &mut body, content_length, &mut pace).await?;
+                (body, Params::new())
+            }
+            BodyFraming::Chunked => read_chunked_body(&mut reader, limits, &mut pace).await?,
+        };
 
         Ok(HttpRequest {
             method,
@@ -152,9 +288,33 @@ impl HttpRequest {
             query_params,
             headers,
             body,
+            trailers,
         })
     }
 
+    /// Whether the body arrived with `Transfer-Encoding: chunked` and must be
+    /// forwarded the same way, see [`HttpRequest::chunked_body`]
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding")
+            .is_some_and(|encoding| is_chunked_encoding(encoding))
+    }
+
+    /// The body and trailers in `Transfer-Encoding: chunked` framing
+    pub fn chunked_body(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 32);
+        if !self.body.is_empty() {
+            out.extend_from_slice(format!("{:x}\r\n", self.body.len()).as_bytes());
+            out.extend_from_slice(&self.body);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"0\r\n");
+        for (key, value) in &self.trailers {
+            out.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        out
+    }
+
     /// Get body as string
     pub fn body_as_string(&self) -> String {
         String::from_utf8_lossy(&self.body).to_string()
@@ -168,15 +328,489 @@ impl HttpRequest {
         serde_json::from_slice(&self.body).map_err(|e| anyhow!("Invalid JSON body: {}", e))
     }
 
-    /// Get a query parameter by key
+    /// Split a `multipart/form-data` body into its parts
+    pub fn multipart(&self) -> Result<Vec<MultipartPart>> {
+        let boundary = self
+            .header("content-type")
+            .and_then(|content_type| multipart_boundary(content_type))
+            .ok_or_else(|| anyhow!("Content-Type must be multipart/form-data with a boundary"))?;
+        parse_multipart(&self.body, &boundary)
+    }
+
+    /// Get the first value of a query parameter by key
     pub fn query_param(&self, key: &str) -> Option<&String> {
         self.query_params.get(key)
     }
 
-    /// Get a header by key
+    /// Get every value of a repeated query parameter, e.g. `?tag=a&tag=b`
+    pub fn query_param_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> {
+        self.query_params.get_all(key)
+    }
+
+    /// Get the first value of a header by key
     pub fn header(&self, key: &str) -> Option<&String> {
         self.headers.get(&key.to_lowercase())
     }
+
+    /// Replace the identity headers with the caller as arps saw it, so that
+    /// a caller can't pass off its own values
+    pub fn set_identity(&mut self, remote_addr: Option<&str>, conn_id: &str, client_id: &str) {
+        self.headers.remove(REMOTE_ADDR_HEADER);
+        if let Some(remote_addr) = remote_addr {
+            self.headers.insert(REMOTE_ADDR_HEADER, remote_addr);
+        }
+        self.headers.insert(CONN_ID_HEADER, conn_id);
+        self.headers.insert(CLIENT_ID_HEADER, client_id);
+    }
+
+    /// Address of the caller, set by arps
+    pub fn remote_addr(&self) -> Option<&String> {
+        self.headers.get(REMOTE_ADDR_HEADER)
+    }
+
+    /// Get every value of a header sent on several lines
+    pub fn header_values(&self, key: &str) -> impl Iterator<Item = &String> {
+        let key = key.to_lowercase();
+        self.headers
+            .iter()
+            .filter(move |(k, _)| **k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+type RequestHead = (HttpMethod, String, Params, Params, BodyFraming);
+
+/// How the end of a request body is found
+enum BodyFraming {
+    Length(usize),
+    Chunked,
+}
+
+/// Whether `chunked` is the final transfer coding, which frames the body
+fn is_chunked_encoding(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Read the request line and headers, capped at `max_header_bytes` in total
+/// and `max_headers` header lines.
+async fn read_head<R>(
+    reader: &mut BufReader<R>,
+    proxy_conn_id: &str,
+    limits: &ParseLimits,
+) -> Result<RequestHead>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut limited = reader.take(limits.max_header_bytes as u64);
+
+    // Read request line
+    let mut request_line = String::new();
+    limited.read_line(&mut request_line).await?;
+    if !request_line.ends_with('\n') {
+        if limited.limit() == 0 {
+            return Err(anyhow!(SlowClientError("Request headers too large")));
+        }
+        return Err(anyhow!("Invalid HTTP request line"));
+    }
+    info!(
+        "('{}') Request line: {}",
+        proxy_conn_id,
+        request_line.trim()
+    );
+
+    // Parse request line: METHOD PATH HTTP/VERSION
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    if parts.len() < 3 {
+        return Err(anyhow!("Invalid HTTP request line"));
+    }
+
+    let method = parts[0]
+        .parse::<HttpMethod>()
+        .map_err(|_| anyhow!("Invalid HTTP method: {}", parts[0]))?;
+
+    let url_part = parts[1];
+
+    // Parse path and query string
+    let (path, query_string) = if let Some(pos) = url_part.find('?') {
+        let (p, q) = url_part.split_at(pos);
+        (p.to_string(), Some(&q[1..])) // Skip the '?'
+    } else {
+        (url_part.to_string(), None)
+    };
+
+    // Parse query parameters
+    let mut query_params = Params::new();
+    if let Some(qs) = query_string {
+        for pair in qs.split('&') {
+            if let Some((key, value)) = pair.split_once('=') {
+                query_params.append(decode_query_component(key), decode_query_component(value));
+            }
+        }
+    }
+
+    // Read headers
+    let mut headers = Params::new();
+    let mut content_length = None;
+    let mut header_count = 0usize;
+
+    loop {
+        let mut header_line = String::new();
+        let n = limited.read_line(&mut header_line).await?;
+
+        if n == 0 || !header_line.ends_with('\n') {
+            if limited.limit() == 0 {
+                return Err(anyhow!(SlowClientError("Request headers too large")));
+            }
+            return Err(anyhow!("Connection closed while reading headers"));
+        }
+
+        if header_line == "\r\n" || header_line == "\n" {
+            break; // End of headers
+        }
+
+        header_count += 1;
+        if header_count > limits.max_headers {
+            return Err(anyhow!(SlowClientError("Too many request headers")));
+        }
+
+        if let Some((key, value)) = header_line.split_once(':') {
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_string();
+
+            if key == "content-length" {
+                // A body length we can't trust would desynchronize the stream
+                let length = value
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("Invalid Content-Length: {}", value))?;
+                if content_length.is_some_and(|previous| previous != length) {
+                    return Err(anyhow!("Conflicting Content-Length headers"));
+                }
+                content_length = Some(length);
+            }
+
+            headers.append(key, value);
+        }
+    }
+
+    info!(
+        "('{}') Method: {}, Path: {}, Query params: {:?}",
+        proxy_conn_id,
+        method.as_str(),
+        path,
+        query_params
+    );
+    let framing = match headers.get("transfer-encoding") {
+        // Both would let the relay and the client disagree on where the body ends
+        Some(_) if content_length.is_some() => {
+            return Err(anyhow!("Both Content-Length and Transfer-Encoding are set"));
+        }
+        Some(encoding) if is_chunked_encoding(encoding) => BodyFraming::Chunked,
+        Some(encoding) => {
+            return Err(anyhow!("Unsupported Transfer-Encoding: {}", encoding));
+        }
+        None => BodyFraming::Length(content_length.unwrap_or(0)),
+    };
+    match framing {
+        BodyFraming::Length(length) => info!("('{}') Content-Length: {}", proxy_conn_id, length),
+        BodyFraming::Chunked => info!("('{}') Transfer-Encoding: chunked", proxy_conn_id),
+    }
+
+    Ok((method, path, query_params, headers, framing))
+}
+
+/// Decode a query key or value the way browsers encode forms: `+` is a
+/// space and `%XX` a byte. Invalid escapes are kept as they are.
+fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    match urlencoding::decode(&component) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(_) => component,
+    }
+}
+
+/// Boundary of a `multipart/form-data` content type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = header_params(content_type).into_iter();
+    let (media_type, _) = params.next()?;
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .and_then(|(_, boundary)| boundary)
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<MultipartPart>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+
+    let start = find_bytes(body, &delimiter)
+        .ok_or_else(|| anyhow!("Multipart body does not contain its boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| anyhow!("Malformed multipart boundary line"))?;
+
+        let headers_end = find_bytes(rest, b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("Multipart part headers are not terminated"))?;
+        let headers = std::str::from_utf8(&rest[..headers_end])
+            .map_err(|_| anyhow!("Multipart part headers are not valid UTF-8"))?;
+        let content = &rest[headers_end + 4..];
+        let content_end = find_bytes(content, &separator)
+            .ok_or_else(|| anyhow!("Multipart body is truncated"))?;
+
+        parts.push(multipart_part(headers, content[..content_end].to_vec())?);
+        rest = &content[content_end + separator.len()..];
+    }
+}
+
+fn multipart_part(headers: &str, data: Vec<u8>) -> Result<MultipartPart> {
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in headers.split("\r\n") {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if key.trim().eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(header_params(value));
+        } else if key.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.to_string());
+        }
+    }
+
+    let disposition = disposition
+        .filter(|params| {
+            params
+                .first()
+                .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case("form-data"))
+        })
+        .ok_or_else(|| anyhow!("Multipart part is missing Content-Disposition: form-data"))?;
+    let param = |name: &str| {
+        disposition
+            .iter()
+            .skip(1)
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.clone())
+    };
+
+    Ok(MultipartPart {
+        name: param("name").ok_or_else(|| anyhow!("Multipart part has no field name"))?,
+        filename: param("filename"),
+        content_type,
+        data,
+    })
+}
+
+/// Split a header value like `form-data; name="a;b"` into its leading token
+/// and `key=value` parameters, unquoting quoted values
+fn header_params(value: &str) -> Vec<(String, Option<String>)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == ';' || c == '=' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+
+        let mut param_value = None;
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            let mut text = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => text.extend(chars.next()),
+                        c => text.push(c),
+                    }
+                }
+            }
+            while let Some(&c) = chars.peek() {
+                if c == ';' {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+            param_value = Some(text.trim().to_string());
+        }
+        chars.next(); // the `;`
+
+        params.push((key.trim().to_string(), param_value));
+    }
+    params
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Body bytes buffered up front; larger bodies grow as data actually arrives
+/// so a lying `Content-Length` can't reserve memory it never sends.
+const BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Longest chunk size or trailer line accepted in a chunked body.
+const MAX_CHUNK_LINE_BYTES: u64 = 8 * 1024;
+
+/// Enforces `ParseLimits::min_body_rate` across everything read for one body.
+struct BodyPace<'a> {
+    limits: &'a ParseLimits,
+    start: Instant,
+    received: usize,
+}
+
+impl<'a> BodyPace<'a> {
+    fn new(limits: &'a ParseLimits) -> Self {
+        BodyPace {
+            limits,
+            start: Instant::now(),
+            received: 0,
+        }
+    }
+
+    /// Run one read, failing if it doesn't finish before the peer falls
+    /// below the minimum rate.
+    async fn read<F>(&mut self, read: F) -> Result<usize>
+    where
+        F: Future<Output = std::io::Result<usize>>,
+    {
+        let n = if self.limits.min_body_rate == 0 {
+            read.await?
+        } else {
+            // The peer may use the grace period plus one second per
+            // `min_body_rate` bytes already received; the next read must
+            // complete within that budget.
+            let allowed = self.limits.body_grace
+                + Duration::from_secs_f64(self.received as f64 / self.limits.min_body_rate as f64);
+            let remaining = allowed.saturating_sub(self.start.elapsed());
+            if remaining.is_zero() {
+                return Err(anyhow!(SlowClientError("Request body transfer too slow")));
+            }
+            tokio::time::timeout(remaining, read)
+                .await
+                .map_err(|_| anyhow!(SlowClientError("Request body transfer too slow")))??
+        };
+        self.received += n;
+        Ok(n)
+    }
+
+    /// Read one line of chunk framing, failing if it is unterminated.
+    async fn read_line<R>(&mut self, reader: &mut R) -> Result<String>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut line = String::new();
+        let mut limited = reader.take(MAX_CHUNK_LINE_BYTES);
+        self.read(limited.read_line(&mut line)).await?;
+        if !line.ends_with('\n') {
+            if limited.limit() == 0 {
+                return Err(anyhow!("Chunked body line too long"));
+            }
+            return Err(anyhow!("Connection closed while reading body"));
+        }
+        Ok(line)
+    }
+}
+
+/// Append `len` bytes of body to `body`.
+async fn read_body<R>(
+    reader: &mut R,
+    body: &mut Vec<u8>,
+    len: usize,
+    pace: &mut BodyPace<'_>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let end = body.len() + len;
+    body.reserve(len.min(BODY_CHUNK_BYTES));
+    while body.len() < end {
+        let filled = body.len();
+        let chunk = (end - filled).min(BODY_CHUNK_BYTES);
+        body.resize(filled + chunk, 0);
+
+        let n = pace.read(reader.read(&mut body[filled..])).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed while reading body"));
+        }
+        body.truncate(filled + n);
+    }
+    Ok(())
+}
+
+/// Read a `Transfer-Encoding: chunked` body and the trailer fields after it.
+async fn read_chunked_body<R>(
+    reader: &mut R,
+    limits: &ParseLimits,
+    pace: &mut BodyPace<'_>,
+) -> Result<(Vec<u8>, Params)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let line = pace.read_line(reader).await?;
+        // Chunk extensions after `;` carry nothing we forward
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| anyhow!("Invalid chunk size: {}", size))?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes.saturating_sub(body.len()) {
+            return Err(anyhow!(BodyTooLargeError {
+                limit: limits.max_body_bytes,
+            }));
+        }
+        read_body(reader, &mut body, size, pace).await?;
+        if !matches!(pace.read_line(reader).await?.as_str(), "\r\n" | "\n") {
+            return Err(anyhow!("Chunk is longer than its declared size"));
+        }
+    }
+
+    let mut trailers = Params::new();
+    loop {
+        let line = pace.read_line(reader).await?;
+        if line == "\r\n" || line == "\n" {
+            return Ok((body, trailers));
+        }
+        if trailers.len() >= limits.max_headers {
+            return Err(anyhow!(SlowClientError("Too many request headers")));
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            trailers.append(key.trim().to_lowercase(), value.trim());
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Status of the first response sent inside `record_status`
+    static SENT_STATUS: Arc<AtomicU16>;
+}
+
+/// Run `future`, storing the status code of the first response it sends
+/// with `HttpResponse::send` in `sent` (0 until then). The caller keeps
+/// `sent`, so it still knows after `future` panicked or was dropped.
+pub async fn record_status<F: Future>(sent: Arc<AtomicU16>, future: F) -> F::Output {
+    SENT_STATUS.scope(sent, future).await
 }
 
 /// HTTP response builder
@@ -196,9 +830,20 @@ impl HttpResponse {
             201 => "Created",
             204 => "No Content",
             400 => "Bad Request",
+            401 => "Unauthorized",
             404 => "Not Found",
+            403 => "Forbidden",
             405 => "Method Not Allowed",
+            409 => "Conflict",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            422 => "Unprocessable Entity",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
             500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
             _ => "Unknown",
         }
         .to_string();
@@ -264,8 +909,8 @@ impl HttpResponse {
         self
     }
 
-    /// Send the response to a TCP stream
-    pub async fn send(mut self, stream: &mut TcpStream) -> Result<()> {
+    /// Send the response to a stream
+    pub async fn send<S: AsyncWrite + Unpin>(mut self, stream: &mut S) -> Result<()> {
         // Add CORS headers
         if !self.headers.contains_key("Access-Control-Allow-Origin") {
             self.headers
@@ -280,10 +925,15 @@ impl HttpResponse {
         if !self.headers.contains_key("Access-Control-Allow-Headers") {
             self.headers.insert(
                 "Access-Control-Allow-Headers".to_string(),
-                "Content-Type, Authorization".to_string(),
+                "Content-Type, Authorization, Last-Event-ID".to_string(),
             );
         }
 
+        let _ = SENT_STATUS.try_with(|status| {
+            let _ =
+                status.compare_exchange(0, self.status_code, Ordering::SeqCst, Ordering::SeqCst);
+        });
+
         // Add content-length header
         self.headers
             .insert("Content-Length".to_string(), self.body.len().to_string());
@@ -319,3 +969,260 @@ pub fn json_error(status_code: u16, message: impl Into<String>) -> HttpResponse
 
     HttpResponse::new(status_code).json(&error_body)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict_limits() -> ParseLimits {
+        ParseLimits {
+            header_timeout: Some(Duration::from_millis(100)),
+            max_header_bytes: 256,
+            min_body_rate: 1024,
+            body_grace: Duration::from_millis(100),
+            ..ParseLimits::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_reads_complete_request() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /api/sessions?token=abc HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}")
+            .await
+            .unwrap();
+
+        let request = HttpRequest::parse_with_limits(&mut server, "t", &strict_limits())
+            .await
+            .unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.query_param("token").map(String::as_str),
+            Some("abc")
+        );
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[tokio::test]
+    async fn identity_headers_replace_what_the_caller_sent() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nX-ARP-Remote-Addr: 10.0.0.1:1\r\nX-ARP-Client-Id: other\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut request = HttpRequest::parse_with_limits(&mut server, "t", &strict_limits())
+            .await
+            .unwrap();
+
+        request.set_identity(Some("203.0.113.7:52100"), "c0ffee", "my-client");
+        assert_eq!(
+            request.remote_addr().map(String::as_str),
+            Some("203.0.113.7:52100")
+        );
+        assert_eq!(request.header_values("X-ARP-Client-Id").count(), 1);
+        assert_eq!(
+            request.header("X-ARP-Client-Id").map(String::as_str),
+            Some("my-client")
+        );
+        assert_eq!(
+            request.header("X-ARP-Conn-Id").map(String::as_str),
+            Some("c0ffee")
+        );
+
+        request.set_identity(None, "c0ffee", "my-client");
+        assert_eq!(request.remote_addr(), None);
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_times_out_trickled_headers() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x")
+            .await
+            .unwrap();
+
+        let err = HttpRequest::parse_with_limits(&mut server, "t", &strict_limits())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<SlowClientError>().is_some());
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_rejects_oversized_headers() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let big = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(512));
+        client.write_all(big.as_bytes()).await.unwrap();
+
+        let err = HttpRequest::parse_with_limits(&mut server, "t", &strict_limits())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<SlowClientError>().is_some());
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_refuses_oversized_body_unread() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 4096\r\n\r\n")
+            .await
+            .unwrap();
+
+        let limits = ParseLimits {
+            max_body_bytes: 1024,
+            ..strict_limits()
+        };
+        let err = HttpRequest::parse_with_limits(&mut server, "t", &limits)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<BodyTooLargeError>().unwrap().limit, 1024);
+    }
+
+    #[tokio::test]
+    async fn parse_keeps_repeated_headers_and_query_params() {
+        let mut input: &[u8] =
+            b"GET /?tag=a&tag=b&x=1 HTTP/1.1\r\nCookie: a=1\r\ncookie: b=2\r\n\r\n";
+        let request = HttpRequest::parse_with_limits(&mut input, "t", &ParseLimits::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            request.query_param_values("tag").collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(request.query_param("tag").map(String::as_str), Some("a"));
+        assert_eq!(
+            request.header_values("Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+
+        let mut params = request.query_params;
+        params.insert("tag", "c");
+        assert_eq!(params.get_all("tag").collect::<Vec<_>>(), ["c"]);
+        assert_eq!(params.remove("x").as_deref(), Some("1"));
+        assert_eq!(params.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn parse_decodes_chunked_body_and_trailers() {
+        let mut input: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nX-Checksum: 42\r\n\r\n";
+        let request = HttpRequest::parse_with_limits(&mut input, "t", &ParseLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(request.body, b"abcde");
+        assert_eq!(
+            request.trailers.get("x-checksum").map(String::as_str),
+            Some("42")
+        );
+        assert!(request.is_chunked());
+        assert_eq!(
+            request.chunked_body(),
+            b"5\r\nabcde\r\n0\r\nx-checksum: 42\r\n\r\n"
+        );
+
+        let mut smuggled: &[u8] =
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(
+            HttpRequest::parse_with_limits(&mut smuggled, "t", &ParseLimits::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_caps_header_count() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(11));
+        client.write_all(many.as_bytes()).await.unwrap();
+
+        let limits = ParseLimits {
+            max_header_bytes: 4096,
+            max_headers: 10,
+            ..strict_limits()
+        };
+        let err = HttpRequest::parse_with_limits(&mut server, "t", &limits)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<SlowClientError>().is_some());
+    }
+
+    #[tokio::test]
+    async fn parse_rejects_invalid_or_conflicting_content_length() {
+        for head in [
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\n",
+        ] {
+            let mut input = head.as_bytes();
+            let err = HttpRequest::parse_with_limits(&mut input, "t", &ParseLimits::default())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("Content-Length"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_fails_on_short_body_without_reserving_declared_length() {
+        let mut input: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 1000000000000\r\n\r\nab";
+        let err = HttpRequest::parse_with_limits(&mut input, "t", &ParseLimits::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("closed while reading body"));
+    }
+
+    #[test]
+    fn multipart_body_splits_into_parts() {
+        let body = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+hello\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a;b \\\"c\\\".bin\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+\x00\r\n--X\xff\r\n--XyZ--\r\n";
+        let headers =
+            Params::from_iter([("content-type", "multipart/form-data; boundary=\"XyZ\"")]);
+        let request = HttpRequest {
+            method: HttpMethod::POST,
+            path: "/".to_string(),
+            query_params: Params::new(),
+            headers,
+            body: body.to_vec(),
+            trailers: Params::new(),
+        };
+
+        let parts = request.multipart().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            (parts[0].name.as_str(), parts[0].filename.as_ref()),
+            ("note", None)
+        );
+        assert_eq!(parts[0].data, b"hello");
+        assert_eq!(parts[1].filename.as_deref(), Some("a;b \"c\".bin"));
+        assert_eq!(
+            parts[1].content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(parts[1].data, b"\x00\r\n--X\xff");
+
+        let truncated = HttpRequest {
+            body: body[..body.len() - 12].to_vec(),
+            ..request
+        };
+        assert!(truncated.multipart().is_err());
+    }
+
+    #[tokio::test]
+    async fn parse_with_limits_rejects_slow_body() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 100000\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let err = HttpRequest::parse_with_limits(&mut server, "t", &strict_limits())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<SlowClientError>().is_some());
+        drop(client);
+    }
+}
@@ -1,44 +1,360 @@
 use anyhow::{Result, anyhow};
+use futures_util::FutureExt;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+pub mod auth;
+pub mod crash;
 pub mod http;
 
 /// Commands exchanged between client and server.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     /// Register a new client. Sent from arpc to arps.
-    Register { client_id: String },
+    Register {
+        client_id: String,
+        /// Hostnames or subdomains the client wants to reserve for host-based routing.
+        #[serde(default)]
+        hostnames: Vec<String>,
+        /// Tenant token, `<tenant>.<secret>`, on servers shared by several
+        /// tenants.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
     /// Result of the registration. Sent from arps to arpc.
     RegisterResult {
         success: bool,
         error: Option<String>,
+        /// Machine-readable failure reason, e.g. `hostname_conflict`.
+        #[serde(default)]
+        error_code: Option<String>,
+        /// Registration generation assigned by the server. Clients echo it in
+        /// `NewProxyConn` so connections from an older registration can be told apart.
+        #[serde(default)]
+        generation: Option<u64>,
+        /// ID the client was registered under, `<tenant>:<client_id>` when
+        /// it registered with a tenant token.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
     },
     /// Request a new proxy connection. Sent from arps to a chosen arpc.
-    RequestNewProxyConn { proxy_conn_id: String },
+    RequestNewProxyConn {
+        proxy_conn_id: String,
+        /// Local address the connection is for, when it arrived on a port
+        /// opened with `OpenPort`, or the name of a service offered with
+        /// `arpc tunnel`; the client's usual service otherwise.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+    },
     /// Notify the proxy listener that a new client is ready. Sent from arpc to arps.
     NewProxyConn {
         proxy_conn_id: String,
         client_id: String,
+        /// Opened proactively by the client to pre-warm the pool rather than
+        /// in answer to a `RequestNewProxyConn`.
+        #[serde(default)]
+        pooled: bool,
+        /// Generation from the `RegisterResult` this connection belongs to.
+        #[serde(default)]
+        generation: Option<u64>,
+        /// `target` of the `RequestNewProxyConn` answered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        /// Tenant token the client registered with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Answer to a `RequestNewProxyConn`. Sent from arpc to arps on the control
+    /// channel; a rejection lets the server fail the waiting user connection
+    /// right away instead of waiting for it to expire.
+    ProxyConnAck {
+        proxy_conn_id: String,
+        accepted: bool,
+        #[serde(default)]
+        reason: Option<String>,
+        /// `target` of the `RequestNewProxyConn` answered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+    },
+    /// Ask for a public port whose connections are tunneled as raw TCP to
+    /// `target`, a local address of the client. Sent from arpc to arps on
+    /// the control channel; the port closes when the client disconnects.
+    OpenPort {
+        target: String,
+        /// Port wanted; any free one when unset.
+        #[serde(default)]
+        port: Option<u16>,
+    },
+    /// Answer to an `OpenPort`. Sent from arps to arpc.
+    OpenPortResult {
+        target: String,
+        /// The public port opened, unless refused.
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// Join the rest of this connection with a tunnel to `service` of
+    /// `client_id`, or to its usual service when unset. Sent by
+    /// `arpc connect` as the first frame on the proxy port, in place of
+    /// `NewProxyConn`.
+    ConnectService {
+        client_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service: Option<String>,
+        /// Tenant token; `client_id` is then looked up in its tenant.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Ask for a direct connection to `service` of `client_id` through NAT
+    /// hole punching. Sent by `arpc connect --direct` as the first frame on
+    /// the proxy port; answered with `PunchPeer`.
+    PunchRequest {
+        client_id: String,
+        service: String,
+        punch_id: String,
+        /// Tenant token; `client_id` is then looked up in its tenant.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Ask the client to punch a hole towards the peer of `punch_id`. Sent
+    /// from arps to arpc on the control channel; refused with a
+    /// `ProxyConnAck` for `punch_id`.
+    Punch { punch_id: String, service: String },
+    /// Sent by the client answering a `Punch` as the first frame on the
+    /// proxy port, answered with `PunchPeer`, and by `arpc connect` as the
+    /// first frame on the direct connection.
+    PunchReady { punch_id: String },
+    /// Public address of the other side of `punch_id` to punch towards;
+    /// unset when it refused or didn't show up, so the connection is relayed.
+    PunchPeer {
+        punch_id: String,
+        #[serde(default)]
+        peer_addr: Option<String>,
+    },
+    /// Periodic load report. Sent from arpc to arps on the control channel so
+    /// the server can stop routing new work to a saturated client.
+    LoadReport {
+        /// Agent sessions currently running.
+        active_sessions: u32,
+        /// One-minute load average of the host, when available.
+        #[serde(default)]
+        load_avg: Option<f64>,
+        /// The client asks not to receive new connections.
+        busy: bool,
+    },
+    /// A session stopped running. Sent from arpc to arps on the control
+    /// channel so the server can publish it to admin subscribers.
+    SessionEvent {
+        session_id: String,
+        /// `completed`, `failed` or `cancelled`.
+        status: String,
+        /// Executor that ran the session, e.g. `claude`.
+        executor: String,
+        /// The agent's own session ID, when it reported one.
+        #[serde(default)]
+        agent_session_id: Option<String>,
+        #[serde(default)]
+        project_path: Option<String>,
+        #[serde(default)]
+        exit_code: Option<i32>,
+        /// Failure or cancellation reason.
+        #[serde(default)]
+        error: Option<String>,
+        /// Unix timestamp in seconds of when the session ended.
+        timestamp: u64,
+    },
+    /// Change settings of a running client. Sent from arps to arpc; the
+    /// client applies them live and answers with a `ConfigUpdateAck`.
+    ConfigUpdate {
+        update_id: String,
+        settings: ConfigSettings,
+    },
+    /// Answer to a `ConfigUpdate`. Sent from arpc to arps on the control channel.
+    ConfigUpdateAck {
+        update_id: String,
+        /// Names of the settings that were applied.
+        applied: Vec<String>,
+        /// Why nothing was applied, when the update was rejected.
+        #[serde(default)]
+        error: Option<String>,
     },
+    /// Heartbeat on the control channel, sent by either side while it's
+    /// idle; the other answers with a `Pong` echoing `seq`.
+    Ping { seq: u64 },
+    /// Answer to a `Ping`.
+    Pong { seq: u64 },
+    /// Another command, authenticated with the key the server shares with
+    /// its clients; sent in its place on the control channel, and for
+    /// `NewProxyConn`, when a key is configured. See [`auth`].
+    Signed {
+        /// Random, never accepted twice
+        nonce: String,
+        /// Unix seconds when signed
+        timestamp: u64,
+        /// The command as JSON
+        payload: String,
+        /// Hex HMAC-SHA256 of nonce, timestamp and payload
+        mac: String,
+    },
+}
+
+/// Client settings an operator can change without restarting it. Settings
+/// left out keep their current value.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigSettings {
+    /// Proxy connections kept pre-warmed by the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_size: Option<usize>,
+    /// Concurrent proxy connections before new ones are rejected (0 = unlimited).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_proxy_connections: Option<usize>,
+    /// Running sessions at which the client reports busy (0 = unlimited).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// Terminal log filter: a level (`off`, `error`, `warn`, `info`, `debug`
+    /// or `trace`) or target directives such as `info,arpc::session=debug`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    /// Seconds before an unanswered permission request expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permission_timeout_secs: Option<u64>,
+    /// Per-tool expiry timeouts in seconds, replacing the current ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permission_tool_timeouts: Option<std::collections::HashMap<String, u64>>,
+    /// Tools approved instead of denied on expiry, replacing the current list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permission_auto_approve: Option<Vec<String>>,
+}
+
+impl Command {
+    /// Variant names understood by this build, used to tell a newer peer's
+    /// command apart from a malformed frame.
+    const KNOWN: &'static [&'static str] = &[
+        "Register",
+        "RegisterResult",
+        "RequestNewProxyConn",
+        "NewProxyConn",
+        "ProxyConnAck",
+        "OpenPort",
+        "OpenPortResult",
+        "ConnectService",
+        "PunchRequest",
+        "Punch",
+        "PunchReady",
+        "PunchPeer",
+        "LoadReport",
+        "SessionEvent",
+        "ConfigUpdate",
+        "ConfigUpdateAck",
+        "Ping",
+        "Pong",
+        "Signed",
+    ];
 }
 
+/// Largest command frame accepted or sent (1 MiB).
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Bytes reserved for a frame before its payload arrives.
+const FRAME_PREALLOC_SIZE: usize = 16 * 1024;
+
 /// Reads a command from an async reader.
-/// The format is a 4-byte length prefix (u32) followed by the JSON-encoded command.
+/// The format is a 4-byte big-endian length prefix (u32) followed by the
+/// JSON-encoded command. Frames carrying a command variant this build doesn't
+/// know are skipped so newer peers can add commands without breaking us.
 pub async fn read_command<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Command> {
+    loop {
+        let buf = read_frame(reader).await?;
+
+        let value: serde_json::Value = serde_json::from_slice(&buf)
+            .map_err(|e| anyhow!("Failed to deserialize command: {}", e))?;
+        match Command::deserialize(&value) {
+            Ok(command) => return Ok(command),
+            Err(e) => match command_tag(&value) {
+                Some(tag) if !Command::KNOWN.contains(&tag) => {
+                    tracing::debug!("Skipping unknown command '{}'", tag);
+                }
+                _ => return Err(anyhow!("Failed to deserialize command: {}", e)),
+            },
+        }
+    }
+}
+
+/// Reads one length-prefixed frame, rejecting frames larger than `MAX_FRAME_SIZE`.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf).await?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf).await?;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "Command frame of {} bytes exceeds limit of {} bytes",
+            len,
+            MAX_FRAME_SIZE
+        ));
+    }
 
-    serde_json::from_slice(&buf).map_err(|e| anyhow!("Failed to deserialize command: {}", e))
+    // Grow with the bytes actually received rather than trusting the prefix,
+    // so idle peers announcing large frames can't pin memory.
+    let mut buf = Vec::with_capacity(len.min(FRAME_PREALLOC_SIZE));
+    reader.take(len as u64).read_to_end(&mut buf).await?;
+    if buf.len() < len {
+        return Err(anyhow!(std::io::Error::from(
+            std::io::ErrorKind::UnexpectedEof
+        )));
+    }
+    Ok(buf)
+}
+
+/// Read commands from `reader` on a task of their own, so waiting for the
+/// next one can be raced against timers without losing a partly read
+/// frame. Commands failing `auth` are passed on as errors and reading goes
+/// on; the channel closes after any other error, which it carries last.
+/// Dropping the receiver stops the task and drops `reader`.
+pub fn spawn_command_reader<R>(
+    mut reader: R,
+    auth: Option<std::sync::Arc<auth::CommandAuth>>,
+) -> tokio::sync::mpsc::Receiver<Result<Command>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let result = tokio::select! {
+                result = auth::read_command_with(&mut reader, auth.as_deref()) => result,
+                _ = tx.closed() => break,
+            };
+            let fatal = result.as_ref().is_err_and(|e| !e.is::<auth::AuthError>());
+            if tx.send(result).await.is_err() || fatal {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Variant name of an externally tagged command: `"Name"` or `{"Name": {...}}`.
+fn command_tag(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::String(tag) => Some(tag),
+        serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str),
+        _ => None,
+    }
 }
 
 /// Writes a command to an async writer.
-/// The format is a 4-byte length prefix (u32) followed by the JSON-encoded command.
+/// The format is a 4-byte big-endian length prefix (u32) followed by the
+/// JSON-encoded command.
 pub async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, command: &Command) -> Result<()> {
     let buf = serde_json::to_vec(command)?;
+    if buf.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "Command frame of {} bytes exceeds limit of {} bytes",
+            buf.len(),
+            MAX_FRAME_SIZE
+        ));
+    }
     let len = buf.len() as u32;
 
     writer.write_all(&len.to_be_bytes()).await?;
@@ -47,15 +363,252 @@ pub async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, command: &Comm
     Ok(())
 }
 
+/// Default per-direction copy buffer size (64 KiB).
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// When a copy direction flushes buffered data to its writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Write straight through and flush after every chunk. Lowest latency,
+    /// best for interactive traffic such as SSE session streams.
+    Immediate,
+    /// Coalesce chunks in a write buffer and flush only when the source has no
+    /// more data ready (or the buffer fills). Fewer syscalls for bulk transfers.
+    OnIdle,
+}
+
+impl std::str::FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "immediate" => Ok(FlushPolicy::Immediate),
+            "on-idle" | "on_idle" | "idle" => Ok(FlushPolicy::OnIdle),
+            other => Err(format!(
+                "invalid flush policy '{}', expected 'immediate' or 'on-idle'",
+                other
+            )),
+        }
+    }
+}
+
+/// Tuning for one direction of a bidirectional copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionConfig {
+    pub buffer_size: usize,
+    pub flush: FlushPolicy,
+}
+
+impl Default for DirectionConfig {
+    fn default() -> Self {
+        DirectionConfig {
+            buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            flush: FlushPolicy::Immediate,
+        }
+    }
+}
+
+/// Tuning for [`join_streams_with`]. `upstream` copies from the first stream to
+/// the second (user -> agent), `downstream` copies the other way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyConfig {
+    pub upstream: DirectionConfig,
+    pub downstream: DirectionConfig,
+}
+
+/// Joins two streams, copying data in both directions with the default [`CopyConfig`].
+pub async fn join_streams<A, B>(a: A, b: B) -> std::io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    join_streams_with(a, b, &CopyConfig::default()).await
+}
+
 /// Joins two streams, copying data in both directions.
-/// Optimized version using copy_bidirectional for better performance.
-pub async fn join_streams<A, B>(mut a: A, mut b: B) -> std::io::Result<()>
+///
+/// Each direction owns its own buffer and awaits the writer before reading more,
+/// so a slow peer applies backpressure to the fast one instead of growing memory.
+/// When one side reaches EOF the opposite writer is shut down and the other
+/// direction keeps running until it finishes too.
+pub async fn join_streams_with<A, B>(a: A, b: B, config: &CopyConfig) -> std::io::Result<()>
 where
     A: AsyncRead + AsyncWrite + Unpin,
     B: AsyncRead + AsyncWrite + Unpin,
 {
-    // tokio::io::copy_bidirectional is more efficient than dual tokio::select!
-    // It uses a single buffer pool and optimized copying logic
-    tokio::io::copy_bidirectional(&mut a, &mut b).await?;
+    let (mut a_reader, mut a_writer) = tokio::io::split(a);
+    let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+    tokio::try_join!(
+        copy_one_way(&mut a_reader, &mut b_writer, config.upstream),
+        copy_one_way(&mut b_reader, &mut a_writer, config.downstream),
+    )?;
     Ok(())
 }
+
+/// Copies `reader` into `writer` until EOF, then shuts the writer down.
+async fn copy_one_way<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    config: DirectionConfig,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buffer_size = config.buffer_size.max(1);
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0u64;
+
+    match config.flush {
+        FlushPolicy::Immediate => loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await?;
+            total += n as u64;
+        },
+        FlushPolicy::OnIdle => {
+            let mut buffered = tokio::io::BufWriter::with_capacity(buffer_size, &mut *writer);
+            loop {
+                // Reads are cancel-safe, so probing without waiting loses no data.
+                let n = match reader.read(&mut buf).now_or_never() {
+                    Some(res) => res?,
+                    None => {
+                        buffered.flush().await?;
+                        reader.read(&mut buf).await?
+                    }
+                };
+                if n == 0 {
+                    break;
+                }
+                buffered.write_all(&buf[..n]).await?;
+                total += n as u64;
+            }
+            buffered.flush().await?;
+        }
+    }
+
+    writer.shutdown().await?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn write_raw_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) {
+        writer
+            .write_all(&(payload.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        writer.write_all(payload).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_command_skips_unknown_variants() {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        write_raw_frame(&mut tx, br#"{"FutureCommand":{"x":1}}"#).await;
+        write_raw_frame(&mut tx, br#""FutureUnit""#).await;
+        write_command(
+            &mut tx,
+            &Command::RequestNewProxyConn {
+                proxy_conn_id: "abc".into(),
+                target: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        match read_command(&mut rx).await.unwrap() {
+            Command::RequestNewProxyConn { proxy_conn_id, .. } => {
+                assert_eq!(proxy_conn_id, "abc")
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_command_rejects_malformed_known_variant_and_oversized_frames() {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        write_raw_frame(&mut tx, br#"{"Register":{"wrong":1}}"#).await;
+        assert!(read_command(&mut rx).await.is_err());
+
+        tx.write_all(&((MAX_FRAME_SIZE as u32) + 1).to_be_bytes())
+            .await
+            .unwrap();
+        let err = read_command(&mut rx).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds limit"));
+    }
+
+    #[tokio::test]
+    async fn read_command_fails_on_truncated_frame() {
+        let mut input: Vec<u8> = (MAX_FRAME_SIZE as u32).to_be_bytes().to_vec();
+        input.extend_from_slice(br#"{"Register":"#);
+
+        let err = read_command(&mut input.as_slice()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    async fn echo_roundtrip(config: CopyConfig) {
+        let (user, user_peer) = tokio::io::duplex(16);
+        let (agent, mut agent_peer) = tokio::io::duplex(16);
+
+        let join = tokio::spawn(async move { join_streams_with(user_peer, agent, &config).await });
+
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let (mut user_reader, mut user_writer) = tokio::io::split(user);
+
+        let writer = tokio::spawn(async move {
+            user_writer.write_all(&payload).await.unwrap();
+            user_writer.shutdown().await.unwrap();
+        });
+
+        // Agent echoes everything it receives back to the user.
+        let echo = tokio::spawn(async move {
+            let mut received = Vec::new();
+            agent_peer.read_to_end(&mut received).await.unwrap();
+            agent_peer.write_all(&received).await.unwrap();
+            agent_peer.shutdown().await.unwrap();
+        });
+
+        let mut echoed = Vec::new();
+        user_reader.read_to_end(&mut echoed).await.unwrap();
+
+        writer.await.unwrap();
+        echo.await.unwrap();
+        join.await.unwrap().unwrap();
+        assert_eq!(echoed, expected);
+    }
+
+    #[tokio::test]
+    async fn join_streams_with_immediate_flush_roundtrips() {
+        echo_roundtrip(CopyConfig::default()).await;
+    }
+
+    #[tokio::test]
+    async fn join_streams_with_on_idle_flush_roundtrips() {
+        let direction = DirectionConfig {
+            buffer_size: 4096,
+            flush: FlushPolicy::OnIdle,
+        };
+        echo_roundtrip(CopyConfig {
+            upstream: direction,
+            downstream: direction,
+        })
+        .await;
+    }
+
+    #[test]
+    fn flush_policy_parses_cli_values() {
+        assert_eq!("immediate".parse(), Ok(FlushPolicy::Immediate));
+        assert_eq!("on-idle".parse(), Ok(FlushPolicy::OnIdle));
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+}
@@ -0,0 +1,478 @@
+use crate::claims::HostClaims;
+use crate::tls::{CertResolver, CertStore, certified_key, write_private};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// How often claimed hostnames are checked for missing or stale certificates.
+const SCAN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait before retrying a hostname whose issuance failed.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Settings for automatic certificate issuance.
+pub struct AcmeConfig {
+    pub directory: String,
+    pub email: Option<String>,
+    pub renew_after: Duration,
+    pub data_dir: PathBuf,
+}
+
+/// Issues and renews certificates for claimed hostnames in the background.
+pub struct AcmeManager {
+    config: AcmeConfig,
+    store: CertStore,
+    resolver: Arc<CertResolver>,
+    claims: Arc<HostClaims>,
+    wake: Notify,
+}
+
+impl AcmeManager {
+    pub fn new(config: AcmeConfig, resolver: Arc<CertResolver>, claims: Arc<HostClaims>) -> Self {
+        AcmeManager {
+            store: CertStore::new(&config.data_dir),
+            config,
+            resolver,
+            claims,
+            wake: Notify::new(),
+        }
+    }
+
+    /// Check claimed hostnames now instead of at the next scan.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut failures: HashMap<String, Instant> = HashMap::new();
+        let mut client: Option<AcmeClient> = None;
+
+        loop {
+            for host in self.claims.hostnames() {
+                // Bare labels are subdomain claims without a known parent domain
+                if !host.contains('.') {
+                    continue;
+                }
+                if self
+                    .store
+                    .age(&host)
+                    .is_some_and(|age| age < self.config.renew_after)
+                {
+                    continue;
+                }
+                if failures
+                    .get(&host)
+                    .is_some_and(|failed| failed.elapsed() < FAILURE_BACKOFF)
+                {
+                    continue;
+                }
+
+                match self.issue(&mut client, &host).await {
+                    Ok(()) => {
+                        failures.remove(&host);
+                    }
+                    Err(e) => {
+                        error!("ACME issuance for {} failed: {}", host, e);
+                        failures.insert(host, Instant::now());
+                        // Start from a fresh session (directory, nonce) next time
+                        client = None;
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(SCAN_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn issue(&self, client: &mut Option<AcmeClient>, host: &str) -> Result<()> {
+        if client.is_none() {
+            *client = Some(AcmeClient::connect(&self.config).await?);
+        }
+        let client = client.as_mut().expect("client initialized above");
+
+        info!("Requesting certificate for {} from ACME", host);
+        let (cert_pem, key_pem) = client.issue(host, &self.resolver).await?;
+
+        self.resolver.set_cert(
+            host,
+            certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?,
+        );
+        self.store.save(host, &cert_pem, &key_pem)?;
+        info!("Installed certificate for {}", host);
+        Ok(())
+    }
+}
+
+/// Response fields the ACME flow needs.
+struct AcmeResponse {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> Result<Value> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Minimal RFC 8555 client using ES256 account keys and TLS-ALPN-01.
+struct AcmeClient {
+    http: reqwest::Client,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    kid: Option<String>,
+    nonce: Option<String>,
+    new_nonce: String,
+    new_order: String,
+}
+
+impl AcmeClient {
+    async fn connect(config: &AcmeConfig) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let key = load_account_key(&config.data_dir, &rng)?;
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        let directory: Value = http
+            .get(&config.directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let url = |name: &str| {
+            directory[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("ACME directory is missing {}", name))
+        };
+
+        let mut client = AcmeClient {
+            http,
+            jwk: jwk(&key),
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+            new_nonce: url("newNonce")?,
+            new_order: url("newOrder")?,
+        };
+
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &config.email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let response = client.post(&url("newAccount")?, Some(&account)).await?;
+        client.kid = Some(
+            response
+                .location
+                .ok_or_else(|| anyhow!("ACME account response has no Location"))?,
+        );
+        Ok(client)
+    }
+
+    async fn issue(&mut self, host: &str, resolver: &CertResolver) -> Result<(String, String)> {
+        let order = self
+            .post(
+                &self.new_order.clone(),
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": host }] })),
+            )
+            .await?;
+        let order_url = order
+            .location
+            .clone()
+            .ok_or_else(|| anyhow!("ACME order response has no Location"))?;
+        let order = order.json()?;
+
+        for authz_url in order["authorizations"].as_array().into_iter().flatten() {
+            let authz_url = authz_url.as_str().unwrap_or_default();
+            let result = self.authorize(host, authz_url, resolver).await;
+            resolver.clear_challenge(host);
+            result?;
+        }
+
+        let cert_key = rcgen::KeyPair::generate()?;
+        let mut params = rcgen::CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let csr = params.serialize_request(&cert_key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| anyhow!("ACME order has no finalize URL"))?;
+        self.post(
+            finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+        )
+        .await?;
+
+        let order = self.poll(&order_url, "processing").await?;
+        let cert_url = order["certificate"]
+            .as_str()
+            .ok_or_else(|| anyhow!("ACME order has no certificate URL"))?;
+        let cert = self.post(cert_url, None).await?;
+        Ok((String::from_utf8(cert.body)?, cert_key.serialize_pem()))
+    }
+
+    /// Complete one authorization with the TLS-ALPN-01 challenge.
+    async fn authorize(
+        &mut self,
+        host: &str,
+        authz_url: &str,
+        resolver: &CertResolver,
+    ) -> Result<()> {
+        let authz = self.post(authz_url, None).await?.json()?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authz["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| c["type"] == "tls-alpn-01")
+            .ok_or_else(|| anyhow!("ACME server offered no tls-alpn-01 challenge for {}", host))?;
+        let token = challenge["token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("ACME challenge has no token"))?;
+        let challenge_url = challenge["url"]
+            .as_str()
+            .ok_or_else(|| anyhow!("ACME challenge has no URL"))?
+            .to_string();
+
+        let key_authorization = format!("{}.{}", token, thumbprint(&self.jwk));
+        resolver.set_challenge(host, challenge_cert(host, &key_authorization)?);
+
+        self.post(&challenge_url, Some(&json!({}))).await?;
+        self.poll(authz_url, "pending").await?;
+        Ok(())
+    }
+
+    /// Poll an order or authorization until it leaves `waiting_status`.
+    async fn poll(&mut self, url: &str, waiting_status: &str) -> Result<Value> {
+        for _ in 0..30 {
+            let resource = self.post(url, None).await?.json()?;
+            match resource["status"].as_str() {
+                Some("valid") => return Ok(resource),
+                Some(status) if status == waiting_status || status == "ready" => {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+                _ => return Err(anyhow!("ACME validation failed: {}", resource)),
+            }
+        }
+        Err(anyhow!("Timed out waiting for ACME resource {}", url))
+    }
+
+    /// Signed POST; `None` sends a POST-as-GET. Retries once on a bad nonce.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.fetch_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(serde_json::to_vec(&body)?)
+                .send()
+                .await?;
+
+            self.nonce = header(&response, "replay-nonce");
+            let location = header(&response, "location");
+            let status = response.status();
+            let body = response.bytes().await?.to_vec();
+
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            warn!("ACME request to {} failed with {}", url, status);
+            return Err(anyhow!("ACME error {}: {}", status, problem));
+        }
+    }
+
+    async fn fetch_nonce(&self) -> Result<String> {
+        let response = self.http.head(&self.new_nonce).send().await?;
+        header(&response, "replay-nonce").ok_or_else(|| anyhow!("ACME server returned no nonce"))
+    }
+
+    /// Flattened JWS body signed with the account key.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Value> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+}
+
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Load the ACME account key from `<data_dir>/acme/account.pk8`, creating it on first use.
+fn load_account_key(data_dir: &std::path::Path, rng: &SystemRandom) -> Result<EcdsaKeyPair> {
+    let path = data_dir.join("acme").join("account.pk8");
+    let pkcs8 = match std::fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| anyhow!("Failed to generate ACME account key"))?;
+            std::fs::create_dir_all(path.parent().expect("path has a parent"))?;
+            write_private(&path, pkcs8.as_ref())?;
+            info!("Created ACME account key at {:?}", path);
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|_| anyhow!("Invalid ACME account key at {:?}", path))
+}
+
+/// Public JWK of a P-256 key.
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // Uncompressed point: 0x04 || x || y
+    let point = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+        "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+    })
+}
+
+/// RFC 7638 thumbprint: SHA-256 over the required members in lexical order.
+fn thumbprint(jwk: &Value) -> String {
+    let canonical = format!(
+        r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+        jwk["crv"].as_str().unwrap_or_default(),
+        jwk["kty"].as_str().unwrap_or_default(),
+        jwk["x"].as_str().unwrap_or_default(),
+        jwk["y"].as_str().unwrap_or_default(),
+    );
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        canonical.as_bytes(),
+    ))
+}
+
+/// Self-signed certificate carrying the acmeIdentifier extension (RFC 8737).
+fn challenge_cert(
+    host: &str,
+    key_authorization: &str,
+) -> Result<tokio_rustls::rustls::sign::CertifiedKey> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    let key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(vec![host.to_string()])?;
+    params
+        .custom_extensions
+        .push(rcgen::CustomExtension::new_acme_identifier(digest.as_ref()));
+    let cert = params.self_signed(&key)?;
+    certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+
+    fn test_client() -> AcmeClient {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        AcmeClient {
+            http: reqwest::Client::new(),
+            jwk: jwk(&key),
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+            new_nonce: String::new(),
+            new_order: String::new(),
+        }
+    }
+
+    #[test]
+    fn jws_signature_verifies_with_account_key() {
+        let client = test_client();
+        let body = client
+            .sign(
+                "https://acme.test/new-order",
+                "nonce",
+                Some(&json!({ "a": 1 })),
+            )
+            .unwrap();
+
+        let signed = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(body["signature"].as_str().unwrap())
+            .unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, client.key.public_key().as_ref())
+            .verify(signed.as_bytes(), &signature)
+            .expect("signature verifies");
+
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(body["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(protected["jwk"], client.jwk);
+        assert_eq!(protected["nonce"], "nonce");
+    }
+
+    #[test]
+    fn challenge_cert_builds_for_key_authorization() {
+        let client = test_client();
+        let key_authorization = format!("token.{}", thumbprint(&client.jwk));
+        assert!(challenge_cert("agent.example.com", &key_authorization).is_ok());
+        // Thumbprint is base64url of a SHA-256 digest
+        assert_eq!(thumbprint(&client.jwk).len(), 43);
+    }
+}
@@ -0,0 +1,683 @@
+use crate::client_logs::stream_client_logs;
+use crate::events::{EventKind, stream_events};
+use crate::ports;
+use crate::{
+    ClientInfo, ServerState, generate_id, route_public_connection, unix_timestamp,
+    write_http_request,
+};
+use anyhow::Result;
+use common::auth::constant_time_eq;
+use common::http::{HttpMethod, HttpRequest, HttpResponse, Params, ParseLimits, json_error};
+use common::{Command, ConfigSettings};
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncWriteExt, copy};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How long a client gets to acknowledge a config update.
+const CONFIG_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A client's answer to a config update.
+pub struct ConfigAck {
+    pub applied: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Config updates waiting for their acknowledgement, by update ID.
+pub type PendingConfigAcks = Arc<DashMap<String, oneshot::Sender<ConfigAck>>>;
+
+/// Clients an operator evicted, refused registration until their ban ends.
+#[derive(Default)]
+pub struct Evictions {
+    banned_until: DashMap<String, Instant>,
+}
+
+impl Evictions {
+    /// Refuse `client_id` for `duration`; a zero duration lifts its ban.
+    pub fn ban(&self, client_id: &str, duration: Duration) {
+        if duration.is_zero() {
+            self.banned_until.remove(client_id);
+        } else {
+            self.banned_until
+                .insert(client_id.to_string(), Instant::now() + duration);
+        }
+    }
+
+    /// How much longer `client_id` is refused, if it is.
+    pub fn remaining(&self, client_id: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.banned_until
+            .remove_if(client_id, |_, until| *until <= now);
+        self.banned_until
+            .get(client_id)
+            .map(|until| until.saturating_duration_since(now))
+    }
+}
+
+/// Serve the admin HTTP API:
+///
+/// - `GET /events[?client=<id>][&event=session|client]` streams session events
+///   and client registrations/disconnects as SSE
+/// - `GET /admin/agents` lists registered clients with their load and recent sessions
+/// - `GET /admin/clients` lists registered clients with their address, uptime
+///   and pool depth, and `GET /admin/clients/{client_id}/pool` one client's pool
+/// - `DELETE /admin/clients/{client_id}[?ban_secs=N]` disconnects a client,
+///   refusing its registrations for `ban_secs` seconds
+/// - `GET /admin/tenants` shows each tenant's limits, registered clients and
+///   counters when the server is shared with --tenants-file
+/// - `GET /admin/usage[?month=YYYY-MM][&format=csv]` exports the connections
+///   and bytes of each client and tenant per month, for chargeback
+/// - `GET /admin/liveness[/{client_id}]` shows whether the clients seen so far
+///   are flapping, and one client's connect/disconnect history
+/// - `GET /admin/agents/{client_id}/sessions[/...]` is proxied down that client's
+///   tunnel to its `/api/sessions[/...]` endpoints
+/// - `POST /admin/agents/{client_id}/config` pushes settings to one client and
+///   `POST /admin/config` to every client, answering with their acknowledgements
+pub async fn serve_admin(listener: TcpListener, state: ServerState) -> Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Accept error on admin listener: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin_connection(stream, state).await {
+                debug!("Admin connection from {} ended: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_admin_connection(mut stream: TcpStream, state: ServerState) -> Result<()> {
+    let limits = ParseLimits {
+        header_timeout: Some(Duration::from_secs(10)),
+        max_header_bytes: 16 * 1024,
+        ..ParseLimits::default()
+    };
+    let request = HttpRequest::parse_with_limits(&mut stream, "admin", &limits).await?;
+
+    if let Some(token) = &state.admin_token {
+        let presented = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !presented
+            .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+        {
+            return json_error(401, "Missing or invalid admin token")
+                .header("WWW-Authenticate", "Bearer")
+                .send(&mut stream)
+                .await;
+        }
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    if request.method == HttpMethod::DELETE {
+        return match segments.as_slice() {
+            ["admin", "clients", client_id] => {
+                let ban_secs = match request.query_param("ban_secs").map(|secs| secs.parse()) {
+                    Some(Ok(secs)) => secs,
+                    Some(Err(_)) => {
+                        return json_error(400, "ban_secs must be a number of seconds")
+                            .send(&mut stream)
+                            .await;
+                    }
+                    None => 0,
+                };
+                evict_client(
+                    &mut stream,
+                    &state,
+                    client_id,
+                    Duration::from_secs(ban_secs),
+                )
+                .await
+            }
+            _ => {
+                json_error(405, "Method not allowed")
+                    .send(&mut stream)
+                    .await
+            }
+        };
+    }
+    if request.method == HttpMethod::POST {
+        return match segments.as_slice() {
+            ["admin", "config"] => push_config_to_all(&mut stream, &state, &request.body).await,
+            ["admin", "log-level"] => set_log_filter(&mut stream, &state, &request.body).await,
+            ["admin", "agents", client_id, "config"] => {
+                push_config_to_client(&mut stream, &state, client_id, &request.body).await
+            }
+            _ => {
+                json_error(405, "Method not allowed")
+                    .send(&mut stream)
+                    .await
+            }
+        };
+    }
+    if request.method != HttpMethod::GET {
+        return json_error(405, "Method not allowed")
+            .send(&mut stream)
+            .await;
+    }
+
+    match segments.as_slice() {
+        ["events"] => {
+            let client_filter = request.query_param("client").cloned();
+            let kind_filter = match request
+                .query_param("event")
+                .map(|kind| EventKind::parse(kind))
+            {
+                Some(Some(kind)) => Some(kind),
+                Some(None) => {
+                    return json_error(400, "event must be session or client")
+                        .send(&mut stream)
+                        .await;
+                }
+                None => None,
+            };
+            stream_events(&mut stream, &state.events, client_filter, kind_filter).await
+        }
+        ["admin", "agents"] => {
+            let body = json!({ "type": "agents", "agents": list_agents(&state) });
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "clients"] => {
+            let body = json!({ "type": "clients", "clients": list_clients(&state) });
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "clients", client_id, "pool"] => match state
+            .active_clients
+            .get(*client_id)
+            .map(|info| pool_of(&state, client_id, &info))
+        {
+            Some(mut body) => {
+                body["type"] = json!("pool");
+                HttpResponse::ok().json(&body).send(&mut stream).await
+            }
+            None => {
+                json_error(404, format!("Client '{}' not found", client_id))
+                    .send(&mut stream)
+                    .await
+            }
+        },
+        ["admin", "clients", client_id, "logs"] => {
+            stream_client_logs(&mut stream, &state.client_logs, client_id).await
+        }
+        ["admin", "log-level"] => {
+            let mut body = state.log_filter.snapshot();
+            body["type"] = json!("log_level");
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "pairing"] => {
+            let mut body = state.pairing.snapshot();
+            body["type"] = json!("pairing");
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "tenants"] => {
+            let tenants = state.tenants.as_ref().map_or(Vec::new(), |tenants| {
+                tenants.snapshot(|tenant| {
+                    state
+                        .active_clients
+                        .iter()
+                        .filter(|client| tenant.owns(client.key()))
+                        .count()
+                })
+            });
+            let body = json!({ "type": "tenants", "tenants": tenants });
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "usage"] => {
+            let month = request.query_param("month").map(String::as_str);
+            if request
+                .query_param("format")
+                .is_some_and(|format| format == "csv")
+            {
+                return HttpResponse::ok()
+                    .text(state.usage.report_csv(month))
+                    .header("Content-Type", "text/csv")
+                    .send(&mut stream)
+                    .await;
+            }
+            let mut body = state.usage.report(month);
+            body["type"] = json!("usage");
+            body["month"] = json!(month);
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "liveness"] => {
+            let body = json!({ "type": "liveness", "clients": state.liveness.summaries() });
+            HttpResponse::ok().json(&body).send(&mut stream).await
+        }
+        ["admin", "liveness", client_id] => match state.liveness.history(client_id) {
+            Some(history) => {
+                let mut body = state.liveness.summary(client_id);
+                body["client_id"] = json!(client_id);
+                body["history"] = json!(history);
+                HttpResponse::ok().json(&body).send(&mut stream).await
+            }
+            None => {
+                json_error(404, format!("Client {} was never seen", client_id))
+                    .send(&mut stream)
+                    .await
+            }
+        },
+        ["admin", "agents", client_id, "sessions", rest @ ..] => {
+            let path = ["/api/sessions"]
+                .into_iter()
+                .chain(rest.iter().copied())
+                .collect::<Vec<_>>()
+                .join("/");
+            proxy_to_client(&mut stream, &state, client_id, path, request.query_params).await
+        }
+        _ => json_error(404, "Not found").send(&mut stream).await,
+    }
+}
+
+/// Change the server's terminal log filter: `{"filter": "info,arps=debug",
+/// "ttl_secs": 300}` applies a filter, for `ttl_secs` if given; an empty
+/// object restores the default.
+async fn set_log_filter(stream: &mut TcpStream, state: &ServerState, body: &[u8]) -> Result<()> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request @ Value::Object(_)) => request,
+        _ => {
+            return json_error(400, "Body must be a JSON object")
+                .send(stream)
+                .await;
+        }
+    };
+    let ttl = request["ttl_secs"].as_u64().map(Duration::from_secs);
+    let result = match request["filter"].as_str() {
+        Some(filter) => state.log_filter.set(filter, ttl),
+        None => state.log_filter.reset(),
+    };
+    if let Err(e) = result {
+        return json_error(400, e).send(stream).await;
+    }
+    let mut body = state.log_filter.snapshot();
+    body["type"] = json!("log_level");
+    HttpResponse::ok().json(&body).send(stream).await
+}
+
+/// Fleet view of every registered client.
+fn list_agents(state: &ServerState) -> Vec<Value> {
+    let mut agents: Vec<Value> = state
+        .active_clients
+        .iter()
+        .map(|entry| {
+            let client_id = entry.key();
+            let info = entry.value();
+            let load = info
+                .load
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .map(|load| {
+                    json!({
+                        "active_sessions": load.active_sessions,
+                        "load_avg": load.load_avg,
+                        "busy": load.busy,
+                        "reported_secs_ago": load.received.elapsed().as_secs(),
+                    })
+                });
+            json!({
+                "client_id": client_id,
+                "generation": info.generation,
+                "pooled_connections": info.pool.len(),
+                "queued_connections": state.pending_connections.depths(client_id),
+                "deferred_dials": info.dials.deferred(),
+                "refused_dials": info.dials.refused.load(Ordering::Relaxed),
+                "hostnames": state.host_claims.hostnames_of(client_id),
+                "mapped_ports": ports::ports_of(&state.port_maps, client_id),
+                "open_ports": state.open_ports.of(client_id),
+                "load": load,
+                "liveness": state.liveness.summary(client_id),
+                "recent_sessions": state.events.recent(client_id),
+            })
+        })
+        .collect();
+    agents.sort_by(|a, b| a["client_id"].as_str().cmp(&b["client_id"].as_str()));
+    agents
+}
+
+/// Operator view of every registered client.
+fn list_clients(state: &ServerState) -> Vec<Value> {
+    let mut clients: Vec<Value> = state
+        .active_clients
+        .iter()
+        .map(|entry| {
+            let (client_id, info) = (entry.key(), entry.value());
+            json!({
+                "client_id": client_id,
+                "tenant": state
+                    .tenants
+                    .as_ref()
+                    .and_then(|tenants| tenants.owner(client_id))
+                    .map(|tenant| tenant.id.clone()),
+                "generation": info.generation,
+                "remote_addr": info.remote_addr.to_string(),
+                "registered_at": info.registered_at,
+                "connected_secs": unix_timestamp().saturating_sub(info.registered_at),
+                "busy": info.busy_load().is_some(),
+                "pool": pool_of(state, client_id, info),
+            })
+        })
+        .collect();
+    clients.sort_by(|a, b| a["client_id"].as_str().cmp(&b["client_id"].as_str()));
+    clients
+}
+
+/// A client's idle tunnels against the pool size it should have, and the
+/// public connections waiting for one.
+fn pool_of(state: &ServerState, client_id: &str, info: &ClientInfo) -> Value {
+    json!({
+        "client_id": client_id,
+        "pooled_connections": info.pool.len(),
+        "pool_size": state.tuning.pool_size_of(client_id),
+        "queued_connections": state.pending_connections.depths(client_id),
+        "deferred_dials": info.dials.deferred(),
+        "refused_dials": info.dials.refused.load(Ordering::Relaxed),
+    })
+}
+
+/// Close a client's control connection, dropping its registration and
+/// pooled tunnels, and refuse it for `ban` so it doesn't just reconnect.
+/// The ban also applies to a client that isn't connected.
+async fn evict_client(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    client_id: &str,
+    ban: Duration,
+) -> Result<()> {
+    state.evictions.ban(client_id, ban);
+    let info = state
+        .active_clients
+        .get(client_id)
+        .map(|info| info.value().clone());
+    if let Some(info) = &info {
+        info.evicted.notify_one();
+        warn!(
+            "Evicting client {} (generation {}), refused for {}s",
+            client_id,
+            info.generation,
+            ban.as_secs()
+        );
+    }
+    let body = json!({
+        "type": "eviction",
+        "client_id": client_id,
+        "disconnected": info.is_some(),
+        "generation": info.as_ref().map(|info| info.generation),
+        "ban_secs": ban.as_secs(),
+    });
+    HttpResponse::ok().json(&body).send(stream).await
+}
+
+/// Send a GET for `path` through the client's tunnel, like a public request
+/// routed to it, and relay the raw response.
+async fn proxy_to_client(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    client_id: &str,
+    path: String,
+    mut query_params: Params,
+) -> Result<()> {
+    if !state.active_clients.contains_key(client_id) {
+        return json_error(404, format!("Client '{}' not found", client_id))
+            .send(stream)
+            .await;
+    }
+    let Ok(permit) = state.public_guard.try_admit() else {
+        return json_error(503, "Server overloaded").send(stream).await;
+    };
+
+    query_params.insert("token".to_string(), client_id.to_string());
+    let request = HttpRequest {
+        method: HttpMethod::GET,
+        path,
+        query_params,
+        headers: Params::from_iter([
+            ("Host", "arps-admin"),
+            ("X-ARP-Client", client_id),
+            ("Connection", "close"),
+        ]),
+        body: Vec::new(),
+        trailers: Params::new(),
+    };
+
+    let (mut local, tunnel) = tokio::io::duplex(64 * 1024);
+    let tunnel_state = state.clone();
+    tokio::spawn(async move {
+        let _ = route_public_connection(Box::new(tunnel), None, permit, tunnel_state).await;
+    });
+
+    write_http_request(&mut local, &request).await?;
+    copy(&mut local, stream).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Settings from a config push body, or the error response to send.
+fn parse_settings(body: &[u8]) -> Result<ConfigSettings, HttpResponse> {
+    let settings: ConfigSettings = serde_json::from_slice(body)
+        .map_err(|e| json_error(400, format!("Invalid settings: {}", e)))?;
+    if settings == ConfigSettings::default() {
+        return Err(json_error(400, "No settings given"));
+    }
+    Ok(settings)
+}
+
+async fn push_config_to_client(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    client_id: &str,
+    body: &[u8],
+) -> Result<()> {
+    let settings = match parse_settings(body) {
+        Ok(settings) => settings,
+        Err(response) => return response.send(stream).await,
+    };
+
+    let mut result = push_config(state, client_id, settings).await;
+    let status = match result["status"].as_str() {
+        Some("applied") => 200,
+        Some("rejected") => 422,
+        Some("not_found") => 404,
+        _ => 504,
+    };
+    result["type"] = json!("config_update");
+    HttpResponse::new(status).json(&result).send(stream).await
+}
+
+async fn push_config_to_all(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    body: &[u8],
+) -> Result<()> {
+    let settings = match parse_settings(body) {
+        Ok(settings) => settings,
+        Err(response) => return response.send(stream).await,
+    };
+
+    let pushes: Vec<_> = state
+        .active_clients
+        .iter()
+        .map(|entry| {
+            let (state, client_id, settings) =
+                (state.clone(), entry.key().clone(), settings.clone());
+            tokio::spawn(async move { push_config(&state, &client_id, settings).await })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(pushes.len());
+    for push in pushes {
+        results.extend(push.await.ok());
+    }
+    results.sort_by(|a, b| a["client_id"].as_str().cmp(&b["client_id"].as_str()));
+    let body = json!({ "type": "config_update", "results": results });
+    HttpResponse::ok().json(&body).send(stream).await
+}
+
+/// Send `settings` to a client and wait for its acknowledgement. Clients too
+/// old to know `ConfigUpdate` skip it and end up as `timeout`.
+async fn push_config(state: &ServerState, client_id: &str, settings: ConfigSettings) -> Value {
+    let Some(cmd_tx) = state
+        .active_clients
+        .get(client_id)
+        .map(|info| info.cmd_tx.clone())
+    else {
+        return json!({ "client_id": client_id, "status": "not_found" });
+    };
+
+    let update_id = generate_id();
+    let (ack_tx, ack_rx) = oneshot::channel();
+    state.config_acks.insert(update_id.clone(), ack_tx);
+    info!(
+        "Pushing config update {} to {}: {:?}",
+        update_id, client_id, settings
+    );
+
+    let sent = cmd_tx
+        .send(Command::ConfigUpdate {
+            update_id: update_id.clone(),
+            settings,
+        })
+        .is_ok();
+    let ack = if sent {
+        tokio::time::timeout(CONFIG_ACK_TIMEOUT, ack_rx).await.ok()
+    } else {
+        None
+    };
+    state.config_acks.remove(&update_id);
+
+    match ack.and_then(Result::ok) {
+        Some(ConfigAck {
+            applied,
+            error: None,
+        }) => json!({ "client_id": client_id, "status": "applied", "applied": applied }),
+        Some(ConfigAck {
+            error: Some(error), ..
+        }) => json!({ "client_id": client_id, "status": "rejected", "error": error }),
+        None => json!({ "client_id": client_id, "status": "timeout" }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{register_test_client, test_state};
+    use tokio::io::AsyncReadExt;
+
+    /// Send `request` to the admin API and return the status, headers and
+    /// JSON body of the response
+    async fn admin(state: &ServerState, request: &str) -> (u16, String, Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let handled = tokio::spawn(handle_admin_connection(stream, state.clone()));
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handled.await.unwrap().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (
+            status,
+            head.to_string(),
+            serde_json::from_str(body).unwrap(),
+        )
+    }
+
+    fn get(path: &str, token: Option<&str>) -> String {
+        let auth = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        format!("GET {} HTTP/1.1\r\nHost: admin\r\n{}\r\n", path, auth)
+    }
+
+    #[tokio::test]
+    async fn admin_api_requires_the_configured_token() {
+        let mut state = test_state(&[]);
+        let (status, _, _) = admin(&state, &get("/admin/clients", None)).await;
+        assert_eq!(status, 200);
+
+        state.admin_token = Some(Arc::from("s3cret"));
+        for token in [None, Some("wrong"), Some("s3cre")] {
+            let (status, head, body) = admin(&state, &get("/admin/clients", token)).await;
+            assert_eq!(status, 401, "{:?}", token);
+            assert!(head.contains("WWW-Authenticate: Bearer"));
+            assert_eq!(body["type"], "error");
+        }
+        // Every route is behind the token, including mutating ones
+        let evict = "DELETE /admin/clients/agent HTTP/1.1\r\n\r\n";
+        assert_eq!(admin(&state, evict).await.0, 401);
+        assert!(state.evictions.remaining("agent").is_none());
+
+        let (status, _, body) = admin(&state, &get("/admin/clients", Some("s3cret"))).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["type"], "clients");
+    }
+
+    #[tokio::test]
+    async fn listings_describe_registered_clients() {
+        let state = test_state(&["--pool-size", "4"]);
+        let _agent = register_test_client(&state, "agent");
+        let _other = register_test_client(&state, "other");
+
+        let (status, _, body) = admin(&state, &get("/admin/clients", None)).await;
+        assert_eq!(status, 200);
+        let clients = body["clients"].as_array().unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0]["client_id"], "agent");
+        assert_eq!(clients[1]["client_id"], "other");
+        assert_eq!(clients[0]["remote_addr"], "198.51.100.7:40000");
+        assert_eq!(clients[0]["tenant"], Value::Null);
+        assert_eq!(clients[0]["busy"], false);
+        assert_eq!(clients[0]["pool"]["pool_size"], 4);
+        assert_eq!(clients[0]["pool"]["pooled_connections"], 0);
+
+        let (status, _, body) = admin(&state, &get("/admin/clients/other/pool", None)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["type"], "pool");
+        assert_eq!(body["client_id"], "other");
+        let (status, _, _) = admin(&state, &get("/admin/clients/missing/pool", None)).await;
+        assert_eq!(status, 404);
+
+        let (status, _, body) = admin(&state, &get("/admin/agents", None)).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["type"], "agents");
+        let agent = &body["agents"][0];
+        assert_eq!(agent["client_id"], "agent");
+        assert_eq!(agent["load"], Value::Null);
+        assert!(agent["mapped_ports"].as_array().unwrap().is_empty());
+        assert!(agent["recent_sessions"].is_array());
+
+        let put = "PUT /admin/clients HTTP/1.1\r\n\r\n";
+        assert_eq!(admin(&state, put).await.0, 405);
+    }
+
+    #[test]
+    fn evicted_clients_are_refused_until_their_ban_ends() {
+        let evictions = Evictions::default();
+        assert_eq!(evictions.remaining("agent"), None);
+
+        evictions.ban("agent", Duration::from_secs(60));
+        let remaining = evictions.remaining("agent").unwrap();
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60));
+        assert_eq!(evictions.remaining("other"), None);
+        evictions.ban("agent", Duration::ZERO);
+        assert_eq!(evictions.remaining("agent"), None);
+
+        evictions.ban("agent", Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(evictions.remaining("agent"), None);
+        assert!(evictions.banned_until.is_empty());
+    }
+}
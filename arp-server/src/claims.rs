@@ -0,0 +1,205 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use std::fmt;
+
+/// Why a hostname claim was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClaimError {
+    /// The hostname is not a valid DNS name.
+    Invalid(String),
+    /// The hostname is already held by another client.
+    Conflict { hostname: String, owner: String },
+}
+
+impl ClaimError {
+    /// Stable machine-readable code sent back in `RegisterResult`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClaimError::Invalid(_) => "invalid_hostname",
+            ClaimError::Conflict { .. } => "hostname_conflict",
+        }
+    }
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaimError::Invalid(hostname) => write!(f, "Invalid hostname '{}'", hostname),
+            ClaimError::Conflict { hostname, owner } => {
+                write!(
+                    f,
+                    "Hostname '{}' is already claimed by '{}'",
+                    hostname, owner
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClaimError {}
+
+/// Hostnames reserved by registered clients, used for host-based routing.
+#[derive(Default)]
+pub struct HostClaims {
+    // hostname -> (client_id, registration generation)
+    claims: DashMap<String, (String, u64)>,
+}
+
+impl HostClaims {
+    /// Reserve all `hostnames` for `client_id`, or none of them. A client may
+    /// re-claim names it already holds from an earlier registration.
+    pub fn claim(
+        &self,
+        client_id: &str,
+        generation: u64,
+        hostnames: &[String],
+    ) -> Result<(), ClaimError> {
+        let mut normalized = Vec::with_capacity(hostnames.len());
+        for hostname in hostnames {
+            let name = normalize_host(hostname);
+            if !is_valid_hostname(&name) {
+                return Err(ClaimError::Invalid(hostname.clone()));
+            }
+            normalized.push(name);
+        }
+
+        let mut taken = Vec::new();
+        for name in normalized {
+            match self.claims.entry(name.clone()) {
+                Entry::Occupied(entry) if entry.get().0 != client_id => {
+                    let owner = entry.get().0.clone();
+                    drop(entry);
+                    // Roll back what this call already reserved
+                    for name in taken {
+                        self.claims.remove(&name);
+                    }
+                    return Err(ClaimError::Conflict {
+                        hostname: name,
+                        owner,
+                    });
+                }
+                Entry::Occupied(mut entry) => {
+                    entry.insert((client_id.to_string(), generation));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((client_id.to_string(), generation));
+                    taken.push(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the claims held by one registration of `client_id`.
+    pub fn release(&self, client_id: &str, generation: u64) {
+        self.claims.retain(|_, (owner, owner_generation)| {
+            !(owner == client_id && *owner_generation == generation)
+        });
+    }
+
+    /// All currently claimed hostnames.
+    pub fn hostnames(&self) -> Vec<String> {
+        self.claims
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Hostnames currently claimed by `client_id`.
+    pub fn hostnames_of(&self, client_id: &str) -> Vec<String> {
+        let mut hostnames: Vec<String> = self
+            .claims
+            .iter()
+            .filter(|entry| entry.value().0 == client_id)
+            .map(|entry| entry.key().clone())
+            .collect();
+        hostnames.sort();
+        hostnames
+    }
+
+    /// Client that claimed `host` (a `Host` header value), matching the full
+    /// name first and then its first label as a subdomain claim.
+    pub fn is_empty(&self) -> bool {
+        self.claims.is_empty()
+    }
+
+    pub fn resolve(&self, host: &str) -> Option<String> {
+        let host = normalize_host(host);
+        if let Some(entry) = self.claims.get(&host) {
+            return Some(entry.0.clone());
+        }
+        let label = host.split('.').next()?;
+        self.claims.get(label).map(|entry| entry.0.clone())
+    }
+}
+
+/// Lowercase and strip any `:port` suffix.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn conflicting_claim_is_rejected_atomically() {
+        let claims = HostClaims::default();
+        claims.claim("a", 1, &names(&["one.example.com"])).unwrap();
+
+        let err = claims
+            .claim("b", 2, &names(&["two.example.com", "ONE.example.com"]))
+            .unwrap_err();
+        assert_eq!(err.code(), "hostname_conflict");
+        assert_eq!(claims.resolve("two.example.com"), None);
+        assert_eq!(claims.hostnames_of("a"), ["one.example.com"]);
+        assert!(claims.hostnames_of("b").is_empty());
+        assert_eq!(
+            claims.resolve("one.example.com:17003").as_deref(),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn release_only_drops_matching_generation() {
+        let claims = HostClaims::default();
+        claims.claim("a", 1, &names(&["agent"])).unwrap();
+        claims.claim("a", 2, &names(&["agent"])).unwrap();
+
+        claims.release("a", 1);
+        assert_eq!(
+            claims.resolve("agent.proxy.example.com").as_deref(),
+            Some("a")
+        );
+
+        claims.release("a", 2);
+        assert_eq!(claims.resolve("agent.proxy.example.com"), None);
+    }
+
+    #[test]
+    fn invalid_hostnames_are_refused() {
+        let claims = HostClaims::default();
+        for bad in ["", "-bad.example.com", "under_score", "a..b"] {
+            let err = claims.claim("a", 1, &names(&[bad])).unwrap_err();
+            assert_eq!(err.code(), "invalid_hostname");
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::time::interval;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Record};
+use tracing::{Event, Id, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Log lines kept per client.
+const LINES_PER_CLIENT: usize = 500;
+
+/// Clients whose logs are kept; the one logged to least recently is
+/// forgotten first.
+const MAX_CLIENTS: usize = 1024;
+
+/// Lines buffered per live subscriber before slow ones start missing lines.
+const LIVE_BUFFER: usize = 1024;
+
+/// A server log event attributed to a client.
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// Increases with every line, across clients
+    pub seq: u64,
+    pub client_id: Arc<str>,
+    pub payload: Arc<str>,
+}
+
+struct ClientBuffer {
+    lines: VecDeque<LogLine>,
+    last_seq: u64,
+}
+
+/// Recent server log lines per client, filled by [`ClientLogLayer`]. A line
+/// belongs to a client when the event or one of its spans has a `client_id`
+/// field.
+pub struct ClientLogs {
+    buffers: DashMap<Arc<str>, ClientBuffer>,
+    tx: broadcast::Sender<LogLine>,
+    seq: AtomicU64,
+}
+
+impl Default for ClientLogs {
+    fn default() -> Self {
+        ClientLogs {
+            buffers: DashMap::new(),
+            tx: broadcast::channel(LIVE_BUFFER).0,
+            seq: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ClientLogs {
+    fn push(&self, client_id: &str, line: Value) {
+        let line = LogLine {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+            client_id: client_id.into(),
+            payload: line.to_string().into(),
+        };
+
+        if !self.buffers.contains_key(client_id) && self.buffers.len() >= MAX_CLIENTS {
+            let oldest = self
+                .buffers
+                .iter()
+                .min_by_key(|entry| entry.last_seq)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                self.buffers.remove(&oldest);
+            }
+        }
+        let mut buffer = self
+            .buffers
+            .entry(line.client_id.clone())
+            .or_insert_with(|| ClientBuffer {
+                lines: VecDeque::new(),
+                last_seq: 0,
+            });
+        if buffer.lines.len() == LINES_PER_CLIENT {
+            buffer.lines.pop_front();
+        }
+        buffer.last_seq = line.seq;
+        buffer.lines.push_back(line.clone());
+        drop(buffer);
+
+        // No subscribers is the common case and not an error
+        let _ = self.tx.send(line);
+    }
+
+    /// Buffered lines of `client_id`, oldest first.
+    pub fn recent(&self, client_id: &str) -> Vec<LogLine> {
+        self.buffers
+            .get(client_id)
+            .map(|buffer| buffer.lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.tx.subscribe()
+    }
+}
+
+/// Stream the buffered log lines of `client_id` to `stream` as SSE, then
+/// every new one until the subscriber goes away.
+pub async fn stream_client_logs<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    logs: &ClientLogs,
+    client_id: &str,
+) -> anyhow::Result<()> {
+    // Subscribe first so no line falls between the replay and the live tail
+    let mut live = logs.subscribe();
+    let recent = logs.recent(client_id);
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+        )
+        .await?;
+    let mut replayed = 0;
+    for line in recent {
+        replayed = line.seq;
+        stream
+            .write_all(format!("event: log\ndata: {}\n\n", line.payload).as_bytes())
+            .await?;
+    }
+    stream.flush().await?;
+
+    let mut keepalive = interval(crate::events::KEEPALIVE_INTERVAL);
+    loop {
+        let chunk = tokio::select! {
+            line = live.recv() => match line {
+                Ok(line) => {
+                    if line.seq <= replayed || *line.client_id != *client_id {
+                        continue;
+                    }
+                    format!("event: log\ndata: {}\n\n", line.payload)
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    format!(": {} log lines dropped\n\n", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+        stream.write_all(chunk.as_bytes()).await?;
+        stream.flush().await?;
+    }
+}
+
+/// `client_id` recorded on a span
+struct SpanClientId(String);
+
+/// Finds the `client_id` field among span or event fields, and formats the
+/// rest of an event like the terminal log does.
+#[derive(Default)]
+struct FieldVisitor {
+    client_id: Option<String>,
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "client_id" => self.client_id = Some(value.to_string()),
+            "message" => self.message = value.to_string(),
+            name => {
+                let _ = write!(self.fields, " {}={}", name, value);
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "client_id" => self.client_id = Some(format!("{:?}", value)),
+            "message" => self.message = format!("{:?}", value),
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+/// Tracing layer copying every event attributed to a client into
+/// [`ClientLogs`].
+pub struct ClientLogLayer {
+    logs: Arc<ClientLogs>,
+}
+
+impl ClientLogLayer {
+    pub fn new(logs: Arc<ClientLogs>) -> Self {
+        ClientLogLayer { logs }
+    }
+}
+
+impl<S> Layer<S> for ClientLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(client_id), Some(span)) = (visitor.client_id, ctx.span(id)) {
+            span.extensions_mut().insert(SpanClientId(client_id));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let (Some(client_id), Some(span)) = (visitor.client_id, ctx.span(id)) {
+            span.extensions_mut().replace(SpanClientId(client_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let client_id = visitor.client_id.take().or_else(|| {
+            ctx.event_scope(event)?.find_map(|span| {
+                span.extensions()
+                    .get::<SpanClientId>()
+                    .map(|client_id| client_id.0.clone())
+            })
+        });
+        let Some(client_id) = client_id else {
+            return;
+        };
+
+        let metadata = event.metadata();
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.logs.push(
+            &client_id,
+            json!({
+                "timestamp_ms": timestamp_ms,
+                "level": metadata.level().as_str(),
+                "target": metadata.target(),
+                "message": visitor.message + &visitor.fields,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, info_span, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn events_are_attributed_by_field_or_span() {
+        let logs = Arc::new(ClientLogs::default());
+        let subscriber = tracing_subscriber::registry().with(ClientLogLayer::new(logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("Not about any client");
+            warn!(client_id = "a", "Removing expired pending connection");
+
+            let span = info_span!("public", client_id = tracing::field::Empty);
+            let _entered = span.enter();
+            info!("Before the client is known");
+            span.record("client_id", "b");
+            info!(attempt = 2, "Routed to client");
+        });
+
+        let a = logs.recent("a");
+        assert_eq!(a.len(), 1);
+        let line: Value = serde_json::from_str(&a[0].payload).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "Removing expired pending connection");
+
+        let b = logs.recent("b");
+        assert_eq!(b.len(), 1);
+        let line: Value = serde_json::from_str(&b[0].payload).unwrap();
+        assert_eq!(line["message"], "Routed to client attempt=2");
+        assert!(b[0].seq > a[0].seq);
+    }
+
+    #[test]
+    fn buffers_are_bounded() {
+        let logs = ClientLogs::default();
+        for i in 0..LINES_PER_CLIENT + 10 {
+            logs.push("a", json!({ "message": i }));
+        }
+        let recent = logs.recent("a");
+        assert_eq!(recent.len(), LINES_PER_CLIENT);
+        assert_eq!(recent[0].payload.as_ref(), r#"{"message":10}"#);
+
+        for i in 0..MAX_CLIENTS {
+            logs.push(&format!("c{}", i), json!({}));
+        }
+        assert!(logs.recent("a").is_empty());
+        assert_eq!(logs.buffers.len(), MAX_CLIENTS);
+    }
+}
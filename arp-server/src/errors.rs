@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use common::http::HttpResponse;
+use serde_json::json;
+use std::path::Path;
+
+/// Where handling of a public request failed, so failures can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureStage {
+    /// The public listener is at its connection limits
+    Overloaded,
+    /// The request headers or body arrived too slowly
+    SlowRequest,
+    /// The request body is larger than --max-body-bytes
+    BodyTooLarge,
+    /// No client is registered (or none registered while the request was held)
+    NoClient,
+    /// The request names no client, or one that isn't registered
+    UnknownClient,
+    /// The client reported itself saturated
+    ClientBusy,
+    /// The queue of held requests is full
+    QueueFull,
+    /// The client is asked for tunnels faster than --dial-rate and its
+    /// queue of deferred requests is full
+    DialRateLimited,
+    /// The client couldn't open a tunnel for the request
+    PoolExhausted,
+    /// No tunnel arrived for the request in time
+    PairingTimeout,
+    /// The client or its tenant used up a monthly quota
+    QuotaExceeded,
+}
+
+impl FailureStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureStage::Overloaded => "overloaded",
+            FailureStage::SlowRequest => "slow_request",
+            FailureStage::BodyTooLarge => "body_too_large",
+            FailureStage::NoClient => "no_client",
+            FailureStage::UnknownClient => "unknown_client",
+            FailureStage::ClientBusy => "client_busy",
+            FailureStage::QueueFull => "queue_full",
+            FailureStage::DialRateLimited => "dial_rate_limited",
+            FailureStage::PoolExhausted => "pool_exhausted",
+            FailureStage::PairingTimeout => "pairing_timeout",
+            FailureStage::QuotaExceeded => "quota_exceeded",
+        }
+    }
+
+    pub fn status(self) -> u16 {
+        match self {
+            FailureStage::SlowRequest => 408,
+            FailureStage::BodyTooLarge => 413,
+            FailureStage::UnknownClient => 404,
+            FailureStage::PoolExhausted => 502,
+            FailureStage::PairingTimeout => 504,
+            FailureStage::QuotaExceeded => 429,
+            FailureStage::Overloaded
+            | FailureStage::NoClient
+            | FailureStage::ClientBusy
+            | FailureStage::QueueFull
+            | FailureStage::DialRateLimited => 503,
+        }
+    }
+}
+
+/// Body format of error responses on the public port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    Json,
+    /// HTML for browsers (requests accepting `text/html`), JSON otherwise
+    Html,
+}
+
+const DEFAULT_ERROR_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{{status}} {{title}}</title></head>
+<body style="font-family: sans-serif; max-width: 40em; margin: 4em auto; color: #333">
+<h1>{{status}} {{title}}</h1>
+<p>{{message}}</p>
+<p style="color: #888; font-size: 0.9em">Stage: <code>{{stage}}</code> &middot; Request ID: <code>{{request_id}}</code></p>
+</body>
+</html>
+"#;
+
+/// Builds the error responses the server itself sends on the public port.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    format: ErrorFormat,
+    template: String,
+}
+
+impl ErrorPages {
+    pub fn new(format: ErrorFormat) -> Self {
+        ErrorPages {
+            format,
+            template: DEFAULT_ERROR_PAGE.to_string(),
+        }
+    }
+
+    /// Use the HTML template at `path` instead of the built-in page.
+    /// `{{status}}`, `{{title}}`, `{{message}}`, `{{stage}}` and
+    /// `{{request_id}}` are replaced with the details of each failure.
+    pub fn with_template(mut self, path: &Path) -> Result<Self> {
+        self.template = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read error page {}", path.display()))?;
+        Ok(self)
+    }
+
+    /// Response for a request that failed at `stage`. `accept` is the
+    /// request's `Accept` header, if the request was parsed.
+    pub fn response(
+        &self,
+        stage: FailureStage,
+        message: impl Into<String>,
+        request_id: &str,
+        accept: Option<&str>,
+    ) -> HttpResponse {
+        let message = message.into();
+        let status = stage.status();
+        let response = HttpResponse::new(status)
+            .header("X-ARP-Request-Id", request_id)
+            .header("X-ARP-Error-Stage", stage.as_str());
+
+        let wants_html = accept.is_some_and(|accept| accept.contains("text/html"));
+        if self.format == ErrorFormat::Html && wants_html {
+            let title = status_title(status);
+            let page = self
+                .template
+                .replace("{{status}}", &status.to_string())
+                .replace("{{title}}", title)
+                .replace("{{message}}", &escape_html(&message))
+                .replace("{{stage}}", stage.as_str())
+                .replace("{{request_id}}", &escape_html(request_id));
+            return response
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(page.into_bytes());
+        }
+
+        response.json(&json!({
+            "type": "error",
+            "status": status,
+            "stage": stage.as_str(),
+            "message": message,
+            "request_id": request_id,
+        }))
+    }
+}
+
+fn status_title(status: u16) -> &'static str {
+    match status {
+        404 => "Not Found",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Service Unavailable",
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn render(response: HttpResponse) -> String {
+        let mut out = Vec::new();
+        response.send(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn html_is_only_served_to_browsers() {
+        let pages = ErrorPages::new(ErrorFormat::Html);
+        let stage = FailureStage::PairingTimeout;
+
+        let page = render(pages.response(stage, "<slow>", "req-1", Some("text/html"))).await;
+        assert!(page.starts_with("HTTP/1.1 504 Gateway Timeout"));
+        assert!(page.contains("X-ARP-Error-Stage: pairing_timeout"));
+        assert!(page.contains("&lt;slow&gt;"));
+        assert!(page.contains("<code>req-1</code>"));
+
+        let api = render(pages.response(stage, "slow", "req-2", Some("application/json"))).await;
+        assert!(api.contains(r#""stage":"pairing_timeout""#));
+        assert!(api.contains(r#""request_id":"req-2""#));
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, interval};
+use tracing::{info, warn};
+
+/// Events buffered per subscriber before slow ones start missing events.
+const EVENT_BUFFER: usize = 1024;
+
+/// Session events remembered per client for the fleet view.
+const RECENT_EVENTS_PER_CLIENT: usize = 20;
+
+/// Comment line sent to idle subscribers so proxies keep the stream open.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a webhook endpoint gets to answer one delivery.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What an admin event is about; also its SSE event name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A session on a client ended
+    Session,
+    /// A client registered, re-registered or disconnected
+    Client,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Session => "session",
+            EventKind::Client => "client",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "session" => Some(EventKind::Session),
+            "client" => Some(EventKind::Client),
+            _ => None,
+        }
+    }
+}
+
+/// An event published to admin subscribers.
+#[derive(Debug, Clone)]
+pub struct AdminEvent {
+    pub client_id: String,
+    pub kind: EventKind,
+    pub payload: Arc<str>,
+}
+
+/// Fans session and client lifecycle events out to the admin firehose
+/// subscribers and webhooks, keeping the latest few session events per client.
+pub struct EventHub {
+    tx: broadcast::Sender<AdminEvent>,
+    recent: DashMap<String, VecDeque<Value>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        EventHub {
+            tx: broadcast::channel(EVENT_BUFFER).0,
+            recent: DashMap::new(),
+        }
+    }
+}
+
+impl EventHub {
+    /// Publish a session event reported by `client_id`.
+    pub fn publish(&self, client_id: &str, event: Value) {
+        let payload: Arc<str> = event.to_string().into();
+
+        let mut recent = self.recent.entry(client_id.to_string()).or_default();
+        if recent.len() == RECENT_EVENTS_PER_CLIENT {
+            recent.pop_front();
+        }
+        recent.push_back(event);
+        drop(recent);
+
+        self.send(client_id, EventKind::Session, payload);
+    }
+
+    /// Publish a registration or disconnect of `client_id`.
+    pub fn publish_client(&self, client_id: &str, event: Value) {
+        self.send(client_id, EventKind::Client, event.to_string().into());
+    }
+
+    fn send(&self, client_id: &str, kind: EventKind, payload: Arc<str>) {
+        // No subscribers is the common case and not an error
+        let _ = self.tx.send(AdminEvent {
+            client_id: client_id.to_string(),
+            kind,
+            payload,
+        });
+    }
+
+    /// Latest session events reported by `client_id`, newest first.
+    pub fn recent(&self, client_id: &str) -> Vec<Value> {
+        self.recent
+            .get(client_id)
+            .map(|events| events.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AdminEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// Stream every event (or those of `client_filter`, or of `kind_filter`)
+/// to `stream` as SSE until the subscriber goes away.
+pub async fn stream_events<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    hub: &EventHub,
+    client_filter: Option<String>,
+    kind_filter: Option<EventKind>,
+) -> Result<()> {
+    let mut events = hub.subscribe();
+    info!("Admin subscriber attached to event stream");
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+        )
+        .await?;
+    stream.flush().await?;
+
+    let mut keepalive = interval(KEEPALIVE_INTERVAL);
+    loop {
+        let chunk = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if client_filter
+                        .as_ref()
+                        .is_some_and(|client| *client != event.client_id)
+                        || kind_filter.is_some_and(|kind| kind != event.kind)
+                    {
+                        continue;
+                    }
+                    format!("event: {}\ndata: {}\n\n", event.kind.as_str(), event.payload)
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    format!(": {} events dropped\n\n", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+        stream.write_all(chunk.as_bytes()).await?;
+        stream.flush().await?;
+    }
+}
+
+/// POST every client event to `url`, one at a time and in order, for as
+/// long as the server runs. Failed deliveries are logged and not retried.
+pub async fn deliver_webhooks(hub: Arc<EventHub>, url: String) -> Result<()> {
+    let http = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    let mut events = hub.subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Webhook {} missed {} events", url, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if event.kind != EventKind::Client {
+            continue;
+        }
+
+        let delivery = http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(event.payload.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = delivery {
+            warn!(
+                "Webhook {} failed for client {}: {}",
+                url, event.client_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn firehose_streams_events_for_the_requested_client() {
+        let hub = Arc::new(EventHub::default());
+        let (mut subscriber, mut server_side) = tokio::io::duplex(64 * 1024);
+        let streaming_hub = hub.clone();
+        tokio::spawn(async move {
+            let _ = stream_events(
+                &mut server_side,
+                &streaming_hub,
+                Some("a".to_string()),
+                None,
+            )
+            .await;
+        });
+
+        let mut received = String::new();
+        let mut buf = [0u8; 1024];
+        while !received.contains("\r\n\r\n") {
+            let n = subscriber.read(&mut buf).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+
+        hub.publish("b", json!({"session_id": "other"}));
+        hub.publish("a", json!({"session_id": "mine"}));
+        while !received.contains("mine") {
+            let n = subscriber.read(&mut buf).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(received.contains("event: session\ndata: {\"session_id\":\"mine\"}"));
+        assert!(!received.contains("other"));
+
+        for n in 0..RECENT_EVENTS_PER_CLIENT + 5 {
+            hub.publish("b", json!({"n": n}));
+        }
+        let recent = hub.recent("b");
+        assert_eq!(recent.len(), RECENT_EVENTS_PER_CLIENT);
+        assert_eq!(recent[0]["n"], RECENT_EVENTS_PER_CLIENT + 4);
+    }
+
+    #[tokio::test]
+    async fn webhooks_receive_client_events_only() {
+        let hub = Arc::new(EventHub::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(deliver_webhooks(hub.clone(), url));
+        while hub.tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        hub.publish("a", json!({"type": "session_event"}));
+        hub.publish_client("a", json!({"type": "client_registered"}));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = String::new();
+        let mut buf = [0u8; 1024];
+        while !received.ends_with('}') {
+            let n = stream.read(&mut buf).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(received.starts_with("POST /hook HTTP/1.1"));
+        assert!(received.ends_with("{\"type\":\"client_registered\"}"));
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+    }
+}
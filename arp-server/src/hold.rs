@@ -0,0 +1,113 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tokio::time::Duration;
+
+/// Public HTTP requests held while no client can serve them, typically while
+/// their client restarts, instead of failing them right away.
+pub struct HoldQueue {
+    /// How long a request is held before giving up (zero = holding off)
+    pub window: Duration,
+    /// Wait after which a held request starts receiving position updates
+    pub feedback_after: Duration,
+    capacity: usize,
+    /// Held requests in arrival order, by ticket, with their routing target
+    waiting: Mutex<Vec<(u64, Option<String>)>>,
+    next_ticket: AtomicU64,
+    registrations: Notify,
+}
+
+impl HoldQueue {
+    pub fn new(window: Duration, capacity: usize, feedback_after: Duration) -> Self {
+        HoldQueue {
+            window,
+            feedback_after,
+            capacity,
+            waiting: Mutex::new(Vec::new()),
+            next_ticket: AtomicU64::new(0),
+            registrations: Notify::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Queue a request for `target`, or `None` when the queue is full.
+    pub fn enter(&self, target: Option<String>) -> Option<HoldTicket<'_>> {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        if waiting.len() >= self.capacity {
+            return None;
+        }
+        let id = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        waiting.push((id, target));
+        Some(HoldTicket { queue: self, id })
+    }
+
+    /// Wake held requests so they look for their client again.
+    pub fn client_registered(&self) {
+        self.registrations.notify_waiters();
+    }
+
+    /// Resolves at the next registration. Enable it before checking for the
+    /// client so a registration in between isn't missed.
+    pub fn registration(&self) -> tokio::sync::futures::Notified<'_> {
+        self.registrations.notified()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// Place of a held request in the [`HoldQueue`], released on drop.
+pub struct HoldTicket<'a> {
+    queue: &'a HoldQueue,
+    id: u64,
+}
+
+impl HoldTicket<'_> {
+    /// 1-based position among the requests held for the same target.
+    pub fn position(&self) -> usize {
+        let waiting = self.queue.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(index) = waiting.iter().position(|(id, _)| *id == self.id) else {
+            return 0;
+        };
+        let target = &waiting[index].1;
+        waiting[..index]
+            .iter()
+            .filter(|(_, other)| other == target)
+            .count()
+            + 1
+    }
+}
+
+impl Drop for HoldTicket<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .waiting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_count_requests_for_the_same_target() {
+        let queue = HoldQueue::new(Duration::from_secs(30), 3, Duration::from_secs(5));
+        let a1 = queue.enter(Some("a".to_string())).unwrap();
+        let b1 = queue.enter(Some("b".to_string())).unwrap();
+        let a2 = queue.enter(Some("a".to_string())).unwrap();
+        assert!(queue.enter(Some("a".to_string())).is_none());
+        assert_eq!((a1.position(), b1.position(), a2.position()), (1, 1, 2));
+
+        drop(a1);
+        assert_eq!(a2.position(), 1);
+        assert_eq!(queue.len(), 2);
+        assert!(queue.enter(None).is_some());
+    }
+}
@@ -0,0 +1,395 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tracing::warn;
+
+/// Why a connection was shed instead of admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedReason {
+    /// The accept rate limit was exceeded.
+    RateLimited,
+    /// The listener reached its max-open-connection cap.
+    ListenerFull,
+    /// The server-wide max-open-connection cap was reached.
+    GlobalFull,
+}
+
+impl ShedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShedReason::RateLimited => "rate_limited",
+            ShedReason::ListenerFull => "listener_full",
+            ShedReason::GlobalFull => "global_full",
+        }
+    }
+}
+
+/// Simple token bucket refilled continuously at `rate` tokens per second.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next token is available, zero if one is now.
+    fn next_token_in(&self) -> Duration {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = *state;
+        let missing = 1.0 - tokens - last.elapsed().as_secs_f64() * self.rate;
+        Duration::from_secs_f64(missing.max(0.0) / self.rate)
+    }
+}
+
+/// Limits shared by every listener: accept rate and total open connections.
+pub struct GlobalLimits {
+    max_connections: Option<Arc<Semaphore>>,
+    accept_rate: Option<TokenBucket>,
+}
+
+impl GlobalLimits {
+    /// A value of 0 disables the corresponding limit.
+    pub fn new(max_connections: usize, accept_rate: u32, accept_burst: u32) -> Self {
+        GlobalLimits {
+            max_connections: (max_connections > 0)
+                .then(|| Arc::new(Semaphore::new(max_connections))),
+            accept_rate: (accept_rate > 0).then(|| TokenBucket::new(accept_rate, accept_burst)),
+        }
+    }
+}
+
+/// Counters describing admission decisions of one listener.
+#[derive(Default)]
+pub struct ListenerMetrics {
+    pub accepted: AtomicU64,
+    pub shed_rate_limited: AtomicU64,
+    pub shed_listener_full: AtomicU64,
+    pub shed_global_full: AtomicU64,
+}
+
+impl ListenerMetrics {
+    pub fn shed_total(&self) -> u64 {
+        self.shed_rate_limited.load(Ordering::Relaxed)
+            + self.shed_listener_full.load(Ordering::Relaxed)
+            + self.shed_global_full.load(Ordering::Relaxed)
+    }
+}
+
+/// Held for the lifetime of an admitted connection; dropping it frees the slots.
+pub struct ConnectionPermit {
+    _listener: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+    admitted: Instant,
+}
+
+impl ConnectionPermit {
+    /// Time since the connection was admitted.
+    pub fn age(&self) -> Duration {
+        self.admitted.elapsed()
+    }
+}
+
+/// Admission control for a single listener.
+pub struct ListenerGuard {
+    name: &'static str,
+    max_connections: Option<Arc<Semaphore>>,
+    global: Arc<GlobalLimits>,
+    pub metrics: ListenerMetrics,
+}
+
+impl ListenerGuard {
+    /// A `max_connections` of 0 leaves the listener uncapped (global caps still apply).
+    pub fn new(name: &'static str, max_connections: usize, global: Arc<GlobalLimits>) -> Self {
+        ListenerGuard {
+            name,
+            max_connections: (max_connections > 0)
+                .then(|| Arc::new(Semaphore::new(max_connections))),
+            global,
+            metrics: ListenerMetrics::default(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Admit a freshly accepted connection or report why it must be shed.
+    pub fn try_admit(&self) -> Result<ConnectionPermit, ShedReason> {
+        let result = self.admit_inner();
+        match result {
+            Ok(_) => {
+                self.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(reason) => {
+                let counter = match reason {
+                    ShedReason::RateLimited => &self.metrics.shed_rate_limited,
+                    ShedReason::ListenerFull => &self.metrics.shed_listener_full,
+                    ShedReason::GlobalFull => &self.metrics.shed_global_full,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                // Log the first shed and then every 100th to avoid log storms.
+                let total = self.metrics.shed_total();
+                if total % 100 == 1 {
+                    warn!(
+                        "Shedding connection on {} listener ({}), total shed: {}",
+                        self.name,
+                        reason.as_str(),
+                        total
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    fn admit_inner(&self) -> Result<ConnectionPermit, ShedReason> {
+        if let Some(bucket) = &self.global.accept_rate
+            && !bucket.try_take()
+        {
+            return Err(ShedReason::RateLimited);
+        }
+
+        let listener = match &self.max_connections {
+            Some(sem) => Some(
+                sem.clone()
+                    .try_acquire_owned()
+                    .map_err(|_| ShedReason::ListenerFull)?,
+            ),
+            None => None,
+        };
+
+        let global = match &self.global.max_connections {
+            Some(sem) => Some(
+                sem.clone()
+                    .try_acquire_owned()
+                    .map_err(|_| ShedReason::GlobalFull)?,
+            ),
+            None => None,
+        };
+
+        Ok(ConnectionPermit {
+            _listener: listener,
+            _global: global,
+            admitted: Instant::now(),
+        })
+    }
+}
+
+/// What to do with a `RequestNewProxyConn` a waiting public connection needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dial {
+    /// Send the request now.
+    Now,
+    /// The request was queued and will be sent once the rate allows.
+    Deferred,
+    /// The client is over its rate and its queue is full.
+    Refused,
+}
+
+/// Settings from which every client's [`DialPacer`] is built.
+#[derive(Debug, Clone, Copy)]
+pub struct DialLimits {
+    pub rate: u32,
+    pub burst: u32,
+    pub queue_size: usize,
+}
+
+impl DialLimits {
+    pub fn pacer(&self) -> (DialPacer, Option<DeferredDials>) {
+        DialPacer::new(self.rate, self.burst, self.queue_size)
+    }
+}
+
+/// Paces the `RequestNewProxyConn` commands sent to one client, so a flood
+/// of public connections can't ask a slow client to dial faster than it can.
+pub struct DialPacer {
+    bucket: Option<Arc<TokenBucket>>,
+    deferred_tx: Option<mpsc::Sender<String>>,
+    deferred: Arc<AtomicUsize>,
+    pub refused: AtomicU64,
+}
+
+impl DialPacer {
+    /// A `rate` of 0 leaves dials unpaced. Up to `queue_size` dials over the
+    /// rate are handed to the returned [`DeferredDials`] instead of refused.
+    pub fn new(rate: u32, burst: u32, queue_size: usize) -> (Self, Option<DeferredDials>) {
+        let bucket = (rate > 0).then(|| Arc::new(TokenBucket::new(rate, burst)));
+        let deferred = Arc::new(AtomicUsize::new(0));
+        let (deferred_tx, deferred_dials) = match &bucket {
+            Some(bucket) if queue_size > 0 => {
+                let (tx, rx) = mpsc::channel(queue_size);
+                let dials = DeferredDials {
+                    bucket: bucket.clone(),
+                    rx,
+                    deferred: deferred.clone(),
+                };
+                (Some(tx), Some(dials))
+            }
+            _ => (None, None),
+        };
+        let pacer = DialPacer {
+            bucket,
+            deferred_tx,
+            deferred,
+            refused: AtomicU64::new(0),
+        };
+        (pacer, deferred_dials)
+    }
+
+    /// Decide when to request the proxy connection `proxy_conn_id`.
+    pub fn request(&self, proxy_conn_id: &str) -> Dial {
+        let Some(bucket) = &self.bucket else {
+            return Dial::Now;
+        };
+        // Requests already waiting for the rate go first
+        if self.deferred() == 0 && bucket.try_take() {
+            return Dial::Now;
+        }
+        if let Some(tx) = &self.deferred_tx {
+            self.deferred.fetch_add(1, Ordering::Relaxed);
+            if tx.try_send(proxy_conn_id.to_string()).is_ok() {
+                return Dial::Deferred;
+            }
+            self.deferred.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        Dial::Refused
+    }
+
+    /// Decide on a dial that can't wait in the deferred queue: now, or
+    /// refused while the client is over its rate.
+    pub fn request_now(&self) -> Dial {
+        if self.try_refill() {
+            return Dial::Now;
+        }
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        Dial::Refused
+    }
+
+    /// Take a token for a pool refill, which is skipped rather than queued
+    /// while the client is over its rate.
+    pub fn try_refill(&self) -> bool {
+        self.bucket
+            .as_ref()
+            .is_none_or(|bucket| self.deferred() == 0 && bucket.try_take())
+    }
+
+    /// Requests waiting for the rate.
+    pub fn deferred(&self) -> usize {
+        self.deferred.load(Ordering::Relaxed)
+    }
+}
+
+/// Requests a [`DialPacer`] deferred, released at its rate.
+pub struct DeferredDials {
+    bucket: Arc<TokenBucket>,
+    rx: mpsc::Receiver<String>,
+    deferred: Arc<AtomicUsize>,
+}
+
+impl DeferredDials {
+    /// The next deferred proxy connection id, once the rate allows sending
+    /// it; None when the pacer was dropped with its client.
+    pub async fn next(&mut self) -> Option<String> {
+        let proxy_conn_id = self.rx.recv().await?;
+        while !self.bucket.try_take() {
+            tokio::time::sleep(self.bucket.next_token_in()).await;
+        }
+        self.deferred.fetch_sub(1, Ordering::Relaxed);
+        Some(proxy_conn_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_cap_sheds_until_permit_released() {
+        let global = Arc::new(GlobalLimits::new(0, 0, 0));
+        let guard = ListenerGuard::new("public", 1, global);
+
+        let permit = guard.try_admit().expect("first connection admitted");
+        assert_eq!(guard.try_admit().err(), Some(ShedReason::ListenerFull));
+
+        drop(permit);
+        assert!(guard.try_admit().is_ok());
+        assert_eq!(guard.metrics.shed_total(), 1);
+    }
+
+    #[test]
+    fn global_cap_is_shared_between_listeners() {
+        let global = Arc::new(GlobalLimits::new(1, 0, 0));
+        let public = ListenerGuard::new("public", 0, global.clone());
+        let proxy = ListenerGuard::new("proxy", 0, global);
+
+        let _permit = public.try_admit().unwrap();
+        assert_eq!(proxy.try_admit().err(), Some(ShedReason::GlobalFull));
+    }
+
+    #[test]
+    fn accept_rate_limits_bursts() {
+        let global = Arc::new(GlobalLimits::new(0, 1, 2));
+        let guard = ListenerGuard::new("control", 0, global);
+
+        assert!(guard.try_admit().is_ok());
+        assert!(guard.try_admit().is_ok());
+        assert_eq!(guard.try_admit().err(), Some(ShedReason::RateLimited));
+    }
+
+    #[tokio::test]
+    async fn dials_over_the_rate_are_deferred_then_refused() {
+        let (pacer, dials) = DialPacer::new(10, 1, 1);
+        let mut dials = dials.expect("paced with a queue");
+
+        assert_eq!(pacer.request("a"), Dial::Now);
+        assert_eq!(pacer.request("b"), Dial::Deferred);
+        assert_eq!(pacer.request("c"), Dial::Refused);
+        assert!(!pacer.try_refill());
+
+        let started = Instant::now();
+        assert_eq!(dials.next().await.as_deref(), Some("b"));
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert_eq!(pacer.deferred(), 0);
+        assert_eq!(pacer.refused.load(Ordering::Relaxed), 1);
+
+        drop(pacer);
+        assert_eq!(dials.next().await, None);
+    }
+
+    #[test]
+    fn unpaced_clients_dial_at_once() {
+        let (pacer, dials) = DialPacer::new(0, 0, 10);
+        assert!(dials.is_none());
+        for _ in 0..100 {
+            assert_eq!(pacer.request("a"), Dial::Now);
+        }
+        assert!(pacer.try_refill());
+    }
+}
@@ -0,0 +1,318 @@
+use crate::unix_timestamp;
+use anyhow::Result;
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// Connects and disconnects remembered per client.
+const HISTORY_PER_CLIENT: usize = 50;
+
+/// How often the pool maintainer refills the pool of a stable client.
+pub const POOL_REFILL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Longest wait between pool refills of a flapping client.
+const MAX_POOL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Persisted history is written at most this often.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A registration or disconnect of a client.
+#[derive(Debug, Clone, PartialEq)]
+struct LivenessEvent {
+    connected: bool,
+    timestamp: u64,
+    remote_addr: String,
+    generation: u64,
+    /// Why the control connection ended, for disconnects
+    reason: Option<String>,
+}
+
+impl LivenessEvent {
+    fn to_json(&self) -> Value {
+        json!({
+            "event": if self.connected { "connected" } else { "disconnected" },
+            "timestamp": self.timestamp,
+            "remote_addr": self.remote_addr,
+            "generation": self.generation,
+            "reason": self.reason,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(LivenessEvent {
+            connected: value["event"].as_str()? == "connected",
+            timestamp: value["timestamp"].as_u64()?,
+            remote_addr: value["remote_addr"].as_str()?.to_string(),
+            generation: value["generation"].as_u64()?,
+            reason: value["reason"].as_str().map(str::to_string),
+        })
+    }
+}
+
+/// Connect/disconnect history of every client_id seen, kept across
+/// registrations, used to flag clients that keep reconnecting.
+pub struct Liveness {
+    clients: DashMap<String, VecDeque<LivenessEvent>>,
+    /// Disconnects within `flap_window` that make a client flapping
+    flap_threshold: usize,
+    flap_window: Duration,
+    /// File the history is saved to, if persisted
+    persist_path: Option<PathBuf>,
+    changed: Notify,
+}
+
+impl Liveness {
+    pub fn new(flap_threshold: usize, flap_window: Duration) -> Self {
+        Liveness {
+            clients: DashMap::new(),
+            flap_threshold,
+            flap_window,
+            persist_path: None,
+            changed: Notify::new(),
+        }
+    }
+
+    /// Load the history saved at `path` and keep saving it there once
+    /// [`persist`](Self::persist) runs. A missing file starts empty.
+    pub fn with_persistence(mut self, path: PathBuf) -> Result<Self> {
+        match std::fs::read(&path) {
+            Ok(data) => {
+                let saved: Value = serde_json::from_slice(&data)?;
+                for (client_id, events) in saved.as_object().into_iter().flatten() {
+                    let events = events
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(LivenessEvent::from_json)
+                        .collect();
+                    self.clients.insert(client_id.clone(), events);
+                }
+                info!(
+                    "Loaded liveness history of {} clients from {}",
+                    self.clients.len(),
+                    path.display()
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.persist_path = Some(path);
+        Ok(self)
+    }
+
+    pub fn connected(&self, client_id: &str, remote_addr: &str, generation: u64) {
+        self.record(
+            client_id,
+            LivenessEvent {
+                connected: true,
+                timestamp: unix_timestamp(),
+                remote_addr: remote_addr.to_string(),
+                generation,
+                reason: None,
+            },
+        );
+    }
+
+    pub fn disconnected(&self, client_id: &str, remote_addr: &str, generation: u64, reason: &str) {
+        self.record(
+            client_id,
+            LivenessEvent {
+                connected: false,
+                timestamp: unix_timestamp(),
+                remote_addr: remote_addr.to_string(),
+                generation,
+                reason: Some(reason.to_string()),
+            },
+        );
+        if let Some(backoff) = self.pool_backoff(client_id) {
+            warn!(
+                "Client {} is flapping; refilling its pool every {}s",
+                client_id,
+                backoff.as_secs()
+            );
+        }
+    }
+
+    fn record(&self, client_id: &str, event: LivenessEvent) {
+        let mut history = self.clients.entry(client_id.to_string()).or_default();
+        if history.len() == HISTORY_PER_CLIENT {
+            history.pop_front();
+        }
+        history.push_back(event);
+        drop(history);
+        self.changed.notify_one();
+    }
+
+    /// Disconnects of `client_id` within the flap window.
+    fn recent_disconnects(&self, client_id: &str) -> usize {
+        let since = unix_timestamp().saturating_sub(self.flap_window.as_secs());
+        self.clients.get(client_id).map_or(0, |history| {
+            history
+                .iter()
+                .filter(|event| !event.connected && event.timestamp >= since)
+                .count()
+        })
+    }
+
+    pub fn is_flapping(&self, client_id: &str) -> bool {
+        self.flap_threshold > 0 && self.recent_disconnects(client_id) >= self.flap_threshold
+    }
+
+    /// How long the pool maintainer should wait between refills of a
+    /// flapping client: doubling with every disconnect past the threshold.
+    /// `None` for stable clients.
+    pub fn pool_backoff(&self, client_id: &str) -> Option<Duration> {
+        if !self.is_flapping(client_id) {
+            return None;
+        }
+        let excess = (self.recent_disconnects(client_id) - self.flap_threshold) as u32;
+        let backoff = POOL_REFILL_INTERVAL.saturating_mul(2u32.saturating_pow(excess + 1));
+        Some(backoff.min(MAX_POOL_BACKOFF))
+    }
+
+    /// Flapping state and latest activity of `client_id`.
+    pub fn summary(&self, client_id: &str) -> Value {
+        let last = |connected: bool| {
+            self.clients.get(client_id).and_then(|history| {
+                history
+                    .iter()
+                    .rev()
+                    .find(|event| event.connected == connected)
+                    .map(|event| event.timestamp)
+            })
+        };
+        json!({
+            "flapping": self.is_flapping(client_id),
+            "disconnects_in_window": self.recent_disconnects(client_id),
+            "pool_backoff_secs": self.pool_backoff(client_id).map(|backoff| backoff.as_secs()),
+            "last_connected": last(true),
+            "last_disconnected": last(false),
+        })
+    }
+
+    /// Summary of every client seen, sorted by client_id.
+    pub fn summaries(&self) -> Vec<Value> {
+        let mut client_ids: Vec<String> = self
+            .clients
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        client_ids.sort();
+        client_ids
+            .into_iter()
+            .map(|client_id| {
+                let mut summary = self.summary(&client_id);
+                summary["client_id"] = json!(client_id);
+                summary
+            })
+            .collect()
+    }
+
+    /// History of `client_id`, newest first, or `None` if it was never seen.
+    pub fn history(&self, client_id: &str) -> Option<Vec<Value>> {
+        self.clients
+            .get(client_id)
+            .map(|history| history.iter().rev().map(LivenessEvent::to_json).collect())
+    }
+
+    /// Save the history whenever it changes, batching bursts of changes.
+    /// Returns at once when persistence is off.
+    pub async fn persist(self: Arc<Self>) {
+        let Some(path) = self.persist_path.clone() else {
+            return;
+        };
+        loop {
+            self.changed.notified().await;
+            tokio::time::sleep(PERSIST_INTERVAL).await;
+            let saved: serde_json::Map<String, Value> = self
+                .clients
+                .iter()
+                .map(|entry| {
+                    let events = entry.value().iter().map(LivenessEvent::to_json).collect();
+                    (entry.key().clone(), Value::Array(events))
+                })
+                .collect();
+            if let Err(e) = write_atomically(&path, Value::Object(saved).to_string()).await {
+                warn!(
+                    "Failed to save liveness history to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Replace `path` with `contents` without leaving a half-written file.
+pub async fn write_atomically(path: &std::path::Path, contents: String) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_disconnects_flag_flapping_and_back_off() {
+        let liveness = Liveness::new(3, Duration::from_secs(300));
+        for generation in 0..2 {
+            liveness.connected("a", "10.0.0.1:1000", generation);
+            liveness.disconnected("a", "10.0.0.1:1000", generation, "early eof");
+        }
+        assert!(!liveness.is_flapping("a"));
+        assert_eq!(liveness.pool_backoff("a"), None);
+
+        liveness.disconnected("a", "10.0.0.1:1000", 2, "early eof");
+        assert!(liveness.is_flapping("a"));
+        assert_eq!(liveness.pool_backoff("a"), Some(Duration::from_secs(4)));
+        liveness.disconnected("a", "10.0.0.1:1000", 3, "early eof");
+        assert_eq!(liveness.pool_backoff("a"), Some(Duration::from_secs(8)));
+        for generation in 4..20 {
+            liveness.disconnected("a", "10.0.0.1:1000", generation, "early eof");
+        }
+        assert_eq!(liveness.pool_backoff("a"), Some(MAX_POOL_BACKOFF));
+
+        let history = liveness.history("a").unwrap();
+        assert_eq!(history[0]["event"], "disconnected");
+        assert_eq!(history[0]["generation"], 19);
+        assert!(!liveness.is_flapping("b"));
+        assert_eq!(liveness.history("b"), None);
+    }
+
+    #[tokio::test]
+    async fn history_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "arps-liveness-{}-{}.json",
+            std::process::id(),
+            unix_timestamp()
+        ));
+        let liveness = Arc::new(
+            Liveness::new(3, Duration::from_secs(300))
+                .with_persistence(path.clone())
+                .unwrap(),
+        );
+        tokio::spawn(liveness.clone().persist());
+        liveness.connected("a", "10.0.0.1:1000", 7);
+        liveness.disconnected("a", "10.0.0.1:1000", 7, "early eof");
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let restored = Liveness::new(3, Duration::from_secs(300))
+            .with_persistence(path.clone())
+            .unwrap();
+        assert_eq!(restored.history("a"), liveness.history("a"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
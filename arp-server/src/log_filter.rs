@@ -0,0 +1,191 @@
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+use tracing_subscriber::filter::Targets;
+
+/// Replaces the filter of the terminal log output
+pub type SetFilter = Box<dyn Fn(Targets) -> Result<(), String> + Send + Sync>;
+
+/// Directives added by SIGUSR1 on top of the default filter
+const DEBUG_DIRECTIVE: &str = "arps=debug";
+
+struct State {
+    current: String,
+    /// When a temporary filter reverts to the default
+    revert_at: Option<Instant>,
+    /// Bumped on every change so a stale revert does nothing
+    generation: u64,
+}
+
+/// The terminal log filter, changeable at runtime through the admin API or
+/// SIGUSR1 instead of restarting the server to chase an intermittent bug.
+/// Filters use `tracing` target directives, e.g. `info,arps::admin=debug`.
+pub struct LogFilter {
+    default: String,
+    set_filter: SetFilter,
+    state: Mutex<State>,
+}
+
+/// Parse `directives` the way `--log-filter` is parsed.
+pub fn parse(directives: &str) -> Result<Targets, String> {
+    directives
+        .parse::<Targets>()
+        .map_err(|e| format!("Invalid log filter '{}': {}", directives, e))
+}
+
+impl LogFilter {
+    /// `default` must already be in effect.
+    pub fn new(default: String, set_filter: SetFilter) -> Self {
+        LogFilter {
+            state: Mutex::new(State {
+                current: default.clone(),
+                revert_at: None,
+                generation: 0,
+            }),
+            default,
+            set_filter,
+        }
+    }
+
+    /// Apply `directives`, reverting to the default after `ttl` if given.
+    pub fn set(self: &Arc<Self>, directives: &str, ttl: Option<Duration>) -> Result<(), String> {
+        let targets = parse(directives)?;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        (self.set_filter)(targets)?;
+        state.current = directives.to_string();
+        state.generation += 1;
+        state.revert_at = ttl.map(|ttl| Instant::now() + ttl);
+        info!(
+            "Log filter set to '{}'{}",
+            directives,
+            ttl.map(|ttl| format!(" for {}s", ttl.as_secs()))
+                .unwrap_or_default()
+        );
+
+        if let Some(ttl) = ttl {
+            let generation = state.generation;
+            let filter = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(ttl).await;
+                filter.revert(generation);
+            });
+        }
+        Ok(())
+    }
+
+    /// Go back to the default filter.
+    pub fn reset(self: &Arc<Self>) -> Result<(), String> {
+        self.set(&self.default.clone(), None)
+    }
+
+    /// Switch debug logging of the server on or off, for SIGUSR1.
+    pub fn toggle_debug(self: &Arc<Self>) -> Result<(), String> {
+        let debugging =
+            self.state.lock().unwrap_or_else(|e| e.into_inner()).current != self.default;
+        if debugging {
+            self.reset()
+        } else {
+            self.set(&format!("{},{}", self.default, DEBUG_DIRECTIVE), None)
+        }
+    }
+
+    fn revert(self: &Arc<Self>, generation: u64) {
+        let current = self
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .generation;
+        if current == generation
+            && let Err(e) = self.reset()
+        {
+            warn!("Failed to restore the default log filter: {}", e);
+        }
+    }
+
+    /// Current and default filter, for the admin API.
+    pub fn snapshot(&self) -> Value {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        json!({
+            "filter": state.current,
+            "default": self.default,
+            "revert_in_secs": state
+                .revert_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+        })
+    }
+}
+
+/// Toggle debug logging whenever the process receives SIGUSR1.
+#[cfg(unix)]
+pub async fn toggle_on_sigusr1(filter: Arc<LogFilter>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Failed to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        if let Err(e) = filter.toggle_debug() {
+            warn!("Failed to toggle debug logging: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording_filter() -> (Arc<LogFilter>, Arc<Mutex<Vec<String>>>) {
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let log = applied.clone();
+        let filter = LogFilter::new(
+            "info".to_string(),
+            Box::new(move |targets| {
+                log.lock().unwrap().push(targets.to_string());
+                Ok(())
+            }),
+        );
+        (Arc::new(filter), applied)
+    }
+
+    #[tokio::test]
+    async fn temporary_filters_revert_unless_replaced() {
+        let (filter, applied) = recording_filter();
+
+        filter
+            .set("info,arps=debug", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(filter.snapshot()["revert_in_secs"].as_u64().unwrap() >= 59);
+        filter
+            .set("info,arps=debug", Some(Duration::from_millis(50)))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(filter.snapshot()["filter"], "info");
+        assert_eq!(filter.snapshot()["revert_in_secs"], Value::Null);
+
+        filter
+            .set("debug", Some(Duration::from_millis(50)))
+            .unwrap();
+        filter.set("warn", None).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(filter.snapshot()["filter"], "warn");
+        assert_eq!(applied.lock().unwrap().len(), 5);
+
+        assert!(filter.set("arps=loud", None).is_err());
+        assert_eq!(filter.snapshot()["filter"], "warn");
+    }
+
+    #[tokio::test]
+    async fn sigusr1_toggles_debug_on_top_of_the_default() {
+        let (filter, _) = recording_filter();
+        filter.toggle_debug().unwrap();
+        assert_eq!(filter.snapshot()["filter"], "info,arps=debug");
+        filter.toggle_debug().unwrap();
+        assert_eq!(filter.snapshot()["filter"], "info");
+    }
+}
@@ -1,16 +1,68 @@
-use anyhow::{Result, anyhow};
+mod acme;
+mod admin;
+mod claims;
+mod client_logs;
+mod errors;
+mod events;
+mod hold;
+mod limits;
+mod liveness;
+mod log_filter;
+mod pairing;
+mod ports;
+mod priority;
+mod punch;
+mod sni;
+mod tenants;
+mod tls;
+mod tuning;
+mod upgrade;
+mod usage;
+
+use acme::{AcmeConfig, AcmeManager};
+use admin::{ConfigAck, Evictions, PendingConfigAcks};
+use anyhow::{Context, Result, anyhow};
+use claims::HostClaims;
 use clap::Parser;
-use common::http::{HttpRequest, HttpResponse};
-use common::{Command, join_streams, read_command, write_command};
+use client_logs::{ClientLogLayer, ClientLogs};
+use common::auth::{AuthError, CommandAuth, read_command_with, write_command_with};
+use common::crash::CrashReporter;
+use common::http::{BodyTooLargeError, HttpRequest, ParseLimits, SlowClientError};
+use common::{
+    Command, CopyConfig, DirectionConfig, FlushPolicy, join_streams_with, read_command,
+    write_command,
+};
 use crossbeam::queue::SegQueue;
 use dashmap::DashMap;
+use errors::{ErrorFormat, ErrorPages, FailureStage};
+use events::EventHub;
+use hold::HoldQueue;
+use limits::{
+    ConnectionPermit, DeferredDials, Dial, DialLimits, DialPacer, GlobalLimits, ListenerGuard,
+};
+use liveness::Liveness;
+use log_filter::LogFilter;
+use pairing::{PairOutcome, PairingMetrics};
+use ports::{ClientPortPolicy, OpenPorts, PortLease, PortMapping};
+use priority::{PendingQueue, Priority};
+use punch::Rendezvous;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use tokio::io::AsyncReadExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tenants::Tenants;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio::time::{Duration, interval};
-use tracing::{Level, error, info, warn};
+use tokio_rustls::TlsAcceptor;
+use tracing::{Instrument, Level, debug, error, info, info_span, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{Layer, layer::SubscriberExt, reload, util::SubscriberInitExt};
+use tuning::{ServerConfig, Tuning};
+use usage::{Meter, Metered, Quota, QuotaError, Usage};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -24,13 +76,488 @@ struct Args {
     #[arg(long, default_value_t = 17003)]
     public_port: u16,
 
+    /// Serve the admin API (session event firehose at `GET /events`, fleet
+    /// view and config pushes under `/admin`) on this port.
+    #[arg(long)]
+    admin_port: Option<u16>,
+
+    /// Address the admin API binds to.
+    #[arg(long, default_value = "127.0.0.1")]
+    admin_bind: String,
+
+    /// File holding a token the admin API then requires as
+    /// `Authorization: Bearer <token>`. Needed when --admin-bind exposes the
+    /// API beyond the host.
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// POST a JSON event to this URL whenever a client registers,
+    /// re-registers or disconnects (repeatable).
+    #[arg(long = "webhook-url")]
+    webhook_urls: Vec<String>,
+
+    /// Disconnects within --flap-window-secs that mark a client as flapping
+    /// and back off refilling its pool (0 = never).
+    #[arg(long, default_value_t = 5)]
+    flap_threshold: usize,
+
+    /// Window in seconds over which disconnects are counted for flapping.
+    #[arg(long, default_value_t = 300)]
+    flap_window_secs: u64,
+
+    /// Keep the client connect/disconnect history in --data-dir across restarts.
+    #[arg(long)]
+    persist_liveness: bool,
+
+    /// Public and peer connections each client may receive per calendar
+    /// month (UTC) before further ones are refused with 429 (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    client_monthly_connections: u64,
+
+    /// Bytes each client may relay per calendar month (UTC), both
+    /// directions together, before its connections are refused with 429
+    /// (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    client_monthly_bytes: u64,
+
+    /// Keep the monthly usage of clients and tenants in --data-dir across restarts.
+    #[arg(long)]
+    persist_usage: bool,
+
+    /// TOML file with the pending-connection and pool settings below;
+    /// flags given on the command line take precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Idle tunnels kept pre-warmed for each client [default: 5]
+    #[arg(long)]
+    pool_size: Option<usize>,
+
+    /// Idle tunnels kept for one client, e.g. `db-box=20` (repeatable).
+    #[arg(long = "client-pool-size", value_parser = tuning::parse_pool_size)]
+    client_pool_sizes: Vec<(String, usize)>,
+
+    /// Seconds a public connection waits for a tunnel before it is answered
+    /// with 504 [default: 10]
+    #[arg(long)]
+    pending_timeout_secs: Option<u64>,
+
+    /// Seconds between sweeps of public connections past
+    /// --pending-timeout-secs [default: 2]
+    #[arg(long)]
+    pending_cleanup_secs: Option<u64>,
+
+    /// Seconds between refills of the clients' pools [default: 2]
+    #[arg(long)]
+    pool_refill_secs: Option<u64>,
+
+    /// Per-direction buffer size (bytes) used when relaying tunnel traffic.
+    #[arg(long, default_value_t = common::DEFAULT_COPY_BUFFER_SIZE)]
+    copy_buffer_size: usize,
+
+    /// Flush policy for user -> client traffic: immediate | on-idle.
+    #[arg(long, default_value = "immediate")]
+    upstream_flush: FlushPolicy,
+
+    /// Flush policy for client -> user traffic: immediate | on-idle.
+    #[arg(long, default_value = "immediate")]
+    downstream_flush: FlushPolicy,
+
+    /// Max open connections across all listeners (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    max_connections: usize,
+
+    /// Max open connections on the control listener (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    max_control_connections: usize,
+
+    /// Max open connections on the proxy listener, pooled ones included (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    max_proxy_connections: usize,
+
+    /// Max open connections on the public listener (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    max_public_connections: usize,
+
+    /// Accepted connections per second across all listeners (0 = unlimited).
+    #[arg(long, default_value_t = 0)]
+    accept_rate: u32,
+
+    /// Burst size allowed above --accept-rate.
+    #[arg(long, default_value_t = 100)]
+    accept_burst: u32,
+
+    /// Tunnel requests (RequestNewProxyConn) per second sent to each client (0 = unlimited).
+    #[arg(long, default_value_t = 50)]
+    dial_rate: u32,
+
+    /// Milliseconds from accepting a public connection to joining it with a
+    /// tunnel above which the pairing is logged as an SLO breach (0 = never).
+    #[arg(long, default_value_t = 500)]
+    pairing_slo_ms: u64,
+
+    /// Burst size allowed above --dial-rate.
+    #[arg(long, default_value_t = 50)]
+    dial_burst: u32,
+
+    /// Public connections per client waiting for --dial-rate before further
+    /// ones are refused with 503 (0 = refuse at once).
+    #[arg(long, default_value_t = 200)]
+    dial_queue_size: usize,
+
+    /// Seconds a public connection may take to send its request headers.
+    #[arg(long, default_value_t = 10)]
+    header_timeout_secs: u64,
+
+    /// Maximum size in bytes of the request line plus headers on the public port.
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_header_bytes: usize,
+
+    /// Maximum number of request header lines on the public port.
+    #[arg(long, default_value_t = common::http::DEFAULT_MAX_HEADERS)]
+    max_headers: usize,
+
+    /// Minimum request body transfer rate in bytes/sec on the public port (0 = disabled).
+    #[arg(long, default_value_t = 1024)]
+    min_body_rate: u64,
+
+    /// Seconds before --min-body-rate starts being enforced.
+    #[arg(long, default_value_t = 10)]
+    body_grace_secs: u64,
+
+    /// Largest request body in bytes accepted on the public port; larger ones
+    /// are refused with 413. Keep it above the clients' upload limits.
+    #[arg(long, default_value_t = 128 * 1024 * 1024)]
+    max_body_bytes: usize,
+
+    /// Seconds to hold HTTP requests whose client isn't registered, e.g. while
+    /// it restarts, before answering 503 (0 = answer at once).
+    #[arg(long, default_value_t = 0)]
+    hold_secs: u64,
+
+    /// Max HTTP requests held at once; further ones are refused with 503.
+    #[arg(long, default_value_t = 100)]
+    hold_queue_size: usize,
+
+    /// Seconds a held request waits before it gets queue position updates.
     #[arg(long, default_value_t = 5)]
-    pool_size: usize,
+    hold_feedback_secs: u64,
+
+    /// Seconds between heartbeats on an idle control connection (0 = none).
+    #[arg(long, default_value_t = 15)]
+    heartbeat_secs: u64,
+
+    /// Seconds without any command from a client, heartbeats included,
+    /// after which it is considered gone and unregistered.
+    #[arg(long, default_value_t = 45)]
+    heartbeat_timeout_secs: u64,
+
+    /// How public connections are routed: `http` parses each request and
+    /// routes by token, client or Host; `tcp` forwards the bytes untouched
+    /// and routes by TLS server name (SNI) or --default-client.
+    #[arg(long, value_enum, default_value_t = PublicMode::Http)]
+    public_mode: PublicMode,
+
+    /// Client that gets --public-mode tcp connections whose server name no
+    /// client claimed, including all non-TLS ones.
+    #[arg(long)]
+    default_client: Option<String>,
+
+    /// Extra public port, or range of ports, whose raw TCP connections all
+    /// go to one client, e.g. `6000=db` or `7000-7009=ssh` (repeatable).
+    #[arg(long = "port-map")]
+    port_maps: Vec<PortMapping>,
+
+    /// Public ports each client may open for its own raw TCP services with
+    /// `OpenPort`, e.g. for SSH (0 = refuse).
+    #[arg(long, default_value_t = 0)]
+    max_client_ports: usize,
+
+    /// Ports handed out to clients, e.g. `30000-30999`; any free ephemeral
+    /// port when unset.
+    #[arg(long, value_parser = ports::parse_range)]
+    client_port_range: Option<std::ops::RangeInclusive<u16>>,
+
+    /// Format of the error responses the server sends on the public port.
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Json)]
+    error_format: ErrorFormat,
+
+    /// HTML template for --error-format html, with {{status}}, {{title}},
+    /// {{message}}, {{stage}} and {{request_id}} placeholders.
+    #[arg(long)]
+    error_page: Option<PathBuf>,
+
+    /// Serve the public port over TLS, choosing certificates by SNI.
+    #[arg(long)]
+    tls: bool,
+
+    /// PEM certificate chain served when no per-hostname certificate matches,
+    /// and on the control and proxy ports with --client-ca.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates: the control and proxy ports then require TLS
+    /// with a client certificate issued by one of them (mutual TLS).
+    #[arg(long, requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+
+    /// File holding a key shared with the clients: control commands and
+    /// proxy connection announcements must then be signed with it, and
+    /// replayed ones are rejected.
+    #[arg(long)]
+    auth_key_file: Option<PathBuf>,
+
+    /// JSON file of tenants sharing the server, each with its tokens and
+    /// limits. Clients must then register with a tenant token and are
+    /// known as `<tenant>:<client_id>`.
+    #[arg(long)]
+    tenants_file: Option<PathBuf>,
+
+    /// Issue and renew certificates for claimed hostnames via ACME (TLS-ALPN-01 on the public port).
+    #[arg(long, requires = "tls")]
+    acme: bool,
+
+    /// ACME directory URL.
+    #[arg(long, default_value = "https://acme-v02.api.letsencrypt.org/directory")]
+    acme_directory: String,
+
+    /// Contact email registered with the ACME account.
+    #[arg(long)]
+    acme_email: Option<String>,
+
+    /// Renew certificates once they are this many days old.
+    #[arg(long, default_value_t = 60)]
+    acme_renew_days: u64,
+
+    /// Terminal log filter: a level or target directives such as
+    /// `info,arps::admin=debug`. Can be changed at runtime through the admin
+    /// API, and SIGUSR1 toggles debug logging on top of it.
+    #[arg(long, default_value = "info")]
+    log_filter: String,
+
+    /// Also POST crash reports, written to `<data-dir>/crashes`, to this URL.
+    #[arg(long)]
+    crash_report_url: Option<String>,
+
+    /// Directory for persistent server data such as certificates.
+    #[arg(long, default_value = "arps-data")]
+    data_dir: PathBuf,
+}
+
+impl Args {
+    fn parse_limits(&self) -> ParseLimits {
+        ParseLimits {
+            header_timeout: Some(Duration::from_secs(self.header_timeout_secs)),
+            max_header_bytes: self.max_header_bytes,
+            max_headers: self.max_headers,
+            min_body_rate: self.min_body_rate,
+            body_grace: Duration::from_secs(self.body_grace_secs),
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+
+    /// What a crash report says about the configuration.
+    fn crash_summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "control_port": self.control_port,
+            "proxy_port": self.proxy_port,
+            "public_port": self.public_port,
+            "admin_port": self.admin_port,
+            "admin_bind": self.admin_bind,
+            "webhook_urls": self.webhook_urls.len(),
+            "pool_size": self.pool_size,
+            "max_connections": self.max_connections,
+            "max_control_connections": self.max_control_connections,
+            "max_proxy_connections": self.max_proxy_connections,
+            "max_public_connections": self.max_public_connections,
+            "accept_rate": self.accept_rate,
+            "dial_rate": self.dial_rate,
+            "dial_queue_size": self.dial_queue_size,
+            "pairing_slo_ms": self.pairing_slo_ms,
+            "hold_secs": self.hold_secs,
+            "client_monthly_connections": self.client_monthly_connections,
+            "client_monthly_bytes": self.client_monthly_bytes,
+            "heartbeat_secs": self.heartbeat_secs,
+            "heartbeat_timeout_secs": self.heartbeat_timeout_secs,
+            "tls": self.tls,
+            "tls_cert": self.tls_cert,
+            "tls_key": self.tls_key,
+            "client_ca": self.client_ca,
+            "auth_key_file": self.auth_key_file,
+            "tenants_file": self.tenants_file,
+            "acme": self.acme,
+            "acme_directory": self.acme_directory,
+            "log_filter": self.log_filter,
+            "data_dir": self.data_dir,
+        })
+    }
+
+    /// The pending-connection and pool settings: these flags over --config.
+    fn tuning(&self) -> Result<Tuning> {
+        let file = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        Tuning::new(file.overridden_by(ServerConfig {
+            pending_timeout_secs: self.pending_timeout_secs,
+            pending_cleanup_secs: self.pending_cleanup_secs,
+            pool_refill_secs: self.pool_refill_secs,
+            pool_size: self.pool_size,
+            pool_sizes: self.client_pool_sizes.iter().cloned().collect(),
+        }))
+    }
+
+    fn heartbeat(&self) -> Option<HeartbeatPolicy> {
+        (self.heartbeat_secs > 0).then(|| HeartbeatPolicy {
+            interval: Duration::from_secs(self.heartbeat_secs),
+            timeout: Duration::from_secs(self.heartbeat_timeout_secs),
+        })
+    }
+
+    fn dial_limits(&self) -> DialLimits {
+        DialLimits {
+            rate: self.dial_rate,
+            burst: self.dial_burst,
+            queue_size: self.dial_queue_size,
+        }
+    }
+
+    fn copy_config(&self) -> CopyConfig {
+        CopyConfig {
+            upstream: DirectionConfig {
+                buffer_size: self.copy_buffer_size,
+                flush: self.upstream_flush,
+            },
+            downstream: DirectionConfig {
+                buffer_size: self.copy_buffer_size,
+                flush: self.downstream_flush,
+            },
+        }
+    }
 }
 
 struct ClientInfo {
     cmd_tx: mpsc::UnboundedSender<Command>,
-    pool: Arc<SegQueue<TcpStream>>,
+    pool: Arc<SegQueue<PooledConnection>>,
+    /// Bumped on every (re-)registration of the client_id
+    generation: u64,
+    /// Latest load report received on the control channel
+    load: std::sync::Mutex<Option<ClientLoad>>,
+    /// Paces the tunnel requests sent on `cmd_tx`
+    dials: DialPacer,
+    remote_addr: SocketAddr,
+    /// Unix time of the registration
+    registered_at: u64,
+    /// Ends the control connection when an operator evicts the client
+    evicted: tokio::sync::Notify,
+}
+
+// Load reported by a client, used for admission control
+#[derive(Debug, Clone, Copy)]
+struct ClientLoad {
+    active_sessions: u32,
+    load_avg: Option<f64>,
+    busy: bool,
+    received: std::time::Instant,
+}
+
+// Reports older than this are ignored so a silent client isn't refused forever
+const LOAD_REPORT_TTL: Duration = Duration::from_secs(30);
+
+impl ClientInfo {
+    /// The client's latest load report, if it is still fresh and asked for no new work.
+    fn busy_load(&self) -> Option<ClientLoad> {
+        let load = self.load.lock().unwrap_or_else(|e| e.into_inner());
+        (*load).filter(|load| load.busy && load.received.elapsed() < LOAD_REPORT_TTL)
+    }
+
+    fn record_load(
+        &self,
+        client_id: &str,
+        active_sessions: u32,
+        load_avg: Option<f64>,
+        busy: bool,
+    ) {
+        let mut load = self.load.lock().unwrap_or_else(|e| e.into_inner());
+        let was_busy = load.is_some_and(|load| load.busy);
+        if busy != was_busy {
+            info!(
+                "Client {} is now {} (active sessions: {}, load: {:?})",
+                client_id,
+                if busy { "busy" } else { "available" },
+                active_sessions,
+                load_avg
+            );
+        }
+        *load = Some(ClientLoad {
+            active_sessions,
+            load_avg,
+            busy,
+            received: std::time::Instant::now(),
+        });
+    }
+
+    /// Pop a pooled connection from the current generation, discarding stale
+    /// entries left over from a previous registration and ones the client
+    /// closed while they were idle.
+    fn pop_pooled(&self, client_id: &str) -> Option<PooledConnection> {
+        while let Some(mut conn) = self.pool.pop() {
+            if conn.generation != self.generation {
+                warn!(
+                    "Discarding stale pooled connection for {} (generation {} != {})",
+                    client_id, conn.generation, self.generation
+                );
+                continue;
+            }
+            if !idle_connection_open(&mut conn.stream) {
+                debug!("Discarding closed pooled connection for {}", client_id);
+                continue;
+            }
+            return Some(conn);
+        }
+        None
+    }
+}
+
+/// Whether an idle tunnel is still usable. Its client sends nothing until
+/// it gets a request, so anything readable without waiting (EOF, an error
+/// or stray bytes) means the connection was closed or is out of sync.
+/// Writing a request wouldn't tell: the kernel accepts writes to a socket
+/// the peer closed, and the reset only surfaces after the request is lost.
+fn idle_connection_open(stream: &mut TunnelStream) -> bool {
+    let mut byte = [0u8; 1];
+    let mut buf = tokio::io::ReadBuf::new(&mut byte);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    std::pin::Pin::new(stream)
+        .poll_read(&mut cx, &mut buf)
+        .is_pending()
+}
+
+// Idle proxy connection waiting in a client's pool
+struct PooledConnection {
+    stream: TunnelStream,
+    generation: u64,
+    _permit: ConnectionPermit,
+}
+
+/// How often idle control connections are pinged, and how long a client
+/// may stay silent.
+#[derive(Debug, Clone, Copy)]
+struct HeartbeatPolicy {
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// How the public port routes connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PublicMode {
+    Http,
+    /// Raw TCP, for protocols other than HTTP and TLS passthrough
+    Tcp,
 }
 
 // Use DashMap for lock-free concurrent access to active clients
@@ -38,17 +565,96 @@ type ActiveClients = Arc<DashMap<String, Arc<ClientInfo>>>;
 
 // Pending connection with timestamp for timeout tracking
 struct PendingConnection {
-    stream: TcpStream,
+    client_id: String,
+    stream: PublicStream,
+    request_id: String,
     timestamp: std::time::Instant,
     http_request: Option<HttpRequest>,
+    /// WebSocket handshake forwarded in place of `http_request`
+    handshake: Option<Vec<u8>>,
+    permit: ConnectionPermit,
 }
 
-// Use DashMap for lock-free concurrent access to pending connections
-type PendingConnectionsMap = Arc<DashMap<String, PendingConnection>>;
+// A connection that may or may not be TLS-terminated
+trait StreamIo: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> StreamIo for T {}
+
+// User side of a public connection, plain TCP or TLS-terminated
+type PublicStream = Box<dyn StreamIo>;
+
+// Client side of a control or proxy connection, plain TCP or mutual TLS
+type TunnelStream = Box<dyn StreamIo>;
+
+// Public connections waiting for a proxy connection, served by priority
+type PendingConnectionsMap = Arc<PendingQueue<PendingConnection>>;
+
+/// Shared state for the listener tasks
+#[derive(Clone)]
+struct ServerState {
+    active_clients: ActiveClients,
+    pending_connections: PendingConnectionsMap,
+    copy_config: CopyConfig,
+    parse_limits: ParseLimits,
+    control_guard: Arc<ListenerGuard>,
+    proxy_guard: Arc<ListenerGuard>,
+    public_guard: Arc<ListenerGuard>,
+    host_claims: Arc<HostClaims>,
+    tls: Option<TlsAcceptor>,
+    /// Mutual TLS on the control and proxy ports
+    tunnel_tls: Option<TlsAcceptor>,
+    /// Commands from clients must be signed with this key
+    auth: Option<Arc<CommandAuth>>,
+    /// Clients register into the namespace of their tenant token
+    tenants: Option<Arc<Tenants>>,
+    /// Monthly usage of clients and tenants, checked against their quotas
+    usage: Arc<Usage>,
+    /// Token the admin API requires, from --admin-token-file
+    admin_token: Option<Arc<str>>,
+    /// Clients evicted through the admin API and refused for a while
+    evictions: Arc<Evictions>,
+    /// Pool sizes clients are kept at, reported by the admin API
+    tuning: Arc<Tuning>,
+    heartbeat: Option<HeartbeatPolicy>,
+    acme: Option<Arc<AcmeManager>>,
+    events: Arc<EventHub>,
+    config_acks: PendingConfigAcks,
+    liveness: Arc<Liveness>,
+    hold: Arc<HoldQueue>,
+    error_pages: Arc<ErrorPages>,
+    dial_limits: DialLimits,
+    pairing: Arc<PairingMetrics>,
+    client_logs: Arc<ClientLogs>,
+    log_filter: Arc<LogFilter>,
+    public_mode: PublicMode,
+    default_client: Option<String>,
+    port_maps: Arc<Vec<PortMapping>>,
+    client_ports: ClientPortPolicy,
+    open_ports: Arc<OpenPorts>,
+    rendezvous: Arc<Rendezvous>,
+}
 
 // Global counter for fast ID generation
 static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+// Maximum pooled connections tried for one request before using the slow path
+const MAX_POOL_ATTEMPTS: usize = 3;
+
+// Global counter for client registration generations
+static GENERATION_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+/// Seconds since the Unix epoch, for event timestamps.
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Correlation ID of a public request, shown in error responses and logs.
+fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
 fn generate_id() -> String {
     let id = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
     format!("{:x}", id)
@@ -57,37 +663,293 @@ fn generate_id() -> String {
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    if args.default_client.is_some() && args.public_mode != PublicMode::Tcp {
+        return Err(anyhow!("--default-client requires --public-mode tcp"));
+    }
+    ports::check_overlaps(&args.port_maps).map_err(|e| anyhow!(e))?;
+    let tuning = Arc::new(args.tuning()?);
+    if args.tls_cert.is_some() && !args.tls && args.client_ca.is_none() {
+        return Err(anyhow!("--tls-cert requires --tls or --client-ca"));
+    }
+    // Terminal at --log-filter, which can be changed later; lines attributed
+    // to a client, debug ones included, are also kept for the admin API's
+    // per-client log stream
+    let (terminal_filter, terminal_handle) =
+        reload::Layer::new(log_filter::parse(&args.log_filter).map_err(|e| anyhow!(e))?);
+    let client_logs = Arc::new(ClientLogs::default());
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(terminal_filter))
+        .with(
+            ClientLogLayer::new(client_logs.clone())
+                .with_filter(Targets::new().with_target("arps", Level::DEBUG)),
+        )
+        .init();
+    let log_filter = Arc::new(LogFilter::new(
+        args.log_filter.clone(),
+        Box::new(move |targets| {
+            terminal_handle
+                .modify(|filter| *filter = targets)
+                .map_err(|e| e.to_string())
+        }),
+    ));
+    #[cfg(unix)]
+    tokio::spawn(log_filter::toggle_on_sigusr1(log_filter.clone()));
 
     let active_clients: ActiveClients = Arc::new(DashMap::new());
-    let pending_connections: PendingConnectionsMap = Arc::new(DashMap::new());
+    let pending_connections: PendingConnectionsMap = Arc::new(PendingQueue::new());
 
     let control_listener = TcpListener::bind(format!("0.0.0.0:{}", args.control_port)).await?;
     let proxy_listener = TcpListener::bind(format!("0.0.0.0:{}", args.proxy_port)).await?;
     let public_listener = TcpListener::bind(format!("0.0.0.0:{}", args.public_port)).await?;
+    let mut mapped_listeners = Vec::new();
+    for mapping in &args.port_maps {
+        for port in mapping.ports.clone() {
+            let listener = TcpListener::bind(("0.0.0.0", port))
+                .await
+                .with_context(|| format!("Failed to bind mapped port {}", port))?;
+            mapped_listeners.push((listener, mapping.client_id.clone()));
+        }
+        info!(
+            "Mapped public port(s) {} to client {}",
+            mapping.ports_str(),
+            mapping.client_id
+        );
+    }
 
     info!(
         "arps listening on ports: Control={}, Proxy={}, Public={}, Pool Size={}",
-        args.control_port, args.proxy_port, args.public_port, args.pool_size
+        args.control_port, args.proxy_port, args.public_port, tuning.pool_size
     );
 
+    let mut liveness = Liveness::new(
+        args.flap_threshold,
+        Duration::from_secs(args.flap_window_secs),
+    );
+    if args.persist_liveness {
+        liveness = liveness.with_persistence(args.data_dir.join("liveness.json"))?;
+    }
+    let liveness = Arc::new(liveness);
+    tokio::spawn(liveness.clone().persist());
+
+    let mut usage = Usage::new(Quota {
+        connections: args.client_monthly_connections,
+        bytes: args.client_monthly_bytes,
+    });
+    if args.persist_usage {
+        usage = usage.with_persistence(args.data_dir.join("usage.json"))?;
+    }
+    let usage = Arc::new(usage);
+    tokio::spawn(usage.clone().persist());
+
     // Spawn background task to maintain connection pools
     let pool_maintainer_clients = active_clients.clone();
-    let target_pool_size = args.pool_size;
+    let pool_maintainer_liveness = liveness.clone();
+    let pool_maintainer_tuning = tuning.clone();
     tokio::spawn(async move {
-        maintain_connection_pools(pool_maintainer_clients, target_pool_size, true).await;
+        maintain_connection_pools(
+            pool_maintainer_clients,
+            pool_maintainer_liveness,
+            pool_maintainer_tuning,
+            true,
+        )
+        .await;
     });
 
+    let mut error_pages = ErrorPages::new(args.error_format);
+    if let Some(path) = &args.error_page {
+        error_pages = error_pages.with_template(path)?;
+    }
+    let error_pages = Arc::new(error_pages);
+
     // Spawn background task to cleanup expired pending connections
+    let events = Arc::new(EventHub::default());
+    let pairing = Arc::new(PairingMetrics::new(
+        (args.pairing_slo_ms > 0).then(|| Duration::from_millis(args.pairing_slo_ms)),
+    ));
     let cleanup_pending = pending_connections.clone();
+    let cleanup_error_pages = error_pages.clone();
+    let cleanup_pairing = pairing.clone();
+    let cleanup_events = events.clone();
+    let cleanup_tuning = tuning.clone();
     tokio::spawn(async move {
-        cleanup_expired_connections(cleanup_pending).await;
+        cleanup_expired_connections(
+            cleanup_pending,
+            cleanup_error_pages,
+            cleanup_pairing,
+            cleanup_events,
+            cleanup_tuning,
+        )
+        .await;
     });
 
+    for url in &args.webhook_urls {
+        info!("Sending client events to webhook {}", url);
+        tokio::spawn(events::deliver_webhooks(events.clone(), url.clone()));
+    }
+    let admin_listener = match args.admin_port {
+        Some(admin_port) => {
+            let listener = TcpListener::bind((args.admin_bind.as_str(), admin_port)).await?;
+            info!(
+                "Admin API listening on {}:{} (GET /events, /admin/agents, /admin/clients, POST /admin/config)",
+                args.admin_bind, admin_port
+            );
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let host_claims = Arc::new(HostClaims::default());
+    let (tls, acme) = if args.tls {
+        let resolver = Arc::new(tls::CertResolver::default());
+        tls::CertStore::new(&args.data_dir).load_all(&resolver);
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            resolver.set_fallback(tls::certified_key(
+                &std::fs::read(cert)?,
+                &std::fs::read(key)?,
+            )?);
+        }
+
+        let acme = args.acme.then(|| {
+            let manager = Arc::new(AcmeManager::new(
+                AcmeConfig {
+                    directory: args.acme_directory.clone(),
+                    email: args.acme_email.clone(),
+                    renew_after: Duration::from_secs(args.acme_renew_days * 24 * 60 * 60),
+                    data_dir: args.data_dir.clone(),
+                },
+                resolver.clone(),
+                host_claims.clone(),
+            ));
+            tokio::spawn(manager.clone().run());
+            manager
+        });
+
+        info!("TLS enabled on public port (ACME: {})", args.acme);
+        (Some(tls::acceptor(resolver)?), acme)
+    } else {
+        (None, None)
+    };
+    let tunnel_tls = match (&args.client_ca, &args.tls_cert, &args.tls_key) {
+        (Some(ca), Some(cert), Some(key)) => {
+            let key = tls::certified_key(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+            info!("Mutual TLS required on control and proxy ports");
+            Some(tls::tunnel_acceptor(key, &std::fs::read(ca)?)?)
+        }
+        _ => None,
+    };
+    let auth = match &args.auth_key_file {
+        Some(path) => {
+            let key = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(anyhow!("{} holds no key", path.display()));
+            }
+            info!("Control commands must be signed");
+            Some(Arc::new(CommandAuth::new(key)))
+        }
+        None => None,
+    };
+    let admin_token = match &args.admin_token_file {
+        Some(path) => {
+            let token = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let token = token.trim();
+            if token.is_empty() {
+                return Err(anyhow!("{} holds no token", path.display()));
+            }
+            info!("Admin API requires a bearer token");
+            Some(Arc::from(token))
+        }
+        None => None,
+    };
+    let tenants = match &args.tenants_file {
+        Some(path) => {
+            info!("Clients must register with a tenant token");
+            Some(Arc::new(Tenants::load(path)?))
+        }
+        None => None,
+    };
+
+    let global_limits = Arc::new(GlobalLimits::new(
+        args.max_connections,
+        args.accept_rate,
+        args.accept_burst,
+    ));
+    let state = ServerState {
+        active_clients: active_clients.clone(),
+        pending_connections: pending_connections.clone(),
+        copy_config: args.copy_config(),
+        parse_limits: args.parse_limits(),
+        control_guard: Arc::new(ListenerGuard::new(
+            "control",
+            args.max_control_connections,
+            global_limits.clone(),
+        )),
+        proxy_guard: Arc::new(ListenerGuard::new(
+            "proxy",
+            args.max_proxy_connections,
+            global_limits.clone(),
+        )),
+        public_guard: Arc::new(ListenerGuard::new(
+            "public",
+            args.max_public_connections,
+            global_limits,
+        )),
+        host_claims,
+        tls,
+        tunnel_tls,
+        auth,
+        tenants,
+        usage,
+        admin_token,
+        evictions: Arc::new(Evictions::default()),
+        tuning: tuning.clone(),
+        heartbeat: args.heartbeat(),
+        acme,
+        events,
+        config_acks: Arc::new(DashMap::new()),
+        liveness,
+        hold: Arc::new(HoldQueue::new(
+            Duration::from_secs(args.hold_secs),
+            args.hold_queue_size,
+            Duration::from_secs(args.hold_feedback_secs),
+        )),
+        error_pages,
+        dial_limits: args.dial_limits(),
+        pairing,
+        client_logs,
+        log_filter,
+        public_mode: args.public_mode,
+        default_client: args.default_client.clone(),
+        port_maps: Arc::new(args.port_maps.clone()),
+        client_ports: ClientPortPolicy {
+            max_per_client: args.max_client_ports,
+            range: args.client_port_range.clone(),
+        },
+        open_ports: Arc::new(OpenPorts::default()),
+        rendezvous: Arc::new(Rendezvous::default()),
+    };
+
+    install_crash_reporter(&args, state.clone());
+
+    for (listener, client_id) in mapped_listeners {
+        tokio::spawn(handle_mapped_port(listener, client_id, None, state.clone()));
+    }
+
+    if let Some(admin_listener) = admin_listener {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve_admin(admin_listener, state).await {
+                error!("Admin API error: {}", e);
+            }
+        });
+    }
+
     let server_logic = tokio::select! {
-        res = handle_control_connections(control_listener, active_clients.clone()) => res,
-        res = handle_proxy_connections(proxy_listener, pending_connections.clone(), active_clients.clone()) => res,
-        res = handle_public_connections(public_listener, active_clients.clone(), pending_connections.clone()) => res,
+        res = handle_control_connections(control_listener, state.clone()) => res,
+        res = handle_proxy_connections(proxy_listener, state.clone()) => res,
+        res = handle_public_connections(public_listener, state.clone()) => res,
     };
 
     if let Err(e) = server_logic {
@@ -97,52 +959,96 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Write a crash report with the server's state to `<data-dir>/crashes` on
+/// every panic, and POST it to --crash-report-url if set.
+fn install_crash_reporter(args: &Args, state: ServerState) {
+    let started = std::time::Instant::now();
+    let mut reporter = CrashReporter::new(
+        "arps",
+        env!("CARGO_PKG_VERSION"),
+        args.data_dir.join("crashes"),
+    )
+    .with_config(args.crash_summary())
+    .with_state(move || {
+        let listener = |guard: &ListenerGuard| {
+            serde_json::json!({
+                "accepted": guard.metrics.accepted.load(Ordering::Relaxed),
+                "shed": guard.metrics.shed_total(),
+            })
+        };
+        serde_json::json!({
+            "uptime_secs": started.elapsed().as_secs(),
+            "active_clients": state.active_clients.len(),
+            "pooled_connections": state
+                .active_clients
+                .iter()
+                .map(|entry| entry.pool.len())
+                .sum::<usize>(),
+            "pending_connections": state.pending_connections.len(),
+            "listeners": {
+                "control": listener(&state.control_guard),
+                "proxy": listener(&state.proxy_guard),
+                "public": listener(&state.public_guard),
+            },
+        })
+    });
+    if let Some(url) = args.crash_report_url.clone() {
+        reporter = reporter.with_upload(move |body| {
+            reqwest::blocking::Client::new()
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .timeout(Duration::from_secs(10))
+                .body(body)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+    }
+    reporter.install();
+}
+
+/// Kernel send and receive buffer size requested for tunnel sockets
+const SOCKET_BUFFER_SIZE: usize = 512 * 1024;
+
 /// Optimizes TCP socket settings for low latency and high throughput
 fn tune_tcp_socket(stream: &TcpStream) -> Result<()> {
-    use std::os::fd::AsRawFd;
-
     stream.set_nodelay(true)?;
 
-    let fd = stream.as_raw_fd();
-    unsafe {
-        let buf_size: libc::c_int = 524288; // 512KB
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_RCVBUF,
-            &buf_size as *const _ as *const libc::c_void,
-            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-        );
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_SNDBUF,
-            &buf_size as *const _ as *const libc::c_void,
-            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-        );
+    let socket = socket2::SockRef::from(stream);
+    // Best effort: the kernel may clamp or refuse the sizes
+    let _ = socket.set_recv_buffer_size(SOCKET_BUFFER_SIZE);
+    let _ = socket.set_send_buffer_size(SOCKET_BUFFER_SIZE);
 
-        // Enable TCP_QUICKACK on Linux for lower latency
-        #[cfg(target_os = "linux")]
-        {
-            let quickack: libc::c_int = 1;
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_QUICKACK,
-                &quickack as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
+    // Enable TCP_QUICKACK on Linux for lower latency
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let _ = socket.set_quickack(true);
+    Ok(())
+}
+
+/// Accept the next connection, backing off instead of failing when the
+/// process runs out of file descriptors or hits another transient error.
+async fn accept_with_backoff(
+    listener: &TcpListener,
+    guard: &ListenerGuard,
+) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn!("Accept error on {} listener: {}", guard.name(), e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
         }
     }
-    Ok(())
 }
 
-async fn handle_control_connections(
-    listener: TcpListener,
-    active_clients: ActiveClients,
-) -> Result<()> {
+async fn handle_control_connections(listener: TcpListener, state: ServerState) -> Result<()> {
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (stream, addr) = accept_with_backoff(&listener, &state.control_guard).await;
+        let Ok(permit) = state.control_guard.try_admit() else {
+            continue;
+        };
         info!("New control connection from: {}", addr);
 
         // Tune TCP socket for control connection
@@ -150,158 +1056,1165 @@ async fn handle_control_connections(
             warn!("Failed to tune control socket for {}: {}", addr, e);
         }
 
-        let active_clients_clone = active_clients.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_single_client(stream, active_clients_clone).await {
-                error!("Error handling client {}: {}", addr, e);
+        let state = state.clone();
+        // The client ID is recorded on registration
+        let span = info_span!("control", client_id = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                let Some(stream) = accept_tunnel(stream, addr, &state).await else {
+                    return;
+                };
+                if let Err(e) = handle_single_client(stream, addr, state).await {
+                    error!("Error handling client {}: {}", addr, e);
+                }
             }
-        });
+            .instrument(span),
+        );
     }
 }
 
-async fn handle_single_client(stream: TcpStream, active_clients: ActiveClients) -> Result<()> {
-    let (mut reader, mut writer) = stream.into_split();
+/// Answer a registration with a failed `RegisterResult`.
+async fn refuse_registration<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    error: &str,
+    error_code: &str,
+    auth: Option<&CommandAuth>,
+) -> Result<()> {
+    let result = Command::RegisterResult {
+        success: false,
+        error: Some(error.to_string()),
+        error_code: Some(error_code.to_string()),
+        generation: None,
+        client_id: None,
+    };
+    write_command_with(writer, &result, auth).await
+}
 
-    let client_id = if let Command::Register { client_id: id } = read_command(&mut reader).await? {
+/// Complete the mutual TLS handshake of a control or proxy connection when
+/// --client-ca is set; None when the client has no valid certificate.
+async fn accept_tunnel(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: &ServerState,
+) -> Option<TunnelStream> {
+    let Some(acceptor) = &state.tunnel_tls else {
+        return Some(Box::new(stream));
+    };
+    match tokio::time::timeout(TUNNEL_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(Box::new(stream)),
+        Ok(Err(e)) => {
+            warn!("Rejected tunnel connection from {}: {}", addr, e);
+            None
+        }
+        Err(_) => {
+            debug!("TLS handshake with {} timed out", addr);
+            None
+        }
+    }
+}
+
+async fn handle_single_client(
+    stream: TunnelStream,
+    addr: SocketAddr,
+    state: ServerState,
+) -> Result<()> {
+    let ServerState {
+        active_clients,
+        pending_connections,
+        host_claims,
+        acme,
+        events,
+        config_acks,
+        liveness,
+        hold,
+        error_pages,
+        dial_limits,
+        ..
+    } = state.clone();
+    let auth = state.auth.clone();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let (client_id, generation, control_tx, info) = if let Command::Register {
+        client_id: id,
+        hostnames,
+        token,
+    } =
+        read_command_with(&mut reader, auth.as_deref()).await?
+    {
+        // On a shared server the client lives in its tenant's namespace,
+        // under the tenant's limits. The tenant stays locked until the
+        // client is registered, so its clients are counted and added at once
+        let mut admission = None;
+        let (id, dial_limits) = match &state.tenants {
+            Some(tenants) => {
+                let admitted = match tenants.resolve(token.as_deref(), &id) {
+                    Ok((tenant, id)) => {
+                        let guard = tenant.lock_admission().await;
+                        let registered = active_clients
+                            .iter()
+                            .filter(|client| tenant.owns(client.key()) && *client.key() != id)
+                            .count();
+                        tenant.admit(registered).map(|()| {
+                            admission = Some(guard);
+                            (id, tenant.dial_limits(dial_limits))
+                        })
+                    }
+                    Err(e) => Err(e),
+                };
+                match admitted {
+                    Ok(admitted) => admitted,
+                    Err(e) => {
+                        warn!("Rejecting registration of {}: {}", id, e);
+                        refuse_registration(&mut writer, &e.to_string(), e.code(), auth.as_deref())
+                            .await?;
+                        return Err(e.into());
+                    }
+                }
+            }
+            None => (id, dial_limits),
+        };
+        tracing::Span::current().record("client_id", id.as_str());
         info!("Registration attempt for client_id: {}", id);
+        if let Some(remaining) = state.evictions.remaining(&id) {
+            let error = format!(
+                "Client {} was evicted; retry in {}s",
+                id,
+                remaining.as_secs().max(1)
+            );
+            warn!("Rejecting registration of {}: {}", id, error);
+            refuse_registration(&mut writer, &error, "client_evicted", auth.as_deref()).await?;
+            return Err(anyhow!(error));
+        }
+        let generation = GENERATION_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        // Reserve hostnames before touching any existing registration so a
+        // conflicting claim leaves the current owner untouched
+        if let Err(e) = host_claims.claim(&id, generation, &hostnames) {
+            warn!("Rejecting registration of {}: {}", id, e);
+            refuse_registration(&mut writer, &e.to_string(), e.code(), auth.as_deref()).await?;
+            return Err(e.into());
+        }
+        if !hostnames.is_empty() {
+            info!("Client {} claimed hostnames: {:?}", id, hostnames);
+            if let Some(acme) = &acme {
+                acme.wake();
+            }
+        }
 
         // Remove old registration if exists (allow reconnection)
-        if let Some((_, old_info)) = active_clients.remove(&id) {
+        let previous_generation = active_clients.remove(&id).map(|(_, old_info)| {
             warn!(
                 "Client ID {} was already registered, replacing with new connection.",
                 id
             );
-            // Clear old pool connections
+            // Clear old pool connections and claims not renewed above
             while old_info.pool.pop().is_some() {}
-        }
+            host_claims.release(&id, old_info.generation);
+            old_info.generation
+        });
 
         // Create channel for sending commands
         let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel();
+        let (dials, deferred_dials) = dial_limits.pacer();
+        if let Some(deferred_dials) = deferred_dials {
+            tokio::spawn(send_deferred_dials(
+                id.clone(),
+                cmd_tx.downgrade(),
+                deferred_dials,
+                pending_connections.clone(),
+            ));
+        }
 
-        active_clients.insert(
-            id.clone(),
-            Arc::new(ClientInfo {
-                cmd_tx,
-                pool: Arc::new(SegQueue::new()),
-            }),
-        );
+        let control_tx = cmd_tx.clone();
+        let info = Arc::new(ClientInfo {
+            cmd_tx,
+            pool: Arc::new(SegQueue::new()),
+            generation,
+            load: std::sync::Mutex::new(None),
+            dials,
+            remote_addr: addr,
+            registered_at: unix_timestamp(),
+            evicted: tokio::sync::Notify::new(),
+        });
+        active_clients.insert(id.clone(), info.clone());
+        drop(admission);
 
         // Send registration success
-        write_command(
+        write_command_with(
             &mut writer,
             &Command::RegisterResult {
                 success: true,
                 error: None,
+                error_code: None,
+                generation: Some(generation),
+                client_id: Some(id.clone()),
             },
+            auth.as_deref(),
         )
         .await?;
         info!("Client {} registered successfully.", id);
+        hold.client_registered();
+        liveness.connected(&id, &addr.to_string(), generation);
+        events.publish_client(
+            &id,
+            serde_json::json!({
+                "type": if previous_generation.is_some() { "client_reregistered" } else { "client_registered" },
+                "client_id": id,
+                "remote_addr": addr.to_string(),
+                "generation": generation,
+                "previous_generation": previous_generation,
+                "hostnames": hostnames,
+                "timestamp": unix_timestamp(),
+            }),
+        );
 
         // Spawn task to handle command sending
         let client_id_clone = id.clone();
-        tokio::spawn(async move {
-            while let Some(cmd) = cmd_rx.recv().await {
-                if write_command(&mut writer, &cmd).await.is_err() {
-                    error!("Failed to send command to client {}", client_id_clone);
-                    break;
+        let signer = auth.clone();
+        tokio::spawn(
+            async move {
+                while let Some(cmd) = cmd_rx.recv().await {
+                    if write_command_with(&mut writer, &cmd, signer.as_deref())
+                        .await
+                        .is_err()
+                    {
+                        error!("Failed to send command to client {}", client_id_clone);
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        (id, generation, control_tx, info)
+    } else {
+        return Err(anyhow!("First command was not Register"));
+    };
+
+    // Ports the client opened, closed when this connection ends
+    let mut port_leases: Vec<PortLease> = Vec::new();
+
+    // Keep reading from the control channel for acknowledgements; a read
+    // error, or a client that stopped answering heartbeats, means the
+    // client disconnected. Clients that never answered one predate them
+    // and are left to TCP.
+    let mut commands = common::spawn_command_reader(reader, auth.clone());
+    let heartbeat_interval = state
+        .heartbeat
+        .map_or(Duration::from_secs(3600), |policy| policy.interval);
+    let mut heartbeat = tokio::time::interval_at(
+        tokio::time::Instant::now() + heartbeat_interval,
+        heartbeat_interval,
+    );
+    let mut last_heard = std::time::Instant::now();
+    let mut answers_heartbeats = false;
+    let mut ping_seq = 0;
+    loop {
+        let result = tokio::select! {
+            result = commands.recv() => {
+                result.unwrap_or_else(|| Err(anyhow!("Control connection closed")))
+            }
+            _ = info.evicted.notified() => Err(anyhow!("Evicted by an operator")),
+            _ = heartbeat.tick(), if state.heartbeat.is_some() => {
+                let timeout = state.heartbeat.map_or(Duration::MAX, |policy| policy.timeout);
+                if answers_heartbeats && last_heard.elapsed() > timeout {
+                    Err(anyhow!("No heartbeat for {}s", timeout.as_secs()))
+                } else {
+                    ping_seq += 1;
+                    let _ = control_tx.send(Command::Ping { seq: ping_seq });
+                    continue;
+                }
+            }
+        };
+        if result.is_ok() {
+            last_heard = std::time::Instant::now();
+        }
+        match result {
+            Ok(Command::Ping { seq }) => {
+                answers_heartbeats = true;
+                let _ = control_tx.send(Command::Pong { seq });
+            }
+            Ok(Command::Pong { .. }) => answers_heartbeats = true,
+            Ok(Command::ProxyConnAck {
+                proxy_conn_id,
+                accepted: true,
+                ..
+            }) => {
+                debug!("Client {} accepted proxy conn {}", client_id, proxy_conn_id);
+            }
+            Ok(Command::ProxyConnAck {
+                proxy_conn_id,
+                accepted: false,
+                reason,
+                target,
+            }) => {
+                let reason = reason.unwrap_or_else(|| "rejected by client".to_string());
+                warn!(
+                    "Client {} rejected proxy conn {}: {}",
+                    client_id, proxy_conn_id, reason
+                );
+                // A refused `Punch` makes its peer relay right away
+                state.rendezvous.cancel(&proxy_conn_id);
+                fail_pending_connection(
+                    &pending_connections,
+                    &error_pages,
+                    &pending_key(&client_id, target.as_deref()),
+                    &proxy_conn_id,
+                    reason,
+                );
+            }
+            Ok(Command::OpenPort { target, port }) => {
+                let result = match open_port(&state, &client_id, &target, port).await {
+                    Ok(lease) => {
+                        let port = lease.port();
+                        info!(
+                            "Client {} opened public port {} for {}",
+                            client_id, port, target
+                        );
+                        port_leases.push(lease);
+                        Command::OpenPortResult {
+                            target,
+                            port: Some(port),
+                            error: None,
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Refused to open a port for {} of client {}: {}",
+                            target, client_id, e
+                        );
+                        Command::OpenPortResult {
+                            target,
+                            port: None,
+                            error: Some(e),
+                        }
+                    }
+                };
+                let _ = control_tx.send(result);
+            }
+            Ok(Command::LoadReport {
+                active_sessions,
+                load_avg,
+                busy,
+            }) => {
+                if let Some(info) = active_clients.get(&client_id)
+                    && info.generation == generation
+                {
+                    info.record_load(&client_id, active_sessions, load_avg, busy);
+                }
+            }
+            Ok(Command::SessionEvent {
+                session_id,
+                status,
+                executor,
+                agent_session_id,
+                project_path,
+                exit_code,
+                error,
+                timestamp,
+            }) => {
+                debug!("Client {} session {} {}", client_id, session_id, status);
+                events.publish(
+                    &client_id,
+                    serde_json::json!({
+                        "type": "session_event",
+                        "client_id": client_id,
+                        "session_id": session_id,
+                        "status": status,
+                        "executor": executor,
+                        "agent_session_id": agent_session_id,
+                        "project_path": project_path,
+                        "exit_code": exit_code,
+                        "error": error,
+                        "timestamp": timestamp,
+                    }),
+                );
+            }
+            Ok(Command::ConfigUpdateAck {
+                update_id,
+                applied,
+                error,
+            }) => {
+                if let Some((_, ack)) = config_acks.remove(&update_id) {
+                    let _ = ack.send(ConfigAck { applied, error });
+                }
+            }
+            Ok(cmd) => {
+                warn!("Unexpected command from client {}: {:?}", client_id, cmd);
+            }
+            Err(e) if e.is::<AuthError>() => {
+                warn!("Dropped command from client {}: {}", client_id, e);
+            }
+            Err(e) => {
+                warn!("Client {} disconnected: {}", client_id, e);
+                host_claims.release(&client_id, generation);
+                // Only remove our own registration; a newer one may have replaced it
+                let removed =
+                    active_clients.remove_if(&client_id, |_, info| info.generation == generation);
+                if let Some((_, old_info)) = &removed {
+                    // Clear pool connections when client disconnects
+                    while old_info.pool.pop().is_some() {}
+                }
+                liveness.disconnected(&client_id, &addr.to_string(), generation, &e.to_string());
+                events.publish_client(
+                    &client_id,
+                    serde_json::json!({
+                        "type": "client_disconnected",
+                        "client_id": client_id,
+                        "remote_addr": addr.to_string(),
+                        "generation": generation,
+                        "replaced": removed.is_none(),
+                        "reason": e.to_string(),
+                        "timestamp": unix_timestamp(),
+                    }),
+                );
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send the tunnel requests a client's pacer deferred as its rate allows,
+/// skipping those whose public connection was served or gave up meanwhile.
+/// Ends when the client's registration is dropped.
+async fn send_deferred_dials(
+    client_id: String,
+    cmd_tx: mpsc::WeakUnboundedSender<Command>,
+    mut deferred_dials: DeferredDials,
+    pending_connections: PendingConnectionsMap,
+) {
+    while let Some(proxy_conn_id) = deferred_dials.next().await {
+        if !pending_connections.contains(&client_id, &proxy_conn_id) {
+            continue;
+        }
+        let Some(cmd_tx) = cmd_tx.upgrade() else {
+            break;
+        };
+        if cmd_tx
+            .send(Command::RequestNewProxyConn {
+                proxy_conn_id,
+                target: None,
+            })
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Record the time-to-pair of a public connection admitted with `permit`,
+/// publishing an alarm event to admin subscribers when it breaches the SLO.
+fn record_pairing(
+    pairing: &PairingMetrics,
+    events: &EventHub,
+    client_id: &str,
+    outcome: PairOutcome,
+    permit: &ConnectionPermit,
+) {
+    let elapsed = permit.age();
+    if let Some(breaches) = pairing.record(client_id, outcome, elapsed) {
+        events.publish_client(
+            client_id,
+            serde_json::json!({
+                "type": "pairing_slo_breached",
+                "client_id": client_id,
+                "outcome": outcome.as_str(),
+                "elapsed_ms": elapsed.as_millis() as u64,
+                "slo_ms": pairing.slo().map(|slo| slo.as_millis() as u64),
+                "breaches": breaches,
+                "timestamp": unix_timestamp(),
+            }),
+        );
+    }
+}
+
+/// Queue a pending connection of `client_id` waits in. Connections to a port
+/// the client opened wait apart from the rest, as only tunnels dialed for
+/// that port's `target` may serve them.
+fn pending_key(client_id: &str, target: Option<&str>) -> String {
+    match target {
+        Some(target) => format!("{}\n{}", client_id, target),
+        None => client_id.to_string(),
+    }
+}
+
+/// Open a public port whose connections are tunneled to `target` of
+/// `client_id`, for as long as the returned lease is kept.
+async fn open_port(
+    state: &ServerState,
+    client_id: &str,
+    target: &str,
+    port: Option<u16>,
+) -> Result<PortLease, String> {
+    let listener = state
+        .open_ports
+        .bind(&state.client_ports, client_id, port)
+        .await?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let task = tokio::spawn(
+        handle_mapped_port(
+            listener,
+            client_id.to_string(),
+            Some(target.to_string()),
+            state.clone(),
+        )
+        .in_current_span(),
+    );
+    Ok(PortLease::new(
+        state.open_ports.clone(),
+        port,
+        client_id,
+        target,
+        task,
+    ))
+}
+
+/// Fail a pending user connection the client refused to serve, answering
+/// with 502 when it was an HTTP request.
+fn fail_pending_connection(
+    pending_connections: &PendingConnectionsMap,
+    error_pages: &ErrorPages,
+    queue: &str,
+    proxy_conn_id: &str,
+    reason: String,
+) {
+    // Pool refill requests have no pending user connection
+    let Some(pending) = pending_connections.remove(queue, proxy_conn_id) else {
+        return;
+    };
+    answer_pending(
+        pending,
+        error_pages,
+        FailureStage::PoolExhausted,
+        format!("Client unavailable: {}", reason),
+    );
+}
+
+/// Answer a pending user connection that won't be served with an error
+/// response, in the background, when it was an HTTP request.
+fn answer_pending(
+    pending: PendingConnection,
+    error_pages: &ErrorPages,
+    stage: FailureStage,
+    message: String,
+) {
+    let Some(request) = &pending.http_request else {
+        return;
+    };
+    let response = error_pages
+        .response(
+            stage,
+            message,
+            &pending.request_id,
+            request.header("accept").map(String::as_str),
+        )
+        .header("Connection", "close");
+
+    let mut stream = pending.stream;
+    tokio::spawn(async move {
+        let _ = tokio::time::timeout(Duration::from_secs(1), response.send(&mut stream)).await;
+    });
+}
+
+async fn handle_proxy_connections(listener: TcpListener, state: ServerState) -> Result<()> {
+    loop {
+        let (proxy_stream, addr) = accept_with_backoff(&listener, &state.proxy_guard).await;
+        let Ok(permit) = state.proxy_guard.try_admit() else {
+            continue;
+        };
+
+        // Tune TCP socket for proxy connection (high throughput)
+        let _ = tune_tcp_socket(&proxy_stream);
+
+        let pending_clone = state.pending_connections.clone();
+        let clients_clone = state.active_clients.clone();
+        let copy_config = state.copy_config;
+        let pairing = state.pairing.clone();
+        let events = state.events.clone();
+        let state = state.clone();
+
+        let span = info_span!("proxy", client_id = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                let Some(mut proxy_stream) = accept_tunnel(proxy_stream, addr, &state).await else {
+                    return;
+                };
+                let command = read_command(&mut proxy_stream).await.and_then(|command| {
+                    // Only clients announce proxy connections, so those must
+                    // be signed; `arpc connect` and hole punching need no key
+                    match (&state.auth, command) {
+                        (Some(auth), command @ Command::Signed { .. })
+                        | (Some(auth), command @ Command::NewProxyConn { .. }) => {
+                            Ok(auth.verify(command)?)
+                        }
+                        (_, command) => Ok(command),
+                    }
+                });
+                if let Err(e) = &command
+                    && e.is::<AuthError>()
+                {
+                    warn!("Rejected proxy connection from {}: {}", addr, e);
+                    return;
+                }
+                // `arpc connect` asks for a tunnel to another client's service
+                // and is then relayed like a public connection to it
+                if let Ok(Command::ConnectService {
+                    client_id,
+                    service,
+                    token,
+                }) = command
+                {
+                    let Some(client_id) = tunnel_client_id(&state, token, client_id, addr) else {
+                        return;
+                    };
+                    debug!(
+                        "Peer {} connecting to {} {}",
+                        addr,
+                        client_id,
+                        service.as_deref().unwrap_or("(usual service)")
+                    );
+                    let _ = route_tcp_to_client(
+                        proxy_stream,
+                        &client_id,
+                        service.as_deref(),
+                        addr,
+                        permit,
+                        &state,
+                    )
+                    .await;
+                    return;
+                }
+                if let Ok(Command::PunchRequest {
+                    client_id,
+                    service,
+                    punch_id,
+                    token,
+                }) = command
+                {
+                    let Some(client_id) = tunnel_client_id(&state, token, client_id, addr) else {
+                        return;
+                    };
+                    punch_rendezvous(proxy_stream, &client_id, service, punch_id, addr, &state)
+                        .await;
+                    return;
+                }
+                if let Ok(Command::PunchReady { punch_id }) = command {
+                    let peer_addr = state.rendezvous.meet(&punch_id, addr);
+                    let reply = Command::PunchPeer {
+                        punch_id,
+                        peer_addr: peer_addr.map(|peer| peer.to_string()),
+                    };
+                    let _ = write_command(&mut proxy_stream, &reply).await;
+                    return;
+                }
+                if let Ok(Command::NewProxyConn {
+                    proxy_conn_id,
+                    client_id,
+                    pooled,
+                    generation,
+                    target,
+                    token,
+                }) = command
+                {
+                    let Some(client_id) = tunnel_client_id(&state, token, client_id, addr) else {
+                        return;
+                    };
+                    tracing::Span::current().record("client_id", client_id.as_str());
+                    // Any proxy connection of the client, pre-warmed ones included,
+                    // serves the most urgent waiting user connection first. Pre-warmed
+                    // connections of an older registration never serve users
+                    let stale = pooled
+                        && clients_clone.get(&client_id).is_none_or(|info| {
+                            generation.is_some_and(|generation| generation != info.generation)
+                        });
+                    let requested = (!pooled).then_some(proxy_conn_id.as_str());
+                    let pending = if stale {
+                        None
+                    } else {
+                        pending_clone.pair(&pending_key(&client_id, target.as_deref()), requested)
+                    };
+                    if let Some(pending_conn) = pending {
+                        let user_stream = pending_conn.stream;
+                        let http_request = pending_conn.http_request;
+                        let handshake = pending_conn.handshake;
+                        let user_permit = pending_conn.permit;
+                        tokio::spawn(
+                            async move {
+                                let permits = (permit, user_permit);
+                                // If there's a parsed HTTP request, forward it first
+                                if let Err(e) = forward_request(
+                                    &mut proxy_stream,
+                                    http_request.as_ref(),
+                                    handshake.as_deref(),
+                                )
+                                .await
+                                {
+                                    error!("Failed to write HTTP request to proxy stream: {}", e);
+                                    return;
+                                }
+                                record_pairing(
+                                    &pairing,
+                                    &events,
+                                    &client_id,
+                                    PairOutcome::PoolMiss,
+                                    &permits.1,
+                                );
+
+                                // Now join the streams
+                                let _ = join_streams_with(user_stream, proxy_stream, &copy_config)
+                                    .await;
+                            }
+                            .in_current_span(),
+                        );
+                    } else if target.is_some() {
+                        debug!(
+                            "Dropping tunnel {} whose public connection is gone",
+                            proxy_conn_id
+                        );
+                    } else {
+                        // No pending request (or pre-warmed by the client) - this is for the pool
+                        if let Some(client_info) = clients_clone.get(&client_id) {
+                            // Clients that don't report a generation belong to the current one
+                            let generation = generation.unwrap_or(client_info.generation);
+                            if generation != client_info.generation {
+                                warn!(
+                                    "Dropping proxy connection for {} from stale generation {}",
+                                    client_id, generation
+                                );
+                                return;
+                            }
+                            client_info.pool.push(PooledConnection {
+                                stream: proxy_stream,
+                                generation,
+                                _permit: permit,
+                            });
+                        }
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// `client_id` as named on the proxy port by a client or peer holding
+/// `token`: in the token's tenant on a shared server. None, once logged,
+/// when the tenant refuses it.
+fn tunnel_client_id(
+    state: &ServerState,
+    token: Option<String>,
+    client_id: String,
+    addr: SocketAddr,
+) -> Option<String> {
+    let Some(tenants) = &state.tenants else {
+        return Some(client_id);
+    };
+    match tenants.resolve(token.as_deref(), &client_id) {
+        Ok((_, client_id)) => Some(client_id),
+        Err(e) => {
+            warn!("Rejected proxy connection from {}: {}", addr, e);
+            None
+        }
+    }
+}
+
+/// How long a client gets to complete the TLS handshake on the control and
+/// proxy ports
+const TUNNEL_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a `PunchRequest` waits for the client to get ready; the peer
+/// relays after that.
+const PUNCH_RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Ask `client_id` to punch a hole for `service` towards the peer at `addr`,
+/// and tell the peer the client's address once it's ready. The peer is told
+/// to relay instead when the client refuses or doesn't answer in time.
+async fn punch_rendezvous(
+    mut stream: TunnelStream,
+    client_id: &str,
+    service: String,
+    punch_id: String,
+    addr: SocketAddr,
+    state: &ServerState,
+) {
+    let peer = state.rendezvous.expect(&punch_id, addr);
+    let asked = state.active_clients.get(client_id).is_some_and(|info| {
+        let punch = Command::Punch {
+            punch_id: punch_id.clone(),
+            service,
+        };
+        info.cmd_tx.send(punch).is_ok()
+    });
+    let peer_addr = if asked {
+        tokio::time::timeout(PUNCH_RENDEZVOUS_TIMEOUT, peer)
+            .await
+            .ok()
+            .and_then(Result::ok)
+    } else {
+        None
+    };
+    state.rendezvous.cancel(&punch_id);
+    debug!(
+        "Punch {} from {} to {}: {}",
+        punch_id,
+        addr,
+        client_id,
+        peer_addr.map_or("relaying".to_string(), |peer| peer.to_string())
+    );
+    let reply = Command::PunchPeer {
+        punch_id,
+        peer_addr: peer_addr.map(|peer| peer.to_string()),
+    };
+    let _ = write_command(&mut stream, &reply).await;
+}
+
+async fn handle_public_connections(listener: TcpListener, state: ServerState) -> Result<()> {
+    loop {
+        let (mut user_stream, addr) = accept_with_backoff(&listener, &state.public_guard).await;
+        let permit = match state.public_guard.try_admit() {
+            Ok(permit) => permit,
+            // A plaintext 503 means nothing to a TLS client; just close
+            Err(_) if state.tls.is_some() => continue,
+            Err(reason) => {
+                // Shed gracefully: best-effort 503 without blocking the accept loop
+                let response = state
+                    .error_pages
+                    .response(
+                        FailureStage::Overloaded,
+                        format!("Server overloaded ({})", reason.as_str()),
+                        &new_request_id(),
+                        None,
+                    )
+                    .header("Retry-After", "1")
+                    .header("Connection", "close");
+                tokio::spawn(async move {
+                    let _ = tokio::time::timeout(
+                        Duration::from_secs(1),
+                        response.send(&mut user_stream),
+                    )
+                    .await;
+                });
+                continue;
+            }
+        };
+
+        // Tune TCP socket for public connection (low latency critical)
+        let _ = tune_tcp_socket(&user_stream);
+
+        let state = state.clone();
+        // The client ID is recorded once the request is routed
+        let span = info_span!("public", client_id = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                if state.public_mode == PublicMode::Tcp {
+                    let _ = accept_tcp_connection(user_stream, addr, permit, state).await;
+                    return;
                 }
+                let user_stream: PublicStream = match &state.tls {
+                    Some(acceptor) => match accept_tls(acceptor, user_stream, &state).await {
+                        Some(stream) => Box::new(stream),
+                        None => return,
+                    },
+                    None => Box::new(user_stream),
+                };
+                let _ = route_public_connection(user_stream, Some(addr), permit, state).await;
             }
-        });
+            .instrument(span),
+        );
+    }
+}
 
-        id
-    } else {
-        return Err(anyhow!("First command was not Register"));
+/// Complete the TLS handshake of a public connection within the header
+/// timeout. ACME validation handshakes are finished here and yield `None`.
+async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    state: &ServerState,
+) -> Option<tokio_rustls::server::TlsStream<TcpStream>> {
+    let handshake = acceptor.accept(stream);
+    let result = match state.parse_limits.header_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, handshake).await {
+            Ok(result) => result,
+            Err(_) => {
+                debug!("TLS handshake timed out");
+                return None;
+            }
+        },
+        None => handshake.await,
     };
 
-    // Keep reading from the control channel, but we don't expect more commands.
-    // The main purpose is to detect when the client disconnects.
-    loop {
-        if reader.read_u8().await.is_err() {
-            warn!("Client {} disconnected.", client_id);
-            if let Some((_, old_info)) = active_clients.remove(&client_id) {
-                // Clear pool connections when client disconnects
-                while old_info.pool.pop().is_some() {}
-            }
-            break;
+    let stream = match result {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("TLS handshake failed: {}", e);
+            return None;
         }
+    };
+
+    if stream.get_ref().1.alpn_protocol() == Some(tls::ACME_TLS_ALPN) {
+        debug!("Answered ACME TLS-ALPN-01 validation handshake");
+        return None;
     }
+    Some(stream)
+}
 
-    Ok(())
+/// Route a `--public-mode tcp` connection by its TLS server name: the one
+/// from the handshake when arps terminates TLS, else the one peeked from the
+/// ClientHello so the client receives it untouched.
+async fn accept_tcp_connection(
+    user_stream: TcpStream,
+    addr: SocketAddr,
+    permit: ConnectionPermit,
+    state: ServerState,
+) -> Result<()> {
+    let (user_stream, server_name): (PublicStream, Option<String>) = match &state.tls {
+        Some(acceptor) => {
+            let Some(stream) = accept_tls(acceptor, user_stream, &state).await else {
+                return Ok(());
+            };
+            let server_name = stream.get_ref().1.server_name().map(str::to_string);
+            (Box::new(stream), server_name)
+        }
+        // Without claimed hostnames there is nothing to route by, so don't
+        // hold up protocols where the server speaks first
+        None if state.host_claims.is_empty() => (Box::new(user_stream), None),
+        None => {
+            let server_name = sni::peek_server_name(&user_stream, SNI_PEEK_TIMEOUT).await;
+            (Box::new(user_stream), server_name)
+        }
+    };
+    route_tcp_connection(user_stream, server_name, addr, permit, state).await
 }
 
-async fn handle_proxy_connections(
-    listener: TcpListener,
-    pending_connections: PendingConnectionsMap,
-    active_clients: ActiveClients,
+/// How long a `--public-mode tcp` connection may take to send a ClientHello
+/// before it is routed as if it had no server name
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Route a raw TCP connection to the client claiming `server_name`, or to
+/// --default-client. Failures can't be answered in an unknown protocol, so
+/// the connection is just closed.
+async fn route_tcp_connection(
+    user_stream: PublicStream,
+    server_name: Option<String>,
+    remote_addr: SocketAddr,
+    permit: ConnectionPermit,
+    state: ServerState,
 ) -> Result<()> {
-    loop {
-        let (mut proxy_stream, _addr) = listener.accept().await?;
+    let target = server_name
+        .as_deref()
+        .and_then(|name| state.host_claims.resolve(name))
+        .or_else(|| state.default_client.clone());
+    let Some(token) = target else {
+        warn!(
+            "No client for TCP connection from {} (server name: {:?})",
+            remote_addr, server_name
+        );
+        return Err(anyhow!("No client for TCP connection"));
+    };
+    route_tcp_to_client(user_stream, &token, None, remote_addr, permit, &state).await
+}
 
-        // Tune TCP socket for proxy connection (high throughput)
-        let _ = tune_tcp_socket(&proxy_stream);
+/// Join a raw TCP connection with a tunnel to `token`, or to its `target`
+/// address, closing it when the client is offline or busy.
+async fn route_tcp_to_client(
+    user_stream: PublicStream,
+    token: &str,
+    target: Option<&str>,
+    remote_addr: SocketAddr,
+    permit: ConnectionPermit,
+    state: &ServerState,
+) -> Result<()> {
+    // Copied out of the map, so its shard stays unlocked for the connection
+    let Some(client_info) = state
+        .active_clients
+        .get(token)
+        .map(|entry| entry.value().clone())
+    else {
+        warn!(
+            "Client '{}' not found for TCP connection from {}",
+            token, remote_addr
+        );
+        return Err(anyhow!("Client '{}' not found", token));
+    };
+    tracing::Span::current().record("client_id", token);
+    if client_info.busy_load().is_some() {
+        warn!("Client '{}' is busy, refusing TCP connection", token);
+        return Err(anyhow!("Client '{}' is busy", token));
+    }
+    let user_stream: PublicStream = match admit_usage(state, token) {
+        Ok(meter) => Box::new(Metered::new(user_stream, meter)),
+        Err(e) => {
+            warn!("Refusing TCP connection to '{}': {}", token, e);
+            return Err(e.into());
+        }
+    };
 
-        let pending_clone = pending_connections.clone();
-        let clients_clone = active_clients.clone();
+    let destination = Destination {
+        client_id: token,
+        info: client_info,
+        target,
+    };
+    dispatch_to_client(
+        user_stream,
+        None,
+        None,
+        new_request_id(),
+        destination,
+        permit,
+        state,
+    )
+    .await
+}
 
-        tokio::spawn(async move {
-            if let Ok(Command::NewProxyConn {
-                proxy_conn_id,
-                client_id,
-            }) = read_command(&mut proxy_stream).await
-            {
-                if let Some((_, pending_conn)) = pending_clone.remove(&proxy_conn_id) {
-                    let user_stream = pending_conn.stream;
-                    let http_request = pending_conn.http_request;
-                    tokio::spawn(async move {
-                        // If there's a parsed HTTP request, reconstruct it first
-                        if let Some(request) = http_request {
-                            if let Err(e) = write_http_request(&mut proxy_stream, &request).await {
-                                error!("Failed to write HTTP request to proxy stream: {}", e);
-                                return;
-                            }
-                        }
+/// Accept connections on a `--port-map` port, or one the client opened for
+/// `target`, and forward them all to `client_id`. They count against the
+/// public port's limits.
+async fn handle_mapped_port(
+    listener: TcpListener,
+    client_id: String,
+    target: Option<String>,
+    state: ServerState,
+) {
+    loop {
+        let (user_stream, addr) = accept_with_backoff(&listener, &state.public_guard).await;
+        let Ok(permit) = state.public_guard.try_admit() else {
+            continue;
+        };
+        let _ = tune_tcp_socket(&user_stream);
 
-                        // Now join the streams
-                        let _ = join_streams(user_stream, proxy_stream).await;
-                    });
-                } else {
-                    // No pending request - this is for the pool
-                    if let Some(client_info) = clients_clone.get(&client_id) {
-                        client_info.pool.push(proxy_stream);
-                    }
-                }
+        let state = state.clone();
+        let client_id = client_id.clone();
+        let target = target.clone();
+        let span = info_span!("public", client_id = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                let _ = route_tcp_to_client(
+                    Box::new(user_stream),
+                    &client_id,
+                    target.as_deref(),
+                    addr,
+                    permit,
+                    &state,
+                )
+                .await;
             }
-        });
+            .instrument(span),
+        );
     }
 }
 
-async fn handle_public_connections(
-    listener: TcpListener,
-    active_clients: ActiveClients,
-    pending_connections: PendingConnectionsMap,
-) -> Result<()> {
-    loop {
-        let (user_stream, _addr) = listener.accept().await?;
+/// Client a public request should be routed to. An explicit `X-ARP-Client`
+/// header wins over the `client` query parameter, which wins over `token`.
+fn routing_target(request: &HttpRequest) -> Option<String> {
+    [
+        request.header("x-arp-client"),
+        request.query_param("client"),
+        request.query_param("token"),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|value| value.split_whitespace().next())
+    .next()
+    .map(str::to_string)
+}
 
-        // Tune TCP socket for public connection (low latency critical)
-        let _ = tune_tcp_socket(&user_stream);
+/// Resolve the target client from the X-ARP-Client header, `client` or
+/// `token`, falling back to a hostname claimed at registration.
+fn resolve_target(request: &HttpRequest, state: &ServerState) -> Option<String> {
+    routing_target(request).or_else(|| {
+        request
+            .header("host")
+            .and_then(|host| state.host_claims.resolve(host))
+    })
+}
 
-        let active_clients_clone = active_clients.clone();
-        let pending_connections_clone = pending_connections.clone();
+/// Wait up to `--hold-secs` for a client able to serve `request` to register.
+/// Past `--hold-feedback-secs`, callers sending `X-ARP-Queue-Events` get
+/// `102 Processing` interim responses carrying their queue position. A
+/// request that runs out of time or finds the queue full is answered with 503.
+async fn hold_until_client<S: AsyncWrite + Unpin>(
+    user_stream: &mut S,
+    request: &HttpRequest,
+    request_id: &str,
+    state: &ServerState,
+) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
 
-        tokio::spawn(async move {
-            let _ = route_public_connection(
-                user_stream,
-                active_clients_clone,
-                pending_connections_clone,
+    let hold = &state.hold;
+    let accept = request.header("accept").map(String::as_str);
+    let ready =
+        || resolve_target(request, state).is_some_and(|t| state.active_clients.contains_key(&t));
+    let Some(ticket) = hold.enter(routing_target(request)) else {
+        warn!("Hold queue is full, refusing request for an unregistered client");
+        let _ = state
+            .error_pages
+            .response(
+                FailureStage::QueueFull,
+                "No client available and the request queue is full",
+                request_id,
+                accept,
             )
+            .header("Retry-After", hold.window.as_secs().to_string())
+            .send(user_stream)
             .await;
-        });
+        return Err(anyhow!("Hold queue full"));
+    };
+    debug!("Holding request for {} ({} held)", request.path, hold.len());
+
+    let wants_events = request.header("x-arp-queue-events").is_some();
+    let deadline = tokio::time::Instant::now() + hold.window;
+    let mut next_feedback = tokio::time::Instant::now() + hold.feedback_after;
+    loop {
+        let registration = hold.registration();
+        tokio::pin!(registration);
+        registration.as_mut().enable();
+        if ready() {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = registration => {}
+            _ = tokio::time::sleep_until(next_feedback), if wants_events && next_feedback < deadline => {
+                next_feedback += hold.feedback_after.max(Duration::from_secs(1));
+                let interim = format!(
+                    "HTTP/1.1 102 Processing\r\nX-ARP-Queue-Position: {}\r\n\r\n",
+                    ticket.position()
+                );
+                user_stream.write_all(interim.as_bytes()).await?;
+                user_stream.flush().await?;
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                let position = ticket.position();
+                warn!(
+                    "No client registered for {} within {}s",
+                    request.path,
+                    hold.window.as_secs()
+                );
+                let _ = state
+                    .error_pages
+                    .response(
+                        FailureStage::NoClient,
+                        "No client became available in time",
+                        request_id,
+                        accept,
+                    )
+                    .header("Retry-After", hold.window.as_secs().to_string())
+                    .header("X-ARP-Queue-Position", position.to_string())
+                    .send(user_stream)
+                    .await;
+                return Err(anyhow!("No client registered in time"));
+            }
+        }
     }
 }
 
 /// Reconstruct HTTP request and write it to a stream
-async fn write_http_request(stream: &mut TcpStream, request: &HttpRequest) -> Result<()> {
+async fn write_http_request<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &HttpRequest,
+) -> Result<()> {
     use tokio::io::AsyncWriteExt;
 
     // Reconstruct request line with query parameters
@@ -311,7 +2224,7 @@ async fn write_http_request(stream: &mut TcpStream, request: &HttpRequest) -> Re
         let params: Vec<String> = request
             .query_params
             .iter()
-            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
             .collect();
         format!("?{}", params.join("&"))
     };
@@ -334,8 +2247,10 @@ async fn write_http_request(stream: &mut TcpStream, request: &HttpRequest) -> Re
     // End of headers
     stream.write_all(b"\r\n").await?;
 
-    // Write body
-    if !request.body.is_empty() {
+    // Write body, re-framed with its trailers when it arrived chunked
+    if request.is_chunked() {
+        stream.write_all(&request.chunked_body()).await?;
+    } else if !request.body.is_empty() {
         stream.write_all(&request.body).await?;
     }
 
@@ -343,118 +2258,392 @@ async fn write_http_request(stream: &mut TcpStream, request: &HttpRequest) -> Re
     Ok(())
 }
 
+/// Send a routed public request down a tunnel: a WebSocket handshake as
+/// received, any other request rebuilt from its parsed form.
+async fn forward_request<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: Option<&HttpRequest>,
+    handshake: Option<&[u8]>,
+) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    match (handshake, request) {
+        (Some(handshake), _) => {
+            stream.write_all(handshake).await?;
+            stream.flush().await?;
+            Ok(())
+        }
+        (None, Some(request)) => write_http_request(stream, request).await,
+        (None, None) => Ok(()),
+    }
+}
+
+/// Route a public connection from `remote_addr` (None when arps makes the
+/// request itself) to its client.
 async fn route_public_connection(
-    mut user_stream: TcpStream,
-    active_clients: ActiveClients,
-    pending_connections: PendingConnectionsMap,
+    mut user_stream: PublicStream,
+    remote_addr: Option<SocketAddr>,
+    permit: ConnectionPermit,
+    state: ServerState,
 ) -> Result<()> {
+    let active_clients = &state.active_clients;
+
     // Try to parse as HTTP request to extract token
-    let proxy_conn_id_for_parsing = generate_id();
-    let http_request = match HttpRequest::parse(&mut user_stream, &proxy_conn_id_for_parsing).await
-    {
+    let request_id = new_request_id();
+    let mut recording =
+        upgrade::Recording::new(&mut user_stream, state.parse_limits.max_header_bytes);
+    let parsed =
+        HttpRequest::parse_with_limits(&mut recording, &request_id, &state.parse_limits).await;
+    let raw = recording.into_bytes();
+    let mut http_request = match parsed {
         Ok(req) => Some(req),
+        Err(e)
+            if e.downcast_ref::<SlowClientError>().is_some()
+                || e.downcast_ref::<BodyTooLargeError>().is_some() =>
+        {
+            let stage = if e.downcast_ref::<SlowClientError>().is_some() {
+                FailureStage::SlowRequest
+            } else {
+                FailureStage::BodyTooLarge
+            };
+            warn!("Dropping public connection ({}): {}", stage.as_str(), e);
+            let _ = tokio::time::timeout(
+                Duration::from_secs(1),
+                state
+                    .error_pages
+                    .response(stage, e.to_string(), &request_id, None)
+                    .header("Connection", "close")
+                    .send(&mut user_stream),
+            )
+            .await;
+            return Err(e);
+        }
         Err(e) => {
             warn!("Failed to parse HTTP request: {}, treating as raw TCP", e);
             None
         }
     };
 
+    // Hold the request while its client (re)connects instead of failing at once
+    if let Some(request) = &http_request
+        && state.hold.enabled()
+        && (active_clients.is_empty()
+            || routing_target(request).is_some_and(|t| !active_clients.contains_key(&t)))
+    {
+        hold_until_client(&mut user_stream, request, &request_id, &state).await?;
+    }
+
+    // Answer an HTTP request the server can't route with an error document
+    let accept = http_request
+        .as_ref()
+        .and_then(|req| req.header("accept"))
+        .map(String::as_str);
+    let error_response = |stage: FailureStage, message: String| {
+        state
+            .error_pages
+            .response(stage, message, &request_id, accept)
+    };
+
     // Phase 1: Determine which client to route to based on token (if present)
     if active_clients.is_empty() {
         warn!("No active clients available to handle new public connection.");
 
         // If we parsed HTTP, send 503 Service Unavailable
         if http_request.is_some() {
-            let _ = HttpResponse::new(503)
-                .text("No active clients available")
-                .send(&mut user_stream)
-                .await;
+            let _ = error_response(
+                FailureStage::NoClient,
+                "No active clients available".to_string(),
+            )
+            .send(&mut user_stream)
+            .await;
         }
 
         return Err(anyhow!("No active clients"));
     }
 
-    // Check if token parameter exists in HTTP request
-    let token_raw = match http_request
+    let token = match http_request
         .as_ref()
-        .and_then(|req| req.query_param("token"))
+        .and_then(|req| resolve_target(req, &state))
     {
         Some(t) => t,
         None => {
             if http_request.is_some() {
-                let _ = HttpResponse::not_found()
-                    .text("Client Token not found")
-                    .send(&mut user_stream)
-                    .await;
+                let _ = error_response(
+                    FailureStage::UnknownClient,
+                    "Client Token not found".to_string(),
+                )
+                .send(&mut user_stream)
+                .await;
             }
             return Err(anyhow!("Client Token not found"));
         }
     };
+    let token = token.as_str();
 
-    let token = token_raw.split_whitespace().next().unwrap_or("");
+    // Token-based routing. The entry is copied out so no shard lock of the
+    // client map is held while the connection is served
 
-    if token.is_empty() {
-        if http_request.is_some() {
-            let _ = HttpResponse::not_found()
-                .text("Client Token not found")
+    let client_info = match active_clients.get(token).map(|entry| entry.value().clone()) {
+        Some(info) => {
+            tracing::Span::current().record("client_id", token);
+            info
+        }
+        None => {
+            warn!("Client '{}' not found for token", token);
+            if http_request.is_some() {
+                let _ = error_response(
+                    FailureStage::UnknownClient,
+                    format!("Client '{}' not found", token),
+                )
                 .send(&mut user_stream)
                 .await;
+            }
+            return Err(anyhow!("Client '{}' not found", token));
         }
-        return Err(anyhow!("Client Token not found"));
-    }
+    };
 
-    // Token-based routing
+    // Refuse new work while the client reports itself saturated
+    if let Some(load) = client_info.busy_load() {
+        warn!(
+            "Client '{}' is busy (active sessions: {}, load: {:?}), refusing new connection",
+            token, load.active_sessions, load.load_avg
+        );
+        if http_request.is_some() {
+            let _ = error_response(
+                FailureStage::ClientBusy,
+                format!("Client '{}' is busy", token),
+            )
+            .header("Retry-After", "5")
+            .send(&mut user_stream)
+            .await;
+        }
+        return Err(anyhow!("Client '{}' is busy", token));
+    }
 
-    let client_info = match active_clients.get(token) {
-        Some(info) => info,
-        None => {
-            warn!("Client '{}' not found for token", token);
+    // Refuse once the client or its tenant used up a monthly quota
+    let user_stream: PublicStream = match admit_usage(&state, token) {
+        Ok(meter) => Box::new(Metered::new(user_stream, meter)),
+        Err(e) => {
+            warn!("Refusing connection to '{}': {}", token, e);
             if http_request.is_some() {
-                let _ = HttpResponse::not_found()
-                    .text(format!("Client '{}' not found", token))
+                let _ = error_response(FailureStage::QuotaExceeded, e.to_string())
                     .send(&mut user_stream)
                     .await;
             }
-            return Err(anyhow!("Client '{}' not found", token));
+            return Err(e.into());
         }
     };
 
-    // Phase 2: Try to get connection from pool first (fast path)
-    if let Some(mut proxy_stream) = client_info.pool.pop() {
-        // If we parsed HTTP, we need to reconstruct and send the request
-        if let Some(request) = http_request {
-            // Write reconstructed HTTP request to proxy stream
-            if let Err(e) = write_http_request(&mut proxy_stream, &request).await {
-                error!("Failed to write HTTP request to proxy stream: {}", e);
-                return Err(e);
-            }
+    // Tell the client and the services behind it who is calling
+    if let Some(request) = &mut http_request {
+        request.set_identity(
+            remote_addr.map(|addr| addr.to_string()).as_deref(),
+            &request_id,
+            token,
+        );
+    }
+
+    // Hand WebSocket handshakes over as they came, with whatever the caller
+    // sent after them, and pipe the connection from there on
+    let handshake = http_request
+        .as_ref()
+        .filter(|request| upgrade::is_websocket(request))
+        .and_then(|request| upgrade::handshake(raw.as_deref()?, request));
+    if handshake.is_some() {
+        debug!("Forwarding WebSocket handshake to '{}'", token);
+    }
+
+    let destination = Destination {
+        client_id: token,
+        info: client_info,
+        target: None,
+    };
+    dispatch_to_client(
+        user_stream,
+        http_request,
+        handshake,
+        request_id,
+        destination,
+        permit,
+        &state,
+    )
+    .await
+}
+
+/// Count a connection routed to `client_id` against the monthly quotas of
+/// the client and its tenant; the meter then counts the bytes it relays.
+fn admit_usage(state: &ServerState, client_id: &str) -> Result<Meter, QuotaError> {
+    let tenant = state
+        .tenants
+        .as_ref()
+        .and_then(|tenants| tenants.owner(client_id))
+        .map(|tenant| (tenant.id.as_str(), tenant.quota));
+    state.usage.admit(client_id, tenant)
+}
+
+/// Client a routed public connection goes to.
+struct Destination<'a> {
+    client_id: &'a str,
+    info: Arc<ClientInfo>,
+    /// Address among the client's opened ports, or name of the service it
+    /// offers, the connection is for
+    target: Option<&'a str>,
+}
+
+/// Join a routed public connection with a tunnel to its destination: an
+/// idle pooled one when any still works, else one requested from the
+/// client. Connections for a `target` address always get a tunnel dialed
+/// for it.
+async fn dispatch_to_client(
+    user_stream: PublicStream,
+    http_request: Option<HttpRequest>,
+    handshake: Option<Vec<u8>>,
+    request_id: String,
+    destination: Destination<'_>,
+    permit: ConnectionPermit,
+    state: &ServerState,
+) -> Result<()> {
+    let Destination {
+        client_id: token,
+        info: client_info,
+        target,
+    } = destination;
+    let pending_connections = &state.pending_connections;
+    if let Some(tenants) = &state.tenants {
+        tenants.record_connection(token);
+    }
+
+    // Phase 2: Try to get connection from pool first (fast path).
+    // Pooled connections that died while idle are skipped when popped; if
+    // writing the request still fails, try another one before falling back
+    // to the slow path.
+    let mut attempts = 0;
+    while target.is_none() && attempts < MAX_POOL_ATTEMPTS {
+        let Some(pooled) = client_info.pop_pooled(token) else {
+            break;
+        };
+        attempts += 1;
+        let mut proxy_stream = pooled.stream;
+
+        // If we parsed HTTP, we need to forward the request first
+        if let Err(e) = forward_request(
+            &mut proxy_stream,
+            http_request.as_ref(),
+            handshake.as_deref(),
+        )
+        .await
+        {
+            warn!(
+                "Failed to write HTTP request to pooled connection (attempt {}): {}",
+                attempts, e
+            );
+            continue;
         }
+        record_pairing(
+            &state.pairing,
+            &state.events,
+            token,
+            PairOutcome::PoolHit,
+            &permit,
+        );
 
         // Join the streams directly
-        if let Err(e) = join_streams(user_stream, proxy_stream).await {
+        if let Err(e) = join_streams_with(user_stream, proxy_stream, &state.copy_config).await {
             error!("Error joining streams from pool: {}", e);
         }
 
         return Ok(());
     }
 
+    if attempts > 0 {
+        debug!(
+            "Pooled connections for {} failed {} time(s), falling back to slow path",
+            token, attempts
+        );
+    }
+
     // Phase 3: Fallback to traditional proxy request (slow path)
     let proxy_conn_id = generate_id();
     let command = Command::RequestNewProxyConn {
         proxy_conn_id: proxy_conn_id.clone(),
+        target: target.map(str::to_string),
     };
 
     // Insert into pending before sending command to avoid race condition
+    let queue = pending_key(token, target);
+    let priority = Priority::classify(http_request.as_ref());
     let pending_conn = PendingConnection {
+        client_id: token.to_string(),
         stream: user_stream,
+        request_id,
         timestamp: std::time::Instant::now(),
         http_request,
+        handshake,
+        permit,
     };
-    pending_connections.insert(proxy_conn_id.clone(), pending_conn);
+    pending_connections.push(&queue, proxy_conn_id.clone(), priority, pending_conn);
+    debug!(
+        "Queued {} connection {} for {}",
+        priority.as_str(),
+        proxy_conn_id,
+        token
+    );
+
+    // Pace the request so a flood of public connections can't make the
+    // client dial faster than it can; overflow is answered with 503.
+    // Deferred dials go out without a target, so those for one are refused
+    // rather than deferred
+    let dial = match target {
+        Some(_) => client_info.dials.request_now(),
+        None => client_info.dials.request(&proxy_conn_id),
+    };
+    match dial {
+        Dial::Now => {}
+        Dial::Deferred => {
+            debug!("Deferred tunnel request {} for {}", proxy_conn_id, token);
+            return Ok(());
+        }
+        Dial::Refused => {
+            if let Some(pending) = pending_connections.remove(&queue, &proxy_conn_id) {
+                // Log the first refusal and then every 100th to avoid log storms
+                let refused = client_info.dials.refused.load(Ordering::Relaxed);
+                if refused % 100 == 1 {
+                    warn!(
+                        "Client '{}' is asked for tunnels faster than --dial-rate, refused {} so far",
+                        token, refused
+                    );
+                }
+                if let Some(request) = &pending.http_request {
+                    let response = state
+                        .error_pages
+                        .response(
+                            FailureStage::DialRateLimited,
+                            format!("Too many connections waiting for client '{}'", token),
+                            &pending.request_id,
+                            request.header("accept").map(String::as_str),
+                        )
+                        .header("Retry-After", "1")
+                        .header("Connection", "close");
+                    let mut stream = pending.stream;
+                    let _ =
+                        tokio::time::timeout(Duration::from_secs(1), response.send(&mut stream))
+                            .await;
+                }
+            }
+            return Err(anyhow!("Client '{}' is over its dial rate", token));
+        }
+    }
 
     // Send command to client via channel
     if client_info.cmd_tx.send(command).is_err() {
-        pending_connections.remove(&proxy_conn_id);
+        if let Some(pending) = pending_connections.remove(&queue, &proxy_conn_id) {
+            answer_pending(
+                pending,
+                &state.error_pages,
+                FailureStage::PoolExhausted,
+                format!("Client '{}' disconnected", token),
+            );
+        }
         return Err(anyhow!("Client channel closed"));
     }
 
@@ -462,31 +2651,51 @@ async fn route_public_connection(
 }
 
 // Background task to cleanup expired pending connections
-async fn cleanup_expired_connections(pending_connections: PendingConnectionsMap) {
-    let mut ticker = interval(Duration::from_secs(2));
-    const TIMEOUT_SECS: u64 = 10;
+async fn cleanup_expired_connections(
+    pending_connections: PendingConnectionsMap,
+    error_pages: Arc<ErrorPages>,
+    pairing: Arc<PairingMetrics>,
+    events: Arc<EventHub>,
+    tuning: Arc<Tuning>,
+) {
+    let mut ticker = interval(tuning.pending_cleanup_interval);
+    let timeout = tuning.pending_timeout;
 
     loop {
         ticker.tick().await;
 
         let now = std::time::Instant::now();
-        let initial_count = pending_connections.len();
 
         // Remove expired connections
-        pending_connections.retain(|id, conn| {
+        let expired = pending_connections.remove_where(|id, conn| {
             let age = now.duration_since(conn.timestamp);
-            if age.as_secs() > TIMEOUT_SECS {
+            if age > timeout {
                 warn!(
-                    "Removing expired pending connection {} (age: {:?})",
-                    id, age
+                    client_id = conn.client_id,
+                    "Removing expired pending connection {} (age: {:?})", id, age
                 );
-                false
-            } else {
                 true
+            } else {
+                false
             }
         });
 
-        let removed = initial_count - pending_connections.len();
+        let removed = expired.len();
+        for pending in expired {
+            record_pairing(
+                &pairing,
+                &events,
+                &pending.client_id,
+                PairOutcome::Timeout,
+                &pending.permit,
+            );
+            answer_pending(
+                pending,
+                &error_pages,
+                FailureStage::PairingTimeout,
+                format!("No tunnel from the client within {}s", timeout.as_secs()),
+            );
+        }
         if removed > 0 {
             info!("Cleaned up {} expired pending connections", removed);
         }
@@ -496,22 +2705,28 @@ async fn cleanup_expired_connections(pending_connections: PendingConnectionsMap)
 // Background task to maintain connection pools for all clients
 async fn maintain_connection_pools(
     active_clients: ActiveClients,
-    target_pool_size: usize,
+    liveness: Arc<Liveness>,
+    tuning: Arc<Tuning>,
     prewarm: bool,
 ) {
     // Prewarm pools immediately on first run
     if prewarm {
         for entry in active_clients.iter() {
             let (client_id, client_info) = entry.pair();
+            let target_pool_size = tuning.pool_size_of(client_id);
             info!(
                 "Prewarming pool for client {} with {} connections",
                 client_id, target_pool_size
             );
 
             for _ in 0..target_pool_size {
+                if !client_info.dials.try_refill() {
+                    break;
+                }
                 let pool_conn_id = generate_id();
                 let command = Command::RequestNewProxyConn {
                     proxy_conn_id: pool_conn_id.clone(),
+                    target: None,
                 };
                 if client_info.cmd_tx.send(command).is_err() {
                     break;
@@ -520,29 +2735,47 @@ async fn maintain_connection_pools(
         }
     }
 
-    let mut ticker = interval(Duration::from_secs(2));
+    let mut ticker = interval(tuning.pool_refill_interval);
+    // Earliest next refill of flapping clients, so their pools are refilled
+    // less often instead of storming them with RequestNewProxyConn
+    let mut backed_off: HashMap<String, tokio::time::Instant> = HashMap::new();
 
     loop {
         ticker.tick().await;
+        let now = tokio::time::Instant::now();
+        backed_off.retain(|client_id, _| active_clients.contains_key(client_id));
 
         for entry in active_clients.iter() {
             let (client_id, client_info) = entry.pair();
             let current_size = client_info.pool.len();
+            let target_pool_size = tuning.pool_size_of(client_id);
+
+            if let Some(backoff) = liveness.pool_backoff(client_id) {
+                if backed_off.get(client_id).is_some_and(|next| now < *next) {
+                    continue;
+                }
+                backed_off.insert(client_id.clone(), now + backoff);
+            }
 
             if current_size < target_pool_size {
                 let needed = target_pool_size - current_size;
 
                 // Request additional connections to fill the pool
                 for _ in 0..needed {
+                    // Public connections waiting on the rate take precedence
+                    if !client_info.dials.try_refill() {
+                        break;
+                    }
                     let pool_conn_id = generate_id();
                     let command = Command::RequestNewProxyConn {
                         proxy_conn_id: pool_conn_id.clone(),
+                        target: None,
                     };
 
                     if client_info.cmd_tx.send(command).is_err() {
                         error!(
-                            "Failed to request pool connection for {}: channel closed",
-                            client_id
+                            client_id = client_id.as_str(),
+                            "Failed to request pool connection: channel closed"
                         );
                         break;
                     }
@@ -551,3 +2784,549 @@ async fn maintain_connection_pools(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: &[(&str, &str)], headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: common::http::HttpMethod::GET,
+            path: "/".to_string(),
+            query_params: query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: Vec::new(),
+            trailers: common::http::Params::new(),
+        }
+    }
+
+    /// Server state for `args`, built as `main` builds it but without
+    /// listeners or background tasks
+    pub(crate) fn test_state(args: &[&str]) -> ServerState {
+        let args = Args::parse_from(std::iter::once("arps").chain(args.iter().copied()));
+        let global_limits = Arc::new(GlobalLimits::new(
+            args.max_connections,
+            args.accept_rate,
+            args.accept_burst,
+        ));
+        let guard = |name, max_connections| {
+            Arc::new(ListenerGuard::new(
+                name,
+                max_connections,
+                global_limits.clone(),
+            ))
+        };
+        ServerState {
+            active_clients: Arc::new(DashMap::new()),
+            pending_connections: Arc::new(PendingQueue::new()),
+            copy_config: args.copy_config(),
+            parse_limits: args.parse_limits(),
+            control_guard: guard("control", args.max_control_connections),
+            proxy_guard: guard("proxy", args.max_proxy_connections),
+            public_guard: guard("public", args.max_public_connections),
+            host_claims: Arc::new(HostClaims::default()),
+            tls: None,
+            tunnel_tls: None,
+            auth: None,
+            tenants: None,
+            usage: Arc::new(Usage::new(Quota {
+                connections: args.client_monthly_connections,
+                bytes: args.client_monthly_bytes,
+            })),
+            admin_token: None,
+            evictions: Arc::new(Evictions::default()),
+            tuning: Arc::new(args.tuning().unwrap()),
+            heartbeat: args.heartbeat(),
+            acme: None,
+            events: Arc::new(EventHub::default()),
+            config_acks: Arc::new(DashMap::new()),
+            liveness: Arc::new(Liveness::new(
+                args.flap_threshold,
+                Duration::from_secs(args.flap_window_secs),
+            )),
+            hold: Arc::new(HoldQueue::new(
+                Duration::from_secs(args.hold_secs),
+                args.hold_queue_size,
+                Duration::from_secs(args.hold_feedback_secs),
+            )),
+            error_pages: Arc::new(ErrorPages::new(args.error_format)),
+            dial_limits: args.dial_limits(),
+            pairing: Arc::new(PairingMetrics::new(None)),
+            client_logs: Arc::new(ClientLogs::default()),
+            log_filter: Arc::new(LogFilter::new(
+                args.log_filter.clone(),
+                Box::new(|_| Ok(())),
+            )),
+            public_mode: args.public_mode,
+            default_client: args.default_client.clone(),
+            port_maps: Arc::new(args.port_maps.clone()),
+            client_ports: ClientPortPolicy {
+                max_per_client: args.max_client_ports,
+                range: args.client_port_range.clone(),
+            },
+            open_ports: Arc::new(OpenPorts::default()),
+            rendezvous: Arc::new(Rendezvous::default()),
+        }
+    }
+
+    /// Register `client_id` in `state` as its control connection would,
+    /// returning the commands sent to it
+    pub(crate) fn register_test_client(
+        state: &ServerState,
+        client_id: &str,
+    ) -> mpsc::UnboundedReceiver<Command> {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let info = ClientInfo {
+            cmd_tx,
+            pool: Arc::new(SegQueue::new()),
+            generation: GENERATION_COUNTER.fetch_add(1, Ordering::Relaxed),
+            load: std::sync::Mutex::new(None),
+            dials: state.dial_limits.pacer().0,
+            remote_addr: "198.51.100.7:40000".parse().unwrap(),
+            registered_at: unix_timestamp(),
+            evicted: tokio::sync::Notify::new(),
+        };
+        state
+            .active_clients
+            .insert(client_id.to_string(), Arc::new(info));
+        cmd_rx
+    }
+
+    #[test]
+    fn routing_target_prefers_header_then_client_then_token() {
+        let req = request(
+            &[("token", "tok"), ("client", "cli")],
+            &[("x-arp-client", "hdr")],
+        );
+        assert_eq!(routing_target(&req).as_deref(), Some("hdr"));
+
+        let req = request(&[("token", "tok"), ("client", "cli")], &[]);
+        assert_eq!(routing_target(&req).as_deref(), Some("cli"));
+
+        let req = request(&[("token", "tok"), ("client", "  ")], &[]);
+        assert_eq!(routing_target(&req).as_deref(), Some("tok"));
+
+        assert_eq!(routing_target(&request(&[], &[])), None);
+    }
+
+    #[tokio::test]
+    async fn public_bodies_are_limited_by_default() {
+        let limits = Args::parse_from(["arps"]).parse_limits();
+        assert_eq!(limits.max_body_bytes, 128 * 1024 * 1024);
+
+        let oversized = format!(
+            "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            limits.max_body_bytes + 1
+        );
+        let error = HttpRequest::parse_with_limits(&mut oversized.as_bytes(), "t", &limits)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<BodyTooLargeError>().is_some());
+
+        let limits = Args::parse_from(["arps", "--max-body-bytes", "4"]).parse_limits();
+        let mut small: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        let request = HttpRequest::parse_with_limits(&mut small, "t", &limits)
+            .await
+            .unwrap();
+        assert_eq!(request.body, b"body");
+        assert_eq!(FailureStage::BodyTooLarge.status(), 413);
+    }
+
+    /// Serve the proxy port of `state` on an ephemeral local port
+    async fn proxy_listener(state: &ServerState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(handle_proxy_connections(listener, state.clone()));
+        addr
+    }
+
+    /// Open a proxy connection to `addr` announced as pre-warmed for
+    /// `generation` of `client_id`
+    async fn announce_pooled(addr: SocketAddr, client_id: &str, generation: u64) -> TcpStream {
+        let mut proxy = TcpStream::connect(addr).await.unwrap();
+        let command = Command::NewProxyConn {
+            proxy_conn_id: generate_id(),
+            client_id: client_id.to_string(),
+            pooled: true,
+            generation: Some(generation),
+            target: None,
+            token: None,
+        };
+        write_command(&mut proxy, &command).await.unwrap();
+        proxy
+    }
+
+    #[tokio::test]
+    async fn pre_warmed_connections_wait_in_the_pool() {
+        let state = test_state(&[]);
+        let mut commands = register_test_client(&state, "c1");
+        let info = state.active_clients.get("c1").unwrap().clone();
+        let addr = proxy_listener(&state).await;
+
+        let _proxy = announce_pooled(addr, "c1", info.generation).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while info.pool.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("pre-warmed connection never reached the pool");
+
+        // Served from the pool, so the client isn't asked for a tunnel
+        assert!(info.pop_pooled("c1").is_some());
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn pooled_connections_closed_while_idle_are_skipped() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state = test_state(&[]);
+        let _commands = register_test_client(&state, "c1");
+        let info = state.active_clients.get("c1").unwrap().clone();
+        let addr = proxy_listener(&state).await;
+        let pooled = |count: usize| {
+            let info = info.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while info.pool.len() < count {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("connection never reached the pool");
+            }
+        };
+
+        // The first pooled connection is closed by its client while idle.
+        // Only its write side, so writes to it keep succeeding as they do
+        // until the reset of a fully closed socket makes it back
+        let mut closed = announce_pooled(addr, "c1", info.generation).await;
+        pooled(1).await;
+        closed.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut live = announce_pooled(addr, "c1", info.generation).await;
+        pooled(2).await;
+
+        let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let public_addr = public.local_addr().unwrap();
+        tokio::spawn(handle_public_connections(public, state.clone()));
+        let mut user = TcpStream::connect(public_addr).await.unwrap();
+        user.write_all(b"GET /hello?token=c1 HTTP/1.1\r\nHost: arps\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut request = [0u8; 10];
+        tokio::time::timeout(Duration::from_secs(5), live.read_exact(&mut request))
+            .await
+            .expect("request never reached the open pooled connection")
+            .unwrap();
+        assert_eq!(&request, b"GET /hello");
+        live.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        drop(live);
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+        assert!(info.pool.is_empty());
+    }
+
+    mod reconstruction {
+        use super::*;
+        use common::http::HttpMethod;
+        use proptest::prelude::*;
+        use std::collections::BTreeMap;
+
+        /// A query component as sent on the wire and the text it stands for
+        fn query_component() -> impl Strategy<Value = (String, String)> {
+            let piece = prop_oneof![
+                "[A-Za-z0-9._~-]".prop_map(|c| (c.clone(), c)),
+                Just(("+".to_string(), " ".to_string())),
+                any::<char>().prop_map(|c| {
+                    let text = c.to_string();
+                    (urlencoding::encode(&text).into_owned(), text)
+                }),
+            ];
+            prop::collection::vec(piece, 0..8).prop_map(|pieces| {
+                pieces
+                    .into_iter()
+                    .fold((String::new(), String::new()), |(raw, text), (r, t)| {
+                        (raw + &r, text + &t)
+                    })
+            })
+        }
+
+        fn method() -> impl Strategy<Value = HttpMethod> {
+            prop_oneof![
+                Just(HttpMethod::GET),
+                Just(HttpMethod::POST),
+                Just(HttpMethod::PUT),
+                Just(HttpMethod::DELETE),
+                Just(HttpMethod::PATCH),
+                Just(HttpMethod::OPTIONS),
+                Just(HttpMethod::HEAD),
+            ]
+        }
+
+        fn header_name() -> impl Strategy<Value = String> {
+            "[a-z][a-z0-9-]{0,15}".prop_filter("set by the test", |name| name != "content-length")
+        }
+
+        fn header_value() -> impl Strategy<Value = String> {
+            "[!-~]([ -~]{0,30}[!-~])?"
+        }
+
+        /// Raw bytes of a request as a user agent would send it
+        fn raw_request(
+            method: &HttpMethod,
+            path: &str,
+            query: &[(String, String)],
+            headers: &[(String, String)],
+            body: &[u8],
+        ) -> Vec<u8> {
+            let mut head = format!("{} {}", method.as_str(), path);
+            if !query.is_empty() {
+                let pairs: Vec<String> =
+                    query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                head.push('?');
+                head.push_str(&pairs.join("&"));
+            }
+            head.push_str(" HTTP/1.1\r\n");
+            for (name, value) in headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+            let mut raw = head.into_bytes();
+            raw.extend_from_slice(body);
+            raw
+        }
+
+        /// Parse `raw` and write it back out the way it is forwarded to a client
+        fn reconstruct(raw: &[u8]) -> (HttpRequest, Vec<u8>) {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let received =
+                        HttpRequest::parse_with_limits(&mut &raw[..], "t", &ParseLimits::default())
+                            .await
+                            .unwrap();
+                    let mut forwarded = Vec::new();
+                    write_http_request(&mut forwarded, &received).await.unwrap();
+                    (received, forwarded)
+                })
+        }
+
+        /// The request as received and as the client parses it after forwarding
+        fn forward(raw: &[u8]) -> (HttpRequest, HttpRequest) {
+            let (received, forwarded) = reconstruct(raw);
+            let reparsed = reconstruct(&forwarded).0;
+            (received, reparsed)
+        }
+
+        proptest! {
+            #[test]
+            fn forwarded_request_matches_received(
+                method in method(),
+                path in "(/[A-Za-z0-9._~%-]{0,12}){1,4}",
+                query in prop::collection::btree_map("[a-z_]{1,8}", query_component(), 0..6),
+                headers in prop::collection::btree_map(header_name(), header_value(), 0..8),
+                body in prop::collection::vec(any::<u8>(), 0..512),
+            ) {
+                let raw_query: Vec<(String, String)> = query
+                    .iter()
+                    .map(|(key, (raw, _))| (key.clone(), raw.clone()))
+                    .collect();
+                let headers: Vec<(String, String)> = headers.into_iter().collect();
+                let raw = raw_request(&method, &path, &raw_query, &headers, &body);
+                let (received, forwarded) = forward(&raw);
+
+                prop_assert_eq!(&forwarded.method, &method);
+                prop_assert_eq!(&forwarded.path, &path);
+                let expected_query: BTreeMap<String, String> = query
+                    .into_iter()
+                    .map(|(key, (_, text))| (key, text))
+                    .collect();
+                let forwarded_query: BTreeMap<String, String> =
+                    forwarded.query_params.clone().into_iter().collect();
+                prop_assert_eq!(forwarded_query, expected_query);
+                for (name, value) in &headers {
+                    prop_assert_eq!(forwarded.header(name), Some(value));
+                }
+                prop_assert_eq!(&forwarded.headers, &received.headers);
+                prop_assert_eq!(&forwarded.body, &body);
+            }
+
+            #[test]
+            fn forwarded_request_keeps_repeated_headers(
+                name in header_name(),
+                values in prop::collection::vec(header_value(), 2..5),
+            ) {
+                let headers: Vec<(String, String)> =
+                    values.iter().map(|value| (name.clone(), value.clone())).collect();
+                let raw = raw_request(&HttpMethod::GET, "/", &[], &headers, b"");
+                let (_, forwarded) = reconstruct(&raw);
+
+                let forwarded_raw = String::from_utf8_lossy(&forwarded).into_owned();
+                let sent: Vec<&str> = forwarded_raw
+                    .lines()
+                    .filter_map(|line| line.strip_prefix(&format!("{}: ", name)))
+                    .collect();
+                prop_assert_eq!(sent, values.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+
+            #[test]
+            fn forwarded_request_keeps_repeated_query_keys(
+                query in prop::collection::vec(("[ab]", query_component()), 0..6),
+            ) {
+                let raw_query: Vec<(String, String)> = query
+                    .iter()
+                    .map(|(key, (raw, _))| (key.clone(), raw.clone()))
+                    .collect();
+                let raw = raw_request(&HttpMethod::GET, "/", &raw_query, &[], b"");
+                let (_, forwarded) = forward(&raw);
+
+                let expected: Vec<(String, String)> = query
+                    .into_iter()
+                    .map(|(key, (_, text))| (key, text))
+                    .collect();
+                let forwarded: Vec<(String, String)> = forwarded.query_params.into_iter().collect();
+                prop_assert_eq!(forwarded, expected);
+            }
+        }
+    }
+
+    mod join_path {
+        use super::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        /// Send `request` through the steps a routed public connection takes
+        /// (parse, reconstruct, join) to a local server answering with
+        /// `response`. Returns the request as the server parsed it and the
+        /// bytes the user received.
+        async fn relay(request: &[u8], response: &'static [u8]) -> (HttpRequest, Vec<u8>) {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = upstream.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut conn, _) = upstream.accept().await.unwrap();
+                let received =
+                    HttpRequest::parse_with_limits(&mut conn, "upstream", &ParseLimits::default())
+                        .await
+                        .unwrap();
+                conn.write_all(response).await.unwrap();
+                conn.shutdown().await.unwrap();
+                received
+            });
+
+            let (mut user, mut public) = tokio::io::duplex(64 * 1024);
+            user.write_all(request).await.unwrap();
+            user.shutdown().await.unwrap();
+            let join = tokio::spawn(async move {
+                let parsed =
+                    HttpRequest::parse_with_limits(&mut public, "t", &ParseLimits::default())
+                        .await
+                        .unwrap();
+                let mut tunnel = TcpStream::connect(addr).await.unwrap();
+                write_http_request(&mut tunnel, &parsed).await.unwrap();
+                join_streams_with(public, tunnel, &CopyConfig::default())
+                    .await
+                    .unwrap();
+            });
+
+            let mut received = Vec::new();
+            user.read_to_end(&mut received).await.unwrap();
+            join.await.unwrap();
+            (server.await.unwrap(), received)
+        }
+
+        #[tokio::test]
+        async fn responses_pass_through_unmodified() {
+            let responses: [&'static [u8]; 3] = [
+                // Informational responses ahead of a bodyless final one
+                b"HTTP/1.1 100 Continue\r\n\r\n\
+HTTP/1.1 103 Early Hints\r\nLink: </app.css>; rel=preload\r\n\r\n\
+HTTP/1.1 204 No Content\r\nX-Request: 1\r\n\r\n",
+                // Content-Length describes the cached representation, no body follows
+                b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 1024\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n\
+4\r\nwiki\r\n0\r\nX-Checksum: 7\r\n\r\n",
+            ];
+            for response in responses {
+                let (_, received) =
+                    relay(b"GET /page HTTP/1.1\r\nHost: example\r\n\r\n", response).await;
+                assert_eq!(
+                    String::from_utf8_lossy(&received),
+                    String::from_utf8_lossy(response)
+                );
+            }
+        }
+
+        #[tokio::test]
+        async fn chunked_request_trailers_reach_the_server() {
+            let request = b"POST /upload HTTP/1.1\r\nHost: example\r\n\
+Transfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n\
+3\r\nabc\r\n2\r\nde\r\n0\r\nX-Checksum: 42\r\n\r\n";
+            let (received, _) = relay(request, b"HTTP/1.1 204 No Content\r\n\r\n").await;
+
+            assert_eq!(received.body, b"abcde");
+            assert_eq!(
+                received.trailers.get("x-checksum").map(String::as_str),
+                Some("42")
+            );
+        }
+
+        #[tokio::test]
+        async fn websocket_connections_are_piped_after_the_handshake() {
+            let handshake: &[u8] = b"GET /ws?room=a%20b HTTP/1.1\r\nHost: example\r\n\
+Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: a2V5\r\n\r\n";
+            let frame: &[u8] = b"\x81\x05hello";
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = upstream.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut conn, _) = upstream.accept().await.unwrap();
+                // The handshake and the frame sent right behind it
+                let mut received = vec![0; handshake.len() + frame.len()];
+                conn.read_exact(&mut received).await.unwrap();
+                conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n\x81\x02ok")
+                    .await
+                    .unwrap();
+                conn.shutdown().await.unwrap();
+                received
+            });
+
+            let (mut user, mut public) = tokio::io::duplex(64 * 1024);
+            user.write_all(&[handshake, frame].concat()).await.unwrap();
+            let join = tokio::spawn(async move {
+                let limits = ParseLimits::default();
+                let mut recording = upgrade::Recording::new(&mut public, limits.max_header_bytes);
+                let parsed = HttpRequest::parse_with_limits(&mut recording, "t", &limits)
+                    .await
+                    .unwrap();
+                let raw = recording.into_bytes().unwrap();
+                assert!(upgrade::is_websocket(&parsed));
+                let handshake = upgrade::handshake(&raw, &parsed);
+                let mut tunnel = TcpStream::connect(addr).await.unwrap();
+                forward_request(&mut tunnel, Some(&parsed), handshake.as_deref())
+                    .await
+                    .unwrap();
+                join_streams_with(public, tunnel, &CopyConfig::default())
+                    .await
+                    .unwrap();
+            });
+
+            let mut reply = Vec::new();
+            user.read_to_end(&mut reply).await.unwrap();
+            user.shutdown().await.unwrap();
+            join.await.unwrap();
+            assert_eq!(server.await.unwrap(), [handshake, frame].concat());
+            assert_eq!(reply, b"HTTP/1.1 101 Switching Protocols\r\n\r\n\x81\x02ok");
+        }
+    }
+}
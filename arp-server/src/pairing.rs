@@ -0,0 +1,198 @@
+use serde_json::{Value, json};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::warn;
+
+/// Upper bounds in milliseconds of the time-to-pair histogram buckets; a
+/// last bucket without a bound takes everything slower.
+const BUCKET_BOUNDS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// How a public connection got (or failed to get) a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairOutcome {
+    /// Served by an idle connection from the client's pool
+    PoolHit,
+    /// Waited for a tunnel requested with `RequestNewProxyConn`
+    PoolMiss,
+    /// No tunnel arrived in time
+    Timeout,
+}
+
+impl PairOutcome {
+    const ALL: [PairOutcome; 3] = [
+        PairOutcome::PoolHit,
+        PairOutcome::PoolMiss,
+        PairOutcome::Timeout,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PairOutcome::PoolHit => "pool_hit",
+            PairOutcome::PoolMiss => "pool_miss",
+            PairOutcome::Timeout => "timeout",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+    slo_breaches: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed_ms: u64) {
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| elapsed_ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(elapsed_ms, Ordering::Relaxed);
+    }
+
+    /// Bound of the bucket holding quantile `q`; None when it is the
+    /// unbounded bucket or nothing was observed.
+    fn quantile_ms(&self, counts: &[u64], q: f64) -> Option<u64> {
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = (q * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKET_BOUNDS_MS.get(i).copied();
+            }
+        }
+        None
+    }
+
+    fn snapshot(&self) -> Value {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let mut cumulative = 0;
+        let buckets: Vec<Value> = counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                cumulative += count;
+                json!({ "le_ms": BUCKET_BOUNDS_MS.get(i), "count": cumulative })
+            })
+            .collect();
+        json!({
+            "count": self.count.load(Ordering::Relaxed),
+            "sum_ms": self.sum_ms.load(Ordering::Relaxed),
+            "slo_breaches": self.slo_breaches.load(Ordering::Relaxed),
+            "p50_ms": self.quantile_ms(&counts, 0.5),
+            "p90_ms": self.quantile_ms(&counts, 0.9),
+            "p99_ms": self.quantile_ms(&counts, 0.99),
+            "buckets": buckets,
+        })
+    }
+}
+
+/// Time-to-pair of public connections, from accept until the user stream
+/// is joined with a tunnel, per outcome. The key health signal of the relay:
+/// a growing share of pool misses or slow pairings means clients can't keep
+/// their pools filled.
+pub struct PairingMetrics {
+    slo: Option<Duration>,
+    outcomes: [Histogram; 3],
+}
+
+impl PairingMetrics {
+    /// Pairings slower than `slo` are logged as SLO breaches (None = never).
+    pub fn new(slo: Option<Duration>) -> Self {
+        PairingMetrics {
+            slo,
+            outcomes: Default::default(),
+        }
+    }
+
+    /// Record a pairing of `client_id`. Returns the number of breaches of
+    /// this outcome so far when this one exceeded the SLO and should be
+    /// alerted on: the first and then every 100th, to avoid alert storms.
+    pub fn record(&self, client_id: &str, outcome: PairOutcome, elapsed: Duration) -> Option<u64> {
+        let histogram = &self.outcomes[outcome as usize];
+        histogram.observe(elapsed.as_millis() as u64);
+
+        let slo = self.slo.filter(|slo| elapsed > *slo)?;
+        let breaches = histogram.slo_breaches.fetch_add(1, Ordering::Relaxed) + 1;
+        if breaches % 100 != 1 {
+            return None;
+        }
+        warn!(
+            client_id,
+            "Pairing SLO breached ({}): {:?} > {:?}, {} breaches so far",
+            outcome.as_str(),
+            elapsed,
+            slo,
+            breaches
+        );
+        Some(breaches)
+    }
+
+    pub fn slo(&self) -> Option<Duration> {
+        self.slo
+    }
+
+    /// Histograms of every outcome, for the admin API.
+    pub fn snapshot(&self) -> Value {
+        let mut outcomes = serde_json::Map::new();
+        for outcome in PairOutcome::ALL {
+            outcomes.insert(
+                outcome.as_str().to_string(),
+                self.outcomes[outcome as usize].snapshot(),
+            );
+        }
+        json!({
+            "slo_ms": self.slo.map(|slo| slo.as_millis() as u64),
+            "outcomes": outcomes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairings_are_bucketed_per_outcome_and_breaches_alerted_sparingly() {
+        let metrics = PairingMetrics::new(Some(Duration::from_millis(100)));
+        for _ in 0..9 {
+            metrics.record("c", PairOutcome::PoolHit, Duration::from_millis(3));
+        }
+        assert_eq!(
+            metrics.record("c", PairOutcome::PoolHit, Duration::from_millis(300)),
+            Some(1)
+        );
+        assert_eq!(
+            metrics.record("c", PairOutcome::PoolHit, Duration::from_millis(300)),
+            None
+        );
+        metrics.record("c", PairOutcome::Timeout, Duration::from_secs(12));
+
+        let snapshot = metrics.snapshot();
+        let hit = &snapshot["outcomes"]["pool_hit"];
+        assert_eq!(hit["count"], 11);
+        assert_eq!(hit["slo_breaches"], 2);
+        assert_eq!(hit["p50_ms"], 5);
+        assert_eq!(hit["p99_ms"], 500);
+        assert_eq!(snapshot["outcomes"]["pool_miss"]["count"], 0);
+        assert_eq!(snapshot["outcomes"]["pool_miss"]["p50_ms"], Value::Null);
+        let timeout = &snapshot["outcomes"]["timeout"];
+        assert_eq!(timeout["p50_ms"], Value::Null);
+        assert_eq!(
+            timeout["buckets"].as_array().unwrap().last().unwrap()["count"],
+            1
+        );
+        assert_eq!(snapshot["slo_ms"], 100);
+    }
+}
@@ -0,0 +1,326 @@
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Ports one `--port-map` may open at once.
+const MAX_MAPPED_PORTS: usize = 1024;
+
+/// Public ports whose connections all go to one client as raw TCP, like
+/// frp's `remote_port`: no token, HTTP parsing or SNI involved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub ports: RangeInclusive<u16>,
+    pub client_id: String,
+}
+
+/// `<port>` or `<first>-<last>`, at most [`MAX_MAPPED_PORTS`] ports.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let port = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|p| *p != 0)
+            .ok_or_else(|| format!("invalid port '{}' in '{}'", p, s))
+    };
+    let ports = match s.split_once('-') {
+        Some((first, last)) => port(first)?..=port(last)?,
+        None => {
+            let port = port(s)?;
+            port..=port
+        }
+    };
+    if ports.is_empty() {
+        return Err(format!("empty port range '{}'", s));
+    }
+    if ports.len() > MAX_MAPPED_PORTS {
+        return Err(format!(
+            "'{}' spans {} ports, at most {} are allowed",
+            s,
+            ports.len(),
+            MAX_MAPPED_PORTS
+        ));
+    }
+    Ok(ports)
+}
+
+impl FromStr for PortMapping {
+    type Err = String;
+
+    /// `<port>=<client_id>` or `<first>-<last>=<client_id>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, client_id) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <port>[-<port>]=<client_id>, got '{}'", s))?;
+        let client_id = client_id.trim();
+        if client_id.is_empty() {
+            return Err(format!("no client_id in '{}'", s));
+        }
+        Ok(PortMapping {
+            ports: parse_range(ports)?,
+            client_id: client_id.to_string(),
+        })
+    }
+}
+
+impl PortMapping {
+    /// The ports as written on the command line
+    pub fn ports_str(&self) -> String {
+        if self.ports.start() == self.ports.end() {
+            self.ports.start().to_string()
+        } else {
+            format!("{}-{}", self.ports.start(), self.ports.end())
+        }
+    }
+}
+
+/// Ports mapped to `client_id`, for the admin API.
+pub fn ports_of(mappings: &[PortMapping], client_id: &str) -> Vec<String> {
+    mappings
+        .iter()
+        .filter(|mapping| mapping.client_id == client_id)
+        .map(PortMapping::ports_str)
+        .collect()
+}
+
+/// Which ports clients may open with `OpenPort`.
+#[derive(Debug, Clone)]
+pub struct ClientPortPolicy {
+    /// Ports open at once per client (0 = `OpenPort` is refused)
+    pub max_per_client: usize,
+    /// Ports handed out; any free ephemeral port when unset, in which case
+    /// a specific port can't be asked for
+    pub range: Option<RangeInclusive<u16>>,
+}
+
+/// A port a client opened, listed in the admin API
+struct OpenPort {
+    client_id: String,
+    target: String,
+}
+
+/// Ports opened by clients with `OpenPort`.
+#[derive(Default)]
+pub struct OpenPorts {
+    ports: DashMap<u16, OpenPort>,
+}
+
+impl OpenPorts {
+    /// Bind a port for `target` of `client_id`, `requested` or else any the
+    /// policy allows. The caller starts serving the listener and hands the
+    /// task to [`PortLease::new`].
+    pub async fn bind(
+        &self,
+        policy: &ClientPortPolicy,
+        client_id: &str,
+        requested: Option<u16>,
+    ) -> Result<TcpListener, String> {
+        if policy.max_per_client == 0 {
+            return Err("opening ports is disabled on this server".to_string());
+        }
+        let open = self
+            .ports
+            .iter()
+            .filter(|entry| entry.client_id == client_id)
+            .count();
+        if open >= policy.max_per_client {
+            return Err(format!(
+                "at most {} open ports per client",
+                policy.max_per_client
+            ));
+        }
+
+        let candidates: Vec<u16> = match (&policy.range, requested) {
+            (None, Some(_)) => {
+                return Err("this server only hands out ephemeral ports".to_string());
+            }
+            (None, None) => vec![0],
+            (Some(range), Some(port)) if !range.contains(&port) => {
+                return Err(format!(
+                    "port {} is outside {}-{}",
+                    port,
+                    range.start(),
+                    range.end()
+                ));
+            }
+            (Some(_), Some(port)) => vec![port],
+            (Some(range), None) => {
+                // Start somewhere random so freed ports aren't reused at once
+                let offset = rand::random::<u16>() as usize % range.len();
+                range
+                    .clone()
+                    .cycle()
+                    .skip(offset)
+                    .take(range.len())
+                    .collect()
+            }
+        };
+        for port in candidates {
+            if port != 0 && self.ports.contains_key(&port) {
+                continue;
+            }
+            if let Ok(listener) = TcpListener::bind(("0.0.0.0", port)).await {
+                return Ok(listener);
+            }
+        }
+        Err(match requested {
+            Some(port) => format!("port {} is not available", port),
+            None => "no free port".to_string(),
+        })
+    }
+
+    /// Ports of `client_id`, for the admin API.
+    pub fn of(&self, client_id: &str) -> Vec<Value> {
+        let mut ports: Vec<(u16, Value)> = self
+            .ports
+            .iter()
+            .filter(|entry| entry.client_id == client_id)
+            .map(|entry| {
+                let port = *entry.key();
+                (port, json!({ "port": port, "target": entry.target }))
+            })
+            .collect();
+        ports.sort_by_key(|(port, _)| *port);
+        ports.into_iter().map(|(_, port)| port).collect()
+    }
+}
+
+/// Keeps a client's port open; dropping it, when the client's control
+/// connection ends, stops the listener and frees the port.
+pub struct PortLease {
+    port: u16,
+    ports: Arc<OpenPorts>,
+    task: JoinHandle<()>,
+}
+
+impl PortLease {
+    pub fn new(
+        ports: Arc<OpenPorts>,
+        port: u16,
+        client_id: &str,
+        target: &str,
+        task: JoinHandle<()>,
+    ) -> Self {
+        ports.ports.insert(
+            port,
+            OpenPort {
+                client_id: client_id.to_string(),
+                target: target.to_string(),
+            },
+        );
+        PortLease { port, ports, task }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.ports.ports.remove(&self.port);
+    }
+}
+
+/// Fail when two mappings share a port, naming it.
+pub fn check_overlaps(mappings: &[PortMapping]) -> Result<(), String> {
+    for (i, a) in mappings.iter().enumerate() {
+        for b in &mappings[i + 1..] {
+            let first = *a.ports.start().max(b.ports.start());
+            if first <= *a.ports.end().min(b.ports.end()) {
+                return Err(format!(
+                    "Port {} is mapped to both '{}' and '{}'",
+                    first, a.client_id, b.client_id
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_parse_single_ports_and_ranges() {
+        let single: PortMapping = "6000=db".parse().unwrap();
+        assert_eq!(single.ports, 6000..=6000);
+        assert_eq!(single.client_id, "db");
+
+        let range: PortMapping = "7000-7009 = ssh".parse().unwrap();
+        assert_eq!(range.ports, 7000..=7009);
+        assert_eq!(range.client_id, "ssh");
+        assert_eq!(range.ports_str(), "7000-7009");
+
+        for bad in [
+            "6000",
+            "6000=",
+            "0=db",
+            "70000=db",
+            "7009-7000=ssh",
+            "1-2000=x",
+        ] {
+            assert!(bad.parse::<PortMapping>().is_err(), "{}", bad);
+        }
+
+        let overlapping = [range, "7005=db".parse().unwrap()];
+        assert_eq!(
+            check_overlaps(&overlapping).unwrap_err(),
+            "Port 7005 is mapped to both 'ssh' and 'db'"
+        );
+        assert!(check_overlaps(&overlapping[..1]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn open_ports_follow_the_policy_and_close_with_their_lease() {
+        let ports = Arc::new(OpenPorts::default());
+        let disabled = ClientPortPolicy {
+            max_per_client: 0,
+            range: None,
+        };
+        assert!(ports.bind(&disabled, "a", None).await.is_err());
+
+        let ephemeral = ClientPortPolicy {
+            max_per_client: 1,
+            range: None,
+        };
+        assert!(ports.bind(&ephemeral, "a", Some(2222)).await.is_err());
+        let listener = ports.bind(&ephemeral, "a", None).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            let _listener = listener;
+            std::future::pending::<()>().await
+        });
+        let lease = PortLease::new(ports.clone(), port, "a", "127.0.0.1:22", task);
+        assert_eq!(
+            ports.of("a"),
+            vec![json!({ "port": port, "target": "127.0.0.1:22" })]
+        );
+        assert!(ports.bind(&ephemeral, "a", None).await.is_err());
+        assert!(ports.of("b").is_empty());
+
+        let ranged = ClientPortPolicy {
+            max_per_client: 2,
+            range: Some(port..=port),
+        };
+        assert!(
+            ports
+                .bind(&ranged, "b", Some(port.wrapping_sub(1)))
+                .await
+                .is_err()
+        );
+        assert!(ports.bind(&ranged, "b", None).await.is_err());
+
+        drop(lease);
+        assert!(ports.of("a").is_empty());
+        // The aborted task drops the listener shortly after
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let listener = ports.bind(&ranged, "b", None).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+}
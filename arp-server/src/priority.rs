@@ -0,0 +1,308 @@
+use common::http::HttpRequest;
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+
+/// Scheduling class of a public connection waiting for a proxy connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Session streams, LSP and other requests a user is watching
+    Interactive,
+    Normal,
+    /// File downloads and uploads
+    Bulk,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Interactive, Priority::Normal, Priority::Bulk];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Normal => "normal",
+            Priority::Bulk => "bulk",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|priority| priority.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+
+    /// Share of proxy connections the class gets while every class is waiting.
+    fn weight(self) -> i64 {
+        match self {
+            Priority::Interactive => 8,
+            Priority::Normal => 3,
+            Priority::Bulk => 1,
+        }
+    }
+
+    /// Class of a public connection. An `X-ARP-Priority` header wins over a
+    /// `priority` query parameter next to the token, which wins over the path.
+    /// Raw TCP connections are `Normal`.
+    pub fn classify(request: Option<&HttpRequest>) -> Self {
+        let Some(request) = request else {
+            return Priority::Normal;
+        };
+        if let Some(priority) = [
+            request.header("x-arp-priority"),
+            request.query_param("priority"),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|value| Priority::parse(value))
+        {
+            return priority;
+        }
+
+        let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            ["api", "fs", ..] | ["api", "sessions", _, "fs", ..] => Priority::Bulk,
+            ["api", "sessions", ..] | ["api", "lsp", ..] => Priority::Interactive,
+            _ if request.header("upgrade").is_some()
+                || request
+                    .header("accept")
+                    .is_some_and(|accept| accept.contains("text/event-stream")) =>
+            {
+                Priority::Interactive
+            }
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Public connections of one client waiting for a proxy connection.
+struct ClientQueue<T> {
+    /// One FIFO per class, indexed like `Priority::ALL`
+    classes: [VecDeque<(String, T)>; 3],
+    /// Smooth weighted round-robin credit per class
+    credit: [i64; 3],
+}
+
+impl<T> ClientQueue<T> {
+    fn new() -> Self {
+        ClientQueue {
+            classes: Default::default(),
+            credit: [0; 3],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.classes.iter().all(VecDeque::is_empty)
+    }
+
+    /// Take the next connection by weight: interactive first when classes tie,
+    /// without starving bulk transfers behind a stream of interactive ones.
+    fn pop_weighted(&mut self) -> Option<(String, T)> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, priority) in Priority::ALL.into_iter().enumerate() {
+            if self.classes[i].is_empty() {
+                self.credit[i] = 0;
+                continue;
+            }
+            self.credit[i] += priority.weight();
+            total += priority.weight();
+            if best.is_none_or(|best| self.credit[i] > self.credit[best]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        self.credit[best] -= total;
+        self.classes[best].pop_front()
+    }
+
+    fn position(&self, proxy_conn_id: &str) -> Option<(usize, usize)> {
+        self.classes.iter().enumerate().find_map(|(class, queue)| {
+            queue
+                .iter()
+                .position(|(id, _)| id == proxy_conn_id)
+                .map(|index| (class, index))
+        })
+    }
+}
+
+/// Public connections waiting for a proxy connection, queued per client and
+/// priority class. Each waiting connection holds the id of the
+/// `RequestNewProxyConn` sent for it, but is served by whichever proxy
+/// connection of its client arrives first according to the weights.
+pub struct PendingQueue<T> {
+    clients: DashMap<String, ClientQueue<T>>,
+}
+
+impl<T> PendingQueue<T> {
+    pub fn new() -> Self {
+        PendingQueue {
+            clients: DashMap::new(),
+        }
+    }
+
+    pub fn push(&self, client_id: &str, proxy_conn_id: String, priority: Priority, item: T) {
+        let mut queue = self
+            .clients
+            .entry(client_id.to_string())
+            .or_insert_with(ClientQueue::new);
+        queue.classes[priority as usize].push_back((proxy_conn_id, item));
+    }
+
+    /// Pick the waiting connection a new proxy connection of `client_id`
+    /// should serve. When it was requested for another connection, that
+    /// connection inherits the request of the one picked, so every waiting
+    /// connection keeps exactly one request outstanding.
+    pub fn pair(&self, client_id: &str, proxy_conn_id: Option<&str>) -> Option<T> {
+        let mut queue = self.clients.get_mut(client_id)?;
+        let (picked_id, item) = queue.pop_weighted()?;
+        if let Some(proxy_conn_id) = proxy_conn_id
+            && proxy_conn_id != picked_id
+            && let Some((class, index)) = queue.position(proxy_conn_id)
+        {
+            queue.classes[class][index].0 = picked_id;
+        }
+        let empty = queue.is_empty();
+        drop(queue);
+        if empty {
+            self.clients
+                .remove_if(client_id, |_, queue| queue.is_empty());
+        }
+        Some(item)
+    }
+
+    /// Remove the connection waiting on the request `proxy_conn_id`.
+    pub fn remove(&self, client_id: &str, proxy_conn_id: &str) -> Option<T> {
+        let mut queue = self.clients.get_mut(client_id)?;
+        let (class, index) = queue.position(proxy_conn_id)?;
+        let (_, item) = queue.classes[class].remove(index)?;
+        let empty = queue.is_empty();
+        drop(queue);
+        if empty {
+            self.clients
+                .remove_if(client_id, |_, queue| queue.is_empty());
+        }
+        Some(item)
+    }
+
+    /// Whether a connection still waits on the request `proxy_conn_id`.
+    pub fn contains(&self, client_id: &str, proxy_conn_id: &str) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|queue| queue.position(proxy_conn_id).is_some())
+    }
+
+    /// Remove and return the waiting connections for which `expired` returns true.
+    pub fn remove_where(&self, mut expired: impl FnMut(&str, &T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        self.clients.retain(|_, queue| {
+            for class in &mut queue.classes {
+                let (gone, kept) = std::mem::take(class)
+                    .into_iter()
+                    .partition(|(id, item)| expired(id, item));
+                *class = kept;
+                removed.extend(gone.into_iter().map(|(_, item): (String, T)| item));
+            }
+            !queue.is_empty()
+        });
+        removed
+    }
+
+    /// Waiting connections of every client.
+    pub fn len(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|queue| queue.classes.iter().map(VecDeque::len).sum::<usize>())
+            .sum()
+    }
+
+    /// Waiting connections of `client_id` per class.
+    pub fn depths(&self, client_id: &str) -> Value {
+        let queue = self.clients.get(client_id);
+        let mut depths = serde_json::Map::new();
+        for (i, priority) in Priority::ALL.into_iter().enumerate() {
+            let depth = queue.as_ref().map_or(0, |queue| queue.classes[i].len());
+            depths.insert(priority.as_str().to_string(), json!(depth));
+        }
+        Value::Object(depths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::http::{HttpMethod, Params};
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::GET,
+            path: path.to_string(),
+            query_params: Params::new(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: Vec::new(),
+            trailers: Params::new(),
+        }
+    }
+
+    #[test]
+    fn classify_prefers_header_then_path() {
+        assert_eq!(
+            Priority::classify(Some(&request("/api/sessions/abc", &[]))),
+            Priority::Interactive
+        );
+        assert_eq!(
+            Priority::classify(Some(&request("/api/sessions/abc/fs/big.bin", &[]))),
+            Priority::Bulk
+        );
+        assert_eq!(
+            Priority::classify(Some(&request(
+                "/api/fs/big.bin",
+                &[("x-arp-priority", "Normal")]
+            ))),
+            Priority::Normal
+        );
+        assert_eq!(
+            Priority::classify(Some(&request("/proxy/3000/", &[]))),
+            Priority::Normal
+        );
+        assert_eq!(Priority::classify(None), Priority::Normal);
+    }
+
+    #[test]
+    fn interactive_connections_jump_ahead_without_starving_bulk() {
+        let queue = PendingQueue::new();
+        for i in 0..10 {
+            queue.push("a", format!("b{i}"), Priority::Bulk, format!("bulk{i}"));
+        }
+        for i in 0..10 {
+            queue.push(
+                "a",
+                format!("i{i}"),
+                Priority::Interactive,
+                format!("int{i}"),
+            );
+        }
+
+        let served: Vec<String> = (0..9).filter_map(|_| queue.pair("a", None)).collect();
+        assert_eq!(served.iter().filter(|s| s.starts_with("int")).count(), 8);
+        assert_eq!(served.iter().filter(|s| s.starts_with("bulk")).count(), 1);
+        assert_eq!(served[0], "int0");
+        assert_eq!(queue.pair("b", None), None);
+    }
+
+    #[test]
+    fn served_connection_hands_its_request_to_the_skipped_one() {
+        let queue = PendingQueue::new();
+        queue.push("a", "1".to_string(), Priority::Bulk, "download");
+        queue.push("a", "2".to_string(), Priority::Interactive, "stream");
+
+        // The proxy connection requested for the download serves the stream,
+        // so the download now waits on the request made for the stream
+        assert_eq!(queue.pair("a", Some("1")), Some("stream"));
+        assert_eq!(queue.remove("a", "1"), None);
+        assert_eq!(queue.depths("a")["bulk"], 1);
+        assert_eq!(queue.remove("a", "2"), Some("download"));
+        assert_eq!(queue.pair("a", None), None);
+    }
+}
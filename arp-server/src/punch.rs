@@ -0,0 +1,63 @@
+use dashmap::DashMap;
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+
+/// One side of a hole punching attempt, waiting for the other
+struct Waiting {
+    addr: SocketAddr,
+    peer: oneshot::Sender<SocketAddr>,
+}
+
+/// Pairs the two ends of a hole punching attempt so each learns the public
+/// address the server sees the other at.
+#[derive(Default)]
+pub struct Rendezvous {
+    waiting: DashMap<String, Waiting>,
+}
+
+impl Rendezvous {
+    /// Start waiting for the peer of `punch_id`, the side at `addr` having
+    /// asked for it. Register before asking the peer so it can't be missed.
+    pub fn expect(&self, punch_id: &str, addr: SocketAddr) -> oneshot::Receiver<SocketAddr> {
+        let (peer, rx) = oneshot::channel();
+        self.waiting
+            .insert(punch_id.to_string(), Waiting { addr, peer });
+        rx
+    }
+
+    /// Meet the side waiting on `punch_id` from `addr`, returning where it
+    /// is; None when nobody waits (anymore).
+    pub fn meet(&self, punch_id: &str, addr: SocketAddr) -> Option<SocketAddr> {
+        let (_, waiting) = self.waiting.remove(punch_id)?;
+        waiting.peer.send(addr).ok()?;
+        Some(waiting.addr)
+    }
+
+    /// Stop waiting on `punch_id`, e.g. when the peer refused or didn't show
+    /// up in time; the waiting side falls back to the relay.
+    pub fn cancel(&self, punch_id: &str) {
+        self.waiting.remove(punch_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn both_sides_learn_the_other_address() {
+        let rendezvous = Rendezvous::default();
+        let a: SocketAddr = "203.0.113.1:40000".parse().unwrap();
+        let b: SocketAddr = "198.51.100.7:50000".parse().unwrap();
+
+        let waiting = rendezvous.expect("p1", a);
+        assert_eq!(rendezvous.meet("p1", b), Some(a));
+        assert_eq!(waiting.await.unwrap(), b);
+        assert_eq!(rendezvous.meet("p1", b), None);
+
+        let refused = rendezvous.expect("p2", a);
+        rendezvous.cancel("p2");
+        assert!(refused.await.is_err());
+        assert_eq!(rendezvous.meet("p2", b), None);
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Bytes of a TLS ClientHello looked at for the server name; larger hellos
+/// are routed as if they had none.
+const MAX_HELLO_BYTES: usize = 16 * 1024;
+
+/// Pause between peeks while the rest of a ClientHello is in flight.
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// What the first bytes of a connection say about its TLS server name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sni {
+    /// The ClientHello names this host
+    Found(String),
+    /// Not TLS, or a ClientHello without a usable server name
+    Absent,
+    /// More bytes are needed to tell
+    Incomplete,
+}
+
+/// Server name of the TLS ClientHello a connection starts with, without
+/// consuming any of it so the bytes still reach the client untouched.
+/// Gives up after `timeout`.
+pub async fn peek_server_name(stream: &TcpStream, timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; MAX_HELLO_BYTES];
+    loop {
+        let n = tokio::time::timeout_at(deadline, stream.peek(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        match parse_client_hello(&buf[..n]) {
+            Sni::Found(name) => return Some(name),
+            Sni::Absent => return None,
+            Sni::Incomplete if n == 0 || n == buf.len() => return None,
+            Sni::Incomplete => {
+                if Instant::now() + PEEK_INTERVAL > deadline {
+                    return None;
+                }
+                tokio::time::sleep(PEEK_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Reads big-endian fields off a byte slice, failing when it runs out.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    /// A field prefixed with its length, read by `len`
+    fn prefixed(&mut self, len: fn(&mut Self) -> Option<usize>) -> Option<Reader<'a>> {
+        let n = len(self)?;
+        self.take(n).map(Reader)
+    }
+}
+
+/// Find the server name in the first TLS record of `data`.
+pub fn parse_client_hello(data: &[u8]) -> Sni {
+    const HANDSHAKE: u8 = 0x16;
+    const CLIENT_HELLO: usize = 0x01;
+
+    let mut record = Reader(data);
+    match record.u8() {
+        None => return Sni::Incomplete,
+        Some(t) if t != HANDSHAKE as usize => return Sni::Absent,
+        Some(_) => {}
+    }
+    let Some(_version) = record.take(2) else {
+        return Sni::Incomplete;
+    };
+    let Some(mut fragment) = record.prefixed(Reader::u16) else {
+        return Sni::Incomplete;
+    };
+    // A complete record that still doesn't hold the server name means the
+    // hello is malformed or split over records; route it without one
+    server_name(&mut fragment, CLIENT_HELLO).map_or(Sni::Absent, |name| match name {
+        Some(name) => Sni::Found(name),
+        None => Sni::Absent,
+    })
+}
+
+/// The server name from a handshake message; None when it's malformed,
+/// Some(None) when it has none.
+fn server_name(fragment: &mut Reader<'_>, client_hello: usize) -> Option<Option<String>> {
+    if fragment.u8()? != client_hello {
+        return Some(None);
+    }
+    let mut hello = fragment.prefixed(Reader::u24)?;
+    hello.take(2 + 32)?; // client_version, random
+    hello.prefixed(Reader::u8)?; // session_id
+    hello.prefixed(Reader::u16)?; // cipher_suites
+    hello.prefixed(Reader::u8)?; // compression_methods
+    let Some(mut extensions) = hello.prefixed(Reader::u16) else {
+        return Some(None);
+    };
+
+    const SERVER_NAME: usize = 0x0000;
+    const HOST_NAME: usize = 0x00;
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut body = extensions.prefixed(Reader::u16)?;
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let mut names = body.prefixed(Reader::u16)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.prefixed(Reader::u16)?;
+            if name_type == HOST_NAME {
+                return Some(
+                    std::str::from_utf8(name.0)
+                        .ok()
+                        .map(|name| name.to_ascii_lowercase()),
+                );
+            }
+        }
+    }
+    Some(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal TLS 1.2 ClientHello, naming `host` when given
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // An unrelated extension first: supported_groups with x25519
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        if let Some(host) = host {
+            let name = host.as_bytes();
+            let list_len = 3 + name.len();
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+            extensions.extend_from_slice(&(list_len as u16).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.push(0); // session_id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+        hello.extend_from_slice(&[0x01, 0x00]); // null compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn server_name_is_read_from_the_client_hello() {
+        let hello = client_hello(Some("Agent.Example.com"));
+        assert_eq!(
+            parse_client_hello(&hello),
+            Sni::Found("agent.example.com".to_string())
+        );
+        assert_eq!(parse_client_hello(&client_hello(None)), Sni::Absent);
+        for split in [0, 1, 5, 40, hello.len() - 1] {
+            assert_eq!(parse_client_hello(&hello[..split]), Sni::Incomplete);
+        }
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), Sni::Absent);
+        assert_eq!(parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"), Sni::Absent);
+    }
+
+    #[tokio::test]
+    async fn peeking_leaves_the_hello_in_the_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let hello = client_hello(Some("agent.example.com"));
+        let (first, rest) = hello.split_at(20);
+        client.write_all(first).await.unwrap();
+        let rest = rest.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            client.write_all(&rest).await.unwrap();
+            client
+        });
+
+        let name = peek_server_name(&server, Duration::from_secs(2)).await;
+        assert_eq!(name.as_deref(), Some("agent.example.com"));
+        let mut received = vec![0; hello.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, hello);
+    }
+}
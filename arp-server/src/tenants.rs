@@ -0,0 +1,358 @@
+//! Tenants sharing one server. Each tenant has its own tokens, of the form
+//! `<tenant>.<secret>`, and the clients registering with one of them live in
+//! its namespace: they are known as `<tenant>:<client_id>`, so two tenants
+//! may both run an `agent`, and a tenant's clients and peers can only reach
+//! clients of the same tenant through the tunnel ports.
+
+use crate::limits::DialLimits;
+use crate::usage::Quota;
+use anyhow::{Context, Result, anyhow};
+use common::auth::constant_time_eq;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Separates the tenant from the client ID in namespaced IDs.
+pub const SEPARATOR: char = ':';
+
+/// One tenant's entry in the tenants file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantConfig {
+    /// Secrets of the tenant's tokens
+    tokens: Vec<String>,
+    /// Clients registered at once (0 = unlimited)
+    #[serde(default)]
+    max_clients: usize,
+    /// Overrides --dial-rate for the tenant's clients
+    #[serde(default)]
+    dial_rate: Option<u32>,
+    /// Overrides --dial-burst for the tenant's clients
+    #[serde(default)]
+    dial_burst: Option<u32>,
+    /// Connections routed to the tenant's clients per month (0 = unlimited)
+    #[serde(default)]
+    monthly_connections: u64,
+    /// Bytes relayed for the tenant's clients per month (0 = unlimited)
+    #[serde(default)]
+    monthly_bytes: u64,
+}
+
+/// Why a client was refused a tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TenantError {
+    /// No token, although the server has tenants.
+    Missing,
+    /// The token names no tenant or has the wrong secret.
+    Invalid,
+    /// The client ID contains [`SEPARATOR`].
+    InvalidClientId(String),
+    /// The tenant already has `max_clients` registered.
+    TooManyClients { tenant: String, max: usize },
+}
+
+impl TenantError {
+    /// Stable machine-readable code sent back in `RegisterResult`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TenantError::Missing => "tenant_token_required",
+            TenantError::Invalid => "invalid_tenant_token",
+            TenantError::InvalidClientId(_) => "invalid_client_id",
+            TenantError::TooManyClients { .. } => "tenant_client_limit",
+        }
+    }
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantError::Missing => write!(f, "A tenant token is required"),
+            TenantError::Invalid => write!(f, "Invalid tenant token"),
+            TenantError::InvalidClientId(id) => {
+                write!(f, "Client ID '{}' must not contain '{}'", id, SEPARATOR)
+            }
+            TenantError::TooManyClients { tenant, max } => {
+                write!(f, "Tenant '{}' already has {} clients", tenant, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TenantError {}
+
+/// A tenant, its limits and its counters.
+pub struct Tenant {
+    pub id: String,
+    secrets: Vec<String>,
+    max_clients: usize,
+    dial_rate: Option<u32>,
+    dial_burst: Option<u32>,
+    pub quota: Quota,
+    registrations: AtomicU64,
+    rejected: AtomicU64,
+    connections: AtomicU64,
+    /// Held while a client is admitted and registered
+    admission: Mutex<()>,
+}
+
+impl Tenant {
+    /// Serialize admitting the tenant's clients: held from counting its
+    /// registered clients until the new one is registered, so concurrent
+    /// registrations can't both take its last slot.
+    pub async fn lock_admission(&self) -> MutexGuard<'_, ()> {
+        self.admission.lock().await
+    }
+
+    /// `client_id` in the tenant's namespace.
+    pub fn qualify(&self, client_id: &str) -> String {
+        format!("{}{}{}", self.id, SEPARATOR, client_id)
+    }
+
+    /// Whether the namespaced `client_id` belongs to the tenant.
+    pub fn owns(&self, client_id: &str) -> bool {
+        client_id
+            .split_once(SEPARATOR)
+            .is_some_and(|(tenant, _)| tenant == self.id)
+    }
+
+    /// Admit one more client when `registered` of the tenant's clients are
+    /// registered already.
+    pub fn admit(&self, registered: usize) -> Result<(), TenantError> {
+        if self.max_clients > 0 && registered >= self.max_clients {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(TenantError::TooManyClients {
+                tenant: self.id.clone(),
+                max: self.max_clients,
+            });
+        }
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// `defaults` with the tenant's own dial rate and burst.
+    pub fn dial_limits(&self, defaults: DialLimits) -> DialLimits {
+        DialLimits {
+            rate: self.dial_rate.unwrap_or(defaults.rate),
+            burst: self.dial_burst.unwrap_or(defaults.burst),
+            ..defaults
+        }
+    }
+
+    fn accepts(&self, secret: &str) -> bool {
+        self.secrets
+            .iter()
+            .any(|known| constant_time_eq(known.as_bytes(), secret.as_bytes()))
+    }
+}
+
+/// The tenants of a shared server, by ID.
+pub struct Tenants {
+    tenants: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Read a tenants file: a JSON object from tenant ID to its `tokens`,
+    /// and optionally `max_clients`, `dial_rate` and `dial_burst`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&json).with_context(|| format!("Invalid tenants file {}", path.display()))
+    }
+
+    fn parse(json: &str) -> Result<Self> {
+        let configs: HashMap<String, TenantConfig> = serde_json::from_str(json)?;
+        let mut tenants = HashMap::new();
+        for (id, config) in configs {
+            if id.is_empty() || id.contains(SEPARATOR) || id.contains('.') {
+                return Err(anyhow!(
+                    "Tenant ID '{}' must be non-empty, without '{}' or '.'",
+                    id,
+                    SEPARATOR
+                ));
+            }
+            if config.tokens.iter().any(String::is_empty) {
+                return Err(anyhow!("Tenant '{}' has an empty token", id));
+            }
+            let tenant = Tenant {
+                id: id.clone(),
+                secrets: config.tokens,
+                max_clients: config.max_clients,
+                dial_rate: config.dial_rate,
+                dial_burst: config.dial_burst,
+                quota: Quota {
+                    connections: config.monthly_connections,
+                    bytes: config.monthly_bytes,
+                },
+                registrations: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                connections: AtomicU64::new(0),
+                admission: Mutex::new(()),
+            };
+            tenants.insert(id, Arc::new(tenant));
+        }
+        Ok(Tenants { tenants })
+    }
+
+    /// The tenant `token` belongs to.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<&Arc<Tenant>, TenantError> {
+        let (tenant, secret) = token
+            .ok_or(TenantError::Missing)?
+            .trim()
+            .split_once('.')
+            .ok_or(TenantError::Invalid)?;
+        self.tenants
+            .get(tenant)
+            .filter(|tenant| tenant.accepts(secret))
+            .ok_or(TenantError::Invalid)
+    }
+
+    /// The tenant of `token` and `client_id` in its namespace.
+    pub fn resolve(
+        &self,
+        token: Option<&str>,
+        client_id: &str,
+    ) -> Result<(&Arc<Tenant>, String), TenantError> {
+        if client_id.contains(SEPARATOR) {
+            return Err(TenantError::InvalidClientId(client_id.to_string()));
+        }
+        let tenant = self.authenticate(token)?;
+        Ok((tenant, tenant.qualify(client_id)))
+    }
+
+    /// The tenant of the namespaced `client_id`.
+    pub fn owner(&self, client_id: &str) -> Option<&Arc<Tenant>> {
+        client_id
+            .split_once(SEPARATOR)
+            .and_then(|(tenant, _)| self.tenants.get(tenant))
+    }
+
+    /// Count a public or peer connection routed to the namespaced `client_id`.
+    pub fn record_connection(&self, client_id: &str) {
+        if let Some(tenant) = self.owner(client_id) {
+            tenant.connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Every tenant's limits and counters, with `registered(tenant)` of its
+    /// clients registered right now.
+    pub fn snapshot(&self, registered: impl Fn(&Tenant) -> usize) -> Vec<Value> {
+        let mut tenants: Vec<&Arc<Tenant>> = self.tenants.values().collect();
+        tenants.sort_by(|a, b| a.id.cmp(&b.id));
+        tenants
+            .into_iter()
+            .map(|tenant| {
+                json!({
+                    "tenant": tenant.id,
+                    "clients": registered(tenant),
+                    "max_clients": tenant.max_clients,
+                    "dial_rate": tenant.dial_rate,
+                    "dial_burst": tenant.dial_burst,
+                    "monthly_connections": tenant.quota.connections,
+                    "monthly_bytes": tenant.quota.bytes,
+                    "registrations": tenant.registrations.load(Ordering::Relaxed),
+                    "rejected_registrations": tenant.rejected.load(Ordering::Relaxed),
+                    "connections": tenant.connections.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants() -> Tenants {
+        Tenants::parse(
+            r#"{
+                "acme": { "tokens": ["alpha", "beta"], "max_clients": 1, "dial_rate": 5 },
+                "globex": { "tokens": ["gamma"] }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens_place_client_ids_in_their_tenant() {
+        let tenants = tenants();
+        let (acme, id) = tenants.resolve(Some("acme.beta"), "agent").unwrap();
+        assert_eq!(id, "acme:agent");
+        assert!(acme.owns(&id));
+        let (globex, id) = tenants.resolve(Some("globex.gamma"), "agent").unwrap();
+        assert_eq!(id, "globex:agent");
+        assert!(!globex.owns("acme:agent"));
+
+        let refused =
+            |token: Option<&str>, client_id: &str| tenants.resolve(token, client_id).err().unwrap();
+        assert_eq!(refused(None, "agent"), TenantError::Missing);
+        assert_eq!(refused(Some("acme.gamma"), "agent"), TenantError::Invalid);
+        assert_eq!(
+            refused(Some("initech.alpha"), "agent"),
+            TenantError::Invalid
+        );
+        assert_eq!(refused(Some("alpha"), "agent"), TenantError::Invalid);
+        assert_eq!(
+            refused(Some("globex.gamma"), "acme:agent").code(),
+            "invalid_client_id"
+        );
+    }
+
+    #[test]
+    fn tenants_have_their_own_limits() {
+        let tenants = tenants();
+        let acme = tenants.authenticate(Some("acme.alpha")).unwrap();
+        assert!(acme.admit(0).is_ok());
+        assert_eq!(acme.admit(1).unwrap_err().code(), "tenant_client_limit");
+        let globex = tenants.authenticate(Some("globex.gamma")).unwrap();
+        assert!(globex.admit(100).is_ok());
+
+        let defaults = DialLimits {
+            rate: 50,
+            burst: 50,
+            queue_size: 200,
+        };
+        assert_eq!(acme.dial_limits(defaults).rate, 5);
+        assert_eq!(acme.dial_limits(defaults).burst, 50);
+        assert_eq!(globex.dial_limits(defaults).rate, 50);
+
+        tenants.record_connection("acme:agent");
+        tenants.record_connection("agent");
+        let snapshot = tenants.snapshot(|_| 0);
+        assert_eq!(snapshot[0]["tenant"], "acme");
+        assert_eq!(snapshot[0]["registrations"], 1);
+        assert_eq!(snapshot[0]["rejected_registrations"], 1);
+        assert_eq!(snapshot[0]["connections"], 1);
+        assert_eq!(snapshot[1]["connections"], 0);
+
+        assert!(Tenants::parse(r#"{ "a:b": { "tokens": ["x"] } }"#).is_err());
+        assert!(Tenants::parse(r#"{ "a": { "tokens": [""] } }"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn concurrent_registrations_share_the_last_slot() {
+        let tenants = Arc::new(tenants());
+        let clients = Arc::new(dashmap::DashMap::<String, ()>::new());
+        let registrations = ["first", "second"].map(|client_id| {
+            let (tenants, clients) = (tenants.clone(), clients.clone());
+            tokio::spawn(async move {
+                let (tenant, id) = tenants.resolve(Some("acme.alpha"), client_id).unwrap();
+                let _admission = tenant.lock_admission().await;
+                let registered = clients.iter().filter(|c| tenant.owns(c.key())).count();
+                // Registering a client awaits between the count and the insert
+                tokio::task::yield_now().await;
+                tenant.admit(registered).map(|()| clients.insert(id, ()))
+            })
+        });
+        let mut admitted = 0;
+        for registration in registrations {
+            admitted += registration.await.unwrap().is_ok() as usize;
+        }
+        assert_eq!(admitted, 1);
+        assert_eq!(clients.len(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tracing::{info, warn};
+
+/// ALPN protocol used by the ACME TLS-ALPN-01 challenge (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Picks the public listener's certificate by SNI, answering ACME
+/// TLS-ALPN-01 validation handshakes with the pending challenge certificate.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    fallback: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn set_cert(&self, host: &str, key: CertifiedKey) {
+        write(&self.certs).insert(host.to_ascii_lowercase(), Arc::new(key));
+    }
+
+    /// Certificate served when no per-hostname certificate matches.
+    pub fn set_fallback(&self, key: CertifiedKey) {
+        *self.fallback.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(key));
+    }
+
+    pub fn set_challenge(&self, host: &str, key: CertifiedKey) {
+        write(&self.challenges).insert(host.to_ascii_lowercase(), Arc::new(key));
+    }
+
+    pub fn clear_challenge(&self, host: &str) {
+        write(&self.challenges).remove(&host.to_ascii_lowercase());
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().map(str::to_ascii_lowercase);
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+
+        if is_challenge {
+            return read(&self.challenges).get(host.as_deref()?).cloned();
+        }
+
+        host.and_then(|host| read(&self.certs).get(&host).cloned())
+            .or_else(|| {
+                self.fallback
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone()
+            })
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Build a TLS acceptor for the public listener backed by `resolver`.
+pub fn acceptor(resolver: Arc<CertResolver>) -> Result<TlsAcceptor> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build the mutual TLS acceptor for the control and proxy ports: the server
+/// presents `key`, and clients must present a certificate issued by one of
+/// the CAs in `client_ca_pem`.
+pub fn tunnel_acceptor(key: CertifiedKey, client_ca_pem: &[u8]) -> Result<TlsAcceptor> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(client_ca_pem) {
+        roots.add(cert.map_err(|e| anyhow!("Invalid CA certificate PEM: {:?}", e))?)?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("No CA certificate found in PEM"));
+    }
+
+    let provider = Arc::new(default_provider());
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+    let resolver = CertResolver::default();
+    resolver.set_fallback(key);
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(Arc::new(resolver));
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Parse a PEM certificate chain and private key into a signing key.
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Invalid certificate PEM: {:?}", e))?;
+    if chain.is_empty() {
+        return Err(anyhow!("No certificate found in PEM"));
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem)
+        .map_err(|e| anyhow!("Invalid private key PEM: {:?}", e))?;
+    Ok(CertifiedKey::new(chain, any_supported_type(&key)?))
+}
+
+/// Issued certificates on disk, one directory per hostname under
+/// `<data_dir>/certs/`.
+pub struct CertStore {
+    dir: PathBuf,
+}
+
+impl CertStore {
+    pub fn new(data_dir: &Path) -> Self {
+        CertStore {
+            dir: data_dir.join("certs"),
+        }
+    }
+
+    pub fn save(&self, host: &str, cert_pem: &str, key_pem: &str) -> Result<()> {
+        let dir = self.dir.join(host);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("cert.pem"), cert_pem)?;
+        write_private(&dir.join("key.pem"), key_pem)?;
+
+        let issued_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        std::fs::write(
+            dir.join("meta.json"),
+            serde_json::to_vec(&serde_json::json!({ "issued_at": issued_at }))?,
+        )?;
+        Ok(())
+    }
+
+    /// Age of the stored certificate for `host`, if there is one.
+    pub fn age(&self, host: &str) -> Option<Duration> {
+        let meta = std::fs::read(self.dir.join(host).join("meta.json")).ok()?;
+        let meta: serde_json::Value = serde_json::from_slice(&meta).ok()?;
+        let issued_at = UNIX_EPOCH + Duration::from_secs(meta["issued_at"].as_u64()?);
+        Some(
+            SystemTime::now()
+                .duration_since(issued_at)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Install every stored certificate into `resolver`.
+    pub fn load_all(&self, resolver: &CertResolver) -> usize {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return 0;
+        };
+
+        let mut loaded = 0;
+        for entry in entries.flatten() {
+            let host = entry.file_name().to_string_lossy().to_string();
+            match self.load(&host) {
+                Ok(key) => {
+                    resolver.set_cert(&host, key);
+                    loaded += 1;
+                }
+                Err(e) => warn!("Skipping stored certificate for {}: {}", host, e),
+            }
+        }
+        if loaded > 0 {
+            info!(
+                "Loaded {} stored certificate(s) from {:?}",
+                loaded, self.dir
+            );
+        }
+        loaded
+    }
+
+    fn load(&self, host: &str) -> Result<CertifiedKey> {
+        let dir = self.dir.join(host);
+        certified_key(
+            &std::fs::read(dir.join("cert.pem"))?,
+            &std::fs::read(dir.join("key.pem"))?,
+        )
+    }
+}
+
+/// Write a file readable only by the owner.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(contents.as_ref())?;
+    }
+    #[cfg(not(unix))]
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_certificates_round_trip() {
+        let data_dir = std::env::temp_dir().join(format!("arps-tls-test-{}", std::process::id()));
+        let store = CertStore::new(&data_dir);
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["agent.example.com".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        store
+            .save("agent.example.com", &cert.pem(), &key.serialize_pem())
+            .unwrap();
+
+        let resolver = CertResolver::default();
+        assert_eq!(store.load_all(&resolver), 1);
+        assert!(read(&resolver.certs).contains_key("agent.example.com"));
+        assert!(store.age("agent.example.com").unwrap() < Duration::from_secs(60));
+        assert!(store.age("other.example.com").is_none());
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tunnel_ports_only_accept_certificates_from_the_client_ca() {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+        use tokio_rustls::TlsConnector;
+        use tokio_rustls::rustls::ClientConfig;
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        let ca = |name: &str| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, name);
+            (params.self_signed(&key).unwrap(), key)
+        };
+        let issue = |name: &str, (ca, ca_key): &(Certificate, KeyPair)| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, ca, ca_key)
+                .unwrap();
+            (cert, key)
+        };
+        let trusted = ca("arps test CA");
+        let (server_cert, server_key) = issue("arps.test", &trusted);
+        let acceptor = tunnel_acceptor(
+            certified_key(
+                server_cert.pem().as_bytes(),
+                server_key.serialize_pem().as_bytes(),
+            )
+            .unwrap(),
+            trusted.0.pem().as_bytes(),
+        )
+        .unwrap();
+
+        // Whether the server completes a handshake with a client presenting
+        // `client`, if any
+        let accepted = |client: Option<(Certificate, KeyPair)>| {
+            let acceptor = acceptor.clone();
+            let mut roots = RootCertStore::empty();
+            roots.add(trusted.0.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match client {
+                Some((cert, key)) => builder
+                    .with_client_auth_cert(
+                        vec![cert.der().clone()],
+                        PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            async move {
+                let (client, server) = tokio::io::duplex(16 * 1024);
+                let connector = TlsConnector::from(Arc::new(config));
+                let name = ServerName::try_from("arps.test").unwrap();
+                let (_, server) =
+                    tokio::join!(connector.connect(name, client), acceptor.accept(server));
+                server.is_ok()
+            }
+        };
+
+        assert!(accepted(Some(issue("agent", &trusted))).await);
+        assert!(!accepted(None).await);
+        assert!(!accepted(Some(issue("agent", &ca("other CA")))).await);
+        assert!(
+            tunnel_acceptor(
+                certified_key(
+                    server_cert.pem().as_bytes(),
+                    server_key.serialize_pem().as_bytes()
+                )
+                .unwrap(),
+                b"",
+            )
+            .is_err()
+        );
+    }
+}
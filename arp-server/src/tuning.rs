@@ -0,0 +1,154 @@
+//! Latency and throughput knobs: how long public connections wait for a
+//! tunnel, how often the waiting ones are swept and pools refilled, and
+//! how many idle tunnels each client keeps. Read from the `--config` TOML
+//! file, with command-line flags taking precedence.
+
+use crate::liveness::POOL_REFILL_INTERVAL;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::time::Duration;
+
+const DEFAULT_PENDING_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_PENDING_CLEANUP_SECS: u64 = 2;
+const DEFAULT_POOL_SIZE: usize = 5;
+
+/// Settings of the config file, or those given on the command line; unset
+/// ones fall back to the other source, then to the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Seconds a public connection waits for a tunnel before it is answered
+    /// with 504
+    pub pending_timeout_secs: Option<u64>,
+    /// Seconds between sweeps of public connections past the timeout
+    pub pending_cleanup_secs: Option<u64>,
+    /// Seconds between pool refills
+    pub pool_refill_secs: Option<u64>,
+    /// Idle tunnels kept for each client
+    pub pool_size: Option<usize>,
+    /// Idle tunnels kept for particular clients, by client ID
+    #[serde(default)]
+    pub pool_sizes: HashMap<String, usize>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// These settings, with those set in `overrides` replacing them.
+    pub fn overridden_by(mut self, overrides: ServerConfig) -> Self {
+        self.pending_timeout_secs = overrides.pending_timeout_secs.or(self.pending_timeout_secs);
+        self.pending_cleanup_secs = overrides.pending_cleanup_secs.or(self.pending_cleanup_secs);
+        self.pool_refill_secs = overrides.pool_refill_secs.or(self.pool_refill_secs);
+        self.pool_size = overrides.pool_size.or(self.pool_size);
+        self.pool_sizes.extend(overrides.pool_sizes);
+        self
+    }
+}
+
+/// The settings in effect.
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub pending_timeout: Duration,
+    pub pending_cleanup_interval: Duration,
+    pub pool_refill_interval: Duration,
+    /// Idle tunnels kept for clients without their own size
+    pub pool_size: usize,
+    pool_sizes: HashMap<String, usize>,
+}
+
+impl Tuning {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let secs = |name: &str, value: Option<u64>, default: u64| match value.unwrap_or(default) {
+            0 => Err(anyhow!("{} must be at least 1 second", name)),
+            secs => Ok(Duration::from_secs(secs)),
+        };
+        Ok(Tuning {
+            pending_timeout: secs(
+                "pending_timeout_secs",
+                config.pending_timeout_secs,
+                DEFAULT_PENDING_TIMEOUT_SECS,
+            )?,
+            pending_cleanup_interval: secs(
+                "pending_cleanup_secs",
+                config.pending_cleanup_secs,
+                DEFAULT_PENDING_CLEANUP_SECS,
+            )?,
+            pool_refill_interval: secs(
+                "pool_refill_secs",
+                config.pool_refill_secs,
+                POOL_REFILL_INTERVAL.as_secs(),
+            )?,
+            pool_size: config.pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+            pool_sizes: config.pool_sizes,
+        })
+    }
+
+    /// Idle tunnels kept for `client_id`.
+    pub fn pool_size_of(&self, client_id: &str) -> usize {
+        self.pool_sizes
+            .get(client_id)
+            .copied()
+            .unwrap_or(self.pool_size)
+    }
+}
+
+/// Parse a `--client-pool-size` value, `<client_id>=<size>`.
+pub fn parse_pool_size(value: &str) -> Result<(String, usize), String> {
+    let (client_id, size) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <client_id>=<size>, got '{}'", value))?;
+    let size = size
+        .parse()
+        .map_err(|_| format!("invalid pool size '{}'", size))?;
+    Ok((client_id.to_string(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_settings_win_over_the_config_file() {
+        let file: ServerConfig = toml::from_str(
+            r#"
+            pending_timeout_secs = 30
+            pool_size = 8
+
+            [pool_sizes]
+            "db-box" = 20
+            "acme:agent" = 0
+            "#,
+        )
+        .unwrap();
+        let cli = ServerConfig {
+            pool_size: Some(3),
+            pool_sizes: [parse_pool_size("db-box=12").unwrap()].into(),
+            ..ServerConfig::default()
+        };
+
+        let tuning = Tuning::new(file.overridden_by(cli)).unwrap();
+        assert_eq!(tuning.pending_timeout, Duration::from_secs(30));
+        assert_eq!(
+            tuning.pending_cleanup_interval,
+            Duration::from_secs(DEFAULT_PENDING_CLEANUP_SECS)
+        );
+        assert_eq!(tuning.pool_refill_interval, POOL_REFILL_INTERVAL);
+        assert_eq!(tuning.pool_size_of("db-box"), 12);
+        assert_eq!(tuning.pool_size_of("acme:agent"), 0);
+        assert_eq!(tuning.pool_size_of("other"), 3);
+
+        assert!(toml::from_str::<ServerConfig>("pool_sise = 3").is_err());
+        let zero = ServerConfig {
+            pending_cleanup_secs: Some(0),
+            ..ServerConfig::default()
+        };
+        assert!(Tuning::new(zero).is_err());
+        assert!(parse_pool_size("db-box").is_err());
+    }
+}
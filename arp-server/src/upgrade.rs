@@ -0,0 +1,175 @@
+//! WebSocket handshakes on public connections. The request arps parsed to
+//! route a connection is normally rebuilt for the client, which re-encodes
+//! the query, lowercases header names and drops whatever the caller sent
+//! past the headers. A handshake is instead forwarded as the bytes it
+//! arrived as, with only the identity headers replaced, and the streams are
+//! then piped as they are.
+
+use common::http::{CLIENT_ID_HEADER, CONN_ID_HEADER, HttpMethod, HttpRequest, REMOTE_ADDR_HEADER};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// How far the request parser may read past the headers into its buffer
+const READ_AHEAD: usize = 8 * 1024;
+
+/// Headers arps sets itself, see [`HttpRequest::set_identity`]
+const IDENTITY_HEADERS: [&str; 3] = [REMOTE_ADDR_HEADER, CONN_ID_HEADER, CLIENT_ID_HEADER];
+
+/// Reads from a stream while keeping a copy of what was read, up to a limit.
+pub struct Recording<'a, S> {
+    inner: &'a mut S,
+    bytes: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+impl<'a, S> Recording<'a, S> {
+    /// Record what is read from `inner` while a request with up to
+    /// `max_header_bytes` of headers is parsed from it.
+    pub fn new(inner: &'a mut S, max_header_bytes: usize) -> Self {
+        Recording {
+            inner,
+            bytes: Vec::new(),
+            limit: max_header_bytes.saturating_add(READ_AHEAD),
+            truncated: false,
+        }
+    }
+
+    /// Everything read; None when more than the limit was.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        (!self.truncated).then_some(self.bytes)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recording<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if self.truncated || self.bytes.len() + read.len() > self.limit {
+            self.truncated = true;
+            self.bytes = Vec::new();
+        } else {
+            self.bytes.extend_from_slice(read);
+        }
+        result
+    }
+}
+
+/// Whether `request` asks to switch the connection to WebSocket.
+pub fn is_websocket(request: &HttpRequest) -> bool {
+    let has_token = |header: &str, token: &str| {
+        request.header_values(header).any(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    request.method == HttpMethod::GET
+        && has_token("upgrade", "websocket")
+        && has_token("connection", "upgrade")
+}
+
+/// The handshake `raw` as received, with the identity headers of `request`
+/// in place of any the caller sent, followed by whatever the caller sent
+/// after it. None when `raw` doesn't hold a complete request head.
+pub fn handshake(raw: &[u8], request: &HttpRequest) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(raw.len() + 128);
+    let mut rest = raw;
+    let mut request_line = true;
+    loop {
+        let end = rest.iter().position(|&b| b == b'\n')? + 1;
+        let (line, next) = rest.split_at(end);
+        rest = next;
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !request_line && is_identity_header(line) {
+            continue;
+        }
+        request_line = false;
+        out.extend_from_slice(line);
+    }
+    for key in IDENTITY_HEADERS {
+        if let Some(value) = request.header(key) {
+            out.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(rest);
+    Some(out)
+}
+
+fn is_identity_header(line: &[u8]) -> bool {
+    let Some(colon) = line.iter().position(|&b| b == b':') else {
+        return false;
+    };
+    let name = String::from_utf8_lossy(&line[..colon]);
+    IDENTITY_HEADERS
+        .iter()
+        .any(|key| name.trim().eq_ignore_ascii_case(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::http::ParseLimits;
+
+    #[tokio::test]
+    async fn handshakes_are_forwarded_as_received() {
+        let sent: &[u8] = b"GET /ws?token=a%2Bb&room=x+y HTTP/1.1\r\n\
+            Host: agent.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            X-Arp-Client-Id: spoofed\r\n\
+            \r\n\
+            \x81\x02hi";
+        let mut stream = sent;
+        let mut recording = Recording::new(&mut stream, 16 * 1024);
+        let mut request =
+            HttpRequest::parse_with_limits(&mut recording, "test", &ParseLimits::default())
+                .await
+                .unwrap();
+        let raw = recording.into_bytes().unwrap();
+        assert!(is_websocket(&request));
+        request.set_identity(Some("203.0.113.7:5000"), "r-1", "agent");
+
+        let forwarded = handshake(&raw, &request).unwrap();
+        let expected: &[u8] = b"GET /ws?token=a%2Bb&room=x+y HTTP/1.1\r\n\
+            Host: agent.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            x-arp-remote-addr: 203.0.113.7:5000\r\n\
+            x-arp-conn-id: r-1\r\n\
+            x-arp-client-id: agent\r\n\
+            \r\n\
+            \x81\x02hi";
+        assert_eq!(forwarded, expected);
+
+        // A plain request, and one too large to keep a copy of
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nConnection: Upgrade\r\n\r\n";
+        let mut recording = Recording::new(&mut stream, 0);
+        let request =
+            HttpRequest::parse_with_limits(&mut recording, "test", &ParseLimits::default())
+                .await
+                .unwrap();
+        assert!(!is_websocket(&request));
+        assert!(recording.into_bytes().is_some());
+        let mut stream = [b'x'; 3 * READ_AHEAD].as_slice();
+        let mut recording = Recording::new(&mut stream, 0);
+        let mut buf = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut recording, &mut buf)
+            .await
+            .unwrap();
+        assert!(recording.into_bytes().is_none());
+        assert!(handshake(b"GET / HTTP/1.1\r\nHost: x\r\n", &request).is_none());
+    }
+}
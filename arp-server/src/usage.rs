@@ -0,0 +1,414 @@
+//! Monthly usage of every client and tenant: connections routed to them and
+//! bytes relayed to and from their users. Checked against quotas before a
+//! connection is routed, and exported from the admin API for chargeback.
+
+use crate::liveness::write_atomically;
+use anyhow::Result;
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// How often changed usage is saved
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// One month's usage of a client or tenant.
+#[derive(Default)]
+pub struct Counters {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Counters {
+    fn bytes(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed) + self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "connections": self.connections.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+        })
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let field = |name: &str| AtomicU64::new(value[name].as_u64().unwrap_or(0));
+        Counters {
+            connections: field("connections"),
+            bytes_in: field("bytes_in"),
+            bytes_out: field("bytes_out"),
+        }
+    }
+}
+
+/// Limits on one month's usage (0 = unlimited).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    pub connections: u64,
+    /// Bytes relayed in both directions
+    pub bytes: u64,
+}
+
+impl Quota {
+    fn check(&self, scope: &str, usage: &Counters) -> Result<(), QuotaError> {
+        let exhausted = |kind: &'static str, limit: u64| QuotaError {
+            scope: scope.to_string(),
+            kind,
+            limit,
+        };
+        if self.connections > 0 && usage.connections.load(Ordering::Relaxed) >= self.connections {
+            return Err(exhausted("connection", self.connections));
+        }
+        if self.bytes > 0 && usage.bytes() >= self.bytes {
+            return Err(exhausted("byte", self.bytes));
+        }
+        Ok(())
+    }
+}
+
+/// A monthly quota that is used up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaError {
+    /// `client 'x'` or `tenant 'y'`
+    scope: String,
+    kind: &'static str,
+    limit: u64,
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Monthly {} quota of {} used up for {}",
+            self.kind, self.limit, self.scope
+        )
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+/// Usage of every client and tenant, by month (`YYYY-MM`, UTC).
+pub struct Usage {
+    clients: DashMap<(String, String), Arc<Counters>>,
+    tenants: DashMap<(String, String), Arc<Counters>>,
+    /// Applies to every client
+    client_quota: Quota,
+    /// File the usage is saved to, if persisted
+    persist_path: Option<PathBuf>,
+    changed: AtomicBool,
+}
+
+impl Usage {
+    pub fn new(client_quota: Quota) -> Self {
+        Usage {
+            clients: DashMap::new(),
+            tenants: DashMap::new(),
+            client_quota,
+            persist_path: None,
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// Load the usage saved at `path` and keep saving it there once
+    /// [`persist`](Self::persist) runs. A missing file starts empty.
+    pub fn with_persistence(mut self, path: PathBuf) -> Result<Self> {
+        match std::fs::read(&path) {
+            Ok(data) => {
+                let saved: Value = serde_json::from_slice(&data)?;
+                for (map, key) in [(&self.clients, "clients"), (&self.tenants, "tenants")] {
+                    for row in saved[key].as_array().into_iter().flatten() {
+                        if let (Some(month), Some(id)) = (row["month"].as_str(), row["id"].as_str())
+                        {
+                            let counters = Arc::new(Counters::from_json(row));
+                            map.insert((month.to_string(), id.to_string()), counters);
+                        }
+                    }
+                }
+                info!(
+                    "Loaded usage of {} clients from {}",
+                    self.clients.len(),
+                    path.display()
+                );
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.persist_path = Some(path);
+        Ok(self)
+    }
+
+    /// Count a connection to `client_id` this month, unless its quota or
+    /// that of its `tenant` is used up. The returned meter counts the bytes
+    /// relayed over the connection.
+    pub fn admit(
+        &self,
+        client_id: &str,
+        tenant: Option<(&str, Quota)>,
+    ) -> Result<Meter, QuotaError> {
+        self.admit_in(&current_month(), client_id, tenant)
+    }
+
+    fn admit_in(
+        &self,
+        month: &str,
+        client_id: &str,
+        tenant: Option<(&str, Quota)>,
+    ) -> Result<Meter, QuotaError> {
+        // Checked before counting so refused connections leave no trace
+        let key = |id: &str| (month.to_string(), id.to_string());
+        if let Some(client) = self.clients.get(&key(client_id)) {
+            self.client_quota
+                .check(&format!("client '{}'", client_id), &client)?;
+        }
+        if let Some((tenant_id, quota)) = tenant
+            && let Some(tenant) = self.tenants.get(&key(tenant_id))
+        {
+            quota.check(&format!("tenant '{}'", tenant_id), &tenant)?;
+        }
+
+        let mut counters = vec![self.clients.entry(key(client_id)).or_default().clone()];
+        if let Some((tenant_id, _)) = tenant {
+            counters.push(self.tenants.entry(key(tenant_id)).or_default().clone());
+        }
+        for counter in &counters {
+            counter.connections.fetch_add(1, Ordering::Relaxed);
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(Meter { counters })
+    }
+
+    /// Usage rows of `month`, or of every month, sorted by month and ID.
+    pub fn report(&self, month: Option<&str>) -> Value {
+        let rows = |map: &DashMap<(String, String), Arc<Counters>>| {
+            let mut rows: Vec<((String, String), Value)> = map
+                .iter()
+                .filter(|entry| month.is_none_or(|month| entry.key().0 == month))
+                .map(|entry| {
+                    let (month, id) = entry.key();
+                    let mut row = entry.value().to_json();
+                    row["month"] = json!(month);
+                    row["id"] = json!(id);
+                    (entry.key().clone(), row)
+                })
+                .collect();
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+            rows.into_iter().map(|(_, row)| row).collect::<Vec<_>>()
+        };
+        json!({ "clients": rows(&self.clients), "tenants": rows(&self.tenants) })
+    }
+
+    /// [`report`](Self::report) as CSV, one line per client and tenant.
+    pub fn report_csv(&self, month: Option<&str>) -> String {
+        let report = self.report(month);
+        let mut csv = String::from("month,scope,id,connections,bytes_in,bytes_out\n");
+        for (scope, key) in [("client", "clients"), ("tenant", "tenants")] {
+            for row in report[key].as_array().into_iter().flatten() {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{}\n",
+                    row["month"].as_str().unwrap_or_default(),
+                    scope,
+                    csv_field(row["id"].as_str().unwrap_or_default()),
+                    row["connections"],
+                    row["bytes_in"],
+                    row["bytes_out"],
+                ));
+            }
+        }
+        csv
+    }
+
+    /// Save the usage every [`PERSIST_INTERVAL`] while it changes. Returns
+    /// at once when persistence is off.
+    pub async fn persist(self: Arc<Self>) {
+        let Some(path) = self.persist_path.clone() else {
+            return;
+        };
+        let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !self.changed.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            if let Err(e) = write_atomically(&path, self.report(None).to_string()).await {
+                warn!("Failed to save usage to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Quote a CSV field holding a separator or a quote.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The current month, `YYYY-MM` in UTC.
+pub fn current_month() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    month_of(secs)
+}
+
+/// The month of a Unix timestamp, `YYYY-MM` in UTC.
+fn month_of(unix_secs: u64) -> String {
+    // Civil-from-days, after Howard Hinnant's date algorithms
+    let days = (unix_secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Counts the bytes of one connection into its client's, and tenant's,
+/// usage.
+pub struct Meter {
+    counters: Vec<Arc<Counters>>,
+}
+
+impl Meter {
+    fn add(&self, pick: fn(&Counters) -> &AtomicU64, bytes: usize) {
+        for counter in &self.counters {
+            pick(counter).fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A user connection whose traffic is metered: bytes read from the user
+/// count as `bytes_in`, bytes written to them as `bytes_out`.
+pub struct Metered<S> {
+    inner: S,
+    meter: Meter,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, meter: Meter) -> Self {
+        Metered { inner, meter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        if read > 0 {
+            self.meter.add(|c| &c.bytes_in, read);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.meter.add(|c| &c.bytes_out, written);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn months_are_utc_calendar_months() {
+        assert_eq!(month_of(0), "1970-01");
+        // 2024-02-29T23:59:59Z and the second after it
+        assert_eq!(month_of(1_709_251_199), "2024-02");
+        assert_eq!(month_of(1_709_251_200), "2024-03");
+        // 2026-12-31T12:00:00Z
+        assert_eq!(month_of(1_798_718_400), "2026-12");
+    }
+
+    #[tokio::test]
+    async fn quotas_refuse_connections_once_used_up() {
+        let usage = Usage::new(Quota {
+            connections: 0,
+            bytes: 10,
+        });
+        let tenant = Some((
+            "acme",
+            Quota {
+                connections: 2,
+                bytes: 0,
+            },
+        ));
+
+        // Bytes count towards the client and its tenant
+        let meter = usage.admit_in("2026-10", "acme:a", tenant).unwrap();
+        let (mut user, server) = tokio::io::duplex(64);
+        let mut metered = Metered::new(server, meter);
+        user.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        metered.read_exact(&mut buf).await.unwrap();
+        metered.write_all(b"world!").await.unwrap();
+        let error = usage.admit_in("2026-10", "acme:a", tenant).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Monthly byte quota of 10 used up for client 'acme:a'"
+        );
+
+        usage.admit_in("2026-10", "acme:b", tenant).unwrap();
+        let error = usage.admit_in("2026-10", "acme:c", tenant).err().unwrap();
+        assert_eq!(error.kind, "connection");
+        // A new month starts over
+        assert!(usage.admit_in("2026-11", "acme:c", tenant).is_ok());
+
+        let report = usage.report(Some("2026-10"));
+        assert_eq!(report["clients"][0]["id"], "acme:a");
+        assert_eq!(report["clients"][0]["bytes_in"], 5);
+        assert_eq!(report["clients"][0]["bytes_out"], 6);
+        assert_eq!(report["tenants"][0]["connections"], 2);
+        assert_eq!(
+            usage.report_csv(Some("2026-10")),
+            "month,scope,id,connections,bytes_in,bytes_out\n\
+             2026-10,client,acme:a,1,5,6\n\
+             2026-10,client,acme:b,1,0,0\n\
+             2026-10,tenant,acme,2,5,6\n"
+        );
+    }
+}
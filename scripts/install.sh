@@ -7,9 +7,14 @@ ARCH=$(uname -m)
 
 case $OS in
   linux)
+    # musl 系统（如 Alpine）或设置了 ARP_STATIC=1 时使用静态链接版本
+    LIBC="gnu"
+    if [ "${ARP_STATIC:-0}" = "1" ] || (ldd --version 2>&1 | grep -qi musl); then
+      LIBC="musl"
+    fi
     case $ARCH in
-      x86_64) TARGET="x86_64-unknown-linux-gnu" ;;
-      aarch64|arm64) TARGET="aarch64-unknown-linux-gnu" ;;
+      x86_64) TARGET="x86_64-unknown-linux-${LIBC}" ;;
+      aarch64|arm64) TARGET="aarch64-unknown-linux-${LIBC}" ;;
       *) echo "❌ 不支持的架构: $ARCH"; exit 1 ;;
     esac
     ;;